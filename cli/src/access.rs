@@ -0,0 +1,146 @@
+//! # Access Requests
+//!
+//! Asks an agent's owner for access to one of its targets when the
+//! controller may not connect on its own. The server keeps the request
+//! until the owner decides, so the answer can be collected later with
+//! `access-status`:
+//!
+//! ```text
+//! $ tunnel-cli request-access --reason "Nightly backup" office-pc 127.0.0.1 5432
+//! Access request 9c0f6a3e2b7d4e1f8a5b6c7d8e9f0a1b is pending
+//! $ tunnel-cli access-status 9c0f6a3e2b7d4e1f8a5b6c7d8e9f0a1b
+//! Access approved for 59 more min, invitation: 4d2e…
+//! $ tunnel-cli stdio --invite 4d2e… office-pc 127.0.0.1 5432
+//! ```
+//!
+//! The invitation is handed out once: the first `AccessUpdate` after the
+//! approval carries it, later ones do not.
+
+use crate::quic;
+use crate::tunnel::{self, Target};
+use tunnel_protocol::{unix_time_ms, AccessState, ControlMessage, ErrorCode};
+use uuid::Uuid;
+
+/// Requests access to `target`, optionally waiting for the owner's
+/// decision, and prints where the request stands.
+pub async fn request(
+    server: &str,
+    token: Option<String>,
+    target: Target,
+    reason: Option<String>,
+    wait: bool,
+) -> Result<(), String> {
+    let (connection, mut control) = quic::connect(server).await?;
+    tunnel::register(&mut control, token).await?;
+
+    let request_id = format!("cli-{}", &Uuid::new_v4().to_string()[..8]);
+    let request = ControlMessage::RequestAccess {
+        request_id: request_id.clone(),
+        target_id: target.agent,
+        remote_host: tunnel_protocol::normalize_host(&target.remote_host).to_string(),
+        remote_port: target.remote_port,
+        reason,
+    };
+    request.validate()?;
+    control.send(&request).await?;
+
+    let result = loop {
+        match control.recv().await? {
+            ControlMessage::AccessUpdate {
+                request_id: id,
+                access_id,
+                state,
+                invite,
+                expires_at_ms,
+                code,
+                message,
+            } if id == request_id => {
+                let Some(access_id) = access_id else {
+                    break Err(format!(
+                        "Access request refused ({:?}): {}",
+                        code.unwrap_or(ErrorCode::Internal),
+                        message.unwrap_or_default()
+                    ));
+                };
+                println!(
+                    "{}",
+                    describe(&access_id, state, invite, expires_at_ms, message)
+                );
+                if !wait || state != AccessState::Pending {
+                    break Ok(());
+                }
+            }
+            ControlMessage::Error { message, .. } => break Err(message),
+            _ => {}
+        }
+    };
+    connection.close(0u32.into(), b"done");
+    result
+}
+
+/// Prints where the access request `access_id` stands.
+pub async fn status(server: &str, token: Option<String>, access_id: String) -> Result<(), String> {
+    let (connection, mut control) = quic::connect(server).await?;
+    tunnel::register(&mut control, token).await?;
+
+    let request_id = format!("cli-{}", &Uuid::new_v4().to_string()[..8]);
+    let status = ControlMessage::AccessStatus {
+        request_id: request_id.clone(),
+        access_id: access_id.clone(),
+    };
+    status.validate()?;
+    control.send(&status).await?;
+
+    let result = loop {
+        match control.recv().await? {
+            ControlMessage::AccessUpdate {
+                request_id: id,
+                state,
+                invite,
+                expires_at_ms,
+                code,
+                message,
+                ..
+            } if id == request_id => {
+                if code.is_some() {
+                    break Err(message.unwrap_or_default());
+                }
+                println!(
+                    "{}",
+                    describe(&access_id, state, invite, expires_at_ms, message)
+                );
+                break Ok(());
+            }
+            ControlMessage::Error { message, .. } => break Err(message),
+            _ => {}
+        }
+    };
+    connection.close(0u32.into(), b"done");
+    result
+}
+
+/// One line describing an `AccessUpdate`.
+fn describe(
+    access_id: &str,
+    state: AccessState,
+    invite: Option<String>,
+    expires_at_ms: u64,
+    message: Option<String>,
+) -> String {
+    match (state, invite) {
+        (AccessState::Pending, _) => format!("Access request {} is pending", access_id),
+        (AccessState::Approved, Some(invite)) => format!(
+            "Access approved for {} more min, invitation: {}",
+            expires_at_ms.saturating_sub(unix_time_ms()) / 60_000,
+            invite
+        ),
+        (state, _) => {
+            let outcome = match state {
+                AccessState::Denied => "denied",
+                AccessState::Expired => "expired",
+                _ => "approved",
+            };
+            format!("Access {}: {}", outcome, message.unwrap_or_default())
+        }
+    }
+}
@@ -0,0 +1,755 @@
+//! # Agent Mode
+//!
+//! A headless agent for machines nobody sits at, such as lab machines
+//! that must stay reachable with no user logged in. It runs as a systemd
+//! unit, a launchd daemon or a Windows task started at boot, where the
+//! desktop app cannot:
+//!
+//! ```text
+//! tunnel-cli agent [--name NAME] [--tags TAG,...] [--identity FILE]
+//!                  (--allow TARGET,... | --allow-any)
+//!                  [--services NAME=HOST:PORT,...]
+//! ```
+//!
+//! It registers with `TUNNEL_TOKEN` like the desktop agent. The agent ID
+//! comes from `TUNNEL_AGENT_ID` and `TUNNEL_AGENT_KEY` for an ID reserved
+//! in the server's `[[agent_keys]]`, or from the Ed25519 key in
+//! `--identity`, which is created on first use and has the same format as
+//! the desktop app's `identity.key`. Without either, the server assigns a
+//! random ID on every connection.
+//!
+//! There is nobody to approve tunnels, so the agent accepts a
+//! `TunnelRequest` the server lets through when `--allow` lists its targets
+//! (`host:port` or `unix:/path`); probes are answered the same way. Every
+//! target is allowed only with an explicit `--allow-any`, and one of the two
+//! is required, so a forgotten flag cannot open the whole network. Access
+//! requests are denied: approving one would let in a controller the
+//! server's ACL has not vetted. `--services` advertises named services,
+//! which controllers can open by name and which count as allowed. Reverse
+//! SOCKS tunnels are refused.
+//!
+//! The agent reconnects with backoff when the connection drops, unless
+//! the server closed it because an admin banned the agent: then it exits
+//! with [`EXIT_BANNED`], which the systemd unit does not restart.
+
+use crate::quic::{self, ControlSend};
+use crate::tunnel::{id_string, DATA_PREFIX_LEN};
+use crate::{take_flag, take_option};
+use quinn::{RecvStream, SendStream};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, Mutex, Notify};
+use tokio::task::AbortHandle;
+use tracing::{error, info, info_span, warn, Instrument};
+use tunnel_protocol::{
+    describe_target, generate_identity_key, identity_public_key, key_agent_id, register_proof,
+    sign_register, unpack_data_message, ControlMessage, ErrorCode, ServiceInfo, TrafficClass,
+    ECHO_HOST,
+};
+
+/// How often the agent pings the server, which records it as its heartbeat.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Exit status when the server banned the agent.
+pub const EXIT_BANNED: i32 = 3;
+
+/// Longest wait between reconnection attempts.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Target connect timeout when the controller set none.
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a data stream of a multi-port tunnel waits for the
+/// `StreamOpen` naming its port.
+const STREAM_PORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How the agent registers and what it serves, from the command line.
+#[derive(Debug)]
+pub struct Options {
+    name: Option<String>,
+    tags: Vec<String>,
+    identity: Option<PathBuf>,
+
+    /// Targets tunnels may reach, services included; `None` allows any.
+    allow: Option<Vec<String>>,
+    services: Vec<ServiceInfo>,
+}
+
+impl Options {
+    /// Takes the agent options out of `args`.
+    pub fn parse(args: &mut Vec<String>) -> Result<Self, String> {
+        let list = |value: Option<String>| -> Vec<String> {
+            value
+                .iter()
+                .flat_map(|v| v.split(','))
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        let name = take_option(args, "--name")?;
+        let tags = list(take_option(args, "--tags")?);
+        let identity = take_option(args, "--identity")?.map(PathBuf::from);
+        let mut allow = list(take_option(args, "--allow")?);
+        let allow_any = take_flag(args, "--allow-any");
+        let services: Vec<ServiceInfo> = list(take_option(args, "--services")?)
+            .iter()
+            .map(|spec| ServiceInfo::parse(spec))
+            .collect::<Result<_, _>>()?;
+        let allow = match allow_any {
+            true if !allow.is_empty() => {
+                return Err("--allow and --allow-any cannot be combined".to_string())
+            }
+            true => None,
+            false if allow.is_empty() && services.is_empty() => {
+                return Err(
+                    "The agent needs --allow TARGET,... or --allow-any to accept tunnels"
+                        .to_string(),
+                )
+            }
+            false => {
+                allow.extend(
+                    services
+                        .iter()
+                        .map(|s| describe_target(&s.host, s.port, None)),
+                );
+                Some(allow)
+            }
+        };
+        Ok(Self {
+            name,
+            tags,
+            identity,
+            allow,
+            services,
+        })
+    }
+}
+
+/// How the agent proves its ID when the server challenges it.
+enum Credential {
+    /// An ID reserved in `[[agent_keys]]`, with its pre-shared key.
+    Reserved { agent_id: String, key: String },
+    /// An ID derived from an Ed25519 key, in PKCS#8.
+    Identity { pkcs8: Vec<u8>, public_key: Vec<u8> },
+}
+
+/// Serves tunnels until the process is stopped, reconnecting to `server`
+/// whenever the connection is lost.
+pub async fn run(server: &str, token: Option<String>, options: Options) -> Result<(), String> {
+    let fixed = std::env::var("TUNNEL_AGENT_ID")
+        .ok()
+        .zip(std::env::var("TUNNEL_AGENT_KEY").ok());
+    let credential = match (fixed, &options.identity) {
+        (Some((agent_id, key)), _) => Some(Credential::Reserved { agent_id, key }),
+        (None, Some(path)) => Some(load_identity(path)?),
+        (None, None) => None,
+    };
+    let mut delay = Duration::from_secs(1);
+    loop {
+        let result = match quic::connect(server).await {
+            Ok((connection, control)) => {
+                let result = serve(
+                    &connection,
+                    control,
+                    token.clone(),
+                    &options,
+                    credential.as_ref(),
+                )
+                .await;
+                if let Some(reason) = quic::ban_reason(&connection) {
+                    error!("Banned by {}: {}", server, reason);
+                    std::process::exit(EXIT_BANNED);
+                }
+                result
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(reason) => {
+                warn!("Disconnected from {}: {}", server, reason);
+                delay = Duration::from_secs(1);
+            }
+            Err(e) => warn!("{}", e),
+        }
+        info!("Reconnecting in {}s", delay.as_secs());
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Loads the identity key at `path`, generating it on first use.
+fn load_identity(path: &Path) -> Result<Credential, String> {
+    let pkcs8 = match std::fs::read(path) {
+        Ok(pkcs8) => pkcs8,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let pkcs8 = generate_identity_key();
+            write_key(path, &pkcs8)?;
+            info!("Generated a new identity key at {}", path.display());
+            pkcs8
+        }
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+    let public_key = identity_public_key(&pkcs8)
+        .ok_or_else(|| format!("{} is not a valid identity key", path.display()))?;
+    Ok(Credential::Identity { pkcs8, public_key })
+}
+
+/// Writes `pkcs8` to `path`, readable by the owner only on Unix.
+fn write_key(path: &Path, pkcs8: &[u8]) -> Result<(), String> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    std::fs::write(path, pkcs8)
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Registers on `connection` and serves it until it is lost. Returns
+/// why it was lost, or `Err` if the agent never registered.
+async fn serve(
+    connection: &quinn::Connection,
+    mut control: quic::Control,
+    token: Option<String>,
+    options: &Options,
+    credential: Option<&Credential>,
+) -> Result<String, String> {
+    control
+        .send(&ControlMessage::Register {
+            token,
+            tags: options.tags.clone(),
+            name: options.name.clone(),
+            agent_id: match credential {
+                Some(Credential::Reserved { agent_id, .. }) => Some(agent_id.clone()),
+                _ => None,
+            },
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            services: options.services.clone(),
+            public_key: match credential {
+                Some(Credential::Identity { public_key, .. }) => Some(public_key.clone()),
+                _ => None,
+            },
+        })
+        .await?;
+    let agent_id = loop {
+        match control.recv().await? {
+            ControlMessage::RegisterChallenge { nonce } => {
+                let proof = match credential {
+                    Some(Credential::Reserved { agent_id, key }) => {
+                        register_proof(key, agent_id, &nonce)
+                    }
+                    Some(Credential::Identity { pkcs8, public_key }) => {
+                        sign_register(pkcs8, &key_agent_id(public_key), &nonce)
+                            .ok_or("Failed to sign the registration challenge")?
+                    }
+                    None => return Err("The server challenged an agent without a key".to_string()),
+                };
+                control
+                    .send(&ControlMessage::RegisterProof { proof })
+                    .await?;
+            }
+            ControlMessage::RegisterOk {
+                agent_id: Some(agent_id),
+                ..
+            } => break agent_id,
+            ControlMessage::RegisterOk { agent_id: None, .. } => {
+                return Err("The token may not register agents".to_string())
+            }
+            ControlMessage::Error { message, .. } => {
+                return Err(format!("Registration failed: {}", message))
+            }
+            _ => {}
+        }
+    };
+    info!("Registered as agent {}", agent_id);
+
+    let (send, mut recv) = control.split();
+    let (tx, rx) = mpsc::unbounded_channel();
+    let agent = Arc::new(Agent {
+        allow: options.allow.clone(),
+        tx: tx.clone(),
+        tunnels: Mutex::default(),
+        stream_ports: Mutex::default(),
+        port_named: Notify::new(),
+    });
+    let writer = tokio::spawn(write_messages(send, rx));
+    let heartbeat = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            interval.tick().await;
+            if tx.send(ControlMessage::Ping).is_err() {
+                break;
+            }
+        }
+    });
+    let streams = tokio::spawn(accept_streams(connection.clone(), agent.clone()));
+
+    let reason = loop {
+        match recv.recv().await {
+            Ok(msg) => agent.handle(msg).await,
+            Err(e) => break e,
+        }
+    };
+    writer.abort();
+    heartbeat.abort();
+    streams.abort();
+    for (_, tunnel) in agent.tunnels.lock().await.drain() {
+        tunnel.abort();
+    }
+    Ok(reason)
+}
+
+/// Sends the messages queued on `rx` until the connection or the queue closes.
+async fn write_messages(mut send: ControlSend, mut rx: mpsc::UnboundedReceiver<ControlMessage>) {
+    while let Some(msg) = rx.recv().await {
+        if send.send(&msg).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// An accepted tunnel and the streams it is relaying.
+struct AgentTunnel {
+    remote_host: String,
+    remote_port: u16,
+    remote_socket: Option<String>,
+    extra_ports: Vec<u16>,
+    connect_timeout: Duration,
+    traffic_class: TrafficClass,
+    streams: Vec<AbortHandle>,
+}
+
+impl AgentTunnel {
+    fn abort(self) {
+        for stream in self.streams {
+            stream.abort();
+        }
+    }
+}
+
+/// The state of one registered connection.
+struct Agent {
+    allow: Option<Vec<String>>,
+    tx: mpsc::UnboundedSender<ControlMessage>,
+    tunnels: Mutex<HashMap<String, AgentTunnel>>,
+
+    /// Ports named by `StreamOpen` for streams of multi-port tunnels,
+    /// keyed by session and stream ID.
+    stream_ports: Mutex<HashMap<(String, String), u16>>,
+    port_named: Notify,
+}
+
+impl Agent {
+    async fn handle(&self, msg: ControlMessage) {
+        match msg {
+            ControlMessage::TunnelRequest {
+                session_id,
+                remote_host,
+                remote_port,
+                remote_socket,
+                requester,
+                connect_timeout_ms,
+                extra_ports,
+                reverse_socks,
+                traffic_class,
+                ..
+            } => {
+                let target = describe_target(&remote_host, remote_port, remote_socket.as_deref());
+                let requester = requester.as_deref().unwrap_or("anonymous");
+                let refusal = if reverse_socks {
+                    Some("Reverse SOCKS is not supported by this agent".to_string())
+                } else {
+                    std::iter::once(remote_port)
+                        .chain(extra_ports.iter().copied())
+                        .map(|port| describe_target(&remote_host, port, remote_socket.as_deref()))
+                        .find(|t| !self.allows(t))
+                        .map(|t| format!("{} is not allowed on this agent", t))
+                };
+                if let Some(message) = refusal {
+                    warn!(%session_id, %requester, %target, "Refusing tunnel: {}", message);
+                    let _ = self.tx.send(ControlMessage::TunnelReject {
+                        session_id,
+                        code: ErrorCode::Unauthorized,
+                        message,
+                    });
+                    return;
+                }
+                info!(%session_id, %requester, %target, "Accepting tunnel");
+                self.tunnels.lock().await.insert(
+                    session_id.clone(),
+                    AgentTunnel {
+                        remote_host,
+                        remote_port,
+                        remote_socket,
+                        extra_ports,
+                        connect_timeout: connect_timeout_ms.map_or(DEFAULT_CONNECT_TIMEOUT, |ms| {
+                            Duration::from_millis(ms.into())
+                        }),
+                        traffic_class,
+                        streams: Vec::new(),
+                    },
+                );
+                let _ = self.tx.send(ControlMessage::TunnelAccept {
+                    session_id,
+                    max_streams: None,
+                });
+            }
+            ControlMessage::TunnelClose { session_id } => {
+                if let Some(tunnel) = self.tunnels.lock().await.remove(&session_id) {
+                    info!(%session_id, "Tunnel closed");
+                    tunnel.abort();
+                }
+                self.stream_ports
+                    .lock()
+                    .await
+                    .retain(|(session, _), _| *session != session_id);
+            }
+            ControlMessage::StreamOpen {
+                session_id,
+                stream_id,
+                remote_port: Some(port),
+            } => {
+                self.stream_ports
+                    .lock()
+                    .await
+                    .insert((session_id, stream_id), port);
+                self.port_named.notify_waiters();
+            }
+            ControlMessage::ProbeTarget {
+                request_id,
+                host,
+                port,
+                ..
+            } => {
+                let target = describe_target(&host, port, None);
+                if !self.allows(&target) {
+                    let _ = self.tx.send(ControlMessage::ProbeResult {
+                        request_id,
+                        latency_ms: None,
+                        code: Some(ErrorCode::Unauthorized),
+                        message: Some(format!("{} is not allowed on this agent", target)),
+                    });
+                    return;
+                }
+                let tx = self.tx.clone();
+                tokio::spawn(async move {
+                    let started = std::time::Instant::now();
+                    let dialed =
+                        tokio::time::timeout(DEFAULT_CONNECT_TIMEOUT, dial(&host, port, None));
+                    let (latency_ms, code, message) = match dialed.await {
+                        Ok(Ok(_)) => (Some(started.elapsed().as_millis() as u64), None, None),
+                        Ok(Err(e)) => (
+                            None,
+                            Some(ErrorCode::Internal),
+                            Some(format!("Failed to connect to {}: {}", target, e)),
+                        ),
+                        Err(_) => (
+                            None,
+                            Some(ErrorCode::Timeout),
+                            Some(format!("Timed out connecting to {}", target)),
+                        ),
+                    };
+                    let _ = tx.send(ControlMessage::ProbeResult {
+                        request_id,
+                        latency_ms,
+                        code,
+                        message,
+                    });
+                });
+            }
+            ControlMessage::AccessRequested {
+                access_id,
+                requester,
+                remote_host,
+                remote_port,
+                ..
+            } => {
+                let target = describe_target(&remote_host, remote_port, None);
+                let requester = requester.as_deref().unwrap_or("anonymous");
+                info!(%access_id, %requester, %target, "Denying access request");
+                let _ = self.tx.send(ControlMessage::DecideAccess {
+                    access_id,
+                    approve: false,
+                    ttl_secs: 0,
+                });
+            }
+            ControlMessage::Error { message, .. } => warn!("Server error: {}", message),
+            _ => {}
+        }
+    }
+
+    fn allows(&self, target: &str) -> bool {
+        self.allow
+            .as_ref()
+            .is_none_or(|allow| allow.iter().any(|a| a == target))
+    }
+
+    /// Waits for the `StreamOpen` naming the port of a multi-port stream.
+    async fn stream_port(&self, session_id: &str, stream_id: &str) -> Option<u16> {
+        let key = (session_id.to_string(), stream_id.to_string());
+        let deadline = tokio::time::Instant::now() + STREAM_PORT_TIMEOUT;
+        loop {
+            let named = self.port_named.notified();
+            tokio::pin!(named);
+            named.as_mut().enable();
+            if let Some(port) = self.stream_ports.lock().await.remove(&key) {
+                return Some(port);
+            }
+            tokio::time::timeout_at(deadline, named).await.ok()?;
+        }
+    }
+
+    fn stream_failed(&self, session_id: &str, stream_id: &str, code: ErrorCode, message: String) {
+        let _ = self.tx.send(ControlMessage::StreamOpenFailed {
+            session_id: session_id.to_string(),
+            stream_id: stream_id.to_string(),
+            code,
+            message,
+        });
+    }
+}
+
+/// Accepts the data streams the relay opens and links each to its
+/// tunnel's target.
+async fn accept_streams(connection: quinn::Connection, agent: Arc<Agent>) {
+    while let Ok((send, mut recv)) = connection.accept_bi().await {
+        let mut prefix = [0u8; DATA_PREFIX_LEN];
+        if recv.read_exact(&mut prefix).await.is_err() {
+            continue;
+        }
+        let Some((session, stream, _)) = unpack_data_message(&prefix) else {
+            warn!("Ignoring a data stream without a Data prefix");
+            continue;
+        };
+        let (session_id, stream_id) = (id_string(&session), id_string(&stream));
+        let mut tunnels = agent.tunnels.lock().await;
+        let Some(tunnel) = tunnels.get_mut(&session_id) else {
+            warn!(%session_id, %stream_id, "Data stream for unknown session");
+            continue;
+        };
+        let _ = send.set_priority(tunnel.traffic_class.stream_priority());
+        let span = info_span!("stream", session_id = %session_id, stream_id = %stream_id);
+        let task = tokio::spawn(
+            serve_stream(
+                agent.clone(),
+                session_id,
+                stream_id,
+                tunnel.remote_host.clone(),
+                tunnel.remote_port,
+                tunnel.remote_socket.clone(),
+                !tunnel.extra_ports.is_empty(),
+                tunnel.connect_timeout,
+                tokio::io::join(recv, send),
+            )
+            .instrument(span),
+        );
+        tunnel.streams.retain(|s| !s.is_finished());
+        tunnel.streams.push(task.abort_handle());
+    }
+}
+
+/// Dials the target of one data stream and relays between the two.
+#[allow(clippy::too_many_arguments)]
+async fn serve_stream(
+    agent: Arc<Agent>,
+    session_id: String,
+    stream_id: String,
+    host: String,
+    mut port: u16,
+    socket: Option<String>,
+    multi_port: bool,
+    connect_timeout: Duration,
+    mut quic: tokio::io::Join<RecvStream, SendStream>,
+) {
+    if multi_port {
+        match agent.stream_port(&session_id, &stream_id).await {
+            Some(named) => port = named,
+            None => {
+                warn!("Refusing stream: no StreamOpen named its port");
+                agent.stream_failed(
+                    &session_id,
+                    &stream_id,
+                    ErrorCode::Timeout,
+                    "No StreamOpen named the stream's port".to_string(),
+                );
+                return;
+            }
+        }
+    }
+    let target = describe_target(&host, port, socket.as_deref());
+    if socket.is_none() && host == ECHO_HOST {
+        let (mut recv, mut send) = quic.into_inner();
+        let _ = tokio::io::copy(&mut recv, &mut send).await;
+        let _ = send.finish();
+        return;
+    }
+    let dialed = tokio::time::timeout(connect_timeout, dial(&host, port, socket.as_deref())).await;
+    let mut local = match dialed {
+        Ok(Ok(local)) => local,
+        Ok(Err(e)) => {
+            warn!("Failed to connect to {}: {}", target, e);
+            let message = format!("Failed to connect to {}: {}", target, e);
+            agent.stream_failed(&session_id, &stream_id, ErrorCode::Internal, message);
+            return;
+        }
+        Err(_) => {
+            warn!("Timed out connecting to {}", target);
+            let message = format!("Timed out connecting to {}", target);
+            agent.stream_failed(&session_id, &stream_id, ErrorCode::Timeout, message);
+            return;
+        }
+    };
+    info!(%target, "Linked stream to local target");
+    match tokio::io::copy_bidirectional(&mut local, &mut quic).await {
+        Ok((from_target, to_target)) => info!(to_target, from_target, "Stream finished"),
+        Err(e) => info!("Stream ended: {}", e),
+    }
+}
+
+/// A connection to a local target, over TCP or a Unix socket.
+trait LocalStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> LocalStream for T {}
+
+async fn dial(
+    host: &str,
+    port: u16,
+    socket: Option<&str>,
+) -> std::io::Result<Box<dyn LocalStream>> {
+    match socket {
+        #[cfg(unix)]
+        Some(path) => Ok(Box::new(tokio::net::UnixStream::connect(path).await?)),
+        #[cfg(not(unix))]
+        Some(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Unix sockets are not supported on this platform",
+        )),
+        None => Ok(Box::new(
+            tokio::net::TcpStream::connect((host, port)).await?,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        let mut args = args.iter().map(|a| a.to_string()).collect();
+        Options::parse(&mut args)
+    }
+
+    fn agent(allow: Option<Vec<String>>) -> (Agent, mpsc::UnboundedReceiver<ControlMessage>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let agent = Agent {
+            allow,
+            tx,
+            tunnels: Mutex::default(),
+            stream_ports: Mutex::default(),
+            port_named: Notify::new(),
+        };
+        (agent, rx)
+    }
+
+    fn request(remote_port: u16, extra_ports: Vec<u16>) -> ControlMessage {
+        ControlMessage::TunnelRequest {
+            session_id: "s1".to_string(),
+            remote_host: "127.0.0.1".to_string(),
+            remote_port,
+            remote_socket: None,
+            requester: Some("alice".to_string()),
+            pairing_token: None,
+            connect_timeout_ms: None,
+            extra_ports,
+            invited: false,
+            ttl_secs: None,
+            reverse_socks: false,
+            traffic_class: TrafficClass::Interactive,
+        }
+    }
+
+    #[test]
+    fn options_need_an_allow_list_or_allow_any() {
+        let options = parse(&[
+            "agent",
+            "--name",
+            "lab-07",
+            "--allow",
+            "127.0.0.1:22, unix:/run/x",
+        ])
+        .unwrap();
+        assert_eq!(options.name.as_deref(), Some("lab-07"));
+        assert_eq!(
+            options.allow,
+            Some(vec!["127.0.0.1:22".to_string(), "unix:/run/x".to_string()])
+        );
+
+        let options = parse(&[
+            "agent",
+            "--allow=127.0.0.1:22",
+            "--services",
+            "web=127.0.0.1:80",
+        ])
+        .unwrap();
+        assert_eq!(
+            options.allow,
+            Some(vec!["127.0.0.1:22".to_string(), "127.0.0.1:80".to_string()])
+        );
+        assert_eq!(options.services[0].name, "web");
+
+        let options = parse(&["agent", "--services", "ssh=127.0.0.1:22"]).unwrap();
+        assert_eq!(options.allow, Some(vec!["127.0.0.1:22".to_string()]));
+        assert_eq!(parse(&["agent", "--allow-any"]).unwrap().allow, None);
+
+        assert!(parse(&["agent"]).is_err());
+        assert!(parse(&["agent", "--allow", ""]).is_err());
+        assert!(parse(&["agent", "--allow", "127.0.0.1:22", "--allow-any"]).is_err());
+        assert!(parse(&["agent", "--allow-any", "--services", "bad"]).is_err());
+    }
+
+    #[test]
+    fn allows_only_listed_targets() {
+        let (listed, _rx) = agent(Some(vec!["127.0.0.1:22".to_string()]));
+        assert!(listed.allows("127.0.0.1:22"));
+        assert!(!listed.allows("127.0.0.1:23"));
+        assert!(!listed.allows("localhost:22"));
+
+        let (any, _rx) = agent(None);
+        assert!(any.allows("10.0.0.1:3389"));
+    }
+
+    #[tokio::test]
+    async fn refused_tunnels_are_rejected() {
+        let (agent, mut rx) = agent(Some(vec!["127.0.0.1:22".to_string()]));
+        agent.handle(request(22, vec![23])).await;
+        match rx.try_recv().unwrap() {
+            ControlMessage::TunnelReject {
+                session_id,
+                code,
+                message,
+            } => {
+                assert_eq!(session_id, "s1");
+                assert_eq!(code, ErrorCode::Unauthorized);
+                assert!(message.contains("127.0.0.1:23"));
+            }
+            other => panic!("unexpected reply {:?}", other),
+        }
+        assert!(agent.tunnels.lock().await.is_empty());
+
+        agent.handle(request(22, Vec::new())).await;
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            ControlMessage::TunnelAccept { .. }
+        ));
+        assert!(agent.tunnels.lock().await.contains_key("s1"));
+    }
+}
@@ -0,0 +1,280 @@
+//! # Bench Mode
+//!
+//! Pushes synthetic traffic through a relay to measure the relay path:
+//!
+//! ```text
+//! tunnel-cli bench [--agents N] [--controllers N] [--streams N]
+//!                  [--size BYTES] [--duration SECS] [--agent ID]
+//! ```
+//!
+//! Each synthetic agent registers like a desktop agent, accepts every
+//! tunnel and echoes every data stream back. Controllers are spread over
+//! the agents; each opens one tunnel with `--streams` data streams, and
+//! each stream repeatedly sends a `--size` chunk and waits for its echo
+//! until `--duration` is up. With `--agent` the controllers target that
+//! existing agent's `@echo` service instead, and no synthetic agents start.
+//!
+//! The report gives the chunk round trips completed, the throughput in
+//! each direction, and round-trip latency percentiles.
+
+use crate::quic::{self, Control};
+use crate::take_option;
+use crate::tunnel::{Target, Tunnel, DATA_PREFIX_LEN};
+use quinn::{RecvStream, SendStream};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use tunnel_protocol::{ControlMessage, TrafficClass, ECHO_HOST};
+
+/// What to run, from the command line.
+pub struct Options {
+    agents: usize,
+    controllers: usize,
+    streams: usize,
+    size: usize,
+    duration: Duration,
+    agent: Option<String>,
+}
+
+impl Options {
+    /// Takes the bench options out of `args`, using defaults for the rest.
+    pub fn parse(args: &mut Vec<String>) -> Result<Self, String> {
+        let number =
+            |args: &mut Vec<String>, name: &str, default: usize| match take_option(args, name)? {
+                Some(v) => v
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| format!("{} must be a positive number", name)),
+                None => Ok(default),
+            };
+        Ok(Self {
+            agents: number(args, "--agents", 1)?,
+            controllers: number(args, "--controllers", 4)?,
+            streams: number(args, "--streams", 1)?,
+            size: number(args, "--size", 16 * 1024)?,
+            duration: Duration::from_secs(number(args, "--duration", 10)? as u64),
+            agent: take_option(args, "--agent")?,
+        })
+    }
+}
+
+/// Round trips completed by one or more streams.
+#[derive(Default)]
+struct Sample {
+    bytes: u64,
+    latencies: Vec<Duration>,
+}
+
+impl Sample {
+    fn merge(&mut self, other: Sample) {
+        self.bytes += other.bytes;
+        self.latencies.extend(other.latencies);
+    }
+}
+
+/// Starts the agents and controllers, waits for the traffic to finish and
+/// prints the report.
+pub async fn run(server: &str, token: Option<String>, options: Options) -> Result<(), String> {
+    let agents = match &options.agent {
+        Some(agent) => vec![agent.clone()],
+        None => {
+            let mut agents = Vec::with_capacity(options.agents);
+            for index in 0..options.agents {
+                agents.push(start_agent(server, token.clone(), index).await?);
+            }
+            info!("Started {} synthetic agent(s)", agents.len());
+            agents
+        }
+    };
+
+    let started = Instant::now();
+    let deadline = started + options.duration;
+    let controllers: Vec<_> = (0..options.controllers)
+        .map(|i| {
+            let target = Target {
+                agent: agents[i % agents.len()].clone(),
+                remote_host: ECHO_HOST.to_string(),
+                remote_port: 7,
+                service: None,
+                invite: None,
+                traffic_class: TrafficClass::default(),
+            };
+            tokio::spawn(run_controller(
+                server.to_string(),
+                token.clone(),
+                target,
+                options.streams,
+                options.size,
+                deadline,
+            ))
+        })
+        .collect();
+
+    let mut total = Sample::default();
+    let mut failed = 0;
+    for controller in controllers {
+        match controller.await.map_err(|e| e.to_string()).and_then(|r| r) {
+            Ok(sample) => total.merge(sample),
+            Err(e) => {
+                warn!("Controller failed: {}", e);
+                failed += 1;
+            }
+        }
+    }
+    if failed == options.controllers {
+        return Err("Every controller failed; run with RUST_LOG=warn for details".to_string());
+    }
+    report(&options, agents.len(), failed, started.elapsed(), total);
+    Ok(())
+}
+
+/// Registers a synthetic agent and serves its tunnels in the background.
+/// Returns its agent ID.
+async fn start_agent(server: &str, token: Option<String>, index: usize) -> Result<String, String> {
+    let (connection, mut control) = quic::connect(server).await?;
+    control
+        .send(&ControlMessage::Register {
+            token,
+            tags: vec!["bench".to_string()],
+            name: Some(format!("bench-{}", index)),
+            agent_id: None,
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            services: Vec::new(),
+            public_key: None,
+        })
+        .await?;
+    let agent_id = loop {
+        match control.recv().await? {
+            ControlMessage::RegisterOk {
+                agent_id: Some(agent_id),
+                ..
+            } => break agent_id,
+            ControlMessage::RegisterOk { agent_id: None, .. } => {
+                return Err("The token may not register agents".to_string())
+            }
+            ControlMessage::Error { message, .. } => {
+                return Err(format!("Registration failed: {}", message))
+            }
+            _ => {}
+        }
+    };
+    tokio::spawn(accept_tunnels(control));
+    tokio::spawn(echo_streams(connection));
+    Ok(agent_id)
+}
+
+/// Accepts every tunnel offered to a synthetic agent.
+async fn accept_tunnels(mut control: Control) {
+    while let Ok(msg) = control.recv().await {
+        if let ControlMessage::TunnelRequest { session_id, .. } = msg {
+            if control
+                .send(&ControlMessage::TunnelAccept {
+                    session_id,
+                    max_streams: None,
+                })
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    }
+}
+
+/// Echoes every data stream the relay opens to a synthetic agent.
+async fn echo_streams(connection: quinn::Connection) {
+    while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+        tokio::spawn(async move {
+            let mut prefix = [0u8; DATA_PREFIX_LEN];
+            if recv.read_exact(&mut prefix).await.is_err() {
+                return;
+            }
+            let _ = tokio::io::copy(&mut recv, &mut send).await;
+            let _ = send.finish();
+        });
+    }
+}
+
+/// Opens a tunnel to `target` and pumps chunks through `streams` data
+/// streams until `deadline`.
+async fn run_controller(
+    server: String,
+    token: Option<String>,
+    target: Target,
+    streams: usize,
+    size: usize,
+    deadline: Instant,
+) -> Result<Sample, String> {
+    let mut tunnel = Tunnel::open(&server, token, target).await?;
+    let mut pumps = Vec::with_capacity(streams);
+    for _ in 0..streams {
+        let (send, recv) = tunnel.open_stream().await?;
+        pumps.push(tokio::spawn(pump(send, recv, size, deadline)));
+    }
+    let mut sample = Sample::default();
+    for pump in pumps {
+        sample.merge(pump.await.map_err(|e| e.to_string())??);
+    }
+    tunnel.close().await;
+    Ok(sample)
+}
+
+/// Sends a chunk and waits for its echo, over and over until `deadline`.
+async fn pump(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    size: usize,
+    deadline: Instant,
+) -> Result<Sample, String> {
+    let chunk = vec![0x5a; size];
+    let mut echo = vec![0u8; size];
+    let mut sample = Sample::default();
+    while Instant::now() < deadline {
+        let start = Instant::now();
+        send.write_all(&chunk)
+            .await
+            .map_err(|e| format!("Send failed: {}", e))?;
+        recv.read_exact(&mut echo)
+            .await
+            .map_err(|e| format!("Echo failed: {}", e))?;
+        sample.latencies.push(start.elapsed());
+        sample.bytes += size as u64;
+    }
+    let _ = send.finish();
+    Ok(sample)
+}
+
+fn report(options: &Options, agents: usize, failed: usize, elapsed: Duration, mut total: Sample) {
+    total.latencies.sort();
+    let secs = elapsed.as_secs_f64();
+    let ms = |p: f64| percentile(&total.latencies, p).as_secs_f64() * 1000.0;
+    println!(
+        "agents {}, controllers {} ({} failed), {} stream(s) each, {} B chunks, {:.1} s",
+        agents, options.controllers, failed, options.streams, options.size, secs
+    );
+    println!(
+        "round trips  {} ({:.0}/s)",
+        total.latencies.len(),
+        total.latencies.len() as f64 / secs
+    );
+    println!(
+        "throughput   {:.2} MiB/s each way",
+        total.bytes as f64 / secs / (1024.0 * 1024.0)
+    );
+    println!(
+        "latency      p50 {:.2} ms, p90 {:.2} ms, p99 {:.2} ms, max {:.2} ms",
+        ms(0.50),
+        ms(0.90),
+        ms(0.99),
+        ms(1.0)
+    );
+}
+
+/// The `p` quantile (0 to 1) of the ascending `sorted`, or zero when empty.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[rank.min(sorted.len() - 1)]
+}
@@ -0,0 +1,235 @@
+//! # Tunnel CLI
+//!
+//! A headless controller for the tunnel relay, for scripts and tools that
+//! cannot use the desktop app, and a headless agent for machines without
+//! a desktop session.
+//!
+//! ```text
+//! tunnel-cli [--server HOST:PORT] stdio [--invite TOKEN] [--class CLASS] <AGENT> <HOST> <PORT>
+//! tunnel-cli [--server HOST:PORT] stdio [--class CLASS] <AGENT> <SERVICE>
+//! tunnel-cli [--server HOST:PORT] probe <AGENT> <HOST> <PORT>
+//! tunnel-cli [--server HOST:PORT] request-access [--reason TEXT] [--wait] <AGENT> <HOST> <PORT>
+//! tunnel-cli [--server HOST:PORT] access-status <ACCESS_ID>
+//! tunnel-cli [--server HOST:PORT] bench [OPTIONS]
+//! tunnel-cli [--server HOST:PORT] agent [OPTIONS]
+//! ```
+//!
+//! The server defaults to `TUNNEL_SERVER` or `127.0.0.1:7070`. Like the
+//! desktop client, it registers with `TUNNEL_TOKEN` when set and verifies
+//! the server against `TUNNEL_CA_CERT`. `--invite` redeems an invitation
+//! the agent created, for a controller without access of its own.
+//! `--class bulk` sends the tunnel's data behind that of interactive
+//! tunnels, e.g. for `scp` next to an SSH shell. Given a service name
+//! instead of a host and port, `stdio` opens the service the agent
+//! advertises under that name. `request-access` asks the agent's owner
+//! for access instead, answered later with an invitation for `--invite`.
+//!
+//! ## Modules
+//!
+//! - [`quic`]   — QUIC connection and framed control stream
+//! - [`tunnel`] — Opening tunnels and their data streams
+//! - [`stdio`]  — Single-stream relay over stdin/stdout (SSH `ProxyCommand`)
+//! - [`access`] — Access requests the agent's owner decides later
+//! - [`bench`]  — Synthetic agents and controllers measuring a relay
+//! - [`agent`]  — Headless agent for services and daemons
+//! - [`cert`]   — Certificate verifier for dev mode
+
+mod access;
+mod agent;
+mod bench;
+mod quic;
+mod stdio;
+mod tunnel;
+
+use tracing_subscriber::EnvFilter;
+use tunnel::Target;
+
+/// Relay server used when neither `--server` nor `TUNNEL_SERVER` is given.
+const DEFAULT_SERVER: &str = "127.0.0.1:7070";
+
+const USAGE: &str =
+    "Usage: tunnel-cli [--server HOST:PORT] stdio [--invite TOKEN] [--class interactive|bulk]
+                  <AGENT> <HOST> <PORT>
+       tunnel-cli [--server HOST:PORT] stdio [--class interactive|bulk] <AGENT> <SERVICE>
+       tunnel-cli [--server HOST:PORT] probe <AGENT> <HOST> <PORT>
+       tunnel-cli [--server HOST:PORT] request-access [--reason TEXT] [--wait]
+                  <AGENT> <HOST> <PORT>
+       tunnel-cli [--server HOST:PORT] access-status <ACCESS_ID>
+       tunnel-cli [--server HOST:PORT] bench [--agents N] [--controllers N] [--streams N]
+                  [--size BYTES] [--duration SECS] [--agent ID]
+       tunnel-cli [--server HOST:PORT] agent [--name NAME] [--tags TAG,...]
+                  [--identity FILE] (--allow TARGET,... | --allow-any)
+                  [--services NAME=HOST:PORT,...]";
+
+#[tokio::main]
+async fn main() {
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    // stdout may carry tunnel data, so logs always go to stderr.
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn")),
+        )
+        .init();
+
+    let code = match run(std::env::args().skip(1).collect()).await {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("tunnel-cli: {}", e);
+            1
+        }
+    };
+    // Exit right away: a blocking read on stdin would otherwise keep the
+    // runtime from shutting down.
+    std::process::exit(code);
+}
+
+async fn run(mut args: Vec<String>) -> Result<(), String> {
+    let mut server = std::env::var("TUNNEL_SERVER").unwrap_or_else(|_| DEFAULT_SERVER.into());
+    if let Some(value) = take_option(&mut args, "--server")? {
+        server = value;
+    }
+    let token = std::env::var("TUNNEL_TOKEN").ok();
+    let invite = take_option(&mut args, "--invite")?;
+    let traffic_class = take_option(&mut args, "--class")?
+        .map(|class| class.parse())
+        .transpose()?
+        .unwrap_or_default();
+
+    match args.first().map(String::as_str) {
+        Some("stdio") if args.len() == 3 => {
+            if invite.is_some() {
+                return Err("--invite needs a host and port, not a service".to_string());
+            }
+            let target = Target {
+                agent: args[1].clone(),
+                remote_host: String::new(),
+                remote_port: 0,
+                service: Some(args[2].clone()),
+                invite,
+                traffic_class,
+            };
+            stdio::run(&server, token, target).await
+        }
+        Some(command @ ("stdio" | "probe")) => {
+            let [_, agent, host, port] = args.as_slice() else {
+                return Err(USAGE.to_string());
+            };
+            let target = Target {
+                agent: agent.clone(),
+                remote_host: host.clone(),
+                remote_port: port
+                    .parse()
+                    .map_err(|_| format!("Invalid port: {}", port))?,
+                service: None,
+                invite,
+                traffic_class,
+            };
+            if command == "stdio" {
+                return stdio::run(&server, token, target).await;
+            }
+            let latency_ms = tunnel::probe(&server, token, target).await?;
+            println!("{}:{} is reachable ({} ms)", host, port, latency_ms);
+            Ok(())
+        }
+        Some("request-access") => {
+            let reason = take_option(&mut args, "--reason")?;
+            let wait = take_flag(&mut args, "--wait");
+            let [_, agent, host, port] = args.as_slice() else {
+                return Err(USAGE.to_string());
+            };
+            let target = Target {
+                agent: agent.clone(),
+                remote_host: host.clone(),
+                remote_port: port
+                    .parse()
+                    .map_err(|_| format!("Invalid port: {}", port))?,
+                service: None,
+                invite: None,
+                traffic_class,
+            };
+            access::request(&server, token, target, reason, wait).await
+        }
+        Some("access-status") => {
+            let [_, access_id] = args.as_slice() else {
+                return Err(USAGE.to_string());
+            };
+            access::status(&server, token, access_id.clone()).await
+        }
+        Some("bench") => {
+            let options = bench::Options::parse(&mut args)?;
+            if args.len() != 1 {
+                return Err(USAGE.to_string());
+            }
+            bench::run(&server, token, options).await
+        }
+        Some("agent") => {
+            let options = agent::Options::parse(&mut args)?;
+            if args.len() != 1 {
+                return Err(USAGE.to_string());
+            }
+            agent::run(&server, token, options).await
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+/// Removes `--name <value>` or `--name=<value>` from `args` and returns the value.
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>, String> {
+    let Some(pos) = args
+        .iter()
+        .position(|a| a == name || a.starts_with(&format!("{}=", name)))
+    else {
+        return Ok(None);
+    };
+    let arg = args.remove(pos);
+    match arg.split_once('=') {
+        Some((_, value)) => Ok(Some(value.to_string())),
+        None if pos < args.len() => Ok(Some(args.remove(pos))),
+        None => Err(format!("{} needs a value", name)),
+    }
+}
+
+/// Removes the flag `name` from `args`, returning whether it was given.
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    let given = args.iter().any(|a| a == name);
+    args.retain(|a| a != name);
+    given
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn options_are_taken_out_of_the_arguments() {
+        let mut list = args(&["agent", "--name", "lab", "--tags=a,b", "--allow-any"]);
+        assert_eq!(
+            take_option(&mut list, "--name").unwrap().as_deref(),
+            Some("lab")
+        );
+        assert_eq!(
+            take_option(&mut list, "--tags").unwrap().as_deref(),
+            Some("a,b")
+        );
+        assert_eq!(take_option(&mut list, "--identity").unwrap(), None);
+        assert!(take_flag(&mut list, "--allow-any"));
+        assert!(!take_flag(&mut list, "--allow-any"));
+        assert_eq!(list, args(&["agent"]));
+
+        let mut list = args(&["--name=", "--tagsx", "1"]);
+        assert_eq!(
+            take_option(&mut list, "--name").unwrap().as_deref(),
+            Some("")
+        );
+        assert_eq!(take_option(&mut list, "--tags").unwrap(), None);
+
+        let mut list = args(&["agent", "--name"]);
+        assert!(take_option(&mut list, "--name").is_err());
+    }
+}
@@ -0,0 +1,156 @@
+//! # Relay Connection
+//!
+//! Opens the QUIC connection to the relay server and its control stream.
+//! TLS follows the desktop client: the server certificate is verified
+//! against `TUNNEL_CA_CERT` when set, and not verified otherwise (dev mode).
+
+use quinn::{Endpoint, RecvStream, SendStream};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{debug, info};
+use tunnel_protocol::tls::SkipServerVerification;
+use tunnel_protocol::{ControlMessage, CLOSE_BANNED, CONTROL_STREAM_PRIORITY, MAX_CONTROL_FRAME};
+
+/// Keeps the connection alive while a tunnel carries no traffic.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// The length-prefixed control stream: `[4-byte LE len][tag][bincode]`.
+pub struct Control {
+    send: ControlSend,
+    recv: ControlRecv,
+}
+
+impl Control {
+    /// Sends one control message.
+    pub async fn send(&mut self, msg: &ControlMessage) -> Result<(), String> {
+        self.send.send(msg).await
+    }
+
+    /// Waits for the next control message from the server.
+    pub async fn recv(&mut self) -> Result<ControlMessage, String> {
+        self.recv.recv().await
+    }
+
+    /// Splits the stream so one task can send while another waits for
+    /// the next message.
+    pub fn split(self) -> (ControlSend, ControlRecv) {
+        (self.send, self.recv)
+    }
+}
+
+/// The sending half of a [`Control`] stream.
+pub struct ControlSend(SendStream);
+
+impl ControlSend {
+    /// Sends one control message.
+    pub async fn send(&mut self, msg: &ControlMessage) -> Result<(), String> {
+        let bytes = msg.serialize().map_err(|e| e.to_string())?;
+        self.0
+            .write_u32_le(bytes.len() as u32)
+            .await
+            .map_err(|e| e.to_string())?;
+        self.0.write_all(&bytes).await.map_err(|e| e.to_string())
+    }
+}
+
+/// The receiving half of a [`Control`] stream.
+pub struct ControlRecv(RecvStream);
+
+impl ControlRecv {
+    /// Waits for the next control message from the server.
+    pub async fn recv(&mut self) -> Result<ControlMessage, String> {
+        let len = self
+            .0
+            .read_u32_le()
+            .await
+            .map_err(|_| "Connection to the server closed".to_string())? as usize;
+        if len > MAX_CONTROL_FRAME {
+            return Err(format!("Control frame too large: {}", len));
+        }
+        let mut buf = vec![0u8; len];
+        self.0
+            .read_exact(&mut buf)
+            .await
+            .map_err(|_| "Connection to the server closed".to_string())?;
+        ControlMessage::deserialize(&buf)
+    }
+}
+
+/// Connects to the relay at `server` (`host:port`) and opens the control stream.
+pub async fn connect(server: &str) -> Result<(quinn::Connection, Control), String> {
+    let addr: SocketAddr = tokio::net::lookup_host(server)
+        .await
+        .map_err(|e| format!("Invalid server address {}: {}", server, e))?
+        .next()
+        .ok_or_else(|| format!("No address for {}", server))?;
+
+    let mut endpoint = Endpoint::client("[::]:0".parse().unwrap()).map_err(|e| e.to_string())?;
+    endpoint.set_default_client_config(client_config()?);
+
+    let connection = endpoint
+        .connect(addr, "localhost")
+        .map_err(|e| e.to_string())?
+        .await
+        .map_err(|e| format!("Connection to {} failed: {}", addr, e))?;
+    info!("Connected to {}", addr);
+
+    let (send, recv) = connection.open_bi().await.map_err(|e| e.to_string())?;
+    let _ = send.set_priority(CONTROL_STREAM_PRIORITY);
+    Ok((
+        connection,
+        Control {
+            send: ControlSend(send),
+            recv: ControlRecv(recv),
+        },
+    ))
+}
+
+/// Returns the reason the server gave if it closed `connection` because
+/// an admin banned this client.
+pub fn ban_reason(connection: &quinn::Connection) -> Option<String> {
+    match connection.close_reason()? {
+        quinn::ConnectionError::ApplicationClosed(close)
+            if close.error_code == quinn::VarInt::from_u32(CLOSE_BANNED) =>
+        {
+            Some(String::from_utf8_lossy(&close.reason).into_owned())
+        }
+        _ => None,
+    }
+}
+
+fn client_config() -> Result<quinn::ClientConfig, String> {
+    let mut crypto = match std::env::var("TUNNEL_CA_CERT") {
+        Ok(path) => {
+            let pem = std::fs::read(&path)
+                .map_err(|e| format!("Failed to read CA certificate {}: {}", path, e))?;
+            let mut roots = rustls::RootCertStore::empty();
+            let mut reader = &pem[..];
+            let certs = rustls_pemfile::certs(&mut reader).filter_map(Result::ok);
+            let (added, _) = roots.add_parsable_certificates(certs);
+            if added == 0 {
+                return Err(format!("No CA certificate found in {}", path));
+            }
+            rustls::ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth()
+        }
+        Err(_) => {
+            debug!("No custom CA provided, skipping server verification (dev mode)");
+            rustls::ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(SkipServerVerification::new())
+                .with_no_client_auth()
+        }
+    };
+    crypto.alpn_protocols = vec![b"tunnel".to_vec()];
+
+    let quic_crypto =
+        quinn::crypto::rustls::QuicClientConfig::try_from(crypto).map_err(|e| e.to_string())?;
+    let mut config = quinn::ClientConfig::new(Arc::new(quic_crypto));
+    let mut transport = quinn::TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE));
+    config.transport_config(Arc::new(transport));
+    Ok(config)
+}
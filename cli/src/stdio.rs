@@ -0,0 +1,46 @@
+//! # Stdio Mode
+//!
+//! Relays a single stream between stdin/stdout and a tunnel, so the CLI can
+//! stand in for a TCP connection without a local port, e.g. in
+//! `~/.ssh/config`:
+//!
+//! ```text
+//! Host office-pc
+//!     ProxyCommand tunnel-cli stdio A3F8-B2C1 127.0.0.1 22
+//! ```
+//!
+//! stdout carries the stream, so all logging goes to stderr.
+
+use crate::tunnel::{Target, Tunnel};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tracing::debug;
+
+/// Opens a tunnel to `target` and relays one stream over stdio until either
+/// side closes it.
+pub async fn run(server: &str, token: Option<String>, target: Target) -> Result<(), String> {
+    let mut tunnel = Tunnel::open(server, token, target).await?;
+    let (mut send, mut recv) = tunnel.open_stream().await?;
+
+    let upload = tokio::spawn(async move {
+        let mut stdin = tokio::io::stdin();
+        let _ = tokio::io::copy(&mut stdin, &mut send).await;
+        let _ = send.finish();
+    });
+    let download = async {
+        let mut stdout = BufWriter::new(tokio::io::stdout());
+        let received = tokio::io::copy(&mut recv, &mut stdout).await;
+        let _ = stdout.flush().await;
+        received.map(|_| ()).map_err(|e| e.to_string())
+    };
+
+    // The stream is over once the agent stops sending, whether or not stdin
+    // has reached EOF (ssh keeps stdin open after the server hangs up).
+    let result = tokio::select! {
+        received = download => received,
+        closed = tunnel.closed() => closed,
+    };
+    upload.abort();
+    debug!("Stdio relay finished: {:?}", result);
+    tunnel.close().await;
+    result
+}
@@ -0,0 +1,248 @@
+//! # Tunnel Sessions
+//!
+//! Opens a tunnel to an agent: `RegisterController` with the optional
+//! token, `Connect`, then wait for `TunnelReady`. Each data stream is a QUIC bi-stream that starts with the
+//! 17-byte `Data` prefix naming the session and stream. [`probe`] asks the
+//! agent to try the target instead, without opening a tunnel.
+
+use crate::quic::{self, Control};
+use quinn::{RecvStream, SendStream};
+use tracing::info;
+use tunnel_protocol::{
+    describe_target, pack_data_message, ControlMessage, ErrorCode, TrafficClass,
+};
+use uuid::Uuid;
+
+/// Length of the `Data` prefix that starts every data stream.
+pub const DATA_PREFIX_LEN: usize = 17;
+
+/// Where a tunnel leads.
+pub struct Target {
+    /// Agent ID or registered agent name.
+    pub agent: String,
+    pub remote_host: String,
+    pub remote_port: u16,
+    /// Service the agent advertises, used in place of the host and port.
+    pub service: Option<String>,
+    /// Invitation token from the agent, used in place of access rights.
+    pub invite: Option<String>,
+    /// Scheduling class of the tunnel's data streams.
+    pub traffic_class: TrafficClass,
+}
+
+/// An established tunnel session.
+pub struct Tunnel {
+    connection: quinn::Connection,
+    control: Control,
+    session_id: String,
+    traffic_class: TrafficClass,
+}
+
+impl Tunnel {
+    /// Connects to `server` and opens a tunnel to `target`.
+    ///
+    /// The client registers as a controller first, so the server's access
+    /// rules see the identity of `token`, or an anonymous client without one.
+    pub async fn open(server: &str, token: Option<String>, target: Target) -> Result<Self, String> {
+        let (connection, mut control) = quic::connect(server).await?;
+        register(&mut control, token).await?;
+
+        let request_id = format!("cli-{}", &Uuid::new_v4().to_string()[..8]);
+        let connect = match &target.service {
+            Some(service_name) => ControlMessage::ConnectService {
+                request_id: request_id.clone(),
+                target_id: target.agent.clone(),
+                service_name: service_name.clone(),
+                traffic_class: target.traffic_class,
+            },
+            None => ControlMessage::Connect {
+                target_id: target.agent.clone(),
+                remote_host: tunnel_protocol::normalize_host(&target.remote_host).to_string(),
+                remote_port: target.remote_port,
+                request_id: request_id.clone(),
+                remote_socket: None,
+                pairing_token: None,
+                connect_timeout_ms: None,
+                requester: None,
+                extra_ports: Vec::new(),
+                invite: target.invite.clone(),
+                ttl_secs: None,
+                reverse_socks: false,
+                traffic_class: target.traffic_class,
+            },
+        };
+        connect.validate()?;
+        control.send(&connect).await?;
+
+        let session_id = loop {
+            match control.recv().await? {
+                ControlMessage::TunnelReady {
+                    session_id,
+                    request_id: id,
+                    ..
+                } if id == request_id => break session_id,
+                ControlMessage::ConnectFailed {
+                    request_id: id,
+                    code,
+                    message,
+                } if id == request_id => {
+                    return Err(format!("Connect failed ({:?}): {}", code, message))
+                }
+                ControlMessage::Error { message, .. } => {
+                    return Err(format!("Server error: {}", message))
+                }
+                _ => {}
+            }
+        };
+        let remote = match &target.service {
+            Some(service_name) => format!("service {}", service_name),
+            None => describe_target(&target.remote_host, target.remote_port, None),
+        };
+        info!(%session_id, "Tunnel to {} ready ({})", target.agent, remote);
+
+        Ok(Self {
+            connection,
+            control,
+            session_id,
+            traffic_class: target.traffic_class,
+        })
+    }
+
+    /// Opens a data stream to the agent's target.
+    pub async fn open_stream(&mut self) -> Result<(SendStream, RecvStream), String> {
+        let stream_id = Uuid::new_v4().to_string()[..8].to_string();
+        let (mut send, recv) = self
+            .connection
+            .open_bi()
+            .await
+            .map_err(|e| format!("Failed to open data stream: {}", e))?;
+        let _ = send.set_priority(self.traffic_class.stream_priority());
+
+        self.control
+            .send(&ControlMessage::StreamOpen {
+                session_id: self.session_id.clone(),
+                stream_id: stream_id.clone(),
+                remote_port: None,
+            })
+            .await?;
+        let prefix = pack_data_message(id_bytes(&self.session_id), id_bytes(&stream_id), &[]);
+        send.write_all(&prefix)
+            .await
+            .map_err(|e| format!("Failed to open data stream: {}", e))?;
+        Ok((send, recv))
+    }
+
+    /// Waits until the server or the agent closes the tunnel.
+    pub async fn closed(&mut self) -> Result<(), String> {
+        loop {
+            match self.control.recv().await? {
+                ControlMessage::TunnelClose { session_id } if session_id == self.session_id => {
+                    return Ok(())
+                }
+                ControlMessage::Error { message, .. } => {
+                    return Err(format!("Server error: {}", message))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Closes the tunnel and the connection.
+    pub async fn close(mut self) {
+        let _ = self
+            .control
+            .send(&ControlMessage::TunnelClose {
+                session_id: self.session_id.clone(),
+            })
+            .await;
+        self.connection.close(0u32.into(), b"done");
+    }
+}
+
+/// Pads or truncates an ID to the 8 bytes the `Data` prefix carries.
+fn id_bytes(id: &str) -> [u8; 8] {
+    let mut bytes = [0u8; 8];
+    let len = id.len().min(8);
+    bytes[..len].copy_from_slice(&id.as_bytes()[..len]);
+    bytes
+}
+
+/// The ID in an 8-byte field of the `Data` prefix, without its padding.
+pub fn id_string(bytes: &[u8; 8]) -> String {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+/// Registers on `control` as a controller, with `token` when given.
+pub async fn register(control: &mut Control, token: Option<String>) -> Result<(), String> {
+    control
+        .send(&ControlMessage::RegisterController {
+            token,
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+        })
+        .await?;
+    loop {
+        match control.recv().await? {
+            ControlMessage::RegisterOk { .. } => return Ok(()),
+            ControlMessage::Error { message, .. } => {
+                return Err(format!("Registration failed: {}", message))
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Asks the agent of `target` to try a TCP connect to the target and
+/// returns how long it took, in milliseconds.
+pub async fn probe(server: &str, token: Option<String>, target: Target) -> Result<u64, String> {
+    let (connection, mut control) = quic::connect(server).await?;
+    register(&mut control, token).await?;
+
+    let request_id = format!("cli-{}", &Uuid::new_v4().to_string()[..8]);
+    let probe = ControlMessage::ProbeTarget {
+        request_id: request_id.clone(),
+        target_id: target.agent,
+        host: tunnel_protocol::normalize_host(&target.remote_host).to_string(),
+        port: target.remote_port,
+        requester: None,
+    };
+    probe.validate()?;
+    control.send(&probe).await?;
+
+    let result = loop {
+        match control.recv().await? {
+            ControlMessage::ProbeResult {
+                request_id: id,
+                latency_ms,
+                code,
+                message,
+            } if id == request_id => {
+                break latency_ms.ok_or_else(|| {
+                    format!(
+                        "Probe failed ({:?}): {}",
+                        code.unwrap_or(ErrorCode::Internal),
+                        message.unwrap_or_default()
+                    )
+                })
+            }
+            ControlMessage::Error { message, .. } => break Err(message),
+            _ => {}
+        }
+    };
+    connection.close(0u32.into(), b"done");
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_fit_the_data_prefix() {
+        assert_eq!(&id_bytes("3f2a9c1b"), b"3f2a9c1b");
+        assert_eq!(&id_bytes("3f2a9c1b-extra"), b"3f2a9c1b");
+        assert_eq!(&id_bytes("s1"), b"s1\0\0\0\0\0\0");
+        assert_eq!(id_string(&id_bytes("s1")), "s1");
+        assert_eq!(id_string(&id_bytes("3f2a9c1b")), "3f2a9c1b");
+    }
+}
@@ -6,22 +6,60 @@
 //! - Incoming message dispatch to the appropriate handler
 //! - Clean state reset on disconnect
 
-use crate::cert::SkipServerVerification;
+use crate::commands;
+use crate::identity::to_hex;
+use crate::known_agents::IdentityChange;
 use crate::relay::handle_stream_relay;
-use crate::state::{AgentState, AgentTunnelInfo, TunnelInfo};
-use quinn::Endpoint;
+use crate::socks;
+use crate::state::{
+    AccessLogEntry, AccessRequest, AccessUpdate, AgentState, AgentTunnelInfo, ObserveEnded,
+    ObserverRequest, PendingConnect, PortPair, ProbeReport, StreamOpenFailure, StreamRefusal,
+    TunnelApproval, TunnelInfo, TunnelRtt, CLOCK_SKEW_WARN_MS,
+};
+use quinn::{Endpoint, RecvStream, SendStream, VarInt};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tauri::Emitter;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tauri_plugin_notification::NotificationExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
 use tokio::sync::mpsc;
-use tracing::{error, info, warn};
-use tunnel_protocol::ControlMessage;
-use uuid::Uuid;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use tunnel_protocol::tls::SkipServerVerification;
+use tunnel_protocol::{
+    describe_target, estimate_clock_skew_ms, host_port, register_proof, unix_time_ms,
+    ControlMessage, ErrorCode, ResetCode, TrafficClass, CLOSE_BANNED, CONTROL_STREAM_PRIORITY,
+    ECHO_HOST, MAX_CONTROL_FRAME, RESET_DUPLICATE_STREAM, RESET_STREAM_LIMIT,
+};
 
 /// How long to wait before attempting to reconnect after a disconnect.
 const RECONNECT_DELAY_SECS: u64 = 3;
 
+/// How long an incoming tunnel request waits for the user before it is denied.
+const APPROVAL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a controller's `Connect` may go unanswered before its tunnel
+/// is marked "failed". Longer than the server's own request timeout, whose
+/// `ConnectFailed` normally arrives first.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How often a controller measures the round trip to each tunnel's agent.
+const SESSION_PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long quitting waits for the server to acknowledge `Unregister`.
+const UNREGISTER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long a data stream of a multi-port tunnel waits for the
+/// `StreamOpen` naming its port.
+const STREAM_PORT_TIMEOUT: Duration = Duration::from_secs(10);
+
 // ─── Main Connection Loop ───────────────────────────────────────
 
 pub async fn run_agent_loop(state: Arc<AgentState>, app_handle: tauri::AppHandle) {
@@ -75,12 +113,21 @@ pub async fn run_agent_loop(state: Arc<AgentState>, app_handle: tauri::AppHandle
     let mut transport_config = quinn::TransportConfig::default();
     transport_config.max_concurrent_bidi_streams(4096u32.into());
     transport_config.max_concurrent_uni_streams(4096u32.into());
+    // Phones drop idle NAT mappings and radio links quickly; QUIC pings
+    // keep the path open between heartbeats.
+    #[cfg(mobile)]
+    transport_config.keep_alive_interval(Some(crate::mobile::KEEP_ALIVE_INTERVAL));
     client_config.transport_config(std::sync::Arc::new(transport_config));
 
     endpoint.set_default_client_config(client_config);
 
+    // Position in the relay list of the next attempt, and how many
+    // attempts in a row have failed.
+    let mut next = 0;
+    let mut failures = 0;
     loop {
-        let server_url = state.server_url.read().await.clone();
+        let relays = state.relays().await;
+        let server_url = relays[next % relays.len()].clone();
         info!("Connecting to server: {}", server_url);
         let _ = app_handle.emit("connection-status", false);
 
@@ -91,18 +138,48 @@ pub async fn run_agent_loop(state: Arc<AgentState>, app_handle: tauri::AppHandle
                         match connecting.await {
                             Ok(connection) => {
                                 info!("Connected to server via QUIC!");
+                                failures = 0;
+                                *state.relay.write().await = Some(server_url.clone());
                                 *state.connected.write().await = true;
+                                *state.connection.write().await = Some(connection.clone());
+                                state.quality.lock().await.connected();
                                 let _ = app_handle.emit("connection-status", true);
 
                                 // Open the primary bi-directional stream for ControlMessages
                                 match connection.open_bi().await {
                                     Ok((mut control_send, mut control_recv)) => {
+                                        // Control messages go out ahead of bulk data
+                                        let _ = control_send.set_priority(CONTROL_STREAM_PRIORITY);
                                         let (tx, mut rx) =
                                             mpsc::unbounded_channel::<ControlMessage>();
                                         *state.ctrl_tx.write().await = Some(tx.clone());
 
                                         // Request registration
-                                        let _ = tx.send(ControlMessage::Register);
+                                        let token = state.auth_token.read().await.clone();
+                                        let tags = state.tags.read().await.clone();
+                                        let name = state.name.read().await.clone();
+                                        let services = state.services.read().await.clone();
+                                        let agent_id =
+                                            state.agent_key.as_ref().map(|(id, _)| id.clone());
+                                        let public_key = match agent_id {
+                                            Some(_) => None,
+                                            None => state
+                                                .identity
+                                                .read()
+                                                .await
+                                                .as_ref()
+                                                .map(|identity| identity.public_key().to_vec()),
+                                        };
+                                        *state.probe_sent_ms.lock().await = Some(unix_time_ms());
+                                        let _ = tx.send(ControlMessage::Register {
+                                            token,
+                                            tags,
+                                            name,
+                                            agent_id,
+                                            public_key,
+                                            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+                                            services,
+                                        });
 
                                         // ── Outbound Sender Task ──
                                         let outbound = tokio::spawn(async move {
@@ -118,17 +195,39 @@ pub async fn run_agent_loop(state: Arc<AgentState>, app_handle: tauri::AppHandle
                                                         break;
                                                     }
                                                 }
+                                                // Nothing follows `Unregister`; ending the
+                                                // stream lets the server close the connection.
+                                                if matches!(msg, ControlMessage::Unregister) {
+                                                    let _ = control_send.finish();
+                                                    break;
+                                                }
                                             }
                                         });
 
                                         // ── Heartbeat Task ──
                                         let tx_ping = tx.clone();
+                                        let state_ping = state.clone();
                                         let heartbeat = tokio::spawn(async move {
                                             loop {
                                                 tokio::time::sleep(
                                                     tokio::time::Duration::from_secs(30),
                                                 )
                                                 .await;
+                                                // A probe still pending means the
+                                                // last heartbeat went unanswered.
+                                                let unanswered = state_ping
+                                                    .probe_sent_ms
+                                                    .lock()
+                                                    .await
+                                                    .replace(unix_time_ms())
+                                                    .is_some();
+                                                if unanswered {
+                                                    state_ping
+                                                        .quality
+                                                        .lock()
+                                                        .await
+                                                        .missed_heartbeat();
+                                                }
                                                 if tx_ping.send(ControlMessage::Ping).is_err() {
                                                     break;
                                                 }
@@ -144,9 +243,6 @@ pub async fn run_agent_loop(state: Arc<AgentState>, app_handle: tauri::AppHandle
                                             while let Ok((send, mut recv)) =
                                                 connection_clone.accept_bi().await
                                             {
-                                                tracing::info!(
-                                                    "Agent accepted a new bi QUIC stream!"
-                                                );
                                                 let mut prefix = [0u8; 17];
                                                 if let Err(e) = recv.read_exact(&mut prefix).await {
                                                     tracing::error!(
@@ -184,44 +280,160 @@ pub async fn run_agent_loop(state: Arc<AgentState>, app_handle: tauri::AppHandle
                                                 )
                                                 .unwrap_or_default();
 
-                                                let at = state_clone.agent_tunnels.read().await;
-                                                if let Some(info) = at.get(&sess_str).cloned() {
-                                                    drop(at); // Drop before spawning
-                                                    tracing::info!("Agent linking stream {} for session {} to {}:{}", strm_str, sess_str, info.remote_host, info.remote_port);
-                                                    let addr = format!(
-                                                        "{}:{}",
-                                                        info.remote_host, info.remote_port
-                                                    );
+                                                let span = info_span!(
+                                                    "stream",
+                                                    session_id = %sess_str,
+                                                    stream_id = %strm_str
+                                                );
+                                                let info = state_clone
+                                                    .agent_tunnels
+                                                    .read()
+                                                    .await
+                                                    .get(&sess_str)
+                                                    .cloned();
+                                                if let Some(info) = info {
+                                                    let max_streams =
+                                                        state_clone.max_streams_per_session;
+                                                    match info.streams.claim(&strm_str, max_streams)
+                                                    {
+                                                        Ok(()) => {}
+                                                        Err(StreamRefusal::Limit) => {
+                                                            warn!(
+                                                                parent: &span,
+                                                                max_streams,
+                                                                "Refusing stream: session stream limit reached"
+                                                            );
+                                                            reset_stream(
+                                                                send,
+                                                                &mut recv,
+                                                                RESET_STREAM_LIMIT,
+                                                            );
+                                                            let _ = tx_clone.send(
+                                                                ControlMessage::StreamOpenFailed {
+                                                                    session_id: sess_str,
+                                                                    stream_id: strm_str,
+                                                                    code: ErrorCode::LimitExceeded,
+                                                                    message: format!(
+                                                                        "Agent reached its limit of {} streams for this tunnel",
+                                                                        max_streams
+                                                                    ),
+                                                                },
+                                                            );
+                                                            continue;
+                                                        }
+                                                        // The open stream keeps its ID, so no
+                                                        // StreamClose is sent for this one.
+                                                        Err(StreamRefusal::DuplicateId) => {
+                                                            warn!(
+                                                                parent: &span,
+                                                                "Refusing stream: stream ID already open in session"
+                                                            );
+                                                            reset_stream(
+                                                                send,
+                                                                &mut recv,
+                                                                RESET_DUPLICATE_STREAM,
+                                                            );
+                                                            continue;
+                                                        }
+                                                    }
                                                     let tx2 = tx_clone.clone();
                                                     let st3 = state_clone.clone();
 
-                                                    tokio::spawn(async move {
-                                                        match tokio::net::TcpStream::connect(&addr)
-                                                            .await
-                                                        {
-                                                            Ok(tcp_stream) => {
-                                                                tracing::info!("Agent connected to local target {}", addr);
-                                                                handle_stream_relay(
-                                                                    tcp_stream,
-                                                                    sess_str.clone(),
-                                                                    strm_str.clone(),
-                                                                    send,
-                                                                    recv,
-                                                                    tx2,
-                                                                    st3,
-                                                                )
-                                                                .await;
+                                                    tokio::spawn(
+                                                        async move {
+                                                            let mut info = info;
+                                                            if !info.extra_ports.is_empty() {
+                                                                match info
+                                                                    .stream_ports
+                                                                    .take(&strm_str, STREAM_PORT_TIMEOUT)
+                                                                    .await
+                                                                {
+                                                                    Some(port) => info.remote_port = port,
+                                                                    None => {
+                                                                        warn!("Refusing stream: no StreamOpen named its port");
+                                                                        let _ = tx2.send(
+                                                                            ControlMessage::StreamOpenFailed {
+                                                                                session_id: sess_str,
+                                                                                stream_id: strm_str.clone(),
+                                                                                code: ErrorCode::Timeout,
+                                                                                message: "No StreamOpen named the stream's port".to_string(),
+                                                                            },
+                                                                        );
+                                                                        info.streams.release(&strm_str);
+                                                                        return;
+                                                                    }
+                                                                }
                                                             }
-                                                            Err(_) => {
+                                                            let addr = describe_target(
+                                                                &info.remote_host,
+                                                                info.remote_port,
+                                                                info.remote_socket.as_deref(),
+                                                            );
+                                                            info!(target = %addr, "Linking stream to local target");
+                                                            st3.record_access(AccessLogEntry {
+                                                                time_ms: unix_time_ms(),
+                                                                session_id: sess_str.clone(),
+                                                                stream_id: strm_str.clone(),
+                                                                target: addr.clone(),
+                                                                requester: info.requester.clone(),
+                                                            })
+                                                            .await;
+                                                            if let Err(e) = dial_and_relay(
+                                                                st3,
+                                                                &info,
+                                                                sess_str.clone(),
+                                                                strm_str.clone(),
+                                                                send,
+                                                                recv,
+                                                                tx2.clone(),
+                                                            )
+                                                            .await
+                                                            {
+                                                                warn!(
+                                                                    "Agent failed to dial {}: {}",
+                                                                    addr, e
+                                                                );
+                                                                let code = if e.kind()
+                                                                    == std::io::ErrorKind::TimedOut
+                                                                {
+                                                                    ErrorCode::Timeout
+                                                                } else {
+                                                                    ErrorCode::Internal
+                                                                };
                                                                 let _ = tx2.send(
-                                                                    ControlMessage::StreamClose {
+                                                                    ControlMessage::StreamOpenFailed {
                                                                         session_id: sess_str,
-                                                                        stream_id: strm_str,
+                                                                        stream_id: strm_str.clone(),
+                                                                        code,
+                                                                        message: format!(
+                                                                            "Failed to connect to {}: {}",
+                                                                            addr, e
+                                                                        ),
                                                                     },
                                                                 );
                                                             }
+                                                            info.streams.release(&strm_str);
                                                         }
-                                                    });
+                                                        .instrument(span),
+                                                    );
+                                                } else if let Some(policy) =
+                                                    state_clone.socks_egress(&sess_str).await
+                                                {
+                                                    // ── Controller Side: Reverse SOCKS ──
+                                                    // The agent opened the stream for a
+                                                    // connection to its SOCKS proxy.
+                                                    tokio::spawn(
+                                                        socks::egress(
+                                                            state_clone.clone(),
+                                                            sess_str,
+                                                            strm_str,
+                                                            policy,
+                                                            send,
+                                                            recv,
+                                                            tx_clone.clone(),
+                                                        )
+                                                        .instrument(span),
+                                                    );
                                                 }
                                             }
                                         });
@@ -229,6 +441,10 @@ pub async fn run_agent_loop(state: Arc<AgentState>, app_handle: tauri::AppHandle
                                         // ── Inbound Message Loop ──
                                         while let Ok(l) = control_recv.read_u32_le().await {
                                             let len = l as usize;
+                                            if len > MAX_CONTROL_FRAME {
+                                                error!("Control frame too large: {}", len);
+                                                break;
+                                            }
 
                                             let mut buf = vec![0u8; len];
                                             if control_recv.read_exact(&mut buf).await.is_err() {
@@ -236,6 +452,11 @@ pub async fn run_agent_loop(state: Arc<AgentState>, app_handle: tauri::AppHandle
                                             }
 
                                             if let Ok(msg) = ControlMessage::deserialize(&buf) {
+                                                let span = info_span!(
+                                                    "control",
+                                                    tag = msg.tag(),
+                                                    session_id = msg.session_id()
+                                                );
                                                 handle_server_message(
                                                     &state,
                                                     &tx,
@@ -243,6 +464,7 @@ pub async fn run_agent_loop(state: Arc<AgentState>, app_handle: tauri::AppHandle
                                                     &app_handle,
                                                     msg,
                                                 )
+                                                .instrument(span)
                                                 .await;
                                             }
                                         }
@@ -256,15 +478,51 @@ pub async fn run_agent_loop(state: Arc<AgentState>, app_handle: tauri::AppHandle
                                 }
 
                                 *state.connected.write().await = false;
+                                *state.relay.write().await = None;
                                 *state.ctrl_tx.write().await = None;
+                                *state.connection.write().await = None;
+                                let reason = connection
+                                    .close_reason()
+                                    .map_or("control stream closed".to_string(), |e| e.to_string());
+                                if let Some(reason) = ban_reason(&connection) {
+                                    error!("Banned by the relay: {}", reason);
+                                    let _ = app_handle.emit("banned", &reason);
+                                    *state.banned.write().await = Some(reason);
+                                }
+                                state.quality.lock().await.disconnected(reason);
+                                state.agent_list_waiters.lock().await.clear();
                                 state.agent_tunnels.write().await.clear();
                                 state.abort_all_tasks().await;
+                                state.session_buffers.write().await.clear();
+                                let open = state.history.read().await.open_sessions();
+                                let mut recorded = false;
+                                for session_id in open {
+                                    recorded |= state.finish_history(&session_id).await;
+                                }
+                                if recorded {
+                                    let _ = app_handle.emit("history-updated", ());
+                                }
+                                state.drop_all_traffic().await;
+                                state.captures.write().await.clear();
+                                state.outgoing_streams.write().await.clear();
                                 state.tunnels.write().await.clear();
+                                state.observed.write().await.clear();
+                                state.observer_requests.write().await.clear();
+                                state.tunnel_approvals.write().await.clear();
+                                state.access_requests.write().await.clear();
+                                state.identity_changes.write().await.clear();
+                                let _ = app_handle.emit("tunnel-requests-updated", ());
+                                let _ = app_handle.emit("access-requests-updated", ());
+                                let _ = app_handle.emit("identity-changes-updated", ());
                                 let _ = app_handle.emit("tunnels-updated", ());
+                                let _ = app_handle.emit("observed-updated", ());
                                 let _ = app_handle.emit("connection-status", false);
                                 warn!("Disconnected from server");
                             }
-                            Err(e) => error!("Connection failed: {}", e),
+                            Err(e) => {
+                                error!("Connection failed: {}", e);
+                                state.quality.lock().await.disconnected(e.to_string());
+                            }
                         }
                     }
                     Err(e) => error!("QUIC Endpoint connect failed: {}", e),
@@ -273,9 +531,498 @@ pub async fn run_agent_loop(state: Arc<AgentState>, app_handle: tauri::AppHandle
             Err(e) => error!("Invalid server address {}: {}", server_url, e),
         }
 
+        // Fail over to the next relay right away; once every relay has
+        // failed in a row, wait and start again from the primary.
+        next = next % relays.len() + 1;
+        failures += 1;
+        if failures < relays.len() {
+            info!("Failing over to {}", relays[next % relays.len()]);
+            continue;
+        }
+        next = 0;
+        failures = 0;
+
+        // A banned client only tries again when asked to, e.g. after the
+        // token or identity changed.
+        if state.banned.read().await.is_some() {
+            info!("Not reconnecting while banned");
+            state.reconnect_now.notified().await;
+            *state.banned.write().await = None;
+            continue;
+        }
+
         // Wait before attempting to reconnect
         info!("Reconnecting in {}s...", RECONNECT_DELAY_SECS);
-        tokio::time::sleep(tokio::time::Duration::from_secs(RECONNECT_DELAY_SECS)).await;
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(RECONNECT_DELAY_SECS)) => {}
+            _ = state.reconnect_now.notified() => info!("Reconnecting now"),
+        }
+    }
+}
+
+/// Returns the reason the relay gave if it closed `connection` because an
+/// admin banned this client.
+fn ban_reason(connection: &quinn::Connection) -> Option<String> {
+    match connection.close_reason()? {
+        quinn::ConnectionError::ApplicationClosed(close)
+            if close.error_code == quinn::VarInt::from_u32(CLOSE_BANNED) =>
+        {
+            Some(String::from_utf8_lossy(&close.reason).into_owned())
+        }
+        _ => None,
+    }
+}
+
+/// Tells the server the agent is shutting down so it drops the
+/// registration and closes its tunnels right away, then waits up to
+/// [`UNREGISTER_TIMEOUT`] for the server to close the connection.
+pub async fn unregister(state: &AgentState) {
+    let Some(tx) = state.ctrl_tx.read().await.clone() else {
+        return;
+    };
+    let Some(connection) = state.connection.read().await.clone() else {
+        return;
+    };
+    if tx.send(ControlMessage::Unregister).is_err() {
+        return;
+    }
+    info!("Unregistering from server");
+    let _ = tokio::time::timeout(UNREGISTER_TIMEOUT, connection.closed()).await;
+}
+
+/// Opens every autostart profile that is not open yet. Runs on each
+/// `RegisterOk`, so these tunnels come back after a launch or a reconnect.
+async fn open_autostart_tunnels(state: &Arc<AgentState>, app_handle: &tauri::AppHandle) {
+    let profiles = state.profiles.read().await.autostart();
+    if profiles.is_empty() {
+        return;
+    }
+    let open: Vec<String> = state
+        .tunnels
+        .read()
+        .await
+        .iter()
+        .filter_map(|t| t.profile.clone())
+        .collect();
+
+    for profile in profiles.into_iter().filter(|p| !open.contains(&p.name)) {
+        let name = profile.name.clone();
+        match commands::open_tunnel(state, app_handle, profile.into()).await {
+            Ok(_) => info!("Autostarted profile {}", name),
+            Err(e) => {
+                warn!("Failed to autostart profile {}: {}", name, e);
+                let _ = app_handle.emit("server-error", &e);
+            }
+        }
+    }
+}
+
+// ─── Tunnel Approval ────────────────────────────────────────────
+
+/// Accepts an incoming tunnel and starts serving its target.
+pub(crate) async fn accept_tunnel(
+    state: &Arc<AgentState>,
+    tx: &mpsc::UnboundedSender<ControlMessage>,
+    app_handle: &tauri::AppHandle,
+    request: TunnelApproval,
+) {
+    // A reverse SOCKS tunnel is only accepted once its port is ours.
+    let socks = if request.reverse_socks {
+        let connection = state.connection.read().await.clone();
+        match (connection, bind_loopback(request.remote_port).await) {
+            (Some(connection), Ok(listeners)) => Some((connection, listeners)),
+            (None, _) => return,
+            (_, Err(e)) => {
+                warn!(
+                    port = request.remote_port,
+                    "Rejecting reverse SOCKS tunnel: {}", e
+                );
+                let _ = tx.send(ControlMessage::TunnelReject {
+                    session_id: request.session_id,
+                    code: ErrorCode::InUse,
+                    message: format!(
+                        "Port {} is unavailable on the agent: {}",
+                        request.remote_port, e
+                    ),
+                });
+                return;
+            }
+        }
+    } else {
+        None
+    };
+
+    let _ = tx.send(ControlMessage::TunnelAccept {
+        session_id: request.session_id.clone(),
+        max_streams: u32::try_from(state.max_streams_per_session).ok(),
+    });
+    state.stats.lock().await.tunnel_opened();
+
+    // Store the target address so we can connect to it
+    // when StreamOpen messages arrive later
+    state.agent_tunnels.write().await.insert(
+        request.session_id.clone(),
+        AgentTunnelInfo {
+            remote_host: request.remote_host.clone(),
+            remote_port: request.remote_port,
+            remote_socket: request.remote_socket.clone(),
+            requester: request.requester.clone(),
+            streams: Arc::default(),
+            connect_timeout: request
+                .connect_timeout_ms
+                .map_or(state.connect_timeout, |ms| {
+                    Duration::from_millis(u64::from(ms))
+                }),
+            extra_ports: request.extra_ports.clone(),
+            stream_ports: Arc::default(),
+            reverse_socks: request.reverse_socks,
+            traffic_class: request.traffic_class,
+        },
+    );
+
+    if let Some((connection, listeners)) = socks {
+        info!(port = request.remote_port, "Serving reverse SOCKS proxy");
+        let mut handles = state.task_handles.write().await;
+        let session_handles = handles.entry(request.session_id.clone()).or_default();
+        for listener in listeners {
+            session_handles.push(tokio::spawn(
+                socks::serve(
+                    listener,
+                    connection.clone(),
+                    tx.clone(),
+                    state.clone(),
+                    request.session_id.clone(),
+                )
+                .in_current_span(),
+            ));
+        }
+    }
+
+    // The server closes the tunnel when its TTL is up as well; this
+    // timer keeps the promise should that message not arrive.
+    if let Some(ttl_secs) = request.ttl_secs {
+        tokio::spawn(
+            expire_tunnel(
+                state.clone(),
+                tx.clone(),
+                app_handle.clone(),
+                request.session_id.clone(),
+                ttl_secs,
+            )
+            .in_current_span(),
+        );
+    }
+
+    // Add the tunnel to the UI list
+    state.tunnels.write().await.push(TunnelInfo {
+        session_id: request.session_id,
+        remote_host: request.remote_host,
+        remote_port: request.remote_port,
+        local_port: 0, // Agent side doesn't listen on a local port
+        remote_socket: request.remote_socket,
+        local_socket: None,
+        direction: "incoming".to_string(),
+        status: "active".to_string(),
+        profile: None,
+        group: None,
+        public_port: None,
+        public_host: None,
+        label: None,
+        rtt_ms: None,
+        nodelay: false,
+        expires_at_ms: request.ttl_secs.map(|secs| unix_time_ms() + secs * 1000),
+        reverse_socks: request.reverse_socks,
+        socks_egress: None,
+        traffic_class: request.traffic_class,
+        max_streams: None,
+        extra_ports: request
+            .extra_ports
+            .iter()
+            .map(|&remote_port| PortPair {
+                local_port: 0,
+                remote_port,
+            })
+            .collect(),
+    });
+    let _ = app_handle.emit("tunnels-updated", ());
+}
+
+/// Closes an accepted tunnel once its `ttl_secs` are up, unless it has
+/// already ended.
+async fn expire_tunnel(
+    state: Arc<AgentState>,
+    tx: mpsc::UnboundedSender<ControlMessage>,
+    app_handle: tauri::AppHandle,
+    session_id: String,
+    ttl_secs: u64,
+) {
+    tokio::time::sleep(Duration::from_secs(ttl_secs)).await;
+    if !state.agent_tunnels.read().await.contains_key(&session_id) {
+        return;
+    }
+    info!(%session_id, ttl_secs, "Tunnel lifetime over, closing");
+    let _ = tx.send(ControlMessage::TunnelClose {
+        session_id: session_id.clone(),
+    });
+    forget_tunnel(&state, &app_handle, &session_id).await;
+}
+
+/// Drops everything held for a closed tunnel and removes it from the UI.
+async fn forget_tunnel(state: &AgentState, app_handle: &tauri::AppHandle, session_id: &str) {
+    state.abort_session_tasks(session_id).await;
+    state.stop_capture(session_id).await;
+    state.agent_tunnels.write().await.remove(session_id);
+    state.session_buffers.write().await.remove(session_id);
+    if state.finish_history(session_id).await {
+        let _ = app_handle.emit("history-updated", ());
+    }
+    state.drop_traffic(session_id).await;
+    state.outgoing_streams.write().await.remove(session_id);
+    state.dialer.forget_session(session_id);
+    state
+        .observer_requests
+        .write()
+        .await
+        .retain(|r| r.session_id != session_id);
+    if state.take_tunnel_approval(session_id).await.is_some() {
+        let _ = app_handle.emit("tunnel-requests-updated", ());
+    }
+    if state.take_identity_change(session_id).await.is_some() {
+        let _ = app_handle.emit("identity-changes-updated", ());
+    }
+    let group = {
+        let mut tunnels = state.tunnels.write().await;
+        let group = tunnels
+            .iter()
+            .find(|t| t.session_id == session_id)
+            .and_then(|t| t.group.clone());
+        tunnels.retain(|t| t.session_id != session_id);
+        group
+    };
+    let _ = app_handle.emit("tunnels-updated", ());
+    if let Some(group) = group {
+        let _ = app_handle.emit("group-updated", &group);
+    }
+}
+
+/// Starts a ready outgoing tunnel: binds its local listeners, or for a
+/// reverse SOCKS tunnel just keeps the session alive.
+pub(crate) async fn start_tunnel(
+    state: &Arc<AgentState>,
+    tx: &mpsc::UnboundedSender<ControlMessage>,
+    app_handle: &tauri::AppHandle,
+    connection: &quinn::Connection,
+    session_id: String,
+    pending: PendingConnect,
+) {
+    state
+        .history
+        .write()
+        .await
+        .opened(&session_id, &pending, unix_time_ms());
+    state.stats.lock().await.tunnel_opened();
+
+    // A reverse SOCKS tunnel has no local listeners; the agent
+    // opens the streams.
+    if pending.reverse_socks {
+        info!(
+            port = pending.remote_port,
+            "Reverse SOCKS proxy open on the agent"
+        );
+        state
+            .task_handles
+            .write()
+            .await
+            .entry(session_id.clone())
+            .or_default()
+            .push(tokio::spawn(ping_session(tx.clone(), session_id)));
+        return;
+    }
+
+    // Start local listeners to accept local connections
+    let local = match &pending.local_socket {
+        Some(path) => path.display().to_string(),
+        None => format!("Port {}", pending.local_port),
+    };
+    let span = info_span!("session", session_id = %session_id);
+    let mut listeners = match bind_local(&pending, pending.local_port)
+        .instrument(span.clone())
+        .await
+    {
+        Ok(listeners) => listeners.into_iter().map(|l| (l, None)).collect::<Vec<_>>(),
+        Err(e) => {
+            error!(parent: &span, "Failed to bind {}: {}", local, e);
+            let _ = app_handle.emit("server-error", &format!("{} unavailable: {}", local, e));
+            return;
+        }
+    };
+    // Each extra port gets listeners of its own; one that cannot
+    // be bound is reported without failing the others.
+    for pair in &pending.extra_ports {
+        match bind_local(&pending, pair.local_port)
+            .instrument(span.clone())
+            .await
+        {
+            Ok(bound) => listeners.extend(bound.into_iter().map(|l| (l, Some(pair.remote_port)))),
+            Err(e) => {
+                error!(parent: &span, "Failed to bind port {}: {}", pair.local_port, e);
+                let _ = app_handle.emit(
+                    "server-error",
+                    &format!("Port {} unavailable: {}", pair.local_port, e),
+                );
+            }
+        }
+    }
+
+    // Track the task handles for cleanup when the tunnel is closed
+    let mut handles = state.task_handles.write().await;
+    let session_handles = handles.entry(session_id.clone()).or_default();
+    session_handles.push(tokio::spawn(ping_session(tx.clone(), session_id.clone())));
+    for (listener, remote_port) in listeners {
+        session_handles.push(tokio::spawn(
+            accept_local(
+                listener,
+                remote_port,
+                pending.nodelay,
+                connection.clone(),
+                tx.clone(),
+                state.clone(),
+                session_id.clone(),
+            )
+            .instrument(span.clone()),
+        ));
+    }
+}
+
+/// Refuses an incoming tunnel on the user's behalf.
+pub(crate) fn deny_tunnel(
+    tx: &mpsc::UnboundedSender<ControlMessage>,
+    session_id: String,
+    message: &str,
+) {
+    let _ = tx.send(ControlMessage::TunnelReject {
+        session_id,
+        code: ErrorCode::Unauthorized,
+        message: message.to_string(),
+    });
+}
+
+/// Denies a tunnel request that is still unanswered after [`APPROVAL_TIMEOUT`],
+/// so the controller is not left waiting forever.
+async fn expire_tunnel_request(
+    state: Arc<AgentState>,
+    app_handle: tauri::AppHandle,
+    session_id: String,
+) {
+    tokio::time::sleep(APPROVAL_TIMEOUT).await;
+    if state.take_tunnel_approval(&session_id).await.is_none() {
+        return;
+    }
+    info!("Tunnel request timed out");
+    if let Some(tx) = state.ctrl_tx.read().await.as_ref() {
+        deny_tunnel(tx, session_id, "The agent did not answer the request");
+    }
+    let _ = app_handle.emit("tunnel-requests-updated", ());
+}
+
+/// Marks the tunnel of `Connect` `request_id` as "failed" if it is still
+/// waiting after [`CONNECT_TIMEOUT`].
+pub async fn expire_connect(
+    state: Arc<AgentState>,
+    app_handle: tauri::AppHandle,
+    request_id: String,
+) {
+    tokio::time::sleep(CONNECT_TIMEOUT).await;
+    if state
+        .pending_connects
+        .read()
+        .await
+        .contains_key(&request_id)
+    {
+        warn!("Connect {} got no answer, giving up", request_id);
+        let message = format!(
+            "No answer to the tunnel request within {}s",
+            CONNECT_TIMEOUT.as_secs()
+        );
+        fail_connect(&state, &app_handle, &request_id, &message).await;
+    }
+}
+
+/// Drops the pending `Connect` `request_id` and shows its "connecting"
+/// placeholder as "failed".
+async fn fail_connect(
+    state: &AgentState,
+    app_handle: &tauri::AppHandle,
+    request_id: &str,
+    message: &str,
+) {
+    state.pending_connects.write().await.remove(request_id);
+    let group = {
+        let mut tunnels = state.tunnels.write().await;
+        tunnels
+            .iter_mut()
+            .find(|t| t.session_id == request_id && t.status == "connecting")
+            .and_then(|t| {
+                t.status = "failed".to_string();
+                t.group.clone()
+            })
+    };
+    let _ = app_handle.emit("tunnels-updated", ());
+    let _ = app_handle.emit("server-error", message);
+    if let Some(group) = group {
+        let _ = app_handle.emit("group-updated", &group);
+    }
+}
+
+/// Warns that a tunnel reached a different agent than the one known for
+/// its target.
+fn notify_identity_change(app_handle: &tauri::AppHandle, change: &IdentityChange) {
+    let result = app_handle
+        .notification()
+        .builder()
+        .title("Agent identity changed")
+        .body(format!(
+            "{} is now agent {} (was {}). The tunnel is on hold until you trust it.",
+            change.target, change.agent_id, change.previous.agent_id
+        ))
+        .show();
+    if let Err(e) = result {
+        debug!("Failed to show notification: {}", e);
+    }
+}
+
+/// Announces an incoming tunnel with a native notification. Requests that
+/// wait for an answer are answered in the app, from the `tunnel-request`
+/// event.
+fn notify_tunnel_request(app_handle: &tauri::AppHandle, request: &TunnelApproval, accepted: bool) {
+    let requester = request
+        .requester
+        .as_deref()
+        .unwrap_or("An anonymous controller");
+    let target = if request.reverse_socks {
+        format!(
+            "a SOCKS proxy on port {} that exits through their network",
+            request.remote_port
+        )
+    } else {
+        describe_target(
+            &request.remote_host,
+            request.remote_port,
+            request.remote_socket.as_deref(),
+        )
+    };
+    let builder = app_handle.notification().builder();
+    let builder = if accepted {
+        builder
+            .title("Tunnel opened")
+            .body(format!("{} connected to {}", requester, target))
+    } else {
+        builder
+            .title("Tunnel request")
+            .body(format!("{} wants to reach {}", requester, target))
+    };
+    if let Err(e) = builder.show() {
+        debug!("Failed to show notification: {}", e);
     }
 }
 
@@ -296,209 +1043,280 @@ async fn handle_server_message(
 ) {
     match msg {
         // ── Registration Confirmed with Server-Assigned ID ──
-        ControlMessage::RegisterOk { agent_id } => {
-            info!("Registered as agent: {}", agent_id);
-            // Store the server-assigned agent ID
+        ControlMessage::RegisterOk {
+            agent_id,
+            server_time_ms,
+            max_chunk_bytes,
+            server_version,
+        } => {
+            let chunk = state.negotiate_chunk_size(max_chunk_bytes);
+            debug!(chunk, server_max = max_chunk_bytes, "Relay chunk size");
+            info!(%server_version, "Server version");
+            *state.server_version.write().await = Some(server_version);
+            *state.upgrade_required.write().await = None;
+            *state.banned.write().await = None;
+            update_clock_skew(state, app_handle, server_time_ms).await;
+            // Store the server-assigned agent ID; none when our token may
+            // only open tunnels.
+            match &agent_id {
+                Some(agent_id) => info!("Registered as agent: {}", agent_id),
+                None => warn!("Registered without an agent ID: the token may only open tunnels"),
+            }
             *state.agent_id.write().await = agent_id.clone();
             let _ = app_handle.emit("registered", &agent_id);
+            open_autostart_tunnels(state, app_handle).await;
+        }
+
+        // ── Fixed or Key-Backed Agent ID: Prove Ownership ──
+        // A fixed ID answers with its pre-shared key, otherwise the
+        // identity keypair signs the nonce.
+        ControlMessage::RegisterChallenge { nonce } => {
+            let proof = match &state.agent_key {
+                Some((agent_id, key)) => Some(register_proof(key, agent_id, &nonce)),
+                None => state
+                    .identity
+                    .read()
+                    .await
+                    .as_ref()
+                    .and_then(|identity| identity.sign(&nonce)),
+            };
+            let Some(proof) = proof else {
+                warn!("Unexpected registration challenge");
+                return;
+            };
+            let _ = tx.send(ControlMessage::RegisterProof { proof });
         }
 
         // ── Agent Side: Incoming Tunnel Request ──
         // When another client wants to connect to us, the server asks
-        // if we accept. We auto-accept all tunnel requests.
+        // if we accept. The request is announced with a notification and
+        // held until the user answers via `approve_tunnel_request` or
+        // `deny_tunnel_request`, unless auto-accept is on.
         ControlMessage::TunnelRequest {
             session_id,
             remote_host,
             remote_port,
+            remote_socket,
+            requester,
+            pairing_token,
+            connect_timeout_ms,
+            extra_ports,
+            invited,
+            ttl_secs,
+            reverse_socks,
+            traffic_class,
         } => {
             info!(
-                "Tunnel request: {} → {}:{}",
-                session_id, remote_host, remote_port
+                target = %describe_target(&remote_host, remote_port, remote_socket.as_deref()),
+                extra_ports = ?extra_ports,
+                requester = requester.as_deref().unwrap_or("anonymous"),
+                "Tunnel request"
             );
 
-            // Auto-accept the tunnel request
-            let _ = tx.send(ControlMessage::TunnelAccept {
-                session_id: session_id.clone(),
-            });
-
-            // Store the target address so we can connect to it
-            // when StreamOpen messages arrive later
-            {
-                let mut at = state.agent_tunnels.write().await;
-                at.insert(
-                    session_id.clone(),
-                    AgentTunnelInfo {
-                        remote_host: remote_host.clone(),
-                        remote_port,
-                    },
-                );
+            // Requests awaiting an answer count toward the limit, so a
+            // flood of them cannot pile up unanswered.
+            let open_tunnels =
+                state.agent_tunnels.read().await.len() + state.tunnel_approvals.read().await.len();
+            if open_tunnels >= state.max_tunnels {
+                warn!(open_tunnels, "Rejecting tunnel: tunnel limit reached");
+                let _ = tx.send(ControlMessage::TunnelReject {
+                    session_id,
+                    code: ErrorCode::LimitExceeded,
+                    message: format!("Agent reached its limit of {} tunnels", state.max_tunnels),
+                });
+                return;
             }
 
-            // Add the tunnel to the UI list
-            {
-                let mut tunnels = state.tunnels.write().await;
-                tunnels.push(TunnelInfo {
-                    session_id: session_id.clone(),
-                    remote_host,
-                    remote_port,
-                    local_port: 0, // Agent side doesn't listen on a local port
-                    direction: "incoming".to_string(),
-                    status: "active".to_string(),
-                });
+            let request = TunnelApproval {
+                session_id: session_id.clone(),
+                requester,
+                remote_host,
+                remote_port,
+                remote_socket,
+                connect_timeout_ms,
+                extra_ports,
+                ttl_secs,
+                reverse_socks,
+                traffic_class,
+            };
+            // A controller holding one of our pairing tokens was let in
+            // when the code was scanned.
+            let paired = match &pairing_token {
+                Some(token) => state.take_pairing_token(token).await,
+                None => false,
+            };
+            if paired {
+                info!("Accepting paired controller");
             }
-            let _ = app_handle.emit("tunnels-updated", ());
+            // The server only sets `invited` for an invitation we created.
+            if invited {
+                info!("Accepting invited controller");
+            }
+            let accept = state.auto_accept || paired || invited;
+            notify_tunnel_request(app_handle, &request, accept);
+            if accept {
+                accept_tunnel(state, tx, app_handle, request).await;
+                return;
+            }
+
+            state.tunnel_approvals.write().await.push(request.clone());
+            let _ = app_handle.emit("tunnel-request", &request);
+            tokio::spawn(
+                expire_tunnel_request(state.clone(), app_handle.clone(), session_id)
+                    .in_current_span(),
+            );
         }
 
         // ── Controller Side: Tunnel is Ready ──
-        // The agent accepted our tunnel request. Now we start a TCP
-        // listener on the local port and relay incoming connections.
-        ControlMessage::TunnelReady { session_id } => {
-            info!("Tunnel ready: {}", session_id);
-
-            // Retrieve and remove the pending connection parameters
-            let pending = {
-                let mut pm = state.pending_connects.write().await;
-                let key = pm.keys().next().cloned();
-                key.and_then(|k| pm.remove(&k))
+        // The agent accepted our tunnel request. Unless the agent differs
+        // from the one known for the target, we start a TCP listener on
+        // the local port and relay incoming connections.
+        ControlMessage::TunnelReady {
+            session_id,
+            request_id,
+            agent_id,
+            public_key,
+            max_streams,
+        } => {
+            info!(%request_id, %agent_id, max_streams, "Tunnel ready");
+
+            // Retrieve and remove the pending connection parameters. None
+            // means the tunnel was closed while it was still connecting, so
+            // the relay is told to close the session it just opened.
+            let Some(pending) = state.pending_connects.write().await.remove(&request_id) else {
+                warn!(%request_id, %session_id, "Tunnel ready after it was closed, closing it");
+                let _ = tx.send(ControlMessage::TunnelClose { session_id });
+                return;
             };
 
+            // Trust on first use: a target whose agent holds another key
+            // than before, or none, is held until the user confirms it.
+            let public_key = public_key.as_deref().map(to_hex);
+            let previous = state
+                .known_agents
+                .read()
+                .await
+                .changed(&pending.target_id, public_key.as_deref());
+            if previous.is_none() {
+                if let Err(e) = state.known_agents.write().await.trust(
+                    &pending.target_id,
+                    &agent_id,
+                    public_key.as_deref(),
+                    unix_time_ms(),
+                ) {
+                    warn!("Failed to save known agent: {}", e);
+                }
+            }
+
             // Update the UI: change status from "connecting" to "active"
             // and replace the placeholder session ID with the real one
-            {
+            let expires_at_ms = pending.ttl_secs.map(|secs| unix_time_ms() + secs * 1000);
+            let status = if previous.is_some() {
+                "unverified"
+            } else {
+                "active"
+            };
+            let group = {
                 let mut tunnels = state.tunnels.write().await;
-                if let Some(t) = tunnels
-                    .iter_mut()
-                    .find(|t| t.direction == "outgoing" && t.status == "connecting")
-                {
-                    t.session_id = session_id.clone();
-                    t.status = "active".to_string();
+                match tunnels.iter_mut().find(|t| t.session_id == request_id) {
+                    Some(t) => {
+                        t.session_id = session_id.clone();
+                        t.status = status.to_string();
+                        t.max_streams = Some(max_streams);
+                        t.expires_at_ms = expires_at_ms;
+                        t.group.clone()
+                    }
+                    None => None,
                 }
-            }
+            };
             let _ = app_handle.emit("tunnels-updated", ());
+            if let Some(group) = group {
+                let _ = app_handle.emit("group-updated", &group);
+            }
 
-            // Start a TCP listener to accept local connections
-            if let Some(pending) = pending {
-                let local_port = pending.local_port;
-                let tx_clone = tx.clone();
-                let state_clone = state.clone();
-                let app_clone = app_handle.clone();
-                let sid = session_id.clone();
-
-                let sid_for_handle = session_id.clone();
-                let handle = tokio::spawn(async move {
-                    let bind_addr = format!("127.0.0.1:{}", local_port);
-                    match TcpListener::bind(&bind_addr).await {
-                        Ok(listener) => {
-                            info!("Listening on {} for tunnel {}", bind_addr, sid);
-
-                            // Accept loop: each new TCP connection becomes
-                            // a new "stream" within the tunnel session
-                            loop {
-                                match listener.accept().await {
-                                    Ok((tcp_stream, peer)) => {
-                                        // Generate a unique stream ID for this TCP connection
-                                        let stream_id = Uuid::new_v4().to_string()[..8].to_string();
-                                        info!(
-                                            "New stream {} from {} (tunnel {})",
-                                            stream_id, peer, sid
-                                        );
-
-                                        let _quic_send = match connection.open_bi().await {
-                                            Ok((tx, _rx)) => tx,
-                                            Err(e) => {
-                                                error!("Failed to open QUIC data stream: {}", e);
-                                                break;
-                                            }
-                                        };
-
-                                        let tx2 = tx_clone.clone();
-                                        let st2 = state_clone.clone();
-                                        let sid2 = sid.clone();
-
-                                        // A new QUIC stream means we need to open it and then send
-                                        // the `Data` protocol prefix so the server knows where to route it.
-                                        let conn2 = connection.clone();
-                                        tokio::spawn(async move {
-                                            match conn2.open_bi().await {
-                                                Ok((mut q_send, q_recv)) => {
-                                                    // Tell the agent to open its TCP connection.
-                                                    let _ = tx2.send(ControlMessage::StreamOpen {
-                                                        session_id: sid2.clone(),
-                                                        stream_id: stream_id.clone(),
-                                                    });
-
-                                                    // Send the prefix: 0x0A + 8 bytes session + 8 bytes stream
-                                                    let mut prefix = vec![0x0A]; // TAG_DATA
-                                                    let mut sess_bytes = [0u8; 8];
-                                                    let s_bytes = sid2.as_bytes();
-                                                    sess_bytes[..s_bytes.len().min(8)]
-                                                        .copy_from_slice(
-                                                            &s_bytes[..s_bytes.len().min(8)],
-                                                        );
-
-                                                    let mut strm_bytes = [0u8; 8];
-                                                    let st_bytes = stream_id.as_bytes();
-                                                    strm_bytes[..st_bytes.len().min(8)]
-                                                        .copy_from_slice(
-                                                            &st_bytes[..st_bytes.len().min(8)],
-                                                        );
-
-                                                    prefix.extend_from_slice(&sess_bytes);
-                                                    prefix.extend_from_slice(&strm_bytes);
-                                                    if q_send.write_all(&prefix).await.is_err() {
-                                                        return;
-                                                    }
+            if let Some(previous) = previous {
+                warn!(
+                    target = %pending.target_id,
+                    previous = %previous.agent_id,
+                    "Agent identity changed, holding the tunnel"
+                );
+                let change = IdentityChange {
+                    session_id,
+                    target: pending.target_id.clone(),
+                    agent_id,
+                    public_key,
+                    previous,
+                    pending,
+                };
+                notify_identity_change(app_handle, &change);
+                state.identity_changes.write().await.push(change);
+                let _ = app_handle.emit("identity-changes-updated", ());
+                return;
+            }
 
-                                                    handle_stream_relay(
-                                                        tcp_stream, sid2, stream_id, q_send,
-                                                        q_recv, tx2, st2,
-                                                    )
-                                                    .await;
-                                                }
-                                                Err(e) => {
-                                                    error!("Failed to open QUIC bi-stream: {}", e)
-                                                }
-                                            }
-                                        });
-                                    }
-                                    Err(e) => {
-                                        error!("Accept error: {}", e);
-                                        break;
-                                    }
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            error!("Failed to bind {}: {}", bind_addr, e);
-                            let _ = app_clone.emit(
-                                "server-error",
-                                &format!("Port {} unavailable: {}", local_port, e),
-                            );
-                        }
-                    }
-                });
+            start_tunnel(state, tx, app_handle, &connection, session_id, pending).await;
+        }
 
-                // Track the task handle for cleanup when the tunnel is closed
-                {
-                    let mut handles = state.task_handles.write().await;
-                    handles.entry(sid_for_handle).or_default().push(handle);
-                }
-            } else {
-                warn!("TunnelReady but no pending connect for {}", session_id);
-            }
+        // ── Agent Side: Relay Publishes Our Service ──
+        // Connections to the port arrive as data streams for this session,
+        // handled by the inbound stream loop like a controller's streams.
+        ControlMessage::ExposeReady {
+            request_id,
+            session_id,
+            public_port,
+        } => {
+            info!(%request_id, public_port, "Exposed on relay port");
+            activate_public_tunnel(state, tx, app_handle, &request_id, session_id, |t| {
+                t.public_port = Some(public_port)
+            })
+            .await;
+        }
+        ControlMessage::ExposeHttpReady {
+            request_id,
+            session_id,
+            hostname,
+        } => {
+            info!(%request_id, %hostname, "Exposed on relay HTTP ingress");
+            activate_public_tunnel(state, tx, app_handle, &request_id, session_id, |t| {
+                t.public_host = Some(hostname)
+            })
+            .await;
+        }
+        ControlMessage::ExposeTlsReady {
+            request_id,
+            session_id,
+            hostname,
+        } => {
+            info!(%request_id, %hostname, "Exposed on relay TLS ingress");
+            activate_public_tunnel(state, tx, app_handle, &request_id, session_id, |t| {
+                t.public_host = Some(hostname)
+            })
+            .await;
         }
 
         // ── Agent Side: Controller Opened a New Stream ──
         // The controller has a new TCP connection. The Server will map the stream and just send it to us.
-        // We handle this exclusively in the incoming `accept_bi()` loop.
+        // We handle this in the incoming `accept_bi()` loop; a multi-port
+        // tunnel only learns the stream's port from here.
         ControlMessage::StreamOpen {
             session_id,
             stream_id,
+            remote_port,
         } => {
-            info!(
-                "StreamOpen: session={}, stream={} (Handled by inbound stream listener)",
-                session_id, stream_id
-            );
+            info!(%stream_id, ?remote_port, "StreamOpen (handled by inbound stream listener)");
+            let tunnels = state.agent_tunnels.read().await;
+            let Some(info) = tunnels.get(&session_id) else {
+                return;
+            };
+            match remote_port {
+                Some(port) if !info.extra_ports.contains(&port) => {
+                    warn!(%stream_id, port, "Ignoring StreamOpen for a port the tunnel does not forward");
+                }
+                _ if info.extra_ports.is_empty() => {}
+                port => info
+                    .stream_ports
+                    .announce(&stream_id, port.unwrap_or(info.remote_port)),
+            }
         }
 
         // ── Stream Closed by the Other Side ──
@@ -508,27 +1326,850 @@ async fn handle_server_message(
             stream_id: _, // Keep stream_id in pattern for future use or remove completely if not needed
         } => {}
 
+        // ── Controller Side: Agent Could Not Reach the Target ──
+        // The agent closes the data stream itself; tell the user why.
+        ControlMessage::StreamOpenFailed {
+            session_id,
+            stream_id,
+            code,
+            message,
+        } => {
+            warn!(%session_id, %stream_id, "Stream failed to open: {}", message);
+            let _ = app_handle.emit(
+                "stream-open-failed",
+                &StreamOpenFailure {
+                    session_id,
+                    stream_id,
+                    timed_out: code == ErrorCode::Timeout,
+                    limit_reached: code == ErrorCode::LimitExceeded,
+                    message,
+                },
+            );
+        }
+
         // ── Tunnel Closed ──
         // Clean up all resources associated with this tunnel session.
         ControlMessage::TunnelClose { session_id } => {
-            info!("Tunnel closed: {}", session_id);
-            state.abort_session_tasks(&session_id).await;
-            state.agent_tunnels.write().await.remove(&session_id);
-            let mut tunnels = state.tunnels.write().await;
-            tunnels.retain(|t| t.session_id != session_id);
+            info!("Tunnel closed");
+            forget_tunnel(state, app_handle, &session_id).await;
+        }
+
+        // ── Controller Side: Connect Refused ──
+        // The server or the target agent refused a `Connect`; drop the
+        // "connecting" placeholder since the tunnel will never be ready.
+        // A timeout leaves the placeholder as "failed" until the user
+        // dismisses it, so the tunnel does not silently disappear.
+        ControlMessage::ConnectFailed {
+            request_id,
+            code: ErrorCode::Timeout,
+            message,
+        } => {
+            error!("Connect {} timed out: {}", request_id, message);
+            fail_connect(state, app_handle, &request_id, &message).await;
+        }
+        ControlMessage::ConnectFailed {
+            request_id,
+            code,
+            message,
+        } => {
+            error!("Connect {} failed ({:?}): {}", request_id, code, message);
+            state.pending_connects.write().await.remove(&request_id);
+            let group = {
+                let mut tunnels = state.tunnels.write().await;
+                let group = tunnels
+                    .iter()
+                    .find(|t| t.session_id == request_id)
+                    .and_then(|t| t.group.clone());
+                tunnels.retain(|t| t.session_id != request_id);
+                group
+            };
             let _ = app_handle.emit("tunnels-updated", ());
+            let _ = app_handle.emit("server-error", &message);
+            if let Some(group) = group {
+                let _ = app_handle.emit("group-updated", &group);
+            }
         }
 
         // ── Error from Server ──
-        ControlMessage::Error { message } => {
-            error!("Server error: {}", message);
+        // The server refuses this version; reconnecting will not help,
+        // so tell the user instead of failing silently.
+        ControlMessage::Error {
+            code: ErrorCode::UpgradeRequired,
+            message,
+        } => {
+            error!("Upgrade required: {}", message);
+            *state.upgrade_required.write().await = Some(message.clone());
+            let _ = app_handle.emit("upgrade-required", &message);
+        }
+
+        ControlMessage::Error { code, message } => {
+            error!("Server error ({:?}): {}", code, message);
             let _ = app_handle.emit("server-error", &message);
         }
 
+        // ── Controller Side: Someone Asks to Observe Our Tunnel ──
+        // Nothing is shared until the user answers via `respond_observe_request`.
+        ControlMessage::ObserveConsent {
+            session_id,
+            observer_id,
+            observer,
+        } => {
+            info!(%observer, "Observe requested");
+            let request = ObserverRequest {
+                session_id,
+                observer_id,
+                observer,
+            };
+            state.observer_requests.write().await.push(request.clone());
+            let _ = app_handle.emit("observe-request", &request);
+        }
+
+        // ── Observer Side: Fresh Stats for a Watched Session ──
+        ControlMessage::SessionStats { stats } => {
+            let _ = app_handle.emit("session-stats", &stats);
+            state
+                .observed
+                .write()
+                .await
+                .insert(stats.session_id.clone(), stats);
+        }
+
+        // ── Observer Side: Observation Declined or Ended ──
+        ControlMessage::ObserveEnd { session_id, reason } => {
+            info!(%reason, "Stopped observing");
+            state.observed.write().await.remove(&session_id);
+            let _ = app_handle.emit("observed-updated", ());
+            let _ = app_handle.emit("observe-ended", &ObserveEnded { session_id, reason });
+        }
+
+        // ── Reply to a `list_agents` command ──
+        ControlMessage::AgentList { agents } => {
+            // Skip callers that already gave up waiting.
+            let mut waiters = state.agent_list_waiters.lock().await;
+            while let Some(waiter) = waiters.pop_front() {
+                if !waiter.is_closed() {
+                    let _ = waiter.send(agents);
+                    break;
+                }
+            }
+        }
+
+        // ── Agent Side: A Controller Probes a Target ──
+        ControlMessage::ProbeTarget {
+            request_id,
+            host,
+            port,
+            requester,
+            ..
+        } => {
+            tokio::spawn(probe(
+                state.clone(),
+                tx.clone(),
+                request_id,
+                host,
+                port,
+                requester,
+            ));
+        }
+
+        // ── Controller Side: Probe Answered ──
+        ControlMessage::ProbeResult {
+            request_id,
+            latency_ms,
+            message,
+            ..
+        } => {
+            if let Some(waiter) = state.probe_waiters.lock().await.remove(&request_id) {
+                let _ = waiter.send(ProbeReport {
+                    reachable: latency_ms.is_some(),
+                    latency_ms,
+                    message,
+                });
+            }
+        }
+
+        ControlMessage::InviteCreated {
+            request_id,
+            token,
+            expires_at_ms,
+            message,
+            ..
+        } => {
+            if let Some(waiter) = state.invite_waiters.lock().await.remove(&request_id) {
+                let _ = waiter.send(token.map(|t| (t, expires_at_ms)).ok_or_else(|| {
+                    message.unwrap_or_else(|| "The server refused the invitation".to_string())
+                }));
+            }
+        }
+
+        // ── Agent Side: Access Request ──
+        // Held until the user decides, however long that takes; the server
+        // sends it again after a reconnect.
+        ControlMessage::AccessRequested {
+            access_id,
+            requester,
+            remote_host,
+            remote_port,
+            reason,
+            requested_at_ms,
+        } => {
+            let mut requests = state.access_requests.write().await;
+            if requests.iter().any(|r| r.access_id == access_id) {
+                return;
+            }
+            info!(
+                %access_id,
+                requester = requester.as_deref().unwrap_or("anonymous"),
+                remote = %host_port(&remote_host, remote_port),
+                "Access requested"
+            );
+            let request = AccessRequest {
+                access_id,
+                requester,
+                remote_host,
+                remote_port,
+                reason,
+                requested_at_ms,
+            };
+            let _ = app_handle.emit("access-request", &request);
+            requests.push(request);
+        }
+
+        // ── Controller Side: Access Request Progress ──
+        // The first update answers `request_access` or `check_access`; a
+        // decision arrives later on its own.
+        ControlMessage::AccessUpdate {
+            request_id,
+            access_id,
+            state: access_state,
+            invite,
+            expires_at_ms,
+            message,
+            ..
+        } => {
+            let update = AccessUpdate {
+                access_id,
+                state: access_state,
+                invite,
+                expires_at_ms,
+                message,
+            };
+            match state.access_waiters.lock().await.remove(&request_id) {
+                Some(waiter) => {
+                    let _ = waiter.send(update);
+                }
+                None => {
+                    info!(access_id = ?update.access_id, state = ?update.state, "Access decided");
+                    let _ = app_handle.emit("access-update", &update);
+                }
+            }
+        }
+
         // ── Heartbeat ──
-        ControlMessage::Pong => {
-            // No action needed; confirms the connection is alive
+        ControlMessage::Pong { server_time_ms } => {
+            // Confirms the connection is alive and refreshes the skew estimate
+            update_clock_skew(state, app_handle, server_time_ms).await;
+        }
+
+        // ── Agent Side: Controller Measures Latency ──
+        ControlMessage::SessionPing {
+            session_id,
+            sent_ms,
+        } if state.agent_tunnels.read().await.contains_key(&session_id) => {
+            let _ = tx.send(ControlMessage::SessionPong {
+                session_id,
+                sent_ms,
+            });
+        }
+
+        // ── Controller Side: Round Trip Completed ──
+        // `sent_ms` is our own clock, so clock skew does not matter here.
+        ControlMessage::SessionPong {
+            session_id,
+            sent_ms,
+        } => {
+            let rtt_ms = unix_time_ms().saturating_sub(sent_ms);
+            let found = match state
+                .tunnels
+                .write()
+                .await
+                .iter_mut()
+                .find(|t| t.session_id == session_id)
+            {
+                Some(t) => {
+                    t.rtt_ms = Some(rtt_ms);
+                    true
+                }
+                None => false,
+            };
+            if found {
+                debug!(%session_id, rtt_ms, "Session round trip");
+                let _ = app_handle.emit("tunnel-rtt", &TunnelRtt { session_id, rtt_ms });
+            }
         }
         _ => {}
     }
 }
+
+/// Tries a TCP connect to `host:port` for a controller's `ProbeTarget` and
+/// answers with `ProbeResult`. Only targets the agent would serve without
+/// asking are probed: any unless `TUNNEL_AUTO_ACCEPT=0`, otherwise its
+/// advertised services.
+async fn probe(
+    state: Arc<AgentState>,
+    tx: mpsc::UnboundedSender<ControlMessage>,
+    request_id: String,
+    host: String,
+    port: u16,
+    requester: Option<String>,
+) {
+    let target = host_port(&host, port);
+    let requester = requester.as_deref().unwrap_or("anonymous");
+    let advertised = state
+        .services
+        .read()
+        .await
+        .iter()
+        .any(|s| s.port == port && s.host.eq_ignore_ascii_case(&host));
+    let result = |latency_ms, code, message| ControlMessage::ProbeResult {
+        request_id: request_id.clone(),
+        latency_ms,
+        code,
+        message,
+    };
+    let reply = if !state.auto_accept && !advertised {
+        info!(%requester, %target, "Refused probe of a target we do not advertise");
+        result(
+            None,
+            Some(ErrorCode::Unauthorized),
+            Some(format!("The agent does not advertise {}", target)),
+        )
+    } else if host == ECHO_HOST {
+        result(Some(0), None, None)
+    } else {
+        let started = Instant::now();
+        match state
+            .dialer
+            .dial("probe", &host, port, state.connect_timeout)
+            .await
+        {
+            Ok(_) => {
+                let latency_ms = started.elapsed().as_millis() as u64;
+                info!(%requester, %target, latency_ms, "Probe succeeded");
+                result(Some(latency_ms), None, None)
+            }
+            Err(e) => {
+                info!(%requester, %target, "Probe failed: {}", e);
+                let code = if e.kind() == std::io::ErrorKind::TimedOut {
+                    ErrorCode::Timeout
+                } else {
+                    ErrorCode::Internal
+                };
+                result(
+                    None,
+                    Some(code),
+                    Some(format!("Failed to connect to {}: {}", target, e)),
+                )
+            }
+        }
+    };
+    let _ = tx.send(reply);
+}
+
+/// A controller-side listener feeding one tunnel session.
+enum LocalListener {
+    Tcp(TcpListener),
+    /// The socket file is removed when the listener is dropped.
+    #[cfg(unix)]
+    Unix(UnixListener, SocketFile),
+}
+
+/// Removes a Unix socket file once its listener is gone.
+#[cfg(unix)]
+struct SocketFile(PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Binds the controller's local listeners for `pending`: its Unix socket if
+/// one is set, otherwise `port` on IPv4 loopback, plus IPv6 loopback where
+/// available so clients resolving `localhost` to `::1` reach the tunnel too.
+/// An explicit `bind_address` replaces both loopbacks.
+async fn bind_local(pending: &PendingConnect, port: u16) -> std::io::Result<Vec<LocalListener>> {
+    match &pending.local_socket {
+        Some(path) => bind_local_socket(path).map(|listener| vec![listener]),
+        None => bind_tcp(pending.bind_address, port).await,
+    }
+}
+
+/// Binds `port` on `bind_address`, or on both loopbacks when it is `None`.
+async fn bind_tcp(bind_address: Option<IpAddr>, port: u16) -> std::io::Result<Vec<LocalListener>> {
+    if let Some(addr) = bind_address {
+        let listener = TcpListener::bind((addr, port)).await?;
+        if addr.is_loopback() {
+            info!("Listening on {}", listener.local_addr()?);
+        } else {
+            warn!(
+                "Listening on {}: the tunnel is reachable from other machines",
+                listener.local_addr()?
+            );
+        }
+        return Ok(vec![LocalListener::Tcp(listener)]);
+    }
+    let listeners = bind_loopback(port).await?;
+    Ok(listeners.into_iter().map(LocalListener::Tcp).collect())
+}
+
+/// Binds `port` on IPv4 loopback, and on IPv6 loopback where available.
+pub(crate) async fn bind_loopback(port: u16) -> std::io::Result<Vec<TcpListener>> {
+    let mut listeners = vec![TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await?];
+    match TcpListener::bind((Ipv6Addr::LOCALHOST, port)).await {
+        Ok(listener) => listeners.push(listener),
+        Err(e) => debug!("IPv6 loopback unavailable on port {}: {}", port, e),
+    }
+    for listener in &listeners {
+        if let Ok(addr) = listener.local_addr() {
+            info!("Listening on {}", addr);
+        }
+    }
+    Ok(listeners)
+}
+
+/// Listens on the Unix socket at `path`, readable and writable by the
+/// current user only, since anyone who can open it can use the tunnel.
+///
+/// The socket is bound inside a fresh 0700 directory next to `path`, made
+/// 0600, and only then linked to `path`, so no other user can connect to
+/// it before its mode is set. Linking fails if `path` exists, as binding
+/// it directly would.
+#[cfg(unix)]
+fn bind_local_socket(path: &Path) -> std::io::Result<LocalListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "socket path has no file name",
+        )
+    })?;
+    let staging = path.with_file_name(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        uuid::Uuid::new_v4().simple()
+    ));
+    std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("socket");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::hard_link(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&staged);
+    let _ = std::fs::remove_dir(&staging);
+    let listener = bound?;
+    info!("Listening on {}", path.display());
+    Ok(LocalListener::Unix(
+        listener,
+        SocketFile(path.to_path_buf()),
+    ))
+}
+
+#[cfg(not(unix))]
+fn bind_local_socket(_path: &Path) -> std::io::Result<LocalListener> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Unix sockets are not supported on this platform",
+    ))
+}
+
+/// Starts one more local listener for the established outgoing tunnel
+/// `session_id`, on `local_port` of the loopback addresses. Its streams go
+/// to `remote_port`, or to the tunnel's own port when `None`.
+pub(crate) async fn add_listener(
+    state: &Arc<AgentState>,
+    session_id: &str,
+    local_port: u16,
+    remote_port: Option<u16>,
+    nodelay: bool,
+) -> Result<(), String> {
+    let tx = state.ctrl_tx.read().await.clone().ok_or("Not connected")?;
+    let connection = state
+        .connection
+        .read()
+        .await
+        .clone()
+        .ok_or("Not connected")?;
+    let span = info_span!("session", session_id = %session_id);
+    let listeners = bind_tcp(None, local_port)
+        .instrument(span.clone())
+        .await
+        .map_err(|e| format!("Port {} unavailable: {}", local_port, e))?;
+
+    let mut handles = state.task_handles.write().await;
+    let session_handles = handles.entry(session_id.to_string()).or_default();
+    for listener in listeners {
+        session_handles.push(tokio::spawn(
+            accept_local(
+                listener,
+                remote_port,
+                nodelay,
+                connection.clone(),
+                tx.clone(),
+                state.clone(),
+                session_id.to_string(),
+            )
+            .instrument(span.clone()),
+        ));
+    }
+    Ok(())
+}
+
+/// Accept loop of a local listener: each new connection becomes a new
+/// "stream" within the tunnel session, going to `remote_port` when the
+/// listener serves one of the tunnel's extra ports.
+async fn accept_local(
+    listener: LocalListener,
+    remote_port: Option<u16>,
+    nodelay: bool,
+    connection: quinn::Connection,
+    tx: mpsc::UnboundedSender<ControlMessage>,
+    state: Arc<AgentState>,
+    sid: String,
+) {
+    loop {
+        let opened = match &listener {
+            LocalListener::Tcp(listener) => match listener.accept().await {
+                Ok((stream, peer)) => {
+                    if let Err(e) = stream.set_nodelay(nodelay) {
+                        debug!("Failed to set TCP_NODELAY: {}", e);
+                    }
+                    open_local_stream(
+                        stream,
+                        &peer.to_string(),
+                        remote_port,
+                        &connection,
+                        &tx,
+                        &state,
+                        &sid,
+                    )
+                    .await
+                }
+                Err(e) => {
+                    error!("Accept error: {}", e);
+                    false
+                }
+            },
+            #[cfg(unix)]
+            LocalListener::Unix(listener, _) => match listener.accept().await {
+                Ok((stream, _)) => {
+                    open_local_stream(
+                        stream,
+                        "unix socket",
+                        remote_port,
+                        &connection,
+                        &tx,
+                        &state,
+                        &sid,
+                    )
+                    .await
+                }
+                Err(e) => {
+                    error!("Accept error: {}", e);
+                    false
+                }
+            },
+        };
+        if !opened {
+            break;
+        }
+    }
+}
+
+/// Opens a QUIC data stream for a newly accepted local connection and relays
+/// it. Returns `false` once the QUIC connection can no longer open streams.
+async fn open_local_stream<S>(
+    local_stream: S,
+    peer: &str,
+    remote_port: Option<u16>,
+    connection: &quinn::Connection,
+    tx: &mpsc::UnboundedSender<ControlMessage>,
+    state: &Arc<AgentState>,
+    sid: &str,
+) -> bool
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    // Draw a stream ID not used by our other streams in this session
+    let stream_ids = state.outgoing_streams(sid).await;
+    // Streams beyond the agreed limit would only be refused by the relay,
+    // so the local connection is dropped right away.
+    if let Some(max_streams) = state.stream_limit(sid).await {
+        if stream_ids.count() >= max_streams {
+            warn!(%peer, max_streams, "Dropping connection: tunnel stream limit reached");
+            return true;
+        }
+    }
+    let stream_id = stream_ids.claim_new();
+    state
+        .session_traffic(sid)
+        .await
+        .streams
+        .fetch_add(1, Ordering::Relaxed);
+    let stream_span = info_span!("stream", stream_id = %stream_id);
+    info!(parent: &stream_span, %peer, "New stream");
+
+    let _quic_send = match connection.open_bi().await {
+        Ok((tx, _rx)) => tx,
+        Err(e) => {
+            error!("Failed to open QUIC data stream: {}", e);
+            stream_ids.release(&stream_id);
+            return false;
+        }
+    };
+
+    let tx2 = tx.clone();
+    let st2 = state.clone();
+    let sid2 = sid.to_string();
+
+    // A new QUIC stream means we need to open it and then send
+    // the `Data` protocol prefix so the server knows where to route it.
+    let conn2 = connection.clone();
+    tokio::spawn(
+        async move {
+            match conn2.open_bi().await {
+                Ok((mut q_send, q_recv)) => {
+                    let priority = st2.traffic_class(&sid2).await.stream_priority();
+                    let _ = q_send.set_priority(priority);
+                    // Tell the agent to open its TCP connection.
+                    let _ = tx2.send(ControlMessage::StreamOpen {
+                        session_id: sid2.clone(),
+                        stream_id: stream_id.clone(),
+                        remote_port,
+                    });
+
+                    let prefix = data_prefix(&sid2, &stream_id);
+                    if q_send.write_all(&prefix).await.is_ok() {
+                        let capture = st2.stream_capture(&sid2, remote_port).await;
+                        handle_stream_relay(
+                            local_stream,
+                            sid2,
+                            stream_id.clone(),
+                            q_send,
+                            q_recv,
+                            tx2,
+                            st2,
+                            capture,
+                        )
+                        .await;
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to open QUIC bi-stream: {}", e)
+                }
+            }
+            stream_ids.release(&stream_id);
+        }
+        .instrument(stream_span),
+    );
+    true
+}
+
+/// The routing prefix of a data stream we open: 0x0A, then the session and
+/// stream IDs, each padded or cut to 8 bytes.
+pub(crate) fn data_prefix(session_id: &str, stream_id: &str) -> Vec<u8> {
+    let mut prefix = vec![0x0A]; // TAG_DATA
+    for id in [session_id, stream_id] {
+        let mut bytes = [0u8; 8];
+        let len = id.len().min(8);
+        bytes[..len].copy_from_slice(&id.as_bytes()[..len]);
+        prefix.extend_from_slice(&bytes);
+    }
+    prefix
+}
+
+/// Refuses an incoming data stream with the reset `code`.
+fn reset_stream(mut send: SendStream, recv: &mut RecvStream, code: ResetCode) {
+    let code = VarInt::from_u32(code);
+    let _ = recv.stop(code);
+    let _ = send.reset(code);
+}
+
+/// Dials the agent-side target of a tunnel, its Unix socket or
+/// `remote_host:remote_port`, and relays the data stream to it. The
+/// [`ECHO_HOST`] target is served in-process and never dialed.
+/// Returns the dial error; the caller then closes the stream.
+async fn dial_and_relay(
+    state: Arc<AgentState>,
+    info: &AgentTunnelInfo,
+    session_id: String,
+    stream_id: String,
+    send: SendStream,
+    recv: RecvStream,
+    tx: mpsc::UnboundedSender<ControlMessage>,
+) -> std::io::Result<()> {
+    let _ = send.set_priority(info.traffic_class.stream_priority());
+    let capture = state
+        .stream_capture(&session_id, Some(info.remote_port))
+        .await;
+    match &info.remote_socket {
+        #[cfg(unix)]
+        Some(path) => {
+            let stream = state
+                .dialer
+                .dial_unix(&session_id, path, info.connect_timeout)
+                .await?;
+            info!("Connected to local target");
+            handle_stream_relay(
+                stream, session_id, stream_id, send, recv, tx, state, capture,
+            )
+            .await;
+        }
+        #[cfg(not(unix))]
+        Some(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Unix sockets are not supported on this platform",
+            ))
+        }
+        None if info.remote_host == ECHO_HOST => {
+            info!("Echoing stream");
+            handle_stream_relay(
+                echo_stream(),
+                session_id,
+                stream_id,
+                send,
+                recv,
+                tx,
+                state,
+                capture,
+            )
+            .await;
+        }
+        None => {
+            let stream = state
+                .dialer
+                .dial(
+                    &session_id,
+                    &info.remote_host,
+                    info.remote_port,
+                    info.connect_timeout,
+                )
+                .await?;
+            info!("Connected to local target");
+            handle_stream_relay(
+                stream, session_id, stream_id, send, recv, tx, state, capture,
+            )
+            .await;
+        }
+    }
+    Ok(())
+}
+
+/// One end of an in-memory pipe whose other end writes back whatever it
+/// reads, serving the [`ECHO_HOST`] target.
+fn echo_stream() -> tokio::io::DuplexStream {
+    let (local, remote) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(async move {
+        let (mut read, mut write) = tokio::io::split(remote);
+        let _ = tokio::io::copy(&mut read, &mut write).await;
+    });
+    local
+}
+
+/// Turns the "connecting" placeholder of a public tunnel into an active
+/// tunnel once the relay confirms it, and records the target the agent
+/// dials for the data streams that follow.
+async fn activate_public_tunnel(
+    state: &AgentState,
+    tx: &mpsc::UnboundedSender<ControlMessage>,
+    app_handle: &tauri::AppHandle,
+    request_id: &str,
+    session_id: String,
+    publish: impl FnOnce(&mut TunnelInfo),
+) {
+    let target = {
+        let mut tunnels = state.tunnels.write().await;
+        tunnels
+            .iter_mut()
+            .find(|t| t.session_id == request_id)
+            .map(|t| {
+                t.session_id = session_id.clone();
+                t.status = "active".to_string();
+                publish(t);
+                (t.remote_host.clone(), t.remote_port)
+            })
+    };
+    match target {
+        Some((remote_host, remote_port)) => {
+            state.stats.lock().await.tunnel_opened();
+            state.agent_tunnels.write().await.insert(
+                session_id,
+                AgentTunnelInfo {
+                    remote_host,
+                    remote_port,
+                    remote_socket: None,
+                    requester: None,
+                    streams: Arc::default(),
+                    connect_timeout: state.connect_timeout,
+                    extra_ports: Vec::new(),
+                    stream_ports: Arc::default(),
+                    reverse_socks: false,
+                    traffic_class: TrafficClass::default(),
+                },
+            );
+            let _ = app_handle.emit("tunnels-updated", ());
+        }
+        None => {
+            // The user removed the placeholder before the server answered.
+            let _ = tx.send(ControlMessage::TunnelClose { session_id });
+        }
+    }
+}
+
+/// Sends a `SessionPing` for `session_id` every [`SESSION_PING_INTERVAL`]
+/// until the tunnel closes and aborts this task.
+async fn ping_session(tx: mpsc::UnboundedSender<ControlMessage>, session_id: String) {
+    let mut interval = tokio::time::interval(SESSION_PING_INTERVAL);
+    loop {
+        interval.tick().await;
+        let ping = ControlMessage::SessionPing {
+            session_id: session_id.clone(),
+            sent_ms: unix_time_ms(),
+        };
+        if tx.send(ping).is_err() {
+            break;
+        }
+    }
+}
+
+/// Updates the clock skew estimate from a server timestamp and warns when
+/// it is large enough to break token expiry or scheduled tunnels. The
+/// round trip of the probe is recorded for connection quality.
+async fn update_clock_skew(state: &AgentState, app_handle: &tauri::AppHandle, server_time_ms: u64) {
+    let received_ms = unix_time_ms();
+    let probe = state.probe_sent_ms.lock().await.take();
+    if let Some(sent_ms) = probe {
+        state
+            .quality
+            .lock()
+            .await
+            .record_rtt(received_ms.saturating_sub(sent_ms));
+    }
+    let sent_ms = probe.unwrap_or(received_ms);
+    let skew = estimate_clock_skew_ms(sent_ms, server_time_ms, received_ms);
+
+    let previous = state.clock_skew_ms.write().await.replace(skew);
+    if skew.abs() > CLOCK_SKEW_WARN_MS {
+        warn!(
+            "Local clock differs from the server by {} ms; token expiry and scheduled tunnels may misbehave",
+            skew
+        );
+    }
+    if previous != Some(skew) {
+        let _ = app_handle.emit("clock-skew", skew);
+    }
+}
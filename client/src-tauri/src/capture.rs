@@ -0,0 +1,308 @@
+//! # Traffic Capture
+//!
+//! Writes the bytes a tunnel relays into a pcapng file that Wireshark can
+//! open. The tunnel carries plain byte streams, so each stream is written
+//! as a synthetic TCP connection between `10.0.0.1` (the controller's
+//! application) and `10.0.0.2` (the target on the agent's side), with a
+//! handshake, one segment per relayed chunk and a FIN when a direction
+//! ends. Sequence numbers follow the bytes, so "Follow TCP Stream" shows
+//! the conversation as the application saw it.
+//!
+//! Packets are encoded on the relay tasks and written by a dedicated
+//! thread, so a slow disk never holds up the tunnel.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{error, info};
+
+/// Address standing for the controller's application in captures.
+const CLIENT_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+/// Address standing for the agent-side target in captures.
+const SERVER_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+/// First synthetic client port; each stream takes the next one.
+const FIRST_CLIENT_PORT: u16 = 40000;
+
+/// Most payload one synthetic IPv4 packet carries.
+const MAX_SEGMENT: usize = u16::MAX as usize - 40;
+
+/// pcapng link type for raw IPv4/IPv6 packets without a link header.
+const LINKTYPE_RAW: u16 = 101;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+/// An open capture file for one tunnel session.
+#[derive(Debug)]
+pub struct Capture {
+    /// Queue to the writer thread; `None` once the capture is stopped.
+    tx: Mutex<Option<mpsc::Sender<Vec<u8>>>>,
+    /// Whether this end of the tunnel is the controller, whose local
+    /// connections are the client side of each flow.
+    local_is_client: bool,
+    /// Agent-side port of streams that do not name one.
+    remote_port: u16,
+    next_client_port: AtomicU16,
+    packets: AtomicU64,
+}
+
+impl Capture {
+    /// Creates `path`, writes the pcapng header and starts the writer thread.
+    pub fn create(path: &Path, local_is_client: bool, remote_port: u16) -> Result<Self, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+        let mut writer = BufWriter::new(file);
+        writer
+            .write_all(&section_header())
+            .and_then(|_| writer.write_all(&interface_description()))
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+        let (tx, rx) = mpsc::channel::<Vec<u8>>();
+        let display = path.display().to_string();
+        std::thread::Builder::new()
+            .name("capture-writer".to_string())
+            .spawn(move || write_loop(writer, rx, display))
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            tx: Mutex::new(Some(tx)),
+            local_is_client,
+            remote_port,
+            next_client_port: AtomicU16::new(FIRST_CLIENT_PORT),
+            packets: AtomicU64::new(0),
+        })
+    }
+
+    /// Starts a flow for a new stream to agent-side `remote_port`, or the
+    /// tunnel's own port when `None`, and writes its handshake.
+    pub fn stream(self: &Arc<Self>, remote_port: Option<u16>) -> Arc<StreamCapture> {
+        let client_port = self.next_client_port.fetch_add(1, Ordering::Relaxed);
+        let stream = Arc::new(StreamCapture {
+            capture: self.clone(),
+            flow: Mutex::new(Flow {
+                client_port: client_port.max(FIRST_CLIENT_PORT),
+                server_port: remote_port.unwrap_or(self.remote_port),
+                client_seq: 0,
+                server_seq: 0,
+            }),
+        });
+        stream.handshake();
+        stream
+    }
+
+    /// Stops writing; the file is flushed and closed once queued packets
+    /// are written. Returns the number of packets captured.
+    pub fn stop(&self) -> u64 {
+        self.tx.lock().unwrap().take();
+        self.packets.load(Ordering::Relaxed)
+    }
+
+    fn send(&self, block: Vec<u8>) {
+        if let Some(tx) = self.tx.lock().unwrap().as_ref() {
+            if tx.send(block).is_ok() {
+                self.packets.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// The synthetic TCP connection of one captured stream.
+#[derive(Debug)]
+pub struct StreamCapture {
+    capture: Arc<Capture>,
+    flow: Mutex<Flow>,
+}
+
+#[derive(Debug)]
+struct Flow {
+    client_port: u16,
+    server_port: u16,
+    /// Next sequence number of each side.
+    client_seq: u32,
+    server_seq: u32,
+}
+
+impl StreamCapture {
+    /// Records `data` read from the local connection (`from_local`) or
+    /// from the tunnel.
+    pub fn record(&self, from_local: bool, data: &[u8]) {
+        let from_client = from_local == self.capture.local_is_client;
+        for segment in data.chunks(MAX_SEGMENT) {
+            self.segment(from_client, TCP_PSH | TCP_ACK, segment);
+        }
+    }
+
+    /// Records the end of one direction as a FIN.
+    pub fn finish(&self, from_local: bool) {
+        let from_client = from_local == self.capture.local_is_client;
+        self.segment(from_client, TCP_FIN | TCP_ACK, &[]);
+    }
+
+    fn handshake(&self) {
+        self.segment(true, TCP_SYN, &[]);
+        self.segment(false, TCP_SYN | TCP_ACK, &[]);
+        self.segment(true, TCP_ACK, &[]);
+    }
+
+    /// Encodes one packet and advances the sender's sequence number by
+    /// its payload, plus one for a SYN or FIN.
+    fn segment(&self, from_client: bool, flags: u8, payload: &[u8]) {
+        let packet = {
+            let mut flow = self.flow.lock().unwrap();
+            let (seq, ack) = if from_client {
+                (flow.client_seq, flow.server_seq)
+            } else {
+                (flow.server_seq, flow.client_seq)
+            };
+            let packet = if from_client {
+                tcp_packet(
+                    (CLIENT_ADDR, flow.client_port),
+                    (SERVER_ADDR, flow.server_port),
+                    seq,
+                    ack,
+                    flags,
+                    payload,
+                )
+            } else {
+                tcp_packet(
+                    (SERVER_ADDR, flow.server_port),
+                    (CLIENT_ADDR, flow.client_port),
+                    seq,
+                    ack,
+                    flags,
+                    payload,
+                )
+            };
+            let advance = payload.len() as u32 + u32::from(flags & (TCP_SYN | TCP_FIN) != 0);
+            let next = if from_client {
+                &mut flow.client_seq
+            } else {
+                &mut flow.server_seq
+            };
+            *next = next.wrapping_add(advance);
+            packet
+        };
+        self.capture.send(enhanced_packet(&packet));
+    }
+}
+
+fn write_loop(mut writer: BufWriter<File>, rx: mpsc::Receiver<Vec<u8>>, path: String) {
+    info!("Capturing tunnel traffic to {}", path);
+    while let Ok(block) = rx.recv() {
+        let mut result = writer.write_all(&block);
+        // Flush whenever the queue runs dry, so the file can be opened
+        // while the capture is still running.
+        while let (Ok(()), Ok(block)) = (&result, rx.try_recv()) {
+            result = writer.write_all(&block);
+        }
+        if let Err(e) = result.and_then(|_| writer.flush()) {
+            error!("Failed to write capture {}: {}", path, e);
+            return;
+        }
+    }
+    info!("Capture {} closed", path);
+}
+
+/// Section Header Block: little-endian, version 1.0, unknown length.
+fn section_header() -> Vec<u8> {
+    let mut body = Vec::with_capacity(16);
+    body.extend_from_slice(&0x1A2B_3C4Du32.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    body.extend_from_slice(&(-1i64).to_le_bytes());
+    block(0x0A0D_0D0A, &body)
+}
+
+/// Interface Description Block for raw IP with microsecond timestamps.
+fn interface_description() -> Vec<u8> {
+    let mut body = Vec::with_capacity(8);
+    body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    body.extend_from_slice(&0u32.to_le_bytes());
+    block(1, &body)
+}
+
+/// Enhanced Packet Block holding `packet`, stamped with the current time.
+fn enhanced_packet(packet: &[u8]) -> Vec<u8> {
+    let micros = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or_default();
+    let mut body = Vec::with_capacity(20 + packet.len() + 3);
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(micros as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(packet);
+    body.resize(body.len().next_multiple_of(4), 0);
+    block(6, &body)
+}
+
+/// Frames `body` as a pcapng block of `block_type`.
+fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    let total = (body.len() + 12) as u32;
+    let mut out = Vec::with_capacity(total as usize);
+    out.extend_from_slice(&block_type.to_le_bytes());
+    out.extend_from_slice(&total.to_le_bytes());
+    out.extend_from_slice(body);
+    out.extend_from_slice(&total.to_le_bytes());
+    out
+}
+
+/// An IPv4 packet with a TCP segment, both checksums filled in.
+fn tcp_packet(
+    (src, src_port): (Ipv4Addr, u16),
+    (dst, dst_port): (Ipv4Addr, u16),
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let tcp_len = 20 + payload.len();
+    let mut packet = Vec::with_capacity(20 + tcp_len);
+
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&((20 + tcp_len) as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+    packet.extend_from_slice(&src.octets());
+    packet.extend_from_slice(&dst.octets());
+    let ip_checksum = checksum(0, &packet[..20]);
+    packet[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+
+    packet.extend_from_slice(&src_port.to_be_bytes());
+    packet.extend_from_slice(&dst_port.to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(&ack.to_be_bytes());
+    packet.extend_from_slice(&[5 << 4, flags, 0xFF, 0xFF, 0, 0, 0, 0]);
+    packet.extend_from_slice(payload);
+
+    let mut pseudo = 0u32;
+    for word in src.octets().chunks(2).chain(dst.octets().chunks(2)) {
+        pseudo += u32::from(u16::from_be_bytes([word[0], word[1]]));
+    }
+    pseudo += 6 + tcp_len as u32;
+    let tcp_checksum = checksum(pseudo, &packet[20..]);
+    packet[36..38].copy_from_slice(&tcp_checksum.to_be_bytes());
+    packet
+}
+
+/// Internet checksum of `data`, starting from the partial sum `sum`.
+fn checksum(mut sum: u32, data: &[u8]) -> u16 {
+    for word in data.chunks(2) {
+        let hi = u32::from(word[0]) << 8;
+        let lo = word.get(1).copied().map_or(0, u32::from);
+        sum += hi | lo;
+        while sum > 0xFFFF {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+    }
+    !(sum as u16)
+}
@@ -4,13 +4,57 @@
 //! Each `#[tauri::command]` function can be called from JavaScript using
 //! `invoke("command_name", { args })`.
 
-use crate::state::{AgentState, AgentStatus, PendingConnect, TunnelInfo};
+use crate::agent;
+use crate::capture::Capture;
+use crate::deeplink;
+use crate::history::HistoryEntry;
+use crate::identity::IdentityInfo;
+use crate::known_agents::{IdentityChange, KnownAgent};
+use crate::logs::{self, LogBuffer, LogEntry, LogLevel, DEFAULT_LOG_LIMIT};
+use crate::oidc::{self, DeviceLogin, SsoSettings};
+use crate::pairing::{self, PairingCode, PairingPayload, PAIRING_TTL};
+use crate::presets::{PresetTemplate, PRESETS};
+use crate::profiles::{ConfigExport, ConflictPolicy, ImportSummary, TunnelProfile, EXPORT_VERSION};
+use crate::quality::ConnectionQuality;
+use crate::resolver::{Resolver, ResolverConfig};
+use crate::schedule::{ScheduleStatus, TunnelSchedule};
+use crate::settings::{self, Settings};
+use crate::socks::EgressPolicy;
+use crate::state::{
+    parse_services, parse_tags, AccessLogEntry, AccessRequest, AccessUpdate, AgentState,
+    AgentStatus, BufferStats, DiscoveredServer, GroupStatus, Invitation, ObserverRequest,
+    PendingConnect, PortPair, ProbeReport, TunnelApproval, TunnelInfo, CLOCK_SKEW_WARN_MS,
+};
+use crate::stats::AgentStats;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::Arc;
-use tauri::Emitter;
-use tracing::info;
-use tunnel_protocol::ControlMessage;
+use std::time::{Duration, Instant};
+use tauri::{Emitter, Manager};
+use tauri_plugin_opener::OpenerExt;
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+use tunnel_protocol::{
+    find_service, host_port, normalize_host, unix_time_ms, AgentSummary, ControlMessage,
+    SessionSnapshot, TrafficClass, MAX_EXTRA_PORTS, MAX_LABEL_LEN, MDNS_SERVICE_TYPE,
+};
 use uuid::Uuid;
 
+/// How long `list_agents` waits for the server's reply.
+const LIST_AGENTS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long `create_invite` waits for the server's reply.
+const INVITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long `request_access` and `check_access` wait for the server's reply.
+const ACCESS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long `probe_target` waits for the agent's answer.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long `discover_servers` listens for mDNS announcements.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
 /// Returns the current agent status (ID, connection state, server URL).
 ///
 /// Called by the frontend on startup to display the agent's identity
@@ -22,13 +66,100 @@ pub async fn get_agent_info(
     let connected = *state.connected.read().await;
     let server_url = state.server_url.read().await.clone();
     let agent_id = state.agent_id.read().await.clone();
+    let tags = state.tags.read().await.clone();
+    let name = state.name.read().await.clone();
+    let clock_skew_ms = *state.clock_skew_ms.read().await;
     Ok(AgentStatus {
         agent_id,
         connected,
         server_url,
+        fallback_servers: state.fallback_servers.read().await.clone(),
+        relay: state.relay.read().await.clone(),
+        tags,
+        name,
+        clock_skew_ms,
+        clock_skew_warning: clock_skew_ms.is_some_and(|s| s.abs() > CLOCK_SKEW_WARN_MS),
+        sso_issuer: oidc::issuer(&state).await,
+        server_version: state.server_version.read().await.clone(),
+        upgrade_required: state.upgrade_required.read().await.clone(),
+        banned: state.banned.read().await.clone(),
     })
 }
 
+/// Returns diagnostics for the relay connection: reconnects, recent
+/// disconnect reasons, heartbeat round trips and jitter, missed
+/// heartbeats and QUIC packet loss.
+#[tauri::command]
+pub async fn get_connection_quality(
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<ConnectionQuality, String> {
+    let connection = state.connection.read().await.clone();
+    Ok(state.quality.lock().await.report(connection.as_ref()))
+}
+
+/// Returns totals since the app started: uptime, reconnects, bytes
+/// relayed and tunnels opened today.
+#[tauri::command]
+pub async fn get_stats(state: tauri::State<'_, Arc<AgentState>>) -> Result<AgentStats, String> {
+    let quality = state.quality.lock().await.report(None);
+    let live: Vec<_> = state
+        .session_traffic
+        .read()
+        .await
+        .values()
+        .cloned()
+        .collect();
+    Ok(state.stats.lock().await.report(
+        live.iter().map(|c| c.as_ref()),
+        quality.connected,
+        quality.reconnects,
+    ))
+}
+
+/// Returns up to `limit` (default 200) of the newest log entries at
+/// `level` (default "info") or more severe, oldest first.
+#[tauri::command]
+pub async fn get_recent_logs(
+    level: Option<String>,
+    limit: Option<usize>,
+    logs: tauri::State<'_, LogBuffer>,
+) -> Result<Vec<LogEntry>, String> {
+    let level = match level {
+        Some(level) => logs::parse_level(&level)?,
+        None => tracing::Level::INFO,
+    };
+    Ok(logs.recent(level, limit.unwrap_or(DEFAULT_LOG_LIMIT)))
+}
+
+/// Returns the level logs are recorded at, e.g. "info".
+#[tauri::command]
+pub async fn get_log_level(log_level: tauri::State<'_, LogLevel>) -> Result<String, String> {
+    Ok(log_level.current())
+}
+
+/// Changes the level logs are recorded at ("error", "warn", "info",
+/// "debug", "trace" or "off") until the app quits.
+#[tauri::command]
+pub async fn set_log_level(
+    level: String,
+    log_level: tauri::State<'_, LogLevel>,
+) -> Result<(), String> {
+    log_level.set(&level)?;
+    warn!("Log level set to {}", log_level.current());
+    Ok(())
+}
+
+/// Writes all buffered log entries to `path`, one per line, and returns
+/// how many were written.
+#[tauri::command]
+pub async fn export_logs(path: String, logs: tauri::State<'_, LogBuffer>) -> Result<usize, String> {
+    let count = logs
+        .export(std::path::Path::new(&path))
+        .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+    info!("Exported {} log entries to {}", count, path);
+    Ok(count)
+}
+
 /// Updates the relay server URL.
 ///
 /// The new URL takes effect on the next connection attempt.
@@ -41,16 +172,503 @@ pub async fn set_server_url(
 ) -> Result<(), String> {
     info!("Server URL updated to: {}", url);
     *state.server_url.write().await = url;
+    if state.banned.read().await.is_some() {
+        state.reconnect_now.notify_one();
+    }
+    Ok(())
+}
+
+/// Sets the relays to fail over to, from a comma-separated list such as
+/// `"relay2.example.com:7070, relay3.example.com:7070"`. When the server
+/// URL cannot be reached or the connection to it is lost, the client tries
+/// them in order. Tunnels are not carried over: they close with the lost
+/// connection, and autostart profiles reopen on the new relay.
+#[tauri::command]
+pub async fn set_fallback_servers(
+    servers: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<(), String> {
+    let servers = parse_tags(&servers);
+    info!("Fallback servers updated to: {:?}", servers);
+    *state.fallback_servers.write().await = servers;
+    Ok(())
+}
+
+/// Looks for relay servers advertising themselves over mDNS on the local
+/// network. Listens for a few seconds and returns one entry per address
+/// found; pass an entry's `address` to `set_server_url` to use it.
+#[tauri::command]
+pub async fn discover_servers() -> Result<Vec<DiscoveredServer>, String> {
+    let daemon =
+        mdns_sd::ServiceDaemon::new().map_err(|e| format!("Failed to start mDNS: {}", e))?;
+    let events = daemon
+        .browse(MDNS_SERVICE_TYPE)
+        .map_err(|e| format!("Failed to browse: {}", e))?;
+
+    let mut servers: Vec<DiscoveredServer> = Vec::new();
+    let deadline = tokio::time::Instant::now() + DISCOVERY_TIMEOUT;
+    while let Ok(Ok(event)) = tokio::time::timeout_at(deadline, events.recv_async()).await {
+        let mdns_sd::ServiceEvent::ServiceResolved(info) = event else {
+            continue;
+        };
+        let name = info
+            .get_fullname()
+            .strip_suffix(MDNS_SERVICE_TYPE)
+            .unwrap_or(info.get_fullname())
+            .trim_end_matches('.')
+            .to_string();
+        let version = info.get_property_val_str("version").map(str::to_string);
+        for ip in info.get_addresses() {
+            let address = SocketAddr::new(*ip, info.get_port()).to_string();
+            if !servers.iter().any(|s| s.address == address) {
+                servers.push(DiscoveredServer {
+                    name: name.clone(),
+                    address,
+                    version: version.clone(),
+                });
+            }
+        }
+    }
+
+    let _ = daemon.stop_browse(MDNS_SERVICE_TYPE);
+    let _ = daemon.shutdown();
+    info!("Discovered {} relay address(es) on the LAN", servers.len());
+    Ok(servers)
+}
+
+/// Sets the credential sent to the relay server during registration.
+///
+/// Pass `None` to register anonymously. Like the server URL, the new
+/// token takes effect on the next connection attempt.
+#[tauri::command]
+pub async fn set_auth_token(
+    token: Option<String>,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<(), String> {
+    info!(
+        "Auth token {}",
+        if token.is_some() {
+            "updated"
+        } else {
+            "cleared"
+        }
+    );
+    *state.auth_token.write().await = token;
+    // A banned client waits for a new credential before it tries again.
+    if state.banned.read().await.is_some() {
+        state.reconnect_now.notify_one();
+    }
+    Ok(())
+}
+
+/// Starts an SSO login with the device authorization flow and opens the
+/// provider's verification page in the browser.
+///
+/// Settings not given default to the `TUNNEL_OIDC_*` environment
+/// variables. Returns the code the user has to confirm; `sso-logged-in`
+/// follows once they have, and the new credential is used from the next
+/// connection to the relay.
+#[tauri::command]
+pub async fn sso_login(
+    issuer: Option<String>,
+    client_id: Option<String>,
+    scope: Option<String>,
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<DeviceLogin, String> {
+    let settings = SsoSettings::resolve(issuer, client_id, scope)?;
+    let login = oidc::start_login(&state, &app_handle, settings).await?;
+    let page = login
+        .verification_uri_complete
+        .as_ref()
+        .unwrap_or(&login.verification_uri);
+    if let Err(e) = app_handle.opener().open_url(page, None::<&str>) {
+        warn!("Failed to open {}: {}", page, e);
+    }
+    Ok(login)
+}
+
+/// Signs out of SSO and clears the credential it provided.
+#[tauri::command]
+pub async fn sso_logout(state: tauri::State<'_, Arc<AgentState>>) -> Result<(), String> {
+    oidc::logout(&state).await;
+    Ok(())
+}
+
+/// Sets the tags this agent registers with, from a comma-separated list
+/// such as `"env=prod, site=hanoi"`. Takes effect on the next connection.
+#[tauri::command]
+pub async fn set_agent_tags(
+    tags: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<(), String> {
+    let tags = parse_tags(&tags);
+    info!("Agent tags updated to: {:?}", tags);
+    *state.tags.write().await = tags;
+    Ok(())
+}
+
+/// Sets the name this agent registers with. Controllers can pass it as
+/// `target_id` instead of the agent ID. Takes effect on the next connection.
+#[tauri::command]
+pub async fn set_agent_name(
+    name: Option<String>,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<(), String> {
+    let name = name.map(|n| n.trim().to_string()).filter(|n| !n.is_empty());
+    info!("Agent name updated to: {:?}", name);
+    *state.name.write().await = name;
+    Ok(())
+}
+
+/// Sets the services this agent advertises, from a comma-separated list
+/// such as `"ssh=127.0.0.1:22, grafana=127.0.0.1:3000"`. Takes effect on
+/// the next connection.
+#[tauri::command]
+pub async fn set_agent_services(
+    services: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<(), String> {
+    let services = parse_services(&services)?;
+    info!("Agent services updated to: {:?}", services);
+    *state.services.write().await = services;
+    Ok(())
+}
+
+/// Returns the agent ID backed by this install's identity key, and the
+/// key's public half.
+#[tauri::command]
+pub async fn get_identity(
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<IdentityInfo, String> {
+    let identity = state.identity.read().await;
+    let identity = identity.as_ref().ok_or("No identity key")?;
+    Ok(identity.info())
+}
+
+/// Saves a copy of the identity key to `path`. Keep it private: whoever
+/// holds it can register as this agent.
+#[tauri::command]
+pub async fn export_identity(
+    path: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<(), String> {
+    let identity = state.identity.read().await;
+    let identity = identity.as_ref().ok_or("No identity key")?;
+    identity.export(std::path::Path::new(&path))?;
+    info!("Exported identity key to {}", path);
+    Ok(())
+}
+
+/// Replaces the identity key with a backup from `path`, e.g. after a
+/// reinstall, and reconnects so the agent registers under the restored ID.
+/// Open tunnels are dropped.
+#[tauri::command]
+pub async fn import_identity(
+    path: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<IdentityInfo, String> {
+    let info = {
+        let mut identity = state.identity.write().await;
+        let identity = identity.as_mut().ok_or("No identity key")?;
+        identity.import(std::path::Path::new(&path))?;
+        identity.info()
+    };
+    info!(agent_id = %info.agent_id, "Imported identity key from {}", path);
+    if state.agent_key.is_none() {
+        if let Some(connection) = state.connection.read().await.as_ref() {
+            connection.close(0u32.into(), b"identity changed");
+        }
+        state.reconnect_now.notify_one();
+    }
+    Ok(info)
+}
+
+/// Returns the resolver settings used for agent-side target lookups.
+#[tauri::command]
+pub async fn get_resolver(
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<ResolverConfig, String> {
+    Ok(state.settings().get().resolver)
+}
+
+/// Sets how the agent resolves tunnel targets: fixed `hosts` entries and a
+/// nameserver (plain DNS or DoH) per domain. Takes effect on the next dial
+/// and is saved with the app settings.
+#[tauri::command]
+pub async fn set_resolver(
+    config: ResolverConfig,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<(), String> {
+    let resolver = Resolver::new(&config)?;
+    info!(
+        "Resolver updated: {} host override(s), {} domain nameserver(s)",
+        config.hosts.len(),
+        config.domains.len()
+    );
+    let mut store = state.settings();
+    let updated = Settings {
+        resolver: config,
+        ..store.get()
+    };
+    store.set(updated)?;
+    state.dialer.set_resolver(resolver);
     Ok(())
 }
 
+/// Lists agents connected to the server, optionally filtered by tag
+/// (`env=prod` for an exact tag or `env` for any value), with the
+/// services each advertises.
+#[tauri::command]
+pub async fn list_agents(
+    tag: Option<String>,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<Vec<AgentSummary>, String> {
+    fetch_agents(&state, tag).await
+}
+
+/// The relay this client is connected to, which invitations and pairing
+/// codes point at; the primary server URL while disconnected.
+async fn connected_relay(state: &AgentState) -> String {
+    match state.relay.read().await.clone() {
+        Some(relay) => relay,
+        None => state.server_url.read().await.clone(),
+    }
+}
+
+/// Asks the server for its agent list and waits for the reply.
+async fn fetch_agents(
+    state: &AgentState,
+    tag: Option<String>,
+) -> Result<Vec<AgentSummary>, String> {
+    let tx = state
+        .ctrl_tx
+        .read()
+        .await
+        .as_ref()
+        .ok_or("Not connected to server")?
+        .clone();
+
+    let (reply_tx, reply_rx) = oneshot::channel();
+    state.agent_list_waiters.lock().await.push_back(reply_tx);
+    tx.send(ControlMessage::ListAgents { tag })
+        .map_err(|e| format!("Failed to send: {}", e))?;
+
+    match tokio::time::timeout(LIST_AGENTS_TIMEOUT, reply_rx).await {
+        Ok(Ok(agents)) => Ok(agents),
+        Ok(Err(_)) => Err("Disconnected before the server replied".to_string()),
+        Err(_) => Err("Timed out waiting for the agent list".to_string()),
+    }
+}
+
+/// Asks an agent to try a TCP connect to `host:port` on its side and
+/// reports whether it got through and how long it took, without opening
+/// a tunnel. The agent only probes targets it advertises, or any target
+/// when it accepts tunnels without asking.
+#[tauri::command]
+pub async fn probe_target(
+    target_id: String,
+    host: String,
+    port: u16,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<ProbeReport, String> {
+    let tx = state
+        .ctrl_tx
+        .read()
+        .await
+        .as_ref()
+        .ok_or("Not connected to server")?
+        .clone();
+
+    let request_id = format!("probe-{}", &Uuid::new_v4().to_string()[..8]);
+    let (reply_tx, reply_rx) = oneshot::channel();
+    state
+        .probe_waiters
+        .lock()
+        .await
+        .insert(request_id.clone(), reply_tx);
+    tx.send(ControlMessage::ProbeTarget {
+        request_id: request_id.clone(),
+        target_id,
+        host: normalize_host(&host).to_string(),
+        port,
+        requester: None,
+    })
+    .map_err(|e| format!("Failed to send: {}", e))?;
+
+    let reply = tokio::time::timeout(PROBE_TIMEOUT, reply_rx).await;
+    state.probe_waiters.lock().await.remove(&request_id);
+    match reply {
+        Ok(Ok(report)) => Ok(report),
+        Ok(Err(_)) => Err("Disconnected before the agent replied".to_string()),
+        Err(_) => Err("Timed out waiting for the agent".to_string()),
+    }
+}
+
+/// Creates a single-use invitation that lets one controller open a tunnel
+/// to `remote_host:remote_port` on this machine within `ttl_secs`, without
+/// a token of its own or an ACL rule. The tunnel is accepted without
+/// asking. Pass the returned token or link on to the person invited.
+#[tauri::command]
+pub async fn create_invite(
+    remote_host: String,
+    remote_port: u16,
+    ttl_secs: u64,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<Invitation, String> {
+    let Some(agent_id) = state.agent_id.read().await.clone() else {
+        return Err("Not registered with a server yet".to_string());
+    };
+    let tx = state
+        .ctrl_tx
+        .read()
+        .await
+        .as_ref()
+        .ok_or("Not connected to server")?
+        .clone();
+
+    let remote_host = normalize_host(&remote_host).to_string();
+    let request_id = format!("invite-{}", &Uuid::new_v4().to_string()[..8]);
+    let request = ControlMessage::CreateInvite {
+        request_id: request_id.clone(),
+        remote_host: remote_host.clone(),
+        remote_port,
+        ttl_secs,
+    };
+    request.validate()?;
+    let (reply_tx, reply_rx) = oneshot::channel();
+    state
+        .invite_waiters
+        .lock()
+        .await
+        .insert(request_id.clone(), reply_tx);
+    tx.send(request)
+        .map_err(|e| format!("Failed to send: {}", e))?;
+
+    let reply = tokio::time::timeout(INVITE_TIMEOUT, reply_rx).await;
+    state.invite_waiters.lock().await.remove(&request_id);
+    let (token, expires_at_ms) = match reply {
+        Ok(Ok(result)) => result?,
+        Ok(Err(_)) => return Err("Disconnected before the server replied".to_string()),
+        Err(_) => return Err("Timed out waiting for the server".to_string()),
+    };
+    info!(remote = %host_port(&remote_host, remote_port), ttl_secs, "Created invitation");
+    Ok(Invitation {
+        link: deeplink::connect_url(&agent_id, &remote_host, remote_port, Some(&token)),
+        token,
+        agent_id,
+        server: connected_relay(&state).await,
+        remote_host,
+        remote_port,
+        expires_at_ms,
+    })
+}
+
+/// Asks the owner of agent `target_id` for access to
+/// `remote_host:remote_port` on it, for an agent we may not connect to on
+/// our own. The server keeps the request until the owner decides; the
+/// decision arrives as an "access-update" event while we stay connected,
+/// or from `check_access` later. An approval carries an invitation for
+/// `connect_to_agent`.
+#[tauri::command]
+pub async fn request_access(
+    target_id: String,
+    remote_host: String,
+    remote_port: u16,
+    reason: Option<String>,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<AccessUpdate, String> {
+    let request_id = format!("access-{}", &Uuid::new_v4().to_string()[..8]);
+    let request = ControlMessage::RequestAccess {
+        request_id: request_id.clone(),
+        target_id,
+        remote_host: normalize_host(&remote_host).to_string(),
+        remote_port,
+        reason: reason.filter(|r| !r.trim().is_empty()),
+    };
+    let update = await_access_update(&state, request_id, request).await?;
+    match update.access_id {
+        Some(_) => Ok(update),
+        None => Err(update
+            .message
+            .unwrap_or_else(|| "The server refused the access request".to_string())),
+    }
+}
+
+/// Returns where the access request `access_id` stands, with the
+/// invitation if it was approved and not collected yet.
+#[tauri::command]
+pub async fn check_access(
+    access_id: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<AccessUpdate, String> {
+    let request_id = format!("access-{}", &Uuid::new_v4().to_string()[..8]);
+    let request = ControlMessage::AccessStatus {
+        request_id: request_id.clone(),
+        access_id,
+    };
+    await_access_update(&state, request_id, request).await
+}
+
+/// Sends `request` and waits for the `AccessUpdate` answering it.
+async fn await_access_update(
+    state: &AgentState,
+    request_id: String,
+    request: ControlMessage,
+) -> Result<AccessUpdate, String> {
+    request.validate()?;
+    let tx = state
+        .ctrl_tx
+        .read()
+        .await
+        .as_ref()
+        .ok_or("Not connected to server")?
+        .clone();
+    let (reply_tx, reply_rx) = oneshot::channel();
+    state
+        .access_waiters
+        .lock()
+        .await
+        .insert(request_id.clone(), reply_tx);
+    tx.send(request)
+        .map_err(|e| format!("Failed to send: {}", e))?;
+
+    let reply = tokio::time::timeout(ACCESS_TIMEOUT, reply_rx).await;
+    state.access_waiters.lock().await.remove(&request_id);
+    match reply {
+        Ok(Ok(update)) => Ok(update),
+        Ok(Err(_)) => Err("Disconnected before the server replied".to_string()),
+        Err(_) => Err("Timed out waiting for the server".to_string()),
+    }
+}
+
 /// Initiates a tunnel connection to a remote agent.
 ///
 /// ## Parameters
-/// - `target_id`: The agent ID to connect to (e.g., "A3F8-B2C1")
+/// - `target_id`: The agent ID (e.g., "A3F8-B2C1") or registered name to connect to
 /// - `remote_host`: The host on the agent's side to forward to
 /// - `remote_port`: The port on the agent's side (e.g., 22 for SSH)
 /// - `local_port`: The local port to listen on (e.g., 2222)
+/// - `bind_address`: Address to listen on; both loopbacks when omitted
+/// - `allow_lan`: Must be `true` for a non-loopback `bind_address`, since
+///   anyone who can reach that address can then use the tunnel
+/// - `remote_socket`: Unix socket on the agent's side (e.g.,
+///   "/var/run/docker.sock") to forward to instead of `remote_host:remote_port`
+/// - `local_socket`: Unix socket to listen on instead of `local_port`
+/// - `connect_timeout_ms`: How long the agent may take to connect to the
+///   target for each stream; the agent's default when omitted
+/// - `extra_ports`: Further ports to forward in the same tunnel, as
+///   comma-separated `local:remote` pairs, single ports or ranges forwarded
+///   to the same port on the agent (e.g., "8000-8010,9090:90")
+/// - `nodelay`: Set `TCP_NODELAY` on local connections, for interactive
+///   protocols such as SSH
+/// - `invite`: Invitation token from the target agent, which opens this
+///   one target without broader access
+/// - `ttl_secs`: Close the tunnel automatically this many seconds after it
+///   is accepted (e.g., 3600 for an hour of contractor access)
+/// - `traffic_class`: "interactive" (the default) or "bulk"; a bulk
+///   tunnel's data waits while interactive tunnels have data to send, so
+///   a file copy does not make a shell sluggish
 ///
 /// ## Flow
 /// 1. Stores the pending connection parameters
@@ -58,96 +676,1498 @@ pub async fn set_server_url(
 /// 3. Adds a "connecting" tunnel entry to the UI
 /// 4. Returns a temporary session ID (updated when the tunnel is ready)
 #[tauri::command]
+#[allow(clippy::too_many_arguments)]
 pub async fn connect_to_agent(
     target_id: String,
     remote_host: String,
     remote_port: u16,
     local_port: u16,
+    bind_address: Option<String>,
+    allow_lan: Option<bool>,
+    remote_socket: Option<String>,
+    local_socket: Option<String>,
+    connect_timeout_ms: Option<u32>,
+    extra_ports: Option<String>,
+    nodelay: Option<bool>,
+    invite: Option<String>,
+    ttl_secs: Option<u64>,
+    traffic_class: Option<TrafficClass>,
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<String, String> {
+    let extra_ports = extra_ports
+        .as_deref()
+        .map(parse_port_pairs)
+        .transpose()?
+        .unwrap_or_default();
+    let bind_address = bind_address
+        .as_deref()
+        .map(|addr| parse_bind_address(addr, allow_lan.unwrap_or(false)))
+        .transpose()?;
+    let local_socket = local_socket
+        .as_deref()
+        .map(parse_local_socket)
+        .transpose()?;
+    if local_socket.is_some() && !extra_ports.is_empty() {
+        return Err("Extra ports need TCP listeners, not a local socket".to_string());
+    }
+    open_tunnel(
+        &state,
+        &app_handle,
+        PendingConnect {
+            target_id,
+            local_port,
+            bind_address,
+            local_socket,
+            remote_host,
+            remote_port,
+            remote_socket,
+            profile: None,
+            group: None,
+            label: None,
+            connect_timeout_ms,
+            extra_ports,
+            nodelay: nodelay.unwrap_or(false),
+            invite,
+            ttl_secs,
+            reverse_socks: false,
+            egress: EgressPolicy::default(),
+            traffic_class: traffic_class.unwrap_or_default(),
+        },
+    )
+    .await
+}
+
+/// Opens a tunnel to a service the target agent advertises, looked up by
+/// name (e.g., "grafana") so its host and port need not be known.
+#[tauri::command]
+pub async fn connect_to_service(
+    target_id: String,
+    service: String,
+    local_port: u16,
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<String, String> {
+    let agents = fetch_agents(&state, None).await?;
+    let mut matches = agents
+        .iter()
+        .filter(|a| a.agent_id == target_id || a.name.as_deref() == Some(target_id.as_str()));
+    let agent = matches
+        .next()
+        .ok_or_else(|| format!("Agent '{}' is not connected", target_id))?;
+    if matches.next().is_some() {
+        return Err(format!(
+            "'{}' names more than one agent; use its agent ID",
+            target_id
+        ));
+    }
+    let found = find_service(&agent.services, &service).ok_or_else(|| {
+        format!(
+            "Agent '{}' does not advertise a service named '{}'",
+            target_id, service
+        )
+    })?;
+    open_tunnel(
+        &state,
+        &app_handle,
+        PendingConnect {
+            target_id: agent.agent_id.clone(),
+            local_port,
+            bind_address: None,
+            local_socket: None,
+            remote_host: found.host.clone(),
+            remote_port: found.port,
+            remote_socket: None,
+            profile: None,
+            group: None,
+            label: None,
+            connect_timeout_ms: None,
+            extra_ports: Vec::new(),
+            nodelay: false,
+            invite: None,
+            ttl_secs: None,
+            reverse_socks: false,
+            egress: EgressPolicy::default(),
+            traffic_class: TrafficClass::default(),
+        },
+    )
+    .await
+}
+
+/// Opens a reverse SOCKS tunnel: the target agent serves a SOCKS5 proxy
+/// on `port` of its loopback addresses, and each connection made through
+/// it is dialed from this machine, so the remote box can reach our network.
+///
+/// ## Parameters
+/// - `port`: The port the agent's proxy listens on (e.g., 1080)
+/// - `ttl_secs`: Optional lifetime in seconds, as in `connect_to_agent`
+/// - `allow`: Optional destinations the proxy may reach, and nothing else:
+///   host names, `*.domain` wildcards, addresses and CIDR networks
+/// - `allow_private`: Without `allow`, let the proxy reach private and
+///   loopback addresses too; by default it only reaches public ones
+///
+/// Returns a temporary session ID, replaced once the agent accepts.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub async fn reverse_socks(
+    target_id: String,
+    port: u16,
+    ttl_secs: Option<u64>,
+    allow: Option<Vec<String>>,
+    allow_private: Option<bool>,
     state: tauri::State<'_, Arc<AgentState>>,
     app_handle: tauri::AppHandle,
 ) -> Result<String, String> {
+    let egress = EgressPolicy {
+        allow: allow.unwrap_or_default(),
+        allow_private: allow_private.unwrap_or(false),
+    };
+    egress.validate()?;
+    open_tunnel(
+        &state,
+        &app_handle,
+        PendingConnect {
+            target_id,
+            local_port: 0,
+            bind_address: None,
+            local_socket: None,
+            remote_host: "127.0.0.1".to_string(),
+            remote_port: port,
+            remote_socket: None,
+            profile: None,
+            group: None,
+            label: None,
+            connect_timeout_ms: None,
+            extra_ports: Vec::new(),
+            nodelay: false,
+            invite: None,
+            ttl_secs,
+            reverse_socks: true,
+            egress,
+            traffic_class: TrafficClass::default(),
+        },
+    )
+    .await
+}
+
+/// Parses extra ports to forward: comma-separated `local:remote` pairs,
+/// single ports and `first-last` ranges, the last two forwarded to the
+/// same port on the agent.
+fn parse_port_pairs(spec: &str) -> Result<Vec<PortPair>, String> {
+    let port = |value: &str| {
+        value
+            .trim()
+            .parse::<u16>()
+            .ok()
+            .filter(|&p| p != 0)
+            .ok_or_else(|| format!("Invalid port: {}", value.trim()))
+    };
+    let mut pairs = Vec::new();
+    for item in spec.split(',').filter(|item| !item.trim().is_empty()) {
+        if let Some((local, remote)) = item.split_once(':') {
+            pairs.push(PortPair {
+                local_port: port(local)?,
+                remote_port: port(remote)?,
+            });
+        } else if let Some((first, last)) = item.split_once('-') {
+            let (first, last) = (port(first)?, port(last)?);
+            if first > last {
+                return Err(format!("Invalid port range: {}", item.trim()));
+            }
+            pairs.extend((first..=last).map(|p| PortPair {
+                local_port: p,
+                remote_port: p,
+            }));
+        } else {
+            let p = port(item)?;
+            pairs.push(PortPair {
+                local_port: p,
+                remote_port: p,
+            });
+        }
+        if pairs.len() > MAX_EXTRA_PORTS {
+            return Err(format!(
+                "At most {} extra ports are allowed",
+                MAX_EXTRA_PORTS
+            ));
+        }
+    }
+    Ok(pairs)
+}
+
+/// Parses a listener bind address, refusing non-loopback addresses unless
+/// `allow_lan` confirms the user wants the tunnel reachable from other hosts.
+fn parse_bind_address(addr: &str, allow_lan: bool) -> Result<IpAddr, String> {
+    let ip: IpAddr = normalize_host(addr)
+        .parse()
+        .map_err(|_| format!("Invalid bind address: {}", addr))?;
+    if !ip.is_loopback() && !allow_lan {
+        return Err(format!(
+            "Binding to {} lets other machines use this tunnel; confirm with allow_lan",
+            ip
+        ));
+    }
+    Ok(ip)
+}
+
+/// Checks a local Unix socket path to listen on.
+fn parse_local_socket(path: &str) -> Result<PathBuf, String> {
+    if !cfg!(unix) {
+        return Err("Unix sockets are not supported on this platform".to_string());
+    }
+    let path = path.trim();
+    if path.is_empty() {
+        return Err("Local socket path must not be empty".to_string());
+    }
+    Ok(PathBuf::from(path))
+}
+
+/// Sends a `Connect` for `spec` and adds a "connecting" placeholder to the
+/// tunnel list. Shared by `connect_to_agent` and the profile/group commands.
+///
+/// Returns the placeholder session ID, which doubles as the `request_id`
+/// the server echoes in `TunnelReady`.
+pub async fn open_tunnel(
+    state: &AgentState,
+    app_handle: &tauri::AppHandle,
+    mut spec: PendingConnect,
+) -> Result<String, String> {
+    // Accept IPv6 literals typed in URL form, e.g. `[::1]`. A socket target
+    // replaces host and port, so those are cleared rather than shown.
+    spec.remote_host = normalize_host(&spec.remote_host).to_string();
+    if spec.remote_socket.is_some() {
+        spec.remote_host.clear();
+        spec.remote_port = 0;
+    }
+
     // Get the control sender (fails if not connected)
-    let ctrl_tx = state.ctrl_tx.read().await;
-    let tx = ctrl_tx.as_ref().ok_or("Not connected to server")?.clone();
+    let tx = state
+        .ctrl_tx
+        .read()
+        .await
+        .as_ref()
+        .ok_or("Not connected to server")?
+        .clone();
+
+    let session_id = format!("pending-{}", &Uuid::new_v4().to_string()[..8]);
+    let connect = ControlMessage::Connect {
+        target_id: spec.target_id.clone(),
+        remote_host: spec.remote_host.clone(),
+        remote_port: spec.remote_port,
+        request_id: session_id.clone(),
+        remote_socket: spec.remote_socket.clone(),
+        pairing_token: state
+            .paired_agents
+            .read()
+            .await
+            .get(&spec.target_id)
+            .cloned(),
+        connect_timeout_ms: spec.connect_timeout_ms,
+        requester: None,
+        extra_ports: spec.extra_ports.iter().map(|p| p.remote_port).collect(),
+        invite: spec.invite.clone(),
+        ttl_secs: spec.ttl_secs,
+        reverse_socks: spec.reverse_socks,
+        traffic_class: spec.traffic_class,
+    };
+    // Catch bad input here rather than have the server drop the message.
+    connect.validate()?;
 
     // Store the pending connection info so we can use it when
     // the server responds with TunnelReady
-    {
-        let mut pending = state.pending_connects.write().await;
-        pending.insert(
-            target_id.clone(),
-            PendingConnect {
-                local_port,
-                remote_host: remote_host.clone(),
-                remote_port,
-            },
-        );
-    }
+    state
+        .pending_connects
+        .write()
+        .await
+        .insert(session_id.clone(), spec.clone());
 
     // Send the connect request to the relay server
-    tx.send(ControlMessage::Connect {
-        target_id: target_id.clone(),
-        remote_host: remote_host.clone(),
-        remote_port,
-    })
-    .map_err(|e| format!("Failed to send: {}", e))?;
+    if let Err(e) = tx.send(connect) {
+        state.pending_connects.write().await.remove(&session_id);
+        return Err(format!("Failed to send: {}", e));
+    }
+    // A pairing token is good for one tunnel.
+    state.paired_agents.write().await.remove(&spec.target_id);
 
     // Add a placeholder tunnel entry for the UI with "connecting" status.
     // The session_id will be updated when we receive TunnelReady.
-    let mut tunnels = state.tunnels.write().await;
-    let session_id = format!("pending-{}", &Uuid::new_v4().to_string()[..8]);
-    tunnels.push(TunnelInfo {
+    state.tunnels.write().await.push(TunnelInfo {
         session_id: session_id.clone(),
-        remote_host,
-        remote_port,
-        local_port,
+        remote_host: spec.remote_host,
+        remote_port: spec.remote_port,
+        local_port: spec.local_port,
+        remote_socket: spec.remote_socket,
+        local_socket: spec.local_socket.as_ref().map(|p| p.display().to_string()),
         direction: "outgoing".to_string(),
         status: "connecting".to_string(),
+        profile: spec.profile,
+        group: spec.group,
+        public_port: None,
+        public_host: None,
+        label: spec.label,
+        rtt_ms: None,
+        extra_ports: spec.extra_ports,
+        nodelay: spec.nodelay,
+        expires_at_ms: None,
+        reverse_socks: spec.reverse_socks,
+        socks_egress: spec.reverse_socks.then(|| spec.egress.clone()),
+        traffic_class: spec.traffic_class,
+        max_streams: None,
     });
 
     // Notify the frontend to refresh the tunnel list
     let _ = app_handle.emit("tunnels-updated", ());
+    tauri::async_runtime::spawn(agent::expire_connect(
+        app_handle.state::<Arc<AgentState>>().inner().clone(),
+        app_handle.clone(),
+        session_id.clone(),
+    ));
 
+    let local = match &spec.local_socket {
+        Some(path) => path.display().to_string(),
+        None if spec.reverse_socks => "reverse SOCKS".to_string(),
+        None => spec.local_port.to_string(),
+    };
     info!(
         "Connect request → agent {} (local={})",
-        target_id, local_port
+        spec.target_id, local
     );
     Ok(session_id)
 }
 
-/// Disconnects an active tunnel by session ID.
+/// Publishes a local service on a public TCP port of the relay.
 ///
-/// Sends a `TunnelClose` message to the server and removes the
-/// tunnel from the local UI list.
+/// ## Parameters
+/// - `remote_host`: The host on this machine's side to forward to
+/// - `remote_port`: The port on this machine's side (e.g., 8080)
+/// - `public_port`: The relay port to listen on; `None` lets the server pick
+///
+/// Returns a temporary session ID. When the server answers `ExposeReady`
+/// the entry gets the real session ID and `public_port`.
 #[tauri::command]
-pub async fn disconnect_tunnel(
-    session_id: String,
+pub async fn expose_port(
+    remote_host: String,
+    remote_port: u16,
+    public_port: Option<u16>,
     state: tauri::State<'_, Arc<AgentState>>,
     app_handle: tauri::AppHandle,
-) -> Result<(), String> {
-    // Send close message to the server
-    let ctrl_tx = state.ctrl_tx.read().await;
-    if let Some(tx) = ctrl_tx.as_ref() {
-        let _ = tx.send(ControlMessage::TunnelClose {
-            session_id: session_id.clone(),
-        });
-    }
-
-    // Remove from local tunnel list
-    let mut tunnels = state.tunnels.write().await;
-    tunnels.retain(|t| t.session_id != session_id);
+) -> Result<String, String> {
+    let remote_host = normalize_host(&remote_host).to_string();
+    let request_id = format!("pending-{}", &Uuid::new_v4().to_string()[..8]);
+    let expose = ControlMessage::Expose {
+        request_id: request_id.clone(),
+        remote_host: remote_host.clone(),
+        remote_port,
+        public_port: public_port.unwrap_or(0),
+    };
+    publish_service(
+        &state,
+        &app_handle,
+        request_id,
+        expose,
+        remote_host,
+        remote_port,
+    )
+    .await
+}
 
-    // Notify the frontend
-    let _ = app_handle.emit("tunnels-updated", ());
-    Ok(())
+/// Publishes a local web service on the relay's HTTP ingress under
+/// `hostname` (e.g., "demo.tunnel.example.com").
+///
+/// Returns a temporary session ID. When the server answers
+/// `ExposeHttpReady` the entry gets the real session ID and `public_host`.
+#[tauri::command]
+pub async fn expose_http(
+    hostname: String,
+    remote_host: String,
+    remote_port: u16,
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<String, String> {
+    let remote_host = normalize_host(&remote_host).to_string();
+    let request_id = format!("pending-{}", &Uuid::new_v4().to_string()[..8]);
+    let expose = ControlMessage::ExposeHttp {
+        request_id: request_id.clone(),
+        hostname,
+        remote_host: remote_host.clone(),
+        remote_port,
+    };
+    publish_service(
+        &state,
+        &app_handle,
+        request_id,
+        expose,
+        remote_host,
+        remote_port,
+    )
+    .await
 }
 
-/// Returns the list of all active tunnels.
+/// Publishes a local TLS service (e.g., HTTPS with its own certificate)
+/// on the relay's TLS ingress for connections naming `hostname` in SNI.
+/// The relay forwards the encrypted bytes; TLS ends at `remote_host`.
 ///
-/// Called by the frontend whenever it receives a "tunnels-updated" event.
+/// Returns a temporary session ID. When the server answers
+/// `ExposeTlsReady` the entry gets the real session ID and `public_host`.
 #[tauri::command]
-pub async fn get_tunnels(
+pub async fn expose_tls(
+    hostname: String,
+    remote_host: String,
+    remote_port: u16,
     state: tauri::State<'_, Arc<AgentState>>,
-) -> Result<Vec<TunnelInfo>, String> {
-    Ok(state.tunnels.read().await.clone())
+    app_handle: tauri::AppHandle,
+) -> Result<String, String> {
+    let remote_host = normalize_host(&remote_host).to_string();
+    let request_id = format!("pending-{}", &Uuid::new_v4().to_string()[..8]);
+    let expose = ControlMessage::ExposeTls {
+        request_id: request_id.clone(),
+        hostname,
+        remote_host: remote_host.clone(),
+        remote_port,
+    };
+    publish_service(
+        &state,
+        &app_handle,
+        request_id,
+        expose,
+        remote_host,
+        remote_port,
+    )
+    .await
+}
+
+/// Sends an `Expose`, `ExposeHttp` or `ExposeTls` and adds a "connecting" placeholder
+/// with direction "public"; `disconnect_tunnel` closes the result.
+async fn publish_service(
+    state: &AgentState,
+    app_handle: &tauri::AppHandle,
+    request_id: String,
+    msg: ControlMessage,
+    remote_host: String,
+    remote_port: u16,
+) -> Result<String, String> {
+    let tx = state
+        .ctrl_tx
+        .read()
+        .await
+        .as_ref()
+        .ok_or("Not connected to server")?
+        .clone();
+    msg.validate()?;
+
+    // Added before sending so the server's answer always finds the entry.
+    state.tunnels.write().await.push(TunnelInfo {
+        session_id: request_id.clone(),
+        remote_host,
+        remote_port,
+        local_port: 0,
+        remote_socket: None,
+        local_socket: None,
+        direction: "public".to_string(),
+        status: "connecting".to_string(),
+        profile: None,
+        group: None,
+        public_port: None,
+        public_host: None,
+        label: None,
+        rtt_ms: None,
+        extra_ports: Vec::new(),
+        nodelay: false,
+        expires_at_ms: None,
+        reverse_socks: false,
+        socks_egress: None,
+        traffic_class: TrafficClass::default(),
+        max_streams: None,
+    });
+    if let Err(e) = tx.send(msg) {
+        state
+            .tunnels
+            .write()
+            .await
+            .retain(|t| t.session_id != request_id);
+        return Err(format!("Failed to send: {}", e));
+    }
+    let _ = app_handle.emit("tunnels-updated", ());
+    Ok(request_id)
+}
+
+/// Disconnects an active tunnel by session ID.
+///
+/// Sends a `TunnelClose` message to the server and removes the
+/// tunnel from the local UI list.
+#[tauri::command]
+pub async fn disconnect_tunnel(
+    session_id: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    close_tunnel(&state, &app_handle, &session_id).await;
+    Ok(())
+}
+
+/// Sends `TunnelClose` for `session_id` and drops it from the tunnel list.
+/// Shared by `disconnect_tunnel` and the tray menu.
+pub async fn close_tunnel(state: &AgentState, app_handle: &tauri::AppHandle, session_id: &str) {
+    // Send close message to the server
+    if let Some(tx) = state.ctrl_tx.read().await.as_ref() {
+        let _ = tx.send(ControlMessage::TunnelClose {
+            session_id: session_id.to_string(),
+        });
+    }
+
+    // Remove from local tunnel list
+    state
+        .tunnels
+        .write()
+        .await
+        .retain(|t| t.session_id != session_id);
+    if state.finish_history(session_id).await {
+        let _ = app_handle.emit("history-updated", ());
+    }
+    state.drop_traffic(session_id).await;
+    state.outgoing_streams.write().await.remove(session_id);
+    state.stop_capture(session_id).await;
+
+    // Notify the frontend
+    let _ = app_handle.emit("tunnels-updated", ());
+}
+
+/// Sets or clears the label shown for a tunnel.
+///
+/// For a tunnel opened from a profile the label is saved in the profile,
+/// so the tunnel keeps it the next time it is opened.
+#[tauri::command]
+pub async fn rename_tunnel(
+    session_id: String,
+    label: Option<String>,
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    let label = label
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty());
+    if label.as_ref().is_some_and(|l| l.len() > MAX_LABEL_LEN) {
+        return Err(format!("Label exceeds {} bytes", MAX_LABEL_LEN));
+    }
+
+    let profile = {
+        let mut tunnels = state.tunnels.write().await;
+        let tunnel = tunnels
+            .iter_mut()
+            .find(|t| t.session_id == session_id)
+            .ok_or_else(|| format!("Tunnel '{}' not found", session_id))?;
+        tunnel.label = label.clone();
+        tunnel.profile.clone()
+    };
+    let _ = app_handle.emit("tunnels-updated", ());
+
+    if let Some(name) = profile {
+        let mut profiles = state.profiles.write().await;
+        if let Some(mut profile) = profiles.get(&name).cloned() {
+            profile.label = label;
+            profiles.upsert(profile)?;
+            let _ = app_handle.emit("profiles-updated", ());
+        }
+    }
+    Ok(())
+}
+
+/// Adds a local listener on `local_port` to an established outgoing
+/// tunnel, so another port is reached without opening a new session.
+///
+/// The agent approved the tunnel for its target, so the listener may only
+/// forward to `remote_host` on a port the tunnel already forwards: its own
+/// `remote_port` or one of its `extra_ports`. Other targets need a tunnel
+/// of their own.
+#[tauri::command]
+pub async fn add_listener(
+    session_id: String,
+    local_port: u16,
+    remote_host: String,
+    remote_port: u16,
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    if local_port == 0 {
+        return Err("Local port must not be 0".to_string());
+    }
+    let (stream_port, nodelay) = {
+        let tunnels = state.tunnels.read().await;
+        let tunnel = tunnels
+            .iter()
+            .find(|t| t.session_id == session_id && t.direction == "outgoing")
+            .ok_or_else(|| format!("Tunnel '{}' not found", session_id))?;
+        if tunnel.status != "active" {
+            return Err(format!("Tunnel '{}' is not established", session_id));
+        }
+        if tunnel.remote_socket.is_some()
+            || !normalize_host(&remote_host)
+                .eq_ignore_ascii_case(normalize_host(&tunnel.remote_host))
+        {
+            return Err(format!(
+                "Tunnel '{}' does not forward to {}; open a tunnel for it instead",
+                session_id, remote_host
+            ));
+        }
+        let stream_port = if remote_port == tunnel.remote_port {
+            None
+        } else if tunnel
+            .extra_ports
+            .iter()
+            .any(|p| p.remote_port == remote_port)
+        {
+            Some(remote_port)
+        } else {
+            return Err(format!(
+                "Tunnel '{}' does not forward port {}; open a tunnel for it instead",
+                session_id, remote_port
+            ));
+        };
+        (stream_port, tunnel.nodelay)
+    };
+
+    agent::add_listener(state.inner(), &session_id, local_port, stream_port, nodelay).await?;
+    info!(%session_id, local_port, remote_port, "Added local listener");
+
+    if let Some(tunnel) = state
+        .tunnels
+        .write()
+        .await
+        .iter_mut()
+        .find(|t| t.session_id == session_id)
+    {
+        tunnel.extra_ports.push(PortPair {
+            local_port,
+            remote_port,
+        });
+    }
+    let _ = app_handle.emit("tunnels-updated", ());
+    Ok(())
+}
+
+/// Starts writing the traffic of an open tunnel to a pcapng file at
+/// `path`, replacing any file there. Streams opened from now on are
+/// captured as synthetic TCP connections that Wireshark can follow; the
+/// capture ends with `stop_capture` or when the tunnel closes.
+#[tauri::command]
+pub async fn start_capture(
+    session_id: String,
+    path: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<(), String> {
+    // Applications connect on the controller's side, except through a
+    // reverse SOCKS proxy, which runs on the agent.
+    let (local_is_client, remote_port) = state
+        .tunnels
+        .read()
+        .await
+        .iter()
+        .find(|t| t.session_id == session_id && t.status == "active")
+        .map(|t| {
+            (
+                (t.direction == "outgoing") != t.reverse_socks,
+                t.remote_port,
+            )
+        })
+        .ok_or_else(|| format!("No active tunnel '{}'", session_id))?;
+    let mut captures = state.captures.write().await;
+    if captures.contains_key(&session_id) {
+        return Err(format!("Tunnel '{}' is already being captured", session_id));
+    }
+    let capture = Capture::create(&PathBuf::from(&path), local_is_client, remote_port)?;
+    captures.insert(session_id, Arc::new(capture));
+    Ok(())
+}
+
+/// Stops the capture of a tunnel and returns the number of packets written.
+#[tauri::command]
+pub async fn stop_capture(
+    session_id: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<u64, String> {
+    state
+        .stop_capture(&session_id)
+        .await
+        .ok_or_else(|| format!("Tunnel '{}' is not being captured", session_id))
+}
+
+/// Returns the list of all active tunnels.
+///
+/// Called by the frontend whenever it receives a "tunnels-updated" event.
+#[tauri::command]
+pub async fn get_tunnels(
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<Vec<TunnelInfo>, String> {
+    Ok(state.tunnels.read().await.clone())
+}
+
+/// Returns the streams this agent served, oldest first: when, through
+/// which tunnel, to which target and for which controller.
+#[tauri::command]
+pub async fn get_access_log(
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<Vec<AccessLogEntry>, String> {
+    Ok(state.access_log.lock().await.iter().cloned().collect())
+}
+
+/// Returns relay buffer usage per session, including high-water marks.
+#[tauri::command]
+pub async fn get_buffer_stats(
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<Vec<BufferStats>, String> {
+    let buffers = state.session_buffers.read().await;
+    Ok(buffers
+        .iter()
+        .map(|(session_id, budget)| budget.stats(session_id))
+        .collect())
+}
+
+// ─── Profiles & Groups ──────────────────────────────────────────
+
+/// Returns all saved tunnel profiles.
+#[tauri::command]
+pub async fn get_profiles(
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<Vec<TunnelProfile>, String> {
+    Ok(state.profiles.read().await.list().to_vec())
+}
+
+/// Returns the built-in templates for common protocols (SSH, RDP, VNC,
+/// PostgreSQL, MySQL) with their ports and socket options.
+#[tauri::command]
+pub async fn get_preset_templates() -> Result<Vec<PresetTemplate>, String> {
+    Ok(PRESETS.to_vec())
+}
+
+/// Saves a tunnel profile, replacing any existing profile with the same name.
+///
+/// A profile binding a non-loopback address needs `allow_lan`, as for
+/// `connect_to_agent`.
+#[tauri::command]
+pub async fn save_profile(
+    profile: TunnelProfile,
+    allow_lan: Option<bool>,
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    check_profile(&profile, allow_lan.unwrap_or(false))?;
+    info!("Saving profile {}", profile.name);
+    state.profiles.write().await.upsert(profile)?;
+    let _ = app_handle.emit("profiles-updated", ());
+    Ok(())
+}
+
+/// Checks a profile before it is stored: a name, and `allow_lan` for a
+/// non-loopback `bind_address`.
+fn check_profile(profile: &TunnelProfile, allow_lan: bool) -> Result<(), String> {
+    if profile.name.trim().is_empty() {
+        return Err("Profile name must not be empty".to_string());
+    }
+    if let Some(addr) = profile.bind_address {
+        parse_bind_address(&addr.to_string(), allow_lan)
+            .map_err(|e| format!("Profile '{}': {}", profile.name, e))?;
+    }
+    if let Some(path) = &profile.local_socket {
+        parse_local_socket(&path.to_string_lossy())?;
+    }
+    Ok(())
+}
+
+/// Writes the saved profiles and the shared settings (server URL, tags,
+/// resolver) to a JSON file at `path`, for `import_config` on another
+/// machine. Credentials are left out. Returns how many profiles it holds.
+#[tauri::command]
+pub async fn export_config(
+    path: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<usize, String> {
+    let resolver = state.settings().get().resolver;
+    let export = ConfigExport {
+        version: EXPORT_VERSION,
+        server_url: Some(state.server_url.read().await.clone()),
+        fallback_servers: Some(state.fallback_servers.read().await.clone()),
+        tags: Some(state.tags.read().await.clone()),
+        resolver: Some(resolver),
+        profiles: state.profiles.read().await.list().to_vec(),
+    };
+    let json = serde_json::to_string_pretty(&export).map_err(|e| e.to_string())?;
+    std::fs::write(&path, json).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+    info!("Exported {} profile(s) to {}", export.profiles.len(), path);
+    Ok(export.profiles.len())
+}
+
+/// Reads a file written by `export_config`: applies the settings it holds
+/// and adds its profiles, keeping, replacing or renaming those whose name
+/// is taken as `on_conflict` says (`skip` by default).
+///
+/// Nothing is changed unless the whole file is valid. Profiles binding a
+/// non-loopback address need `allow_lan`, as for `save_profile`.
+#[tauri::command]
+pub async fn import_config(
+    path: String,
+    on_conflict: Option<ConflictPolicy>,
+    allow_lan: Option<bool>,
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<ImportSummary, String> {
+    let raw =
+        std::fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let import: ConfigExport =
+        serde_json::from_str(&raw).map_err(|e| format!("Invalid configuration file: {}", e))?;
+    if import.version > EXPORT_VERSION {
+        return Err(format!(
+            "The file has format version {}; update the app to import it",
+            import.version
+        ));
+    }
+    for profile in &import.profiles {
+        check_profile(profile, allow_lan.unwrap_or(false))?;
+    }
+    let resolver = import.resolver.as_ref().map(Resolver::new).transpose()?;
+
+    if let Some(url) = import.server_url {
+        *state.server_url.write().await = url;
+    }
+    if let Some(servers) = import.fallback_servers {
+        *state.fallback_servers.write().await = servers;
+    }
+    if let Some(tags) = import.tags {
+        *state.tags.write().await = tags;
+    }
+    if let (Some(resolver), Some(config)) = (resolver, import.resolver) {
+        {
+            let mut store = state.settings();
+            let updated = Settings {
+                resolver: config,
+                ..store.get()
+            };
+            store.set(updated)?;
+        }
+        state.dialer.set_resolver(resolver);
+    }
+    let summary = state
+        .profiles
+        .write()
+        .await
+        .import(import.profiles, on_conflict.unwrap_or_default())?;
+    info!(
+        added = summary.added,
+        replaced = summary.replaced,
+        renamed = summary.renamed,
+        skipped = summary.skipped,
+        "Imported configuration from {}",
+        path
+    );
+    let _ = app_handle.emit("profiles-updated", ());
+    Ok(summary)
+}
+
+/// Deletes a saved tunnel profile by name.
+#[tauri::command]
+pub async fn delete_profile(
+    name: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    if !state.profiles.write().await.remove(&name)? {
+        return Err(format!("Profile '{}' not found", name));
+    }
+    let _ = app_handle.emit("profiles-updated", ());
+    Ok(())
+}
+
+/// Opens a tunnel for every profile in `group` that is not already open.
+///
+/// Returns the placeholder session IDs of the tunnels that were started.
+/// Emits `group-updated` with the group name.
+#[tauri::command]
+pub async fn connect_group(
+    group: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<Vec<String>, String> {
+    let profiles = state.profiles.read().await.in_group(&group);
+    if profiles.is_empty() {
+        return Err(format!("Group '{}' has no profiles", group));
+    }
+
+    let open: Vec<String> = state
+        .tunnels
+        .read()
+        .await
+        .iter()
+        .filter_map(|t| t.profile.clone())
+        .collect();
+
+    let mut started = Vec::new();
+    for profile in profiles.into_iter().filter(|p| !open.contains(&p.name)) {
+        started.push(open_tunnel(&state, &app_handle, profile.into()).await?);
+    }
+
+    info!("Group {}: started {} tunnel(s)", group, started.len());
+    let _ = app_handle.emit("group-updated", &group);
+    Ok(started)
+}
+
+/// Closes every tunnel that was opened from a profile in `group`.
+/// Emits `group-updated` with the group name.
+#[tauri::command]
+pub async fn disconnect_group(
+    group: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    let members: Vec<String> = state
+        .tunnels
+        .read()
+        .await
+        .iter()
+        .filter(|t| t.group.as_deref() == Some(group.as_str()))
+        .map(|t| t.session_id.clone())
+        .collect();
+
+    // Tunnels still connecting only have a request ID. Forgetting their
+    // pending connect makes the `TunnelReady` handler close them instead.
+    let mut pending = state.pending_connects.write().await;
+    let connecting: Vec<&String> = members
+        .iter()
+        .filter(|id| pending.remove(id.as_str()).is_some())
+        .collect();
+    drop(pending);
+    if let Some(tx) = state.ctrl_tx.read().await.as_ref() {
+        for session_id in members.iter().filter(|id| !connecting.contains(id)) {
+            let _ = tx.send(ControlMessage::TunnelClose {
+                session_id: session_id.clone(),
+            });
+        }
+    }
+    for session_id in &members {
+        state.abort_session_tasks(session_id).await;
+    }
+    state
+        .tunnels
+        .write()
+        .await
+        .retain(|t| t.group.as_deref() != Some(group.as_str()));
+
+    info!("Group {}: closed {} tunnel(s)", group, members.len());
+    let _ = app_handle.emit("tunnels-updated", ());
+    let _ = app_handle.emit("group-updated", &group);
+    Ok(())
+}
+
+/// Returns the aggregate status of one group.
+#[tauri::command]
+pub async fn get_group_status(
+    group: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<GroupStatus, String> {
+    Ok(state.group_status(&group).await)
+}
+
+/// Returns the aggregate status of every group that has profiles.
+#[tauri::command]
+pub async fn get_groups(
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<Vec<GroupStatus>, String> {
+    let groups = state.profiles.read().await.groups();
+    let mut statuses = Vec::with_capacity(groups.len());
+    for group in groups {
+        statuses.push(state.group_status(&group).await);
+    }
+    Ok(statuses)
+}
+
+// ─── Schedules ──────────────────────────────────────────────────
+
+/// Returns all tunnel schedules and whether each window is open now.
+#[tauri::command]
+pub async fn get_schedules(
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<Vec<ScheduleStatus>, String> {
+    let now = chrono::Local::now().naive_local();
+    Ok(state
+        .schedules
+        .read()
+        .await
+        .list()
+        .iter()
+        .map(|s| ScheduleStatus {
+            open: s.enabled && s.is_open(now),
+            schedule: s.clone(),
+        })
+        .collect())
+}
+
+/// Saves a tunnel schedule, replacing any existing schedule with the same
+/// name. The window takes effect within a minute.
+#[tauri::command]
+pub async fn save_schedule(
+    schedule: TunnelSchedule,
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    schedule.check()?;
+    if state.profiles.read().await.get(&schedule.profile).is_none() {
+        return Err(format!("Profile '{}' not found", schedule.profile));
+    }
+    info!("Saving schedule {}", schedule.name);
+    state.schedules.write().await.upsert(schedule)?;
+    let _ = app_handle.emit("schedules-updated", ());
+    Ok(())
+}
+
+/// Deletes a tunnel schedule by name. A tunnel it holds open is closed
+/// on the scheduler's next check.
+#[tauri::command]
+pub async fn delete_schedule(
+    name: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    if !state.schedules.write().await.remove(&name)? {
+        return Err(format!("Schedule '{}' not found", name));
+    }
+    let _ = app_handle.emit("schedules-updated", ());
+    Ok(())
+}
+
+/// Asks to observe another controller's tunnel.
+///
+/// Requires a token with the observer role. The session's owner is asked
+/// for consent; stats then arrive as "session-stats" events, and an
+/// "observe-ended" event reports a refusal or the end of observation.
+#[tauri::command]
+pub async fn observe_session(
+    session_id: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<(), String> {
+    let ctrl_tx = state.ctrl_tx.read().await;
+    let tx = ctrl_tx.as_ref().ok_or("Not connected to server")?;
+    tx.send(ControlMessage::ObserveRequest { session_id })
+        .map_err(|e| format!("Failed to send: {}", e))
+}
+
+/// Stops observing a session.
+#[tauri::command]
+pub async fn stop_observing(
+    session_id: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    if let Some(tx) = state.ctrl_tx.read().await.as_ref() {
+        let _ = tx.send(ControlMessage::ObserveEnd {
+            session_id: session_id.clone(),
+            reason: String::new(),
+        });
+    }
+    state.observed.write().await.remove(&session_id);
+    let _ = app_handle.emit("observed-updated", ());
+    Ok(())
+}
+
+/// Returns the latest stats of every session being observed.
+#[tauri::command]
+pub async fn get_observed_sessions(
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<Vec<SessionSnapshot>, String> {
+    Ok(state.observed.read().await.values().cloned().collect())
+}
+
+/// Returns observe requests for our tunnels that await an answer.
+#[tauri::command]
+pub async fn get_observer_requests(
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<Vec<ObserverRequest>, String> {
+    Ok(state.observer_requests.read().await.clone())
+}
+
+/// Allows or declines a pending observe request for one of our tunnels.
+#[tauri::command]
+pub async fn respond_observe_request(
+    session_id: String,
+    observer_id: String,
+    allow: bool,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<(), String> {
+    state
+        .observer_requests
+        .write()
+        .await
+        .retain(|r| !(r.session_id == session_id && r.observer_id == observer_id));
+
+    let ctrl_tx = state.ctrl_tx.read().await;
+    let tx = ctrl_tx.as_ref().ok_or("Not connected to server")?;
+    info!(
+        "{} observer {} for tunnel {}",
+        if allow { "Allowing" } else { "Declining" },
+        observer_id,
+        session_id
+    );
+    tx.send(ControlMessage::ObserveReply {
+        session_id,
+        observer_id,
+        allow,
+    })
+    .map_err(|e| format!("Failed to send: {}", e))
+}
+
+/// Revokes every observer of one of our tunnels.
+#[tauri::command]
+pub async fn revoke_observers(
+    session_id: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<(), String> {
+    let ctrl_tx = state.ctrl_tx.read().await;
+    let tx = ctrl_tx.as_ref().ok_or("Not connected to server")?;
+    tx.send(ControlMessage::ObserveEnd {
+        session_id,
+        reason: String::new(),
+    })
+    .map_err(|e| format!("Failed to send: {}", e))
+}
+
+/// Returns incoming tunnel requests that await an answer.
+#[tauri::command]
+pub async fn get_tunnel_requests(
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<Vec<TunnelApproval>, String> {
+    Ok(state.tunnel_approvals.read().await.clone())
+}
+
+/// Accepts a pending incoming tunnel request.
+#[tauri::command]
+pub async fn approve_tunnel_request(
+    session_id: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    let request = state
+        .take_tunnel_approval(&session_id)
+        .await
+        .ok_or("No pending request for this tunnel")?;
+    let _ = app_handle.emit("tunnel-requests-updated", ());
+
+    let tx = state
+        .ctrl_tx
+        .read()
+        .await
+        .clone()
+        .ok_or("Not connected to server")?;
+    info!("Approving tunnel {}", session_id);
+    agent::accept_tunnel(state.inner(), &tx, &app_handle, request).await;
+    Ok(())
+}
+
+/// Refuses a pending incoming tunnel request.
+#[tauri::command]
+pub async fn deny_tunnel_request(
+    session_id: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    state
+        .take_tunnel_approval(&session_id)
+        .await
+        .ok_or("No pending request for this tunnel")?;
+    let _ = app_handle.emit("tunnel-requests-updated", ());
+
+    let ctrl_tx = state.ctrl_tx.read().await;
+    let tx = ctrl_tx.as_ref().ok_or("Not connected to server")?;
+    info!("Denying tunnel {}", session_id);
+    agent::deny_tunnel(tx, session_id, "Denied by the agent");
+    Ok(())
+}
+
+/// Returns access requests to our targets that await a decision.
+#[tauri::command]
+pub async fn get_access_requests(
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<Vec<AccessRequest>, String> {
+    Ok(state.access_requests.read().await.clone())
+}
+
+/// Approves an access request: its controller may open one tunnel to the
+/// requested target within `ttl_secs`, without asking again.
+#[tauri::command]
+pub async fn approve_access_request(
+    access_id: String,
+    ttl_secs: u64,
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    decide_access(&state, &app_handle, access_id, true, ttl_secs).await
+}
+
+/// Denies an access request.
+#[tauri::command]
+pub async fn deny_access_request(
+    access_id: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    decide_access(&state, &app_handle, access_id, false, 0).await
+}
+
+async fn decide_access(
+    state: &AgentState,
+    app_handle: &tauri::AppHandle,
+    access_id: String,
+    approve: bool,
+    ttl_secs: u64,
+) -> Result<(), String> {
+    let decision = ControlMessage::DecideAccess {
+        access_id: access_id.clone(),
+        approve,
+        ttl_secs,
+    };
+    decision.validate()?;
+    {
+        let ctrl_tx = state.ctrl_tx.read().await;
+        let tx = ctrl_tx.as_ref().ok_or("Not connected to server")?;
+        let mut requests = state.access_requests.write().await;
+        let index = requests
+            .iter()
+            .position(|r| r.access_id == access_id)
+            .ok_or("No pending access request with this ID")?;
+        tx.send(decision)
+            .map_err(|e| format!("Failed to send: {}", e))?;
+        requests.remove(index);
+    }
+    info!(%access_id, approve, "Decided access request");
+    let _ = app_handle.emit("access-requests-updated", ());
+    Ok(())
+}
+
+/// Returns the agent identities trusted for each target tunnels were
+/// opened to.
+#[tauri::command]
+pub async fn get_known_agents(
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<Vec<KnownAgent>, String> {
+    Ok(state.known_agents.read().await.list().to_vec())
+}
+
+/// Forgets the identity trusted for `target`; the next tunnel to it is
+/// trusted on first use again.
+#[tauri::command]
+pub async fn forget_known_agent(
+    target: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<(), String> {
+    if !state.known_agents.write().await.remove(&target)? {
+        return Err(format!("No known agent for '{}'", target));
+    }
+    info!("Forgot known agent for {}", target);
+    Ok(())
+}
+
+/// Returns closed outgoing tunnels, newest first.
+#[tauri::command]
+pub async fn get_tunnel_history(
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<Vec<HistoryEntry>, String> {
+    Ok(state.history.read().await.list())
+}
+
+/// Forgets every closed tunnel. Tunnels still open are recorded when
+/// they close.
+#[tauri::command]
+pub async fn clear_history(
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    state.history.write().await.clear()?;
+    info!("Cleared tunnel history");
+    let _ = app_handle.emit("history-updated", ());
+    Ok(())
+}
+
+/// Opens a tunnel like the closed one `session_id` from the history.
+/// Returns the placeholder session ID like `connect_to_agent`.
+#[tauri::command]
+pub async fn reopen_from_history(
+    session_id: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<String, String> {
+    let entry = state
+        .history
+        .read()
+        .await
+        .get(&session_id)
+        .cloned()
+        .ok_or_else(|| format!("No tunnel '{}' in the history", session_id))?;
+    open_tunnel(&state, &app_handle, entry.into()).await
+}
+
+/// Returns the tunnels held because their agent differs from the one
+/// known for their target.
+#[tauri::command]
+pub async fn get_identity_changes(
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<Vec<IdentityChange>, String> {
+    Ok(state.identity_changes.read().await.clone())
+}
+
+/// Trusts the new agent of a held tunnel for its target and starts the
+/// tunnel. To refuse it, close the tunnel with `disconnect_tunnel`.
+#[tauri::command]
+pub async fn trust_agent(
+    session_id: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    let tx = state
+        .ctrl_tx
+        .read()
+        .await
+        .clone()
+        .ok_or("Not connected to server")?;
+    let connection = state
+        .connection
+        .read()
+        .await
+        .clone()
+        .ok_or("Not connected to server")?;
+    let change = state
+        .take_identity_change(&session_id)
+        .await
+        .ok_or("No held tunnel with this session ID")?;
+    let _ = app_handle.emit("identity-changes-updated", ());
+
+    state.known_agents.write().await.trust(
+        &change.target,
+        &change.agent_id,
+        change.public_key.as_deref(),
+        unix_time_ms(),
+    )?;
+    info!(target = %change.target, agent_id = %change.agent_id, "Trusted new agent identity");
+
+    let group = {
+        let mut tunnels = state.tunnels.write().await;
+        tunnels
+            .iter_mut()
+            .find(|t| t.session_id == session_id)
+            .and_then(|t| {
+                t.status = "active".to_string();
+                t.group.clone()
+            })
+    };
+    let _ = app_handle.emit("tunnels-updated", ());
+    if let Some(group) = group {
+        let _ = app_handle.emit("group-updated", &group);
+    }
+    agent::start_tunnel(
+        state.inner(),
+        &tx,
+        &app_handle,
+        &connection,
+        session_id,
+        change.pending,
+    )
+    .await;
+    Ok(())
+}
+
+/// Creates a pairing code for this agent: a QR code holding the server
+/// address, the agent ID and a one-time token that lets the scanning
+/// controller in without approval.
+#[tauri::command]
+pub async fn create_pairing(
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<PairingCode, String> {
+    let Some(agent_id) = state.agent_id.read().await.clone() else {
+        return Err("Not registered with a server yet".to_string());
+    };
+    let payload = PairingPayload {
+        server: connected_relay(&state).await,
+        agent_id,
+        token: Uuid::new_v4().simple().to_string(),
+    };
+    let code = payload.to_code()?;
+    state
+        .pairing_tokens
+        .lock()
+        .await
+        .insert(payload.token, Instant::now() + PAIRING_TTL);
+    info!("Created pairing code");
+    Ok(code)
+}
+
+/// Ingests a scanned pairing code and returns what it contained.
+#[tauri::command]
+pub async fn ingest_pairing(
+    payload: String,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<PairingPayload, String> {
+    pairing::ingest(&state, &payload).await
+}
+
+/// Applies the pairing link held for confirmation because it switches to
+/// another relay, and returns what it contained.
+#[tauri::command]
+pub async fn confirm_pairing(
+    state: tauri::State<'_, Arc<AgentState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<PairingPayload, String> {
+    let pairing = pairing::confirm(&state).await?;
+    let _ = app_handle.emit("paired", &pairing);
+    Ok(pairing)
+}
+
+/// Drops the pairing link held for confirmation.
+#[tauri::command]
+pub async fn reject_pairing(state: tauri::State<'_, Arc<AgentState>>) -> Result<(), String> {
+    if state.pending_pairing.write().await.take().is_some() {
+        info!("Pairing link rejected");
+    }
+    Ok(())
+}
+
+/// Returns the launch-at-login and background-run settings.
+#[tauri::command]
+pub async fn get_settings(state: tauri::State<'_, Arc<AgentState>>) -> Result<Settings, String> {
+    Ok(state.settings().get())
+}
+
+/// Starts the app at login, or stops doing so.
+#[tauri::command]
+pub async fn set_launch_at_login(
+    enabled: bool,
+    app_handle: tauri::AppHandle,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<Settings, String> {
+    settings::apply_launch_at_login(&app_handle, enabled)?;
+    let mut store = state.settings();
+    let updated = Settings {
+        launch_at_login: enabled,
+        ..store.get()
+    };
+    store.set(updated.clone())?;
+    info!(
+        "Launch at login {}",
+        if enabled { "enabled" } else { "disabled" }
+    );
+    Ok(updated)
+}
+
+/// Chooses whether closing the window keeps the app running in the tray
+/// or quits it.
+#[tauri::command]
+pub async fn set_run_in_background(
+    enabled: bool,
+    state: tauri::State<'_, Arc<AgentState>>,
+) -> Result<Settings, String> {
+    let mut store = state.settings();
+    let updated = Settings {
+        run_in_background: enabled,
+        ..store.get()
+    };
+    store.set(updated.clone())?;
+    info!(
+        "Run in background {}",
+        if enabled { "enabled" } else { "disabled" }
+    );
+    Ok(updated)
 }
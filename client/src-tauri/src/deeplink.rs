@@ -0,0 +1,177 @@
+//! # Deep Links
+//!
+//! Opens tunnels from shared `tunnel://` links such as
+//! `tunnel://connect?agent=A3F8-B2C1&port=5432`. Query parameters:
+//!
+//! - `agent`      — agent ID or registered name (required)
+//! - `port`       — target port on the agent (required)
+//! - `host`       — target host on the agent (default `127.0.0.1`)
+//! - `local_port` — local port to listen on (default: `port`)
+//! - `invite`     — invitation token from the agent (see `create_invite`)
+//!
+//! Links always listen on loopback: sharing a tunnel on the LAN needs the
+//! explicit opt-in of `connect_to_agent`.
+//!
+//! `tunnel://pair` links from scanned pairing codes go to [`crate::pairing`].
+//!
+//! Links are logged without their query, which can carry one-time tokens.
+
+use crate::commands;
+use crate::pairing::{self, LinkOutcome};
+use crate::socks::EgressPolicy;
+use crate::state::{AgentState, PendingConnect};
+use std::sync::Arc;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager};
+use tauri_plugin_deep_link::DeepLinkExt;
+use tracing::{info, warn};
+use tunnel_protocol::TrafficClass;
+use url::Url;
+
+/// URL scheme registered for the app (also set in `tauri.conf.json`).
+pub const SCHEME: &str = "tunnel";
+
+/// How long a link opened at startup waits for the server connection.
+const CONNECT_WAIT: Duration = Duration::from_secs(15);
+
+/// Handles the link the app was started with and any opened later.
+pub fn init(app: &tauri::App) {
+    // Installers register the scheme; this covers unpacked builds such as
+    // an AppImage started directly.
+    #[cfg(any(windows, target_os = "linux"))]
+    if let Err(e) = app.deep_link().register_all() {
+        warn!("Failed to register the {}:// scheme: {}", SCHEME, e);
+    }
+
+    if let Ok(Some(urls)) = app.deep_link().get_current() {
+        handle_urls(app.handle(), urls);
+    }
+    let app_handle = app.handle().clone();
+    app.deep_link().on_open_url(move |event| {
+        handle_urls(&app_handle, event.urls());
+    });
+}
+
+/// Parses a `tunnel://connect` link into the tunnel it describes.
+pub fn parse_connect_url(url: &Url) -> Result<PendingConnect, String> {
+    if url.scheme() != SCHEME || url.host_str() != Some("connect") {
+        return Err(format!("Unsupported link: {}", redact(url)));
+    }
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    let port = |name: &str, value: String| {
+        value
+            .parse::<u16>()
+            .ok()
+            .filter(|&p| p != 0)
+            .ok_or_else(|| format!("Invalid {} in link: {}", name, value))
+    };
+
+    let target_id = param("agent").ok_or("Link is missing the agent")?;
+    let remote_port = port("port", param("port").ok_or("Link is missing the port")?)?;
+    let local_port = match param("local_port") {
+        Some(value) => port("local_port", value)?,
+        None => remote_port,
+    };
+    Ok(PendingConnect {
+        target_id,
+        local_port,
+        bind_address: None,
+        local_socket: None,
+        remote_host: param("host").unwrap_or_else(|| "127.0.0.1".to_string()),
+        remote_port,
+        remote_socket: None,
+        profile: None,
+        group: None,
+        label: None,
+        connect_timeout_ms: None,
+        extra_ports: Vec::new(),
+        nodelay: false,
+        invite: param("invite"),
+        ttl_secs: None,
+        reverse_socks: false,
+        egress: EgressPolicy::default(),
+        traffic_class: TrafficClass::default(),
+    })
+}
+
+/// Builds the `tunnel://connect` link for a target, carrying an
+/// invitation token if there is one.
+pub fn connect_url(
+    target_id: &str,
+    remote_host: &str,
+    remote_port: u16,
+    invite: Option<&str>,
+) -> String {
+    let mut url = Url::parse(&format!("{}://connect", SCHEME)).expect("valid base URL");
+    url.query_pairs_mut()
+        .append_pair("agent", target_id)
+        .append_pair("host", remote_host)
+        .append_pair("port", &remote_port.to_string());
+    if let Some(invite) = invite {
+        url.query_pairs_mut().append_pair("invite", invite);
+    }
+    url.to_string()
+}
+
+fn handle_urls(app_handle: &AppHandle, urls: Vec<Url>) {
+    for url in urls {
+        tauri::async_runtime::spawn(open_link(app_handle.clone(), url));
+    }
+}
+
+/// `url` without its query, which may carry one-time tokens, for logs.
+fn redact(url: &Url) -> String {
+    let query = if url.query().is_some() { "?…" } else { "" };
+    format!(
+        "{}://{}{}",
+        url.scheme(),
+        url.host_str().unwrap_or_default(),
+        query
+    )
+}
+
+async fn open_link(app_handle: AppHandle, url: Url) {
+    info!("Opening link {}", redact(&url));
+    crate::show_main_window(&app_handle);
+
+    let state = app_handle.state::<Arc<AgentState>>().inner().clone();
+    let result = if url.host_str() == Some("pair") {
+        pairing::ingest_link(&state, url.as_str())
+            .await
+            .map(|outcome| match outcome {
+                LinkOutcome::Paired(pairing) => {
+                    let _ = app_handle.emit("paired", &pairing);
+                }
+                LinkOutcome::NeedsConfirmation(request) => {
+                    let _ = app_handle.emit("pairing-request", &request);
+                }
+            })
+    } else {
+        match parse_connect_url(&url) {
+            Ok(spec) => {
+                wait_for_connection(&state).await;
+                commands::open_tunnel(&state, &app_handle, spec)
+                    .await
+                    .map(|_| ())
+            }
+            Err(e) => Err(e),
+        }
+    };
+    if let Err(e) = result {
+        warn!("Link {} failed: {}", redact(&url), e);
+        let _ = app_handle.emit("server-error", &e);
+    }
+}
+
+/// Waits up to [`CONNECT_WAIT`] for the control stream, so links that
+/// launch the app do not fail before it has reached the server.
+async fn wait_for_connection(state: &AgentState) {
+    let deadline = tokio::time::Instant::now() + CONNECT_WAIT;
+    while state.ctrl_tx.read().await.is_none() && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+}
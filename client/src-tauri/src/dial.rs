@@ -0,0 +1,407 @@
+//! # Agent-Side Dialing
+//!
+//! Opens TCP (or Unix socket) connections to tunnel targets on behalf of
+//! controllers.
+//! A burst of `StreamOpen`s (a load test, or a crawler behind the tunnel)
+//! would otherwise dial hundreds of sockets at once and hit the resolver
+//! for every one of them, so the [`DialManager`]:
+//!
+//! - caps concurrent dials globally and per session, and
+//! - caches DNS answers, both successful and failed, for a short TTL.
+//!
+//! Each dial, lookup included, is bounded by the tunnel's connect timeout,
+//! so a firewalled target fails fast instead of after the OS default.
+//!
+//! Hostnames go through the agent's [`Resolver`] (hosts overrides and
+//! per-domain nameservers) before falling back to the system resolver.
+//!
+//! A name with both IPv6 and IPv4 addresses is dialed Happy Eyeballs style
+//! (RFC 8305): addresses alternate between families, starting with the
+//! preferred one, and each attempt gets [`CONNECTION_ATTEMPT_DELAY`] before
+//! the next starts alongside it, so a broken route of one family costs a
+//! quarter second instead of the whole connect timeout.
+
+use crate::resolver::{self, Resolver};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, SemaphorePermit};
+use tokio::task::JoinSet;
+use tracing::warn;
+
+/// Maximum dials in flight across all sessions.
+const MAX_CONCURRENT_DIALS: usize = 64;
+
+/// Maximum dials in flight for a single session.
+const MAX_SESSION_DIALS: usize = 16;
+
+/// How long a successful DNS lookup is reused.
+const DNS_POSITIVE_TTL: Duration = Duration::from_secs(60);
+
+/// How long a failed DNS lookup is remembered.
+const DNS_NEGATIVE_TTL: Duration = Duration::from_secs(5);
+
+/// Upper bound on cached host entries; expired ones are purged first.
+const DNS_CACHE_CAPACITY: usize = 256;
+
+/// How long a connection attempt runs alone before the next address is
+/// tried alongside it (RFC 8305 recommends 250 ms).
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Address family tried first when a target has both, from
+/// `TUNNEL_IP_PREFERENCE`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressFamily {
+    #[default]
+    Ipv6,
+    Ipv4,
+}
+
+impl FromStr for AddressFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "ipv6" | "v6" => Ok(Self::Ipv6),
+            "ipv4" | "v4" => Ok(Self::Ipv4),
+            other => Err(format!(
+                "Unknown address family '{}', expected ipv4 or ipv6",
+                other
+            )),
+        }
+    }
+}
+
+impl AddressFamily {
+    /// The family named by `TUNNEL_IP_PREFERENCE`, or the default when it
+    /// is unset or invalid.
+    pub fn from_env() -> Self {
+        let Ok(value) = std::env::var("TUNNEL_IP_PREFERENCE") else {
+            return Self::default();
+        };
+        value.parse().unwrap_or_else(|e| {
+            warn!("Ignoring TUNNEL_IP_PREFERENCE: {}", e);
+            Self::default()
+        })
+    }
+}
+
+/// A cached DNS answer.
+enum CachedLookup {
+    Resolved(Vec<SocketAddr>),
+    Failed(String),
+}
+
+struct CacheEntry {
+    lookup: CachedLookup,
+    expires: Instant,
+}
+
+/// Rate-limits and caches outbound target connections on the agent.
+pub struct DialManager {
+    global: Arc<Semaphore>,
+    sessions: Mutex<HashMap<String, Arc<Semaphore>>>,
+    dns: Mutex<HashMap<(String, u16), CacheEntry>>,
+    resolver: RwLock<Arc<Resolver>>,
+    prefer: AddressFamily,
+}
+
+impl Default for DialManager {
+    fn default() -> Self {
+        Self::new(AddressFamily::default())
+    }
+}
+
+impl DialManager {
+    pub fn new(prefer: AddressFamily) -> Self {
+        Self {
+            global: Arc::new(Semaphore::new(MAX_CONCURRENT_DIALS)),
+            sessions: Mutex::new(HashMap::new()),
+            dns: Mutex::new(HashMap::new()),
+            resolver: RwLock::new(Arc::new(Resolver::default())),
+            prefer,
+        }
+    }
+
+    /// Replaces the resolver and drops answers cached under the old one.
+    pub fn set_resolver(&self, resolver: Resolver) {
+        *self.resolver.write().unwrap() = Arc::new(resolver);
+        self.dns.lock().unwrap().clear();
+    }
+
+    /// Connects to `host:port` for `session_id`, waiting for a free dial
+    /// slot, then giving up with `TimedOut` after `timeout`.
+    pub async fn dial(
+        &self,
+        session_id: &str,
+        host: &str,
+        port: u16,
+        timeout: Duration,
+    ) -> io::Result<TcpStream> {
+        self.dial_where(session_id, host, port, timeout, |_| true)
+            .await
+    }
+
+    /// Like [`dial`](Self::dial), but only to the addresses of `host` that
+    /// `allowed` accepts. Fails with `PermissionDenied` if there are none.
+    pub async fn dial_where(
+        &self,
+        session_id: &str,
+        host: &str,
+        port: u16,
+        timeout: Duration,
+        allowed: impl Fn(IpAddr) -> bool,
+    ) -> io::Result<TcpStream> {
+        let _permits = self.acquire(session_id).await?;
+        with_timeout(timeout, self.connect(host, port, allowed)).await
+    }
+
+    async fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        allowed: impl Fn(IpAddr) -> bool,
+    ) -> io::Result<TcpStream> {
+        let resolved = self.resolve(host, port).await?;
+        let addrs: Vec<SocketAddr> = resolved
+            .iter()
+            .copied()
+            .filter(|addr| allowed(addr.ip()))
+            .collect();
+        if addrs.is_empty() && !resolved.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} has no address this tunnel may reach", host),
+            ));
+        }
+        let addrs = interleave(addrs, self.prefer);
+        if let [addr] = addrs[..] {
+            return TcpStream::connect(addr).await;
+        }
+
+        // Start the next attempt when the current ones fail or after the
+        // attempt delay, whichever comes first; the first connection wins
+        // and dropping the set aborts the others.
+        let mut attempts = JoinSet::new();
+        let mut pending = addrs.into_iter();
+        let mut last_err = None;
+        loop {
+            if let Some(addr) = pending.next() {
+                attempts.spawn(TcpStream::connect(addr));
+            } else if attempts.is_empty() {
+                break;
+            }
+            let more = pending.len() > 0;
+            tokio::select! {
+                Some(result) = attempts.join_next() => match result {
+                    Ok(Ok(stream)) => return Ok(stream),
+                    Ok(Err(e)) => last_err = Some(e),
+                    Err(e) => last_err = Some(io::Error::other(e)),
+                },
+                _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if more => {}
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No addresses for {}", host),
+            )
+        }))
+    }
+
+    /// Connects to the Unix socket at `path` for `session_id`, under the same
+    /// dial limits and timeout as TCP targets.
+    #[cfg(unix)]
+    pub async fn dial_unix(
+        &self,
+        session_id: &str,
+        path: &str,
+        timeout: Duration,
+    ) -> io::Result<UnixStream> {
+        let _permits = self.acquire(session_id).await?;
+        with_timeout(timeout, UnixStream::connect(path)).await
+    }
+
+    /// Waits for a per-session dial slot, then a global one.
+    ///
+    /// The per-session slot is taken first so one busy session queues on its
+    /// own limit instead of holding global slots other sessions need.
+    async fn acquire(
+        &self,
+        session_id: &str,
+    ) -> io::Result<(OwnedSemaphorePermit, SemaphorePermit<'_>)> {
+        let session_permit = self
+            .session_slots(session_id)
+            .acquire_owned()
+            .await
+            .map_err(|_| io::Error::other("dial limiter closed"))?;
+        let global_permit = self
+            .global
+            .acquire()
+            .await
+            .map_err(|_| io::Error::other("dial limiter closed"))?;
+        Ok((session_permit, global_permit))
+    }
+
+    /// Drops the per-session limiter once a tunnel is closed.
+    pub fn forget_session(&self, session_id: &str) {
+        self.sessions.lock().unwrap().remove(session_id);
+    }
+
+    fn session_slots(&self, session_id: &str) -> Arc<Semaphore> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .entry(session_id.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(MAX_SESSION_DIALS)))
+            .clone()
+    }
+
+    /// Resolves `host:port`: overrides first, then the cache, then the
+    /// nameserver configured for `host` or the system resolver.
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        let resolver = self.resolver.read().unwrap().clone();
+        if let Some(ips) = resolver.override_for(host) {
+            return Ok(ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect());
+        }
+
+        let key = (host.to_string(), port);
+        if let Some(entry) = self.dns.lock().unwrap().get(&key) {
+            if entry.expires > Instant::now() {
+                return match &entry.lookup {
+                    CachedLookup::Resolved(addrs) => Ok(addrs.clone()),
+                    CachedLookup::Failed(e) => Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("{} (cached)", e),
+                    )),
+                };
+            }
+        }
+
+        let result = match resolver.nameserver_for(host) {
+            Some(server) => resolver::lookup(server, host).await.map(|ips| {
+                ips.into_iter()
+                    .map(|ip| SocketAddr::new(ip, port))
+                    .collect::<Vec<_>>()
+            }),
+            None => tokio::net::lookup_host((host, port))
+                .await
+                .map(|addrs| addrs.collect::<Vec<_>>()),
+        };
+        let (lookup, ttl) = match &result {
+            Ok(addrs) => (CachedLookup::Resolved(addrs.clone()), DNS_POSITIVE_TTL),
+            Err(e) => (CachedLookup::Failed(e.to_string()), DNS_NEGATIVE_TTL),
+        };
+
+        let mut dns = self.dns.lock().unwrap();
+        if dns.len() >= DNS_CACHE_CAPACITY {
+            let now = Instant::now();
+            dns.retain(|_, e| e.expires > now);
+            if dns.len() >= DNS_CACHE_CAPACITY {
+                dns.clear();
+            }
+        }
+        dns.insert(
+            key,
+            CacheEntry {
+                lookup,
+                expires: Instant::now() + ttl,
+            },
+        );
+        result
+    }
+}
+
+/// Orders `addrs` for connection attempts: duplicates removed, families
+/// alternating and starting with `prefer`, otherwise in resolver order.
+fn interleave(addrs: Vec<SocketAddr>, prefer: AddressFamily) -> Vec<SocketAddr> {
+    let mut unique = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        if !unique.contains(&addr) {
+            unique.push(addr);
+        }
+    }
+    let (first, second): (Vec<_>, Vec<_>) = unique
+        .into_iter()
+        .partition(|a| a.is_ipv6() == (prefer == AddressFamily::Ipv6));
+    let mut ordered = Vec::with_capacity(first.len() + second.len());
+    let mut first = first.into_iter();
+    let mut second = second.into_iter();
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => break,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+    ordered
+}
+
+/// Runs `connect`, failing with `TimedOut` if it takes longer than `timeout`.
+async fn with_timeout<T>(
+    timeout: Duration,
+    connect: impl std::future::Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    tokio::time::timeout(timeout, connect)
+        .await
+        .unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("connect timed out after {} ms", timeout.as_millis()),
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn interleave_alternates_families_from_the_preferred_one() {
+        let resolved = vec![
+            addr("10.0.0.1:22"),
+            addr("10.0.0.2:22"),
+            addr("10.0.0.1:22"),
+            addr("[fd00::1]:22"),
+            addr("10.0.0.3:22"),
+        ];
+        assert_eq!(
+            interleave(resolved.clone(), AddressFamily::Ipv6),
+            vec![
+                addr("[fd00::1]:22"),
+                addr("10.0.0.1:22"),
+                addr("10.0.0.2:22"),
+                addr("10.0.0.3:22"),
+            ]
+        );
+        assert_eq!(
+            interleave(resolved, AddressFamily::Ipv4),
+            vec![
+                addr("10.0.0.1:22"),
+                addr("[fd00::1]:22"),
+                addr("10.0.0.2:22"),
+                addr("10.0.0.3:22"),
+            ]
+        );
+        assert!(interleave(Vec::new(), AddressFamily::Ipv4).is_empty());
+    }
+
+    #[test]
+    fn address_families_parse() {
+        assert_eq!(" IPv4 ".parse(), Ok(AddressFamily::Ipv4));
+        assert_eq!("v6".parse(), Ok(AddressFamily::Ipv6));
+        assert!("both".parse::<AddressFamily>().is_err());
+    }
+}
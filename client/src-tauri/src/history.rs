@@ -0,0 +1,339 @@
+//! # Tunnel History
+//!
+//! Outgoing tunnels this client opened, kept in [`HISTORY_FILE`] once they
+//! close: where they led, how long they stayed open and what they carried.
+//! `get_tunnel_history` lists them newest first so the user can find what
+//! they connected to last week, and `reopen_from_history` opens the same
+//! tunnel again. Only the latest [`MAX_HISTORY_ENTRIES`] are kept, and none
+//! that closed more than [`MAX_HISTORY_AGE_MS`] ago.
+//!
+//! Invitations and TTLs are not recorded: an invitation works once and a
+//! TTL was meant for that one tunnel.
+
+use crate::socks::EgressPolicy;
+use crate::state::{PendingConnect, PortPair};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use tracing::{error, info};
+use tunnel_protocol::{unix_time_ms, TrafficClass};
+
+/// File name of the history inside the app config directory.
+pub const HISTORY_FILE: &str = "tunnel_history.json";
+
+/// Closed tunnels kept; older ones are dropped first.
+pub const MAX_HISTORY_ENTRIES: usize = 500;
+
+/// How long a closed tunnel is kept, in milliseconds: 90 days.
+pub const MAX_HISTORY_AGE_MS: u64 = 90 * 24 * 60 * 60 * 1000;
+
+/// A closed outgoing tunnel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Session ID the relay gave the tunnel.
+    pub session_id: String,
+
+    /// Agent ID or registered name the tunnel was opened to.
+    pub target_id: String,
+    pub remote_host: String,
+    pub remote_port: u16,
+    pub remote_socket: Option<String>,
+
+    /// Where it listened locally.
+    pub local_port: u16,
+    pub bind_address: Option<IpAddr>,
+    pub local_socket: Option<PathBuf>,
+    pub extra_ports: Vec<PortPair>,
+
+    /// Profile, group and label it was opened with, if any.
+    pub profile: Option<String>,
+    pub group: Option<String>,
+    pub label: Option<String>,
+
+    pub connect_timeout_ms: Option<u32>,
+    pub nodelay: bool,
+    pub reverse_socks: bool,
+    pub traffic_class: TrafficClass,
+
+    /// When the tunnel became ready and when it closed, milliseconds since
+    /// the Unix epoch.
+    pub opened_at_ms: u64,
+    pub closed_at_ms: u64,
+
+    /// Bytes sent into and received from the tunnel.
+    pub uploaded_bytes: u64,
+    pub downloaded_bytes: u64,
+
+    /// Data streams opened through it.
+    pub streams: u64,
+}
+
+impl From<HistoryEntry> for PendingConnect {
+    fn from(entry: HistoryEntry) -> Self {
+        Self {
+            target_id: entry.target_id,
+            local_port: entry.local_port,
+            bind_address: entry.bind_address,
+            local_socket: entry.local_socket,
+            remote_host: entry.remote_host,
+            remote_port: entry.remote_port,
+            remote_socket: entry.remote_socket,
+            profile: entry.profile,
+            group: entry.group,
+            label: entry.label,
+            connect_timeout_ms: entry.connect_timeout_ms,
+            extra_ports: entry.extra_ports,
+            nodelay: entry.nodelay,
+            invite: None,
+            ttl_secs: None,
+            reverse_socks: entry.reverse_socks,
+            egress: EgressPolicy::default(),
+            traffic_class: entry.traffic_class,
+        }
+    }
+}
+
+/// What a tunnel carried, read from its counters when it closes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TunnelTotals {
+    pub uploaded_bytes: u64,
+    pub downloaded_bytes: u64,
+    pub streams: u64,
+}
+
+/// In-memory copy of the history file, written back on every change, and
+/// the tunnels still open.
+#[derive(Debug, Default)]
+pub struct HistoryStore {
+    path: Option<PathBuf>,
+    entries: Vec<HistoryEntry>,
+    open: HashMap<String, HistoryEntry>,
+}
+
+impl HistoryStore {
+    /// Loads the history from `path`. A missing or unreadable file yields
+    /// an empty history that will be created on the first save. Entries
+    /// past [`MAX_HISTORY_AGE_MS`] are dropped.
+    pub fn load(path: PathBuf) -> Self {
+        let mut entries: Vec<HistoryEntry> = match std::fs::read_to_string(&path) {
+            Ok(raw) => serde_json::from_str(&raw).unwrap_or_else(|e| {
+                error!("Ignoring invalid history file {}: {}", path.display(), e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        let cutoff = unix_time_ms().saturating_sub(MAX_HISTORY_AGE_MS);
+        entries.retain(|e| e.closed_at_ms >= cutoff);
+        info!("Loaded {} history entries", entries.len());
+        Self {
+            path: Some(path),
+            entries,
+            open: HashMap::new(),
+        }
+    }
+
+    /// Returns closed tunnels, newest first.
+    pub fn list(&self) -> Vec<HistoryEntry> {
+        self.entries.iter().rev().cloned().collect()
+    }
+
+    /// Returns the closed tunnel `session_id`.
+    pub fn get(&self, session_id: &str) -> Option<&HistoryEntry> {
+        self.entries.iter().find(|e| e.session_id == session_id)
+    }
+
+    /// Starts recording the tunnel `session_id`, opened from `spec`, at
+    /// `now_ms`.
+    pub fn opened(&mut self, session_id: &str, spec: &PendingConnect, now_ms: u64) {
+        self.open.insert(
+            session_id.to_string(),
+            HistoryEntry {
+                session_id: session_id.to_string(),
+                target_id: spec.target_id.clone(),
+                remote_host: spec.remote_host.clone(),
+                remote_port: spec.remote_port,
+                remote_socket: spec.remote_socket.clone(),
+                local_port: spec.local_port,
+                bind_address: spec.bind_address,
+                local_socket: spec.local_socket.clone(),
+                extra_ports: spec.extra_ports.clone(),
+                profile: spec.profile.clone(),
+                group: spec.group.clone(),
+                label: spec.label.clone(),
+                connect_timeout_ms: spec.connect_timeout_ms,
+                nodelay: spec.nodelay,
+                reverse_socks: spec.reverse_socks,
+                traffic_class: spec.traffic_class,
+                opened_at_ms: now_ms,
+                closed_at_ms: 0,
+                uploaded_bytes: 0,
+                downloaded_bytes: 0,
+                streams: 0,
+            },
+        );
+    }
+
+    /// Session IDs of tunnels still being recorded.
+    pub fn open_sessions(&self) -> Vec<String> {
+        self.open.keys().cloned().collect()
+    }
+
+    /// Records the tunnel `session_id` as closed at `now_ms` with `totals`,
+    /// returning whether the history changed. Tunnels that were never
+    /// recorded as open are ignored.
+    pub fn closed(
+        &mut self,
+        session_id: &str,
+        totals: TunnelTotals,
+        now_ms: u64,
+    ) -> Result<bool, String> {
+        let Some(mut entry) = self.open.remove(session_id) else {
+            return Ok(false);
+        };
+        entry.closed_at_ms = now_ms;
+        entry.uploaded_bytes = totals.uploaded_bytes;
+        entry.downloaded_bytes = totals.downloaded_bytes;
+        entry.streams = totals.streams;
+        self.entries.push(entry);
+        if self.entries.len() > MAX_HISTORY_ENTRIES {
+            let excess = self.entries.len() - MAX_HISTORY_ENTRIES;
+            self.entries.drain(..excess);
+        }
+        let cutoff = now_ms.saturating_sub(MAX_HISTORY_AGE_MS);
+        self.entries.retain(|e| e.closed_at_ms >= cutoff);
+        self.save().map(|_| true)
+    }
+
+    /// Forgets every closed tunnel.
+    pub fn clear(&mut self) -> Result<(), String> {
+        self.entries.clear();
+        self.save()
+    }
+
+    /// Writes the history to a temporary file next to it and renames it
+    /// into place, so a crash mid-write leaves the previous file intact.
+    fn save(&self) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Err("History storage is not initialized".to_string());
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        let json = serde_json::to_string_pretty(&self.entries).map_err(|e| e.to_string())?;
+        let temp = path.with_extension("json.tmp");
+        std::fs::write(&temp, json).map_err(|e| e.to_string())?;
+        std::fs::rename(&temp, path).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(target: &str) -> PendingConnect {
+        PendingConnect {
+            target_id: target.to_string(),
+            local_port: 8080,
+            bind_address: None,
+            local_socket: None,
+            remote_host: "127.0.0.1".to_string(),
+            remote_port: 80,
+            remote_socket: None,
+            profile: None,
+            group: None,
+            label: None,
+            connect_timeout_ms: None,
+            extra_ports: Vec::new(),
+            nodelay: false,
+            invite: Some("invite".to_string()),
+            ttl_secs: Some(60),
+            reverse_socks: false,
+            egress: EgressPolicy::default(),
+            traffic_class: TrafficClass::default(),
+        }
+    }
+
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "tunnel-history-{}-{}.json",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn closed_tunnels_are_saved_and_reloaded() {
+        let path = temp_file("reload");
+        let mut store = HistoryStore::load(path.clone());
+        store.opened("s1", &spec("web"), 1_000);
+        assert!(store.list().is_empty());
+
+        let totals = TunnelTotals {
+            uploaded_bytes: 10,
+            downloaded_bytes: 20,
+            streams: 3,
+        };
+        let now = unix_time_ms();
+        assert!(store.closed("s1", totals, now).unwrap());
+        assert!(!store.closed("s1", totals, now).unwrap());
+        assert!(!store.closed("unknown", totals, now).unwrap());
+        assert!(!path.with_extension("json.tmp").exists());
+
+        let reloaded = HistoryStore::load(path.clone());
+        let entry = reloaded.get("s1").unwrap();
+        assert_eq!(entry.target_id, "web");
+        assert_eq!(entry.closed_at_ms, now);
+        assert_eq!(entry.downloaded_bytes, 20);
+        assert_eq!(entry.streams, 3);
+
+        let reopened = PendingConnect::from(entry.clone());
+        assert_eq!(reopened.invite, None);
+        assert_eq!(reopened.ttl_secs, None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn only_recent_entries_are_kept() {
+        let path = temp_file("retention");
+        let mut store = HistoryStore::load(path.clone());
+        let now = unix_time_ms();
+        for i in 0..MAX_HISTORY_ENTRIES + 2 {
+            let id = format!("s{}", i);
+            store.opened(&id, &spec("web"), now);
+            store.closed(&id, TunnelTotals::default(), now).unwrap();
+        }
+        let list = store.list();
+        assert_eq!(list.len(), MAX_HISTORY_ENTRIES);
+        assert_eq!(list[0].session_id, format!("s{}", MAX_HISTORY_ENTRIES + 1));
+        assert!(store.get("s0").is_none());
+
+        store.opened("late", &spec("web"), now);
+        store
+            .closed(
+                "late",
+                TunnelTotals::default(),
+                now + MAX_HISTORY_AGE_MS + 1,
+            )
+            .unwrap();
+        assert_eq!(store.list().len(), 1);
+
+        let mut old = store.list();
+        old[0].closed_at_ms = now - MAX_HISTORY_AGE_MS - 1;
+        std::fs::write(&path, serde_json::to_string(&old).unwrap()).unwrap();
+        assert!(HistoryStore::load(path.clone()).list().is_empty());
+
+        store.clear().unwrap();
+        assert!(HistoryStore::load(path.clone()).list().is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn invalid_files_start_an_empty_history() {
+        let path = temp_file("invalid");
+        std::fs::write(&path, "not json").unwrap();
+        assert!(HistoryStore::load(path.clone()).list().is_empty());
+        std::fs::remove_file(path).unwrap();
+        assert!(HistoryStore::default().clear().is_err());
+    }
+}
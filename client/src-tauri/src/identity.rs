@@ -0,0 +1,122 @@
+//! # Agent Identity
+//!
+//! Each install holds an Ed25519 keypair in [`IDENTITY_FILE`] in the app
+//! config directory, created on first launch. The agent ID is derived from
+//! the public key, and registration signs the server's nonce with the
+//! private key, so the relay can verify that an agent really owns its ID.
+//!
+//! Restoring the file, e.g. with `import_identity` after a reinstall,
+//! brings the same agent ID back. When `TUNNEL_AGENT_ID` and
+//! `TUNNEL_AGENT_KEY` are set, they take precedence over the keypair.
+
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tracing::info;
+use tunnel_protocol::{generate_identity_key, identity_public_key, key_agent_id, sign_register};
+
+/// File name of the identity key inside the app config directory.
+pub const IDENTITY_FILE: &str = "identity.key";
+
+/// The install's Ed25519 keypair and where it is stored.
+pub struct Identity {
+    path: PathBuf,
+    /// PKCS#8 document holding the keypair.
+    pkcs8: Vec<u8>,
+    public_key: Vec<u8>,
+}
+
+/// Public half of the identity, returned by `get_identity`.
+#[derive(Debug, Clone, Serialize)]
+pub struct IdentityInfo {
+    /// Agent ID derived from the public key.
+    pub agent_id: String,
+
+    /// Ed25519 public key, hex-encoded.
+    pub public_key: String,
+}
+
+impl Identity {
+    /// Loads the keypair from `path`, generating and saving a new one when
+    /// the file does not exist.
+    pub fn load_or_create(path: PathBuf) -> Result<Self, String> {
+        let pkcs8 = match std::fs::read(&path) {
+            Ok(pkcs8) => pkcs8,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let pkcs8 = generate_identity_key();
+                write_key(&path, &pkcs8)?;
+                info!("Generated a new identity key at {}", path.display());
+                pkcs8
+            }
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+        let public_key = identity_public_key(&pkcs8)
+            .ok_or_else(|| format!("{} is not a valid identity key", path.display()))?;
+        Ok(Self {
+            path,
+            pkcs8,
+            public_key,
+        })
+    }
+
+    /// The Ed25519 public key sent in `Register`.
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// The agent ID the relay assigns to this key.
+    pub fn agent_id(&self) -> String {
+        key_agent_id(&self.public_key)
+    }
+
+    /// Signs the relay's registration challenge.
+    pub fn sign(&self, nonce: &[u8]) -> Option<Vec<u8>> {
+        sign_register(&self.pkcs8, &self.agent_id(), nonce)
+    }
+
+    /// The public half, for display.
+    pub fn info(&self) -> IdentityInfo {
+        IdentityInfo {
+            agent_id: self.agent_id(),
+            public_key: to_hex(&self.public_key),
+        }
+    }
+
+    /// Writes the keypair to `path`, for a backup.
+    pub fn export(&self, path: &Path) -> Result<(), String> {
+        write_key(path, &self.pkcs8)
+    }
+
+    /// Replaces the keypair with the one in `path` and saves it as this
+    /// install's identity.
+    pub fn import(&mut self, path: &Path) -> Result<(), String> {
+        let pkcs8 =
+            std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let public_key = identity_public_key(&pkcs8)
+            .ok_or_else(|| format!("{} is not a valid identity key", path.display()))?;
+        write_key(&self.path, &pkcs8)?;
+        self.pkcs8 = pkcs8;
+        self.public_key = public_key;
+        Ok(())
+    }
+}
+
+/// Lower-case hex encoding of `bytes`.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Writes a private key readable only by the current user.
+fn write_key(path: &Path, pkcs8: &[u8]) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    std::fs::write(path, pkcs8)
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,182 @@
+//! # Known Agents
+//!
+//! Trust on first use for the agents this client opens tunnels to. The
+//! first tunnel to a target (an agent ID or a registered name) reaching an
+//! agent with an Ed25519 key records that key in [`KNOWN_AGENTS_FILE`].
+//! When a later tunnel to the same target reaches a different key or none,
+//! e.g. because someone else registered the name, the tunnel is held
+//! without local listeners until the user trusts the new identity with
+//! `trust_agent` or closes the tunnel.
+//!
+//! Only keys are pinned. Agents without one, such as `tunnel-cli` agents
+//! without `--identity`, draw a new random ID on every start, so their ID
+//! says nothing about who is behind it.
+//!
+//! The key is the one the relay reports in `TunnelReady`, which the agent
+//! proved to the relay when it registered. It guards against the name
+//! moving to another agent, not against a dishonest relay.
+
+use crate::state::PendingConnect;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tracing::{error, info};
+
+/// File name of the known-agents store inside the app config directory.
+pub const KNOWN_AGENTS_FILE: &str = "known_agents.json";
+
+/// The identity last trusted for a target.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KnownAgent {
+    /// Target the tunnel was opened to: agent ID or registered name.
+    pub target: String,
+
+    /// Agent ID the target resolved to when the key was last seen.
+    pub agent_id: String,
+
+    /// Hex-encoded Ed25519 public key. Always set: agents without a key
+    /// are not pinned. Kept optional to read files from older versions.
+    pub public_key: Option<String>,
+
+    /// When this identity was first trusted, milliseconds since the Unix epoch.
+    pub first_seen_ms: u64,
+
+    /// When a tunnel last reached it, milliseconds since the Unix epoch.
+    pub last_seen_ms: u64,
+}
+
+impl KnownAgent {
+    fn same_identity(&self, public_key: Option<&str>) -> bool {
+        self.public_key.as_deref() == public_key
+    }
+}
+
+/// A tunnel whose agent does not match the identity known for its target,
+/// returned by `get_identity_changes`.
+#[derive(Debug, Clone, Serialize)]
+pub struct IdentityChange {
+    pub session_id: String,
+
+    /// Target of the tunnel.
+    pub target: String,
+
+    /// Agent ID the target resolves to now.
+    pub agent_id: String,
+
+    /// Hex-encoded public key of that agent, if it has one.
+    pub public_key: Option<String>,
+
+    /// The identity trusted until now.
+    pub previous: KnownAgent,
+
+    /// Parameters for starting the tunnel once it is trusted.
+    #[serde(skip)]
+    pub pending: PendingConnect,
+}
+
+/// In-memory copy of the known-agents file, written back on every change.
+#[derive(Debug, Default)]
+pub struct KnownAgentStore {
+    path: Option<PathBuf>,
+    agents: Vec<KnownAgent>,
+}
+
+impl KnownAgentStore {
+    /// Loads known agents from `path`. A missing or unreadable file yields
+    /// an empty store that will be created on the first save.
+    pub fn load(path: PathBuf) -> Self {
+        let mut agents: Vec<KnownAgent> = match std::fs::read_to_string(&path) {
+            Ok(raw) => serde_json::from_str(&raw).unwrap_or_else(|e| {
+                error!(
+                    "Ignoring invalid known agents file {}: {}",
+                    path.display(),
+                    e
+                );
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        // Entries without a key come from versions that pinned agent IDs.
+        agents.retain(|a| a.public_key.is_some());
+        info!("Loaded {} known agent(s)", agents.len());
+        Self {
+            path: Some(path),
+            agents,
+        }
+    }
+
+    /// Returns all known agents.
+    pub fn list(&self) -> &[KnownAgent] {
+        &self.agents
+    }
+
+    /// Returns the identity known for `target` if the agent it reached now,
+    /// with hex-encoded `public_key`, holds a different key or none.
+    pub fn changed(&self, target: &str, public_key: Option<&str>) -> Option<KnownAgent> {
+        self.agents
+            .iter()
+            .find(|a| a.target == target)
+            .filter(|a| !a.same_identity(public_key))
+            .cloned()
+    }
+
+    /// Trusts `agent_id` with `public_key` for `target` at `now_ms`,
+    /// replacing any identity known for it. Without a key nothing is
+    /// pinned and any earlier pin for `target` is dropped.
+    pub fn trust(
+        &mut self,
+        target: &str,
+        agent_id: &str,
+        public_key: Option<&str>,
+        now_ms: u64,
+    ) -> Result<(), String> {
+        if public_key.is_none() {
+            return self.remove(target).map(|_| ());
+        }
+        let public_key = public_key.map(str::to_string);
+        match self.agents.iter_mut().find(|a| a.target == target) {
+            Some(known) if known.same_identity(public_key.as_deref()) => {
+                known.agent_id = agent_id.to_string();
+                known.last_seen_ms = now_ms;
+            }
+            Some(known) => {
+                *known = KnownAgent {
+                    target: target.to_string(),
+                    agent_id: agent_id.to_string(),
+                    public_key,
+                    first_seen_ms: now_ms,
+                    last_seen_ms: now_ms,
+                };
+            }
+            None => self.agents.push(KnownAgent {
+                target: target.to_string(),
+                agent_id: agent_id.to_string(),
+                public_key,
+                first_seen_ms: now_ms,
+                last_seen_ms: now_ms,
+            }),
+        }
+        self.save()
+    }
+
+    /// Forgets the identity known for `target`. Returns whether it existed.
+    pub fn remove(&mut self, target: &str) -> Result<bool, String> {
+        let before = self.agents.len();
+        self.agents.retain(|a| a.target != target);
+        let removed = self.agents.len() != before;
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Err("Known agents storage is not initialized".to_string());
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        let json = serde_json::to_string_pretty(&self.agents).map_err(|e| e.to_string())?;
+        std::fs::write(path, json).map_err(|e| e.to_string())
+    }
+}
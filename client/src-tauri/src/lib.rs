@@ -10,16 +10,69 @@
 //! - [`state`]     — Application state (agent ID, tunnels, data channels)
 //! - [`commands`]  — Tauri IPC commands exposed to the React frontend
 //! - [`agent`]     — QUIC connection loop and message handling
+//! - [`dial`]      — Concurrency-limited, DNS-caching target dialer
+//! - [`resolver`]  — Custom DNS for agent-side dials (hosts, split DNS, DoH)
 //! - [`relay`]     — Per-stream TCP ↔ QUIC bidirectional relay
+//! - [`capture`]   — pcapng capture of a tunnel's relayed streams
+//! - [`profiles`]  — Saved tunnel profiles and groups
+//! - [`known_agents`] — Trust on first use for the agents tunnels reach
+//! - [`history`]   — Closed outgoing tunnels, to look up and reopen
+//! - [`presets`]   — Built-in tunnel templates for common protocols
+//! - [`schedule`]  — Tunnels opened and closed on a daily schedule
+//! - [`settings`]  — Launch at login and running in the background
+//! - [`tray`]      — System tray status and quick tunnel controls (desktop)
+//! - [`mobile`]    — Reconnect on resume and QUIC keep-alives (Android/iOS)
+//! - [`deeplink`]  — `tunnel://connect` links that open a tunnel
+//! - [`pairing`]   — QR pairing codes with one-time tokens
+//! - [`quality`]   — Reconnect history, heartbeat jitter and packet loss
+//! - [`stats`]     — Uptime, lifetime bytes and tunnels opened today
+//! - [`logs`]      — In-memory ring buffer of recent log events
+//! - [`identity`]  — Ed25519 keypair backing the agent ID
+//! - [`oidc`]      — SSO login with the OAuth device authorization flow
+//! - [`https`]     — Minimal blocking HTTPS client for DoH and SSO
 
 mod agent;
+pub mod capture;
 pub mod cert;
 pub mod commands;
+pub mod deeplink;
+mod dial;
+pub mod history;
+mod https;
+pub mod identity;
+pub mod known_agents;
+pub mod logs;
+#[cfg(mobile)]
+mod mobile;
+pub mod oidc;
+pub mod pairing;
+pub mod presets;
+pub mod profiles;
+pub mod quality;
 mod relay;
+pub mod resolver;
+pub mod schedule;
+pub mod settings;
+mod socks;
 pub mod state;
+pub mod stats;
+#[cfg(desktop)]
+mod tray;
 
+use history::HistoryStore;
+use identity::Identity;
+use known_agents::KnownAgentStore;
+use logs::{LogBuffer, LogLevel, RingLayer};
+use profiles::ProfileStore;
+use schedule::ScheduleStore;
+use settings::SettingsStore;
 use state::AgentState;
+use stats::StatsTracker;
 use std::sync::Arc;
+use tauri::{Emitter, Manager, WindowEvent};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 
 /// Application entry point.
 ///
@@ -28,29 +81,164 @@ use std::sync::Arc;
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     // Initialize structured logging to stderr (visible in the terminal
-    // when running `tauri dev`)
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
+    // when running `tauri dev`) and to the in-app log buffer
+    let log_buffer = LogBuffer::default();
+    let (level_filter, log_level) = LogLevel::layer(LevelFilter::INFO);
+    tracing_subscriber::registry()
+        .with(level_filter)
+        .with(tracing_subscriber::fmt::layer())
+        .with(RingLayer::new(log_buffer.clone()))
         .init();
 
     // Create the shared agent state with a fresh agent ID
     let agent_state = Arc::new(AgentState::new());
 
-    tauri::Builder::default()
+    let builder = tauri::Builder::default();
+    // A second launch, e.g. by a clicked link on Windows or Linux, hands its
+    // link to the running instance instead of starting another agent.
+    #[cfg(desktop)]
+    let builder = builder.plugin(tauri_plugin_single_instance::init(|app, _argv, _cwd| {
+        show_main_window(app);
+    }));
+    // Launches at login start in the tray, without the window.
+    #[cfg(desktop)]
+    let builder = builder.plugin(tauri_plugin_autostart::init(
+        tauri_plugin_autostart::MacosLauncher::LaunchAgent,
+        Some(vec![settings::MINIMIZED_ARG]),
+    ));
+
+    builder
+        .plugin(tauri_plugin_deep_link::init())
         .plugin(tauri_plugin_opener::init())
+        .plugin(tauri_plugin_notification::init())
         // Make the agent state available to all Tauri commands via dependency injection
         .manage(agent_state.clone())
+        .manage(log_buffer)
+        .manage(log_level)
         // Register the commands that the React frontend can call
         .invoke_handler(tauri::generate_handler![
             commands::get_agent_info,
+            commands::get_connection_quality,
+            commands::get_stats,
+            commands::get_recent_logs,
+            commands::get_log_level,
+            commands::set_log_level,
+            commands::export_logs,
             commands::set_server_url,
+            commands::set_fallback_servers,
+            commands::set_auth_token,
+            commands::sso_login,
+            commands::sso_logout,
+            commands::set_agent_tags,
+            commands::set_agent_name,
+            commands::set_agent_services,
+            commands::get_identity,
+            commands::export_identity,
+            commands::import_identity,
+            commands::get_resolver,
+            commands::set_resolver,
+            commands::list_agents,
+            commands::probe_target,
+            commands::create_invite,
+            commands::request_access,
+            commands::check_access,
             commands::connect_to_agent,
+            commands::connect_to_service,
+            commands::reverse_socks,
+            commands::expose_port,
+            commands::expose_http,
+            commands::expose_tls,
             commands::disconnect_tunnel,
+            commands::rename_tunnel,
+            commands::add_listener,
+            commands::start_capture,
+            commands::stop_capture,
             commands::get_tunnels,
+            commands::get_buffer_stats,
+            commands::get_access_log,
+            commands::get_profiles,
+            commands::get_preset_templates,
+            commands::save_profile,
+            commands::delete_profile,
+            commands::export_config,
+            commands::import_config,
+            commands::connect_group,
+            commands::disconnect_group,
+            commands::get_group_status,
+            commands::get_groups,
+            commands::get_schedules,
+            commands::save_schedule,
+            commands::delete_schedule,
+            commands::observe_session,
+            commands::stop_observing,
+            commands::get_observed_sessions,
+            commands::get_observer_requests,
+            commands::respond_observe_request,
+            commands::revoke_observers,
+            commands::get_tunnel_requests,
+            commands::approve_tunnel_request,
+            commands::deny_tunnel_request,
+            commands::get_access_requests,
+            commands::approve_access_request,
+            commands::deny_access_request,
+            commands::get_known_agents,
+            commands::forget_known_agent,
+            commands::get_tunnel_history,
+            commands::clear_history,
+            commands::reopen_from_history,
+            commands::get_identity_changes,
+            commands::trust_agent,
+            commands::create_pairing,
+            commands::ingest_pairing,
+            commands::confirm_pairing,
+            commands::reject_pairing,
+            commands::discover_servers,
+            commands::get_settings,
+            commands::set_launch_at_login,
+            commands::set_run_in_background,
         ])
+        // Closing the window hides it and the app keeps running in the tray
+        // until "Quit" is chosen there, unless running in the background is
+        // turned off.
+        .on_window_event(|window, event| {
+            if let WindowEvent::CloseRequested { api, .. } = event {
+                let state = window.state::<Arc<AgentState>>();
+                if state.settings().get().run_in_background {
+                    let _ = window.hide();
+                    api.prevent_close();
+                }
+            }
+        })
         .setup(move |app| {
+            #[cfg(desktop)]
+            tray::create(app)?;
+            deeplink::init(app);
+
             let app_handle = app.handle().clone();
             let state = agent_state.clone();
+            let config_dir = app.path().app_config_dir().map_err(|e| e.to_string());
+
+            // Settings are loaded before the window shows, so a launch at
+            // login can keep it hidden.
+            if let Ok(dir) = &config_dir {
+                let settings = SettingsStore::load(dir.join(settings::SETTINGS_FILE));
+                #[cfg(desktop)]
+                if let Err(e) =
+                    settings::apply_launch_at_login(&app_handle, settings.get().launch_at_login)
+                {
+                    tracing::warn!("{}", e);
+                }
+                match resolver::Resolver::new(&settings.get().resolver) {
+                    Ok(resolver) => state.dialer.set_resolver(resolver),
+                    Err(e) => tracing::warn!("Ignoring the saved resolver: {}", e),
+                }
+                *state.settings() = settings;
+            }
+            if std::env::args().any(|arg| arg == settings::MINIMIZED_ARG) {
+                if let Some(window) = app_handle.get_webview_window("main") {
+                    let _ = window.hide();
+                }
+            }
 
             // Spawn the QUIC connection loop on a dedicated OS thread
             // with its own Tokio runtime. This keeps the agent loop isolated
@@ -58,12 +246,55 @@ pub fn run() {
             std::thread::spawn(move || {
                 let rt = tokio::runtime::Runtime::new().expect("Failed to create Tokio runtime");
                 rt.block_on(async move {
+                    match config_dir {
+                        Ok(dir) => {
+                            *state.profiles.write().await =
+                                ProfileStore::load(dir.join(profiles::PROFILES_FILE));
+                            *state.schedules.write().await =
+                                ScheduleStore::load(dir.join(schedule::SCHEDULES_FILE));
+                            *state.known_agents.write().await =
+                                KnownAgentStore::load(dir.join(known_agents::KNOWN_AGENTS_FILE));
+                            *state.history.write().await =
+                                HistoryStore::load(dir.join(history::HISTORY_FILE));
+                            *state.stats.lock().await =
+                                StatsTracker::load(dir.join(stats::STATS_FILE));
+                            match Identity::load_or_create(dir.join(identity::IDENTITY_FILE)) {
+                                Ok(identity) => *state.identity.write().await = Some(identity),
+                                Err(e) => tracing::error!("No identity key: {}", e),
+                            }
+                            oidc::resume(
+                                state.clone(),
+                                app_handle.clone(),
+                                dir.join(oidc::SSO_FILE),
+                            )
+                            .await;
+                        }
+                        Err(e) => tracing::error!("No app config directory: {}", e),
+                    }
+                    let _ = app_handle.emit("profiles-updated", ());
+                    tokio::spawn(relay::run_metrics(state.clone(), app_handle.clone()));
+                    tokio::spawn(schedule::run(state.clone(), app_handle.clone()));
                     agent::run_agent_loop(state, app_handle).await;
                 });
             });
 
             Ok(())
         })
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|_app_handle, _event| {
+            // A suspended phone app comes back with a stale connection
+            #[cfg(mobile)]
+            if let tauri::RunEvent::Resumed = _event {
+                mobile::resumed(_app_handle.state::<Arc<AgentState>>().inner().clone());
+            }
+        });
+}
+
+/// Shows and focuses the main window, e.g. after it was closed to the tray.
+pub(crate) fn show_main_window(app_handle: &tauri::AppHandle) {
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.show();
+        let _ = window.set_focus();
+    }
 }
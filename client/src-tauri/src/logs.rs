@@ -0,0 +1,201 @@
+//! # In-App Logs
+//!
+//! Keeps the most recent `tracing` events in memory so users can read and
+//! export logs from the app instead of hunting for its stdout. A
+//! [`RingLayer`] is installed next to the stderr formatter at startup and
+//! feeds a shared [`LogBuffer`], which the `get_recent_logs` and
+//! `export_logs` commands read.
+//!
+//! Both sit behind a [`LogLevel`] filter that `set_log_level` changes
+//! while the app runs, so debug logs can be captured for a live session.
+//! The level starts at `info` on every launch.
+
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::{reload, Layer, Registry};
+use tunnel_protocol::unix_time_ms;
+
+/// Number of log entries kept in memory.
+pub const LOG_CAPACITY: usize = 2000;
+
+/// Entries returned by `get_recent_logs` when no limit is given.
+pub const DEFAULT_LOG_LIMIT: usize = 200;
+
+/// One recorded `tracing` event.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogEntry {
+    /// Milliseconds since the Unix epoch.
+    pub time_ms: u64,
+
+    /// "ERROR", "WARN", "INFO", "DEBUG" or "TRACE".
+    pub level: String,
+
+    /// Module that logged the event (e.g., "client_lib::agent").
+    pub target: String,
+
+    /// The message followed by the event's other fields as `key=value`.
+    pub message: String,
+}
+
+/// Shared, bounded buffer of recent log entries. Oldest entries are
+/// dropped once [`LOG_CAPACITY`] is reached.
+#[derive(Debug, Clone, Default)]
+pub struct LogBuffer {
+    entries: Arc<Mutex<VecDeque<(Level, LogEntry)>>>,
+}
+
+impl LogBuffer {
+    fn push(&self, level: Level, entry: LogEntry) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() == LOG_CAPACITY {
+            entries.pop_front();
+        }
+        entries.push_back((level, entry));
+    }
+
+    /// Returns up to `limit` of the newest entries at `min_level` or more
+    /// severe, oldest first.
+    pub fn recent(&self, min_level: Level, limit: usize) -> Vec<LogEntry> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let mut recent: Vec<LogEntry> = entries
+            .iter()
+            .rev()
+            .filter(|(level, _)| *level <= min_level)
+            .take(limit)
+            .map(|(_, entry)| entry.clone())
+            .collect();
+        recent.reverse();
+        recent
+    }
+
+    /// Writes every buffered entry to `path` as one line each and returns
+    /// how many were written.
+    pub fn export(&self, path: &Path) -> std::io::Result<usize> {
+        let entries: Vec<LogEntry> = self
+            .entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(_, entry)| entry.clone())
+            .collect();
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        for entry in &entries {
+            writeln!(
+                file,
+                "{} {:>5} {}: {}",
+                entry.time_ms, entry.level, entry.target, entry.message
+            )?;
+        }
+        file.flush()?;
+        Ok(entries.len())
+    }
+}
+
+/// Parses a level name such as "warn" (case-insensitive).
+pub fn parse_level(level: &str) -> Result<Level, String> {
+    level
+        .parse()
+        .map_err(|_| format!("Unknown log level: {}", level))
+}
+
+/// Handle on the level filter installed at startup.
+#[derive(Clone)]
+pub struct LogLevel {
+    handle: reload::Handle<LevelFilter, Registry>,
+}
+
+impl LogLevel {
+    /// Creates the filter layer, starting at `initial`, and its handle.
+    pub fn layer(initial: LevelFilter) -> (reload::Layer<LevelFilter, Registry>, Self) {
+        let (layer, handle) = reload::Layer::new(initial);
+        (layer, Self { handle })
+    }
+
+    /// Returns the current level, e.g. "info".
+    pub fn current(&self) -> String {
+        self.handle
+            .with_current(|level| level.to_string().to_ascii_lowercase())
+            .unwrap_or_default()
+    }
+
+    /// Sets the level from a name such as "debug" or "off"
+    /// (case-insensitive).
+    pub fn set(&self, level: &str) -> Result<(), String> {
+        let filter: LevelFilter = level
+            .trim()
+            .parse()
+            .map_err(|_| format!("Unknown log level: {}", level))?;
+        self.handle.reload(filter).map_err(|e| e.to_string())
+    }
+}
+
+/// `tracing` layer that records every event it sees into a [`LogBuffer`].
+pub struct RingLayer {
+    buffer: LogBuffer,
+}
+
+impl RingLayer {
+    pub fn new(buffer: LogBuffer) -> Self {
+        Self { buffer }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for RingLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        self.buffer.push(
+            *metadata.level(),
+            LogEntry {
+                time_ms: unix_time_ms(),
+                level: metadata.level().to_string(),
+                target: metadata.target().to_string(),
+                message: visitor.finish(),
+            },
+        );
+    }
+}
+
+/// Collects an event's message and its other fields.
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+    fields: String,
+}
+
+impl MessageVisitor {
+    fn finish(self) -> String {
+        if self.message.is_empty() {
+            self.fields.trim_start().to_string()
+        } else {
+            self.message + &self.fields
+        }
+    }
+}
+
+impl Visit for MessageVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            let _ = write!(self.fields, " {}={}", field.name(), value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+}
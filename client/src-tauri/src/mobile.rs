@@ -0,0 +1,53 @@
+//! # Mobile Lifecycle
+//!
+//! Android and iOS suspend an app soon after it leaves the foreground, and
+//! the relay connection usually does not survive the pause: the server or
+//! a NAT on the way drops it while no heartbeat is sent. Rather than
+//! waiting out QUIC's idle timeout and the reconnect delay, the agent
+//! checks the connection as soon as the app is resumed and reconnects at
+//! once when it is gone.
+//!
+//! While the app does run, QUIC keep-alives go out every
+//! [`KEEP_ALIVE_INTERVAL`] so a radio or NAT does not forget the path
+//! between heartbeats.
+
+use crate::state::AgentState;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+use tunnel_protocol::{unix_time_ms, ControlMessage};
+
+/// How often QUIC pings the relay on mobile.
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long the relay may take to answer the ping sent on resume before
+/// the connection is given up.
+const RESUME_PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Called when the app returns to the foreground. Reconnects right away
+/// when there is no connection, and otherwise pings the relay, closing
+/// the connection if the ping goes unanswered.
+pub fn resumed(state: Arc<AgentState>) {
+    tauri::async_runtime::spawn(async move {
+        let tx = state.ctrl_tx.read().await.clone();
+        let Some(tx) = tx else {
+            info!("Resumed while disconnected, reconnecting now");
+            state.reconnect_now.notify_one();
+            return;
+        };
+
+        *state.probe_sent_ms.lock().await = Some(unix_time_ms());
+        if tx.send(ControlMessage::Ping).is_ok() {
+            tokio::time::sleep(RESUME_PING_TIMEOUT).await;
+            if state.probe_sent_ms.lock().await.is_none() {
+                return;
+            }
+        }
+
+        info!("Relay connection did not survive the suspend, reconnecting");
+        if let Some(connection) = state.connection.read().await.as_ref() {
+            connection.close(0u32.into(), b"no reply after resume");
+        }
+        state.reconnect_now.notify_one();
+    });
+}
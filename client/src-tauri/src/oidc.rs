@@ -0,0 +1,561 @@
+//! # SSO Login
+//!
+//! Signs in through an OpenID Connect provider with the OAuth 2.0 device
+//! authorization grant (RFC 8628), so users can log in with their
+//! organization's SSO instead of copying tokens around. `sso_login`
+//! discovers the provider's endpoints, starts a device login, opens the
+//! verification page in the browser and polls the token endpoint until the
+//! user approves it.
+//!
+//! The ID token, or the access token when the provider issues none, becomes
+//! the credential sent in `Register`. The refresh token is saved to
+//! [`SSO_FILE`] in the app config directory, so later launches sign in
+//! again without the browser, and a background task refreshes the
+//! credential shortly before it expires.
+//!
+//! Defaults for the provider come from `TUNNEL_OIDC_ISSUER`,
+//! `TUNNEL_OIDC_CLIENT_ID` and `TUNNEL_OIDC_SCOPE`.
+
+use crate::https;
+use crate::state::AgentState;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+use url::Url;
+
+/// File in the app config directory holding the saved SSO login.
+pub const SSO_FILE: &str = "sso.json";
+
+/// Scope requested when none is configured. `offline_access` asks for a
+/// refresh token.
+const DEFAULT_SCOPE: &str = "openid offline_access";
+
+/// Grant type for polling a device login.
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// Timeout for each request to the provider.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Polling interval when the provider does not suggest one.
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long before expiry the credential is refreshed.
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// Refresh interval when the provider does not say when tokens expire.
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// Delay before retrying a refresh the provider could not be reached for.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Which provider to sign in with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SsoSettings {
+    /// Issuer URL (e.g., "https://login.example.com/realms/corp").
+    pub issuer: String,
+
+    /// OAuth client ID registered for the app at the provider.
+    pub client_id: String,
+
+    /// Space-separated scopes to request.
+    pub scope: String,
+}
+
+impl SsoSettings {
+    /// Fills settings not given from the `TUNNEL_OIDC_*` environment
+    /// variables and checks the issuer.
+    pub fn resolve(
+        issuer: Option<String>,
+        client_id: Option<String>,
+        scope: Option<String>,
+    ) -> Result<Self, String> {
+        let env = |var: &str| std::env::var(var).ok().filter(|v| !v.is_empty());
+        let issuer = issuer
+            .or_else(|| env("TUNNEL_OIDC_ISSUER"))
+            .ok_or("No SSO issuer configured")?;
+        let client_id = client_id
+            .or_else(|| env("TUNNEL_OIDC_CLIENT_ID"))
+            .ok_or("No SSO client ID configured")?;
+        let scope = scope
+            .or_else(|| env("TUNNEL_OIDC_SCOPE"))
+            .unwrap_or_else(|| DEFAULT_SCOPE.to_string());
+        if !issuer.starts_with("https://") {
+            return Err(format!("SSO issuer must be an https:// URL: {}", issuer));
+        }
+        Ok(Self {
+            issuer: issuer.trim_end_matches('/').to_string(),
+            client_id,
+            scope,
+        })
+    }
+}
+
+/// A started device login, returned by `sso_login` so the UI can show the
+/// code the user has to confirm.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceLogin {
+    /// Code the user enters or confirms on the verification page.
+    pub user_code: String,
+
+    /// Page where the user approves the login.
+    pub verification_uri: String,
+
+    /// Verification page with the code already filled in, if offered.
+    pub verification_uri_complete: Option<String>,
+
+    /// Seconds until the login expires if not approved.
+    pub expires_in_secs: u64,
+}
+
+/// SSO state kept in [`AgentState`].
+#[derive(Debug, Default)]
+pub struct SsoSession {
+    /// Where the login is saved. `None` until the config directory is known.
+    pub path: Option<PathBuf>,
+
+    /// Provider of the current login, if signed in.
+    pub issuer: Option<String>,
+
+    /// Task polling a device login or refreshing the credential.
+    pub task: Option<JoinHandle<()>>,
+}
+
+/// Login saved in [`SSO_FILE`].
+#[derive(Serialize, Deserialize)]
+struct SavedLogin {
+    #[serde(flatten)]
+    settings: SsoSettings,
+    token_endpoint: String,
+    refresh_token: String,
+}
+
+/// Endpoints from the provider's discovery document.
+#[derive(Deserialize)]
+struct Discovery {
+    device_authorization_endpoint: Option<String>,
+    token_endpoint: String,
+}
+
+/// Device authorization response.
+#[derive(Deserialize)]
+struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    // Some providers use the name from early drafts of RFC 8628.
+    #[serde(alias = "verification_url")]
+    verification_uri: String,
+    verification_uri_complete: Option<String>,
+    expires_in: u64,
+    interval: Option<u64>,
+}
+
+/// Successful token response.
+#[derive(Deserialize)]
+struct Tokens {
+    access_token: String,
+    id_token: Option<String>,
+    refresh_token: Option<String>,
+    expires_in: Option<u64>,
+}
+
+impl Tokens {
+    /// The credential for `Register`.
+    fn credential(&self) -> String {
+        self.id_token
+            .clone()
+            .unwrap_or_else(|| self.access_token.clone())
+    }
+
+    /// How long to wait before refreshing.
+    fn refresh_delay(&self) -> Duration {
+        self.expires_in
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_TOKEN_LIFETIME)
+            .saturating_sub(REFRESH_MARGIN)
+            .max(REFRESH_MARGIN)
+    }
+}
+
+/// Error response of an OAuth endpoint.
+#[derive(Deserialize)]
+struct OAuthError {
+    error: String,
+    error_description: Option<String>,
+}
+
+impl std::fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.error_description {
+            Some(description) => write!(f, "{} ({})", description, self.error),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
+
+/// Why a token request did not return tokens.
+enum TokenError {
+    /// The user has not approved the device login yet.
+    Pending,
+
+    /// Polling too fast; the interval must grow.
+    SlowDown,
+
+    /// The provider could not be reached or gave an unreadable answer.
+    Unreachable(String),
+
+    /// The provider refused; the login or refresh token is no longer good.
+    Refused(String),
+}
+
+/// Starts a device login with `settings` and spawns the task that waits for
+/// the user to approve it. Any earlier login is replaced.
+pub async fn start_login(
+    state: &Arc<AgentState>,
+    app_handle: &AppHandle,
+    settings: SsoSettings,
+) -> Result<DeviceLogin, String> {
+    let discovery: Discovery = get_json(&format!(
+        "{}/.well-known/openid-configuration",
+        settings.issuer
+    ))
+    .await?;
+    let device_endpoint = discovery
+        .device_authorization_endpoint
+        .ok_or_else(|| format!("{} does not support device login", settings.issuer))?;
+    let device: DeviceAuthorization = post_json(
+        &device_endpoint,
+        vec![
+            ("client_id", settings.client_id.clone()),
+            ("scope", settings.scope.clone()),
+        ],
+    )
+    .await?;
+
+    info!(
+        "SSO login with {} started, code {}",
+        settings.issuer, device.user_code
+    );
+    let login = DeviceLogin {
+        user_code: device.user_code.clone(),
+        verification_uri: device.verification_uri.clone(),
+        verification_uri_complete: device.verification_uri_complete.clone(),
+        expires_in_secs: device.expires_in,
+    };
+    let task = tokio::spawn(poll(
+        state.clone(),
+        app_handle.clone(),
+        settings,
+        discovery.token_endpoint,
+        device,
+    ));
+    if let Some(old) = state.sso.lock().await.task.replace(task) {
+        old.abort();
+    }
+    Ok(login)
+}
+
+/// Polls the token endpoint until the device login is approved, refused
+/// or expired, then keeps the credential fresh.
+async fn poll(
+    state: Arc<AgentState>,
+    app_handle: AppHandle,
+    settings: SsoSettings,
+    token_endpoint: String,
+    device: DeviceAuthorization,
+) {
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(device.expires_in);
+    let mut interval = device
+        .interval
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_POLL_INTERVAL);
+    let form = vec![
+        ("grant_type", DEVICE_CODE_GRANT.to_string()),
+        ("device_code", device.device_code),
+        ("client_id", settings.client_id.clone()),
+    ];
+
+    let tokens = loop {
+        tokio::time::sleep(interval).await;
+        if tokio::time::Instant::now() >= deadline {
+            fail(&app_handle, "SSO login expired before it was approved");
+            return;
+        }
+        match request_tokens(&token_endpoint, form.clone()).await {
+            Ok(tokens) => break tokens,
+            Err(TokenError::Pending) => {}
+            Err(TokenError::SlowDown) => interval += Duration::from_secs(5),
+            Err(TokenError::Unreachable(e)) => warn!("SSO login poll failed: {}", e),
+            Err(TokenError::Refused(e)) => {
+                fail(&app_handle, &format!("SSO login failed: {}", e));
+                return;
+            }
+        }
+    };
+
+    info!("Signed in with {}", settings.issuer);
+    apply(&state, &settings, &token_endpoint, &tokens).await;
+    let _ = app_handle.emit("sso-logged-in", &settings.issuer);
+    if let Some(refresh_token) = tokens.refresh_token.clone() {
+        keep_fresh(
+            state,
+            app_handle,
+            settings,
+            token_endpoint,
+            refresh_token,
+            tokens.refresh_delay(),
+        )
+        .await;
+    }
+}
+
+/// Refreshes the credential every time it is about to expire, until the
+/// provider refuses the refresh token.
+async fn keep_fresh(
+    state: Arc<AgentState>,
+    app_handle: AppHandle,
+    settings: SsoSettings,
+    token_endpoint: String,
+    mut refresh_token: String,
+    mut delay: Duration,
+) {
+    loop {
+        tokio::time::sleep(delay).await;
+        match refresh(&settings, &token_endpoint, &refresh_token).await {
+            Ok(tokens) => {
+                apply(&state, &settings, &token_endpoint, &tokens).await;
+                if let Some(rotated) = tokens.refresh_token.clone() {
+                    refresh_token = rotated;
+                }
+                delay = tokens.refresh_delay();
+            }
+            Err(TokenError::Refused(e)) => {
+                warn!("SSO refresh refused: {}", e);
+                forget(&state).await;
+                fail(&app_handle, "SSO session expired, sign in again");
+                return;
+            }
+            Err(TokenError::Unreachable(e)) => {
+                warn!("SSO refresh failed, retrying: {}", e);
+                delay = RETRY_DELAY;
+            }
+            Err(TokenError::Pending | TokenError::SlowDown) => delay = RETRY_DELAY,
+        }
+    }
+}
+
+/// Restores the login saved at `path`, if any, so the first `Register`
+/// already carries a fresh credential. Called once at startup.
+pub async fn resume(state: Arc<AgentState>, app_handle: AppHandle, path: PathBuf) {
+    state.sso.lock().await.path = Some(path.clone());
+    let saved: SavedLogin = match std::fs::read_to_string(&path) {
+        Ok(raw) => match serde_json::from_str(&raw) {
+            Ok(saved) => saved,
+            Err(e) => {
+                warn!("Ignoring invalid SSO file {}: {}", path.display(), e);
+                return;
+            }
+        },
+        Err(_) => return,
+    };
+
+    let (refresh_token, delay) =
+        match refresh(&saved.settings, &saved.token_endpoint, &saved.refresh_token).await {
+            Ok(tokens) => {
+                info!("Signed in with {}", saved.settings.issuer);
+                apply(&state, &saved.settings, &saved.token_endpoint, &tokens).await;
+                let delay = tokens.refresh_delay();
+                (tokens.refresh_token.unwrap_or(saved.refresh_token), delay)
+            }
+            Err(TokenError::Refused(e)) => {
+                warn!("Saved SSO login refused: {}", e);
+                forget(&state).await;
+                return;
+            }
+            Err(TokenError::Unreachable(e)) => {
+                warn!("SSO provider unreachable, retrying: {}", e);
+                (saved.refresh_token, RETRY_DELAY)
+            }
+            Err(TokenError::Pending | TokenError::SlowDown) => (saved.refresh_token, RETRY_DELAY),
+        };
+    let task = tokio::spawn(keep_fresh(
+        state.clone(),
+        app_handle,
+        saved.settings,
+        saved.token_endpoint,
+        refresh_token,
+        delay,
+    ));
+    state.sso.lock().await.task = Some(task);
+}
+
+/// Signs out: stops refreshing, clears the credential and deletes the
+/// saved login.
+pub async fn logout(state: &AgentState) {
+    if let Some(task) = state.sso.lock().await.task.take() {
+        task.abort();
+    }
+    forget(state).await;
+    info!("Signed out of SSO");
+}
+
+/// Provider of the current login, if signed in.
+pub async fn issuer(state: &AgentState) -> Option<String> {
+    state.sso.lock().await.issuer.clone()
+}
+
+/// Installs the credential from `tokens` and saves the refresh token.
+async fn apply(state: &AgentState, settings: &SsoSettings, token_endpoint: &str, tokens: &Tokens) {
+    *state.auth_token.write().await = Some(tokens.credential());
+    let mut sso = state.sso.lock().await;
+    sso.issuer = Some(settings.issuer.clone());
+    let (Some(path), Some(refresh_token)) = (&sso.path, &tokens.refresh_token) else {
+        return;
+    };
+    let saved = SavedLogin {
+        settings: settings.clone(),
+        token_endpoint: token_endpoint.to_string(),
+        refresh_token: refresh_token.clone(),
+    };
+    let result = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| {
+            let json = serde_json::to_string_pretty(&saved).map_err(std::io::Error::other)?;
+            std::fs::write(path, json)?;
+            // The refresh token is a credential, readable by us alone.
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+            }
+            Ok(())
+        });
+    if let Err(e) = result {
+        warn!("Failed to save SSO login to {}: {}", path.display(), e);
+    }
+}
+
+/// Clears the credential and deletes the saved login.
+async fn forget(state: &AgentState) {
+    *state.auth_token.write().await = None;
+    let mut sso = state.sso.lock().await;
+    sso.issuer = None;
+    if let Some(path) = &sso.path {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+fn fail(app_handle: &AppHandle, message: &str) {
+    warn!("{}", message);
+    let _ = app_handle.emit("server-error", message);
+}
+
+/// Exchanges a refresh token for new tokens.
+async fn refresh(
+    settings: &SsoSettings,
+    token_endpoint: &str,
+    refresh_token: &str,
+) -> Result<Tokens, TokenError> {
+    request_tokens(
+        token_endpoint,
+        vec![
+            ("grant_type", "refresh_token".to_string()),
+            ("refresh_token", refresh_token.to_string()),
+            ("client_id", settings.client_id.clone()),
+            ("scope", settings.scope.clone()),
+        ],
+    )
+    .await
+}
+
+async fn request_tokens(
+    token_endpoint: &str,
+    form: Vec<(&'static str, String)>,
+) -> Result<Tokens, TokenError> {
+    let (status, body) = send(token_endpoint, Some(form))
+        .await
+        .map_err(TokenError::Unreachable)?;
+    if (200..300).contains(&status) {
+        return serde_json::from_slice(&body)
+            .map_err(|e| TokenError::Unreachable(format!("Invalid token response: {}", e)));
+    }
+    match serde_json::from_slice::<OAuthError>(&body) {
+        Ok(e) if e.error == "authorization_pending" => Err(TokenError::Pending),
+        Ok(e) if e.error == "slow_down" => Err(TokenError::SlowDown),
+        Ok(e) => Err(TokenError::Refused(e.to_string())),
+        Err(_) => Err(TokenError::Unreachable(format!(
+            "Token endpoint answered HTTP {}",
+            status
+        ))),
+    }
+}
+
+async fn get_json<T: DeserializeOwned>(url: &str) -> Result<T, String> {
+    let (status, body) = send(url, None).await?;
+    parse(url, status, &body)
+}
+
+async fn post_json<T: DeserializeOwned>(
+    url: &str,
+    form: Vec<(&'static str, String)>,
+) -> Result<T, String> {
+    let (status, body) = send(url, Some(form)).await?;
+    parse(url, status, &body)
+}
+
+fn parse<T: DeserializeOwned>(url: &str, status: u16, body: &[u8]) -> Result<T, String> {
+    if (200..300).contains(&status) {
+        return serde_json::from_slice(body)
+            .map_err(|e| format!("Invalid response from {}: {}", url, e));
+    }
+    Err(match serde_json::from_slice::<OAuthError>(body) {
+        Ok(e) => format!("{} refused: {}", url, e),
+        Err(_) => format!("{} answered HTTP {}", url, status),
+    })
+}
+
+/// GETs `url`, or POSTs `form` to it URL-encoded, and returns the status
+/// and body.
+async fn send(
+    url: &str,
+    form: Option<Vec<(&'static str, String)>>,
+) -> Result<(u16, Vec<u8>), String> {
+    let parsed = Url::parse(url).map_err(|e| format!("Invalid SSO URL {}: {}", url, e))?;
+    let url = url.to_string();
+    tokio::task::spawn_blocking(move || {
+        let response = match form {
+            Some(form) => {
+                let body = url::form_urlencoded::Serializer::new(String::new())
+                    .extend_pairs(form)
+                    .finish();
+                https::request(
+                    "POST",
+                    &parsed,
+                    &[
+                        ("Content-Type", "application/x-www-form-urlencoded"),
+                        ("Accept", "application/json"),
+                    ],
+                    body.as_bytes(),
+                    HTTP_TIMEOUT,
+                )
+            }
+            None => https::request(
+                "GET",
+                &parsed,
+                &[("Accept", "application/json")],
+                &[],
+                HTTP_TIMEOUT,
+            ),
+        };
+        response
+            .map(|r| (r.status, r.body))
+            .map_err(|e| format!("Request to {} failed: {}", url, e))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
@@ -0,0 +1,182 @@
+//! # Pairing
+//!
+//! Pairs a controller with an agent by scanning a QR code instead of
+//! typing IDs. The agent's code is a `tunnel://pair` link carrying its
+//! server address, agent ID and a one-time token:
+//!
+//! ```text
+//! tunnel://pair?server=relay.example.com:7070&agent=A3F8-B2C1&token=4f1c…
+//! ```
+//!
+//! The controller that ingests it switches to that server and sends the
+//! token with its next `Connect` to the agent, which then accepts the
+//! tunnel without asking. Tokens expire after [`PAIRING_TTL`] and are
+//! consumed by their first use.
+//!
+//! Any web page can open a `tunnel://pair` link, so a link that would
+//! move the app to another relay is held until the user confirms it with
+//! `confirm_pairing`; `pairing-request` asks for that.
+
+use crate::deeplink::SCHEME;
+use crate::state::AgentState;
+use qrcode::render::svg;
+use qrcode::QrCode;
+use serde::Serialize;
+use std::time::Duration;
+use tracing::info;
+use url::Url;
+
+/// How long a pairing code stays valid.
+pub const PAIRING_TTL: Duration = Duration::from_secs(600);
+
+/// What a pairing code tells the controller.
+#[derive(Debug, Clone, Serialize)]
+pub struct PairingPayload {
+    /// Relay server address (e.g., "relay.example.com:7070").
+    pub server: String,
+
+    /// ID of the agent to pair with.
+    pub agent_id: String,
+
+    /// One-time token the agent accepts in place of approval.
+    pub token: String,
+}
+
+/// A pairing link waiting for the user to confirm the relay switch, sent
+/// with `pairing-request`. The token is kept back.
+#[derive(Debug, Clone, Serialize)]
+pub struct PairingRequest {
+    /// Relay the link would switch to.
+    pub server: String,
+
+    /// Relay the app uses now.
+    pub current_server: String,
+
+    /// ID of the agent to pair with.
+    pub agent_id: String,
+}
+
+/// What became of a pairing link opened from outside the app.
+pub enum LinkOutcome {
+    /// Applied: the link was for the relay in use.
+    Paired(PairingPayload),
+    /// Held until the user confirms the switch to another relay.
+    NeedsConfirmation(PairingRequest),
+}
+
+/// A pairing code ready to display, returned by `create_pairing`.
+#[derive(Debug, Clone, Serialize)]
+pub struct PairingCode {
+    /// The `tunnel://pair` link encoded in the QR code.
+    pub payload: String,
+
+    /// The QR code as an SVG document.
+    pub svg: String,
+
+    /// Seconds until the token expires.
+    pub expires_in_secs: u64,
+}
+
+impl PairingPayload {
+    /// Encodes the payload as a `tunnel://pair` link.
+    pub fn to_url(&self) -> String {
+        let mut url = Url::parse(&format!("{}://pair", SCHEME)).expect("valid base URL");
+        url.query_pairs_mut()
+            .append_pair("server", &self.server)
+            .append_pair("agent", &self.agent_id)
+            .append_pair("token", &self.token);
+        url.into()
+    }
+
+    /// Parses a scanned `tunnel://pair` link.
+    pub fn parse(payload: &str) -> Result<Self, String> {
+        let url = Url::parse(payload.trim()).map_err(|_| "Not a pairing code".to_string())?;
+        if url.scheme() != SCHEME || url.host_str() != Some("pair") {
+            return Err("Not a pairing code".to_string());
+        }
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+                .filter(|value| !value.is_empty())
+                .ok_or_else(|| format!("Pairing code is missing the {}", name))
+        };
+        Ok(Self {
+            server: param("server")?,
+            agent_id: param("agent")?,
+            token: param("token")?,
+        })
+    }
+
+    /// Renders the payload as a QR code.
+    pub fn to_code(&self) -> Result<PairingCode, String> {
+        let payload = self.to_url();
+        let svg = QrCode::new(&payload)
+            .map_err(|e| format!("Failed to encode pairing code: {}", e))?
+            .render::<svg::Color>()
+            .min_dimensions(240, 240)
+            .build();
+        Ok(PairingCode {
+            payload,
+            svg,
+            expires_in_secs: PAIRING_TTL.as_secs(),
+        })
+    }
+}
+
+/// Applies a scanned pairing code on the controller: the server address
+/// takes effect on the next connection, and the token is sent with the
+/// next `Connect` to the agent.
+pub async fn ingest(state: &AgentState, payload: &str) -> Result<PairingPayload, String> {
+    let pairing = PairingPayload::parse(payload)?;
+    apply(state, &pairing).await;
+    Ok(pairing)
+}
+
+/// Applies a pairing link opened from outside the app. One for the relay
+/// in use is applied; one for another relay is held for
+/// `confirm_pairing`.
+pub async fn ingest_link(state: &AgentState, payload: &str) -> Result<LinkOutcome, String> {
+    let pairing = PairingPayload::parse(payload)?;
+    let current_server = state.server_url.read().await.clone();
+    if pairing.server == current_server {
+        apply(state, &pairing).await;
+        return Ok(LinkOutcome::Paired(pairing));
+    }
+    info!(
+        "Pairing link for agent {} on {} waits for confirmation",
+        pairing.agent_id, pairing.server
+    );
+    let request = PairingRequest {
+        server: pairing.server.clone(),
+        current_server,
+        agent_id: pairing.agent_id.clone(),
+    };
+    *state.pending_pairing.write().await = Some(pairing);
+    Ok(LinkOutcome::NeedsConfirmation(request))
+}
+
+/// Applies the pairing link held by [`ingest_link`], if any.
+pub async fn confirm(state: &AgentState) -> Result<PairingPayload, String> {
+    let pairing = state
+        .pending_pairing
+        .write()
+        .await
+        .take()
+        .ok_or("No pairing waits for confirmation")?;
+    apply(state, &pairing).await;
+    Ok(pairing)
+}
+
+async fn apply(state: &AgentState, pairing: &PairingPayload) {
+    info!(
+        "Paired with agent {} on {}",
+        pairing.agent_id, pairing.server
+    );
+    *state.server_url.write().await = pairing.server.clone();
+    state
+        .paired_agents
+        .write()
+        .await
+        .insert(pairing.agent_id.clone(), pairing.token.clone());
+}
@@ -0,0 +1,68 @@
+//! # Protocol Presets
+//!
+//! Built-in templates for common services, so a tunnel to SSH or a
+//! database needs only the agent. Each preset gives the service's usual
+//! port on the agent, a local port that does not clash with a copy of the
+//! service running locally, and the socket options that suit it.
+
+use serde::Serialize;
+
+/// A template for a tunnel to one kind of service, returned by
+/// `get_preset_templates`.
+#[derive(Debug, Clone, Serialize)]
+pub struct PresetTemplate {
+    /// Stable identifier (e.g., "ssh").
+    pub id: &'static str,
+
+    /// Name shown to the user.
+    pub name: &'static str,
+
+    /// Port the service listens on at the agent's side.
+    pub remote_port: u16,
+
+    /// Suggested local port to listen on.
+    pub local_port: u16,
+
+    /// Whether local connections get `TCP_NODELAY`. Interactive and
+    /// request/response protocols send small writes that should not wait.
+    pub nodelay: bool,
+}
+
+/// The built-in presets, in display order.
+pub const PRESETS: &[PresetTemplate] = &[
+    PresetTemplate {
+        id: "ssh",
+        name: "SSH",
+        remote_port: 22,
+        local_port: 2222,
+        nodelay: true,
+    },
+    PresetTemplate {
+        id: "rdp",
+        name: "Remote Desktop (RDP)",
+        remote_port: 3389,
+        local_port: 13389,
+        nodelay: true,
+    },
+    PresetTemplate {
+        id: "vnc",
+        name: "VNC",
+        remote_port: 5900,
+        local_port: 15900,
+        nodelay: true,
+    },
+    PresetTemplate {
+        id: "postgres",
+        name: "PostgreSQL",
+        remote_port: 5432,
+        local_port: 15432,
+        nodelay: true,
+    },
+    PresetTemplate {
+        id: "mysql",
+        name: "MySQL",
+        remote_port: 3306,
+        local_port: 13306,
+        nodelay: true,
+    },
+];
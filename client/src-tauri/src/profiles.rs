@@ -0,0 +1,287 @@
+//! # Tunnel Profiles
+//!
+//! Saved tunnel definitions (target agent, remote address, local port),
+//! persisted as JSON in the app config directory. Profiles may belong to a
+//! named group such as "staging stack", which the group commands act on
+//! as a unit.
+//!
+//! A team can share a standard setup as a [`ConfigExport`] file: the
+//! profiles plus the settings that are the same on every machine (server
+//! URL, tags, resolver). Credentials and machine-specific settings such as
+//! the agent name are never exported.
+
+use crate::resolver::ResolverConfig;
+use crate::socks::EgressPolicy;
+use crate::state::PendingConnect;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use tracing::{error, info};
+use tunnel_protocol::TrafficClass;
+
+/// File name of the profile store inside the app config directory.
+pub const PROFILES_FILE: &str = "profiles.json";
+
+/// A saved tunnel definition.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TunnelProfile {
+    /// Unique profile name (e.g., "staging-postgres").
+    pub name: String,
+
+    /// Agent ID or registered agent name to connect to.
+    pub target_id: String,
+
+    /// Host on the agent's side to forward to.
+    pub remote_host: String,
+
+    /// Port on the agent's side.
+    pub remote_port: u16,
+
+    /// Local port to listen on.
+    pub local_port: u16,
+
+    /// Unix socket on the agent's side, replacing `remote_host:remote_port`.
+    #[serde(default)]
+    pub remote_socket: Option<String>,
+
+    /// Address the local port binds to; loopback when unset.
+    #[serde(default)]
+    pub bind_address: Option<IpAddr>,
+
+    /// Local Unix socket to listen on instead of `local_port`.
+    #[serde(default)]
+    pub local_socket: Option<PathBuf>,
+
+    /// Group this profile belongs to, if any.
+    #[serde(default)]
+    pub group: Option<String>,
+
+    /// Label given to tunnels opened from this profile.
+    #[serde(default)]
+    pub label: Option<String>,
+
+    /// Target connect timeout asked of the agent, in milliseconds.
+    #[serde(default)]
+    pub connect_timeout_ms: Option<u32>,
+
+    /// Open this tunnel whenever the client registers with the server,
+    /// including at launch and after a reconnect.
+    #[serde(default)]
+    pub autostart: bool,
+
+    /// Set `TCP_NODELAY` on local connections.
+    #[serde(default)]
+    pub nodelay: bool,
+
+    /// Scheduling class of the tunnel's data streams.
+    #[serde(default)]
+    pub traffic_class: TrafficClass,
+}
+
+impl From<TunnelProfile> for PendingConnect {
+    fn from(profile: TunnelProfile) -> Self {
+        Self {
+            target_id: profile.target_id,
+            local_port: profile.local_port,
+            bind_address: profile.bind_address,
+            local_socket: profile.local_socket,
+            remote_host: profile.remote_host,
+            remote_port: profile.remote_port,
+            remote_socket: profile.remote_socket,
+            profile: Some(profile.name),
+            group: profile.group,
+            label: profile.label,
+            connect_timeout_ms: profile.connect_timeout_ms,
+            extra_ports: Vec::new(),
+            nodelay: profile.nodelay,
+            invite: None,
+            ttl_secs: None,
+            reverse_socks: false,
+            egress: EgressPolicy::default(),
+            traffic_class: profile.traffic_class,
+        }
+    }
+}
+
+/// Version of the [`ConfigExport`] format written by this client.
+pub const EXPORT_VERSION: u32 = 1;
+
+/// A tunnel configuration as written by `export_config` and read by
+/// `import_config`. Settings left out of the file are not changed on
+/// import.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigExport {
+    /// Format version; files from a newer client are refused.
+    pub version: u32,
+
+    /// Relay server URL.
+    #[serde(default)]
+    pub server_url: Option<String>,
+
+    /// Relays to fail over to.
+    #[serde(default)]
+    pub fallback_servers: Option<Vec<String>>,
+
+    /// Tags sent in `Register`.
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+
+    /// Agent-side resolver settings.
+    #[serde(default)]
+    pub resolver: Option<ResolverConfig>,
+
+    #[serde(default)]
+    pub profiles: Vec<TunnelProfile>,
+}
+
+/// What to do with an imported profile whose name is already taken.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Keep the existing profile.
+    #[default]
+    Skip,
+    /// Replace the existing profile.
+    Replace,
+    /// Import under a free name such as "staging-postgres-2".
+    Rename,
+}
+
+/// Outcome of importing profiles, returned by `import_config`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImportSummary {
+    pub added: usize,
+    pub replaced: usize,
+    pub renamed: usize,
+    pub skipped: usize,
+}
+
+/// In-memory copy of the profile file, written back on every change.
+#[derive(Debug, Default)]
+pub struct ProfileStore {
+    path: Option<PathBuf>,
+    profiles: Vec<TunnelProfile>,
+}
+
+impl ProfileStore {
+    /// Loads profiles from `path`. A missing or unreadable file yields an
+    /// empty store that will be created on the first save.
+    pub fn load(path: PathBuf) -> Self {
+        let profiles = match std::fs::read_to_string(&path) {
+            Ok(raw) => serde_json::from_str(&raw).unwrap_or_else(|e| {
+                error!("Ignoring invalid profile file {}: {}", path.display(), e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        info!("Loaded {} tunnel profile(s)", profiles.len());
+        Self {
+            path: Some(path),
+            profiles,
+        }
+    }
+
+    /// Returns all profiles.
+    pub fn list(&self) -> &[TunnelProfile] {
+        &self.profiles
+    }
+
+    /// Returns the profile named `name`.
+    pub fn get(&self, name: &str) -> Option<&TunnelProfile> {
+        self.profiles.iter().find(|p| p.name == name)
+    }
+
+    /// Returns the profiles belonging to `group`.
+    pub fn in_group(&self, group: &str) -> Vec<TunnelProfile> {
+        self.profiles
+            .iter()
+            .filter(|p| p.group.as_deref() == Some(group))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the profiles flagged for autostart.
+    pub fn autostart(&self) -> Vec<TunnelProfile> {
+        self.profiles
+            .iter()
+            .filter(|p| p.autostart)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the distinct group names, sorted.
+    pub fn groups(&self) -> Vec<String> {
+        let mut groups: Vec<String> = self
+            .profiles
+            .iter()
+            .filter_map(|p| p.group.clone())
+            .collect();
+        groups.sort();
+        groups.dedup();
+        groups
+    }
+
+    /// Inserts `profile`, replacing any profile with the same name.
+    pub fn upsert(&mut self, profile: TunnelProfile) -> Result<(), String> {
+        match self.profiles.iter_mut().find(|p| p.name == profile.name) {
+            Some(existing) => *existing = profile,
+            None => self.profiles.push(profile),
+        }
+        self.save()
+    }
+
+    /// Adds `profiles`, resolving name clashes with `policy`, and saves once.
+    pub fn import(
+        &mut self,
+        profiles: Vec<TunnelProfile>,
+        policy: ConflictPolicy,
+    ) -> Result<ImportSummary, String> {
+        let mut summary = ImportSummary::default();
+        for mut profile in profiles {
+            let Some(index) = self.profiles.iter().position(|p| p.name == profile.name) else {
+                self.profiles.push(profile);
+                summary.added += 1;
+                continue;
+            };
+            match policy {
+                ConflictPolicy::Skip => summary.skipped += 1,
+                ConflictPolicy::Replace => {
+                    self.profiles[index] = profile;
+                    summary.replaced += 1;
+                }
+                ConflictPolicy::Rename => {
+                    profile.name = (2..)
+                        .map(|n| format!("{}-{}", profile.name, n))
+                        .find(|name| self.get(name).is_none())
+                        .expect("a free name exists");
+                    self.profiles.push(profile);
+                    summary.renamed += 1;
+                }
+            }
+        }
+        self.save()?;
+        Ok(summary)
+    }
+
+    /// Removes the profile named `name`. Returns whether it existed.
+    pub fn remove(&mut self, name: &str) -> Result<bool, String> {
+        let before = self.profiles.len();
+        self.profiles.retain(|p| p.name != name);
+        let removed = self.profiles.len() != before;
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Err("Profile storage is not initialized".to_string());
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        let json = serde_json::to_string_pretty(&self.profiles).map_err(|e| e.to_string())?;
+        std::fs::write(path, json).map_err(|e| e.to_string())
+    }
+}
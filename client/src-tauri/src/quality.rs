@@ -0,0 +1,165 @@
+//! # Connection Quality
+//!
+//! Diagnostics for the QUIC connection to the relay, returned by
+//! `get_connection_quality` so users on flaky links can see why tunnels
+//! stall. The [`QualityTracker`] records:
+//!
+//! - connection attempts, reconnects and the reasons of recent disconnects,
+//! - heartbeat round trips (`Ping`/`Pong`), their mean and jitter, and
+//! - heartbeats that got no answer before the next one was due.
+//!
+//! Packet loss comes from the live connection's QUIC path statistics.
+
+use serde::Serialize;
+use std::collections::VecDeque;
+use tunnel_protocol::unix_time_ms;
+
+/// Number of recent disconnects kept in the history.
+const DISCONNECT_HISTORY: usize = 20;
+
+/// Number of recent heartbeat round trips used for mean and jitter.
+const RTT_SAMPLES: usize = 20;
+
+/// A lost or failed connection to the relay.
+#[derive(Debug, Clone, Serialize)]
+pub struct Disconnect {
+    /// When it happened, milliseconds since the Unix epoch.
+    pub time_ms: u64,
+
+    /// Why, as reported by the QUIC stack (e.g., "timed out").
+    pub reason: String,
+}
+
+/// Connection diagnostics, returned by `get_connection_quality`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionQuality {
+    /// Whether the relay connection is currently up.
+    pub connected: bool,
+
+    /// When the current connection was established.
+    pub connected_since_ms: Option<u64>,
+
+    /// Connections established after the first one.
+    pub reconnects: u32,
+
+    /// Connection attempts that failed before a connection was established.
+    pub failed_attempts: u32,
+
+    /// Most recent disconnects and failed attempts, oldest first.
+    pub disconnects: Vec<Disconnect>,
+
+    /// Latest heartbeat round trip.
+    pub heartbeat_rtt_ms: Option<u64>,
+
+    /// Mean of the recent heartbeat round trips.
+    pub heartbeat_rtt_avg_ms: Option<u64>,
+
+    /// Standard deviation of the recent heartbeat round trips.
+    pub heartbeat_jitter_ms: Option<u64>,
+
+    /// Heartbeats sent without an answer before the next one.
+    pub missed_heartbeats: u32,
+
+    /// Smoothed round trip estimated by QUIC on the current connection.
+    pub quic_rtt_ms: Option<u64>,
+
+    /// Packets sent on the current connection.
+    pub sent_packets: u64,
+
+    /// Packets the current connection declared lost.
+    pub lost_packets: u64,
+
+    /// `lost_packets` as a percentage of `sent_packets`.
+    pub loss_percent: f64,
+}
+
+/// Accumulates connection events for [`ConnectionQuality`].
+#[derive(Debug, Default)]
+pub struct QualityTracker {
+    connects: u32,
+    failed_attempts: u32,
+    connected_since_ms: Option<u64>,
+    disconnects: VecDeque<Disconnect>,
+    rtt_samples: VecDeque<u64>,
+    missed_heartbeats: u32,
+}
+
+impl QualityTracker {
+    /// Records an established connection.
+    pub fn connected(&mut self) {
+        self.connects += 1;
+        self.connected_since_ms = Some(unix_time_ms());
+    }
+
+    /// Records a lost connection, or a failed attempt if none was up.
+    pub fn disconnected(&mut self, reason: String) {
+        if self.connected_since_ms.take().is_none() {
+            self.failed_attempts += 1;
+        }
+        if self.disconnects.len() == DISCONNECT_HISTORY {
+            self.disconnects.pop_front();
+        }
+        self.disconnects.push_back(Disconnect {
+            time_ms: unix_time_ms(),
+            reason,
+        });
+    }
+
+    /// Records a heartbeat round trip.
+    pub fn record_rtt(&mut self, rtt_ms: u64) {
+        if self.rtt_samples.len() == RTT_SAMPLES {
+            self.rtt_samples.pop_front();
+        }
+        self.rtt_samples.push_back(rtt_ms);
+    }
+
+    /// Records a heartbeat that got no answer.
+    pub fn missed_heartbeat(&mut self) {
+        self.missed_heartbeats += 1;
+    }
+
+    /// Builds the report, adding QUIC path statistics from `connection`.
+    pub fn report(&self, connection: Option<&quinn::Connection>) -> ConnectionQuality {
+        let (avg, jitter) = mean_and_deviation(&self.rtt_samples);
+        let path = connection.map(|c| c.stats().path);
+        let sent_packets = path.as_ref().map_or(0, |p| p.sent_packets);
+        let lost_packets = path.as_ref().map_or(0, |p| p.lost_packets);
+        ConnectionQuality {
+            connected: self.connected_since_ms.is_some(),
+            connected_since_ms: self.connected_since_ms,
+            reconnects: self.connects.saturating_sub(1),
+            failed_attempts: self.failed_attempts,
+            disconnects: self.disconnects.iter().cloned().collect(),
+            heartbeat_rtt_ms: self.rtt_samples.back().copied(),
+            heartbeat_rtt_avg_ms: avg,
+            heartbeat_jitter_ms: jitter,
+            missed_heartbeats: self.missed_heartbeats,
+            quic_rtt_ms: path.as_ref().map(|p| p.rtt.as_millis() as u64),
+            sent_packets,
+            lost_packets,
+            loss_percent: if sent_packets == 0 {
+                0.0
+            } else {
+                lost_packets as f64 * 100.0 / sent_packets as f64
+            },
+        }
+    }
+}
+
+/// Returns the rounded mean and standard deviation of `samples`.
+fn mean_and_deviation(samples: &VecDeque<u64>) -> (Option<u64>, Option<u64>) {
+    if samples.is_empty() {
+        return (None, None);
+    }
+    let n = samples.len() as f64;
+    let mean = samples.iter().sum::<u64>() as f64 / n;
+    let variance = samples
+        .iter()
+        .map(|&s| (s as f64 - mean).powi(2))
+        .sum::<f64>()
+        / n;
+    (
+        Some(mean.round() as u64),
+        Some(variance.sqrt().round() as u64),
+    )
+}
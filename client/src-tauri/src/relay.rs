@@ -2,7 +2,8 @@
 //!
 //! Handles the bidirectional relay of data between a local TCP connection
 //! and a QUIC tunnel stream. Each TCP connection within a tunnel session
-//! is represented as a stream with its own `stream_id`.
+//! is represented as a stream with its own `stream_id`. Unix socket
+//! connections are relayed the same way.
 //!
 //! ## Data Flow
 //!
@@ -11,72 +12,150 @@
 //! ```
 //!
 //! The relay task manually copies data back and forth
-//! between the TCP socket and the QUIC stream.
+//! between the TCP socket and the QUIC stream. Data from QUIC is written
+//! out in the chunks QUIC received it in, without a copy of its own. Every chunk is reserved
+//! against the session's [`BufferBudget`], so a stalled reader pauses the
+//! session and, if it stays stalled, the stream is reset with
+//! [`RESET_BUFFER_LIMIT`].
+//!
+//! Delivered bytes are added to the session's [`TrafficCounters`], from
+//! which [`run_metrics`] emits a `tunnel-metrics` event every second.
+//!
+//! With `TUNNEL_COALESCE_MS` set, small local writes such as keystrokes are
+//! gathered for up to that many milliseconds, or until [`COALESCE_BYTES`]
+//! are pending, before they are sent into the tunnel.
+//!
+//! A stream of a session under `start_capture` is also written to the
+//! session's [`StreamCapture`] as it is delivered.
 
-use crate::state::AgentState;
-use quinn::{RecvStream, SendStream};
+use crate::capture::StreamCapture;
+use crate::state::{AgentState, BufferBudget, TrafficCounters, TrafficMetrics, TunnelRates};
+use quinn::{RecvStream, SendStream, VarInt};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::net::TcpStream;
+use std::time::Duration;
+use tauri::Emitter;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::sync::mpsc;
-use tunnel_protocol::ControlMessage;
+use tracing::Instrument;
+use tunnel_protocol::{ControlMessage, RESET_BUFFER_LIMIT};
+
+/// How long a stream may stay blocked on a full buffer before it is reset.
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Pending bytes that end coalescing early: about one QUIC packet payload.
+const COALESCE_BYTES: usize = 1200;
+
+/// How often `tunnel-metrics` is emitted.
+const METRICS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Why one direction of the relay stopped.
+#[derive(Debug)]
+enum RelayError {
+    Io(std::io::Error),
+    /// The session budget or the destination stayed blocked past [`STALL_TIMEOUT`].
+    BufferLimit,
+}
+
+impl std::fmt::Display for RelayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::BufferLimit => write!(f, "buffer limit reached, stream reset"),
+        }
+    }
+}
 
-/// Runs a bidirectional relay between a TCP stream and a QUIC stream.
-pub async fn handle_stream_relay(
-    tcp_stream: TcpStream,
+/// Runs a bidirectional relay between a local stream (TCP or Unix socket)
+/// and a QUIC stream.
+///
+/// Callers run this inside a `stream` span so both directions log with the
+/// session and stream IDs.
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_stream_relay<S>(
+    local_stream: S,
     session_id: String,
     stream_id: String,
     mut quic_send: SendStream,
     mut quic_recv: RecvStream,
     ctrl_tx: mpsc::UnboundedSender<ControlMessage>,
-    _state: Arc<AgentState>,
-) {
-    // We use tokio::io::copy_bidirectional to easily pipe data
-    // between the TCP socket and the QUIC stream natively.
+    state: Arc<AgentState>,
+    capture: Option<Arc<StreamCapture>>,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    // SendStream and RecvStream are split types in Quinn, so we run one
+    // copy loop per direction.
+    let (mut tcp_read, mut tcp_write) = tokio::io::split(local_stream);
+    let budget = state.session_budget(&session_id).await;
+    let traffic = state.session_traffic(&session_id).await;
+    let chunk_size = state.chunk_size();
+    let coalesce = state.coalesce_delay;
 
-    // Note: copy_bidirectional requires AsyncRead + AsyncWrite
-    // We can map SendStream and RecvStream into a unified Read/Write type
-    // or just run two manual tokio::spawn loops. Let's do the loops
-    // since SendStream and RecvStream are split types in Quinn.
-
-    let (mut tcp_read, mut tcp_write) = tcp_stream.into_split();
-
-    let stream_id_clone1 = stream_id.clone();
+    let budget1 = budget.clone();
+    let traffic1 = traffic.clone();
+    let capture1 = capture.clone();
     // TCP -> QUIC
-    let tcp_to_quic = tokio::spawn(async move {
-        tracing::info!("Starting relay TCP->QUIC for stream {}", stream_id_clone1);
-        match tokio::io::copy(&mut tcp_read, &mut quic_send).await {
-            Ok(total) => {
-                tracing::info!(
-                    "Relay TCP->QUIC [{}] finished, {} bytes",
-                    stream_id_clone1,
-                    total
-                );
-            }
-            Err(e) => {
-                tracing::error!("TCP->QUIC [{}] error: {}", stream_id_clone1, e);
+    let tcp_to_quic = tokio::spawn(
+        async move {
+            tracing::debug!("Starting relay TCP->QUIC");
+            match copy_with_budget(
+                &mut tcp_read,
+                &mut quic_send,
+                &budget1,
+                chunk_size,
+                coalesce,
+                &traffic1.uploaded,
+                capture1.as_deref().map(|c| (c, true)),
+            )
+            .await
+            {
+                Ok(total) => {
+                    tracing::info!(bytes = total, "Relay TCP->QUIC finished");
+                    let _ = quic_send.finish();
+                }
+                Err(e) => {
+                    tracing::error!("TCP->QUIC error: {}", e);
+                    if matches!(e, RelayError::BufferLimit) {
+                        let _ = quic_send.reset(VarInt::from_u32(RESET_BUFFER_LIMIT));
+                    } else {
+                        let _ = quic_send.finish();
+                    }
+                }
             }
         }
-        let _ = quic_send.finish();
-    });
+        .in_current_span(),
+    );
 
-    let stream_id_clone2 = stream_id.clone();
     // QUIC -> TCP
-    let quic_to_tcp = tokio::spawn(async move {
-        tracing::info!("Starting relay QUIC->TCP for stream {}", stream_id_clone2);
-        match tokio::io::copy(&mut quic_recv, &mut tcp_write).await {
-            Ok(total) => {
-                tracing::info!(
-                    "Relay QUIC->TCP [{}] finished, {} bytes",
-                    stream_id_clone2,
-                    total
-                );
-            }
-            Err(e) => {
-                tracing::error!("QUIC->TCP [{}] error: {}", stream_id_clone2, e);
+    let quic_to_tcp = tokio::spawn(
+        async move {
+            tracing::debug!("Starting relay QUIC->TCP");
+            match copy_chunks(
+                &mut quic_recv,
+                &mut tcp_write,
+                &budget,
+                chunk_size,
+                &traffic.downloaded,
+                capture.as_deref().map(|c| (c, false)),
+            )
+            .await
+            {
+                Ok(total) => {
+                    tracing::info!(bytes = total, "Relay QUIC->TCP finished");
+                }
+                Err(e) => {
+                    tracing::error!("QUIC->TCP error: {}", e);
+                    if matches!(e, RelayError::BufferLimit) {
+                        let _ = quic_recv.stop(VarInt::from_u32(RESET_BUFFER_LIMIT));
+                    }
+                }
             }
+            // Optionally shutdown TCP write half
         }
-        // Optionally shutdown TCP write half
-    });
+        .in_current_span(),
+    );
 
     // Wait for both to finish
     let _ = tokio::join!(tcp_to_quic, quic_to_tcp);
@@ -87,3 +166,195 @@ pub async fn handle_stream_relay(
         stream_id,
     });
 }
+
+/// Copies `reader` into `writer` in chunks of up to `chunk_size` bytes until
+/// EOF, reserving each chunk against `budget` and giving up if either the
+/// budget or the writer stalls. Delivered bytes are added to `counter`
+/// and, with `capture` set, recorded as coming from the local side or not.
+/// With `coalesce` set, a short read waits that long for more data first.
+async fn copy_with_budget<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    budget: &BufferBudget,
+    chunk_size: usize,
+    coalesce: Option<Duration>,
+    counter: &AtomicU64,
+    capture: Option<(&StreamCapture, bool)>,
+) -> Result<u64, RelayError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; chunk_size];
+    let mut total = 0u64;
+    let mut eof = false;
+
+    while !eof {
+        let mut n = reader.read(&mut buf).await.map_err(RelayError::Io)?;
+        if n == 0 {
+            break;
+        }
+        if let Some(delay) = coalesce {
+            (n, eof) = read_more(reader, &mut buf, n, delay)
+                .await
+                .map_err(RelayError::Io)?;
+        }
+
+        deliver(writer, budget, &buf[..n], counter, capture).await?;
+        total += n as u64;
+    }
+    if let Some((capture, from_local)) = capture {
+        capture.finish(from_local);
+    }
+    Ok(total)
+}
+
+/// Copies the QUIC stream `recv` into `writer` like [`copy_with_budget`],
+/// passing on the chunks QUIC already holds instead of copying them into a
+/// buffer of its own first.
+async fn copy_chunks<W>(
+    recv: &mut RecvStream,
+    writer: &mut W,
+    budget: &BufferBudget,
+    chunk_size: usize,
+    counter: &AtomicU64,
+    capture: Option<(&StreamCapture, bool)>,
+) -> Result<u64, RelayError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut total = 0u64;
+    while let Some(chunk) = recv
+        .read_chunk(chunk_size, true)
+        .await
+        .map_err(|e| RelayError::Io(e.into()))?
+    {
+        if chunk.bytes.is_empty() {
+            continue;
+        }
+        deliver(writer, budget, &chunk.bytes, counter, capture).await?;
+        total += chunk.bytes.len() as u64;
+    }
+    if let Some((capture, from_local)) = capture {
+        capture.finish(from_local);
+    }
+    Ok(total)
+}
+
+/// Reserves `data` against `budget`, writes it to `writer` and counts it,
+/// giving up if either the budget or the writer stalls.
+async fn deliver<W>(
+    writer: &mut W,
+    budget: &BufferBudget,
+    data: &[u8],
+    counter: &AtomicU64,
+    capture: Option<(&StreamCapture, bool)>,
+) -> Result<(), RelayError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let n = data.len();
+    let permit = tokio::time::timeout(STALL_TIMEOUT, budget.reserve(n))
+        .await
+        .map_err(|_| RelayError::BufferLimit)?;
+
+    budget.track(n);
+    let written = tokio::time::timeout(STALL_TIMEOUT, writer.write_all(data)).await;
+    budget.release(n);
+    drop(permit);
+
+    match written {
+        Ok(Ok(())) => {
+            counter.fetch_add(n as u64, Ordering::Relaxed);
+            if let Some((capture, from_local)) = capture {
+                capture.record(from_local, data);
+            }
+            Ok(())
+        }
+        Ok(Err(e)) => Err(RelayError::Io(e)),
+        Err(_) => Err(RelayError::BufferLimit),
+    }
+}
+
+/// Keeps reading into `buf` after its first `n` bytes until
+/// [`COALESCE_BYTES`] are pending, `delay` has passed or EOF is reached.
+/// Returns the pending byte count and whether EOF was reached.
+async fn read_more<R>(
+    reader: &mut R,
+    buf: &mut [u8],
+    mut n: usize,
+    delay: Duration,
+) -> std::io::Result<(usize, bool)>
+where
+    R: AsyncRead + Unpin,
+{
+    let deadline = tokio::time::Instant::now() + delay;
+    while n < COALESCE_BYTES.min(buf.len()) {
+        match tokio::time::timeout_at(deadline, reader.read(&mut buf[n..])).await {
+            Ok(Ok(0)) => return Ok((n, true)),
+            Ok(Ok(m)) => n += m,
+            Ok(Err(e)) => return Err(e),
+            Err(_) => break,
+        }
+    }
+    Ok((n, false))
+}
+
+/// Emits a `tunnel-metrics` event every [`METRICS_INTERVAL`] with each
+/// session's upload and download rate since the previous one. Nothing is
+/// emitted while no open tunnel has carried a stream.
+pub async fn run_metrics(state: Arc<AgentState>, app_handle: tauri::AppHandle) {
+    let mut interval = tokio::time::interval(METRICS_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut last_tick = interval.tick().await;
+    let mut previous: HashMap<String, (u64, u64)> = HashMap::new();
+
+    loop {
+        let tick = interval.tick().await;
+        let secs = tick.duration_since(last_tick).as_secs_f64().max(0.001);
+        last_tick = tick;
+
+        let totals: HashMap<String, (u64, u64)> = state
+            .session_traffic
+            .read()
+            .await
+            .iter()
+            .map(|(session_id, counters)| (session_id.clone(), totals(counters)))
+            .collect();
+        if totals.is_empty() && previous.is_empty() {
+            continue;
+        }
+
+        let rate = |now: u64, before: u64| (now.saturating_sub(before) as f64 / secs) as u64;
+        let mut tunnels: Vec<TunnelRates> = totals
+            .iter()
+            .map(|(session_id, &(uploaded, downloaded))| {
+                let (up_before, down_before) =
+                    previous.get(session_id).copied().unwrap_or_default();
+                TunnelRates {
+                    session_id: session_id.clone(),
+                    upload_bps: rate(uploaded, up_before),
+                    download_bps: rate(downloaded, down_before),
+                    uploaded_bytes: uploaded,
+                    downloaded_bytes: downloaded,
+                }
+            })
+            .collect();
+        tunnels.sort_by(|a, b| a.session_id.cmp(&b.session_id));
+
+        let metrics = TrafficMetrics {
+            upload_bps: tunnels.iter().map(|t| t.upload_bps).sum(),
+            download_bps: tunnels.iter().map(|t| t.download_bps).sum(),
+            tunnels,
+        };
+        let _ = app_handle.emit("tunnel-metrics", &metrics);
+        previous = totals;
+    }
+}
+
+fn totals(counters: &TrafficCounters) -> (u64, u64) {
+    (
+        counters.uploaded.load(Ordering::Relaxed),
+        counters.downloaded.load(Ordering::Relaxed),
+    )
+}
@@ -0,0 +1,453 @@
+//! # Custom DNS Resolution
+//!
+//! Agents inside split-DNS networks often cannot resolve internal names
+//! through the system resolver. [`ResolverConfig`] lets the agent answer
+//! target lookups from:
+//!
+//! - `hosts`: fixed addresses per name, like `/etc/hosts`;
+//! - `domains`: a nameserver per domain suffix, either plain DNS
+//!   (`10.0.0.53`, `10.0.0.53:5353`) or DNS over HTTPS
+//!   (`https://dns.corp.example/dns-query`). The longest matching suffix
+//!   wins, and `.` matches every name.
+//!
+//! Names matching neither go to the system resolver. Only A and AAAA
+//! records are queried; recursive servers include the records a CNAME
+//! points to in the same answer.
+
+use crate::https;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{TcpStream, UdpSocket};
+
+/// How long one query may take before the lookup fails.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Largest DNS message accepted over UDP.
+const MAX_UDP_MESSAGE: usize = 4096;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+/// Agent-side resolver settings, as set by the `set_resolver` command.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResolverConfig {
+    /// Fixed addresses per hostname.
+    #[serde(default)]
+    pub hosts: HashMap<String, Vec<IpAddr>>,
+
+    /// Nameservers for names under a domain.
+    #[serde(default)]
+    pub domains: Vec<DomainResolver>,
+}
+
+/// A nameserver used for every name under `domain`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DomainResolver {
+    /// Domain suffix such as `corp.example`, or `.` for all names.
+    pub domain: String,
+
+    /// `ip`, `ip:port` or an `https://` DoH URL.
+    pub server: String,
+}
+
+/// Where to send a query.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Nameserver {
+    Dns(SocketAddr),
+    Doh(url::Url),
+}
+
+impl Nameserver {
+    /// Parses the `server` field of a [`DomainResolver`].
+    pub fn parse(server: &str) -> Result<Self, String> {
+        let server = server.trim();
+        if server.starts_with("https://") {
+            let url = url::Url::parse(server).map_err(|e| format!("Invalid DoH URL: {}", e))?;
+            if url.host_str().is_none() {
+                return Err(format!("DoH URL has no host: {}", server));
+            }
+            return Ok(Self::Doh(url));
+        }
+        if let Ok(addr) = server.parse::<SocketAddr>() {
+            return Ok(Self::Dns(addr));
+        }
+        tunnel_protocol::normalize_host(server)
+            .parse::<IpAddr>()
+            .map(|ip| Self::Dns(SocketAddr::new(ip, 53)))
+            .map_err(|_| format!("Invalid nameserver: {}", server))
+    }
+}
+
+/// A validated [`ResolverConfig`].
+#[derive(Debug, Default)]
+pub struct Resolver {
+    hosts: HashMap<String, Vec<IpAddr>>,
+    /// Sorted longest domain first, so the first match is the most specific.
+    domains: Vec<(String, Nameserver)>,
+}
+
+impl Resolver {
+    /// Validates `config`, normalizing names to lowercase without a trailing dot.
+    pub fn new(config: &ResolverConfig) -> Result<Self, String> {
+        let hosts = config
+            .hosts
+            .iter()
+            .map(|(name, addrs)| (normalize_name(name), addrs.clone()))
+            .collect();
+        let mut domains = config
+            .domains
+            .iter()
+            .map(|d| Ok((normalize_name(&d.domain), Nameserver::parse(&d.server)?)))
+            .collect::<Result<Vec<_>, String>>()?;
+        domains.sort_by_key(|(domain, _)| std::cmp::Reverse(domain.len()));
+        Ok(Self { hosts, domains })
+    }
+
+    /// The fixed addresses configured for `host`, if any.
+    pub fn override_for(&self, host: &str) -> Option<&[IpAddr]> {
+        self.hosts.get(&normalize_name(host)).map(Vec::as_slice)
+    }
+
+    /// The nameserver responsible for `host`, or `None` for the system resolver.
+    pub fn nameserver_for(&self, host: &str) -> Option<&Nameserver> {
+        let host = normalize_name(host);
+        self.domains
+            .iter()
+            .find(|(domain, _)| {
+                domain.is_empty()
+                    || host == *domain
+                    || host
+                        .strip_suffix(domain.as_str())
+                        .is_some_and(|label| label.ends_with('.'))
+            })
+            .map(|(_, server)| server)
+    }
+}
+
+/// Lowercases `name` and strips a trailing dot; `.` becomes the empty root.
+fn normalize_name(name: &str) -> String {
+    name.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Resolves `host` to its IPv4 then IPv6 addresses through `server`.
+pub async fn lookup(server: &Nameserver, host: &str) -> io::Result<Vec<IpAddr>> {
+    let mut addrs = Vec::new();
+    for qtype in [TYPE_A, TYPE_AAAA] {
+        let id = rand_id();
+        let query = encode_query(id, host, qtype)?;
+        let reply = tokio::time::timeout(QUERY_TIMEOUT, exchange(server, &query))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "DNS query timed out"))??;
+        addrs.extend(decode_answers(&reply, id)?);
+    }
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No A or AAAA records for {}", host),
+        ));
+    }
+    Ok(addrs)
+}
+
+async fn exchange(server: &Nameserver, query: &[u8]) -> io::Result<Vec<u8>> {
+    match server {
+        Nameserver::Dns(addr) => {
+            let reply = exchange_udp(*addr, query).await?;
+            // Truncated: the full answer is only available over TCP.
+            if reply.len() > 2 && reply[2] & 0x02 != 0 {
+                exchange_tcp(*addr, query).await
+            } else {
+                Ok(reply)
+            }
+        }
+        Nameserver::Doh(url) => {
+            let url = url.clone();
+            let query = query.to_vec();
+            tokio::task::spawn_blocking(move || exchange_doh(&url, &query))
+                .await
+                .map_err(io::Error::other)?
+        }
+    }
+}
+
+async fn exchange_udp(addr: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0u8; MAX_UDP_MESSAGE];
+    let n = socket.recv(&mut buf).await?;
+    buf.truncate(n);
+    Ok(buf)
+}
+
+async fn exchange_tcp(addr: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut stream = TcpStream::connect(addr).await?;
+    let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(query);
+    stream.write_all(&framed).await?;
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await?;
+    let mut reply = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut reply).await?;
+    Ok(reply)
+}
+
+/// Sends `query` as an RFC 8484 POST.
+fn exchange_doh(url: &url::Url, query: &[u8]) -> io::Result<Vec<u8>> {
+    let response = https::request(
+        "POST",
+        url,
+        &[
+            ("Content-Type", "application/dns-message"),
+            ("Accept", "application/dns-message"),
+        ],
+        query,
+        QUERY_TIMEOUT,
+    )?;
+    if response.status != 200 {
+        return Err(io::Error::other(format!(
+            "DoH server answered {}",
+            response.status
+        )));
+    }
+    Ok(response.body)
+}
+
+/// Builds a recursive query for `name` and `qtype`.
+fn encode_query(id: u16, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let mut msg = Vec::with_capacity(18 + name.len());
+    msg.extend_from_slice(&id.to_be_bytes());
+    msg.extend_from_slice(&0x0100u16.to_be_bytes()); // recursion desired
+    msg.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // one question
+    for label in normalize_name(name).split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid hostname: {}", name),
+            ));
+        }
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    msg.extend_from_slice(&qtype.to_be_bytes());
+    msg.extend_from_slice(&1u16.to_be_bytes()); // class IN
+    Ok(msg)
+}
+
+/// Extracts the A and AAAA records of a reply to query `id`.
+fn decode_answers(msg: &[u8], id: u16) -> io::Result<Vec<IpAddr>> {
+    let u16_at = |pos: usize| -> io::Result<u16> {
+        msg.get(pos..pos + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(|| invalid("Truncated DNS reply"))
+    };
+    let flags = u16_at(2)?;
+    if u16_at(0)? != id || flags & 0x8000 == 0 {
+        return Err(invalid("Unexpected DNS reply"));
+    }
+    match flags & 0x000F {
+        0 => {}
+        3 => return Ok(Vec::new()), // NXDOMAIN
+        rcode => {
+            return Err(io::Error::other(format!(
+                "DNS server error (rcode {})",
+                rcode
+            )))
+        }
+    }
+
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(msg, pos)? + 4;
+    }
+
+    let mut addrs = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(msg, pos)?;
+        let rtype = u16_at(pos)?;
+        let len = u16_at(pos + 8)? as usize;
+        let data = msg
+            .get(pos + 10..pos + 10 + len)
+            .ok_or_else(|| invalid("Truncated DNS reply"))?;
+        match (rtype, len) {
+            (TYPE_A, 4) => addrs.push(IpAddr::from(<[u8; 4]>::try_from(data).unwrap())),
+            (TYPE_AAAA, 16) => addrs.push(IpAddr::from(<[u8; 16]>::try_from(data).unwrap())),
+            _ => {}
+        }
+        pos += 10 + len;
+    }
+    Ok(addrs)
+}
+
+/// Returns the position just past the name starting at `pos`.
+fn skip_name(msg: &[u8], mut pos: usize) -> io::Result<usize> {
+    loop {
+        let len = *msg.get(pos).ok_or_else(|| invalid("Truncated DNS name"))?;
+        match len {
+            0 => return Ok(pos + 1),
+            // Compression pointer: the name continues elsewhere.
+            l if l & 0xC0 == 0xC0 => return Ok(pos + 2),
+            l => pos += 1 + l as usize,
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn rand_id() -> u16 {
+    let bytes = uuid::Uuid::new_v4().into_bytes();
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TYPE_CNAME: u16 = 5;
+
+    /// Builds a reply to query `id` for `db.corp` with `answers`, each a
+    /// record type and its data, named by a pointer to the question.
+    fn reply(id: u16, answers: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut msg = encode_query(id, "db.corp", TYPE_A).unwrap();
+        msg[2] |= 0x80; // response
+        msg[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+        for (rtype, data) in answers {
+            msg.extend_from_slice(&[0xC0, 12]);
+            msg.extend_from_slice(&rtype.to_be_bytes());
+            msg.extend_from_slice(&[0, 1, 0, 0, 0, 60]); // class IN, TTL 60
+            msg.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            msg.extend_from_slice(data);
+        }
+        msg
+    }
+
+    #[test]
+    fn answers_of_other_types_are_skipped() {
+        let v6 = "fd00::1".parse::<Ipv6Addr>().unwrap().octets();
+        let msg = reply(
+            7,
+            &[
+                (TYPE_CNAME, b"\x02db\x04corp\x00"),
+                (TYPE_A, &[10, 0, 0, 5]),
+                (TYPE_AAAA, &v6),
+                // An A record must hold four bytes.
+                (TYPE_A, &v6),
+            ],
+        );
+        assert_eq!(
+            decode_answers(&msg, 7).unwrap(),
+            vec![
+                "10.0.0.5".parse::<IpAddr>().unwrap(),
+                "fd00::1".parse::<IpAddr>().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn unexpected_replies_are_refused() {
+        let msg = reply(7, &[(TYPE_A, &[10, 0, 0, 5])]);
+        assert!(decode_answers(&msg, 8).is_err());
+
+        let mut query = msg.clone();
+        query[2] &= !0x80;
+        assert!(decode_answers(&query, 7).is_err());
+
+        let mut nxdomain = msg.clone();
+        nxdomain[3] |= 3;
+        assert!(decode_answers(&nxdomain, 7).unwrap().is_empty());
+
+        let mut servfail = msg;
+        servfail[3] |= 2;
+        assert!(decode_answers(&servfail, 7).is_err());
+    }
+
+    #[test]
+    fn truncated_replies_are_errors() {
+        let msg = reply(7, &[(TYPE_A, &[10, 0, 0, 5]), (TYPE_A, &[10, 0, 0, 6])]);
+        for len in 0..msg.len() {
+            assert!(decode_answers(&msg[..len], 7).is_err(), "length {}", len);
+        }
+        // More answers announced than sent.
+        let mut short = msg.clone();
+        short[7] = 3;
+        assert!(decode_answers(&short, 7).is_err());
+    }
+
+    #[test]
+    fn compression_pointers_are_not_followed() {
+        // The answer's name points at itself; following it would never end.
+        let mut msg = reply(7, &[(TYPE_A, &[10, 0, 0, 5])]);
+        let answer = encode_query(7, "db.corp", TYPE_A).unwrap().len();
+        msg[answer + 1] = answer as u8;
+        assert_eq!(
+            decode_answers(&msg, 7).unwrap(),
+            vec!["10.0.0.5".parse::<IpAddr>().unwrap()]
+        );
+
+        // A pointer cut in half leaves the record truncated.
+        assert!(decode_answers(&msg[..answer + 1], 7).is_err());
+    }
+
+    #[test]
+    fn queries_hold_one_question() {
+        let msg = encode_query(0x1234, "DB.Corp.", TYPE_AAAA).unwrap();
+        assert_eq!(&msg[..6], &[0x12, 0x34, 0x01, 0x00, 0, 1]);
+        assert_eq!(&msg[12..], b"\x02db\x04corp\x00\x00\x1c\x00\x01");
+        assert!(encode_query(1, "a..b", TYPE_A).is_err());
+        assert!(encode_query(1, &"a".repeat(64), TYPE_A).is_err());
+    }
+
+    #[test]
+    fn longest_domain_wins() {
+        let config = ResolverConfig {
+            hosts: HashMap::from([("Build.Corp.".to_string(), vec![[10, 0, 0, 9].into()])]),
+            domains: vec![
+                DomainResolver {
+                    domain: ".".to_string(),
+                    server: "https://dns.example/dns-query".to_string(),
+                },
+                DomainResolver {
+                    domain: "corp".to_string(),
+                    server: "10.0.0.53".to_string(),
+                },
+                DomainResolver {
+                    domain: "lab.corp".to_string(),
+                    server: "[fd00::53]:5353".to_string(),
+                },
+            ],
+        };
+        let resolver = Resolver::new(&config).unwrap();
+        assert_eq!(
+            resolver.override_for("build.corp"),
+            Some(&[IpAddr::from([10, 0, 0, 9])][..])
+        );
+        let server = |host| resolver.nameserver_for(host).unwrap().clone();
+        assert_eq!(
+            server("db.lab.corp"),
+            Nameserver::Dns("[fd00::53]:5353".parse().unwrap())
+        );
+        assert_eq!(
+            server("CORP"),
+            Nameserver::Dns("10.0.0.53:53".parse().unwrap())
+        );
+        // `notcorp` is not under `corp`.
+        assert!(matches!(server("notcorp"), Nameserver::Doh(_)));
+
+        assert!(Nameserver::parse("dns.example").is_err());
+        assert!(Nameserver::parse("https://").is_err());
+    }
+}
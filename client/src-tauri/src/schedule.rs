@@ -0,0 +1,288 @@
+//! # Scheduled Tunnel Windows
+//!
+//! A schedule opens the tunnel of a saved profile for a daily window in
+//! local time, such as a backup window from 01:00 to 03:00, optionally on
+//! some weekdays only. A window whose end is before its start runs past
+//! midnight and belongs to the day it starts on.
+//!
+//! Schedules are persisted as JSON in the app config directory next to
+//! the profiles. [`run`] checks them every [`SCHEDULE_TICK`]: while a
+//! window is open it keeps the profile's tunnel open, reopening it after
+//! a reconnect, and when the window ends it closes the tunnel.
+
+use crate::commands;
+use crate::state::AgentState;
+use chrono::{Datelike, Local, NaiveDateTime, NaiveTime, Weekday};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tauri::Emitter;
+use tracing::{error, info, warn};
+
+/// File name of the schedule store inside the app config directory.
+pub const SCHEDULES_FILE: &str = "schedules.json";
+
+/// How often schedules are checked against the clock.
+pub const SCHEDULE_TICK: Duration = Duration::from_secs(30);
+
+/// Opens the tunnel of `profile` from `start` to `end` each scheduled day.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TunnelSchedule {
+    /// Unique schedule name (e.g., "nightly-backup").
+    pub name: String,
+
+    /// Name of the saved profile whose tunnel is opened.
+    pub profile: String,
+
+    /// Local time the window opens, as "HH:MM".
+    pub start: String,
+
+    /// Local time the window closes, as "HH:MM".
+    pub end: String,
+
+    /// Days the window opens on (e.g., `["Mon", "Fri"]`); every day when empty.
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+
+    /// Whether the schedule is followed; a disabled schedule keeps its
+    /// definition but opens nothing.
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl TunnelSchedule {
+    /// Checks the name and window before the schedule is stored.
+    pub fn check(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("Schedule name must not be empty".to_string());
+        }
+        let (start, end) = self.window()?;
+        if start == end {
+            return Err(format!(
+                "Schedule '{}' opens and closes at the same time",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether the window is open at local time `now`.
+    pub fn is_open(&self, now: NaiveDateTime) -> bool {
+        let Ok((start, end)) = self.window() else {
+            return false;
+        };
+        let time = now.time();
+        let today = now.weekday();
+        if start < end {
+            self.runs_on(today) && start <= time && time < end
+        } else {
+            (self.runs_on(today) && time >= start) || (self.runs_on(today.pred()) && time < end)
+        }
+    }
+
+    fn runs_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    fn window(&self) -> Result<(NaiveTime, NaiveTime), String> {
+        let parse = |value: &str| {
+            NaiveTime::parse_from_str(value.trim(), "%H:%M")
+                .map_err(|_| format!("Invalid time '{}', expected HH:MM", value))
+        };
+        Ok((parse(&self.start)?, parse(&self.end)?))
+    }
+}
+
+/// A schedule and whether its window is open right now, returned by
+/// `get_schedules`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduleStatus {
+    #[serde(flatten)]
+    pub schedule: TunnelSchedule,
+    pub open: bool,
+}
+
+/// Payload of the "schedule-opened" and "schedule-closed" events.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduleEvent {
+    pub schedule: String,
+    pub profile: String,
+}
+
+/// In-memory copy of the schedule file, written back on every change.
+#[derive(Debug, Default)]
+pub struct ScheduleStore {
+    path: Option<PathBuf>,
+    schedules: Vec<TunnelSchedule>,
+}
+
+impl ScheduleStore {
+    /// Loads schedules from `path`. A missing or unreadable file yields an
+    /// empty store that will be created on the first save.
+    pub fn load(path: PathBuf) -> Self {
+        let schedules = match std::fs::read_to_string(&path) {
+            Ok(raw) => serde_json::from_str(&raw).unwrap_or_else(|e| {
+                error!("Ignoring invalid schedule file {}: {}", path.display(), e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        info!("Loaded {} tunnel schedule(s)", schedules.len());
+        Self {
+            path: Some(path),
+            schedules,
+        }
+    }
+
+    /// Returns all schedules.
+    pub fn list(&self) -> &[TunnelSchedule] {
+        &self.schedules
+    }
+
+    /// Inserts `schedule`, replacing any schedule with the same name.
+    pub fn upsert(&mut self, schedule: TunnelSchedule) -> Result<(), String> {
+        match self.schedules.iter_mut().find(|s| s.name == schedule.name) {
+            Some(existing) => *existing = schedule,
+            None => self.schedules.push(schedule),
+        }
+        self.save()
+    }
+
+    /// Removes the schedule named `name`. Returns whether it existed.
+    pub fn remove(&mut self, name: &str) -> Result<bool, String> {
+        let before = self.schedules.len();
+        self.schedules.retain(|s| s.name != name);
+        let removed = self.schedules.len() != before;
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Err("Schedule storage is not initialized".to_string());
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        let json = serde_json::to_string_pretty(&self.schedules).map_err(|e| e.to_string())?;
+        std::fs::write(path, json).map_err(|e| e.to_string())
+    }
+}
+
+/// Follows the saved schedules for the life of the app, opening and
+/// closing profile tunnels as their windows start and end.
+pub async fn run(state: Arc<AgentState>, app_handle: tauri::AppHandle) {
+    // Schedules whose window is open, with the profile each one opened.
+    let mut open: HashMap<String, String> = HashMap::new();
+    loop {
+        let now = Local::now().naive_local();
+        let schedules = state.schedules.read().await.list().to_vec();
+        for schedule in &schedules {
+            if schedule.enabled && schedule.is_open(now) {
+                let opening = open
+                    .insert(schedule.name.clone(), schedule.profile.clone())
+                    .is_none();
+                if opening {
+                    info!("Schedule {}: window opened", schedule.name);
+                    emit(
+                        &app_handle,
+                        "schedule-opened",
+                        &schedule.name,
+                        &schedule.profile,
+                    );
+                }
+                if let Err(e) = keep_open(&state, &app_handle, &schedule.profile).await {
+                    // Reported once per window rather than on every tick.
+                    if opening {
+                        warn!("Schedule {}: {}", schedule.name, e);
+                        let _ = app_handle.emit("server-error", &e);
+                    }
+                }
+            }
+        }
+
+        // Windows that ended, and schedules disabled or deleted mid-window.
+        let ended: Vec<(String, String)> = open
+            .iter()
+            .filter(|(name, _)| {
+                !schedules
+                    .iter()
+                    .any(|s| &s.name == *name && s.enabled && s.is_open(now))
+            })
+            .map(|(name, profile)| (name.clone(), profile.clone()))
+            .collect();
+        for (name, profile) in ended {
+            open.remove(&name);
+            info!("Schedule {}: window closed", name);
+            close_profile(&state, &app_handle, &profile).await;
+            emit(&app_handle, "schedule-closed", &name, &profile);
+        }
+
+        tokio::time::sleep(SCHEDULE_TICK).await;
+    }
+}
+
+fn emit(app_handle: &tauri::AppHandle, event: &str, schedule: &str, profile: &str) {
+    let _ = app_handle.emit(
+        event,
+        &ScheduleEvent {
+            schedule: schedule.to_string(),
+            profile: profile.to_string(),
+        },
+    );
+}
+
+/// Opens the tunnel of `profile` unless it is open or the client is not
+/// connected; the next tick tries again after a reconnect.
+async fn keep_open(
+    state: &AgentState,
+    app_handle: &tauri::AppHandle,
+    profile: &str,
+) -> Result<(), String> {
+    if state.ctrl_tx.read().await.is_none() {
+        return Ok(());
+    }
+    let already_open = state
+        .tunnels
+        .read()
+        .await
+        .iter()
+        .any(|t| t.profile.as_deref() == Some(profile));
+    if already_open {
+        return Ok(());
+    }
+    let spec = state
+        .profiles
+        .read()
+        .await
+        .get(profile)
+        .cloned()
+        .ok_or_else(|| format!("Profile '{}' not found", profile))?;
+    commands::open_tunnel(state, app_handle, spec.into()).await?;
+    Ok(())
+}
+
+/// Closes every tunnel opened from `profile`.
+async fn close_profile(state: &AgentState, app_handle: &tauri::AppHandle, profile: &str) {
+    let sessions: Vec<String> = state
+        .tunnels
+        .read()
+        .await
+        .iter()
+        .filter(|t| t.profile.as_deref() == Some(profile))
+        .map(|t| t.session_id.clone())
+        .collect();
+    for session_id in &sessions {
+        state.abort_session_tasks(session_id).await;
+        state.pending_connects.write().await.remove(session_id);
+        commands::close_tunnel(state, app_handle, session_id).await;
+    }
+}
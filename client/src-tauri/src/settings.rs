@@ -0,0 +1,121 @@
+//! # App Settings
+//!
+//! Desktop behaviour the user chooses in the app, kept in [`SETTINGS_FILE`]:
+//! whether the app starts at login, whether closing the window leaves it
+//! running in the tray, and how the agent resolves tunnel targets. Starting at login is registered with the OS through
+//! the autostart plugin; such launches pass [`MINIMIZED_ARG`] so the agent
+//! comes up in the tray without opening its window.
+
+use crate::resolver::ResolverConfig;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tracing::error;
+
+/// File name of the settings inside the app config directory.
+pub const SETTINGS_FILE: &str = "settings.json";
+
+/// Command line argument of launches at login.
+pub const MINIMIZED_ARG: &str = "--minimized";
+
+/// User-chosen app behaviour.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Start the app when the user logs in.
+    pub launch_at_login: bool,
+
+    /// Closing the window hides it and keeps the agent running in the
+    /// tray. When off, closing the window quits the app.
+    pub run_in_background: bool,
+
+    /// Host overrides and nameservers for agent-side target lookups, as
+    /// set by `set_resolver`.
+    pub resolver: ResolverConfig,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            launch_at_login: false,
+            run_in_background: true,
+            resolver: ResolverConfig::default(),
+        }
+    }
+}
+
+/// In-memory copy of the settings file, written back on every change.
+#[derive(Debug, Default)]
+pub struct SettingsStore {
+    path: Option<PathBuf>,
+    settings: Settings,
+}
+
+impl SettingsStore {
+    /// Loads settings from `path`. A missing or unreadable file yields the
+    /// defaults, written on the first change.
+    pub fn load(path: PathBuf) -> Self {
+        let settings = match std::fs::read_to_string(&path) {
+            Ok(raw) => serde_json::from_str(&raw).unwrap_or_else(|e| {
+                error!("Ignoring invalid settings file {}: {}", path.display(), e);
+                Settings::default()
+            }),
+            Err(_) => Settings::default(),
+        };
+        Self {
+            path: Some(path),
+            settings,
+        }
+    }
+
+    /// Returns the current settings.
+    pub fn get(&self) -> Settings {
+        self.settings.clone()
+    }
+
+    /// Replaces the settings and saves them.
+    pub fn set(&mut self, settings: Settings) -> Result<(), String> {
+        self.settings = settings;
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Err("Settings storage is not initialized".to_string());
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        let json = serde_json::to_string_pretty(&self.settings).map_err(|e| e.to_string())?;
+        std::fs::write(path, json).map_err(|e| e.to_string())
+    }
+}
+
+/// Registers or unregisters the app to start at login, unless the OS
+/// already has it that way.
+#[cfg(desktop)]
+pub fn apply_launch_at_login(app_handle: &tauri::AppHandle, enabled: bool) -> Result<(), String> {
+    use tauri_plugin_autostart::ManagerExt;
+
+    let autolaunch = app_handle.autolaunch();
+    if autolaunch
+        .is_enabled()
+        .is_ok_and(|current| current == enabled)
+    {
+        return Ok(());
+    }
+    let result = if enabled {
+        autolaunch.enable()
+    } else {
+        autolaunch.disable()
+    };
+    result.map_err(|e| format!("Failed to update launch at login: {}", e))
+}
+
+/// Launching at login is not available on mobile.
+#[cfg(mobile)]
+pub fn apply_launch_at_login(_app_handle: &tauri::AppHandle, enabled: bool) -> Result<(), String> {
+    if enabled {
+        return Err("Launch at login is not supported on this platform".to_string());
+    }
+    Ok(())
+}
@@ -0,0 +1,594 @@
+//! # Reverse SOCKS
+//!
+//! In a reverse SOCKS tunnel the agent serves a SOCKS5 proxy on a loopback
+//! port of its machine, and every connection made through it egresses from
+//! the controller's network: the agent opens a data stream to the
+//! controller, which dials the requested destination and relays the stream
+//! like any other. This lets a remote box browse through the controller's
+//! local network.
+//!
+//! The destination travels at the head of the data stream, after the
+//! routing prefix: the port (2 bytes, big-endian), the host length (1 byte)
+//! and the host. The controller answers with one byte, a SOCKS5 reply code
+//! that the agent passes on to its client, before any payload.
+//!
+//! The controller only dials what the tunnel's [`EgressPolicy`] allows:
+//! public addresses unless the tunnel was opened with `allow_private`, or
+//! only its `allow` list when it has one. Host names are checked by the
+//! addresses they resolve to, so a name cannot smuggle a private address
+//! past the policy.
+
+use crate::agent::data_prefix;
+use crate::relay::handle_stream_relay;
+use crate::state::AgentState;
+use quinn::{RecvStream, SendStream};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use tunnel_protocol::{host_port, ControlMessage};
+
+/// How long a SOCKS client, or the agent announcing a destination, may
+/// take before the connection is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SOCKS_VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xFF;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// SOCKS5 reply codes, sent to the client and over the data stream.
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_GENERAL_FAILURE: u8 = 0x01;
+const REPLY_NOT_ALLOWED: u8 = 0x02;
+const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// Where the connections of a reverse SOCKS tunnel may go from the
+/// controller's machine, chosen when the tunnel is opened.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EgressPolicy {
+    /// The only destinations allowed, when not empty: host names,
+    /// `*.example.com` wildcards, IP addresses and CIDR networks such as
+    /// `10.0.0.0/8`.
+    pub allow: Vec<String>,
+
+    /// Without an `allow` list, dial private, loopback and link-local
+    /// addresses too. Off by default, so a tunnel only reaches the local
+    /// network when asked to.
+    pub allow_private: bool,
+}
+
+impl EgressPolicy {
+    /// Checks that every `allow` entry is a name, an address or a network.
+    pub fn validate(&self) -> Result<(), String> {
+        for pattern in &self.allow {
+            let valid = match pattern.split_once('/') {
+                Some(_) => network(pattern).is_some(),
+                None => !pattern.trim_start_matches("*.").is_empty(),
+            };
+            if !valid {
+                return Err(format!("Invalid egress rule '{}'", pattern));
+            }
+        }
+        Ok(())
+    }
+
+    /// Which addresses of `host` the tunnel may dial.
+    pub fn allows<'a>(&'a self, host: &str) -> impl Fn(IpAddr) -> bool + 'a {
+        let named = self.allow.iter().any(|pattern| matches_name(pattern, host));
+        move |ip| {
+            if self.allow.is_empty() {
+                self.allow_private || is_public(ip)
+            } else {
+                named || self.allow.iter().any(|pattern| contains(pattern, ip))
+            }
+        }
+    }
+}
+
+/// Whether the name rule `pattern` covers `host`.
+fn matches_name(pattern: &str, host: &str) -> bool {
+    if pattern.contains('/') || pattern.parse::<IpAddr>().is_ok() {
+        return false;
+    }
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host.len().checked_sub(domain.len() + 1).is_some_and(|at| {
+            host.as_bytes()[at] == b'.' && host[at + 1..].eq_ignore_ascii_case(domain)
+        }),
+        None => host.eq_ignore_ascii_case(pattern),
+    }
+}
+
+/// Whether the address or network rule `pattern` covers `ip`.
+fn contains(pattern: &str, ip: IpAddr) -> bool {
+    let ip = canonical(ip);
+    if let Ok(single) = pattern.parse::<IpAddr>() {
+        return canonical(single) == ip;
+    }
+    let Some((base, bits)) = network(pattern) else {
+        return false;
+    };
+    match (base, ip) {
+        (IpAddr::V4(base), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(bits)).unwrap_or(0);
+            u32::from(base) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(base), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(bits)).unwrap_or(0);
+            u128::from(base) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Parses a CIDR network such as `10.0.0.0/8`.
+fn network(pattern: &str) -> Option<(IpAddr, u8)> {
+    let (base, bits) = pattern.split_once('/')?;
+    let base = canonical(base.parse().ok()?);
+    let bits: u8 = bits.parse().ok()?;
+    let max = if base.is_ipv4() { 32 } else { 128 };
+    (bits <= max).then_some((base, bits))
+}
+
+/// IPv4-mapped IPv6 addresses as the IPv4 address they carry.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Whether `ip` is reachable on the internet rather than only from this
+/// machine or its networks.
+fn is_public(ip: IpAddr) -> bool {
+    match canonical(ip) {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            !(a == 0
+                || v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_broadcast()
+                || v4.is_multicast()
+                || (a == 100 && b & 0xC0 == 64))
+        }
+        IpAddr::V6(v6) => {
+            let first = v6.segments()[0];
+            !(v6.is_unspecified()
+                || v6.is_loopback()
+                || v6.is_multicast()
+                || first & 0xFE00 == 0xFC00
+                || first & 0xFFC0 == 0xFE80)
+        }
+    }
+}
+
+// ─── Agent Side ─────────────────────────────────────────────────
+
+/// Accept loop of the agent's SOCKS listener for tunnel `session_id`; runs
+/// until the tunnel's tasks are aborted.
+pub async fn serve(
+    listener: TcpListener,
+    connection: quinn::Connection,
+    tx: mpsc::UnboundedSender<ControlMessage>,
+    state: Arc<AgentState>,
+    session_id: String,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((tcp, peer)) => {
+                tokio::spawn(
+                    proxy(
+                        tcp,
+                        connection.clone(),
+                        tx.clone(),
+                        state.clone(),
+                        session_id.clone(),
+                    )
+                    .instrument(info_span!("socks", %peer)),
+                );
+            }
+            Err(e) => {
+                error!("SOCKS accept error: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+/// Serves one SOCKS client: reads its `CONNECT`, has the controller dial
+/// the destination through a new data stream and relays the connection.
+async fn proxy(
+    mut tcp: TcpStream,
+    connection: quinn::Connection,
+    tx: mpsc::UnboundedSender<ControlMessage>,
+    state: Arc<AgentState>,
+    session_id: String,
+) {
+    let (host, port) = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut tcp)).await {
+        Ok(Ok(destination)) => destination,
+        Ok(Err(e)) => {
+            debug!("SOCKS handshake failed: {}", e);
+            return;
+        }
+        Err(_) => {
+            debug!("SOCKS handshake timed out");
+            return;
+        }
+    };
+
+    let stream_ids = state.outgoing_streams(&session_id).await;
+    let stream_id = stream_ids.claim_new();
+    let span = info_span!("stream", stream_id = %stream_id);
+    async {
+        let target = host_port(&host, port);
+        info!(%target, "New SOCKS connection");
+        let streams = match open_egress(&connection, &session_id, &stream_id, &host, port).await {
+            Ok((send, recv, REPLY_SUCCEEDED)) => Some((send, recv)),
+            Ok((_, _, code)) => {
+                warn!(%target, code, "Controller could not reach the destination");
+                let _ = reply(&mut tcp, code).await;
+                None
+            }
+            Err(e) => {
+                warn!(%target, "Failed to reach the controller: {}", e);
+                let _ = reply(&mut tcp, REPLY_GENERAL_FAILURE).await;
+                None
+            }
+        };
+        let Some((send, recv)) = streams else {
+            return;
+        };
+        let _ = send.set_priority(state.traffic_class(&session_id).await.stream_priority());
+        if reply(&mut tcp, REPLY_SUCCEEDED).await.is_err() {
+            return;
+        }
+        let capture = state.stream_capture(&session_id, Some(port)).await;
+        handle_stream_relay(
+            tcp,
+            session_id.clone(),
+            stream_id.clone(),
+            send,
+            recv,
+            tx,
+            state.clone(),
+            capture,
+        )
+        .await;
+    }
+    .instrument(span)
+    .await;
+    stream_ids.release(&stream_id);
+}
+
+/// Opens the data stream for one SOCKS connection, names its destination
+/// and waits for the controller's reply code.
+async fn open_egress(
+    connection: &quinn::Connection,
+    session_id: &str,
+    stream_id: &str,
+    host: &str,
+    port: u16,
+) -> io::Result<(SendStream, RecvStream, u8)> {
+    let (mut send, mut recv) = connection.open_bi().await.map_err(io::Error::other)?;
+    let mut head = data_prefix(session_id, stream_id);
+    head.extend_from_slice(&port.to_be_bytes());
+    head.push(host.len() as u8);
+    head.extend_from_slice(host.as_bytes());
+    send.write_all(&head).await.map_err(io::Error::other)?;
+    // Generous, since the controller's dial has a timeout of its own.
+    let code = tokio::time::timeout(HANDSHAKE_TIMEOUT * 6, recv.read_u8())
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    Ok((send, recv, code))
+}
+
+/// Reads a SOCKS5 greeting and `CONNECT` request. Returns the destination,
+/// or the error after refusing the client.
+async fn handshake(tcp: &mut (impl AsyncRead + AsyncWrite + Unpin)) -> io::Result<(String, u16)> {
+    let [version, methods] = read_array(tcp).await?;
+    if version != SOCKS_VERSION {
+        return Err(invalid(format!("unsupported SOCKS version {}", version)));
+    }
+    let mut offered = vec![0u8; usize::from(methods)];
+    tcp.read_exact(&mut offered).await?;
+    if !offered.contains(&METHOD_NO_AUTH) {
+        tcp.write_all(&[SOCKS_VERSION, METHOD_NONE_ACCEPTABLE])
+            .await?;
+        return Err(invalid("client requires authentication"));
+    }
+    tcp.write_all(&[SOCKS_VERSION, METHOD_NO_AUTH]).await?;
+
+    let [_, command, _, address_type] = read_array(tcp).await?;
+    let host = match address_type {
+        ATYP_IPV4 => Ipv4Addr::from(read_array::<4>(tcp).await?).to_string(),
+        ATYP_IPV6 => Ipv6Addr::from(read_array::<16>(tcp).await?).to_string(),
+        ATYP_DOMAIN => {
+            let [len] = read_array(tcp).await?;
+            let mut name = vec![0u8; usize::from(len)];
+            tcp.read_exact(&mut name).await?;
+            String::from_utf8(name).map_err(|_| invalid("host name is not UTF-8"))?
+        }
+        _ => {
+            reply(tcp, REPLY_ADDRESS_NOT_SUPPORTED).await?;
+            return Err(invalid(format!(
+                "unsupported address type {}",
+                address_type
+            )));
+        }
+    };
+    let port = u16::from_be_bytes(read_array(tcp).await?);
+    if command != CMD_CONNECT {
+        reply(tcp, REPLY_COMMAND_NOT_SUPPORTED).await?;
+        return Err(invalid(format!("unsupported command {}", command)));
+    }
+    if host.is_empty() || port == 0 {
+        reply(tcp, REPLY_ADDRESS_NOT_SUPPORTED).await?;
+        return Err(invalid("empty destination"));
+    }
+    Ok((host, port))
+}
+
+/// Sends a SOCKS5 reply with an unspecified bound address.
+async fn reply(tcp: &mut (impl AsyncWrite + Unpin), code: u8) -> io::Result<()> {
+    tcp.write_all(&[SOCKS_VERSION, code, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await
+}
+
+// ─── Controller Side ────────────────────────────────────────────
+
+/// Serves a data stream the agent opened for reverse SOCKS tunnel
+/// `session_id`: dials the destination named at its head from this
+/// machine, if `policy` allows it, and relays the stream to it.
+pub async fn egress(
+    state: Arc<AgentState>,
+    session_id: String,
+    stream_id: String,
+    policy: EgressPolicy,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    tx: mpsc::UnboundedSender<ControlMessage>,
+) {
+    let (host, port) =
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, read_destination(&mut recv)).await {
+            Ok(Ok(destination)) => destination,
+            _ => {
+                warn!("Reverse SOCKS stream without a destination");
+                let _ = send.finish();
+                return;
+            }
+        };
+    let _ = send.set_priority(state.traffic_class(&session_id).await.stream_priority());
+    let target = host_port(&host, port);
+    let stream = match state
+        .dialer
+        .dial_where(
+            &session_id,
+            &host,
+            port,
+            state.connect_timeout,
+            policy.allows(&host),
+        )
+        .await
+    {
+        Ok(stream) => stream,
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            warn!(%target, "Reverse SOCKS destination refused by the tunnel's egress policy");
+            let _ = send.write_all(&[REPLY_NOT_ALLOWED]).await;
+            let _ = send.finish();
+            return;
+        }
+        Err(e) => {
+            warn!(%target, "Reverse SOCKS dial failed: {}", e);
+            let _ = send.write_all(&[reply_code(&e)]).await;
+            let _ = send.finish();
+            return;
+        }
+    };
+    info!(%target, "Reverse SOCKS connection");
+    if send.write_all(&[REPLY_SUCCEEDED]).await.is_err() {
+        return;
+    }
+    let capture = state.stream_capture(&session_id, Some(port)).await;
+    handle_stream_relay(
+        stream, session_id, stream_id, send, recv, tx, state, capture,
+    )
+    .await;
+}
+
+/// Reads the destination the agent wrote at the head of a stream.
+async fn read_destination(recv: &mut (impl AsyncRead + Unpin)) -> io::Result<(String, u16)> {
+    let [port_hi, port_lo, len] = read_array(recv).await?;
+    let port = u16::from_be_bytes([port_hi, port_lo]);
+    let mut host = vec![0u8; usize::from(len)];
+    recv.read_exact(&mut host).await?;
+    let host = String::from_utf8(host).map_err(|_| invalid("host name is not UTF-8"))?;
+    if host.is_empty() || port == 0 {
+        return Err(invalid("empty destination"));
+    }
+    Ok((host, port))
+}
+
+/// The SOCKS5 reply code closest to a failed dial.
+fn reply_code(e: &io::Error) -> u8 {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused => REPLY_CONNECTION_REFUSED,
+        io::ErrorKind::TimedOut | io::ErrorKind::NotFound => REPLY_HOST_UNREACHABLE,
+        _ => REPLY_GENERAL_FAILURE,
+    }
+}
+
+async fn read_array<const N: usize>(tcp: &mut (impl AsyncRead + Unpin)) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    tcp.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    /// Runs `handshake` against a client that sends `request`, returning
+    /// the result and what the client received.
+    async fn handshake_with(request: &[u8]) -> (io::Result<(String, u16)>, Vec<u8>) {
+        let (mut client, mut server) = duplex(1024);
+        client.write_all(request).await.unwrap();
+        client.shutdown().await.unwrap();
+        let result = handshake(&mut server).await;
+        drop(server);
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        (result, received)
+    }
+
+    #[tokio::test]
+    async fn handshake_reads_connect_requests() {
+        let (result, received) =
+            handshake_with(&[5, 1, 0, 5, 1, 0, 1, 10, 0, 0, 7, 0x01, 0xBB]).await;
+        assert_eq!(result.unwrap(), ("10.0.0.7".to_string(), 443));
+        assert_eq!(received, [5, 0]);
+
+        let mut domain = vec![5, 2, 2, 0, 5, 1, 0, 3, 11];
+        domain.extend_from_slice(b"example.com");
+        domain.extend_from_slice(&80u16.to_be_bytes());
+        let (result, _) = handshake_with(&domain).await;
+        assert_eq!(result.unwrap(), ("example.com".to_string(), 80));
+
+        let mut ipv6 = vec![5, 1, 0, 5, 1, 0, 4];
+        ipv6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        ipv6.extend_from_slice(&22u16.to_be_bytes());
+        let (result, _) = handshake_with(&ipv6).await;
+        assert_eq!(result.unwrap(), ("::1".to_string(), 22));
+    }
+
+    #[tokio::test]
+    async fn handshake_refuses_malformed_requests() {
+        // SOCKS4, authentication only, BIND, an unknown address type and
+        // an empty host.
+        let (result, received) = handshake_with(&[4, 1, 0]).await;
+        assert!(result.is_err());
+        assert!(received.is_empty());
+        let (result, received) = handshake_with(&[5, 1, 2]).await;
+        assert!(result.is_err());
+        assert_eq!(received, [5, METHOD_NONE_ACCEPTABLE]);
+        let (result, received) = handshake_with(&[5, 1, 0, 5, 2, 0, 1, 10, 0, 0, 7, 0, 80]).await;
+        assert!(result.is_err());
+        assert_eq!(received[3], REPLY_COMMAND_NOT_SUPPORTED);
+        let (result, received) = handshake_with(&[5, 1, 0, 5, 1, 0, 9]).await;
+        assert!(result.is_err());
+        assert_eq!(received[3], REPLY_ADDRESS_NOT_SUPPORTED);
+        let (result, received) = handshake_with(&[5, 1, 0, 5, 1, 0, 3, 0, 0, 80]).await;
+        assert!(result.is_err());
+        assert_eq!(received[3], REPLY_ADDRESS_NOT_SUPPORTED);
+    }
+
+    #[tokio::test]
+    async fn handshake_fails_on_truncated_requests() {
+        let full = [5, 1, 0, 5, 1, 0, 3, 4, b'h', b'o', b's', b't', 0, 80];
+        for len in 0..full.len() {
+            let (result, _) = handshake_with(&full[..len]).await;
+            assert!(result.is_err(), "accepted {} bytes", len);
+        }
+    }
+
+    #[tokio::test]
+    async fn destinations_are_read_from_the_stream_head() {
+        let mut head = 5432u16.to_be_bytes().to_vec();
+        head.push(2);
+        head.extend_from_slice(b"db");
+        assert_eq!(
+            read_destination(&mut &head[..]).await.unwrap(),
+            ("db".to_string(), 5432)
+        );
+
+        for len in 0..head.len() {
+            assert!(read_destination(&mut &head[..len]).await.is_err());
+        }
+        assert!(read_destination(&mut &[0u8, 80, 0][..]).await.is_err());
+        assert!(read_destination(&mut &[0u8, 0, 2, b'd', b'b'][..])
+            .await
+            .is_err());
+        assert!(read_destination(&mut &[0u8, 80, 1, 0xFF][..])
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn egress_reaches_public_addresses_by_default() {
+        let policy = EgressPolicy::default();
+        let allows = policy.allows("example.com");
+        assert!(allows("93.184.216.34".parse().unwrap()));
+        assert!(allows("2606:2800:220:1::".parse().unwrap()));
+        for private in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:192.168.1.1",
+        ] {
+            assert!(!allows(private.parse().unwrap()), "allowed {}", private);
+        }
+
+        let open = EgressPolicy {
+            allow_private: true,
+            ..EgressPolicy::default()
+        };
+        assert!(open.allows("nas.lan")("192.168.1.10".parse().unwrap()));
+    }
+
+    #[test]
+    fn egress_allow_lists_are_exclusive() {
+        let policy = EgressPolicy {
+            allow: vec![
+                "*.corp.example".to_string(),
+                "10.20.0.0/16".to_string(),
+                "192.168.1.5".to_string(),
+            ],
+            allow_private: false,
+        };
+        assert!(policy.validate().is_ok());
+        let any = "10.99.0.1".parse().unwrap();
+        assert!(policy.allows("git.corp.example")(any));
+        assert!(policy.allows("GIT.Corp.Example")(any));
+        assert!(!policy.allows("corp.example")(any));
+        assert!(!policy.allows("evilcorp.example")(any));
+        assert!(policy.allows("db")("10.20.3.4".parse().unwrap()));
+        assert!(policy.allows("db")("::ffff:10.20.3.4".parse().unwrap()));
+        assert!(!policy.allows("db")("10.21.0.1".parse().unwrap()));
+        assert!(policy.allows("192.168.1.5")("192.168.1.5".parse().unwrap()));
+        assert!(!policy.allows("example.com")(
+            "93.184.216.34".parse().unwrap()
+        ));
+
+        for invalid in ["10.0.0.0/33", "host/8", "*.", ""] {
+            let policy = EgressPolicy {
+                allow: vec![invalid.to_string()],
+                allow_private: false,
+            };
+            assert!(policy.validate().is_err(), "accepted {:?}", invalid);
+        }
+    }
+}
@@ -6,15 +6,44 @@
 //! - [`TunnelInfo`] — UI-facing tunnel information
 //! - [`AgentStatus`] — agent connection status for the frontend
 //! - [`PendingConnect`] — temporary storage for outgoing tunnel parameters
+//! - [`GroupStatus`] — aggregate status of a tunnel group
 //! - [`AgentTunnelInfo`] — agent-side tunnel target address
+//! - [`ObserverRequest`] / [`ObserveEnded`] — observer consent and teardown
+//! - [`TunnelApproval`] — incoming tunnel request awaiting the user's answer
+//! - [`AccessLogEntry`] — a data stream served by this agent, for auditing
+//! - [`BufferBudget`] — per-session cap on bytes held by relay tasks
+//! - [`TrafficCounters`] / [`TrafficMetrics`] — bytes relayed per session
+//!   and the throughput derived from them
 
-use serde::Serialize;
-use std::collections::HashMap;
-use tokio::sync::{mpsc, RwLock};
+use crate::capture::{Capture, StreamCapture};
+use crate::dial::{AddressFamily, DialManager};
+use crate::history::{HistoryStore, TunnelTotals};
+use crate::identity::Identity;
+use crate::known_agents::{IdentityChange, KnownAgentStore};
+use crate::oidc::SsoSession;
+use crate::pairing::PairingPayload;
+use crate::profiles::ProfileStore;
+use crate::quality::QualityTracker;
+use crate::schedule::ScheduleStore;
+use crate::settings::SettingsStore;
+use crate::socks::EgressPolicy;
+use crate::stats::StatsTracker;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot, Mutex, RwLock, Semaphore, SemaphorePermit};
 use tokio::task::JoinHandle;
-use tracing::info;
+use tracing::{error, info, warn};
+use uuid::Uuid;
 
-use tunnel_protocol::ControlMessage;
+use tunnel_protocol::{
+    unix_time_ms, AccessState, AgentSummary, ControlMessage, ServiceInfo, SessionSnapshot,
+    TrafficClass, MAX_CONNECT_TIMEOUT_MS, MAX_SERVICES,
+};
 
 // ─── Data Types ─────────────────────────────────────────────────
 
@@ -33,24 +62,122 @@ pub struct TunnelInfo {
     /// The local port being listened on (controller side only).
     pub local_port: u16,
 
-    /// Direction: "incoming" (agent receiving) or "outgoing" (controller initiating).
+    /// Unix socket on the agent being tunneled to, in place of
+    /// `remote_host:remote_port`.
+    pub remote_socket: Option<String>,
+
+    /// Local Unix socket listened on instead of `local_port` (controller side only).
+    pub local_socket: Option<String>,
+
+    /// Direction: "incoming" (agent receiving), "outgoing" (controller
+    /// initiating) or "public" (exposed on a relay port).
     pub direction: String,
 
-    /// Current status: "connecting", "active", or "error".
+    /// Current status: "connecting", "active", "error", "failed" when
+    /// the `Connect` timed out, or "unverified" while held because the
+    /// agent differs from the one known for the target.
     pub status: String,
+
+    /// Name of the profile this tunnel was opened from, if any.
+    pub profile: Option<String>,
+
+    /// Group of the profile this tunnel was opened from, if any.
+    pub group: Option<String>,
+
+    /// Relay port the service is published on ("public" tunnels only).
+    pub public_port: Option<u16>,
+
+    /// Relay ingress hostname the service is published on, over HTTP or
+    /// TLS ("public" tunnels only).
+    pub public_host: Option<String>,
+
+    /// User-chosen name shown instead of the session ID and target
+    /// (e.g., "prod-postgres").
+    pub label: Option<String>,
+
+    /// Latest round trip through the relay to the agent, in milliseconds
+    /// ("outgoing" tunnels only; `None` until the first `SessionPong`).
+    pub rtt_ms: Option<u64>,
+
+    /// Further ports forwarded by the tunnel besides `remote_port`, and
+    /// listeners added with `add_listener`. Their `local_port` is 0 on the
+    /// agent side.
+    pub extra_ports: Vec<PortPair>,
+
+    /// Whether local connections are set to `TCP_NODELAY` (controller side
+    /// only).
+    pub nodelay: bool,
+
+    /// When the tunnel is closed automatically, as Unix time in
+    /// milliseconds; `None` for tunnels without a TTL.
+    pub expires_at_ms: Option<u64>,
+
+    /// Whether this is a reverse SOCKS tunnel: the agent proxies on
+    /// `remote_port` and its connections egress from the controller.
+    pub reverse_socks: bool,
+
+    /// Where the connections of a reverse SOCKS tunnel we opened may go
+    /// ("outgoing" reverse SOCKS tunnels only).
+    pub socks_egress: Option<EgressPolicy>,
+
+    /// Scheduling class of the tunnel's data streams.
+    pub traffic_class: TrafficClass,
+
+    /// Most data streams the tunnel may have open at once, agreed by the
+    /// relay and the agent ("outgoing" tunnels only; `None` until ready).
+    pub max_streams: Option<u32>,
+}
+
+/// A local port and the agent-side port it forwards to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortPair {
+    pub local_port: u16,
+    pub remote_port: u16,
 }
 
 /// Agent connection status, returned to the frontend.
 #[derive(Debug, Clone, Serialize)]
 pub struct AgentStatus {
-    /// This agent's unique ID (e.g., "A3F8-B2C1").
-    pub agent_id: String,
+    /// This agent's unique ID (e.g., "A3F8-B2C1"), or `None` while not
+    /// registered as an agent.
+    pub agent_id: Option<String>,
 
     /// Whether the agent is currently connected to the relay server.
     pub connected: bool,
 
     /// The relay server URL this agent connects to.
     pub server_url: String,
+
+    /// Relays tried when `server_url` is unreachable, in order.
+    pub fallback_servers: Vec<String>,
+
+    /// The relay currently connected to, primary or fallback.
+    pub relay: Option<String>,
+
+    /// Tags this agent registers with (e.g., "env=prod").
+    pub tags: Vec<String>,
+
+    /// Name this agent registers with, usable by controllers instead of the ID.
+    pub name: Option<String>,
+
+    /// Estimated server clock minus local clock, once measured.
+    pub clock_skew_ms: Option<i64>,
+
+    /// Whether the skew exceeds [`CLOCK_SKEW_WARN_MS`].
+    pub clock_skew_warning: bool,
+
+    /// Identity provider of the SSO login, if signed in with one.
+    pub sso_issuer: Option<String>,
+
+    /// Version of the relay server, from the last `RegisterOk`.
+    pub server_version: Option<String>,
+
+    /// Why the server refused this client's version, if it did.
+    pub upgrade_required: Option<String>,
+
+    /// The reason an admin gave for banning this client, if the relay
+    /// closed the connection for it.
+    pub banned: Option<String>,
 }
 
 /// Temporary storage for a pending outgoing tunnel connection.
@@ -58,14 +185,246 @@ pub struct AgentStatus {
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub struct PendingConnect {
+    /// Agent ID or registered name of the target agent.
+    pub target_id: String,
+
     /// The local port to listen on once the tunnel is established.
     pub local_port: u16,
 
+    /// Address the local listener binds to. `None` binds both loopbacks;
+    /// anything else has been explicitly allowed by the user.
+    pub bind_address: Option<IpAddr>,
+
+    /// Local Unix socket to listen on instead of `local_port`.
+    pub local_socket: Option<PathBuf>,
+
     /// The remote host the agent should connect to.
     pub remote_host: String,
 
     /// The remote port the agent should connect to.
     pub remote_port: u16,
+
+    /// Unix socket the agent should connect to instead of `remote_host:remote_port`.
+    pub remote_socket: Option<String>,
+
+    /// Profile the tunnel is opened from, if any.
+    pub profile: Option<String>,
+
+    /// Group of that profile, if any.
+    pub group: Option<String>,
+
+    /// Label of that profile, if any.
+    pub label: Option<String>,
+
+    /// How long the agent may take to connect to the target for each
+    /// stream; `None` leaves it to the agent.
+    pub connect_timeout_ms: Option<u32>,
+
+    /// Further ports to forward, each with a listener of its own.
+    pub extra_ports: Vec<PortPair>,
+
+    /// Set `TCP_NODELAY` on local connections, so small writes of
+    /// interactive protocols are not held back.
+    pub nodelay: bool,
+
+    /// Invitation token from the target agent, sent in `Connect`.
+    pub invite: Option<String>,
+
+    /// Seconds after which the tunnel is closed automatically.
+    pub ttl_secs: Option<u64>,
+
+    /// Ask the agent for a SOCKS5 proxy on `remote_port` whose connections
+    /// this client dials, instead of listening locally.
+    pub reverse_socks: bool,
+
+    /// Where those connections may go.
+    pub egress: EgressPolicy,
+
+    /// Send the tunnel's data behind that of interactive tunnels.
+    pub traffic_class: TrafficClass,
+}
+
+/// Aggregate status of a tunnel group, returned by `get_group_status`.
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupStatus {
+    /// Group name (e.g., "staging stack").
+    pub group: String,
+
+    /// Number of profiles in the group.
+    pub profiles: usize,
+
+    /// Tunnels of the group that are established.
+    pub active: usize,
+
+    /// Tunnels of the group still waiting for `TunnelReady`.
+    pub connecting: usize,
+
+    /// Bytes currently buffered by the group's sessions.
+    pub buffered_bytes: usize,
+
+    /// "healthy" when every profile is active, "degraded" when some are,
+    /// and "down" when none are.
+    pub health: String,
+}
+
+/// Someone asking to observe one of our outgoing tunnels, awaiting our answer.
+#[derive(Debug, Clone, Serialize)]
+pub struct ObserverRequest {
+    /// The tunnel they want to observe.
+    pub session_id: String,
+
+    /// Server-side connection ID of the requester.
+    pub observer_id: String,
+
+    /// Identity name of the requester (e.g., "support-anna").
+    pub observer: String,
+}
+
+/// An incoming tunnel request held until the user approves or denies it.
+#[derive(Debug, Clone, Serialize)]
+pub struct TunnelApproval {
+    pub session_id: String,
+
+    /// Identity name of the controller; `None` for anonymous controllers.
+    pub requester: Option<String>,
+
+    pub remote_host: String,
+    pub remote_port: u16,
+    pub remote_socket: Option<String>,
+
+    /// Target connect timeout the controller asked for, if any.
+    pub connect_timeout_ms: Option<u32>,
+
+    /// Further ports on `remote_host` the controller asked for.
+    pub extra_ports: Vec<u16>,
+
+    /// Seconds after which the tunnel is closed automatically, if limited.
+    pub ttl_secs: Option<u64>,
+
+    /// Whether the controller asks for a SOCKS5 proxy on `remote_port`
+    /// that egresses from its network.
+    pub reverse_socks: bool,
+
+    /// Scheduling class the controller asked for.
+    pub traffic_class: TrafficClass,
+}
+
+/// A controller's request for access to one of our targets, kept by the
+/// server until the user decides it.
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessRequest {
+    pub access_id: String,
+
+    /// Identity name of the controller; `None` for anonymous controllers.
+    pub requester: Option<String>,
+
+    pub remote_host: String,
+    pub remote_port: u16,
+
+    /// Why the controller wants access, in its own words.
+    pub reason: Option<String>,
+
+    /// When it was made, milliseconds since the Unix epoch.
+    pub requested_at_ms: u64,
+}
+
+/// Where one of our access requests stands, returned by `request_access`
+/// and `check_access` and sent with the "access-update" event.
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessUpdate {
+    /// Handle for `check_access`; `None` when the server refused the
+    /// request outright.
+    pub access_id: Option<String>,
+    pub state: AccessState,
+
+    /// Token for `connect_to_agent`'s `invite`, on the first update after
+    /// an approval.
+    pub invite: Option<String>,
+
+    /// When the approval runs out, milliseconds since the Unix epoch.
+    pub expires_at_ms: u64,
+
+    /// Why the request was refused, denied or expired.
+    pub message: Option<String>,
+}
+
+/// Payload of the "observe-ended" event.
+#[derive(Debug, Clone, Serialize)]
+pub struct ObserveEnded {
+    pub session_id: String,
+    pub reason: String,
+}
+
+/// Payload of the "stream-open-failed" event: the agent could not reach
+/// the target for one of our streams.
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamOpenFailure {
+    pub session_id: String,
+    pub stream_id: String,
+    pub timed_out: bool,
+    pub limit_reached: bool,
+    pub message: String,
+}
+
+/// Payload of the "tunnel-rtt" event.
+#[derive(Debug, Clone, Serialize)]
+pub struct TunnelRtt {
+    pub session_id: String,
+    pub rtt_ms: u64,
+}
+
+/// Outcome of `probe_target`, returned to the frontend.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProbeReport {
+    /// Whether the agent's TCP connect succeeded.
+    pub reachable: bool,
+
+    /// How long the connect took; `None` when it failed.
+    pub latency_ms: Option<u64>,
+
+    /// Why the target could not be probed or reached.
+    pub message: Option<String>,
+}
+
+/// An invitation to one of this agent's targets, returned by
+/// `create_invite` for the user to pass on.
+#[derive(Debug, Clone, Serialize)]
+pub struct Invitation {
+    /// Single-use token for `connect_to_agent`'s `invite`.
+    pub token: String,
+
+    /// This agent's ID.
+    pub agent_id: String,
+
+    /// The relay server the invitation is valid on.
+    pub server: String,
+
+    /// The one target the invitation opens.
+    pub remote_host: String,
+    pub remote_port: u16,
+
+    /// When the token stops working, milliseconds since the Unix epoch.
+    pub expires_at_ms: u64,
+
+    /// A `tunnel://connect` link that redeems the token.
+    pub link: String,
+}
+
+/// The server's answer to `CreateInvite`: the token and its expiry in
+/// milliseconds since the Unix epoch, or why it refused.
+pub type InviteReply = Result<(String, u64), String>;
+
+/// A relay server found on the local network by `discover_servers`.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiscoveredServer {
+    /// Name the relay advertises (its `[mdns] name`).
+    pub name: String,
+
+    /// Address to pass to `set_server_url` (e.g., "192.168.1.20:7070").
+    pub address: String,
+
+    /// Server version from the advertisement, if given.
+    pub version: Option<String>,
 }
 
 /// Agent-side information about an active tunnel's target address.
@@ -78,11 +437,307 @@ pub struct AgentTunnelInfo {
 
     /// Target port (e.g., 3000).
     pub remote_port: u16,
+
+    /// Target Unix socket (e.g., "/var/run/docker.sock"), dialed instead of
+    /// `remote_host:remote_port` when set.
+    pub remote_socket: Option<String>,
+
+    /// Identity name of the controller that opened the tunnel; `None` for
+    /// anonymous controllers and published services.
+    pub requester: Option<String>,
+
+    /// Data streams currently open within this tunnel.
+    pub streams: Arc<StreamIds>,
+
+    /// How long each target connect may take before the stream fails.
+    pub connect_timeout: Duration,
+
+    /// Further ports on `remote_host` the tunnel forwards. When set, each
+    /// data stream goes to the port its `StreamOpen` names.
+    pub extra_ports: Vec<u16>,
+
+    /// Ports announced by `StreamOpen` for streams not yet linked.
+    pub stream_ports: Arc<StreamPorts>,
+
+    /// Whether the tunnel runs a SOCKS listener whose streams go to the
+    /// controller; the controller opens none of its own.
+    pub reverse_socks: bool,
+
+    /// Scheduling class of the data streams we send back.
+    pub traffic_class: TrafficClass,
 }
 
+/// Target ports of a multi-port tunnel's streams, announced by
+/// `StreamOpen` on the control stream and taken when the data stream
+/// itself arrives, which may happen first.
+#[derive(Debug, Default)]
+pub struct StreamPorts {
+    ports: std::sync::Mutex<HashMap<String, u16>>,
+    announced: tokio::sync::Notify,
+}
+
+impl StreamPorts {
+    fn ports(&self) -> std::sync::MutexGuard<'_, HashMap<String, u16>> {
+        self.ports.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Records that stream `stream_id` goes to `port`.
+    pub fn announce(&self, stream_id: &str, port: u16) {
+        self.ports().insert(stream_id.to_string(), port);
+        self.announced.notify_waiters();
+    }
+
+    /// Removes and returns the port of `stream_id`, waiting up to
+    /// `timeout` for its `StreamOpen`.
+    pub async fn take(&self, stream_id: &str, timeout: Duration) -> Option<u16> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            // Registered before checking, so an announcement in between
+            // still wakes this waiter.
+            let announced = self.announced.notified();
+            tokio::pin!(announced);
+            announced.as_mut().enable();
+            if let Some(port) = self.ports().remove(stream_id) {
+                return Some(port);
+            }
+            tokio::time::timeout_at(deadline, announced).await.ok()?;
+        }
+    }
+}
+
+/// Why a data stream was refused by its session.
+#[derive(Debug, PartialEq, Eq)]
+pub enum StreamRefusal {
+    /// The session already has the maximum number of streams.
+    Limit,
+    /// Another open stream of the session uses the same stream ID.
+    DuplicateId,
+}
+
+/// Stream IDs open within one session. IDs only need to be unique per
+/// session, so agents check incoming ones and controllers draw theirs here.
+#[derive(Debug, Default)]
+pub struct StreamIds(std::sync::Mutex<HashSet<String>>);
+
+impl StreamIds {
+    fn ids(&self) -> std::sync::MutexGuard<'_, HashSet<String>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Adds `id` unless `max` streams are open or it is already in use.
+    pub fn claim(&self, id: &str, max: usize) -> Result<(), StreamRefusal> {
+        let mut ids = self.ids();
+        if ids.len() >= max {
+            return Err(StreamRefusal::Limit);
+        }
+        if !ids.insert(id.to_string()) {
+            return Err(StreamRefusal::DuplicateId);
+        }
+        Ok(())
+    }
+
+    /// Adds and returns a fresh random stream ID, drawing again on a collision.
+    pub fn claim_new(&self) -> String {
+        let mut ids = self.ids();
+        loop {
+            let id = Uuid::new_v4().simple().to_string()[..8].to_string();
+            if ids.insert(id.clone()) {
+                return id;
+            }
+        }
+    }
+
+    /// Removes `id` once its stream has ended.
+    pub fn release(&self, id: &str) {
+        self.ids().remove(id);
+    }
+
+    /// Number of streams open.
+    pub fn count(&self) -> usize {
+        self.ids().len()
+    }
+}
+
+/// One data stream the agent linked to a local target, as kept in the
+/// access log returned by `get_access_log`.
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessLogEntry {
+    /// Local time the stream was opened, milliseconds since the Unix epoch.
+    pub time_ms: u64,
+
+    pub session_id: String,
+    pub stream_id: String,
+
+    /// Target the stream was relayed to (e.g., "127.0.0.1:22").
+    pub target: String,
+
+    /// Identity name of the controller; `None` if anonymous or public.
+    pub requester: Option<String>,
+}
+
+/// Per-session cap on bytes read from one side of a stream but not yet
+/// written to the other. Relay tasks reserve each chunk against the budget,
+/// so a stalled consumer pauses its session instead of growing memory.
+#[derive(Debug)]
+pub struct BufferBudget {
+    permits: Semaphore,
+    limit: usize,
+    buffered: AtomicUsize,
+    high_water: AtomicUsize,
+}
+
+impl BufferBudget {
+    /// Creates a budget allowing at most `limit` buffered bytes, capped at
+    /// what a semaphore can hold ([`Semaphore::MAX_PERMITS`]).
+    pub fn new(limit: usize) -> Self {
+        let limit = limit.min(Semaphore::MAX_PERMITS);
+        Self {
+            permits: Semaphore::new(limit),
+            limit,
+            buffered: AtomicUsize::new(0),
+            high_water: AtomicUsize::new(0),
+        }
+    }
+
+    /// Waits until `n` more bytes fit within the budget.
+    pub async fn reserve(&self, n: usize) -> SemaphorePermit<'_> {
+        self.permits
+            .acquire_many(n as u32)
+            .await
+            .expect("buffer budget semaphore is never closed")
+    }
+
+    /// Records `n` newly buffered bytes and updates the high-water mark.
+    pub fn track(&self, n: usize) {
+        let now = self.buffered.fetch_add(n, Ordering::Relaxed) + n;
+        self.high_water.fetch_max(now, Ordering::Relaxed);
+    }
+
+    /// Records that `n` buffered bytes were delivered.
+    pub fn release(&self, n: usize) {
+        self.buffered.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    /// Bytes currently held in relay buffers.
+    pub fn buffered(&self) -> usize {
+        self.buffered.load(Ordering::Relaxed)
+    }
+
+    /// Returns a UI-facing snapshot for `session_id`.
+    pub fn stats(&self, session_id: &str) -> BufferStats {
+        BufferStats {
+            session_id: session_id.to_string(),
+            buffered_bytes: self.buffered(),
+            high_water_bytes: self.high_water.load(Ordering::Relaxed),
+            limit_bytes: self.limit,
+        }
+    }
+}
+
+/// Buffer usage of one session, returned by `get_buffer_stats`.
+#[derive(Debug, Clone, Serialize)]
+pub struct BufferStats {
+    pub session_id: String,
+    pub buffered_bytes: usize,
+    pub high_water_bytes: usize,
+    pub limit_bytes: usize,
+}
+
+/// Bytes relayed by one session across all its streams.
+#[derive(Debug, Default)]
+pub struct TrafficCounters {
+    /// Bytes read from local connections and sent into the tunnel.
+    pub uploaded: AtomicU64,
+
+    /// Bytes received from the tunnel and written to local connections.
+    pub downloaded: AtomicU64,
+
+    /// Data streams opened for local connections.
+    pub streams: AtomicU64,
+}
+
+/// Throughput of one tunnel over the last metrics interval.
+#[derive(Debug, Clone, Serialize)]
+pub struct TunnelRates {
+    pub session_id: String,
+    pub upload_bps: u64,
+    pub download_bps: u64,
+    pub uploaded_bytes: u64,
+    pub downloaded_bytes: u64,
+}
+
+/// Payload of the "tunnel-metrics" event: per-tunnel rates in bytes per
+/// second and their sum across all tunnels.
+#[derive(Debug, Clone, Serialize)]
+pub struct TrafficMetrics {
+    pub tunnels: Vec<TunnelRates>,
+    pub upload_bps: u64,
+    pub download_bps: u64,
+}
+
+/// Maximum bytes buffered across all streams of one session.
+pub const SESSION_BUFFER_BYTES: usize = 8 * 1024 * 1024;
+
+/// Default cap on incoming tunnels this agent serves at once.
+pub const DEFAULT_MAX_TUNNELS: usize = 64;
+
+/// Number of entries kept in the agent's access log.
+pub const ACCESS_LOG_CAPACITY: usize = 1000;
+
+/// Default cap on data streams within one incoming tunnel.
+pub const DEFAULT_MAX_STREAMS: usize = 256;
+
+/// Default size of the read buffer of each stream direction.
+pub const DEFAULT_STREAM_BUFFER: usize = 64 * 1024;
+
+/// Default target connect timeout, overridden by `TUNNEL_CONNECT_TIMEOUT_SECS`
+/// and per tunnel by the controller.
+pub const DEFAULT_CONNECT_TIMEOUT_SECS: usize = 10;
+
+/// Upper bound on `TUNNEL_COALESCE_MS`, so batching stays imperceptible.
+pub const MAX_COALESCE_MS: usize = 50;
+
+/// Smallest accepted stream buffer; larger ones are capped at
+/// [`SESSION_BUFFER_BYTES`] so a single chunk always fits the budget.
+pub const MIN_STREAM_BUFFER: usize = 4 * 1024;
+
+/// Clock skew beyond which token expiry and scheduled tunnels become unreliable.
+pub const CLOCK_SKEW_WARN_MS: i64 = 30_000;
+
 /// Default relay server URL. Used when no custom URL is set.
 pub const DEFAULT_SERVER_URL: &str = "127.0.0.1:7070";
 
+/// Splits a comma-separated tag list, dropping empty entries.
+pub fn parse_tags(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Parses a comma-separated list of `name=host:port` services, such as
+/// `"ssh=127.0.0.1:22, grafana=127.0.0.1:3000"`.
+pub fn parse_services(raw: &str) -> Result<Vec<ServiceInfo>, String> {
+    let services = raw
+        .split(',')
+        .filter(|s| !s.trim().is_empty())
+        .map(ServiceInfo::parse)
+        .collect::<Result<Vec<_>, _>>()?;
+    if services.len() > MAX_SERVICES {
+        return Err(format!("At most {} services are allowed", MAX_SERVICES));
+    }
+    Ok(services)
+}
+
+/// Reads a numeric limit from the environment, falling back to `default`.
+fn env_limit(var: &str, default: usize) -> usize {
+    std::env::var(var)
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(default)
+}
+
 // ─── Central Agent State ────────────────────────────────────────
 
 /// The main application state, shared across all Tauri commands
@@ -91,16 +746,53 @@ pub const DEFAULT_SERVER_URL: &str = "127.0.0.1:7070";
 /// All mutable fields are protected by `RwLock` for safe concurrent access.
 pub struct AgentState {
     /// This agent's unique identifier, assigned by the server on registration.
-    /// Empty string until the server responds with RegisterOk.
-    pub agent_id: RwLock<String>,
+    /// `None` until the server responds with RegisterOk, and when the token
+    /// may only open tunnels.
+    pub agent_id: RwLock<Option<String>>,
 
     /// The relay server address (e.g., "1.2.3.4:7070").
     /// Can be changed at runtime from the UI.
     pub server_url: RwLock<String>,
 
+    /// Relays tried in order when `server_url` cannot be reached or the
+    /// connection to it is lost, from `TUNNEL_FALLBACK_SERVERS`.
+    pub fallback_servers: RwLock<Vec<String>>,
+
+    /// Address of the relay the live connection goes to.
+    pub relay: RwLock<Option<String>>,
+
     /// Whether we're currently connected to the relay server.
     pub connected: RwLock<bool>,
 
+    /// Credential sent in `Register`, if any. Initialized from the
+    /// `TUNNEL_TOKEN` environment variable and changeable from the UI, or
+    /// obtained by an SSO login.
+    pub auth_token: RwLock<Option<String>>,
+
+    /// SSO login state: where it is saved and the task keeping it fresh.
+    pub sso: Mutex<SsoSession>,
+
+    /// Tags sent in `Register`. Initialized from the comma-separated
+    /// `TUNNEL_TAGS` environment variable and changeable from the UI.
+    pub tags: RwLock<Vec<String>>,
+
+    /// Name sent in `Register`, so controllers can connect by name instead
+    /// of the random agent ID. Initialized from `TUNNEL_AGENT_NAME`.
+    pub name: RwLock<Option<String>>,
+
+    /// Services advertised in `Register`, so controllers can pick a target
+    /// by name. Initialized from `TUNNEL_SERVICES`.
+    pub services: RwLock<Vec<ServiceInfo>>,
+
+    /// Fixed agent ID and its pre-shared key, from `TUNNEL_AGENT_ID` and
+    /// `TUNNEL_AGENT_KEY`. When set, `Register` asks for this ID and the
+    /// server's challenge is answered with the key.
+    pub agent_key: Option<(String, String)>,
+
+    /// Ed25519 keypair backing the agent ID when no fixed ID is configured.
+    /// Loaded from the app config directory at startup.
+    pub identity: RwLock<Option<Identity>>,
+
     /// Channel to send outbound messages to the server over the control stream.
     /// `None` when not connected.
     pub ctrl_tx: RwLock<Option<mpsc::UnboundedSender<ControlMessage>>>,
@@ -108,7 +800,8 @@ pub struct AgentState {
     /// List of active tunnels (displayed in the UI).
     pub tunnels: RwLock<Vec<TunnelInfo>>,
 
-    /// Pending outgoing tunnel connections, keyed by target agent ID.
+    /// Pending outgoing tunnel connections, keyed by the `request_id` sent in
+    /// `Connect` (also the placeholder session ID shown in the UI).
     /// Removed once the tunnel is established.
     pub pending_connects: RwLock<HashMap<String, PendingConnect>>,
 
@@ -120,6 +813,141 @@ pub struct AgentState {
     /// Used for cleanup: aborting TCP listeners and relay tasks
     /// when a tunnel is closed.
     pub task_handles: RwLock<HashMap<String, Vec<JoinHandle<()>>>>,
+
+    /// Memory budgets for relayed data, keyed by session_id.
+    pub session_buffers: RwLock<HashMap<String, Arc<BufferBudget>>>,
+
+    /// Stream IDs of our open data streams in outgoing tunnels, keyed by session_id.
+    pub outgoing_streams: RwLock<HashMap<String, Arc<StreamIds>>>,
+
+    /// Relayed byte counts, keyed by session_id.
+    pub session_traffic: RwLock<HashMap<String, Arc<TrafficCounters>>>,
+
+    /// Running traffic captures, keyed by session_id.
+    pub captures: RwLock<HashMap<String, Arc<Capture>>>,
+
+    /// Callers waiting for an `AgentList` reply, in request order.
+    /// The server answers `ListAgents` in order, so replies are matched FIFO.
+    pub agent_list_waiters: Mutex<VecDeque<oneshot::Sender<Vec<AgentSummary>>>>,
+
+    /// Callers waiting for a `ProbeResult`, keyed by the probe's `request_id`.
+    pub probe_waiters: Mutex<HashMap<String, oneshot::Sender<ProbeReport>>>,
+
+    /// Callers waiting for an `InviteCreated`, keyed by `request_id`.
+    pub invite_waiters: Mutex<HashMap<String, oneshot::Sender<InviteReply>>>,
+
+    /// Callers waiting for an `AccessUpdate`, keyed by `request_id`.
+    pub access_waiters: Mutex<HashMap<String, oneshot::Sender<AccessUpdate>>>,
+
+    /// Concurrency-limited, DNS-caching dialer for agent-side target connections.
+    pub dialer: DialManager,
+
+    /// Saved tunnel profiles. Loaded from the app config directory at startup.
+    pub profiles: RwLock<ProfileStore>,
+
+    /// Scheduled tunnel windows. Loaded from the app config directory at startup.
+    pub schedules: RwLock<ScheduleStore>,
+
+    /// Local time the last `Register` or `Ping` was sent, for skew estimates.
+    pub probe_sent_ms: Mutex<Option<u64>>,
+
+    /// Estimated server clock minus local clock from the last reply.
+    pub clock_skew_ms: RwLock<Option<i64>>,
+
+    /// Version of the relay server, from the last `RegisterOk`.
+    pub server_version: RwLock<Option<String>>,
+
+    /// The server's message when it refused to register this client
+    /// version with `UpgradeRequired`.
+    pub upgrade_required: RwLock<Option<String>>,
+
+    /// The ban reason the relay closed the connection with. While set, the
+    /// client does not reconnect on its own, only on `reconnect_now`.
+    pub banned: RwLock<Option<String>>,
+
+    /// The live relay connection, for its QUIC path statistics.
+    pub connection: RwLock<Option<quinn::Connection>>,
+
+    /// Reconnects, heartbeat round trips and missed heartbeats.
+    pub quality: Mutex<QualityTracker>,
+
+    /// Uptime, bytes of closed sessions and tunnels opened today.
+    pub stats: Mutex<StatsTracker>,
+
+    /// Cuts the wait before the next reconnect attempt short, e.g. when
+    /// a mobile app returns to the foreground.
+    pub reconnect_now: tokio::sync::Notify,
+
+    /// Latest stats of sessions we observe, keyed by session ID.
+    pub observed: RwLock<HashMap<String, SessionSnapshot>>,
+
+    /// Observe requests for our tunnels that are waiting for consent.
+    pub observer_requests: RwLock<Vec<ObserverRequest>>,
+
+    /// Incoming tunnel requests waiting for approval.
+    pub tunnel_approvals: RwLock<Vec<TunnelApproval>>,
+
+    /// Access requests to our targets waiting for a decision. The server
+    /// sends them again on every registration.
+    pub access_requests: RwLock<Vec<AccessRequest>>,
+
+    /// Outgoing tunnels held because their agent differs from the one
+    /// known for the target, until the user trusts it or closes them.
+    pub identity_changes: RwLock<Vec<IdentityChange>>,
+
+    /// Agents this client has opened tunnels to. Loaded from the app config
+    /// directory at startup.
+    pub known_agents: RwLock<KnownAgentStore>,
+
+    /// Outgoing tunnels opened and closed. Loaded from the app config
+    /// directory at startup.
+    pub history: RwLock<HistoryStore>,
+
+    /// Launch-at-login and background-run settings. Loaded from the app
+    /// config directory at startup; a blocking lock because the window
+    /// close handler reads it outside the async runtime.
+    pub settings: std::sync::Mutex<SettingsStore>,
+
+    /// Accept incoming tunnels without asking, unless `TUNNEL_AUTO_ACCEPT=0`
+    /// holds them for approval. Requests are announced with a notification
+    /// either way.
+    pub auto_accept: bool,
+
+    /// Most recent data streams served as an agent, oldest first, at most
+    /// [`ACCESS_LOG_CAPACITY`] entries.
+    pub access_log: Mutex<VecDeque<AccessLogEntry>>,
+
+    /// Unexpired one-time tokens from our pairing codes, with their expiry.
+    pub pairing_tokens: Mutex<HashMap<String, Instant>>,
+
+    /// Tokens from scanned pairing codes, keyed by agent ID, sent with the
+    /// next `Connect` to that agent.
+    pub paired_agents: RwLock<HashMap<String, String>>,
+
+    /// A `tunnel://pair` link for another relay, held until the user
+    /// confirms the switch.
+    pub pending_pairing: RwLock<Option<PairingPayload>>,
+
+    /// Maximum incoming tunnels served at once, from `TUNNEL_MAX_TUNNELS`.
+    pub max_tunnels: usize,
+
+    /// Maximum data streams per incoming tunnel, from `TUNNEL_MAX_STREAMS`.
+    pub max_streams_per_session: usize,
+
+    /// Target connect timeout for tunnels that do not set their own, from
+    /// `TUNNEL_CONNECT_TIMEOUT_SECS`.
+    pub connect_timeout: Duration,
+
+    /// Requested read buffer per stream direction, from `TUNNEL_STREAM_BUFFER`.
+    pub stream_buffer_bytes: usize,
+
+    /// Chunk size relays use: `stream_buffer_bytes`, capped at the server's
+    /// `max_chunk_bytes` once registered.
+    pub chunk_bytes: AtomicUsize,
+
+    /// How long small local writes are held to be sent together, from
+    /// `TUNNEL_COALESCE_MS`. `None` (the default) sends every read at once.
+    pub coalesce_delay: Option<Duration>,
 }
 
 impl Default for AgentState {
@@ -132,18 +960,313 @@ impl AgentState {
     /// Creates a new `AgentState` with a freshly generated agent ID
     /// and all registries initialized to empty.
     pub fn new() -> Self {
+        let stream_buffer_bytes = env_limit("TUNNEL_STREAM_BUFFER", DEFAULT_STREAM_BUFFER)
+            .clamp(MIN_STREAM_BUFFER, SESSION_BUFFER_BYTES);
         Self {
-            agent_id: RwLock::new(String::new()),
+            agent_id: RwLock::new(None),
             server_url: RwLock::new(DEFAULT_SERVER_URL.to_string()),
+            fallback_servers: RwLock::new(parse_tags(
+                &std::env::var("TUNNEL_FALLBACK_SERVERS").unwrap_or_default(),
+            )),
+            relay: RwLock::new(None),
             connected: RwLock::new(false),
+            auth_token: RwLock::new(std::env::var("TUNNEL_TOKEN").ok()),
+            sso: Mutex::new(SsoSession::default()),
+            tags: RwLock::new(parse_tags(
+                &std::env::var("TUNNEL_TAGS").unwrap_or_default(),
+            )),
+            name: RwLock::new(std::env::var("TUNNEL_AGENT_NAME").ok()),
+            services: RwLock::new(
+                parse_services(&std::env::var("TUNNEL_SERVICES").unwrap_or_default())
+                    .unwrap_or_else(|e| {
+                        warn!("Ignoring TUNNEL_SERVICES: {}", e);
+                        Vec::new()
+                    }),
+            ),
+            agent_key: std::env::var("TUNNEL_AGENT_ID")
+                .ok()
+                .zip(std::env::var("TUNNEL_AGENT_KEY").ok()),
+            identity: RwLock::new(None),
             ctrl_tx: RwLock::new(None),
             tunnels: RwLock::new(Vec::new()),
             pending_connects: RwLock::new(HashMap::<String, PendingConnect>::new()),
             agent_tunnels: RwLock::new(HashMap::<String, AgentTunnelInfo>::new()),
             task_handles: RwLock::new(HashMap::<String, Vec<JoinHandle<()>>>::new()),
+            session_buffers: RwLock::new(HashMap::new()),
+            session_traffic: RwLock::new(HashMap::new()),
+            captures: RwLock::new(HashMap::new()),
+            outgoing_streams: RwLock::new(HashMap::new()),
+            agent_list_waiters: Mutex::new(VecDeque::new()),
+            probe_waiters: Mutex::new(HashMap::new()),
+            invite_waiters: Mutex::new(HashMap::new()),
+            access_waiters: Mutex::new(HashMap::new()),
+            dialer: DialManager::new(AddressFamily::from_env()),
+            profiles: RwLock::new(ProfileStore::default()),
+            schedules: RwLock::new(ScheduleStore::default()),
+            probe_sent_ms: Mutex::new(None),
+            clock_skew_ms: RwLock::new(None),
+            server_version: RwLock::new(None),
+            upgrade_required: RwLock::new(None),
+            banned: RwLock::new(None),
+            connection: RwLock::new(None),
+            quality: Mutex::new(QualityTracker::default()),
+            stats: Mutex::new(StatsTracker::default()),
+            reconnect_now: tokio::sync::Notify::new(),
+            observed: RwLock::new(HashMap::new()),
+            observer_requests: RwLock::new(Vec::new()),
+            tunnel_approvals: RwLock::new(Vec::new()),
+            access_requests: RwLock::new(Vec::new()),
+            identity_changes: RwLock::new(Vec::new()),
+            known_agents: RwLock::new(KnownAgentStore::default()),
+            history: RwLock::new(HistoryStore::default()),
+            settings: std::sync::Mutex::new(SettingsStore::default()),
+            auto_accept: std::env::var("TUNNEL_AUTO_ACCEPT").map_or(true, |v| v != "0"),
+            access_log: Mutex::new(VecDeque::new()),
+            pairing_tokens: Mutex::new(HashMap::new()),
+            paired_agents: RwLock::new(HashMap::new()),
+            pending_pairing: RwLock::new(None),
+            max_tunnels: env_limit("TUNNEL_MAX_TUNNELS", DEFAULT_MAX_TUNNELS),
+            max_streams_per_session: env_limit("TUNNEL_MAX_STREAMS", DEFAULT_MAX_STREAMS),
+            connect_timeout: Duration::from_secs(
+                env_limit("TUNNEL_CONNECT_TIMEOUT_SECS", DEFAULT_CONNECT_TIMEOUT_SECS)
+                    .clamp(1, MAX_CONNECT_TIMEOUT_MS as usize / 1000) as u64,
+            ),
+            stream_buffer_bytes,
+            chunk_bytes: AtomicUsize::new(stream_buffer_bytes),
+            coalesce_delay: match env_limit("TUNNEL_COALESCE_MS", 0) {
+                0 => None,
+                ms => Some(Duration::from_millis(ms.min(MAX_COALESCE_MS) as u64)),
+            },
         }
     }
 
+    /// Locks the app settings.
+    pub fn settings(&self) -> std::sync::MutexGuard<'_, SettingsStore> {
+        self.settings.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Caps the relay chunk size at the `max_chunk_bytes` the server
+    /// announced in `RegisterOk`, and returns the size now in use.
+    pub fn negotiate_chunk_size(&self, server_max: u32) -> usize {
+        let chunk = self
+            .stream_buffer_bytes
+            .min(server_max as usize)
+            .max(MIN_STREAM_BUFFER);
+        self.chunk_bytes.store(chunk, Ordering::Relaxed);
+        chunk
+    }
+
+    /// Chunk size for relay reads and writes.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the buffer budget for a session, creating it on first use.
+    pub async fn session_budget(&self, session_id: &str) -> Arc<BufferBudget> {
+        self.session_buffers
+            .write()
+            .await
+            .entry(session_id.to_string())
+            .or_insert_with(|| Arc::new(BufferBudget::new(SESSION_BUFFER_BYTES)))
+            .clone()
+    }
+
+    /// Returns the stream IDs of an outgoing session, creating them on first use.
+    pub async fn outgoing_streams(&self, session_id: &str) -> Arc<StreamIds> {
+        self.outgoing_streams
+            .write()
+            .await
+            .entry(session_id.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Returns the traffic counters for a session, creating them on first use.
+    pub async fn session_traffic(&self, session_id: &str) -> Arc<TrafficCounters> {
+        self.session_traffic
+            .write()
+            .await
+            .entry(session_id.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Drops the traffic counters of `session_id`, keeping its bytes in
+    /// the lifetime totals.
+    pub async fn drop_traffic(&self, session_id: &str) {
+        let counters = self.session_traffic.write().await.remove(session_id);
+        if let Some(counters) = counters {
+            self.stats.lock().await.retire(&counters);
+        }
+    }
+
+    /// Drops the traffic counters of every session, keeping their bytes in
+    /// the lifetime totals.
+    pub async fn drop_all_traffic(&self) {
+        let dropped: Vec<_> = self.session_traffic.write().await.drain().collect();
+        let mut stats = self.stats.lock().await;
+        for (_, counters) in dropped {
+            stats.retire(&counters);
+        }
+    }
+
+    /// Records the outgoing tunnel `session_id` in the history as closed,
+    /// with what its traffic counters show. Call before they are dropped.
+    /// Returns whether the history changed, so the caller can emit
+    /// `history-updated`.
+    pub async fn finish_history(&self, session_id: &str) -> bool {
+        let totals = self
+            .session_traffic
+            .read()
+            .await
+            .get(session_id)
+            .map(|t| TunnelTotals {
+                uploaded_bytes: t.uploaded.load(Ordering::Relaxed),
+                downloaded_bytes: t.downloaded.load(Ordering::Relaxed),
+                streams: t.streams.load(Ordering::Relaxed),
+            })
+            .unwrap_or_default();
+        let result = self
+            .history
+            .write()
+            .await
+            .closed(session_id, totals, unix_time_ms());
+        result.unwrap_or_else(|e| {
+            error!("Failed to save tunnel history: {}", e);
+            true
+        })
+    }
+
+    /// Computes the aggregate status of `group` from its profiles and the
+    /// tunnels opened from them.
+    pub async fn group_status(&self, group: &str) -> GroupStatus {
+        let profiles = self.profiles.read().await.in_group(group).len();
+        let tunnels = self.tunnels.read().await;
+        let members: Vec<&TunnelInfo> = tunnels
+            .iter()
+            .filter(|t| t.group.as_deref() == Some(group))
+            .collect();
+        let active = members.iter().filter(|t| t.status == "active").count();
+        let connecting = members.iter().filter(|t| t.status == "connecting").count();
+
+        let buffers = self.session_buffers.read().await;
+        let buffered_bytes = members
+            .iter()
+            .filter_map(|t| buffers.get(&t.session_id))
+            .map(|b| b.buffered())
+            .sum();
+
+        let health = if profiles > 0 && active >= profiles {
+            "healthy"
+        } else if active > 0 {
+            "degraded"
+        } else {
+            "down"
+        };
+
+        GroupStatus {
+            group: group.to_string(),
+            profiles,
+            active,
+            connecting,
+            buffered_bytes,
+            health: health.to_string(),
+        }
+    }
+
+    /// Appends to the access log, dropping the oldest entry when full.
+    pub async fn record_access(&self, entry: AccessLogEntry) {
+        let mut log = self.access_log.lock().await;
+        if log.len() == ACCESS_LOG_CAPACITY {
+            log.pop_front();
+        }
+        log.push_back(entry);
+    }
+
+    /// Consumes a pairing token, returning whether it was valid.
+    pub async fn take_pairing_token(&self, token: &str) -> bool {
+        let mut tokens = self.pairing_tokens.lock().await;
+        let now = Instant::now();
+        tokens.retain(|_, expires| *expires > now);
+        tokens.remove(token).is_some()
+    }
+
+    /// The primary relay followed by the fallbacks, without duplicates.
+    pub async fn relays(&self) -> Vec<String> {
+        let mut relays = vec![self.server_url.read().await.clone()];
+        for relay in self.fallback_servers.read().await.iter() {
+            if !relays.contains(relay) {
+                relays.push(relay.clone());
+            }
+        }
+        relays
+    }
+
+    /// Removes and returns the pending tunnel request for `session_id`.
+    pub async fn take_tunnel_approval(&self, session_id: &str) -> Option<TunnelApproval> {
+        let mut approvals = self.tunnel_approvals.write().await;
+        let index = approvals.iter().position(|a| a.session_id == session_id)?;
+        Some(approvals.remove(index))
+    }
+
+    /// Removes and returns the held tunnel `session_id` whose agent identity
+    /// changed.
+    pub async fn take_identity_change(&self, session_id: &str) -> Option<IdentityChange> {
+        let mut changes = self.identity_changes.write().await;
+        let index = changes.iter().position(|c| c.session_id == session_id)?;
+        Some(changes.remove(index))
+    }
+
+    /// The egress policy of `session_id` if it is a reverse SOCKS tunnel
+    /// this client opened, whose data streams come from the agent.
+    pub async fn socks_egress(&self, session_id: &str) -> Option<EgressPolicy> {
+        self.tunnels
+            .read()
+            .await
+            .iter()
+            .find(|t| t.session_id == session_id && t.direction == "outgoing")
+            .and_then(|t| t.socks_egress.clone())
+    }
+
+    /// Stream limit agreed for the outgoing tunnel `session_id`, if any.
+    pub async fn stream_limit(&self, session_id: &str) -> Option<usize> {
+        self.tunnels
+            .read()
+            .await
+            .iter()
+            .find(|t| t.session_id == session_id)
+            .and_then(|t| t.max_streams)
+            .map(|max| max as usize)
+    }
+
+    /// Scheduling class of tunnel `session_id`.
+    pub async fn traffic_class(&self, session_id: &str) -> TrafficClass {
+        self.tunnels
+            .read()
+            .await
+            .iter()
+            .find(|t| t.session_id == session_id)
+            .map(|t| t.traffic_class)
+            .unwrap_or_default()
+    }
+
+    /// Starts a capture flow for a new stream of `session_id` when the
+    /// session is being captured.
+    pub async fn stream_capture(
+        &self,
+        session_id: &str,
+        remote_port: Option<u16>,
+    ) -> Option<Arc<StreamCapture>> {
+        let capture = self.captures.read().await.get(session_id).cloned()?;
+        Some(capture.stream(remote_port))
+    }
+
+    /// Stops the capture of `session_id`, if any.
+    pub async fn stop_capture(&self, session_id: &str) -> Option<u64> {
+        let capture = self.captures.write().await.remove(session_id)?;
+        Some(capture.stop())
+    }
+
     /// Aborts all spawned async tasks associated with a specific session.
     /// Called when a tunnel is closed to clean up TCP listeners and relays.
     pub async fn abort_session_tasks(&self, session_id: &str) {
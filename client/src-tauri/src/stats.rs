@@ -0,0 +1,169 @@
+//! # Usage Statistics
+//!
+//! Totals for the overview screen, returned by `get_stats`: how long the
+//! app has been running, how often it reconnected to the relay, how many
+//! bytes its tunnels relayed and how many tunnels were opened today.
+//!
+//! Bytes of live sessions are read from their [`TrafficCounters`]; the
+//! [`StatsTracker`] keeps the counts of sessions that already closed. The
+//! byte totals and the daily count, with its date, are kept in
+//! [`STATS_FILE`], so they survive a restart. Uptime and reconnects start
+//! over with the app.
+
+use crate::state::TrafficCounters;
+use chrono::{Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use tracing::{error, warn};
+
+/// File name of the saved totals inside the app config directory.
+pub const STATS_FILE: &str = "stats.json";
+
+/// Totals returned by `get_stats`.
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentStats {
+    /// Seconds since the app started.
+    pub uptime_secs: u64,
+
+    /// Whether the relay connection is currently up.
+    pub connected: bool,
+
+    /// Connections to the relay established after the first one.
+    pub reconnects: u32,
+
+    /// Bytes ever sent into and received from tunnels, in both directions
+    /// of the agent and the controller role.
+    pub uploaded_bytes: u64,
+    pub downloaded_bytes: u64,
+
+    /// Tunnels opened since local midnight, outgoing and incoming.
+    pub tunnels_opened_today: u32,
+}
+
+/// What [`STATS_FILE`] holds.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct SavedStats {
+    uploaded: u64,
+    downloaded: u64,
+
+    /// The day `opened_today` counts.
+    today: NaiveDate,
+    opened_today: u32,
+}
+
+impl Default for SavedStats {
+    fn default() -> Self {
+        Self {
+            uploaded: 0,
+            downloaded: 0,
+            today: Local::now().date_naive(),
+            opened_today: 0,
+        }
+    }
+}
+
+/// Accumulates what outlives a session for [`AgentStats`].
+#[derive(Debug)]
+pub struct StatsTracker {
+    path: Option<PathBuf>,
+    started: Instant,
+    saved: SavedStats,
+}
+
+impl Default for StatsTracker {
+    fn default() -> Self {
+        Self {
+            path: None,
+            started: Instant::now(),
+            saved: SavedStats::default(),
+        }
+    }
+}
+
+impl StatsTracker {
+    /// Loads the totals from `path`. A missing or unreadable file starts
+    /// them at zero, written on the first change.
+    pub fn load(path: PathBuf) -> Self {
+        let saved = match std::fs::read_to_string(&path) {
+            Ok(raw) => serde_json::from_str(&raw).unwrap_or_else(|e| {
+                error!("Ignoring invalid stats file {}: {}", path.display(), e);
+                SavedStats::default()
+            }),
+            Err(_) => SavedStats::default(),
+        };
+        Self {
+            path: Some(path),
+            started: Instant::now(),
+            saved,
+        }
+    }
+
+    /// Records a tunnel that became ready.
+    pub fn tunnel_opened(&mut self) {
+        self.roll_day();
+        self.saved.opened_today += 1;
+        self.save();
+    }
+
+    /// Adds the bytes of a session whose counters are being dropped.
+    pub fn retire(&mut self, counters: &TrafficCounters) {
+        self.saved.uploaded += counters.uploaded.load(Ordering::Relaxed);
+        self.saved.downloaded += counters.downloaded.load(Ordering::Relaxed);
+        self.save();
+    }
+
+    /// Builds the report. `live` are the counters of open sessions.
+    pub fn report<'a>(
+        &mut self,
+        live: impl IntoIterator<Item = &'a TrafficCounters>,
+        connected: bool,
+        reconnects: u32,
+    ) -> AgentStats {
+        self.roll_day();
+        let (uploaded_bytes, downloaded_bytes) = live.into_iter().fold(
+            (self.saved.uploaded, self.saved.downloaded),
+            |(up, down), c| {
+                (
+                    up + c.uploaded.load(Ordering::Relaxed),
+                    down + c.downloaded.load(Ordering::Relaxed),
+                )
+            },
+        );
+        AgentStats {
+            uptime_secs: self.started.elapsed().as_secs(),
+            connected,
+            reconnects,
+            uploaded_bytes,
+            downloaded_bytes,
+            tunnels_opened_today: self.saved.opened_today,
+        }
+    }
+
+    /// Starts the daily count over after local midnight.
+    fn roll_day(&mut self) {
+        let today = Local::now().date_naive();
+        if today != self.saved.today {
+            self.saved.today = today;
+            self.saved.opened_today = 0;
+        }
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let result = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| {
+                let json =
+                    serde_json::to_string_pretty(&self.saved).map_err(std::io::Error::other)?;
+                std::fs::write(path, json)
+            });
+        if let Err(e) = result {
+            warn!("Failed to save stats to {}: {}", path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! # System Tray
+//!
+//! Tray icon that keeps the app usable with its window closed. The menu
+//! shows the connection status, offers saved profiles that are not open
+//! yet, and lists open tunnels so each can be disconnected. It is rebuilt
+//! whenever the tunnel list, the connection or the profiles change.
+
+use crate::state::AgentState;
+use crate::{agent, commands};
+use std::sync::Arc;
+use tauri::menu::{Menu, MenuBuilder, MenuEvent, MenuItemBuilder, SubmenuBuilder};
+use tauri::tray::TrayIconBuilder;
+use tauri::{AppHandle, Emitter, Listener, Manager};
+use tracing::warn;
+
+/// ID of the app's only tray icon.
+const TRAY_ID: &str = "main";
+
+/// Events after which the menu is rebuilt.
+const REFRESH_EVENTS: [&str; 4] = [
+    "tunnels-updated",
+    "connection-status",
+    "registered",
+    "profiles-updated",
+];
+
+/// Menu item IDs. Profile and tunnel items append the profile name or
+/// session ID to their prefix.
+const SHOW_ID: &str = "show";
+const QUIT_ID: &str = "quit";
+const CONNECT_PREFIX: &str = "connect:";
+const DISCONNECT_PREFIX: &str = "disconnect:";
+
+/// Creates the tray icon and keeps its menu in sync with the agent state.
+pub fn create(app: &tauri::App) -> tauri::Result<()> {
+    let mut tray = TrayIconBuilder::with_id(TRAY_ID)
+        .tooltip("Tunnel Agent — disconnected")
+        .menu(&build_menu(app.handle(), "Disconnected", &[], &[])?)
+        .show_menu_on_left_click(true)
+        .on_menu_event(handle_menu_event);
+    if let Some(icon) = app.default_window_icon() {
+        tray = tray.icon(icon.clone());
+    }
+    tray.build(app)?;
+
+    for event in REFRESH_EVENTS {
+        let app_handle = app.handle().clone();
+        app.listen_any(event, move |_| {
+            tauri::async_runtime::spawn(refresh(app_handle.clone()));
+        });
+    }
+    Ok(())
+}
+
+/// Rebuilds the menu and tooltip from the current state.
+async fn refresh(app_handle: AppHandle) {
+    let state = app_handle.state::<Arc<AgentState>>().inner().clone();
+
+    let status = if *state.connected.read().await {
+        match state.agent_id.read().await.as_deref() {
+            Some(agent_id) => format!("Connected as {}", agent_id),
+            None => "Connected".to_string(),
+        }
+    } else {
+        "Disconnected".to_string()
+    };
+
+    // Open tunnels, by label, else by profile or local address.
+    let (tunnels, open_profiles): (Vec<(String, String)>, Vec<String>) = {
+        let tunnels = state.tunnels.read().await;
+        let labels = tunnels
+            .iter()
+            .map(|t| {
+                let label = match (&t.label, &t.profile, &t.local_socket) {
+                    (Some(label), _, _) => label.clone(),
+                    (None, Some(profile), _) => profile.clone(),
+                    (None, None, Some(socket)) => socket.clone(),
+                    (None, None, None) if t.local_port != 0 => {
+                        format!("localhost:{}", t.local_port)
+                    }
+                    (None, None, None) => format!("{} ({})", t.session_id, t.direction),
+                };
+                (t.session_id.clone(), label)
+            })
+            .collect();
+        let open = tunnels.iter().filter_map(|t| t.profile.clone()).collect();
+        (labels, open)
+    };
+    let profiles: Vec<String> = state
+        .profiles
+        .read()
+        .await
+        .list()
+        .iter()
+        .map(|p| p.name.clone())
+        .filter(|name| !open_profiles.contains(name))
+        .collect();
+
+    let Some(tray) = app_handle.tray_by_id(TRAY_ID) else {
+        return;
+    };
+    match build_menu(&app_handle, &status, &profiles, &tunnels) {
+        Ok(menu) => {
+            let _ = tray.set_menu(Some(menu));
+        }
+        Err(e) => warn!("Failed to build tray menu: {}", e),
+    }
+    let _ = tray.set_tooltip(Some(format!("Tunnel Agent — {}", status.to_lowercase())));
+}
+
+fn build_menu(
+    app_handle: &AppHandle,
+    status: &str,
+    profiles: &[String],
+    tunnels: &[(String, String)],
+) -> tauri::Result<Menu<tauri::Wry>> {
+    let status = MenuItemBuilder::with_id("status", status)
+        .enabled(false)
+        .build(app_handle)?;
+
+    let mut connect = SubmenuBuilder::new(app_handle, "Connect").enabled(!profiles.is_empty());
+    for name in profiles {
+        connect = connect.text(format!("{}{}", CONNECT_PREFIX, name), name);
+    }
+    let mut disconnect = SubmenuBuilder::new(app_handle, "Disconnect").enabled(!tunnels.is_empty());
+    for (session_id, label) in tunnels {
+        disconnect = disconnect.text(format!("{}{}", DISCONNECT_PREFIX, session_id), label);
+    }
+
+    MenuBuilder::new(app_handle)
+        .item(&status)
+        .separator()
+        .item(&connect.build()?)
+        .item(&disconnect.build()?)
+        .separator()
+        .text(SHOW_ID, "Show Window")
+        .text(QUIT_ID, "Quit")
+        .build()
+}
+
+fn handle_menu_event(app_handle: &AppHandle, event: MenuEvent) {
+    let id = event.id().as_ref();
+    if id == SHOW_ID {
+        crate::show_main_window(app_handle);
+    } else if id == QUIT_ID {
+        let app_handle = app_handle.clone();
+        tauri::async_runtime::spawn(async move {
+            let state = app_handle.state::<Arc<AgentState>>().inner().clone();
+            agent::unregister(&state).await;
+            app_handle.exit(0);
+        });
+    } else if let Some(name) = id.strip_prefix(CONNECT_PREFIX) {
+        tauri::async_runtime::spawn(connect_profile(app_handle.clone(), name.to_string()));
+    } else if let Some(session_id) = id.strip_prefix(DISCONNECT_PREFIX) {
+        let app_handle = app_handle.clone();
+        let session_id = session_id.to_string();
+        tauri::async_runtime::spawn(async move {
+            let state = app_handle.state::<Arc<AgentState>>().inner().clone();
+            commands::close_tunnel(&state, &app_handle, &session_id).await;
+        });
+    }
+}
+
+async fn connect_profile(app_handle: AppHandle, name: String) {
+    let state = app_handle.state::<Arc<AgentState>>().inner().clone();
+    let profile = state.profiles.read().await.get(&name).cloned();
+    let Some(profile) = profile else {
+        return;
+    };
+    if let Err(e) = commands::open_tunnel(&state, &app_handle, profile.into()).await {
+        warn!("Tray connect of profile {} failed: {}", name, e);
+        let _ = app_handle.emit("server-error", &e);
+    }
+}
@@ -23,7 +23,7 @@ import "./App.css";
 
 /** Agent connection status, returned by the `get_agent_info` command. */
 interface AgentStatus {
-  agent_id: string;
+  agent_id: string | null; // null until registered, or for tunnel-only tokens
   connected: boolean;
   server_url: string;
 }
@@ -35,7 +35,14 @@ interface TunnelInfo {
   remote_port: number;
   local_port: number;
   direction: string; // "incoming" or "outgoing"
-  status: string;    // "connecting", "active", or "error"
+  status: string;    // "connecting", "active", "error", or "failed"
+}
+
+/** A pairing link for another relay, sent with `pairing-request`. */
+interface PairingRequest {
+  server: string;
+  current_server: string;
+  agent_id: string;
 }
 
 // ─── Main Component ─────────────────────────────────────────────
@@ -87,7 +94,7 @@ function App() {
     }).then((u) => unlisteners.push(u));
 
     // Server assigned an Agent ID after registration
-    listen<string>("registered", (event) => {
+    listen<string | null>("registered", (event) => {
       setAgentInfo((prev) =>
         prev ? { ...prev, agent_id: event.payload } : prev
       );
@@ -104,6 +111,23 @@ function App() {
       setTimeout(() => setError(null), 5000);
     }).then((u) => unlisteners.push(u));
 
+    // Banned by an admin: the app stops reconnecting, so the notice stays
+    listen<string>("banned", (event) => {
+      setError(`Banned by the relay: ${event.payload}`);
+    }).then((u) => unlisteners.push(u));
+
+    // A pairing link would move the app to another relay: ask first
+    listen<PairingRequest>("pairing-request", (event) => {
+      const { server, current_server, agent_id } = event.payload;
+      const ok = window.confirm(
+        `A pairing link wants to switch the relay from ${current_server} to ${server} ` +
+          `and pair with agent ${agent_id}. Only continue if you opened this link yourself.`
+      );
+      invoke(ok ? "confirm_pairing" : "reject_pairing").catch((e) =>
+        setError(String(e))
+      );
+    }).then((u) => unlisteners.push(u));
+
     // Cleanup all event listeners on unmount
     return () => {
       unlisteners.forEach((u) => u());
@@ -112,7 +136,7 @@ function App() {
 
   // ── Copy Agent ID to clipboard ──
   const copyAgentId = useCallback(() => {
-    if (agentInfo) {
+    if (agentInfo?.agent_id) {
       navigator.clipboard.writeText(agentInfo.agent_id);
       setCopied(true);
       setTimeout(() => setCopied(false), 2000);
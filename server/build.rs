@@ -0,0 +1,17 @@
+//! Generates the gRPC control service from `proto/tunnel.proto` when the
+//! `grpc` feature is enabled, using a bundled `protoc`.
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/tunnel.proto");
+        let protoc =
+            protoc_bin_vendored::protoc_bin_path().expect("no bundled protoc for this host");
+        std::env::set_var("PROTOC", protoc);
+        tonic_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/tunnel.proto"], &["proto"])
+            .expect("failed to compile proto/tunnel.proto");
+    }
+}
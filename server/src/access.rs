@@ -0,0 +1,316 @@
+//! # Access Requests
+//!
+//! Lets a controller ask an agent's owner for access to one of its
+//! targets, and collect the answer whenever the owner gets to it.
+//!
+//! ## Flow
+//!
+//! 1. The controller sends `RequestAccess`. The server stores it and
+//!    answers with a pending `AccessUpdate` carrying its `access_id`.
+//! 2. The agent receives `AccessRequested` right away when connected, and
+//!    again each time it registers until it decides.
+//! 3. The agent answers `DecideAccess`. An approval holds for `ttl_secs`.
+//! 4. The controller receives an `AccessUpdate` with an invitation token,
+//!    pushed to the requesting connection if it is still open, or in
+//!    answer to a later `AccessStatus`. The token opens one tunnel to the
+//!    requested target through `Connect.invite`, which the agent accepts
+//!    without asking again.
+//!
+//! Requests and the hashes of their invitations live in the
+//! [database](crate::db) and survive a restart;
+//! undecided ones expire after
+//! [`ACCESS_REQUEST_TTL_MS`](tunnel_protocol::ACCESS_REQUEST_TTL_MS). The `access_id`
+//! is the only handle on a request: whoever holds it may collect the
+//! invitation, once.
+
+use crate::audit::AuditEvent;
+use crate::auth::{self, Scope};
+use crate::db::AccessRecord;
+use crate::state::{AppState, ClientTx, ResolveError};
+use tracing::{info, warn};
+use tunnel_protocol::{host_port, unix_time_ms, AccessState, ControlMessage, ErrorCode};
+use uuid::Uuid;
+
+/// Undecided requests one agent may have queued at once.
+const MAX_PENDING_PER_AGENT: usize = 32;
+
+/// Undecided requests one identity may have queued for one agent, so a
+/// single requester cannot take all of the agent's slots.
+const MAX_PENDING_PER_REQUESTER: usize = 4;
+
+/// Queues a controller's `RequestAccess` and notifies the agent if it is
+/// connected. Refusals are answered with an `AccessUpdate` without an
+/// `access_id`.
+#[allow(clippy::too_many_arguments)]
+pub fn request(
+    state: &AppState,
+    conn_id: &str,
+    tx: &ClientTx,
+    request_id: String,
+    target_id: String,
+    remote_host: String,
+    remote_port: u16,
+    reason: Option<String>,
+) {
+    let fail = |code: ErrorCode, message: String| {
+        let _ = tx.send(ControlMessage::AccessUpdate {
+            request_id: request_id.clone(),
+            access_id: None,
+            state: AccessState::Denied,
+            invite: None,
+            expires_at_ms: 0,
+            code: Some(code),
+            message: Some(message),
+        });
+    };
+
+    let controller = state
+        .connections
+        .get(conn_id)
+        .and_then(|c| c.principal.clone());
+    if !auth::permits(controller.as_ref(), Scope::Connect) {
+        fail(
+            ErrorCode::Unauthorized,
+            "This token may not open tunnels".to_string(),
+        );
+        return;
+    }
+    // Offline agents are looked up in the registry by ID or name, as
+    // `not_found_message` does, so they can decide once they are back.
+    let agent_id = match state.resolve_agent(&target_id) {
+        Ok(agent_id) => agent_id,
+        Err(ResolveError::NotFound) => match state.db.find_agent(&target_id) {
+            Ok(Some(agent)) => agent.agent_id,
+            _ => {
+                fail(
+                    ErrorCode::AgentNotFound,
+                    format!("Agent '{}' never registered with this relay", target_id),
+                );
+                return;
+            }
+        },
+        Err(ResolveError::Ambiguous(candidates)) => {
+            fail(
+                ErrorCode::AmbiguousAgent,
+                format!(
+                    "Name '{}' matches several agents: {}",
+                    target_id,
+                    candidates.join(", ")
+                ),
+            );
+            return;
+        }
+    };
+
+    let identity = controller.map(|p| p.name);
+    let pending = state.db.pending_access(&agent_id).unwrap_or_default();
+    if pending.len() >= MAX_PENDING_PER_AGENT {
+        fail(
+            ErrorCode::LimitExceeded,
+            format!(
+                "Agent '{}' already has {} access requests waiting",
+                target_id,
+                pending.len()
+            ),
+        );
+        return;
+    }
+    if pending.iter().filter(|r| r.identity == identity).count() >= MAX_PENDING_PER_REQUESTER {
+        fail(
+            ErrorCode::LimitExceeded,
+            format!(
+                "At most {} of your access requests to '{}' may wait at once",
+                MAX_PENDING_PER_REQUESTER, target_id
+            ),
+        );
+        return;
+    }
+
+    let record = AccessRecord {
+        access_id: Uuid::new_v4().simple().to_string(),
+        request_id: request_id.clone(),
+        agent_id: agent_id.clone(),
+        identity,
+        remote_host,
+        remote_port,
+        reason,
+        requested_at: unix_time_ms(),
+        approved: None,
+        expires_at: None,
+        delivered: false,
+    };
+    if let Err(e) = state.db.queue_access(&record) {
+        warn!("Failed to store access request: {}", e);
+        fail(
+            ErrorCode::Internal,
+            "The access request could not be stored".to_string(),
+        );
+        return;
+    }
+    info!(
+        agent_id = %agent_id,
+        access_id = %record.access_id,
+        remote = %host_port(&record.remote_host, record.remote_port),
+        "Access requested"
+    );
+    state.record(AuditEvent::AccessRequest {
+        conn_id: conn_id.to_string(),
+        identity: record.identity.clone(),
+        agent_id: agent_id.clone(),
+        access_id: record.access_id.clone(),
+        remote_host: record.remote_host.clone(),
+        remote_port,
+    });
+    state
+        .access_waiters
+        .insert(record.access_id.clone(), conn_id.to_string());
+    let _ = tx.send(update(state, &record, request_id));
+    if let Some(agent) = state.agents.get(&agent_id) {
+        let _ = agent.tx.send(requested(&record));
+    }
+}
+
+/// Records an agent's `DecideAccess` and tells the requesting connection,
+/// if it is still open.
+pub fn decide(
+    state: &AppState,
+    conn_id: &str,
+    tx: &ClientTx,
+    agent_id: Option<String>,
+    access_id: String,
+    approve: bool,
+    ttl_secs: u64,
+) {
+    let Some(agent_id) = agent_id else {
+        let _ = tx.send(ControlMessage::Error {
+            code: ErrorCode::Unauthorized,
+            message: "Only registered agents may decide access requests".to_string(),
+        });
+        return;
+    };
+    let expires_at = approve.then(|| unix_time_ms() + ttl_secs * 1000);
+    match state
+        .db
+        .decide_access(&access_id, &agent_id, approve, expires_at)
+    {
+        Ok(true) => {}
+        Ok(false) => {
+            let _ = tx.send(ControlMessage::Error {
+                code: ErrorCode::InvalidMessage,
+                message: format!("No pending access request '{}'", access_id),
+            });
+            return;
+        }
+        Err(e) => {
+            warn!("Failed to store access decision: {}", e);
+            return;
+        }
+    }
+    info!(agent_id = %agent_id, access_id = %access_id, approve, "Access decided");
+    state.record(AuditEvent::AccessDecision {
+        conn_id: conn_id.to_string(),
+        agent_id,
+        access_id: access_id.clone(),
+        approved: approve,
+    });
+
+    let Some((_, waiter)) = state.access_waiters.remove(&access_id) else {
+        return;
+    };
+    let Some(controller) = state.connections.get(&waiter).map(|c| c.tx.clone()) else {
+        return;
+    };
+    if let Ok(Some(record)) = state.db.access_request(&access_id) {
+        let request_id = record.request_id.clone();
+        let _ = controller.send(update(state, &record, request_id));
+    }
+}
+
+/// Answers `AccessStatus` with where the request stands.
+pub fn status(state: &AppState, tx: &ClientTx, request_id: String, access_id: String) {
+    let reply = match state.db.access_request(&access_id) {
+        Ok(Some(record)) => update(state, &record, request_id),
+        _ => ControlMessage::AccessUpdate {
+            request_id,
+            access_id: Some(access_id),
+            state: AccessState::Expired,
+            invite: None,
+            expires_at_ms: 0,
+            code: Some(ErrorCode::InvalidMessage),
+            message: Some("Unknown or expired access request".to_string()),
+        },
+    };
+    let _ = tx.send(reply);
+}
+
+/// Sends a newly registered agent the requests waiting for its decision.
+pub fn deliver_pending(state: &AppState, agent_id: &str, tx: &ClientTx) {
+    let pending = match state.db.pending_access(agent_id) {
+        Ok(pending) => pending,
+        Err(e) => {
+            warn!("Failed to load access requests: {}", e);
+            return;
+        }
+    };
+    if !pending.is_empty() {
+        info!(
+            agent_id,
+            count = pending.len(),
+            "Delivering access requests"
+        );
+    }
+    for record in &pending {
+        let _ = tx.send(requested(record));
+    }
+}
+
+/// Forgets which requests `conn_id` was waiting on.
+pub fn forget_connection(state: &AppState, conn_id: &str) {
+    state.access_waiters.retain(|_, waiter| waiter != conn_id);
+}
+
+fn requested(record: &AccessRecord) -> ControlMessage {
+    ControlMessage::AccessRequested {
+        access_id: record.access_id.clone(),
+        requester: record.identity.clone(),
+        remote_host: record.remote_host.clone(),
+        remote_port: record.remote_port,
+        reason: record.reason.clone(),
+        requested_at_ms: record.requested_at,
+    }
+}
+
+/// The `AccessUpdate` for `record`. The first one after an approval
+/// carries a fresh invitation valid until the approval runs out.
+fn update(state: &AppState, record: &AccessRecord, request_id: String) -> ControlMessage {
+    let now = unix_time_ms();
+    let access_state = record.state(now);
+    let expires_at_ms = match access_state {
+        AccessState::Approved => record.expires_at.unwrap_or(0),
+        _ => 0,
+    };
+    let invite = (access_state == AccessState::Approved && !record.delivered)
+        .then(|| Uuid::new_v4().simple().to_string())
+        .filter(|token| {
+            state
+                .db
+                .access_delivered(&record.access_id, token)
+                .unwrap_or(false)
+        });
+    let message = match access_state {
+        AccessState::Pending => None,
+        AccessState::Approved if invite.is_some() => None,
+        AccessState::Approved => Some("The invitation was already collected".to_string()),
+        AccessState::Denied => Some("The agent denied access".to_string()),
+        AccessState::Expired => Some("The access request expired".to_string()),
+    };
+    ControlMessage::AccessUpdate {
+        request_id,
+        access_id: Some(record.access_id.clone()),
+        state: access_state,
+        invite,
+        expires_at_ms,
+        code: None,
+        message,
+    }
+}
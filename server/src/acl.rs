@@ -0,0 +1,155 @@
+//! # Access Control Lists
+//!
+//! Decides whether a controller may open a tunnel to a given agent.
+//! Rules come from the `[[acl]]` tables of the server configuration; a
+//! `Connect` is allowed when any rule matches both the controller and
+//! the target agent. With no rules configured every connection is allowed.
+//!
+//! ## Pattern Syntax
+//!
+//! - `*`            — matches anyone, including anonymous clients
+//! - `group:<name>` — matches identities that belong to the group
+//! - `tag:<filter>` — matches agents whose tags satisfy the filter (`env=prod` or `env`)
+//! - anything else  — matches an identity name, or an agent ID on the agent side
+
+use crate::auth::Principal;
+use serde::Deserialize;
+use tunnel_protocol::tags_match;
+
+/// A single allow rule from the `[[acl]]` tables.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AclRule {
+    /// Patterns matched against the controller's identity.
+    pub controllers: Vec<String>,
+
+    /// Patterns matched against the target agent's ID or identity.
+    pub agents: Vec<String>,
+}
+
+/// Returns `true` if `controller` may connect to the agent `agent_id`
+/// (registered as `agent` with `agent_tags`) under `rules`.
+pub fn is_allowed(
+    rules: &[AclRule],
+    controller: Option<&Principal>,
+    agent_id: &str,
+    agent: Option<&Principal>,
+    agent_tags: &[String],
+) -> bool {
+    if rules.is_empty() {
+        return true;
+    }
+    rules.iter().any(|rule| {
+        rule.controllers
+            .iter()
+            .any(|p| matches(p, controller, None, &[]))
+            && rule
+                .agents
+                .iter()
+                .any(|p| matches(p, agent, Some(agent_id), agent_tags))
+    })
+}
+
+/// Returns `true` if the agent-side `pattern` matches the agent
+/// `agent_id` registered as `agent` with `agent_tags`.
+pub fn matches_agent(
+    pattern: &str,
+    agent_id: &str,
+    agent: Option<&Principal>,
+    agent_tags: &[String],
+) -> bool {
+    matches(pattern, agent, Some(agent_id), agent_tags)
+}
+
+fn matches(
+    pattern: &str,
+    principal: Option<&Principal>,
+    id: Option<&str>,
+    tags: &[String],
+) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    if id == Some(pattern) {
+        return true;
+    }
+    if let Some(filter) = pattern.strip_prefix("tag:") {
+        return tags_match(tags, filter);
+    }
+    let Some(principal) = principal else {
+        return false;
+    };
+    match pattern.strip_prefix("group:") {
+        Some(group) => principal.groups.iter().any(|g| g == group),
+        None => principal.name == pattern,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Scope;
+
+    fn principal(name: &str, groups: &[&str]) -> Principal {
+        Principal {
+            name: name.to_string(),
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+            observer: false,
+            admin: false,
+            scopes: vec![Scope::Accept, Scope::Connect],
+        }
+    }
+
+    fn rule(controllers: &[&str], agents: &[&str]) -> AclRule {
+        AclRule {
+            controllers: controllers.iter().map(|s| s.to_string()).collect(),
+            agents: agents.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn empty_rules_allow_everything() {
+        assert!(is_allowed(&[], None, "A3F8-B2C1", None, &[]));
+    }
+
+    #[test]
+    fn group_rule_matches_members_only() {
+        let rules = vec![rule(&["group:ops"], &["group:prod"])];
+        let alice = principal("alice", &["ops"]);
+        let bob = principal("bob", &["dev"]);
+        let server = principal("db-1", &["prod"]);
+
+        assert!(is_allowed(
+            &rules,
+            Some(&alice),
+            "A3F8-B2C1",
+            Some(&server),
+            &[]
+        ));
+        assert!(!is_allowed(
+            &rules,
+            Some(&bob),
+            "A3F8-B2C1",
+            Some(&server),
+            &[]
+        ));
+        assert!(!is_allowed(&rules, None, "A3F8-B2C1", Some(&server), &[]));
+    }
+
+    #[test]
+    fn tag_rule_matches_agent_tags() {
+        let rules = vec![rule(&["*"], &["tag:env=staging"])];
+        let tags = vec!["env=staging".to_string()];
+
+        assert!(is_allowed(&rules, None, "A3F8-B2C1", None, &tags));
+        assert!(!is_allowed(&rules, None, "A3F8-B2C1", None, &[]));
+    }
+
+    #[test]
+    fn agent_id_pattern_matches_anonymous_agent() {
+        let rules = vec![rule(&["alice"], &["A3F8-B2C1"])];
+        let alice = principal("alice", &[]);
+
+        assert!(is_allowed(&rules, Some(&alice), "A3F8-B2C1", None, &[]));
+        assert!(!is_allowed(&rules, Some(&alice), "FFFF-0000", None, &[]));
+    }
+}
@@ -1,30 +1,1054 @@
 //! # REST API Endpoints
 //!
 //! Provides HTTP API endpoints for querying server state.
-//! Currently only exposes a list of connected agents.
+//! Exposes the connected agents, the open tunnel sessions and relay buffer
+//! statistics.
+//! Once `[[tokens]]` are configured, every endpoint requires one of them
+//! as `Authorization: Bearer <token>` unless `[api] public` is set, and
+//! endpoints under `/api/admin/` require a token with the admin role:
+//! `purge` applies the retention policy, `log-level` changes the log
+//! filter at runtime, `bans` manages banned agent IDs
+//! and tokens, `tokens` issues and revokes tokens kept in the database, and
+//! `agents` and `sessions` report every agent seen and past sessions.
+//! `/api/openapi.json` describes all of them for client SDK generators.
+//! Browsers may only call the API from the `[api] cors_origins`. Every
+//! endpoint is subject to the `[ip_filter]` rules.
 
+use crate::audit::AuditEvent;
+use crate::auth::{AuthFailure, Principal, Scope};
+use crate::bans::{self, Ban, BanTarget};
+use crate::config::ApiConfig;
+use crate::db::{self, IssuedToken, KnownAgent, SessionRecord};
+use crate::retention::PruneReport;
 use crate::state::AppState;
-use axum::{extract::State, Json};
-use serde::Serialize;
+use axum::{
+    extract::{ConnectInfo, Query, Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tunnel_protocol::{tags_match, unix_time_ms, ServiceInfo};
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{IntoParams, Modify, OpenApi, ToSchema};
 
-/// Response item representing a single connected agent.
-#[derive(Serialize)]
+/// Middleware refusing requests whose client address, behind trusted
+/// proxies the one they report, is outside the `[ip_filter]` ranges.
+pub async fn filter_ip(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let filter = &state.config.ip_filter;
+    let client = filter.client_ip(peer.ip(), request.headers());
+    if !filter.permits(client) {
+        tracing::warn!(%client, %peer, "Refusing API request: address not allowed");
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    next.run(request).await
+}
+
+/// Middleware requiring a configured or issued bearer token on every
+/// request but the OpenAPI document, unless there are no tokens or the
+/// API is public. Unknown tokens are counted as authentication failures
+/// either way, since they may be guesses at an admin token.
+pub async fn require_token(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let token = bearer_token(request.headers());
+    let known = token.is_some_and(|t| state.authenticate(t).is_some());
+    if token.is_some() && !known {
+        let client = state
+            .config
+            .ip_filter
+            .client_ip(peer.ip(), request.headers());
+        state.auth_failures.record(AuthFailure::ApiToken, client);
+    }
+    if known
+        || state.config.api.public
+        || (state.config.tokens.is_empty() && !state.db.has_tokens())
+        || request.uri().path() == OPENAPI_PATH
+    {
+        return next.run(request).await;
+    }
+    StatusCode::UNAUTHORIZED.into_response()
+}
+
+/// Builds the CORS layer allowing browser calls from `config.cors_origins`.
+pub fn cors_layer(config: &ApiConfig) -> Result<CorsLayer, String> {
+    let origins = if config.cors_origins.iter().any(|o| o == "*") {
+        AllowOrigin::any()
+    } else {
+        let origins = config
+            .cors_origins
+            .iter()
+            .map(|o| HeaderValue::from_str(o).map_err(|_| format!("Invalid CORS origin '{}'", o)))
+            .collect::<Result<Vec<_>, _>>()?;
+        AllowOrigin::list(origins)
+    };
+    Ok(CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
+        .expose_headers([TOTAL_COUNT]))
+}
+
+/// Path of the generated OpenAPI document, served without a token.
+pub const OPENAPI_PATH: &str = "/api/openapi.json";
+
+/// OpenAPI description of every endpoint, generated from the handler and
+/// response types below.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        list_agents,
+        list_sessions,
+        get_stats,
+        purge,
+        get_log_level,
+        set_log_level,
+        list_bans,
+        add_ban,
+        remove_ban,
+        list_tokens,
+        issue_token,
+        revoke_token,
+        known_agents,
+        session_history,
+    ),
+    modifiers(&BearerAuth),
+    security(("bearer" = [])),
+    tags(
+        (name = "agents", description = "Connected and known agents"),
+        (name = "sessions", description = "Open tunnel sessions and relay usage"),
+        (name = "admin", description = "Endpoints requiring a token with the admin role"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Declares the bearer token every endpoint may require.
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+/// `GET /api/openapi.json` — Returns the OpenAPI 3.1 document describing
+/// this API, for generating client SDKs.
+pub async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+/// Response item representing a single agent, connected unless listed
+/// with `?offline=true`.
+#[derive(Serialize, ToSchema)]
 pub struct AgentListItem {
     /// The agent's unique identifier (e.g., "A3F8-B2C1").
     pub agent_id: String,
+
+    /// Whether the agent is connected to this relay or another one of the
+    /// cluster.
+    pub online: bool,
+
+    /// When an offline agent was last seen, milliseconds since the Unix
+    /// epoch; `None` for online agents.
+    pub last_seen: Option<u64>,
+
+    /// Alias the agent registered with, usable in place of its ID.
+    pub name: Option<String>,
+
+    /// Labels the agent registered with (e.g., "env=prod").
+    pub tags: Vec<String>,
+
+    /// Client software version the agent reported.
+    pub version: Option<String>,
+
+    /// Named services the agent advertises (e.g., "ssh" at 127.0.0.1:22).
+    pub services: Vec<ServiceInfo>,
+
+    /// When the agent registered, milliseconds since the Unix epoch.
+    pub connected_at: Option<u64>,
+
+    /// Last heartbeat `Ping` from the agent, milliseconds since the Unix epoch.
+    pub last_heartbeat: Option<u64>,
+
+    /// Sessions currently held by the agent, tunnels and exposed ports alike.
+    pub active_tunnels: Option<usize>,
+
+    /// Bytes relayed for the agent's sessions since it registered.
+    pub bytes_relayed: Option<u64>,
+}
+
+/// Query parameters accepted by `GET /api/agents`.
+#[derive(Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AgentQuery {
+    /// Only return agents whose tags match (`env=prod` or just `env`).
+    pub tag: Option<String>,
+
+    /// Only return agents whose ID, name or a tag contains this text,
+    /// ignoring case.
+    pub q: Option<String>,
+
+    /// Page to return, counting from 1.
+    pub page: Option<usize>,
+
+    /// Agents per page; 100 by default, at most 1000.
+    pub limit: Option<usize>,
+
+    /// Also return agents that registered before, even across restarts,
+    /// but are not connected now.
+    #[serde(default)]
+    pub offline: bool,
 }
 
-/// `GET /api/agents` — Returns a JSON array of all currently connected agents.
+impl AgentQuery {
+    fn matches(&self, agent_id: &str, name: Option<&str>, tags: &[String]) -> bool {
+        if !self.tag.as_deref().is_none_or(|t| tags_match(tags, t)) {
+            return false;
+        }
+        let Some(q) = self.q.as_deref().map(str::to_lowercase) else {
+            return true;
+        };
+        let contains = |s: &str| s.to_lowercase().contains(&q);
+        contains(agent_id) || name.is_some_and(contains) || tags.iter().any(|t| contains(t))
+    }
+
+    /// Sorts `agents` by ID and returns the requested page.
+    fn page(&self, mut agents: Vec<AgentListItem>) -> Vec<AgentListItem> {
+        agents.sort_by(|a, b| a.agent_id.cmp(&b.agent_id));
+        let limit = self.limit.unwrap_or(100).clamp(1, 1000);
+        let skip = self
+            .page
+            .unwrap_or(1)
+            .saturating_sub(1)
+            .saturating_mul(limit);
+        agents.into_iter().skip(skip).take(limit).collect()
+    }
+}
+
+/// `GET /api/agents` — Returns a JSON array of the currently connected
+/// agents, including those on other relays of the cluster.
 ///
 /// This endpoint can be used by external tools or dashboards to discover
 /// which agents are online and available for tunnel connections.
-pub async fn list_agents(State(state): State<AppState>) -> Json<Vec<AgentListItem>> {
-    let agents: Vec<AgentListItem> = state
+/// Pass `?tag=env=prod` to list only agents carrying that tag, or `?q=`
+/// to search IDs, names and tags. Agents are ordered by ID and returned a
+/// page at a time (`?page=` and `?limit=`); the `X-Total-Count` header
+/// holds how many matched. Version, liveness and usage fields are `null`
+/// for agents on other relays. With `?offline=true` the agents the
+/// registry remembers but that are not connected are listed too, with
+/// `online: false` and their `last_seen` time.
+#[utoipa::path(
+    get,
+    path = "/api/agents",
+    tag = "agents",
+    params(AgentQuery),
+    responses(
+        (status = 200, body = Vec<AgentListItem>, headers(("x-total-count" = usize, description = "Agents matched before pagination"))),
+        (status = 401, description = "Missing or unknown token"),
+    )
+)]
+pub async fn list_agents(
+    State(state): State<AppState>,
+    Query(query): Query<AgentQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let agents = agent_items(&state, &query).await.map_err(db_error)?;
+    let total = agents.len();
+    Ok(([(TOTAL_COUNT, total)], Json(query.page(agents))))
+}
+
+/// The agents matching `query`, unordered and unpaged: connected ones,
+/// those on other relays of the cluster and, with `offline`, the ones only
+/// the registry remembers.
+pub async fn agent_items(
+    state: &AppState,
+    query: &AgentQuery,
+) -> rusqlite::Result<Vec<AgentListItem>> {
+    let mut agents: Vec<AgentListItem> = state
         .agents
         .iter()
+        .filter(|entry| query.matches(entry.key(), entry.name.as_deref(), &entry.tags))
         .map(|entry| AgentListItem {
             agent_id: entry.key().clone(),
+            online: true,
+            last_seen: None,
+            name: entry.name.clone(),
+            tags: entry.tags.clone(),
+            version: entry.version.clone(),
+            services: entry.services.clone(),
+            connected_at: Some(entry.connected_at),
+            last_heartbeat: Some(entry.usage.last_heartbeat()),
+            active_tunnels: Some(state.tunnel_count(entry.key())),
+            bytes_relayed: Some(state.bytes_relayed(&entry, entry.key())),
         })
         .collect();
-    Json(agents)
+    if let Some(cluster) = &state.cluster {
+        agents.extend(
+            cluster
+                .agents
+                .iter()
+                .filter(|entry| {
+                    query.matches(entry.key(), entry.name.as_deref(), &entry.tags)
+                        && !state.agents.contains_key(entry.key())
+                })
+                .map(|entry| AgentListItem {
+                    agent_id: entry.key().clone(),
+                    online: true,
+                    last_seen: None,
+                    name: entry.name.clone(),
+                    tags: entry.tags.clone(),
+                    version: None,
+                    services: entry.services.clone(),
+                    connected_at: None,
+                    last_heartbeat: None,
+                    active_tunnels: None,
+                    bytes_relayed: None,
+                }),
+        );
+    }
+    if query.offline {
+        let known = state.db.run(|db| db.agents()).await?;
+        let online = |agent_id: &str| {
+            state.agents.contains_key(agent_id)
+                || state
+                    .cluster
+                    .as_ref()
+                    .is_some_and(|c| c.agents.contains_key(agent_id))
+        };
+        agents.extend(
+            known
+                .into_iter()
+                .filter(|agent| {
+                    query.matches(&agent.agent_id, agent.name.as_deref(), &agent.tags)
+                        && !online(&agent.agent_id)
+                })
+                .map(|agent| AgentListItem {
+                    agent_id: agent.agent_id,
+                    online: false,
+                    last_seen: Some(agent.last_seen),
+                    name: agent.name,
+                    tags: agent.tags,
+                    version: agent.version,
+                    services: agent.services,
+                    connected_at: None,
+                    last_heartbeat: None,
+                    active_tunnels: None,
+                    bytes_relayed: None,
+                }),
+        );
+    }
+    Ok(agents)
+}
+
+/// Response header holding how many items matched before pagination.
+const TOTAL_COUNT: HeaderName = HeaderName::from_static("x-total-count");
+
+/// Buffer usage of a single tunnel session.
+#[derive(Serialize, ToSchema)]
+pub struct SessionBufferStats {
+    pub session_id: String,
+    /// Bytes currently held in relay buffers for this session.
+    pub buffered_bytes: usize,
+    /// Highest buffered byte count observed for this session.
+    pub high_water_bytes: usize,
+    /// Configured per-session cap.
+    pub limit_bytes: usize,
+    /// Bytes relayed towards the agent so far.
+    pub bytes_to_agent: u64,
+    /// Bytes relayed from the agent so far.
+    pub bytes_from_agent: u64,
+}
+
+/// Response body of `GET /api/stats`.
+#[derive(Serialize, ToSchema)]
+pub struct StatsResponse {
+    /// Bytes currently buffered across all sessions.
+    pub buffered_bytes: usize,
+    pub sessions: Vec<SessionBufferStats>,
+
+    /// Failed authentication attempts since startup, by kind: `token`,
+    /// `agent_key`, `invitation`, `cluster_secret` and `api_token`.
+    pub auth_failures: BTreeMap<String, u64>,
+}
+
+/// `GET /api/stats` — Returns relay memory usage per session, including
+/// high-water marks, so operators can spot stalled consumers, and the
+/// count of failed authentication attempts.
+#[utoipa::path(
+    get,
+    path = "/api/stats",
+    tag = "sessions",
+    responses(
+        (status = 200, body = StatsResponse),
+        (status = 401, description = "Missing or unknown token"),
+    )
+)]
+pub async fn get_stats(State(state): State<AppState>) -> Json<StatsResponse> {
+    let sessions: Vec<SessionBufferStats> = state
+        .sessions
+        .iter()
+        .map(|entry| SessionBufferStats {
+            session_id: entry.session_id.clone(),
+            buffered_bytes: entry.buffers.buffered(),
+            high_water_bytes: entry.buffers.high_water(),
+            limit_bytes: entry.buffers.limit(),
+            bytes_to_agent: entry.traffic.bytes_to_agent(),
+            bytes_from_agent: entry.traffic.bytes_from_agent(),
+        })
+        .collect();
+    Json(StatsResponse {
+        buffered_bytes: sessions.iter().map(|s| s.buffered_bytes).sum(),
+        sessions,
+        auth_failures: state.auth_failures.counts(),
+    })
+}
+
+/// An open tunnel session in the `GET /api/sessions` response.
+#[derive(Serialize, ToSchema)]
+pub struct SessionItem {
+    pub session_id: String,
+    pub agent_id: String,
+    /// Identity of the controller's token, if it used one.
+    pub controller: Option<String>,
+    /// `host:port` or Unix socket the agent connects to.
+    pub target: String,
+    /// Whether the agent has accepted the tunnel.
+    pub accepted: bool,
+    /// Data streams currently relayed.
+    pub streams: usize,
+    /// Seconds since the session was created.
+    pub age_secs: u64,
+    /// Lifetime the controller gave the tunnel, if any.
+    pub ttl_secs: Option<u64>,
+    /// Whether traffic flows back from a SOCKS proxy on the agent.
+    pub reverse_socks: bool,
+    /// Bytes relayed towards the agent so far.
+    pub bytes_to_agent: u64,
+    /// Bytes relayed from the agent so far.
+    pub bytes_from_agent: u64,
+}
+
+/// `GET /api/sessions` — Lists the open tunnel sessions with the bytes
+/// each has relayed per direction, heaviest first.
+#[utoipa::path(
+    get,
+    path = "/api/sessions",
+    tag = "sessions",
+    responses(
+        (status = 200, body = Vec<SessionItem>),
+        (status = 401, description = "Missing or unknown token"),
+    )
+)]
+pub async fn list_sessions(State(state): State<AppState>) -> Json<Vec<SessionItem>> {
+    Json(session_items(&state))
+}
+
+/// The open tunnel sessions, heaviest first.
+pub fn session_items(state: &AppState) -> Vec<SessionItem> {
+    let mut sessions: Vec<SessionItem> = state
+        .sessions
+        .iter()
+        .map(|entry| SessionItem {
+            session_id: entry.session_id.clone(),
+            agent_id: entry.agent_id.clone(),
+            controller: state
+                .connections
+                .get(&entry.controller_id)
+                .and_then(|c| c.principal.as_ref().map(|p| p.name.clone())),
+            target: match &entry.remote_socket {
+                Some(socket) => socket.clone(),
+                None => format!("{}:{}", entry.remote_host, entry.remote_port),
+            },
+            accepted: entry.accepted,
+            streams: entry.streams.len(),
+            age_secs: entry.created_at.elapsed().as_secs(),
+            ttl_secs: entry.ttl_secs,
+            reverse_socks: entry.reverse_socks,
+            bytes_to_agent: entry.traffic.bytes_to_agent(),
+            bytes_from_agent: entry.traffic.bytes_from_agent(),
+        })
+        .collect();
+    sessions.sort_by_key(|s| std::cmp::Reverse(s.bytes_to_agent + s.bytes_from_agent));
+    sessions
+}
+
+/// Resolves the bearer token in `headers` to a principal holding the admin role.
+fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<Principal, StatusCode> {
+    let token = bearer_token(headers).ok_or(StatusCode::UNAUTHORIZED)?;
+    match state.authenticate(token) {
+        Some(p) if p.admin => Ok(p),
+        Some(_) => Err(StatusCode::FORBIDDEN),
+        None => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+/// The token of an `Authorization: Bearer <token>` header.
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+/// `POST /api/admin/purge` — Applies the retention policy to every persisted
+/// file and the session history immediately instead of waiting for the next
+/// background cleanup.
+#[utoipa::path(
+    post,
+    path = "/api/admin/purge",
+    tag = "admin",
+    responses(
+        (status = 200, body = Vec<PruneReport>),
+        (status = 401, description = "Missing or unknown token"),
+        (status = 403, description = "The token lacks the admin role"),
+    )
+)]
+pub async fn purge(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<PruneReport>>, StatusCode> {
+    let admin = require_admin(&state, &headers)?;
+    tracing::info!("Retention purge requested by {}", admin.name);
+    let retention = state.retention.clone();
+    let db = state.db.clone();
+    let policy = state.config.retention.clone();
+    let reports = tokio::task::spawn_blocking(move || {
+        crate::retention::prune_history(&db, &policy);
+        retention.purge(&policy)
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(reports))
+}
+
+/// Request and response body of `/api/admin/log-level`.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct LogLevel {
+    /// A level (`error`, `warn`, `info`, `debug`, `trace`, `off`) for the
+    /// server's own logs, or `RUST_LOG` directives such as
+    /// `tunnel_server=debug,quinn=info`. Responses carry the full filter.
+    pub level: String,
+}
+
+/// `GET /api/admin/log-level` — Returns the current log filter.
+#[utoipa::path(
+    get,
+    path = "/api/admin/log-level",
+    tag = "admin",
+    responses(
+        (status = 200, body = LogLevel),
+        (status = 401, description = "Missing or unknown token"),
+        (status = 403, description = "The token lacks the admin role"),
+    )
+)]
+pub async fn get_log_level(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<LogLevel>, StatusCode> {
+    require_admin(&state, &headers)?;
+    let level = state
+        .log_filter
+        .current()
+        .ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    Ok(Json(LogLevel { level }))
+}
+
+/// `PUT /api/admin/log-level` — Replaces the log filter until the next
+/// change or restart, e.g. to debug a live problem.
+#[utoipa::path(
+    put,
+    path = "/api/admin/log-level",
+    tag = "admin",
+    request_body = LogLevel,
+    responses(
+        (status = 200, body = LogLevel),
+        (status = 400, description = "The level or directives do not parse"),
+        (status = 401, description = "Missing or unknown token"),
+        (status = 403, description = "The token lacks the admin role"),
+    )
+)]
+pub async fn set_log_level(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<LogLevel>,
+) -> Result<Json<LogLevel>, StatusCode> {
+    let admin = require_admin(&state, &headers)?;
+    let level = state.log_filter.set(&request.level).map_err(|e| {
+        tracing::warn!("Refusing log level change: {}", e);
+        StatusCode::BAD_REQUEST
+    })?;
+    tracing::warn!(filter = %level, admin = %admin.name, "Log level changed");
+    Ok(Json(LogLevel { level }))
+}
+
+/// Names what a ban applies to: exactly one of `agent_id` and `identity`.
+#[derive(Deserialize, IntoParams, ToSchema)]
+#[into_params(parameter_in = Query)]
+pub struct BanQuery {
+    pub agent_id: Option<String>,
+
+    /// `name` of the `[[tokens]]` entry whose token is banned.
+    pub identity: Option<String>,
+}
+
+impl BanQuery {
+    fn target(self) -> Result<BanTarget, StatusCode> {
+        match (self.agent_id, self.identity) {
+            (Some(agent_id), None) => Ok(BanTarget::AgentId(agent_id)),
+            (None, Some(name)) => Ok(BanTarget::Identity(name)),
+            _ => Err(StatusCode::BAD_REQUEST),
+        }
+    }
+}
+
+/// Request body of `POST /api/admin/bans`.
+#[derive(Deserialize, ToSchema)]
+pub struct BanRequest {
+    #[serde(flatten)]
+    pub target: BanQuery,
+
+    /// Why the ban is issued; recorded and shown to the banned client.
+    pub reason: String,
+}
+
+/// Response body of `POST /api/admin/bans`.
+#[derive(Serialize, ToSchema)]
+pub struct BanResponse {
+    #[serde(flatten)]
+    pub ban: Ban,
+
+    /// Connections closed by the ban.
+    pub disconnected: usize,
+}
+
+/// `GET /api/admin/bans` — Lists banned agent IDs and tokens.
+#[utoipa::path(
+    get,
+    path = "/api/admin/bans",
+    tag = "admin",
+    responses(
+        (status = 200, body = Vec<Ban>),
+        (status = 401, description = "Missing or unknown token"),
+        (status = 403, description = "The token lacks the admin role"),
+    )
+)]
+pub async fn list_bans(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Ban>>, StatusCode> {
+    require_admin(&state, &headers)?;
+    Ok(Json(state.bans.list()))
+}
+
+/// `POST /api/admin/bans` — Bans an agent ID or token: matching clients are
+/// disconnected now and refused when they register again.
+#[utoipa::path(
+    post,
+    path = "/api/admin/bans",
+    tag = "admin",
+    request_body = BanRequest,
+    responses(
+        (status = 200, body = BanResponse),
+        (status = 400, description = "Neither or both of `agent_id` and `identity` given"),
+        (status = 401, description = "Missing or unknown token"),
+        (status = 403, description = "The token lacks the admin role"),
+    )
+)]
+pub async fn add_ban(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<BanRequest>,
+) -> Result<Json<BanResponse>, StatusCode> {
+    let admin = require_admin(&state, &headers)?;
+    let ban = Ban {
+        target: request.target.target()?,
+        reason: request.reason,
+        banned_by: admin.name,
+        ts: unix_time_ms(),
+    };
+    state.bans.add(ban.clone()).map_err(|e| {
+        tracing::error!("Failed to save ban list: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let disconnected = bans::disconnect(&state, &ban);
+    tracing::info!(
+        target = %ban.target,
+        reason = %ban.reason,
+        admin = %ban.banned_by,
+        disconnected,
+        "Ban added"
+    );
+    state.record(AuditEvent::Ban {
+        target: ban.target.clone(),
+        reason: ban.reason.clone(),
+        admin: ban.banned_by.clone(),
+        disconnected,
+    });
+    Ok(Json(BanResponse { ban, disconnected }))
+}
+
+/// `DELETE /api/admin/bans?agent_id=<id>` or `?identity=<name>` — Lifts a
+/// ban. Answers 404 if there was none.
+#[utoipa::path(
+    delete,
+    path = "/api/admin/bans",
+    tag = "admin",
+    params(BanQuery),
+    responses(
+        (status = 204, description = "Ban lifted"),
+        (status = 400, description = "Neither or both of `agent_id` and `identity` given"),
+        (status = 401, description = "Missing or unknown token"),
+        (status = 403, description = "The token lacks the admin role"),
+        (status = 404, description = "No such ban"),
+    )
+)]
+pub async fn remove_ban(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<BanQuery>,
+) -> Result<StatusCode, StatusCode> {
+    let admin = require_admin(&state, &headers)?;
+    let target = query.target()?;
+    let removed = state.bans.remove(&target).map_err(|e| {
+        tracing::error!("Failed to save ban list: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if !removed {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    tracing::info!(target = %target, admin = %admin.name, "Ban lifted");
+    state.record(AuditEvent::Unban {
+        target,
+        admin: admin.name,
+    });
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Request body of `POST /api/admin/tokens`.
+#[derive(Deserialize, ToSchema)]
+pub struct TokenRequest {
+    /// Identity name of the new token, unique across configured and
+    /// issued tokens.
+    pub name: String,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub observer: bool,
+    #[serde(default)]
+    pub admin: bool,
+
+    /// What the token may be used for; both scopes when omitted.
+    #[serde(default = "crate::config::all_scopes")]
+    pub scopes: Vec<Scope>,
+
+    /// Bandwidth of agents registered with the token, overriding
+    /// `[limits]`; `rate_bytes = 0` leaves them unshaped.
+    #[serde(default)]
+    pub rate_bytes: Option<u64>,
+    #[serde(default)]
+    pub burst_bytes: Option<u64>,
+}
+
+/// Response body of `POST /api/admin/tokens`.
+#[derive(Serialize, ToSchema)]
+pub struct TokenResponse {
+    #[serde(flatten)]
+    pub issued: IssuedToken,
+
+    /// The secret, returned only this once.
+    pub token: String,
+}
+
+/// Query parameters of `DELETE /api/admin/tokens`.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TokenQuery {
+    pub name: String,
+}
+
+fn db_error(e: rusqlite::Error) -> StatusCode {
+    tracing::error!("Database error: {}", e);
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+/// `GET /api/admin/tokens` — Lists tokens issued through the API, without
+/// their secrets.
+#[utoipa::path(
+    get,
+    path = "/api/admin/tokens",
+    tag = "admin",
+    responses(
+        (status = 200, body = Vec<IssuedToken>),
+        (status = 401, description = "Missing or unknown token"),
+        (status = 403, description = "The token lacks the admin role"),
+    )
+)]
+pub async fn list_tokens(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<IssuedToken>>, StatusCode> {
+    require_admin(&state, &headers)?;
+    state
+        .db
+        .run(|db| db.tokens())
+        .await
+        .map(Json)
+        .map_err(db_error)
+}
+
+/// `POST /api/admin/tokens` — Issues a token and returns its secret. Answers
+/// 409 if the name is already used by a configured or issued token.
+#[utoipa::path(
+    post,
+    path = "/api/admin/tokens",
+    tag = "admin",
+    request_body = TokenRequest,
+    responses(
+        (status = 200, body = TokenResponse),
+        (status = 400, description = "Empty name"),
+        (status = 401, description = "Missing or unknown token"),
+        (status = 403, description = "The token lacks the admin role"),
+        (status = 409, description = "Name already used"),
+    )
+)]
+pub async fn issue_token(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<TokenRequest>,
+) -> Result<Json<TokenResponse>, StatusCode> {
+    let admin = require_admin(&state, &headers)?;
+    if request.name.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if state.config.tokens.iter().any(|t| t.name == request.name) {
+        return Err(StatusCode::CONFLICT);
+    }
+    let issued = IssuedToken {
+        name: request.name,
+        groups: request.groups,
+        observer: request.observer,
+        admin: request.admin,
+        scopes: request.scopes,
+        rate_bytes: request.rate_bytes,
+        burst_bytes: request.burst_bytes,
+        created_by: admin.name,
+        created_at: unix_time_ms(),
+    };
+    let token = db::generate_token();
+    let (stored, secret) = (issued.clone(), token.clone());
+    if !state
+        .db
+        .run(move |db| db.issue_token(&stored, &secret))
+        .await
+        .map_err(db_error)?
+    {
+        return Err(StatusCode::CONFLICT);
+    }
+    tracing::info!(name = %issued.name, admin = %issued.created_by, "Token issued");
+    Ok(Json(TokenResponse { issued, token }))
+}
+
+/// `DELETE /api/admin/tokens?name=<name>` — Revokes an issued token.
+/// Clients already authenticated with it stay connected. Answers 404 if
+/// there was none.
+#[utoipa::path(
+    delete,
+    path = "/api/admin/tokens",
+    tag = "admin",
+    params(TokenQuery),
+    responses(
+        (status = 204, description = "Token revoked"),
+        (status = 401, description = "Missing or unknown token"),
+        (status = 403, description = "The token lacks the admin role"),
+        (status = 404, description = "No such token"),
+    )
+)]
+pub async fn revoke_token(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+) -> Result<StatusCode, StatusCode> {
+    let admin = require_admin(&state, &headers)?;
+    let name = query.name.clone();
+    if !state
+        .db
+        .run(move |db| db.revoke_token(&name))
+        .await
+        .map_err(db_error)?
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    tracing::info!(name = %query.name, admin = %admin.name, "Token revoked");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// An agent from the database, with whether it is connected to this relay.
+#[derive(Serialize, ToSchema)]
+pub struct KnownAgentItem {
+    #[serde(flatten)]
+    pub agent: KnownAgent,
+    pub online: bool,
+}
+
+/// `GET /api/admin/agents` — Lists every agent that has registered with
+/// this relay, most recently seen first.
+#[utoipa::path(
+    get,
+    path = "/api/admin/agents",
+    tag = "admin",
+    responses(
+        (status = 200, body = Vec<KnownAgentItem>),
+        (status = 401, description = "Missing or unknown token"),
+        (status = 403, description = "The token lacks the admin role"),
+    )
+)]
+pub async fn known_agents(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<KnownAgentItem>>, StatusCode> {
+    require_admin(&state, &headers)?;
+    let agents = state.db.run(|db| db.agents()).await.map_err(db_error)?;
+    Ok(Json(
+        agents
+            .into_iter()
+            .map(|agent| KnownAgentItem {
+                online: state.agents.contains_key(&agent.agent_id),
+                agent,
+            })
+            .collect(),
+    ))
+}
+
+/// Query parameters of `GET /api/admin/sessions`.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SessionQuery {
+    /// How many sessions to return, newest first; 100 by default.
+    pub limit: Option<u32>,
+}
+
+/// `GET /api/admin/sessions?limit=<n>` — Returns the most recent tunnel
+/// sessions, open and ended, at most 1000.
+#[utoipa::path(
+    get,
+    path = "/api/admin/sessions",
+    tag = "admin",
+    params(SessionQuery),
+    responses(
+        (status = 200, body = Vec<SessionRecord>),
+        (status = 401, description = "Missing or unknown token"),
+        (status = 403, description = "The token lacks the admin role"),
+    )
+)]
+pub async fn session_history(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<SessionQuery>,
+) -> Result<Json<Vec<SessionRecord>>, StatusCode> {
+    require_admin(&state, &headers)?;
+    let limit = query.limit.unwrap_or(100).min(1000);
+    state
+        .db
+        .run(move |db| db.sessions(limit))
+        .await
+        .map(Json)
+        .map_err(db_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent(agent_id: &str, name: Option<&str>, tags: &[&str]) -> AgentListItem {
+        AgentListItem {
+            agent_id: agent_id.to_string(),
+            online: true,
+            last_seen: None,
+            name: name.map(str::to_string),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            version: None,
+            services: Vec::new(),
+            connected_at: None,
+            last_heartbeat: None,
+            active_tunnels: None,
+            bytes_relayed: None,
+        }
+    }
+
+    #[test]
+    fn agent_search_and_pages() {
+        let query = AgentQuery {
+            tag: Some("env=prod".to_string()),
+            q: Some("DB".to_string()),
+            ..AgentQuery::default()
+        };
+        let tags = ["env=prod".to_string()];
+        assert!(query.matches("A3F8-B2C1", Some("db-server"), &tags));
+        assert!(query.matches("0DB0-0001", None, &tags));
+        assert!(!query.matches("A3F8-B2C1", Some("web"), &tags));
+        assert!(!query.matches("A3F8-B2C1", Some("db"), &["env=dev".to_string()]));
+
+        let agents = || {
+            ["C", "A", "E", "B", "D"]
+                .iter()
+                .map(|id| agent(id, None, &[]))
+                .collect::<Vec<_>>()
+        };
+        let ids = |q: AgentQuery| -> Vec<String> {
+            q.page(agents()).into_iter().map(|a| a.agent_id).collect()
+        };
+        let paged = |page, limit| AgentQuery {
+            page: Some(page),
+            limit: Some(limit),
+            ..AgentQuery::default()
+        };
+        assert_eq!(ids(AgentQuery::default()), ["A", "B", "C", "D", "E"]);
+        assert_eq!(ids(paged(2, 2)), ["C", "D"]);
+        assert_eq!(ids(paged(3, 2)), ["E"]);
+        assert!(ids(paged(4, 2)).is_empty());
+        assert_eq!(ids(paged(0, 0)), ["A"]);
+    }
+
+    #[test]
+    fn openapi_document() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        for path in [
+            "/api/agents",
+            "/api/sessions",
+            "/api/stats",
+            "/api/admin/purge",
+            "/api/admin/bans",
+            "/api/admin/tokens",
+            "/api/admin/agents",
+            "/api/admin/sessions",
+        ] {
+            assert!(doc["paths"][path].is_object(), "{} is missing", path);
+        }
+        // Every referenced schema is defined.
+        let text = doc.to_string();
+        for name in text.split("#/components/schemas/").skip(1) {
+            let name = &name[..name.find('"').unwrap()];
+            assert!(
+                doc["components"]["schemas"][name].is_object(),
+                "{} is undefined",
+                name
+            );
+        }
+        assert!(doc["components"]["securitySchemes"]["bearer"].is_object());
+    }
 }
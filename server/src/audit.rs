@@ -0,0 +1,230 @@
+//! # Audit Log
+//!
+//! Records who registered, who tunneled into which agent and target, which
+//! agents exposed public ports, and when tunnels opened and closed, as one
+//! JSON object per line in the file named by `[audit] path`. Records are
+//! written by a dedicated thread so the control loops never wait on disk,
+//! and the file is pruned by the [`retention`](crate::retention) policy.
+//! The same lines are broadcast to live subscribers such as the gRPC
+//! `WatchEvents` stream, whether or not a file is configured.
+//!
+//! ```json
+//! {"ts":1700000000000,"event":"connect","conn_id":"…","identity":"alice","target":"db-server","agent_id":"A3F8-B2C1","remote_host":"127.0.0.1","remote_port":5432,"remote_socket":null,"session_id":"3f2a9c1b","error":null}
+//! ```
+
+use crate::bans::BanTarget;
+use crate::retention::{RetainedFile, Retention};
+use serde::Serialize;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc};
+use tokio::sync::broadcast;
+use tracing::{error, info};
+use tunnel_protocol::{unix_time_ms, ErrorCode};
+
+/// A security-relevant event.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// A client registered and received an agent ID.
+    Register {
+        conn_id: String,
+        identity: Option<String>,
+        /// `None` when the token may only open tunnels.
+        agent_id: Option<String>,
+        name: Option<String>,
+    },
+    /// An agent announced a clean shutdown with `Unregister`.
+    Unregister { conn_id: String, agent_id: String },
+    /// A client presented a token the server does not know.
+    RegisterDenied { conn_id: String },
+    /// A controller asked for a tunnel; `error` is set when it was refused.
+    Connect {
+        conn_id: String,
+        identity: Option<String>,
+        /// The agent ID or name as requested.
+        target: String,
+        /// The agent `target` resolved to, if any.
+        agent_id: Option<String>,
+        remote_host: String,
+        remote_port: u16,
+        /// Unix socket target, in place of `remote_host:remote_port`.
+        remote_socket: Option<String>,
+        session_id: Option<String>,
+        error: Option<ErrorCode>,
+    },
+    /// An agent asked the relay to publish a local service on a public
+    /// port or ingress hostname; `error` is set when it was refused.
+    Expose {
+        conn_id: String,
+        identity: Option<String>,
+        agent_id: Option<String>,
+        remote_host: String,
+        remote_port: u16,
+        /// Relay port, for `Expose`.
+        public_port: Option<u16>,
+        /// Ingress hostname, for `ExposeHttp`.
+        hostname: Option<String>,
+        session_id: Option<String>,
+        error: Option<ErrorCode>,
+    },
+    /// The agent accepted the tunnel.
+    Accept {
+        session_id: String,
+        agent_id: String,
+    },
+    /// The agent refused the tunnel.
+    Reject {
+        session_id: String,
+        agent_id: String,
+        error: ErrorCode,
+    },
+    /// The tunnel ended.
+    Close {
+        session_id: String,
+        agent_id: String,
+        controller_id: String,
+        reason: String,
+    },
+    /// An admin banned an agent ID or token.
+    Ban {
+        #[serde(flatten)]
+        target: BanTarget,
+        reason: String,
+        admin: String,
+        /// Connections closed by the ban.
+        disconnected: usize,
+    },
+    /// An admin lifted a ban.
+    Unban {
+        #[serde(flatten)]
+        target: BanTarget,
+        admin: String,
+    },
+    /// A banned agent ID or token tried to register.
+    RegisterBanned {
+        conn_id: String,
+        #[serde(flatten)]
+        target: BanTarget,
+    },
+    /// An agent created an invitation to one of its targets.
+    Invite {
+        conn_id: String,
+        agent_id: String,
+        remote_host: String,
+        remote_port: u16,
+        expires_at_ms: u64,
+    },
+    /// A controller asked an agent's owner for access to a target.
+    AccessRequest {
+        conn_id: String,
+        identity: Option<String>,
+        agent_id: String,
+        access_id: String,
+        remote_host: String,
+        remote_port: u16,
+    },
+    /// An agent approved or denied an access request.
+    AccessDecision {
+        conn_id: String,
+        agent_id: String,
+        access_id: String,
+        approved: bool,
+    },
+    /// A client older than `min_client_version` tried to register.
+    RegisterOutdated {
+        conn_id: String,
+        version: Option<String>,
+    },
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    ts: u64,
+    #[serde(flatten)]
+    event: &'a AuditEvent,
+}
+
+/// Events a live subscriber may fall behind by before it misses some.
+const EVENT_BUFFER: usize = 1024;
+
+/// Appends audit events to the configured file, or discards them when
+/// auditing is disabled, and publishes them to live subscribers.
+#[derive(Debug)]
+pub struct AuditLog {
+    tx: Option<mpsc::Sender<String>>,
+    events: broadcast::Sender<String>,
+}
+
+impl AuditLog {
+    /// An audit log that writes no file.
+    pub fn disabled() -> Self {
+        Self {
+            tx: None,
+            events: broadcast::channel(EVENT_BUFFER).0,
+        }
+    }
+
+    /// Starts the writer thread for `path` and registers the file for retention.
+    pub fn open(path: PathBuf, retention: &Retention) -> Result<Self, String> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        }
+        let file = retention.register(path);
+        let (tx, rx) = mpsc::channel::<String>();
+        std::thread::Builder::new()
+            .name("audit-writer".to_string())
+            .spawn(move || write_loop(file, rx))
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            tx: Some(tx),
+            events: broadcast::channel(EVENT_BUFFER).0,
+        })
+    }
+
+    /// Receives every event recorded from now on as its JSON line.
+    #[cfg(feature = "grpc")]
+    pub fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.events.subscribe()
+    }
+
+    /// Queues `event` for writing and publishes it, stamped with the
+    /// current time.
+    pub fn record(&self, event: AuditEvent) {
+        if self.tx.is_none() && self.events.receiver_count() == 0 {
+            return;
+        }
+        let entry = Entry {
+            ts: unix_time_ms(),
+            event: &event,
+        };
+        match serde_json::to_string(&entry) {
+            Ok(line) => {
+                if self.events.receiver_count() > 0 {
+                    let _ = self.events.send(line.clone());
+                }
+                if let Some(tx) = &self.tx {
+                    let _ = tx.send(line);
+                }
+            }
+            Err(e) => error!("Failed to encode audit event: {}", e),
+        }
+    }
+}
+
+fn write_loop(file: Arc<RetainedFile>, rx: mpsc::Receiver<String>) {
+    info!("Writing audit log to {}", file.path().display());
+    while let Ok(line) = rx.recv() {
+        // Reopened per record so a prune's rename is picked up.
+        let _guard = file.lock();
+        let result = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file.path())
+            .and_then(|mut f| writeln!(f, "{}", line));
+        if let Err(e) = result {
+            error!("Failed to write audit log {}: {}", file.path().display(), e);
+        }
+    }
+}
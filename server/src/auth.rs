@@ -0,0 +1,196 @@
+//! # Client Authentication
+//!
+//! Resolves the optional token sent in `Register` to a [`Principal`], the
+//! named identity that access rules are evaluated against. Clients that
+//! register without a token are anonymous and carry no principal.
+//!
+//! A token's `scopes` limit what it may be used for: `accept` lets the
+//! client register as an agent and accept tunnels, `connect` lets it open
+//! tunnels as a controller. Tokens without `scopes` may do both.
+//!
+//! Every failed attempt to authenticate is counted and logged as one line
+//! under the [`FAILURE_LOG_TARGET`] target, naming the client address, so
+//! tools like fail2ban can ban sources that guess credentials:
+//!
+//! ```text
+//! WARN tunnel_server::auth_failure: Authentication failed from 203.0.113.7 (token) client=203.0.113.7 kind="token"
+//! ```
+
+use crate::config::ServerConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use utoipa::ToSchema;
+
+/// What a token may be used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Register as an agent and accept tunnels.
+    Accept,
+    /// Open tunnels to agents.
+    Connect,
+}
+
+/// An authenticated identity derived from a configured token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    /// Identity name from the token configuration (e.g., "alice").
+    pub name: String,
+
+    /// Groups the identity belongs to.
+    pub groups: Vec<String>,
+
+    /// Whether the identity holds the observer role.
+    pub observer: bool,
+
+    /// Whether the identity holds the admin role.
+    pub admin: bool,
+
+    /// What the identity's token may be used for.
+    pub scopes: Vec<Scope>,
+}
+
+/// Returns `true` if a client authenticated as `principal` may act within
+/// `scope`. Anonymous clients are not limited by scopes.
+pub fn permits(principal: Option<&Principal>, scope: Scope) -> bool {
+    principal.is_none_or(|p| p.scopes.contains(&scope))
+}
+
+/// Looks up the principal owning `token`, or `None` if the token is unknown.
+pub fn authenticate(config: &ServerConfig, token: &str) -> Option<Principal> {
+    config
+        .tokens
+        .iter()
+        .find(|t| t.token == token)
+        .map(|t| Principal {
+            name: t.name.clone(),
+            groups: t.groups.clone(),
+            observer: t.observer,
+            admin: t.admin,
+            scopes: t.scopes.clone(),
+        })
+}
+
+/// Log target of failed authentication attempts.
+pub const FAILURE_LOG_TARGET: &str = "tunnel_server::auth_failure";
+
+/// The credential a failed authentication attempt presented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthFailure {
+    /// Unknown token in `Register` or `RegisterController`.
+    Token,
+    /// Wrong proof for a reserved or key-backed agent ID.
+    AgentKey,
+    /// Unknown, expired or mismatched invitation in `Connect`.
+    Invitation,
+    /// Wrong secret in a `RelayHello` from another relay.
+    ClusterSecret,
+    /// Unknown bearer token on the REST or gRPC API.
+    ApiToken,
+}
+
+impl AuthFailure {
+    const ALL: [Self; 5] = [
+        Self::Token,
+        Self::AgentKey,
+        Self::Invitation,
+        Self::ClusterSecret,
+        Self::ApiToken,
+    ];
+
+    /// Stable name used in logs and in `/api/stats`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Token => "token",
+            Self::AgentKey => "agent_key",
+            Self::Invitation => "invitation",
+            Self::ClusterSecret => "cluster_secret",
+            Self::ApiToken => "api_token",
+        }
+    }
+}
+
+/// Failed authentication attempts since the server started, per kind.
+#[derive(Debug, Default)]
+pub struct AuthFailures {
+    counts: [AtomicU64; AuthFailure::ALL.len()],
+}
+
+impl AuthFailures {
+    /// Counts a failed attempt of `kind` from `client` and logs it.
+    pub fn record(&self, kind: AuthFailure, client: IpAddr) {
+        self.counts[kind as usize].fetch_add(1, Ordering::Relaxed);
+        tracing::warn!(
+            target: FAILURE_LOG_TARGET,
+            %client,
+            kind = kind.as_str(),
+            "Authentication failed from {} ({})",
+            client,
+            kind.as_str()
+        );
+    }
+
+    /// Current counts keyed by [`AuthFailure::as_str`].
+    pub fn counts(&self) -> BTreeMap<String, u64> {
+        AuthFailure::ALL
+            .iter()
+            .map(|&kind| {
+                let count = self.counts[kind as usize].load(Ordering::Relaxed);
+                (kind.as_str().to_string(), count)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scopes_limit_tokens() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [[tokens]]
+            name = "office-pc"
+            token = "agent-token"
+            scopes = ["accept"]
+
+            [[tokens]]
+            name = "alice"
+            token = "alice-token"
+            "#,
+        )
+        .unwrap();
+
+        let agent = authenticate(&config, "agent-token").unwrap();
+        assert!(permits(Some(&agent), Scope::Accept));
+        assert!(!permits(Some(&agent), Scope::Connect));
+
+        let alice = authenticate(&config, "alice-token").unwrap();
+        assert!(permits(Some(&alice), Scope::Accept));
+        assert!(permits(Some(&alice), Scope::Connect));
+
+        assert!(permits(None, Scope::Connect));
+        assert!(toml::from_str::<ServerConfig>(
+            "[[tokens]]\nname = \"x\"\ntoken = \"y\"\nscopes = [\"admin\"]"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn failures_counted_by_kind() {
+        let failures = AuthFailures::default();
+        let client = IpAddr::from([203, 0, 113, 7]);
+        failures.record(AuthFailure::Token, client);
+        failures.record(AuthFailure::Token, client);
+        failures.record(AuthFailure::ApiToken, client);
+
+        let counts = failures.counts();
+        assert_eq!(counts.len(), AuthFailure::ALL.len());
+        assert_eq!(counts["token"], 2);
+        assert_eq!(counts["api_token"], 1);
+        assert_eq!(counts["agent_key"], 0);
+    }
+}
@@ -0,0 +1,198 @@
+//! # Bans
+//!
+//! Lets admins shut out an agent ID or a token on a semi-public relay. A
+//! ban immediately disconnects every client it matches and refuses their
+//! later registrations with the recorded reason. Bans are managed through
+//! `/api/admin/bans` and kept in the JSON file named by `[bans] path`, so
+//! they survive restarts:
+//!
+//! ```toml
+//! [bans]
+//! path = "/var/lib/tunnel-server/bans.json"
+//! ```
+//!
+//! Without a path, bans last until the server stops. Tokens are banned by
+//! the `name` of their `[[tokens]]` entry, so the file never holds secrets.
+
+use crate::state::AppState;
+use quinn::VarInt;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+use tracing::{info, warn};
+use tunnel_protocol::CLOSE_BANNED;
+use utoipa::ToSchema;
+
+/// What a ban applies to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BanTarget {
+    /// Clients registering as this agent ID.
+    AgentId(String),
+    /// Clients authenticating with the token of this identity.
+    Identity(String),
+}
+
+impl std::fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::AgentId(id) => write!(f, "agent {}", id),
+            Self::Identity(name) => write!(f, "identity {}", name),
+        }
+    }
+}
+
+/// A banned agent ID or token.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Ban {
+    #[serde(flatten)]
+    pub target: BanTarget,
+
+    /// Why the ban was issued, shown to the banned client.
+    pub reason: String,
+
+    /// Identity of the admin who issued it.
+    pub banned_by: String,
+
+    /// When it was issued, milliseconds since the Unix epoch.
+    pub ts: u64,
+}
+
+/// The current bans, mirrored to the configured file on every change.
+#[derive(Debug, Default)]
+pub struct BanList {
+    path: Option<PathBuf>,
+    bans: Mutex<Vec<Ban>>,
+}
+
+impl BanList {
+    /// Loads the bans saved at `path`. A missing file holds no bans.
+    pub fn load(path: PathBuf) -> Result<Self, String> {
+        let bans: Vec<Ban> = match std::fs::read_to_string(&path) {
+            Ok(raw) => serde_json::from_str(&raw)
+                .map_err(|e| format!("Invalid ban list {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+        info!("Loaded {} ban(s) from {}", bans.len(), path.display());
+        Ok(Self {
+            path: Some(path),
+            bans: Mutex::new(bans),
+        })
+    }
+
+    /// All bans, oldest first.
+    pub fn list(&self) -> Vec<Ban> {
+        self.lock().clone()
+    }
+
+    /// The ban on `target`, if any.
+    pub fn find(&self, target: &BanTarget) -> Option<Ban> {
+        self.lock().iter().find(|b| b.target == *target).cloned()
+    }
+
+    /// Adds `ban`, replacing an earlier ban on the same target, and saves
+    /// the list.
+    pub fn add(&self, ban: Ban) -> io::Result<()> {
+        let mut bans = self.lock();
+        bans.retain(|b| b.target != ban.target);
+        bans.push(ban);
+        self.save(&bans)
+    }
+
+    /// Lifts the ban on `target` and saves the list. Returns `false` if
+    /// there was none.
+    pub fn remove(&self, target: &BanTarget) -> io::Result<bool> {
+        let mut bans = self.lock();
+        let before = bans.len();
+        bans.retain(|b| b.target != *target);
+        if bans.len() == before {
+            return Ok(false);
+        }
+        self.save(&bans).map(|_| true)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Ban>> {
+        self.bans.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Writes `bans` through a temporary sibling renamed into place.
+    fn save(&self, bans: &[Ban]) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let json = serde_json::to_string_pretty(bans).map_err(io::Error::other)?;
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, json)?;
+        std::fs::rename(&tmp, path)
+    }
+}
+
+/// Closes every connection `ban` matches with [`CLOSE_BANNED`] and returns
+/// how many there were. Their tunnels end as the connections go away.
+pub fn disconnect(state: &AppState, ban: &Ban) -> usize {
+    let conn_ids: Vec<String> = match &ban.target {
+        BanTarget::AgentId(agent_id) => state
+            .agents
+            .get(agent_id)
+            .map(|a| a.conn_id.clone())
+            .into_iter()
+            .collect(),
+        BanTarget::Identity(name) => state
+            .connections
+            .iter()
+            .filter(|c| c.principal.as_ref().is_some_and(|p| p.name == *name))
+            .map(|c| c.key().clone())
+            .collect(),
+    };
+    for conn_id in &conn_ids {
+        if let Some(c) = state.connections.get(conn_id) {
+            warn!(conn_id = %conn_id, target = %ban.target, "Disconnecting banned client");
+            c.conn
+                .close(VarInt::from_u32(CLOSE_BANNED), ban.reason.as_bytes());
+        }
+    }
+    conn_ids.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ban(target: BanTarget, reason: &str) -> Ban {
+        Ban {
+            target,
+            reason: reason.to_string(),
+            banned_by: "root".to_string(),
+            ts: 1,
+        }
+    }
+
+    #[test]
+    fn bans_persist_across_loads() {
+        let path = std::env::temp_dir().join(format!("tunnel-bans-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let agent = BanTarget::AgentId("OFFICE-PC".to_string());
+        let alice = BanTarget::Identity("alice".to_string());
+
+        let bans = BanList::load(path.clone()).unwrap();
+        bans.add(ban(agent.clone(), "spam")).unwrap();
+        bans.add(ban(alice.clone(), "abuse")).unwrap();
+        bans.add(ban(agent.clone(), "scanning")).unwrap();
+
+        let raw = std::fs::read_to_string(&path).unwrap();
+        assert!(raw.contains("\"agent_id\": \"OFFICE-PC\""));
+        let reloaded = BanList::load(path.clone()).unwrap();
+        assert_eq!(reloaded.list().len(), 2);
+        assert_eq!(reloaded.find(&agent).unwrap().reason, "scanning");
+
+        assert!(reloaded.remove(&alice).unwrap());
+        assert!(!reloaded.remove(&alice).unwrap());
+        assert!(BanList::load(path.clone()).unwrap().find(&alice).is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+}
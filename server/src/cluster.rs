@@ -0,0 +1,1000 @@
+//! # Clustering
+//!
+//! Lets several relays serve one fleet, so a controller connected to one
+//! relay can reach an agent connected to another. Relays share their agent
+//! registries through Redis:
+//!
+//! ```toml
+//! [cluster]
+//! redis_url = "redis://10.0.0.9/"
+//! relay_id = "relay-a"
+//! advertise = "10.0.0.2:7070"
+//! secret = "another-long-random-secret"
+//! ```
+//!
+//! Each relay keeps its agents in the hash `tunnel:agents:<relay_id>` and
+//! announces every join and departure on the `tunnel:agents` channel, which
+//! the other relays subscribe to. Every `heartbeat_secs` it rewrites that
+//! hash, refreshes `tunnel:relay:<relay_id>` with its advertised address and
+//! TLS certificate, and reloads the other relays' agents. The keys expire
+//! after three missed heartbeats, so the agents of a crashed relay vanish.
+//!
+//! A `Connect` for an agent on another relay is forwarded over a QUIC link
+//! to that relay, opened on first use with `RelayHello` and the shared
+//! `secret` and pinned to the certificate the relay published. There the
+//! link acts as the controller: the session's control messages and data
+//! streams pass through it unchanged, under the session ID the agent's
+//! relay assigned. The controller's relay checks the connect scope and
+//! access rules; the agent's relay applies its tunnel limits and bans.
+
+use crate::auth::Principal;
+use crate::cert::PinnedCert;
+use crate::config::ClusterConfig;
+use crate::relay::{self, BufferBudget};
+use crate::state::{AgentInfo, AppState, ClientTx};
+use dashmap::DashMap;
+use futures::StreamExt;
+use quinn::{RecvStream, SendStream};
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+use tunnel_protocol::{
+    ControlMessage, ErrorCode, ServiceInfo, TrafficClass, CONTROL_STREAM_PRIORITY,
+    MAX_CONTROL_FRAME,
+};
+use uuid::Uuid;
+
+/// Channel carrying [`AgentEvent`]s between relays.
+const AGENTS_CHANNEL: &str = "tunnel:agents";
+
+/// Set of the relay IDs that have joined the cluster.
+const RELAYS_KEY: &str = "tunnel:relays";
+
+/// How long opening a link to another relay may take.
+const LINK_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn relay_key(relay_id: &str) -> String {
+    format!("tunnel:relay:{}", relay_id)
+}
+
+fn agents_key(relay_id: &str) -> String {
+    format!("tunnel:agents:{}", relay_id)
+}
+
+/// An agent connected to another relay of the cluster.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteAgent {
+    /// The relay the agent is connected to.
+    pub relay_id: String,
+
+    /// Alias the agent registered with, usable in place of its ID.
+    pub name: Option<String>,
+
+    /// Labels the agent registered with (e.g., `env=prod`).
+    pub tags: Vec<String>,
+
+    /// Identity the agent authenticated as, if it registered with a token.
+    pub identity: Option<String>,
+
+    /// Groups of that identity, for `group:` ACL patterns.
+    #[serde(default)]
+    pub groups: Vec<String>,
+
+    /// Named services the agent advertises.
+    #[serde(default)]
+    pub services: Vec<ServiceInfo>,
+}
+
+impl RemoteAgent {
+    fn new(relay_id: &str, info: &AgentInfo) -> Self {
+        Self {
+            relay_id: relay_id.to_string(),
+            name: info.name.clone(),
+            tags: info.tags.clone(),
+            identity: info.principal.as_ref().map(|p| p.name.clone()),
+            groups: info
+                .principal
+                .as_ref()
+                .map_or_else(Vec::new, |p| p.groups.clone()),
+            services: info.services.clone(),
+        }
+    }
+
+    /// The agent's identity, as matched by ACL rules.
+    pub fn principal(&self) -> Option<Principal> {
+        self.identity.as_ref().map(|name| Principal {
+            name: name.clone(),
+            groups: self.groups.clone(),
+            observer: false,
+            admin: false,
+            scopes: Vec::new(),
+        })
+    }
+}
+
+/// An agent joining (`agent` set) or leaving a relay, published on
+/// [`AGENTS_CHANNEL`].
+#[derive(Debug, Serialize, Deserialize)]
+struct AgentEvent {
+    relay_id: String,
+    agent_id: String,
+    agent: Option<RemoteAgent>,
+}
+
+/// Where a relay accepts links, stored at `tunnel:relay:<relay_id>`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RelayRecord {
+    addr: SocketAddr,
+
+    /// The relay's self-signed certificate (DER), which links pin.
+    cert: Vec<u8>,
+}
+
+/// A change to this relay's entries in Redis, applied in order by
+/// [`Cluster::write_loop`].
+enum Write {
+    Agent(AgentEvent),
+    /// The heartbeat: every local agent, replacing the stored ones.
+    Refresh(Vec<(String, RemoteAgent)>),
+}
+
+/// An open link to another relay.
+#[derive(Clone)]
+struct Link {
+    tx: ClientTx,
+    conn: quinn::Connection,
+}
+
+/// A tunnel from a local controller to an agent on another relay.
+#[derive(Clone)]
+struct RemoteSession {
+    relay_id: String,
+    controller_id: String,
+    link: Link,
+    buffers: Arc<BufferBudget>,
+    /// Send priority of the tunnel's data streams on both hops.
+    priority: i32,
+}
+
+/// A forwarded `Connect` waiting for `TunnelReady` or `ConnectFailed`.
+struct PendingConnect {
+    relay_id: String,
+    controller_id: String,
+    /// The controller's own `request_id`, replaced by a unique one on the
+    /// link.
+    request_id: String,
+    /// Traffic class from the `Connect`.
+    traffic_class: TrafficClass,
+}
+
+/// This relay's membership in the cluster.
+pub struct Cluster {
+    relay_id: String,
+    secret: String,
+    heartbeat: Duration,
+    record: RelayRecord,
+    redis: redis::Client,
+    writes: mpsc::UnboundedSender<Write>,
+    endpoint: quinn::Endpoint,
+
+    /// Agents connected to other relays, keyed by agent ID.
+    pub agents: DashMap<String, RemoteAgent>,
+    relays: DashMap<String, RelayRecord>,
+    links: tokio::sync::Mutex<HashMap<String, Link>>,
+    sessions: DashMap<String, RemoteSession>,
+    pending: DashMap<String, PendingConnect>,
+}
+
+impl Cluster {
+    /// Connects to Redis and starts publishing this relay's agents.
+    /// `cert` is the relay's QUIC certificate, published for links to pin.
+    pub async fn start(config: &ClusterConfig, cert: Vec<u8>) -> Result<Arc<Self>, String> {
+        let url = config
+            .redis_url
+            .as_deref()
+            .ok_or("[cluster] redis_url is not set")?;
+        let addr = config
+            .advertise
+            .ok_or("[cluster] advertise is required with redis_url")?;
+        if config.secret.is_empty() {
+            return Err("[cluster] secret is required with redis_url".to_string());
+        }
+        let relay_id = config
+            .relay_id
+            .clone()
+            .unwrap_or_else(|| Uuid::new_v4().simple().to_string()[..8].to_string());
+
+        let redis =
+            redis::Client::open(url).map_err(|e| format!("Invalid [cluster] redis_url: {}", e))?;
+        let mut conn = redis
+            .get_connection_manager()
+            .await
+            .map_err(|e| format!("Failed to connect to Redis at {}: {}", redact(url), e))?;
+        // A previous run under the same ID may have left agents behind.
+        let _: () = conn
+            .del(agents_key(&relay_id))
+            .await
+            .map_err(|e| format!("Failed to reset cluster state: {}", e))?;
+        let endpoint = quinn::Endpoint::client(SocketAddr::from(([0, 0, 0, 0], 0)))
+            .map_err(|e| format!("Failed to bind cluster link endpoint: {}", e))?;
+
+        let (writes, rx) = mpsc::unbounded_channel();
+        let cluster = Arc::new(Self {
+            relay_id,
+            secret: config.secret.clone(),
+            heartbeat: Duration::from_secs(config.heartbeat_secs.max(1)),
+            record: RelayRecord { addr, cert },
+            redis,
+            writes,
+            endpoint,
+            agents: DashMap::new(),
+            relays: DashMap::new(),
+            links: tokio::sync::Mutex::default(),
+            sessions: DashMap::new(),
+            pending: DashMap::new(),
+        });
+        tokio::spawn(cluster.clone().write_loop(conn, rx));
+        tokio::spawn(cluster.clone().subscribe_loop());
+        info!(
+            relay_id = %cluster.relay_id,
+            advertise = %addr,
+            redis = %redact(url),
+            "Joined cluster"
+        );
+        Ok(cluster)
+    }
+
+    /// A member that is not connected to Redis, for tests.
+    #[cfg(test)]
+    pub fn detached(secret: &str) -> Arc<Self> {
+        let (writes, _) = mpsc::unbounded_channel();
+        Arc::new(Self {
+            relay_id: "relay-test".to_string(),
+            secret: secret.to_string(),
+            heartbeat: Duration::from_secs(10),
+            record: RelayRecord {
+                addr: SocketAddr::from(([127, 0, 0, 1], 7070)),
+                cert: Vec::new(),
+            },
+            redis: redis::Client::open("redis://127.0.0.1/").unwrap(),
+            writes,
+            endpoint: quinn::Endpoint::client(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap(),
+            agents: DashMap::new(),
+            relays: DashMap::new(),
+            links: tokio::sync::Mutex::default(),
+            sessions: DashMap::new(),
+            pending: DashMap::new(),
+        })
+    }
+
+    /// Checks the secret a linking relay presented, in constant time.
+    pub fn admits(&self, secret: &str) -> bool {
+        secret.as_bytes().ct_eq(self.secret.as_bytes()).into()
+    }
+
+    /// Announces that `agent_id` registered here (`agent` set) or left.
+    pub fn announce(&self, agent_id: &str, agent: Option<&AgentInfo>) {
+        let _ = self.writes.send(Write::Agent(AgentEvent {
+            relay_id: self.relay_id.clone(),
+            agent_id: agent_id.to_string(),
+            agent: agent.map(|a| RemoteAgent::new(&self.relay_id, a)),
+        }));
+    }
+
+    /// Refreshes this relay's entries and reloads the other relays'
+    /// agents every heartbeat, for as long as the server runs.
+    pub async fn run(self: Arc<Self>, state: AppState) {
+        let mut conn = match self.redis.get_connection_manager().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("Cluster heartbeat stopped: {}", e);
+                return;
+            }
+        };
+        let mut ticker = tokio::time::interval(self.heartbeat);
+        loop {
+            ticker.tick().await;
+            let local = state
+                .agents
+                .iter()
+                .map(|a| (a.key().clone(), RemoteAgent::new(&self.relay_id, &a)))
+                .collect();
+            let _ = self.writes.send(Write::Refresh(local));
+            if let Err(e) = self.reload(&mut conn).await {
+                warn!("Failed to reload cluster agents: {}", e);
+            }
+        }
+    }
+
+    /// Applies this relay's changes to Redis in the order they were made.
+    async fn write_loop(
+        self: Arc<Self>,
+        mut conn: ConnectionManager,
+        mut rx: mpsc::UnboundedReceiver<Write>,
+    ) {
+        while let Some(write) = rx.recv().await {
+            if let Err(e) = self.apply_write(&mut conn, write).await {
+                warn!("Failed to update cluster state: {}", e);
+            }
+        }
+    }
+
+    async fn apply_write(
+        &self,
+        conn: &mut ConnectionManager,
+        write: Write,
+    ) -> redis::RedisResult<()> {
+        let key = agents_key(&self.relay_id);
+        let ttl = self.heartbeat.as_secs() * 3;
+        match write {
+            Write::Agent(event) => {
+                let mut pipe = redis::pipe();
+                match &event.agent {
+                    Some(agent) => pipe.hset(&key, &event.agent_id, to_json(agent)).ignore(),
+                    None => pipe.hdel(&key, &event.agent_id).ignore(),
+                };
+                pipe.publish(AGENTS_CHANNEL, to_json(&event)).ignore();
+                pipe.query_async(conn).await
+            }
+            Write::Refresh(agents) => {
+                let mut pipe = redis::pipe();
+                pipe.atomic().del(&key).ignore();
+                if !agents.is_empty() {
+                    let fields: Vec<(String, String)> = agents
+                        .iter()
+                        .map(|(id, agent)| (id.clone(), to_json(agent)))
+                        .collect();
+                    pipe.hset_multiple(&key, &fields)
+                        .ignore()
+                        .expire(&key, ttl as i64)
+                        .ignore();
+                }
+                pipe.set_ex(relay_key(&self.relay_id), to_json(&self.record), ttl)
+                    .ignore()
+                    .sadd(RELAYS_KEY, &self.relay_id)
+                    .ignore();
+                pipe.query_async(conn).await
+            }
+        }
+    }
+
+    /// Replaces the cached agents and relays with what Redis holds for the
+    /// live relays, dropping relays whose entry expired.
+    async fn reload(&self, conn: &mut ConnectionManager) -> redis::RedisResult<()> {
+        let members: Vec<String> = conn.smembers(RELAYS_KEY).await?;
+        let mut live = HashSet::new();
+        let mut agents = HashMap::new();
+        for relay_id in members.into_iter().filter(|id| *id != self.relay_id) {
+            let record: Option<String> = conn.get(relay_key(&relay_id)).await?;
+            let Some(record) = record.and_then(|r| serde_json::from_str::<RelayRecord>(&r).ok())
+            else {
+                info!(relay_id = %relay_id, "Relay left the cluster");
+                let _: () = conn.srem(RELAYS_KEY, &relay_id).await?;
+                continue;
+            };
+            let stored: HashMap<String, String> = conn.hgetall(agents_key(&relay_id)).await?;
+            agents.extend(
+                stored
+                    .into_iter()
+                    .filter_map(|(id, raw)| Some((id, serde_json::from_str(&raw).ok()?))),
+            );
+            self.relays.insert(relay_id.clone(), record);
+            live.insert(relay_id);
+        }
+        self.relays.retain(|id, _| live.contains(id));
+        self.agents.retain(|id, _| agents.contains_key(id));
+        for (agent_id, agent) in agents {
+            self.agents.insert(agent_id, agent);
+        }
+        Ok(())
+    }
+
+    /// Follows the other relays' announcements, resubscribing after Redis
+    /// connection losses.
+    async fn subscribe_loop(self: Arc<Self>) {
+        loop {
+            match self.subscribe().await {
+                Ok(()) => warn!("Cluster subscription ended"),
+                Err(e) => warn!("Cluster subscription failed: {}", e),
+            }
+            tokio::time::sleep(self.heartbeat).await;
+        }
+    }
+
+    async fn subscribe(&self) -> redis::RedisResult<()> {
+        let mut pubsub = self.redis.get_async_pubsub().await?;
+        pubsub.subscribe(AGENTS_CHANNEL).await?;
+        let mut messages = pubsub.on_message();
+        while let Some(msg) = messages.next().await {
+            let event = msg
+                .get_payload::<String>()
+                .ok()
+                .and_then(|raw| serde_json::from_str(&raw).ok());
+            if let Some(event) = event {
+                apply_event(&self.agents, &self.relay_id, event);
+            }
+        }
+        Ok(())
+    }
+
+    // ─── Forwarded Sessions ─────────────────────────────────────
+
+    /// Forwards the `Connect` of controller `controller_id` to `relay_id`,
+    /// where its target agent is connected. The controller's request ID is
+    /// restored in the answer; failures to reach the relay are reported as
+    /// `ConnectFailed`.
+    pub async fn forward_connect(
+        self: Arc<Self>,
+        state: AppState,
+        controller_id: String,
+        relay_id: String,
+        mut msg: ControlMessage,
+    ) {
+        let ControlMessage::Connect {
+            request_id,
+            traffic_class,
+            ..
+        } = &mut msg
+        else {
+            return;
+        };
+        let traffic_class = *traffic_class;
+        let forwarded = Uuid::new_v4().simple().to_string();
+        let original = std::mem::replace(request_id, forwarded.clone());
+
+        let result = match self.link(&state, &relay_id).await {
+            Ok(link) => {
+                self.pending.insert(
+                    forwarded.clone(),
+                    PendingConnect {
+                        relay_id: relay_id.clone(),
+                        controller_id: controller_id.clone(),
+                        request_id: original.clone(),
+                        traffic_class,
+                    },
+                );
+                link.tx.send(msg).map_err(|_| "link closed".to_string())
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            self.pending.remove(&forwarded);
+            warn!(relay_id = %relay_id, "Failed to forward Connect: {}", e);
+            send_to(
+                &state,
+                &controller_id,
+                ControlMessage::ConnectFailed {
+                    request_id: original,
+                    code: ErrorCode::Internal,
+                    message: format!("Relay '{}' is unreachable: {}", relay_id, e),
+                },
+            );
+        }
+    }
+
+    /// Passes a controller's message about one of its forwarded sessions on
+    /// to the agent's relay. Returns `false` if `msg` is not about such a
+    /// session.
+    pub fn forward(&self, conn_id: &str, msg: &ControlMessage) -> bool {
+        let forwardable = matches!(
+            msg,
+            ControlMessage::StreamOpen { .. }
+                | ControlMessage::StreamClose { .. }
+                | ControlMessage::SessionPing { .. }
+                | ControlMessage::TunnelClose { .. }
+        );
+        let Some(session_id) = msg.session_id().filter(|_| forwardable) else {
+            return false;
+        };
+        let Some(session) = self
+            .sessions
+            .get(session_id)
+            .filter(|s| s.controller_id == conn_id)
+            .map(|s| s.clone())
+        else {
+            return false;
+        };
+        if matches!(msg, ControlMessage::TunnelClose { .. }) {
+            self.sessions.remove(session_id);
+        }
+        let _ = session.link.tx.send(msg.clone());
+        true
+    }
+
+    /// Relays a data stream a controller opened for one of its forwarded
+    /// sessions to the agent's relay. Returns the streams back if
+    /// `session_id` is not such a session.
+    pub fn forward_stream(
+        &self,
+        state: &AppState,
+        conn_id: &str,
+        session_id: &str,
+        prefix: [u8; 17],
+        send: SendStream,
+        recv: RecvStream,
+    ) -> Result<(), (SendStream, RecvStream)> {
+        let Some(session) = self
+            .sessions
+            .get(session_id)
+            .filter(|s| s.controller_id == conn_id)
+            .map(|s| s.clone())
+        else {
+            return Err((send, recv));
+        };
+        splice(
+            state,
+            session.link.conn,
+            prefix,
+            send,
+            recv,
+            session.buffers,
+            session.priority,
+        );
+        Ok(())
+    }
+
+    /// Ends the forwarded sessions and pending connects of a controller
+    /// that disconnected.
+    pub fn forget_controller(&self, conn_id: &str) {
+        self.pending.retain(|_, p| p.controller_id != conn_id);
+        let sessions: Vec<String> = self
+            .sessions
+            .iter()
+            .filter(|s| s.controller_id == conn_id)
+            .map(|s| s.key().clone())
+            .collect();
+        for session_id in sessions {
+            if let Some((_, session)) = self.sessions.remove(&session_id) {
+                let _ = session
+                    .link
+                    .tx
+                    .send(ControlMessage::TunnelClose { session_id });
+            }
+        }
+    }
+
+    // ─── Links ──────────────────────────────────────────────────
+
+    /// Returns the link to `relay_id`, opening it if needed.
+    async fn link(self: &Arc<Self>, state: &AppState, relay_id: &str) -> Result<Link, String> {
+        let mut links = self.links.lock().await;
+        if let Some(link) = links.get(relay_id) {
+            if link.conn.close_reason().is_none() {
+                return Ok(link.clone());
+            }
+        }
+        let record = self
+            .relays
+            .get(relay_id)
+            .map(|r| r.clone())
+            .ok_or("relay is not in the cluster")?;
+        let link = tokio::time::timeout(LINK_TIMEOUT, self.open_link(state, relay_id, record))
+            .await
+            .map_err(|_| "timed out".to_string())??;
+        links.insert(relay_id.to_string(), link.clone());
+        Ok(link)
+    }
+
+    async fn open_link(
+        self: &Arc<Self>,
+        state: &AppState,
+        relay_id: &str,
+        record: RelayRecord,
+    ) -> Result<Link, String> {
+        let mut crypto = rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(PinnedCert::new(record.cert))
+            .with_no_client_auth();
+        crypto.alpn_protocols = vec![b"tunnel".to_vec()];
+        let crypto =
+            quinn::crypto::rustls::QuicClientConfig::try_from(crypto).map_err(|e| e.to_string())?;
+        let mut config = quinn::ClientConfig::new(Arc::new(crypto));
+        let mut transport = quinn::TransportConfig::default();
+        transport.keep_alive_interval(Some(Duration::from_secs(5)));
+        transport.max_concurrent_bidi_streams(1024u32.into());
+        transport.stream_receive_window(
+            quinn::VarInt::from_u64(state.config.limits.stream_buffer_bytes as u64)
+                .map_err(|e| e.to_string())?,
+        );
+        config.transport_config(Arc::new(transport));
+
+        let conn = self
+            .endpoint
+            .connect_with(config, record.addr, "localhost")
+            .map_err(|e| e.to_string())?
+            .await
+            .map_err(|e| e.to_string())?;
+        let (mut send, mut recv) = conn.open_bi().await.map_err(|e| e.to_string())?;
+        let _ = send.set_priority(CONTROL_STREAM_PRIORITY);
+        let hello = ControlMessage::RelayHello {
+            relay_id: self.relay_id.clone(),
+            secret: self.secret.clone(),
+        };
+        write_message(&mut send, &hello).await?;
+        match read_message(&mut recv).await? {
+            ControlMessage::RegisterOk { .. } => {}
+            ControlMessage::Error { message, .. } => return Err(message),
+            other => return Err(format!("unexpected answer 0x{:02X}", other.tag())),
+        }
+        info!(relay_id, addr = %record.addr, "Linked to relay");
+
+        let (tx, mut rx) = ClientTx::new(
+            conn.clone(),
+            state.config.limits.outbound_queue_len,
+            Duration::from_secs(state.config.limits.slow_consumer_timeout_secs),
+        );
+        let link = Link {
+            tx,
+            conn: conn.clone(),
+        };
+        tokio::spawn(async move {
+            while let Some(msg) = rx.recv().await {
+                if write_message(&mut send, &msg).await.is_err() {
+                    break;
+                }
+            }
+        });
+        tokio::spawn(self.clone().accept_streams(state.clone(), conn.clone()));
+        let cluster = self.clone();
+        let state = state.clone();
+        let relay_id = relay_id.to_string();
+        let reader_link = link.clone();
+        tokio::spawn(async move {
+            while let Ok(msg) = read_message(&mut recv).await {
+                cluster.deliver(&state, &relay_id, &reader_link, msg);
+            }
+            cluster.drop_link(&state, &relay_id, &conn).await;
+        });
+        Ok(link)
+    }
+
+    /// Hands a message from the agent's relay to the controller of the
+    /// session it concerns.
+    ///
+    /// Messages are queued without waiting: the link carries many
+    /// sessions, and one slow controller must not hold up the others.
+    fn deliver(&self, state: &AppState, relay_id: &str, link: &Link, msg: ControlMessage) {
+        match msg {
+            ControlMessage::TunnelReady {
+                session_id,
+                request_id,
+                agent_id,
+                public_key,
+                max_streams,
+            } => {
+                let Some((_, pending)) = self.pending.remove(&request_id) else {
+                    // The controller left while the agent was deciding.
+                    let _ = link.tx.send(ControlMessage::TunnelClose { session_id });
+                    return;
+                };
+                debug!(session_id = %session_id, relay_id, "Forwarded tunnel ready");
+                self.sessions.insert(
+                    session_id.clone(),
+                    RemoteSession {
+                        relay_id: relay_id.to_string(),
+                        controller_id: pending.controller_id.clone(),
+                        link: link.clone(),
+                        buffers: Arc::new(BufferBudget::new(
+                            state.config.limits.session_buffer_bytes,
+                        )),
+                        priority: pending.traffic_class.stream_priority(),
+                    },
+                );
+                send_to(
+                    state,
+                    &pending.controller_id,
+                    ControlMessage::TunnelReady {
+                        session_id,
+                        request_id: pending.request_id,
+                        agent_id,
+                        public_key,
+                        max_streams,
+                    },
+                );
+            }
+            ControlMessage::ConnectFailed {
+                request_id,
+                code,
+                message,
+            } => {
+                if let Some((_, pending)) = self.pending.remove(&request_id) {
+                    send_to(
+                        state,
+                        &pending.controller_id,
+                        ControlMessage::ConnectFailed {
+                            request_id: pending.request_id,
+                            code,
+                            message,
+                        },
+                    );
+                }
+            }
+            ControlMessage::Error { code, message } => {
+                warn!(relay_id, ?code, "Relay reported an error: {}", message);
+            }
+            msg => {
+                let Some(session_id) = msg.session_id() else {
+                    return;
+                };
+                let session = self
+                    .sessions
+                    .get(session_id)
+                    .filter(|s| s.relay_id == relay_id)
+                    .map(|s| s.controller_id.clone());
+                let Some(controller_id) = session else {
+                    return;
+                };
+                if let ControlMessage::TunnelClose { session_id } = &msg {
+                    self.sessions.remove(session_id);
+                }
+                send_to(state, &controller_id, msg);
+            }
+        }
+    }
+
+    /// Relays data streams the agent's relay opens on a link to the
+    /// controllers of their sessions.
+    async fn accept_streams(self: Arc<Self>, state: AppState, conn: quinn::Connection) {
+        while let Ok((send, mut recv)) = conn.accept_bi().await {
+            let mut prefix = [0u8; 17];
+            if recv.read_exact(&mut prefix).await.is_err() {
+                continue;
+            }
+            let session_id: String = String::from_utf8_lossy(&prefix[1..9])
+                .trim_end_matches('\0')
+                .to_string();
+            let session = self.sessions.get(&session_id).map(|s| s.clone());
+            let controller = session.as_ref().and_then(|s| {
+                state
+                    .connections
+                    .get(&s.controller_id)
+                    .map(|c| c.conn.clone())
+            });
+            match (session, controller) {
+                (Some(session), Some(controller)) => splice(
+                    &state,
+                    controller,
+                    prefix,
+                    send,
+                    recv,
+                    session.buffers,
+                    session.priority,
+                ),
+                _ => warn!(session_id = %session_id, "Linked stream for unknown session"),
+            }
+        }
+    }
+
+    /// Forgets a link that closed and ends the sessions that used it.
+    async fn drop_link(&self, state: &AppState, relay_id: &str, conn: &quinn::Connection) {
+        warn!(relay_id, "Link to relay closed");
+        let mut links = self.links.lock().await;
+        if links
+            .get(relay_id)
+            .is_some_and(|l| l.conn.stable_id() == conn.stable_id())
+        {
+            links.remove(relay_id);
+        }
+        drop(links);
+
+        let failed: Vec<String> = self
+            .pending
+            .iter()
+            .filter(|p| p.relay_id == relay_id)
+            .map(|p| p.key().clone())
+            .collect();
+        for request_id in failed {
+            if let Some((_, pending)) = self.pending.remove(&request_id) {
+                send_to(
+                    state,
+                    &pending.controller_id,
+                    ControlMessage::ConnectFailed {
+                        request_id: pending.request_id,
+                        code: ErrorCode::Internal,
+                        message: format!("Lost the link to relay '{}'", relay_id),
+                    },
+                );
+            }
+        }
+        let closed: Vec<String> = self
+            .sessions
+            .iter()
+            .filter(|s| s.link.conn.stable_id() == conn.stable_id())
+            .map(|s| s.key().clone())
+            .collect();
+        for session_id in closed {
+            if let Some((_, session)) = self.sessions.remove(&session_id) {
+                send_to(
+                    state,
+                    &session.controller_id,
+                    ControlMessage::TunnelClose { session_id },
+                );
+            }
+        }
+    }
+}
+
+/// Applies another relay's announcement to the cached remote agents.
+/// A departure only removes the agent if it was still on that relay.
+fn apply_event(agents: &DashMap<String, RemoteAgent>, own_relay: &str, event: AgentEvent) {
+    if event.relay_id == own_relay {
+        return;
+    }
+    match event.agent {
+        Some(agent) => {
+            agents.insert(event.agent_id, agent);
+        }
+        None => {
+            agents.remove_if(&event.agent_id, |_, a| a.relay_id == event.relay_id);
+        }
+    }
+}
+
+fn send_to(state: &AppState, conn_id: &str, msg: ControlMessage) {
+    if let Some(c) = state.connections.get(conn_id) {
+        let _ = c.tx.send(msg);
+    }
+}
+
+/// Opens the counterpart of a data stream on `target`, writes the routing
+/// `prefix` and relays both directions at send `priority`.
+fn splice(
+    state: &AppState,
+    target: quinn::Connection,
+    prefix: [u8; 17],
+    send: SendStream,
+    recv: RecvStream,
+    buffers: Arc<BufferBudget>,
+    priority: i32,
+) {
+    let limits = state.config.limits.clone();
+    tokio::spawn(async move {
+        let (mut t_send, t_recv) = match target.open_bi().await {
+            Ok(streams) => streams,
+            Err(e) => {
+                warn!("Failed to open linked stream: {}", e);
+                return;
+            }
+        };
+        let _ = t_send.set_priority(priority);
+        let _ = send.set_priority(priority);
+        if t_send.write_all(&prefix).await.is_err() {
+            return;
+        }
+        let chunk = limits.stream_buffer_bytes;
+        let stall = Duration::from_secs(limits.stall_timeout_secs);
+        // The relay holding the session counts its traffic.
+        let back = buffers.clone();
+        tokio::spawn(async move {
+            let uncounted = AtomicU64::new(0);
+            let _ = relay::relay_stream(t_recv, send, &back, &uncounted, chunk, stall).await;
+        });
+        let uncounted = AtomicU64::new(0);
+        let _ = relay::relay_stream(recv, t_send, &buffers, &uncounted, chunk, stall).await;
+    });
+}
+
+async fn write_message(send: &mut SendStream, msg: &ControlMessage) -> Result<(), String> {
+    let bytes = msg.serialize().map_err(|e| e.to_string())?;
+    send.write_all(&(bytes.len() as u32).to_le_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    send.write_all(&bytes).await.map_err(|e| e.to_string())
+}
+
+async fn read_message(recv: &mut RecvStream) -> Result<ControlMessage, String> {
+    let mut len = [0u8; 4];
+    recv.read_exact(&mut len).await.map_err(|e| e.to_string())?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_CONTROL_FRAME {
+        return Err("control frame exceeds limit".to_string());
+    }
+    let mut buf = vec![0u8; len];
+    recv.read_exact(&mut buf).await.map_err(|e| e.to_string())?;
+    ControlMessage::deserialize(&buf)
+}
+
+/// `url` with the password or other user info replaced by `***`, for logs.
+fn redact(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_string();
+    };
+    let authority = rest.split('/').next().unwrap_or(rest);
+    match authority.rfind('@') {
+        Some(at) => format!("{}://***@{}", scheme, &rest[at + 1..]),
+        None => url.to_string(),
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent(relay_id: &str) -> RemoteAgent {
+        RemoteAgent {
+            relay_id: relay_id.to_string(),
+            name: Some("office".to_string()),
+            tags: vec!["env=prod".to_string()],
+            identity: Some("office-pc".to_string()),
+            groups: vec!["hosts".to_string()],
+            services: Vec::new(),
+        }
+    }
+
+    fn event(relay_id: &str, agent_id: &str, agent: Option<RemoteAgent>) -> AgentEvent {
+        AgentEvent {
+            relay_id: relay_id.to_string(),
+            agent_id: agent_id.to_string(),
+            agent,
+        }
+    }
+
+    #[test]
+    fn events_track_agents_on_other_relays() {
+        let agents = DashMap::new();
+        apply_event(
+            &agents,
+            "relay-a",
+            event("relay-a", "A1", Some(agent("relay-a"))),
+        );
+        assert!(agents.is_empty());
+
+        apply_event(
+            &agents,
+            "relay-a",
+            event("relay-b", "B1", Some(agent("relay-b"))),
+        );
+        let principal = agents.get("B1").unwrap().principal().unwrap();
+        assert_eq!(principal.name, "office-pc");
+        assert_eq!(principal.groups, ["hosts"]);
+
+        // The agent moved to relay C before relay B's departure arrived.
+        apply_event(
+            &agents,
+            "relay-a",
+            event("relay-c", "B1", Some(agent("relay-c"))),
+        );
+        apply_event(&agents, "relay-a", event("relay-b", "B1", None));
+        assert_eq!(agents.get("B1").unwrap().relay_id, "relay-c");
+        apply_event(&agents, "relay-a", event("relay-c", "B1", None));
+        assert!(agents.is_empty());
+    }
+
+    #[tokio::test]
+    async fn admits_only_the_cluster_secret() {
+        let cluster = Cluster::detached("another-long-random-secret");
+        assert!(cluster.admits("another-long-random-secret"));
+        assert!(!cluster.admits("another-long-random-secreT"));
+        assert!(!cluster.admits("another-long-random"));
+        assert!(!cluster.admits(""));
+    }
+
+    #[test]
+    fn redis_credentials_are_redacted() {
+        assert_eq!(
+            redact("redis://:hunter2@10.0.0.9:6379/0"),
+            "redis://***@10.0.0.9:6379/0"
+        );
+        assert_eq!(
+            redact("rediss://relay:p@ss@redis.example.com/"),
+            "rediss://***@redis.example.com/"
+        );
+        assert_eq!(redact("redis://10.0.0.9/"), "redis://10.0.0.9/");
+        assert_eq!(
+            redact("redis+unix:///run/redis.sock"),
+            "redis+unix:///run/redis.sock"
+        );
+    }
+}
@@ -0,0 +1,532 @@
+//! # Server Configuration
+//!
+//! Loads the optional TOML configuration file for the relay server.
+//! The file path is taken from the `--config <path>` command-line argument
+//! or, failing that, the `TUNNEL_CONFIG` environment variable. When neither
+//! is set the server runs with defaults: no tokens and no access rules.
+//!
+//! ```toml
+//! min_client_version = "0.6.0"
+//!
+//! [[tokens]]
+//! name = "alice"
+//! token = "s3cr3t"
+//! groups = ["ops"]
+//!
+//! [[tokens]]
+//! name = "office-pc"
+//! token = "agent-only"
+//! scopes = ["accept"]
+//!
+//! [[acl]]
+//! controllers = ["group:ops"]
+//! agents = ["*"]
+//!
+//! [audit]
+//! path = "/var/log/tunnel-server/audit.jsonl"
+//!
+//! [expose]
+//! port_range = [20000, 20099]
+//!
+//! [ingress]
+//! bind = "0.0.0.0:8080"
+//! tls_bind = "0.0.0.0:443"
+//! domain = "tunnel.example.com"
+//!
+//! [ingress.hostnames]
+//! "demo.tunnel.example.com" = "alice"
+//!
+//! [ip_filter]
+//! allow = ["10.0.0.0/8"]
+//!
+//! [targets]
+//! deny_private = true
+//!
+//! [bans]
+//! path = "/var/lib/tunnel-server/bans.json"
+//!
+//! [[agent_keys]]
+//! agent_id = "OFFICE-PC"
+//! key = "long-random-secret"
+//!
+//! [cluster]
+//! redis_url = "redis://10.0.0.9/"
+//! advertise = "10.0.0.2:7070"
+//! secret = "another-long-random-secret"
+//!
+//! [mdns]
+//! enabled = true
+//! name = "office-relay"
+//!
+//! [[listen]]
+//! bind = "0.0.0.0:443"
+//! serve = ["relay"]
+//!
+//! [[listen]]
+//! bind = "127.0.0.1:7070"
+//! serve = ["api", "admin"]
+//! ```
+
+use crate::acl::AclRule;
+use crate::auth::Scope;
+use crate::ipfilter::IpFilter;
+use crate::targets::TargetPolicy;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use tunnel_protocol::parse_version;
+
+/// Top-level server configuration, deserialized from TOML.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Credentials accepted in `Register`, each mapped to a named identity.
+    pub tokens: Vec<TokenConfig>,
+
+    /// Access rules evaluated on every `Connect`.
+    /// An empty list allows every controller to reach every agent.
+    pub acl: Vec<AclRule>,
+
+    /// Memory and concurrency caps.
+    pub limits: LimitsConfig,
+
+    /// Age and size limits for persisted records.
+    pub retention: RetentionConfig,
+
+    /// Where tunnel events are recorded.
+    pub audit: AuditConfig,
+
+    /// Public TCP ports agents may ask the relay to listen on.
+    pub expose: ExposeConfig,
+
+    /// HTTP listener routing requests to agents by `Host` header.
+    pub ingress: IngressConfig,
+
+    /// Client address ranges accepted on QUIC and the REST API.
+    pub ip_filter: IpFilter,
+
+    /// Targets controllers may ask agents of this relay to dial.
+    pub targets: TargetPolicy,
+
+    /// Cross-origin and authentication settings of the REST API.
+    pub api: ApiConfig,
+
+    /// Fixed agent IDs and the pre-shared keys agents must prove to
+    /// register as them.
+    pub agent_keys: Vec<AgentKeyConfig>,
+
+    /// Where banned agent IDs and tokens are kept.
+    pub bans: BansConfig,
+
+    /// Agent registry shared with other relays.
+    pub cluster: ClusterConfig,
+
+    /// Advertisement on the local network.
+    pub mdns: MdnsConfig,
+
+    /// Oldest client version allowed to register (e.g., "0.6.0").
+    /// Clients that are older or do not report a version are refused
+    /// with `UpgradeRequired`.
+    pub min_client_version: Option<String>,
+
+    /// Addresses the relay and the REST API listen on. When empty,
+    /// everything is served on port 7070 of all interfaces.
+    pub listen: Vec<ListenConfig>,
+}
+
+/// What a listener serves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Route {
+    /// QUIC connections of agents and controllers, on UDP.
+    Relay,
+    /// REST endpoints outside `/api/admin` and the OpenAPI document, on TCP.
+    Api,
+    /// `/api/admin/*`, on TCP.
+    Admin,
+}
+
+/// A listener from the `[[listen]]` tables.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListenConfig {
+    /// Address to bind, on UDP for `relay` and on TCP for the REST API.
+    pub bind: SocketAddr,
+
+    /// Route sets served on this address.
+    pub serve: Vec<Route>,
+}
+
+impl ListenConfig {
+    /// Whether this listener serves `route`.
+    pub fn serves(&self, route: Route) -> bool {
+        self.serve.contains(&route)
+    }
+}
+
+/// LAN discovery settings, from the `[mdns]` table.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MdnsConfig {
+    /// Advertise the relay over mDNS so clients on the LAN can find it.
+    pub enabled: bool,
+
+    /// Instance name clients see (e.g., "office-relay").
+    pub name: String,
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            name: "tunnel-relay".to_string(),
+        }
+    }
+}
+
+/// Relay clustering settings, from the `[cluster]` table.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ClusterConfig {
+    /// Redis server the relays share (e.g., "redis://10.0.0.9/").
+    /// Clustering is off when unset.
+    pub redis_url: Option<String>,
+
+    /// Name of this relay within the cluster; a random one when unset.
+    pub relay_id: Option<String>,
+
+    /// QUIC address other relays reach this one at.
+    pub advertise: Option<SocketAddr>,
+
+    /// Secret relays present when linking to each other.
+    pub secret: String,
+
+    /// How often the relay refreshes its entry in Redis and reloads the
+    /// other relays' agents. Entries expire after three missed refreshes.
+    pub heartbeat_secs: u64,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            redis_url: None,
+            relay_id: None,
+            advertise: None,
+            secret: String::new(),
+            heartbeat_secs: 10,
+        }
+    }
+}
+
+/// Ban list settings, from the `[bans]` table.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BansConfig {
+    /// JSON file holding the bans; they are kept in memory only if unset.
+    pub path: Option<PathBuf>,
+}
+
+/// REST API settings, from the `[api]` table.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ApiConfig {
+    /// Web origins allowed to call the API from a browser (e.g.,
+    /// "https://dashboard.example.com"), or `["*"]` for any. Browsers
+    /// block cross-origin calls when empty.
+    pub cors_origins: Vec<String>,
+
+    /// Serve `/api/*` without a token even when `[[tokens]]` are configured.
+    pub public: bool,
+
+    /// Address of the gRPC control API, for builds with the `grpc`
+    /// feature. It is off when unset.
+    pub grpc_bind: Option<SocketAddr>,
+
+    /// Unix socket the REST API also listens on, for a local reverse
+    /// proxy. Requests through it come from 127.0.0.1 as far as
+    /// `[ip_filter]` is concerned. The socket is made readable and
+    /// writable by the server's user and group only (0660).
+    pub socket: Option<PathBuf>,
+
+    /// Serve the REST API on `socket` only, without the TCP listener.
+    pub socket_only: bool,
+}
+
+/// HTTP and TLS ingress settings, from the `[ingress]` table.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct IngressConfig {
+    /// Address of the HTTP listener. HTTP ingress is off when unset.
+    pub bind: Option<SocketAddr>,
+
+    /// Address of the TLS listener that routes by SNI, usually port 443.
+    /// TLS ingress is off when unset.
+    pub tls_bind: Option<SocketAddr>,
+
+    /// Hostnames agents register must be subdomains of this domain.
+    /// Any hostname is accepted when unset.
+    pub domain: Option<String>,
+
+    /// Hostnames reserved for an identity, from the `[ingress.hostnames]`
+    /// table, mapped to its name. Only that identity may claim them; any
+    /// other hostname belongs to the first identity that claims it.
+    pub hostnames: HashMap<String, String>,
+}
+
+/// Public port exposure settings, from the `[expose]` table.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ExposeConfig {
+    /// Address the public listeners bind to.
+    pub bind: IpAddr,
+
+    /// Inclusive range of ports agents may expose. Exposure is off when unset.
+    pub port_range: Option<[u16; 2]>,
+}
+
+impl Default for ExposeConfig {
+    fn default() -> Self {
+        Self {
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port_range: None,
+        }
+    }
+}
+
+/// Audit log settings, from the `[audit]` table.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AuditConfig {
+    /// JSONL file that receives audit events. Auditing is off when unset.
+    pub path: Option<PathBuf>,
+}
+
+/// Memory and concurrency caps, from the `[limits]` table.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Maximum bytes buffered per data stream (also the QUIC stream receive window).
+    pub stream_buffer_bytes: usize,
+
+    /// Maximum bytes buffered across all streams of one session. Values
+    /// above `usize::MAX >> 3`, the most a semaphore can count, are capped.
+    pub session_buffer_bytes: usize,
+
+    /// How long a stream may stay blocked on a full buffer before it is reset.
+    pub stall_timeout_secs: u64,
+
+    /// Maximum concurrent tunnel sessions targeting one agent.
+    pub max_tunnels_per_agent: usize,
+
+    /// Maximum concurrent data streams within one session.
+    pub max_streams_per_session: usize,
+
+    /// Control messages queued for one connection before it counts as saturated.
+    pub outbound_queue_len: usize,
+
+    /// How long a relayed message may wait for room in a saturated queue
+    /// before that connection is dropped as a slow consumer.
+    pub slow_consumer_timeout_secs: u64,
+
+    /// How long the target agent may leave a `TunnelRequest` unanswered
+    /// before the session is cancelled.
+    pub tunnel_request_timeout_secs: u64,
+
+    /// Sustained bytes per second relayed for the agents of one identity,
+    /// or one agent without a token, across all their sessions and both
+    /// directions. `0` leaves agents unshaped.
+    pub agent_rate_bytes: u64,
+
+    /// Bytes an idle agent may relay at once before `agent_rate_bytes`
+    /// applies. `0` allows one second's worth.
+    pub agent_burst_bytes: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            stream_buffer_bytes: 256 * 1024,
+            session_buffer_bytes: 8 * 1024 * 1024,
+            stall_timeout_secs: 30,
+            max_tunnels_per_agent: 64,
+            max_streams_per_session: 256,
+            outbound_queue_len: 1024,
+            slow_consumer_timeout_secs: 10,
+            tunnel_request_timeout_secs: 90,
+            agent_rate_bytes: 0,
+            agent_burst_bytes: 0,
+        }
+    }
+}
+
+/// Age and size limits for persisted records, from the `[retention]` table.
+/// A limit of `0` disables it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// Records older than this many days are deleted.
+    pub max_age_days: u64,
+
+    /// Each file is trimmed, oldest records first, to at most this many bytes.
+    pub max_bytes: u64,
+
+    /// How often the background cleanup runs.
+    pub cleanup_interval_secs: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            max_age_days: 90,
+            max_bytes: 100 * 1024 * 1024,
+            cleanup_interval_secs: 3600,
+        }
+    }
+}
+
+/// A single credential entry from the `[[tokens]]` tables.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenConfig {
+    /// Identity name used in logs and ACL rules (e.g., "alice").
+    pub name: String,
+
+    /// The secret value clients send in `Register`.
+    pub token: String,
+
+    /// Groups this identity belongs to, referenced as `group:<name>` in ACL rules.
+    #[serde(default)]
+    pub groups: Vec<String>,
+
+    /// Grants the observer role: the identity may ask to watch other
+    /// controllers' sessions, subject to their consent.
+    #[serde(default)]
+    pub observer: bool,
+
+    /// Grants the admin role: the token may call `/api/admin/*` endpoints
+    /// as `Authorization: Bearer <token>`.
+    #[serde(default)]
+    pub admin: bool,
+
+    /// What the token may be used for: `accept` tunnels as an agent,
+    /// `connect` to agents as a controller, or both (the default).
+    #[serde(default = "all_scopes")]
+    pub scopes: Vec<Scope>,
+
+    /// Overrides `[limits] agent_rate_bytes` for agents registered with
+    /// this token; `0` leaves them unshaped.
+    #[serde(default)]
+    pub rate_bytes: Option<u64>,
+
+    /// Overrides `[limits] agent_burst_bytes` for agents registered with
+    /// this token.
+    #[serde(default)]
+    pub burst_bytes: Option<u64>,
+}
+
+pub fn all_scopes() -> Vec<Scope> {
+    vec![Scope::Accept, Scope::Connect]
+}
+
+/// A reserved agent ID from the `[[agent_keys]]` tables.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AgentKeyConfig {
+    /// The agent ID (e.g., "OFFICE-PC").
+    pub agent_id: String,
+
+    /// Secret the agent signs registration challenges with.
+    pub key: String,
+}
+
+impl ServerConfig {
+    /// Returns the pre-shared key of a reserved agent ID.
+    pub fn agent_key(&self, agent_id: &str) -> Option<&str> {
+        self.agent_keys
+            .iter()
+            .find(|k| k.agent_id == agent_id)
+            .map(|k| k.key.as_str())
+    }
+
+    /// Returns the `rate_bytes` and `burst_bytes` overrides of the
+    /// configured token `name`, or `None` if there is no such token.
+    pub fn token_shaping(&self, name: &str) -> Option<(Option<u64>, Option<u64>)> {
+        self.tokens
+            .iter()
+            .find(|t| t.name == name)
+            .map(|t| (t.rate_bytes, t.burst_bytes))
+    }
+
+    /// Returns the sustained rate and burst, in bytes, that agents are
+    /// shaped to under a token's overrides, or `None` if they are not
+    /// shaped.
+    pub fn agent_shaping(&self, overrides: (Option<u64>, Option<u64>)) -> Option<(u64, u64)> {
+        let rate = overrides.0.unwrap_or(self.limits.agent_rate_bytes);
+        let burst = overrides.1.unwrap_or(self.limits.agent_burst_bytes);
+        match (rate, burst) {
+            (0, _) => None,
+            (rate, 0) => Some((rate, rate)),
+            (rate, burst) => Some((rate, burst)),
+        }
+    }
+
+    /// Configured listeners, or the default one serving everything on
+    /// port 7070.
+    pub fn listeners(&self) -> Vec<ListenConfig> {
+        if !self.listen.is_empty() {
+            return self.listen.clone();
+        }
+        vec![ListenConfig {
+            bind: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 7070)),
+            serve: vec![Route::Relay, Route::Api, Route::Admin],
+        }]
+    }
+
+    /// Loads the configuration from the path given on the command line or in
+    /// `TUNNEL_CONFIG`, returning defaults when no path is configured.
+    pub fn load() -> Result<Self, String> {
+        let path = cli_arg("--config")
+            .or_else(|| std::env::var("TUNNEL_CONFIG").ok())
+            .map(PathBuf::from);
+
+        match path {
+            Some(path) => {
+                let raw = std::fs::read_to_string(&path)
+                    .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+                let config: Self = toml::from_str(&raw)
+                    .map_err(|e| format!("Invalid config {}: {}", path.display(), e))?;
+                if !config.listen.is_empty()
+                    && !config.listen.iter().any(|l| l.serves(Route::Relay))
+                {
+                    return Err(format!(
+                        "Invalid config {}: no [[listen]] entry serves \"relay\"",
+                        path.display()
+                    ));
+                }
+                if let Some(min) = &config.min_client_version {
+                    if parse_version(min).is_none() {
+                        return Err(format!(
+                            "Invalid config {}: min_client_version '{}' is not a version",
+                            path.display(),
+                            min
+                        ));
+                    }
+                }
+                Ok(config)
+            }
+            None => Ok(Self::default()),
+        }
+    }
+}
+
+/// Returns the value of a `--name <value>` or `--name=<value>` argument.
+pub fn cli_arg(name: &str) -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == name {
+            return args.next();
+        }
+        if let Some(value) = arg.strip_prefix(name).and_then(|r| r.strip_prefix('=')) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
@@ -0,0 +1,1105 @@
+//! # Storage
+//!
+//! Keeps what should outlive a restart in a SQLite database: the agents
+//! that have registered, tokens issued through `/api/admin/tokens`, a
+//! history of tunnel sessions, access requests awaiting or holding an
+//! agent's decision, and the owners of ingress hostnames. The file is named by `--db <path>` or the
+//! `TUNNEL_DB` environment variable; without one the database lives in
+//! memory and is lost when the server stops.
+//!
+//! The schema is created by the [`MIGRATIONS`] below, applied in order and
+//! tracked in SQLite's `user_version`. A migration is never edited once
+//! released: schema changes are made by appending a new one.
+//!
+//! Issued tokens are stored as SHA-256 hashes, so the database never holds
+//! a usable secret. Tokens from `[[tokens]]` stay in the configuration.
+//! The hashes are also kept in memory, so authenticating a client does not
+//! touch SQLite.
+//!
+//! Writes made while relaying (session history and agent registrations)
+//! are queued and applied by a writer thread, so SQLite never holds up the
+//! async workers. Every other call applies the queue first, so it sees all
+//! writes made before it. The API runs its queries through
+//! [`Database::run`] on the blocking thread pool.
+
+use crate::audit::AuditEvent;
+use crate::auth::{Principal, Scope};
+use dashmap::DashMap;
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Serialize;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use tracing::{debug, info, warn};
+use tunnel_protocol::{unix_time_ms, AccessState, ServiceInfo, ACCESS_REQUEST_TTL_MS};
+use utoipa::ToSchema;
+
+/// Schema changes, in order. `user_version` holds how many were applied.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE agents (
+        agent_id   TEXT PRIMARY KEY,
+        name       TEXT,
+        identity   TEXT,
+        tags       TEXT NOT NULL,
+        first_seen INTEGER NOT NULL,
+        last_seen  INTEGER NOT NULL
+    );
+    CREATE TABLE tokens (
+        name       TEXT PRIMARY KEY,
+        token_hash TEXT NOT NULL UNIQUE,
+        groups     TEXT NOT NULL,
+        observer   INTEGER NOT NULL,
+        admin      INTEGER NOT NULL,
+        scopes     TEXT NOT NULL,
+        created_by TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE TABLE sessions (
+        id          INTEGER PRIMARY KEY AUTOINCREMENT,
+        session_id  TEXT NOT NULL,
+        agent_id    TEXT NOT NULL,
+        identity    TEXT,
+        target      TEXT NOT NULL,
+        public      TEXT,
+        opened_at   INTEGER NOT NULL,
+        accepted_at INTEGER,
+        closed_at   INTEGER,
+        outcome     TEXT
+    );
+    CREATE INDEX sessions_session_id ON sessions (session_id);",
+    "ALTER TABLE agents ADD COLUMN version TEXT;
+    ALTER TABLE agents ADD COLUMN services TEXT NOT NULL DEFAULT '[]';
+    ALTER TABLE agents ADD COLUMN public_key TEXT;",
+    "CREATE TABLE access_requests (
+        access_id    TEXT PRIMARY KEY,
+        request_id   TEXT NOT NULL,
+        agent_id     TEXT NOT NULL,
+        identity     TEXT,
+        remote_host  TEXT NOT NULL,
+        remote_port  INTEGER NOT NULL,
+        reason       TEXT,
+        requested_at INTEGER NOT NULL,
+        approved     INTEGER,
+        expires_at   INTEGER,
+        delivered    INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX access_requests_agent_id ON access_requests (agent_id);",
+    "ALTER TABLE access_requests ADD COLUMN invite_hash TEXT;
+    CREATE UNIQUE INDEX access_requests_invite_hash ON access_requests (invite_hash);",
+    "ALTER TABLE tokens ADD COLUMN rate_bytes INTEGER;
+    ALTER TABLE tokens ADD COLUMN burst_bytes INTEGER;",
+    "CREATE TABLE hostnames (
+        hostname   TEXT PRIMARY KEY,
+        identity   TEXT NOT NULL,
+        claimed_at INTEGER NOT NULL
+    );",
+];
+
+/// An agent that has registered at some point.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct KnownAgent {
+    pub agent_id: String,
+    pub name: Option<String>,
+
+    /// Identity of the token it last registered with.
+    pub identity: Option<String>,
+    pub tags: Vec<String>,
+
+    /// Client version and services of its latest registration.
+    pub version: Option<String>,
+    pub services: Vec<ServiceInfo>,
+
+    /// Hex-encoded Ed25519 key backing its ID, if any.
+    pub public_key: Option<String>,
+
+    /// First and latest registration or disconnect, milliseconds since
+    /// the Unix epoch.
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+/// A token issued through the admin API. The secret itself is not kept.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct IssuedToken {
+    pub name: String,
+    pub groups: Vec<String>,
+    pub observer: bool,
+    pub admin: bool,
+    pub scopes: Vec<Scope>,
+
+    /// Override `[limits] agent_rate_bytes` and `agent_burst_bytes` for
+    /// agents registered with it.
+    pub rate_bytes: Option<u64>,
+    pub burst_bytes: Option<u64>,
+
+    /// Identity of the admin who issued it.
+    pub created_by: String,
+    pub created_at: u64,
+}
+
+impl IssuedToken {
+    fn principal(self) -> Principal {
+        Principal {
+            name: self.name,
+            groups: self.groups,
+            observer: self.observer,
+            admin: self.admin,
+            scopes: self.scopes,
+        }
+    }
+}
+
+/// A tunnel session, open or ended.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SessionRecord {
+    pub session_id: String,
+    pub agent_id: String,
+
+    /// Identity of the controller, or of the agent for exposed services.
+    pub identity: Option<String>,
+
+    /// Where the agent forwards to: `host:port` or a Unix socket path.
+    pub target: String,
+
+    /// Public port or ingress hostname, for sessions created by `Expose`
+    /// or `ExposeHttp`.
+    pub public: Option<String>,
+    pub opened_at: u64,
+    pub accepted_at: Option<u64>,
+    pub closed_at: Option<u64>,
+
+    /// Why the session ended, or `None` while it is open.
+    pub outcome: Option<String>,
+}
+
+/// A controller's request for access to a target of an agent.
+#[derive(Debug, Clone)]
+pub struct AccessRecord {
+    pub access_id: String,
+
+    /// The controller's `RequestAccess.request_id`, echoed in updates.
+    pub request_id: String,
+    pub agent_id: String,
+
+    /// Identity of the controller that asked.
+    pub identity: Option<String>,
+    pub remote_host: String,
+    pub remote_port: u16,
+    pub reason: Option<String>,
+    pub requested_at: u64,
+
+    /// The agent's decision, `None` while pending.
+    pub approved: Option<bool>,
+
+    /// When an approval runs out.
+    pub expires_at: Option<u64>,
+
+    /// Whether the invitation of an approval was handed out.
+    pub delivered: bool,
+}
+
+impl AccessRecord {
+    /// Where the request stands at `now`.
+    pub fn state(&self, now: u64) -> AccessState {
+        match self.approved {
+            None if now.saturating_sub(self.requested_at) >= ACCESS_REQUEST_TTL_MS => {
+                AccessState::Expired
+            }
+            None => AccessState::Pending,
+            Some(false) => AccessState::Denied,
+            Some(true) if self.expires_at.is_some_and(|t| t <= now) => AccessState::Expired,
+            Some(true) => AccessState::Approved,
+        }
+    }
+}
+
+/// A write queued for the writer thread, stamped when it was made.
+#[derive(Debug)]
+enum Write {
+    Agent {
+        agent_id: String,
+        name: Option<String>,
+        identity: Option<String>,
+        tags: String,
+        version: Option<String>,
+        services: String,
+        public_key: Option<String>,
+        at: i64,
+    },
+    AgentSeen {
+        agent_id: String,
+        at: i64,
+    },
+    Event {
+        event: AuditEvent,
+        at: i64,
+    },
+}
+
+/// The connection and the writes not yet applied to it.
+#[derive(Debug)]
+struct Inner {
+    conn: Connection,
+    queue: mpsc::Receiver<Write>,
+}
+
+/// The locked connection, with the queued writes applied.
+struct Locked<'a>(MutexGuard<'a, Inner>);
+
+impl<'a> Locked<'a> {
+    fn new(inner: &'a Mutex<Inner>) -> Self {
+        let guard = inner.lock().unwrap_or_else(|e| e.into_inner());
+        while let Ok(write) = guard.queue.try_recv() {
+            apply(&guard.conn, write);
+        }
+        Self(guard)
+    }
+}
+
+impl Deref for Locked<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.0.conn
+    }
+}
+
+/// The server's SQLite database.
+#[derive(Debug)]
+pub struct Database {
+    inner: Arc<Mutex<Inner>>,
+    writes: mpsc::Sender<Write>,
+
+    /// Wakes the writer thread; closing it stops the thread.
+    wake: mpsc::Sender<()>,
+
+    /// Issued tokens by hash.
+    tokens: DashMap<String, IssuedToken>,
+}
+
+impl Database {
+    /// Opens the database at `path`, creating it and applying pending
+    /// migrations. Sessions left open by a previous run are closed.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        }
+        let conn = Connection::open(path)
+            .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")
+            .map_err(|e| format!("Failed to configure {}: {}", path.display(), e))?;
+        let db = Self::init(conn).map_err(|e| format!("Database {}: {}", path.display(), e))?;
+        info!("Opened database {}", path.display());
+        Ok(db)
+    }
+
+    /// A database kept in memory, used when no path is configured.
+    pub fn in_memory() -> Self {
+        Connection::open_in_memory()
+            .map_err(|e| e.to_string())
+            .and_then(Self::init)
+            .expect("Failed to create in-memory database")
+    }
+
+    fn init(mut conn: Connection) -> Result<Self, String> {
+        migrate(&mut conn)?;
+        let stale = conn
+            .execute(
+                "UPDATE sessions SET closed_at = ?1, outcome = 'server stopped'
+                 WHERE closed_at IS NULL",
+                [unix_time_ms() as i64],
+            )
+            .map_err(|e| e.to_string())?;
+        if stale > 0 {
+            info!("Closed {} session(s) left open by the previous run", stale);
+        }
+        let tokens = DashMap::new();
+        {
+            let mut stmt = conn
+                .prepare(
+                    "SELECT name, groups, observer, admin, scopes, created_by, created_at,
+                            rate_bytes, burst_bytes, token_hash FROM tokens",
+                )
+                .map_err(|e| e.to_string())?;
+            let rows = stmt
+                .query_map([], |row| Ok((row.get::<_, String>(9)?, token_row(row)?)))
+                .map_err(|e| e.to_string())?;
+            for row in rows {
+                let (hash, issued) = row.map_err(|e| e.to_string())?;
+                tokens.insert(hash, issued);
+            }
+        }
+
+        let (writes, queue) = mpsc::channel();
+        let (wake, woken) = mpsc::channel();
+        let inner = Arc::new(Mutex::new(Inner { conn, queue }));
+        let writer = inner.clone();
+        std::thread::Builder::new()
+            .name("db-writer".to_string())
+            .spawn(move || {
+                while woken.recv().is_ok() {
+                    drop(Locked::new(&writer));
+                }
+            })
+            .map_err(|e| format!("Failed to start the writer thread: {}", e))?;
+        Ok(Self {
+            inner,
+            writes,
+            wake,
+            tokens,
+        })
+    }
+
+    fn lock(&self) -> Locked<'_> {
+        Locked::new(&self.inner)
+    }
+
+    fn queue(&self, write: Write) {
+        if self.writes.send(write).is_ok() {
+            let _ = self.wake.send(());
+        }
+    }
+
+    /// Runs `f` on the blocking thread pool, off the async workers.
+    pub async fn run<T: Send + 'static>(
+        self: &Arc<Self>,
+        f: impl FnOnce(&Database) -> rusqlite::Result<T> + Send + 'static,
+    ) -> rusqlite::Result<T> {
+        let db = self.clone();
+        tokio::task::spawn_blocking(move || f(&db))
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+    }
+
+    /// Records that `agent_id` registered.
+    #[allow(clippy::too_many_arguments)]
+    pub fn agent_registered(
+        &self,
+        agent_id: &str,
+        name: Option<&str>,
+        identity: Option<&str>,
+        tags: &[String],
+        version: Option<&str>,
+        services: &[ServiceInfo],
+        public_key: Option<&[u8]>,
+    ) {
+        self.queue(Write::Agent {
+            agent_id: agent_id.to_string(),
+            name: name.map(str::to_string),
+            identity: identity.map(str::to_string),
+            tags: to_json(tags),
+            version: version.map(str::to_string),
+            services: to_json(services),
+            public_key: public_key.map(to_hex),
+            at: unix_time_ms() as i64,
+        });
+    }
+
+    /// Records that `agent_id` disconnected.
+    pub fn agent_seen(&self, agent_id: &str) {
+        self.queue(Write::AgentSeen {
+            agent_id: agent_id.to_string(),
+            at: unix_time_ms() as i64,
+        });
+    }
+
+    /// Every agent that has registered, most recently seen first.
+    pub fn agents(&self) -> rusqlite::Result<Vec<KnownAgent>> {
+        let conn = self.lock();
+        let mut stmt = conn.prepare(&format!("{} ORDER BY last_seen DESC", AGENT_COLUMNS))?;
+        let rows = stmt.query_map([], agent_row)?;
+        rows.collect()
+    }
+
+    /// The most recently seen agent with ID `target`, or else with that
+    /// name, ignoring case.
+    pub fn find_agent(&self, target: &str) -> rusqlite::Result<Option<KnownAgent>> {
+        self.lock()
+            .query_row(
+                &format!(
+                    "{} WHERE agent_id = ?1 OR name = ?1 COLLATE NOCASE
+                     ORDER BY agent_id = ?1 DESC, last_seen DESC LIMIT 1",
+                    AGENT_COLUMNS
+                ),
+                [target],
+                agent_row,
+            )
+            .optional()
+    }
+
+    /// Stores `token` for `issued`. Returns `false` if the name is taken.
+    pub fn issue_token(&self, issued: &IssuedToken, token: &str) -> rusqlite::Result<bool> {
+        let hash = hash_token(token);
+        let inserted = self.lock().execute(
+            "INSERT INTO tokens
+                 (name, token_hash, groups, observer, admin, scopes, created_by, created_at,
+                  rate_bytes, burst_bytes)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+             ON CONFLICT (name) DO NOTHING",
+            params![
+                issued.name,
+                hash,
+                to_json(&issued.groups),
+                issued.observer,
+                issued.admin,
+                to_json(&issued.scopes),
+                issued.created_by,
+                issued.created_at as i64,
+                issued.rate_bytes.map(|n| n as i64),
+                issued.burst_bytes.map(|n| n as i64),
+            ],
+        )?;
+        if inserted == 1 {
+            self.tokens.insert(hash, issued.clone());
+        }
+        Ok(inserted == 1)
+    }
+
+    /// Deletes the token named `name` and releases the ingress hostnames
+    /// its identity claimed. Returns `false` if there was none.
+    pub fn revoke_token(&self, name: &str) -> rusqlite::Result<bool> {
+        let conn = self.lock();
+        let deleted = conn.execute("DELETE FROM tokens WHERE name = ?1", [name])?;
+        if deleted == 1 {
+            conn.execute("DELETE FROM hostnames WHERE identity = ?1", [name])?;
+        }
+        self.tokens.retain(|_, issued| issued.name != name);
+        Ok(deleted == 1)
+    }
+
+    /// Issued tokens, oldest first.
+    pub fn tokens(&self) -> rusqlite::Result<Vec<IssuedToken>> {
+        let conn = self.lock();
+        let mut stmt = conn.prepare(&format!("{} ORDER BY created_at", TOKEN_COLUMNS))?;
+        let rows = stmt.query_map([], token_row)?;
+        rows.collect()
+    }
+
+    /// Returns `true` if any token has been issued.
+    pub fn has_tokens(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    /// Returns the `rate_bytes` and `burst_bytes` overrides of the issued
+    /// token `name`, or `None` if there is no such token.
+    pub fn token_shaping(&self, name: &str) -> Option<(Option<u64>, Option<u64>)> {
+        self.tokens
+            .iter()
+            .find(|t| t.name == name)
+            .map(|t| (t.rate_bytes, t.burst_bytes))
+    }
+
+    /// Claims the ingress `hostname` for `identity` unless another identity
+    /// claimed it first. Returns `false` if it belongs to someone else.
+    /// `None` records nothing and only succeeds on an unclaimed hostname.
+    pub fn claim_hostname(&self, hostname: &str, identity: Option<&str>) -> rusqlite::Result<bool> {
+        let conn = self.lock();
+        if let Some(identity) = identity {
+            conn.execute(
+                "INSERT INTO hostnames (hostname, identity, claimed_at) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (hostname) DO NOTHING",
+                params![hostname, identity, unix_time_ms() as i64],
+            )?;
+        }
+        let owner: Option<String> = conn
+            .query_row(
+                "SELECT identity FROM hostnames WHERE hostname = ?1",
+                [hostname],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(owner.is_none() || owner.as_deref() == identity)
+    }
+
+    /// Looks up the principal of an issued `token`.
+    pub fn authenticate(&self, token: &str) -> Option<Principal> {
+        self.tokens
+            .get(&hash_token(token))
+            .map(|issued| issued.clone().principal())
+    }
+
+    /// Updates the session history from a tunnel event.
+    pub fn record(&self, event: &AuditEvent) {
+        self.queue(Write::Event {
+            event: event.clone(),
+            at: unix_time_ms() as i64,
+        });
+    }
+
+    /// The `limit` most recently opened sessions, newest first.
+    pub fn sessions(&self, limit: u32) -> rusqlite::Result<Vec<SessionRecord>> {
+        let conn = self.lock();
+        let mut stmt = conn.prepare(
+            "SELECT session_id, agent_id, identity, target, public,
+                    opened_at, accepted_at, closed_at, outcome
+             FROM sessions ORDER BY id DESC LIMIT ?1",
+        )?;
+        let rows = stmt.query_map([limit], |row| {
+            Ok(SessionRecord {
+                session_id: row.get(0)?,
+                agent_id: row.get(1)?,
+                identity: row.get(2)?,
+                target: row.get(3)?,
+                public: row.get(4)?,
+                opened_at: row.get::<_, i64>(5)? as u64,
+                accepted_at: row.get::<_, Option<i64>>(6)?.map(|t| t as u64),
+                closed_at: row.get::<_, Option<i64>>(7)?.map(|t| t as u64),
+                outcome: row.get(8)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Deletes ended sessions closed before `cutoff_ms`, returning how many.
+    pub fn prune_sessions(&self, cutoff_ms: u64) -> rusqlite::Result<usize> {
+        self.lock().execute(
+            "DELETE FROM sessions WHERE closed_at < ?1",
+            [cutoff_ms as i64],
+        )
+    }
+
+    /// Stores a new access request.
+    pub fn queue_access(&self, record: &AccessRecord) -> rusqlite::Result<()> {
+        self.lock().execute(
+            "INSERT INTO access_requests (access_id, request_id, agent_id, identity,
+                                          remote_host, remote_port, reason, requested_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                record.access_id,
+                record.request_id,
+                record.agent_id,
+                record.identity,
+                record.remote_host,
+                record.remote_port,
+                record.reason,
+                record.requested_at as i64,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The access request `access_id`, if it exists.
+    pub fn access_request(&self, access_id: &str) -> rusqlite::Result<Option<AccessRecord>> {
+        self.lock()
+            .query_row(
+                &format!("{} WHERE access_id = ?1", ACCESS_COLUMNS),
+                [access_id],
+                access_row,
+            )
+            .optional()
+    }
+
+    /// Undecided, unexpired access requests for `agent_id`, oldest first.
+    pub fn pending_access(&self, agent_id: &str) -> rusqlite::Result<Vec<AccessRecord>> {
+        let cutoff = unix_time_ms().saturating_sub(ACCESS_REQUEST_TTL_MS);
+        let conn = self.lock();
+        let mut stmt = conn.prepare(&format!(
+            "{} WHERE agent_id = ?1 AND approved IS NULL AND requested_at > ?2
+             ORDER BY requested_at",
+            ACCESS_COLUMNS
+        ))?;
+        let rows = stmt.query_map(params![agent_id, cutoff as i64], access_row)?;
+        rows.collect()
+    }
+
+    /// Records `agent_id`'s decision on its pending request `access_id`.
+    /// Returns `false` if there is no such request.
+    pub fn decide_access(
+        &self,
+        access_id: &str,
+        agent_id: &str,
+        approved: bool,
+        expires_at: Option<u64>,
+    ) -> rusqlite::Result<bool> {
+        let changed = self.lock().execute(
+            "UPDATE access_requests SET approved = ?3, expires_at = ?4
+             WHERE access_id = ?1 AND agent_id = ?2 AND approved IS NULL",
+            params![access_id, agent_id, approved, expires_at.map(|t| t as i64)],
+        )?;
+        Ok(changed > 0)
+    }
+
+    /// Marks the invitation of the approved request `access_id` as handed
+    /// out, as `invite`. Returns `false` if one already was.
+    pub fn access_delivered(&self, access_id: &str, invite: &str) -> rusqlite::Result<bool> {
+        let changed = self.lock().execute(
+            "UPDATE access_requests SET delivered = 1, invite_hash = ?2
+             WHERE access_id = ?1 AND approved = 1 AND delivered = 0",
+            params![access_id, hash_token(invite)],
+        )?;
+        Ok(changed > 0)
+    }
+
+    /// Consumes the invitation `invite` of an approved, unexpired access
+    /// request for exactly this target of `agent_id`.
+    pub fn redeem_access_invite(
+        &self,
+        invite: &str,
+        agent_id: &str,
+        remote_host: &str,
+        remote_port: u16,
+    ) -> rusqlite::Result<bool> {
+        let changed = self.lock().execute(
+            "UPDATE access_requests SET invite_hash = NULL
+             WHERE invite_hash = ?1 AND agent_id = ?2 AND remote_host = ?3 COLLATE NOCASE
+               AND remote_port = ?4 AND approved = 1 AND expires_at > ?5",
+            params![
+                hash_token(invite),
+                agent_id,
+                remote_host,
+                remote_port,
+                unix_time_ms() as i64
+            ],
+        )?;
+        Ok(changed > 0)
+    }
+
+    /// Deletes access requests made before `cutoff_ms`, returning how many.
+    pub fn prune_access_requests(&self, cutoff_ms: u64) -> rusqlite::Result<usize> {
+        self.lock().execute(
+            "DELETE FROM access_requests WHERE requested_at < ?1",
+            [cutoff_ms as i64],
+        )
+    }
+}
+
+impl Drop for Database {
+    /// Applies the writes still queued.
+    fn drop(&mut self) {
+        drop(self.lock());
+    }
+}
+
+/// Applies the migrations `conn` has not seen yet, each in its own
+/// transaction.
+fn migrate(conn: &mut Connection) -> Result<(), String> {
+    let version: usize = conn
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(|e| e.to_string())?;
+    if version > MIGRATIONS.len() {
+        return Err(format!(
+            "schema version {} is newer than this server supports ({})",
+            version,
+            MIGRATIONS.len()
+        ));
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        tx.execute_batch(migration)
+            .and_then(|_| tx.pragma_update(None, "user_version", i + 1))
+            .and_then(|_| tx.commit())
+            .map_err(|e| format!("migration {} failed: {}", i + 1, e))?;
+        debug!("Applied database migration {}", i + 1);
+    }
+    Ok(())
+}
+
+const AGENT_COLUMNS: &str = "SELECT agent_id, name, identity, tags, first_seen, last_seen,
+                                    version, services, public_key FROM agents";
+
+fn agent_row(row: &Row<'_>) -> rusqlite::Result<KnownAgent> {
+    Ok(KnownAgent {
+        agent_id: row.get(0)?,
+        name: row.get(1)?,
+        identity: row.get(2)?,
+        tags: from_json(row, 3)?,
+        first_seen: row.get::<_, i64>(4)? as u64,
+        last_seen: row.get::<_, i64>(5)? as u64,
+        version: row.get(6)?,
+        services: from_json(row, 7)?,
+        public_key: row.get(8)?,
+    })
+}
+
+const TOKEN_COLUMNS: &str =
+    "SELECT name, groups, observer, admin, scopes, created_by, created_at, rate_bytes,
+            burst_bytes FROM tokens";
+
+fn token_row(row: &Row<'_>) -> rusqlite::Result<IssuedToken> {
+    Ok(IssuedToken {
+        name: row.get(0)?,
+        groups: from_json(row, 1)?,
+        observer: row.get(2)?,
+        admin: row.get(3)?,
+        scopes: from_json(row, 4)?,
+        created_by: row.get(5)?,
+        created_at: row.get::<_, i64>(6)? as u64,
+        rate_bytes: row.get::<_, Option<i64>>(7)?.map(|n| n as u64),
+        burst_bytes: row.get::<_, Option<i64>>(8)?.map(|n| n as u64),
+    })
+}
+
+const ACCESS_COLUMNS: &str = "SELECT access_id, request_id, agent_id, identity, remote_host,
+                                     remote_port, reason, requested_at, approved,
+                                     expires_at, delivered FROM access_requests";
+
+fn access_row(row: &Row<'_>) -> rusqlite::Result<AccessRecord> {
+    Ok(AccessRecord {
+        access_id: row.get(0)?,
+        request_id: row.get(1)?,
+        agent_id: row.get(2)?,
+        identity: row.get(3)?,
+        remote_host: row.get(4)?,
+        remote_port: row.get(5)?,
+        reason: row.get(6)?,
+        requested_at: row.get::<_, i64>(7)? as u64,
+        approved: row.get(8)?,
+        expires_at: row.get::<_, Option<i64>>(9)?.map(|t| t as u64),
+        delivered: row.get(10)?,
+    })
+}
+
+/// Applies a queued write.
+fn apply(conn: &Connection, write: Write) {
+    match write {
+        Write::Agent {
+            agent_id,
+            name,
+            identity,
+            tags,
+            version,
+            services,
+            public_key,
+            at,
+        } => {
+            let result = conn.execute(
+                "INSERT INTO agents (agent_id, name, identity, tags, first_seen, last_seen,
+                                     version, services, public_key)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?5, ?6, ?7, ?8)
+                 ON CONFLICT (agent_id) DO UPDATE SET
+                     name = excluded.name, identity = excluded.identity,
+                     tags = excluded.tags, last_seen = excluded.last_seen,
+                     version = excluded.version, services = excluded.services,
+                     public_key = excluded.public_key",
+                params![agent_id, name, identity, tags, at, version, services, public_key],
+            );
+            log_failure("record agent", result);
+        }
+        Write::AgentSeen { agent_id, at } => {
+            let result = conn.execute(
+                "UPDATE agents SET last_seen = ?2 WHERE agent_id = ?1",
+                params![agent_id, at],
+            );
+            log_failure("record agent", result);
+        }
+        Write::Event { event, at } => record_event(conn, &event, at),
+    }
+}
+
+/// Updates the session history from a tunnel event.
+fn record_event(conn: &Connection, event: &AuditEvent, now: i64) {
+    let result = match event {
+        AuditEvent::Connect {
+            identity,
+            agent_id: Some(agent_id),
+            remote_host,
+            remote_port,
+            remote_socket,
+            session_id: Some(session_id),
+            ..
+        } => {
+            let target = remote_socket
+                .clone()
+                .unwrap_or_else(|| format!("{}:{}", remote_host, remote_port));
+            open_session(conn, session_id, agent_id, identity, &target, None, now)
+        }
+        AuditEvent::Expose {
+            identity,
+            agent_id: Some(agent_id),
+            remote_host,
+            remote_port,
+            public_port,
+            hostname,
+            session_id: Some(session_id),
+            ..
+        } => {
+            let target = format!("{}:{}", remote_host, remote_port);
+            let public = hostname
+                .clone()
+                .or_else(|| public_port.map(|p| p.to_string()));
+            open_session(conn, session_id, agent_id, identity, &target, public, now).and_then(
+                |_| {
+                    conn.execute(
+                        "UPDATE sessions SET accepted_at = ?2
+                         WHERE session_id = ?1 AND closed_at IS NULL",
+                        params![session_id, now],
+                    )
+                },
+            )
+        }
+        AuditEvent::Accept { session_id, .. } => conn.execute(
+            "UPDATE sessions SET accepted_at = ?2
+             WHERE session_id = ?1 AND closed_at IS NULL",
+            params![session_id, now],
+        ),
+        AuditEvent::Reject {
+            session_id, error, ..
+        } => close_session(conn, session_id, &format!("rejected: {:?}", error), now),
+        AuditEvent::Close {
+            session_id, reason, ..
+        } => close_session(conn, session_id, reason, now),
+        _ => Ok(0),
+    };
+    log_failure("record session", result);
+}
+
+fn open_session(
+    conn: &Connection,
+    session_id: &str,
+    agent_id: &str,
+    identity: &Option<String>,
+    target: &str,
+    public: Option<String>,
+    now: i64,
+) -> rusqlite::Result<usize> {
+    conn.execute(
+        "INSERT INTO sessions (session_id, agent_id, identity, target, public, opened_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![session_id, agent_id, identity, target, public, now],
+    )
+}
+
+fn close_session(
+    conn: &Connection,
+    session_id: &str,
+    outcome: &str,
+    now: i64,
+) -> rusqlite::Result<usize> {
+    conn.execute(
+        "UPDATE sessions SET closed_at = ?2, outcome = ?3
+         WHERE session_id = ?1 AND closed_at IS NULL",
+        params![session_id, now, outcome],
+    )
+}
+
+/// A new random token: 32 bytes, hex-encoded.
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("system randomness unavailable");
+    to_hex(&bytes)
+}
+
+/// Hex SHA-256 of `token`, the form tokens are stored and looked up in.
+fn hash_token(token: &str) -> String {
+    to_hex(digest(&SHA256, token.as_bytes()).as_ref())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn to_json<T: Serialize + ?Sized>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_else(|_| "[]".to_string())
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(row: &Row<'_>, idx: usize) -> rusqlite::Result<T> {
+    let raw: String = row.get(idx)?;
+    serde_json::from_str(&raw).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(idx, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+fn log_failure(what: &str, result: rusqlite::Result<usize>) {
+    if let Err(e) = result {
+        warn!("Failed to {}: {}", what, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tunnel_protocol::ErrorCode;
+
+    fn temp_db(name: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("tunnel-db-{}-{}.sqlite", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn issued(name: &str) -> IssuedToken {
+        IssuedToken {
+            name: name.to_string(),
+            groups: vec!["ops".to_string()],
+            observer: false,
+            admin: true,
+            scopes: vec![Scope::Connect],
+            rate_bytes: Some(1000),
+            burst_bytes: None,
+            created_by: "root".to_string(),
+            created_at: 1,
+        }
+    }
+
+    #[test]
+    fn data_survives_reopening() {
+        let path = temp_db("reopen");
+        let db = Database::open(&path).unwrap();
+        assert!(db.issue_token(&issued("ci"), "s3cr3t").unwrap());
+        assert!(!db.issue_token(&issued("ci"), "other").unwrap());
+        db.agent_registered(
+            "A3F8-B2C1",
+            Some("db"),
+            None,
+            &["env=prod".to_string()],
+            Some("0.6.0"),
+            &[ServiceInfo::parse("ssh=127.0.0.1:22").unwrap()],
+            Some(&[0xAB; 32]),
+        );
+        assert!(db.claim_hostname("demo.example.com", None).unwrap());
+        assert!(db.claim_hostname("demo.example.com", Some("ci")).unwrap());
+        drop(db);
+
+        let db = Database::open(&path).unwrap();
+        let principal = db.authenticate("s3cr3t").unwrap();
+        assert_eq!((principal.name.as_str(), principal.admin), ("ci", true));
+        assert_eq!(principal.scopes, vec![Scope::Connect]);
+        assert!(db.authenticate("other").is_none());
+        assert_eq!(db.token_shaping("ci"), Some((Some(1000), None)));
+        let agent = &db.agents().unwrap()[0];
+        assert_eq!(agent.tags, vec!["env=prod"]);
+        assert_eq!(agent.version.as_deref(), Some("0.6.0"));
+        assert_eq!(agent.services[0].name, "ssh");
+        assert_eq!(agent.public_key, Some("ab".repeat(32)));
+        let found = db.find_agent("DB").unwrap().unwrap();
+        assert_eq!(found.agent_id, "A3F8-B2C1");
+        assert!(db.find_agent("web").unwrap().is_none());
+        assert!(db.claim_hostname("demo.example.com", Some("ci")).unwrap());
+        assert!(!db.claim_hostname("demo.example.com", Some("bob")).unwrap());
+        assert!(!db.claim_hostname("demo.example.com", None).unwrap());
+
+        assert!(db.revoke_token("ci").unwrap());
+        assert!(db.authenticate("s3cr3t").is_none());
+        assert!(db.claim_hostname("demo.example.com", Some("bob")).unwrap());
+        drop(db);
+        let version: usize = Connection::open(&path)
+            .unwrap()
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn session_history_follows_events() {
+        let db = Database::in_memory();
+        db.record(&AuditEvent::Connect {
+            conn_id: "c1".to_string(),
+            identity: Some("alice".to_string()),
+            target: "db".to_string(),
+            agent_id: Some("A3F8-B2C1".to_string()),
+            remote_host: "127.0.0.1".to_string(),
+            remote_port: 5432,
+            remote_socket: None,
+            session_id: Some("s1".to_string()),
+            error: None,
+        });
+        db.record(&AuditEvent::Connect {
+            conn_id: "c1".to_string(),
+            identity: Some("alice".to_string()),
+            target: "db".to_string(),
+            agent_id: Some("A3F8-B2C1".to_string()),
+            remote_host: "127.0.0.1".to_string(),
+            remote_port: 22,
+            remote_socket: None,
+            session_id: Some("s2".to_string()),
+            error: None,
+        });
+        db.record(&AuditEvent::Accept {
+            session_id: "s1".to_string(),
+            agent_id: "A3F8-B2C1".to_string(),
+        });
+        db.record(&AuditEvent::Close {
+            session_id: "s1".to_string(),
+            agent_id: "A3F8-B2C1".to_string(),
+            controller_id: "c1".to_string(),
+            reason: "closed by controller".to_string(),
+        });
+        db.record(&AuditEvent::Reject {
+            session_id: "s2".to_string(),
+            agent_id: "A3F8-B2C1".to_string(),
+            error: ErrorCode::Unauthorized,
+        });
+
+        let sessions = db.sessions(10).unwrap();
+        assert_eq!(sessions.len(), 2);
+        let (s2, s1) = (&sessions[0], &sessions[1]);
+        assert_eq!(s1.target, "127.0.0.1:5432");
+        assert!(s1.accepted_at.is_some());
+        assert_eq!(s1.outcome.as_deref(), Some("closed by controller"));
+        assert!(s2.accepted_at.is_none());
+        assert_eq!(s2.outcome.as_deref(), Some("rejected: Unauthorized"));
+
+        assert_eq!(db.prune_sessions(unix_time_ms() + 1).unwrap(), 2);
+        assert!(db.sessions(10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn access_requests_follow_decisions() {
+        let db = Database::in_memory();
+        let now = unix_time_ms();
+        let request = |access_id: &str, requested_at: u64| AccessRecord {
+            access_id: access_id.to_string(),
+            request_id: "access-1".to_string(),
+            agent_id: "A3F8-B2C1".to_string(),
+            identity: Some("alice".to_string()),
+            remote_host: "127.0.0.1".to_string(),
+            remote_port: 5432,
+            reason: None,
+            requested_at,
+            approved: None,
+            expires_at: None,
+            delivered: false,
+        };
+        db.queue_access(&request("a1", now)).unwrap();
+        db.queue_access(&request("a2", now)).unwrap();
+        db.queue_access(&request("old", now - ACCESS_REQUEST_TTL_MS))
+            .unwrap();
+        assert_eq!(db.pending_access("A3F8-B2C1").unwrap().len(), 2);
+        let old = db.access_request("old").unwrap().unwrap();
+        assert_eq!(old.state(now), AccessState::Expired);
+
+        // Only the agent the request was made to decides it, once.
+        assert!(!db
+            .decide_access("a1", "D9E1-0C44", true, Some(now + 60_000))
+            .unwrap());
+        assert!(db
+            .decide_access("a1", "A3F8-B2C1", true, Some(now + 60_000))
+            .unwrap());
+        assert!(!db.decide_access("a1", "A3F8-B2C1", false, None).unwrap());
+        assert!(db.decide_access("a2", "A3F8-B2C1", false, None).unwrap());
+        assert!(db.pending_access("A3F8-B2C1").unwrap().is_empty());
+
+        let approved = db.access_request("a1").unwrap().unwrap();
+        assert_eq!(approved.state(now), AccessState::Approved);
+        assert_eq!(approved.state(now + 60_000), AccessState::Expired);
+        let denied = db.access_request("a2").unwrap().unwrap();
+        assert_eq!(denied.state(now), AccessState::Denied);
+
+        assert!(!db.access_delivered("a2", "t2").unwrap());
+        assert!(db.access_delivered("a1", "t1").unwrap());
+        assert!(!db.access_delivered("a1", "t3").unwrap());
+
+        // The invitation opens one tunnel, to the requested target only.
+        assert!(!db
+            .redeem_access_invite("t1", "A3F8-B2C1", "127.0.0.1", 22)
+            .unwrap());
+        assert!(!db
+            .redeem_access_invite("t2", "A3F8-B2C1", "127.0.0.1", 5432)
+            .unwrap());
+        assert!(db
+            .redeem_access_invite("t1", "A3F8-B2C1", "127.0.0.1", 5432)
+            .unwrap());
+        assert!(!db
+            .redeem_access_invite("t1", "A3F8-B2C1", "127.0.0.1", 5432)
+            .unwrap());
+
+        assert_eq!(db.prune_access_requests(now).unwrap(), 1);
+        assert!(db.access_request("old").unwrap().is_none());
+    }
+}
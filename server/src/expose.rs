@@ -0,0 +1,292 @@
+//! # Public Port Exposure
+//!
+//! Lets an agent publish a local service on a public TCP port of the relay
+//! itself, so clients without the tunnel app can reach it.
+//!
+//! ## Flow
+//!
+//! 1. The agent sends `Expose { remote_host, remote_port, public_port }`.
+//! 2. The server binds a port from `[expose] port_range`, creates a session
+//!    owned by the agent, and answers `ExposeReady { session_id, public_port }`.
+//! 3. Each TCP connection accepted on that port becomes a data stream: the
+//!    server opens a QUIC stream to the agent with the usual 17-byte prefix
+//!    and relays bytes both ways. The agent dials `remote_host:remote_port`
+//!    exactly as it does for a controller's stream.
+//! 4. `TunnelClose` from the agent, or its disconnect, closes the listener.
+
+use crate::config::ExposeConfig;
+use crate::relay::{self, RelayError, StreamSlot};
+use crate::state::{AppState, TunnelSession};
+use quinn::{RecvStream, SendStream, VarInt};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, field, info, info_span, warn, Instrument, Span};
+use tunnel_protocol::{pack_data_message, ErrorCode, RESET_BUFFER_LIMIT};
+
+/// Binds a public listener on `requested`, or on the first free port of the
+/// configured range when `requested` is 0.
+pub async fn bind(
+    config: &ExposeConfig,
+    requested: u16,
+) -> Result<(TcpListener, u16), (ErrorCode, String)> {
+    let Some([first, last]) = config.port_range else {
+        return Err((
+            ErrorCode::Unauthorized,
+            "Port exposure is disabled on this relay".to_string(),
+        ));
+    };
+
+    if requested != 0 {
+        if !(first..=last).contains(&requested) {
+            return Err((
+                ErrorCode::Unauthorized,
+                format!(
+                    "Port {} is outside the exposable range {}-{}",
+                    requested, first, last
+                ),
+            ));
+        }
+        return TcpListener::bind(SocketAddr::new(config.bind, requested))
+            .await
+            .map(|listener| (listener, requested))
+            .map_err(|e| {
+                (
+                    ErrorCode::InUse,
+                    format!("Port {} is unavailable: {}", requested, e),
+                )
+            });
+    }
+
+    // The OS is the allocator: ports held by other exposures fail to bind.
+    for port in first..=last {
+        if let Ok(listener) = TcpListener::bind(SocketAddr::new(config.bind, port)).await {
+            return Ok((listener, port));
+        }
+    }
+    Err((
+        ErrorCode::LimitExceeded,
+        format!("No free port in {}-{}", first, last),
+    ))
+}
+
+/// Starts relaying connections accepted on `listener` to the agent of
+/// `session` over `agent_conn`.
+pub fn spawn(
+    state: &AppState,
+    session: &TunnelSession,
+    listener: TcpListener,
+    agent_conn: quinn::Connection,
+) {
+    let task = tokio::spawn(
+        accept_loop(state.clone(), session.clone(), listener, agent_conn)
+            .instrument(session.span.clone()),
+    );
+    state
+        .exposures
+        .insert(session.session_id.clone(), task.abort_handle());
+}
+
+/// Closes the public listener or ingress routes of `session_id`, if it has any.
+///
+/// Connections already accepted keep running until either end closes them.
+pub fn stop(state: &AppState, session_id: &str) {
+    if let Some((_, task)) = state.exposures.remove(session_id) {
+        task.abort();
+        info!(session_id, "Public listener closed");
+    }
+    state.http_routes.retain(|_, sid| sid != session_id);
+    state.tls_routes.retain(|_, sid| sid != session_id);
+}
+
+async fn accept_loop(
+    state: AppState,
+    session: TunnelSession,
+    listener: TcpListener,
+    agent_conn: quinn::Connection,
+) {
+    info!("Listening for public connections");
+    let max_streams = state.config.limits.max_streams_per_session;
+    loop {
+        let (tcp, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // Usually file descriptor exhaustion; back off instead of spinning.
+                warn!("Public accept failed: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let Ok(slot) = StreamSlot::acquire_new(&session.streams, max_streams) else {
+            warn!(%peer, max_streams, "Public connection refused: session stream limit reached");
+            continue;
+        };
+        let span = info_span!(
+            "stream",
+            stream_id = %slot.id(),
+            peer = %peer,
+            bytes_from_opener = field::Empty,
+            bytes_to_opener = field::Empty
+        );
+        tokio::spawn(
+            serve(
+                state.clone(),
+                session.clone(),
+                tcp,
+                agent_conn.clone(),
+                slot,
+            )
+            .instrument(span),
+        );
+    }
+}
+
+/// Relays one public TCP connection through a new data stream to the agent.
+async fn serve(
+    state: AppState,
+    session: TunnelSession,
+    tcp: TcpStream,
+    agent_conn: quinn::Connection,
+    slot: StreamSlot,
+) {
+    info!("New public connection");
+    if let Some((q_send, q_recv)) = open_agent_stream(&session, &agent_conn, slot.id()).await {
+        relay_tcp(&state, &session, tcp, q_send, q_recv).await;
+    }
+}
+
+/// Opens a data stream to the agent and writes its routing prefix.
+pub async fn open_agent_stream(
+    session: &TunnelSession,
+    agent_conn: &quinn::Connection,
+    stream_id: &str,
+) -> Option<(SendStream, RecvStream)> {
+    let (mut q_send, q_recv) = match agent_conn.open_bi().await {
+        Ok(streams) => streams,
+        Err(e) => {
+            error!("Failed to open stream to agent: {}", e);
+            return None;
+        }
+    };
+    let prefix = pack_data_message(id_bytes(&session.session_id), id_bytes(stream_id), &[]);
+    if q_send.write_all(&prefix).await.is_err() {
+        error!("Failed to write prefix to agent stream");
+        return None;
+    }
+    Some((q_send, q_recv))
+}
+
+/// Copies bytes both ways between a public TCP connection and an agent
+/// data stream, against the session's buffer budget, and records the
+/// totals on the current stream span.
+pub async fn relay_tcp(
+    state: &AppState,
+    session: &TunnelSession,
+    tcp: TcpStream,
+    mut q_send: SendStream,
+    mut q_recv: RecvStream,
+) {
+    let limits = &state.config.limits;
+    let chunk_size = limits.stream_buffer_bytes;
+    let stall_timeout = Duration::from_secs(limits.stall_timeout_secs);
+    let budget = &session.buffers;
+    let (mut tcp_read, mut tcp_write) = tcp.into_split();
+
+    let from_public = async {
+        let result = relay::copy_with_budget(
+            &mut tcp_read,
+            &mut q_send,
+            budget,
+            session.traffic.counter(true),
+            chunk_size,
+            stall_timeout,
+        )
+        .await;
+        match &result {
+            Err(RelayError::BufferLimit) => {
+                let _ = q_send.reset(VarInt::from_u32(RESET_BUFFER_LIMIT));
+            }
+            _ => {
+                let _ = q_send.finish();
+            }
+        }
+        result
+    };
+    let to_public = async {
+        let result = relay::copy_with_budget(
+            &mut q_recv,
+            &mut tcp_write,
+            budget,
+            session.traffic.counter(false),
+            chunk_size,
+            stall_timeout,
+        )
+        .await;
+        if matches!(result, Err(RelayError::BufferLimit)) {
+            let _ = q_recv.stop(VarInt::from_u32(RESET_BUFFER_LIMIT));
+        }
+        let _ = tcp_write.shutdown().await;
+        result
+    };
+    let (from_public, to_public) = tokio::join!(from_public, to_public);
+
+    for (field_name, result) in [
+        ("bytes_from_opener", from_public),
+        ("bytes_to_opener", to_public),
+    ] {
+        match result {
+            Ok(total) => {
+                Span::current().record(field_name, total);
+                info!(direction = field_name, bytes = total, "Proxy finished");
+            }
+            Err(e) => error!(direction = field_name, "Proxy error: {}", e),
+        }
+    }
+}
+
+/// Pads or truncates an ID to the 8 bytes of the data stream prefix.
+fn id_bytes(id: &str) -> [u8; 8] {
+    let mut bytes = [0u8; 8];
+    let len = id.len().min(8);
+    bytes[..len].copy_from_slice(&id.as_bytes()[..len]);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[tokio::test]
+    async fn binds_only_within_range() {
+        let mut config = ExposeConfig {
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port_range: None,
+        };
+        assert!(matches!(
+            bind(&config, 0).await,
+            Err((ErrorCode::Unauthorized, _))
+        ));
+
+        // Borrow a free port from the OS, then offer only it.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        config.port_range = Some([port, port]);
+        let (held, bound) = bind(&config, 0).await.unwrap();
+        assert_eq!(bound, port);
+        assert!(matches!(
+            bind(&config, 0).await,
+            Err((ErrorCode::LimitExceeded, _))
+        ));
+        assert!(matches!(
+            bind(&config, port.wrapping_add(1)).await,
+            Err((ErrorCode::Unauthorized, _))
+        ));
+        drop(held);
+    }
+}
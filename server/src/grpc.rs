@@ -0,0 +1,231 @@
+//! # gRPC Control API
+//!
+//! Serves the `tunnel.v1.TunnelControl` service from `proto/tunnel.proto`
+//! on `[api] grpc_bind` for infrastructure tooling that wants typed calls
+//! and streaming updates instead of polling the REST API. It lists agents
+//! and sessions with the same data as `/api/agents` and `/api/sessions`,
+//! closes sessions, and streams audit events as they are recorded.
+//! Authorization mirrors the REST API: calls carry a bearer token in the
+//! `authorization` metadata, closing sessions and watching events need the
+//! admin role, and the `[ip_filter]` rules apply.
+
+// `tonic::Status` is large, but it is what every call must return.
+#![allow(clippy::result_large_err)]
+
+use crate::api::{self, AgentListItem, AgentQuery, SessionItem};
+use crate::auth::{AuthFailure, Principal};
+use crate::handlers;
+use crate::state::AppState;
+use std::pin::Pin;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+use tracing::{error, info, warn};
+
+/// Types and server stub generated from `proto/tunnel.proto`.
+pub mod proto {
+    tonic::include_proto!("tunnel.v1");
+}
+
+use proto::tunnel_control_server::{TunnelControl, TunnelControlServer};
+
+/// Serves the control API on the configured address until the process exits.
+pub async fn run(state: AppState) {
+    let Some(addr) = state.config.api.grpc_bind else {
+        return;
+    };
+    info!("🚇 gRPC control API listening on TCP {}", addr);
+    let result = tonic::transport::Server::builder()
+        .add_service(TunnelControlServer::new(Control { state }))
+        .serve(addr)
+        .await;
+    if let Err(e) = result {
+        error!("gRPC control API on {} failed: {}", addr, e);
+    }
+}
+
+struct Control {
+    state: AppState,
+}
+
+impl Control {
+    /// Applies the `[ip_filter]` rules and, unless there are no tokens or
+    /// the API is public, requires a known bearer token, like the REST
+    /// middleware.
+    fn authorize<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        self.filter_ip(request)?;
+        let state = &self.state;
+        if state.config.api.public || (state.config.tokens.is_empty() && !state.db.has_tokens()) {
+            return Ok(());
+        }
+        self.principal(request).map(drop)
+    }
+
+    /// Applies the `[ip_filter]` rules and resolves the bearer token to a
+    /// principal holding the admin role.
+    fn require_admin<T>(&self, request: &Request<T>) -> Result<Principal, Status> {
+        self.filter_ip(request)?;
+        match self.principal(request)? {
+            p if p.admin => Ok(p),
+            _ => Err(Status::permission_denied("The admin role is required")),
+        }
+    }
+
+    fn filter_ip<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let Some(peer) = request.remote_addr() else {
+            return Ok(());
+        };
+        let filter = &self.state.config.ip_filter;
+        let headers = request.metadata().clone().into_headers();
+        let client = filter.client_ip(peer.ip(), &headers);
+        if !filter.permits(client) {
+            warn!(%client, %peer, "Refusing gRPC call: address not allowed");
+            return Err(Status::permission_denied("Address not allowed"));
+        }
+        Ok(())
+    }
+
+    fn principal<T>(&self, request: &Request<T>) -> Result<Principal, Status> {
+        let headers = request.metadata().clone().into_headers();
+        let unauthenticated = || Status::unauthenticated("A valid bearer token is required");
+        let token = api::bearer_token(&headers).ok_or_else(unauthenticated)?;
+        self.state.authenticate(token).ok_or_else(|| {
+            if let Some(peer) = request.remote_addr() {
+                let client = self.state.config.ip_filter.client_ip(peer.ip(), &headers);
+                self.state
+                    .auth_failures
+                    .record(AuthFailure::ApiToken, client);
+            }
+            unauthenticated()
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl TunnelControl for Control {
+    async fn list_agents(
+        &self,
+        request: Request<proto::ListAgentsRequest>,
+    ) -> Result<Response<proto::ListAgentsResponse>, Status> {
+        self.authorize(&request)?;
+        let request = request.into_inner();
+        let query = AgentQuery {
+            tag: request.tag,
+            q: request.q,
+            offline: request.offline,
+            ..AgentQuery::default()
+        };
+        let mut agents = api::agent_items(&self.state, &query).await.map_err(|e| {
+            error!("Database error: {}", e);
+            Status::internal("Database error")
+        })?;
+        agents.sort_by(|a, b| a.agent_id.cmp(&b.agent_id));
+        Ok(Response::new(proto::ListAgentsResponse {
+            agents: agents.into_iter().map(agent).collect(),
+        }))
+    }
+
+    async fn list_sessions(
+        &self,
+        request: Request<proto::ListSessionsRequest>,
+    ) -> Result<Response<proto::ListSessionsResponse>, Status> {
+        self.authorize(&request)?;
+        Ok(Response::new(proto::ListSessionsResponse {
+            sessions: api::session_items(&self.state)
+                .into_iter()
+                .map(session)
+                .collect(),
+        }))
+    }
+
+    async fn close_session(
+        &self,
+        request: Request<proto::CloseSessionRequest>,
+    ) -> Result<Response<proto::CloseSessionResponse>, Status> {
+        let admin = self.require_admin(&request)?;
+        let request = request.into_inner();
+        let reason = match request.reason.trim() {
+            "" => format!("closed by {}", admin.name),
+            reason => format!("closed by {}: {}", admin.name, reason),
+        };
+        if !handlers::terminate_session(&self.state, &request.session_id, reason) {
+            return Err(Status::not_found(format!(
+                "No session '{}'",
+                request.session_id
+            )));
+        }
+        info!(session_id = %request.session_id, "Session closed by {} over gRPC", admin.name);
+        Ok(Response::new(proto::CloseSessionResponse {}))
+    }
+
+    type WatchEventsStream = Pin<Box<dyn Stream<Item = Result<proto::Event, Status>> + Send>>;
+
+    async fn watch_events(
+        &self,
+        request: Request<proto::WatchEventsRequest>,
+    ) -> Result<Response<Self::WatchEventsStream>, Status> {
+        self.require_admin(&request)?;
+        let kinds = request.into_inner().events;
+        let events = BroadcastStream::new(self.state.audit.subscribe()).filter_map(move |line| {
+            let line = match line {
+                Ok(line) => line,
+                Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                    warn!(missed, "gRPC event watcher fell behind");
+                    return None;
+                }
+            };
+            let entry: serde_json::Value = serde_json::from_str(&line).ok()?;
+            let event = entry["event"].as_str()?.to_string();
+            if !kinds.is_empty() && !kinds.contains(&event) {
+                return None;
+            }
+            Some(Ok(proto::Event {
+                ts: entry["ts"].as_u64().unwrap_or_default(),
+                event,
+                json: line,
+            }))
+        });
+        Ok(Response::new(Box::pin(events)))
+    }
+}
+
+fn agent(item: AgentListItem) -> proto::Agent {
+    proto::Agent {
+        agent_id: item.agent_id,
+        online: item.online,
+        last_seen: item.last_seen,
+        name: item.name,
+        tags: item.tags,
+        version: item.version,
+        services: item
+            .services
+            .into_iter()
+            .map(|s| proto::Service {
+                name: s.name,
+                host: s.host,
+                port: s.port.into(),
+                note: s.note,
+            })
+            .collect(),
+        connected_at: item.connected_at,
+        last_heartbeat: item.last_heartbeat,
+        active_tunnels: item.active_tunnels.map(|n| n as u64),
+        bytes_relayed: item.bytes_relayed,
+    }
+}
+
+fn session(item: SessionItem) -> proto::Session {
+    proto::Session {
+        session_id: item.session_id,
+        agent_id: item.agent_id,
+        controller: item.controller,
+        target: item.target,
+        accepted: item.accepted,
+        streams: item.streams as u64,
+        age_secs: item.age_secs,
+        ttl_secs: item.ttl_secs,
+        reverse_socks: item.reverse_socks,
+        bytes_to_agent: item.bytes_to_agent,
+        bytes_from_agent: item.bytes_from_agent,
+    }
+}
@@ -11,13 +11,40 @@
 //! 4. Clean up active tunnels and notify peers upon disconnection.
 //! 5. Handle incoming QUIC streams for data relay natively.
 
-use crate::state::{generate_agent_id, AgentInfo, AppState, ConnectionInfo, TunnelSession};
+use crate::audit::AuditEvent;
+use crate::auth::{AuthFailure, Principal, Scope};
+use crate::bans::{Ban, BanTarget};
+use crate::relay::{self, BufferBudget, SessionTraffic, SlotError, StreamSlot};
+use crate::state::{
+    generate_agent_id, AgentInfo, AgentUsage, AppState, ClientTx, ConnectionInfo, Exposure, Invite,
+    PendingProbe, Registration, ResolveError, Role, TunnelSession,
+};
+use crate::{access, acl, auth, expose, ingress, observe};
+use dashmap::mapref::entry::Entry;
+use quinn::{RecvStream, SendStream, VarInt};
 use std::sync::Arc;
-use tokio::sync::mpsc;
-use tracing::{error, info};
-use tunnel_protocol::ControlMessage;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
+use tunnel_protocol::{
+    describe_target, find_service, host_port, key_agent_id, normalize_host, register_challenge,
+    tags_match, unix_time_ms, verify_register_proof, verify_register_signature, version_at_least,
+    AgentSummary, ControlMessage, ErrorCode, TrafficClass, CLOSE_BANNED, CLOSE_REPLACED,
+    CONTROL_STREAM_PRIORITY, MAX_CONTROL_FRAME, RESET_DUPLICATE_STREAM, RESET_STREAM_LIMIT,
+};
 use uuid::Uuid;
 
+/// Invalid control messages tolerated before a connection is dropped.
+const MAX_VIOLATIONS: u32 = 3;
+
+/// How long a probe waits for the agent before it is forgotten.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Probes one controller may have waiting at once.
+const MAX_PROBES_PER_CONTROLLER: usize = 16;
+
+/// Unredeemed invitations one agent may hold at once.
+const MAX_INVITES_PER_AGENT: usize = 16;
+
 // ─── Connection Lifecycle ───────────────────────────────────────
 
 /// Upgrades an incoming QUIC connection and enters the main event loop.
@@ -25,6 +52,17 @@ use uuid::Uuid;
 /// This function spans a new concurrency task for each client.
 pub async fn handle_connection(connection: quinn::Connection, state: AppState) {
     let conn_id = Uuid::new_v4().to_string();
+    let span = info_span!(
+        "connection",
+        conn_id = %conn_id,
+        remote = %connection.remote_address()
+    );
+    serve_connection(connection, state, conn_id)
+        .instrument(span)
+        .await;
+}
+
+async fn serve_connection(connection: quinn::Connection, state: AppState, conn_id: String) {
     info!("New QUIC connection: {}", conn_id);
 
     // Accept the first bi-directional stream as the control stream.
@@ -35,13 +73,22 @@ pub async fn handle_connection(connection: quinn::Connection, state: AppState) {
             return;
         }
     };
+    let _ = send.set_priority(CONTROL_STREAM_PRIORITY);
 
-    let (tx, mut rx) = mpsc::unbounded_channel::<ControlMessage>();
+    let (tx, mut rx) = ClientTx::new(
+        connection.clone(),
+        state.config.limits.outbound_queue_len,
+        Duration::from_secs(state.config.limits.slow_consumer_timeout_secs),
+    );
     state.connections.insert(
         conn_id.clone(),
         ConnectionInfo {
             tx: tx.clone(),
             conn: connection.clone(),
+            principal: None,
+            role: None,
+            pending_register: None,
+            peer: None,
         },
     );
 
@@ -50,57 +97,150 @@ pub async fn handle_connection(connection: quinn::Connection, state: AppState) {
     // The outbound task responsible for sending control messages to the client.
     // Control messages are framed with a 4-byte length prefix to ensure reliable delivery
     // over the QUIC control stream. Format: `[4-byte len][tag][bincode_bytes]`.
-    let outbound_task = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            match msg.serialize() {
-                Ok(bytes) => {
-                    let len = (bytes.len() as u32).to_le_bytes();
-                    if send.write_all(&len).await.is_err() {
-                        break;
+    let outbound_task = tokio::spawn(
+        async move {
+            while let Some(msg) = rx.recv().await {
+                match msg.serialize() {
+                    Ok(bytes) => {
+                        let len = (bytes.len() as u32).to_le_bytes();
+                        if send.write_all(&len).await.is_err() {
+                            break;
+                        }
+                        if send.write_all(&bytes).await.is_err() {
+                            break;
+                        }
                     }
-                    if send.write_all(&bytes).await.is_err() {
-                        break;
+                    Err(e) => {
+                        error!("Serialize error: {}", e);
                     }
                 }
-                Err(e) => {
-                    error!("Serialize error: {}", e);
-                }
             }
         }
-    });
+        .in_current_span(),
+    );
 
     let conn_id_clone = conn_id.clone();
     let cx = connection.clone();
     let state_c = state.clone();
-    let inbound_streams_task = tokio::spawn(async move {
-        while let Ok((mut q_send, mut q_recv)) = cx.accept_bi().await {
-            // Read the 17-byte Data routing prefix
-            // [0x0A, 8-byte session_id, 8-byte stream_id]
-            let mut prefix = [0u8; 17];
-            if q_recv.read_exact(&mut prefix).await.is_err() {
-                continue;
-            }
-            if prefix[0] != 0x0A {
-                continue; // Not a Data stream
-            }
-
-            let sess_bytes = &prefix[1..9];
-            let sess_str =
-                String::from_utf8(sess_bytes.iter().filter(|&&c| c != 0).cloned().collect())
-                    .unwrap_or_default();
-            let strm_bytes = &prefix[9..17];
-            let strm_str =
-                String::from_utf8(strm_bytes.iter().filter(|&&c| c != 0).cloned().collect())
-                    .unwrap_or_default();
-
-            info!(
-                "New data stream for session {} / stream {}",
-                sess_str, strm_str
-            );
+    let inbound_streams_task = tokio::spawn(
+        async move {
+            while let Ok((q_send, mut q_recv)) = cx.accept_bi().await {
+                // Read the 17-byte Data routing prefix
+                // [0x0A, 8-byte session_id, 8-byte stream_id]
+                let mut prefix = [0u8; 17];
+                if q_recv.read_exact(&mut prefix).await.is_err() {
+                    continue;
+                }
+                if prefix[0] != 0x0A {
+                    continue; // Not a Data stream
+                }
 
-            if let Some(session) = state_c.sessions.get(&sess_str) {
+                let sess_bytes = &prefix[1..9];
+                let sess_str =
+                    String::from_utf8(sess_bytes.iter().filter(|&&c| c != 0).cloned().collect())
+                        .unwrap_or_default();
+                let strm_bytes = &prefix[9..17];
+                let strm_str =
+                    String::from_utf8(strm_bytes.iter().filter(|&&c| c != 0).cloned().collect())
+                        .unwrap_or_default();
+
+                // Streams of sessions forwarded to another relay go over its link.
+                let (mut q_send, mut q_recv) = match &state_c.cluster {
+                    Some(cluster) => match cluster.forward_stream(
+                        &state_c,
+                        &conn_id_clone,
+                        &sess_str,
+                        prefix,
+                        q_send,
+                        q_recv,
+                    ) {
+                        Ok(()) => continue,
+                        Err(streams) => streams,
+                    },
+                    None => (q_send, q_recv),
+                };
+
+                let session = state_c.sessions.get(&sess_str).map(|s| s.clone());
+                let Some(session) = session else {
+                    warn!(session_id = %sess_str, stream_id = %strm_str, "Data stream for unknown session");
+                    continue;
+                };
+                let stream_span = info_span!(
+                    parent: &session.span,
+                    "stream",
+                    stream_id = %strm_str,
+                    bytes_from_opener = field::Empty,
+                    bytes_to_opener = field::Empty
+                );
+                info!(parent: &stream_span, "New data stream");
+                // The opener's side comes from its connection, never from
+                // the prefix: a stranger who learned the session ID gets nothing.
+                let Some(opener) = session_side(&state_c, &session, &conn_id_clone) else {
+                    warn!(parent: &stream_span, "Data stream refused: opener is not part of the session");
+                    continue;
+                };
+                // Only the server opens streams for an exposed session.
+                if session.exposure.is_some() {
+                    warn!(parent: &stream_span, "Data stream refused: session is published by the relay");
+                    continue;
+                }
+                // And only the agent opens them for a reverse SOCKS session.
+                if session.reverse_socks && opener == Role::Controller {
+                    warn!(parent: &stream_span, "Data stream refused: reverse SOCKS streams come from the agent");
+                    continue;
+                }
+
+                let max_streams = session.max_streams;
+                let slot = match StreamSlot::acquire(&session.streams, &strm_str, max_streams) {
+                    Ok(slot) => slot,
+                    Err(e) => {
+                        // A stream over the limit is refused as that stream's
+                        // failure, so the opener can tell it from other errors.
+                        let (reset, refusal) = match e {
+                            SlotError::Limit => {
+                                warn!(parent: &stream_span, max_streams, "Stream refused: session stream limit reached");
+                                (
+                                    RESET_STREAM_LIMIT,
+                                    ControlMessage::StreamOpenFailed {
+                                        session_id: sess_str.clone(),
+                                        stream_id: strm_str.clone(),
+                                        code: ErrorCode::LimitExceeded,
+                                        message: format!(
+                                            "Session {} reached its limit of {} streams",
+                                            sess_str, max_streams
+                                        ),
+                                    },
+                                )
+                            }
+                            SlotError::DuplicateId => {
+                                warn!(parent: &stream_span, "Stream refused: stream ID already open in session");
+                                (
+                                    RESET_DUPLICATE_STREAM,
+                                    ControlMessage::Error {
+                                        code: ErrorCode::InvalidMessage,
+                                        message: format!(
+                                            "Stream {} is already open in session {}",
+                                            strm_str, sess_str
+                                        ),
+                                    },
+                                )
+                            }
+                        };
+                        let reset = quinn::VarInt::from_u32(reset);
+                        let _ = q_recv.stop(reset);
+                        let _ = q_send.reset(reset);
+                        if let Some(c) = state_c.connections.get(&conn_id_clone) {
+                            let _ = c.tx.send(refusal);
+                        }
+                        continue;
+                    }
+                };
+                let slot = Arc::new(slot);
+                let buffers = session.buffers.clone();
+                let traffic = session.traffic.clone();
+                let from_controller = opener == Role::Controller;
                 // Determine target connection ID
-                let target_conn_id = if conn_id_clone == session.controller_id {
+                let target_conn_id = if from_controller {
                     let mut agent_conn_id = None;
                     if let Some(agent) = state_c.agents.get(&session.agent_id) {
                         agent_conn_id = Some(agent.conn_id.clone());
@@ -110,92 +250,57 @@ pub async fn handle_connection(connection: quinn::Connection, state: AppState) {
                     Some(session.controller_id.clone())
                 };
 
-                tracing::info!(
-                    "Finding target_id for session {} -> target {:?}",
-                    sess_str,
-                    target_conn_id
-                );
+                debug!(parent: &stream_span, target = ?target_conn_id, "Routing data stream");
                 if let Some(target_id) = target_conn_id {
                     if let Some(target_info) = state_c.connections.get(&target_id) {
                         // Open stream to target and forward
                         match target_info.conn.open_bi().await {
-                            Ok((mut t_send, mut t_recv)) => {
+                            Ok((mut t_send, t_recv)) => {
+                                // Both directions are sent at the session's priority
+                                let priority = session.traffic_class.stream_priority();
+                                let _ = t_send.set_priority(priority);
+                                let _ = q_send.set_priority(priority);
                                 // Forward the prefix
                                 if t_send.write_all(&prefix).await.is_ok() {
-                                    let sid_clone = sess_str.clone();
-                                    let target_id_c = target_id.clone();
-                                    tokio::spawn(async move {
-                                        tracing::info!(
-                                            "Starting proxy {} -> {}",
-                                            sid_clone,
-                                            target_id_c
-                                        );
-                                        match tokio::io::copy(&mut q_recv, &mut t_send).await {
-                                            Ok(total) => {
-                                                tracing::info!(
-                                                    "Proxy {} -> {} finished, {} bytes",
-                                                    sid_clone,
-                                                    target_id_c,
-                                                    total
-                                                );
-                                            }
-                                            Err(e) => {
-                                                tracing::error!(
-                                                    "Proxy Error {} -> {}: {}",
-                                                    sid_clone,
-                                                    target_id_c,
-                                                    e
-                                                );
-                                            }
-                                        }
-                                        let _ = t_send.finish();
-                                    });
-                                    let sid_clone2 = sess_str.clone();
-                                    let target_id_clone = target_id.clone();
-                                    tokio::spawn(async move {
-                                        tracing::info!(
-                                            "Starting proxy {} -> {}",
-                                            target_id_clone,
-                                            sid_clone2
-                                        );
-                                        match tokio::io::copy(&mut t_recv, &mut q_send).await {
-                                            Ok(total) => {
-                                                tracing::info!(
-                                                    "Proxy {} -> {} finished, {} bytes",
-                                                    target_id_clone,
-                                                    sid_clone2,
-                                                    total
-                                                );
-                                            }
-                                            Err(e) => {
-                                                tracing::error!(
-                                                    "Proxy Error {} -> {}: {}",
-                                                    target_id_clone,
-                                                    sid_clone2,
-                                                    e
-                                                );
-                                            }
-                                        }
-                                        let _ = q_send.finish();
-                                    });
-                                } else {
-                                    tracing::error!(
-                                        "Failed to write prefix to target stream for session {}",
-                                        sess_str
+                                    spawn_proxy(
+                                        &state_c,
+                                        q_recv,
+                                        t_send,
+                                        buffers.clone(),
+                                        traffic.clone(),
+                                        from_controller,
+                                        slot.clone(),
+                                        stream_span.clone(),
+                                        "bytes_from_opener",
+                                    );
+                                    spawn_proxy(
+                                        &state_c,
+                                        t_recv,
+                                        q_send,
+                                        buffers,
+                                        traffic,
+                                        !from_controller,
+                                        slot,
+                                        stream_span,
+                                        "bytes_to_opener",
                                     );
+                                } else {
+                                    error!(parent: &stream_span, "Failed to write prefix to target stream");
                                 }
                             }
                             Err(e) => {
-                                error!("Failed to open stream to target {}: {}", target_id, e);
+                                error!(parent: &stream_span, target = %target_id, "Failed to open stream to target: {}", e);
                             }
                         }
                     }
                 }
             }
         }
-    });
+        .in_current_span(),
+    );
 
     // Inbound control loop reading framed messages
+    let mut violations = 0u32;
     loop {
         let mut len_buf = [0u8; 4];
         if recv.read_exact(&mut len_buf).await.is_err() {
@@ -203,9 +308,9 @@ pub async fn handle_connection(connection: quinn::Connection, state: AppState) {
         }
         let len = u32::from_le_bytes(len_buf) as usize;
 
-        // Prevent huge allocations
-        if len > 1024 * 1024 {
-            error!("Message too large: {}", len);
+        // Prevent huge allocations; an oversized frame cannot be skipped safely.
+        if len > MAX_CONTROL_FRAME {
+            warn!(len, "Dropping connection: control frame exceeds limit");
             break;
         }
 
@@ -214,121 +319,1605 @@ pub async fn handle_connection(connection: quinn::Connection, state: AppState) {
             break;
         }
 
-        match ControlMessage::deserialize(&buf) {
+        let parsed = ControlMessage::deserialize(&buf).and_then(|msg| msg.validate().map(|()| msg));
+        match parsed {
             Ok(msg) => {
-                handle_message(&state, &conn_id, &tx, &agent_id, msg).await;
+                let span = message_span(&state, &msg);
+                handle_message(&state, &conn_id, &tx, &agent_id, msg)
+                    .instrument(span)
+                    .await;
             }
             Err(e) => {
-                error!("Deserialize error: {}", e);
-                break;
+                violations += 1;
+                warn!(violations, max = MAX_VIOLATIONS, "Invalid message: {}", e);
+                let _ = tx.send(ControlMessage::Error {
+                    code: ErrorCode::InvalidMessage,
+                    message: format!("Invalid message: {}", e),
+                });
+                if violations >= MAX_VIOLATIONS {
+                    warn!("Dropping connection: too many invalid messages");
+                    break;
+                }
             }
         }
     }
 
-    info!("Disconnecting: {}", conn_id);
+    info!("Disconnecting");
     outbound_task.abort();
     inbound_streams_task.abort();
-    state.connections.remove(&conn_id);
+    let role = state.connections.remove(&conn_id).and_then(|(_, c)| c.role);
+    observe::forget_connection(&state, &conn_id);
+    access::forget_connection(&state, &conn_id);
+
+    if let Some(cluster) = &state.cluster {
+        cluster.forget_controller(&conn_id);
+    }
 
-    let aid = agent_id.lock().await;
-    if let Some(ref aid) = *aid {
-        info!("Agent {} disconnected", aid);
-        state.agents.remove(aid);
+    let aid = agent_id.lock().await.clone();
+    let owner = aid
+        .as_ref()
+        .is_some_and(|aid| remove_agent(&state, &conn_id, aid));
+    if let (true, Some(aid)) = (owner, &aid) {
+        info!(agent_id = %aid, "Agent disconnected");
+    }
 
-        let sessions_to_remove: Vec<String> = state
-            .sessions
-            .iter()
-            .filter(|s| s.agent_id == *aid || s.controller_id == conn_id)
-            .map(|s| s.session_id.clone())
-            .collect();
+    let sessions_to_remove: Vec<(String, String, bool)> = state
+        .sessions
+        .iter()
+        .filter(|s| (owner && aid.as_ref() == Some(&s.agent_id)) || s.controller_id == conn_id)
+        .map(|s| {
+            // Tunnels this connection opened as a controller to another
+            // agent are still open on that agent's side.
+            let opened = s.controller_id == conn_id && s.exposure.is_none();
+            (s.session_id.clone(), s.agent_id.clone(), opened)
+        })
+        .collect();
+    if role == Some(Role::Controller) {
+        info!(
+            sessions = sessions_to_remove.len(),
+            "Controller disconnected"
+        );
+    }
 
-        for sid in sessions_to_remove {
-            state.sessions.remove(&sid);
+    for (sid, target_agent, opened) in sessions_to_remove {
+        if opened {
+            if let Some(a) = state.agents.get(&target_agent) {
+                let _ = a.tx.send(ControlMessage::TunnelClose {
+                    session_id: sid.clone(),
+                });
+            }
         }
+        close_session(&state, &sid, format!("{} disconnected", conn_id));
+    }
+}
+
+/// Drops the registration of `agent_id` if connection `conn_id` still
+/// holds it; a client proving the agent's key may have taken the ID over.
+fn remove_agent(state: &AppState, conn_id: &str, agent_id: &str) -> bool {
+    if state
+        .agents
+        .remove_if(agent_id, |_, a| a.conn_id == conn_id)
+        .is_none()
+    {
+        return false;
     }
+    state.db.agent_seen(agent_id);
+    if let Some(cluster) = &state.cluster {
+        cluster.announce(agent_id, None);
+    }
+    true
 }
 
-fn relay_message(state: &AppState, session: &TunnelSession, msg: ControlMessage, from_role: &str) {
-    match from_role {
-        "agent" => {
-            if let Some(c) = state.connections.get(&session.controller_id) {
-                let _ = c.tx.send(msg);
+/// Removes a session whose agent or controller went away.
+fn close_session(state: &AppState, session_id: &str, reason: String) {
+    expose::stop(state, session_id);
+    if let Some((_, session)) = state.sessions.remove(session_id) {
+        state.session_ended(&session);
+        state.record(AuditEvent::Close {
+            session_id: session_id.to_string(),
+            agent_id: session.agent_id,
+            controller_id: session.controller_id,
+            reason,
+        });
+    }
+    observe::end_session(state, session_id, "Tunnel closed");
+}
+
+/// Spawns one direction of a data stream relay and logs how it ended.
+/// `to_agent` picks the session traffic counter the relayed bytes go to.
+#[allow(clippy::too_many_arguments)]
+fn spawn_proxy(
+    state: &AppState,
+    recv: RecvStream,
+    send: SendStream,
+    buffers: Arc<BufferBudget>,
+    traffic: Arc<SessionTraffic>,
+    to_agent: bool,
+    slot: Arc<StreamSlot>,
+    span: Span,
+    bytes_field: &'static str,
+) {
+    let limits = state.config.limits.clone();
+    let task_span = span.clone();
+    tokio::spawn(
+        async move {
+            // Hold the stream slot until this direction finishes.
+            let _slot = slot;
+            debug!(direction = bytes_field, "Starting proxy");
+            match relay::relay_stream(
+                recv,
+                send,
+                &buffers,
+                traffic.counter(to_agent),
+                limits.stream_buffer_bytes,
+                Duration::from_secs(limits.stall_timeout_secs),
+            )
+            .await
+            {
+                Ok(total) => {
+                    span.record(bytes_field, total);
+                    info!(direction = bytes_field, bytes = total, "Proxy finished")
+                }
+                Err(e) => error!(direction = bytes_field, "Proxy error: {}", e),
             }
         }
-        "controller" => {
-            if let Some(a) = state.agents.get(&session.agent_id) {
-                let _ = a.tx.send(msg);
+        .instrument(task_span),
+    );
+}
+
+/// Checks that `conn_id` may publish a service and returns its agent ID and
+/// connection.
+fn exposing_agent(
+    state: &AppState,
+    conn_id: &str,
+    agent_id: Option<String>,
+) -> Result<(String, quinn::Connection), (ErrorCode, String)> {
+    let agent_id = agent_id.ok_or((
+        ErrorCode::Unauthorized,
+        "Register as an agent before exposing a service".to_string(),
+    ))?;
+    let conn = state
+        .connections
+        .get(conn_id)
+        .map(|c| c.conn.clone())
+        .ok_or((ErrorCode::Internal, "Connection is closing".to_string()))?;
+
+    let max_tunnels = state.config.limits.max_tunnels_per_agent;
+    if state.tunnel_count(&agent_id) >= max_tunnels {
+        return Err((
+            ErrorCode::LimitExceeded,
+            format!("Agent reached its limit of {} tunnels", max_tunnels),
+        ));
+    }
+    Ok((agent_id, conn))
+}
+
+/// Identifiers and local target of a session an agent publishes.
+struct ExposedTarget {
+    session_id: String,
+    request_id: String,
+    remote_host: String,
+    remote_port: u16,
+}
+
+/// Registers the agent-owned session behind an `Expose`, `ExposeHttp` or
+/// `ExposeTls`.
+fn exposed_session(
+    state: &AppState,
+    conn_id: &str,
+    agent_id: String,
+    target: ExposedTarget,
+    exposure: Exposure,
+) -> TunnelSession {
+    let span = info_span!(
+        "session",
+        session_id = %target.session_id,
+        agent_id = %agent_id,
+        target = %host_port(&target.remote_host, target.remote_port),
+        public = %exposure,
+        bytes = field::Empty
+    );
+    let shaper = state.agents.get(&agent_id).and_then(|a| a.shaper.clone());
+    let session = TunnelSession {
+        session_id: target.session_id,
+        agent_id,
+        controller_id: conn_id.to_string(),
+        request_id: target.request_id,
+        remote_host: target.remote_host,
+        remote_port: target.remote_port,
+        remote_socket: None,
+        extra_ports: Vec::new(),
+        ttl_secs: None,
+        reverse_socks: false,
+        traffic_class: TrafficClass::default(),
+        buffers: Arc::new(
+            BufferBudget::new(state.config.limits.session_buffer_bytes).shaped(shaper),
+        ),
+        streams: Arc::default(),
+        max_streams: state.config.limits.max_streams_per_session,
+        traffic: Arc::default(),
+        created_at: Instant::now(),
+        accepted: true,
+        span,
+        exposure: Some(exposure),
+    };
+    state
+        .sessions
+        .insert(session.session_id.clone(), session.clone());
+    session
+}
+
+/// Claims the ingress hostname of an `ExposeHttp` or `ExposeTls` for a new
+/// agent-owned session and answers with the matching ready message.
+fn expose_hostname(
+    state: &AppState,
+    conn_id: &str,
+    tx: &ClientTx,
+    aid: Option<String>,
+    target: ExposedTarget,
+    exposure: Exposure,
+) {
+    let (hostname, tls, routes) = match &exposure {
+        Exposure::Http(hostname) => (hostname.clone(), false, &state.http_routes),
+        Exposure::Tls(hostname) => (hostname.clone(), true, &state.tls_routes),
+        Exposure::Port(_) => return,
+    };
+    let audit = |session_id: Option<String>, error| {
+        state.record(AuditEvent::Expose {
+            conn_id: conn_id.to_string(),
+            identity: state.identity(conn_id),
+            agent_id: aid.clone(),
+            remote_host: target.remote_host.clone(),
+            remote_port: target.remote_port,
+            public_port: None,
+            hostname: Some(hostname.clone()),
+            session_id,
+            error,
+        });
+    };
+    let fail = |code: ErrorCode, message: String| {
+        warn!(?code, "Hostname expose refused: {}", message);
+        audit(None, Some(code));
+        let _ = tx.send(ControlMessage::ConnectFailed {
+            request_id: target.request_id.clone(),
+            code,
+            message,
+        });
+    };
+
+    let aid = match exposing_agent(state, conn_id, aid.clone()).and_then(|(aid, _)| {
+        ingress::check_hostname(&state.config.ingress, &hostname, tls)?;
+        ingress::claim_hostname(state, &hostname, state.identity(conn_id).as_deref())?;
+        Ok(aid)
+    }) {
+        Ok(aid) => aid,
+        Err((code, message)) => {
+            fail(code, message);
+            return;
+        }
+    };
+
+    // Claim the hostname before the session exists so two agents cannot race for it.
+    match routes.entry(hostname.clone()) {
+        Entry::Occupied(_) => {
+            fail(
+                ErrorCode::InUse,
+                format!("Hostname '{}' is already routed", hostname),
+            );
+            return;
+        }
+        Entry::Vacant(route) => {
+            route.insert(target.session_id.clone());
+        }
+    }
+
+    audit(Some(target.session_id.clone()), None);
+    let request_id = target.request_id.clone();
+    let session_id = target.session_id.clone();
+    exposed_session(state, conn_id, aid, target, exposure);
+
+    let _ = tx.send(if tls {
+        ControlMessage::ExposeTlsReady {
+            request_id,
+            session_id,
+            hostname,
+        }
+    } else {
+        ControlMessage::ExposeHttpReady {
+            request_id,
+            session_id,
+            hostname,
+        }
+    });
+}
+
+/// Span for handling `msg`, under the span of the connection that sent it.
+/// A message of a live session follows from the session's span, so its logs
+/// can be tied to the session on both connections.
+fn message_span(state: &AppState, msg: &ControlMessage) -> Span {
+    let span = info_span!("control", tag = msg.tag(), session_id = msg.session_id());
+    if let Some(session) = msg.session_id().and_then(|sid| state.sessions.get(sid)) {
+        span.follows_from(&session.span);
+    }
+    span
+}
+
+/// Cancels `session_id` if its agent has not accepted it within
+/// `tunnel_request_timeout_secs`, so the controller gets a `Timeout`
+/// instead of waiting forever.
+async fn expire_unanswered(state: AppState, session_id: String) {
+    let timeout = Duration::from_secs(state.config.limits.tunnel_request_timeout_secs);
+    tokio::time::sleep(timeout).await;
+    let Some((_, session)) = state.sessions.remove_if(&session_id, |_, s| !s.accepted) else {
+        return;
+    };
+    warn!(
+        timeout_secs = timeout.as_secs(),
+        "Tunnel request unanswered, cancelling"
+    );
+    state.record(AuditEvent::Reject {
+        session_id: session.session_id.clone(),
+        agent_id: session.agent_id.clone(),
+        error: ErrorCode::Timeout,
+    });
+    // The agent may still hold the request, e.g. waiting for its user.
+    if let Some(agent) = state.agents.get(&session.agent_id) {
+        let _ = agent.tx.send(ControlMessage::TunnelClose {
+            session_id: session.session_id.clone(),
+        });
+    }
+    if let Some(c) = state.connections.get(&session.controller_id) {
+        let _ = c.tx.send(ControlMessage::ConnectFailed {
+            request_id: session.request_id,
+            code: ErrorCode::Timeout,
+            message: format!(
+                "Agent '{}' did not answer the tunnel request within {}s",
+                session.agent_id,
+                timeout.as_secs()
+            ),
+        });
+    }
+}
+
+/// Closes `session_id` on both sides once its `ttl_secs` are up.
+async fn expire_session(state: AppState, session_id: String, ttl_secs: u64) {
+    tokio::time::sleep(Duration::from_secs(ttl_secs)).await;
+    if state.sessions.contains_key(&session_id) {
+        info!(ttl_secs, "Tunnel lifetime over, closing");
+        terminate_session(&state, &session_id, format!("expired after {}s", ttl_secs));
+    }
+}
+
+/// Tells both ends of `session_id` the tunnel is closed and drops it.
+/// Returns `false` when there is no such session.
+pub fn terminate_session(state: &AppState, session_id: &str, reason: String) -> bool {
+    let Some(session) = state.sessions.get(session_id).map(|s| s.clone()) else {
+        return false;
+    };
+    let close_msg = ControlMessage::TunnelClose {
+        session_id: session_id.to_string(),
+    };
+    if let Some(c) = state.connections.get(&session.controller_id) {
+        let _ = c.tx.send(close_msg.clone());
+    }
+    if let Some(a) = state.agents.get(&session.agent_id) {
+        let _ = a.tx.send(close_msg);
+    }
+    close_session(state, session_id, reason);
+    true
+}
+
+/// Which side of `session` connection `conn_id` is on, or `None` when it
+/// is neither the session's controller nor its agent's connection.
+fn session_side(state: &AppState, session: &TunnelSession, conn_id: &str) -> Option<Role> {
+    if conn_id == session.controller_id {
+        Some(Role::Controller)
+    } else if state
+        .agents
+        .get(&session.agent_id)
+        .is_some_and(|a| a.conn_id == conn_id)
+    {
+        Some(Role::Agent)
+    } else {
+        None
+    }
+}
+
+/// Looks up `session_id` for a message only `sender` may send, or either
+/// party when `None`. Connections outside the session are refused with
+/// `Unauthorized`; unknown sessions are ignored.
+fn session_for(
+    state: &AppState,
+    tx: &ClientTx,
+    session_id: &str,
+    conn_id: &str,
+    sender: Option<Role>,
+) -> Option<TunnelSession> {
+    let session = state.sessions.get(session_id).map(|s| s.clone())?;
+    // An exposed session's agent is on both ends, so the side is checked
+    // directly rather than through `session_side`.
+    let permitted = match sender {
+        Some(Role::Agent) => state
+            .agents
+            .get(&session.agent_id)
+            .is_some_and(|a| a.conn_id == conn_id),
+        Some(Role::Controller) => session.controller_id == conn_id,
+        None => session_side(state, &session, conn_id).is_some(),
+    };
+    if !permitted {
+        refuse_outsider(tx, session_id);
+        return None;
+    }
+    Some(session)
+}
+
+/// Forwards `msg` to the other side of `session`, waiting for room in its
+/// queue so a slow receiver pushes back on the sender.
+async fn relay_message(state: &AppState, session: &TunnelSession, msg: ControlMessage, from: Role) {
+    // An exposed session has no controller; its agent is on both ends.
+    if session.exposure.is_some() {
+        return;
+    }
+    let peer = match from {
+        Role::Agent => state
+            .connections
+            .get(&session.controller_id)
+            .map(|c| c.tx.clone()),
+        Role::Controller => state.agents.get(&session.agent_id).map(|a| a.tx.clone()),
+    };
+    if let Some(peer) = peer {
+        let _ = peer.send_relayed(msg).await;
+    }
+}
+
+/// Turns a `ConnectService` into the `Connect` for the service's host and
+/// port, or the failure to answer when the agent or the service is unknown.
+/// The caller must pass the scope and ACL checks of a `Connect` to the
+/// agent first, so a caller who may not connect learns nothing about its
+/// services.
+fn service_connect(
+    state: &AppState,
+    conn_id: &str,
+    request_id: &str,
+    target_id: String,
+    service_name: &str,
+    traffic_class: TrafficClass,
+) -> Result<ControlMessage, (ErrorCode, String)> {
+    let controller = state
+        .connections
+        .get(conn_id)
+        .and_then(|c| c.principal.clone());
+    let identity = controller.as_ref().map_or("anonymous", |p| p.name.as_str());
+    if !auth::permits(controller.as_ref(), Scope::Connect) {
+        warn!(
+            identity,
+            "ConnectService refused: token may not open tunnels"
+        );
+        return Err((
+            ErrorCode::Unauthorized,
+            "This token may not open tunnels".to_string(),
+        ));
+    }
+    let agent_id = match state.resolve_agent(&target_id) {
+        Ok(agent_id) => agent_id,
+        Err(ResolveError::NotFound) => {
+            return Err((
+                ErrorCode::AgentNotFound,
+                not_found_message(state, &target_id),
+            ))
+        }
+        Err(ResolveError::Ambiguous(candidates)) => {
+            return Err((
+                ErrorCode::AmbiguousAgent,
+                format!(
+                    "Name '{}' matches several agents: {}",
+                    target_id,
+                    candidates.join(", ")
+                ),
+            ))
+        }
+    };
+    // The agent is checked where `Connect` finds it: here, or on another relay.
+    let (agent, tags) = match state.agents.get(&agent_id) {
+        Some(a) => (a.principal.clone(), a.tags.clone()),
+        None => state
+            .cluster
+            .as_ref()
+            .and_then(|c| {
+                c.agents
+                    .get(&agent_id)
+                    .map(|a| (a.principal(), a.tags.clone()))
+            })
+            .unwrap_or_default(),
+    };
+    if !acl::is_allowed(
+        &state.config.acl,
+        controller.as_ref(),
+        &agent_id,
+        agent.as_ref(),
+        &tags,
+    ) {
+        warn!(identity, agent_id = %agent_id, "ConnectService denied by ACL");
+        return Err((
+            ErrorCode::Unauthorized,
+            format!("Not authorized to connect to agent '{}'", agent_id),
+        ));
+    }
+    let services = state.agent_services(&agent_id).unwrap_or_default();
+    let Some(service) = find_service(&services, service_name) else {
+        warn!(agent_id = %agent_id, service = %service_name, "ConnectService for unknown service");
+        return Err((
+            ErrorCode::InvalidMessage,
+            format!(
+                "Agent '{}' does not advertise a service named '{}'",
+                target_id, service_name
+            ),
+        ));
+    };
+    info!(agent_id = %agent_id, service = %service.name, "Connecting to service");
+    Ok(ControlMessage::Connect {
+        target_id: agent_id,
+        remote_host: service.host.clone(),
+        remote_port: service.port,
+        request_id: request_id.to_string(),
+        remote_socket: None,
+        pairing_token: None,
+        connect_timeout_ms: None,
+        requester: None,
+        extra_ports: Vec::new(),
+        invite: None,
+        ttl_secs: None,
+        reverse_socks: false,
+        traffic_class,
+    })
+}
+
+/// Refuses a session message from a connection that is not part of the
+/// session.
+fn refuse_outsider(tx: &ClientTx, session_id: &str) {
+    warn!(%session_id, "Session message refused: sender is not part of the session");
+    let _ = tx.send(ControlMessage::Error {
+        code: ErrorCode::Unauthorized,
+        message: format!("Not a party to session {}", session_id),
+    });
+}
+
+/// Refuses a `Register` or `RegisterProof`.
+fn deny_register(state: &AppState, conn_id: &str, tx: &ClientTx, message: String) {
+    state.record(AuditEvent::RegisterDenied {
+        conn_id: conn_id.to_string(),
+    });
+    let _ = tx.send(ControlMessage::Error {
+        code: ErrorCode::Unauthorized,
+        message,
+    });
+}
+
+/// Refuses a `Register` or `RegisterController` from a client older than
+/// `min_client_version`. Returns `false` when the client was refused.
+fn check_client_version(
+    state: &AppState,
+    conn_id: &str,
+    tx: &ClientTx,
+    version: Option<&str>,
+) -> bool {
+    let Some(min) = &state.config.min_client_version else {
+        return true;
+    };
+    if version.is_some_and(|v| version_at_least(v, min)) {
+        return true;
+    }
+    warn!(
+        version = version.unwrap_or("-"),
+        min = %min,
+        "Registration rejected: client too old"
+    );
+    state.record(AuditEvent::RegisterOutdated {
+        conn_id: conn_id.to_string(),
+        version: version.map(str::to_string),
+    });
+    let _ = tx.send(ControlMessage::Error {
+        code: ErrorCode::UpgradeRequired,
+        message: format!(
+            "Client version {} is not supported; this server requires {} or newer",
+            version.unwrap_or("unknown"),
+            min
+        ),
+    });
+    false
+}
+
+/// Refuses a `Register` from a banned agent ID or token by closing the
+/// connection with [`CLOSE_BANNED`] and the ban's reason, which tells the
+/// client to stop reconnecting.
+fn refuse_banned(state: &AppState, conn_id: &str, ban: Ban) {
+    warn!(target = %ban.target, reason = %ban.reason, "Registration rejected: banned");
+    if let Some(c) = state.connections.get(conn_id) {
+        c.conn
+            .close(VarInt::from_u32(CLOSE_BANNED), ban.reason.as_bytes());
+    }
+    state.record(AuditEvent::RegisterBanned {
+        conn_id: conn_id.to_string(),
+        target: ban.target,
+    });
+}
+
+/// Resolves the credential of a `Register` or `RegisterController` and
+/// records the identity on the connection. Refuses unknown and banned
+/// tokens, replying to the client, and returns `Err` for them.
+fn identify(
+    state: &AppState,
+    conn_id: &str,
+    tx: &ClientTx,
+    token: Option<String>,
+) -> Result<Option<Principal>, ()> {
+    let principal = match token {
+        Some(token) => match state.authenticate(&token) {
+            Some(p) => Some(p),
+            None => {
+                warn!("Registration rejected: invalid token");
+                state.auth_failed(conn_id, AuthFailure::Token);
+                deny_register(state, conn_id, tx, "Invalid token".to_string());
+                return Err(());
             }
+        },
+        None => None,
+    };
+    if let Some(ban) = principal
+        .as_ref()
+        .and_then(|p| state.bans.find(&BanTarget::Identity(p.name.clone())))
+    {
+        refuse_banned(state, conn_id, ban);
+        return Err(());
+    }
+    if let Some(mut c) = state.connections.get_mut(conn_id) {
+        c.principal = principal.clone();
+    }
+    Ok(principal)
+}
+
+/// Issues the invitation an agent asked for with `CreateInvite`, and
+/// answers with `InviteCreated`.
+#[allow(clippy::too_many_arguments)]
+fn create_invite(
+    state: &AppState,
+    conn_id: &str,
+    tx: &ClientTx,
+    agent_id: Option<String>,
+    request_id: String,
+    remote_host: String,
+    remote_port: u16,
+    ttl_secs: u64,
+) {
+    let reply = |token: Option<String>, expires_at_ms: u64, error: Option<(ErrorCode, String)>| {
+        let (code, message) = error.unzip();
+        let _ = tx.send(ControlMessage::InviteCreated {
+            request_id: request_id.clone(),
+            token,
+            expires_at_ms,
+            code,
+            message,
+        });
+    };
+    let Some(agent_id) = agent_id else {
+        reply(
+            None,
+            0,
+            Some((
+                ErrorCode::Unauthorized,
+                "Only registered agents may create invitations".to_string(),
+            )),
+        );
+        return;
+    };
+
+    let now = unix_time_ms();
+    state.invites.retain(|_, i| i.expires_at_ms > now);
+    let held = state
+        .invites
+        .iter()
+        .filter(|i| i.agent_id == agent_id)
+        .count();
+    if held >= MAX_INVITES_PER_AGENT {
+        reply(
+            None,
+            0,
+            Some((
+                ErrorCode::LimitExceeded,
+                format!(
+                    "At most {} invitations may be open at once",
+                    MAX_INVITES_PER_AGENT
+                ),
+            )),
+        );
+        return;
+    }
+
+    let token = Uuid::new_v4().simple().to_string();
+    let expires_at_ms = now + ttl_secs * 1000;
+    info!(
+        agent_id = %agent_id,
+        remote = %host_port(&remote_host, remote_port),
+        ttl_secs,
+        "Invitation created"
+    );
+    state.record(AuditEvent::Invite {
+        conn_id: conn_id.to_string(),
+        agent_id: agent_id.clone(),
+        remote_host: remote_host.clone(),
+        remote_port,
+        expires_at_ms,
+    });
+    state.invites.insert(
+        token.clone(),
+        Invite {
+            agent_id,
+            remote_host,
+            remote_port,
+            expires_at_ms,
+        },
+    );
+    reply(Some(token), expires_at_ms, None);
+}
+
+/// Consumes the invitation `token` if it is unexpired and was issued by
+/// `agent_id`, or in answer to an access request to it, for exactly this
+/// target.
+fn redeem_invite(
+    state: &AppState,
+    token: &str,
+    agent_id: &str,
+    remote_host: &str,
+    remote_port: u16,
+    remote_socket: Option<&str>,
+    extra_ports: &[u16],
+) -> bool {
+    if remote_socket.is_some() || !extra_ports.is_empty() {
+        return false;
+    }
+    let now = unix_time_ms();
+    state
+        .invites
+        .remove_if(token, |_, i| {
+            i.expires_at_ms > now
+                && i.agent_id == agent_id
+                && i.remote_host.eq_ignore_ascii_case(remote_host)
+                && i.remote_port == remote_port
+        })
+        .is_some()
+        || state
+            .db
+            .redeem_access_invite(token, agent_id, remote_host, remote_port)
+            .unwrap_or(false)
+}
+
+/// Why `target` could not be resolved: an agent the registry knows is
+/// offline, anything else was never seen.
+fn not_found_message(state: &AppState, target: &str) -> String {
+    match state.db.find_agent(target) {
+        Ok(Some(agent)) => format!(
+            "Agent '{}' ({}) is offline, last seen {} min ago",
+            target,
+            agent.agent_id,
+            unix_time_ms().saturating_sub(agent.last_seen) / 60_000
+        ),
+        _ => format!("Agent '{}' not found", target),
+    }
+}
+
+/// Passes a controller's `ProbeTarget` on to the agent, under a request ID
+/// of the server's own, once the controller is allowed to connect to it.
+/// Refusals are answered with a failed `ProbeResult`.
+fn probe_target(
+    state: &AppState,
+    conn_id: &str,
+    tx: &ClientTx,
+    request_id: String,
+    target_id: String,
+    host: String,
+    port: u16,
+) {
+    info!(target = %target_id, remote = %host_port(&host, port), "Probe request");
+    let fail = |code: ErrorCode, message: String| {
+        let _ = tx.send(ControlMessage::ProbeResult {
+            request_id: request_id.clone(),
+            latency_ms: None,
+            code: Some(code),
+            message: Some(message),
+        });
+    };
+
+    let controller = state
+        .connections
+        .get(conn_id)
+        .and_then(|c| c.principal.clone());
+    if !auth::permits(controller.as_ref(), Scope::Connect) {
+        fail(
+            ErrorCode::Unauthorized,
+            "This token may not open tunnels".to_string(),
+        );
+        return;
+    }
+    let agent_id = match state.resolve_agent(&target_id) {
+        Ok(agent_id) => agent_id,
+        Err(ResolveError::NotFound) => {
+            fail(
+                ErrorCode::AgentNotFound,
+                not_found_message(state, &target_id),
+            );
+            return;
         }
-        _ => {}
+        Err(ResolveError::Ambiguous(candidates)) => {
+            fail(
+                ErrorCode::AmbiguousAgent,
+                format!(
+                    "Name '{}' matches several agents: {}",
+                    target_id,
+                    candidates.join(", ")
+                ),
+            );
+            return;
+        }
+    };
+    let Some(agent) = state.agents.get(&agent_id).map(|a| a.clone()) else {
+        fail(
+            ErrorCode::AgentNotFound,
+            format!(
+                "Agent '{}' is connected to another relay; probes only reach agents on this one",
+                agent_id
+            ),
+        );
+        return;
+    };
+    if !acl::is_allowed(
+        &state.config.acl,
+        controller.as_ref(),
+        &agent_id,
+        agent.principal.as_ref(),
+        &agent.tags,
+    ) {
+        warn!(agent_id = %agent_id, "Probe denied by ACL");
+        fail(
+            ErrorCode::Unauthorized,
+            format!("Not authorized to connect to agent '{}'", agent_id),
+        );
+        return;
+    }
+    if !state.config.targets.permits(
+        &agent_id,
+        agent.principal.as_ref(),
+        &agent.tags,
+        &host,
+        None,
+    ) {
+        warn!(agent_id = %agent_id, "Probe refused by target policy");
+        fail(
+            ErrorCode::Unauthorized,
+            format!(
+                "The relay does not allow tunnels to {}",
+                host_port(&host, port)
+            ),
+        );
+        return;
+    }
+
+    state
+        .probes
+        .retain(|_, p| p.started.elapsed() < PROBE_TIMEOUT);
+    let waiting = state
+        .probes
+        .iter()
+        .filter(|p| p.controller_id == conn_id)
+        .count();
+    if waiting >= MAX_PROBES_PER_CONTROLLER {
+        fail(
+            ErrorCode::LimitExceeded,
+            format!(
+                "At most {} probes may wait at once",
+                MAX_PROBES_PER_CONTROLLER
+            ),
+        );
+        return;
+    }
+    let probe_id = Uuid::new_v4().to_string();
+    state.probes.insert(
+        probe_id.clone(),
+        PendingProbe {
+            controller_id: conn_id.to_string(),
+            request_id: request_id.clone(),
+            agent_id: agent_id.clone(),
+            started: Instant::now(),
+        },
+    );
+    let _ = agent.tx.send(ControlMessage::ProbeTarget {
+        request_id: probe_id,
+        target_id: agent_id,
+        host,
+        port,
+        requester: controller.map(|p| p.name),
+    });
+}
+
+/// Marks the connection as a controller, which opens tunnels but is never
+/// reachable as an agent, and confirms with `RegisterOk`.
+fn register_controller(
+    state: &AppState,
+    conn_id: &str,
+    tx: &ClientTx,
+    principal: Option<Principal>,
+    name: Option<String>,
+) {
+    info!(
+        identity = principal.as_ref().map_or("anonymous", |p| p.name.as_str()),
+        "Client registered to open tunnels only"
+    );
+    if let Some(mut c) = state.connections.get_mut(conn_id) {
+        c.role = Some(Role::Controller);
+    }
+    state.record(AuditEvent::Register {
+        conn_id: conn_id.to_string(),
+        identity: principal.map(|p| p.name),
+        agent_id: None,
+        name,
+    });
+    let _ = tx.send(register_ok(state, None));
+}
+
+/// Makes the connection the agent `registration.agent_id` and confirms
+/// with `RegisterOk`.
+async fn register_agent(
+    state: &AppState,
+    conn_id: &str,
+    tx: &ClientTx,
+    agent_id: &Arc<tokio::sync::Mutex<Option<String>>>,
+    registration: Registration,
+) {
+    let Registration {
+        agent_id: aid,
+        principal,
+        tags,
+        name,
+        version,
+        services,
+        public_key,
+    } = registration;
+    info!(
+        agent_id = %aid,
+        key_backed = public_key.is_some(),
+        identity = principal.as_ref().map_or("anonymous", |p| p.name.as_str()),
+        name = name.as_deref().unwrap_or("-"),
+        version = version.as_deref().unwrap_or("-"),
+        "Agent registered"
+    );
+    state.record(AuditEvent::Register {
+        conn_id: conn_id.to_string(),
+        identity: principal.as_ref().map(|p| p.name.clone()),
+        agent_id: Some(aid.clone()),
+        name: name.clone(),
+    });
+    state.db.agent_registered(
+        &aid,
+        name.as_deref(),
+        principal.as_ref().map(|p| p.name.as_str()),
+        &tags,
+        version.as_deref(),
+        &services,
+        public_key.as_deref(),
+    );
+    let now = unix_time_ms();
+    let shaper = state
+        .agent_shaper(principal.as_ref().map(|p| p.name.as_str()), &aid)
+        .map(|((rate, burst), shaper)| {
+            info!(agent_id = %aid, rate, burst, "Agent bandwidth shaped");
+            shaper
+        });
+    let info = AgentInfo {
+        tx: tx.clone(),
+        conn_id: conn_id.to_string(),
+        principal,
+        tags,
+        name,
+        version,
+        services,
+        public_key,
+        connected_at: now,
+        usage: Arc::new(AgentUsage::new(now)),
+        shaper,
+    };
+    if let Some(cluster) = &state.cluster {
+        cluster.announce(&aid, Some(&info));
+    }
+    state.agents.insert(aid.clone(), info);
+    if let Some(mut c) = state.connections.get_mut(conn_id) {
+        c.role = Some(Role::Agent);
+    }
+    *agent_id.lock().await = Some(aid.clone());
+    let _ = tx.send(register_ok(state, Some(aid.clone())));
+    access::deliver_pending(state, &aid, tx);
+}
+
+/// Holds `registration` until the agent proves its ID, after refusing IDs
+/// that are banned, and sends the `RegisterChallenge`.
+fn challenge_register(state: &AppState, conn_id: &str, tx: &ClientTx, registration: Registration) {
+    if let Some(ban) = state
+        .bans
+        .find(&BanTarget::AgentId(registration.agent_id.clone()))
+    {
+        refuse_banned(state, conn_id, ban);
+        return;
+    }
+    let nonce = register_challenge();
+    if let Some(mut c) = state.connections.get_mut(conn_id) {
+        c.pending_register = Some((registration, nonce.clone()));
+    }
+    let _ = tx.send(ControlMessage::RegisterChallenge { nonce });
+}
+
+fn register_ok(state: &AppState, agent_id: Option<String>) -> ControlMessage {
+    ControlMessage::RegisterOk {
+        agent_id,
+        server_time_ms: unix_time_ms(),
+        max_chunk_bytes: u32::try_from(state.config.limits.stream_buffer_bytes).unwrap_or(u32::MAX),
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+    }
+}
+
+/// Ends another connection's registration as `agent_id` after this one
+/// proved the agent's key, e.g. when the agent reconnects before its old
+/// connection timed out. The old connection is closed with
+/// [`CLOSE_REPLACED`].
+fn replace_agent(state: &AppState, agent_id: &str, conn_id: &str) {
+    let Some((_, old)) = state
+        .agents
+        .remove_if(agent_id, |_, a| a.conn_id != conn_id)
+    else {
+        return;
+    };
+    warn!(agent_id, old_conn = %old.conn_id, "Agent ID taken over by a new connection");
+    let sessions: Vec<String> = state
+        .sessions
+        .iter()
+        .filter(|s| s.agent_id == agent_id)
+        .map(|s| s.session_id.clone())
+        .collect();
+    for sid in sessions {
+        close_session(state, &sid, format!("{} re-registered", agent_id));
+    }
+    if let Some(c) = state.connections.get(&old.conn_id) {
+        c.conn.close(
+            VarInt::from_u32(CLOSE_REPLACED),
+            b"agent ID registered again",
+        );
     }
 }
 
 async fn handle_message(
     state: &AppState,
     conn_id: &str,
-    tx: &mpsc::UnboundedSender<ControlMessage>,
+    tx: &ClientTx,
     agent_id: &Arc<tokio::sync::Mutex<Option<String>>>,
     msg: ControlMessage,
 ) {
+    if let Some(cluster) = &state.cluster {
+        if cluster.forward(conn_id, &msg) {
+            return;
+        }
+    }
+    // A service is looked up in the agent's catalog, then opened like any
+    // other target.
+    let msg = match msg {
+        ControlMessage::ConnectService {
+            request_id,
+            target_id,
+            service_name,
+            traffic_class,
+        } => match service_connect(
+            state,
+            conn_id,
+            &request_id,
+            target_id,
+            &service_name,
+            traffic_class,
+        ) {
+            Ok(connect) => connect,
+            Err((code, message)) => {
+                let _ = tx.send(ControlMessage::ConnectFailed {
+                    request_id,
+                    code,
+                    message,
+                });
+                return;
+            }
+        },
+        msg => msg,
+    };
     match msg {
-        ControlMessage::Register => {
-            let aid = generate_agent_id();
-            info!("Agent registered: {} (conn={})", aid, conn_id);
-            state.agents.insert(
-                aid.clone(),
-                AgentInfo {
-                    tx: tx.clone(),
-                    conn_id: conn_id.to_string(),
-                },
-            );
-            *agent_id.lock().await = Some(aid.clone());
-            let _ = tx.send(ControlMessage::RegisterOk { agent_id: aid });
+        ControlMessage::Register {
+            token,
+            tags,
+            name,
+            agent_id: fixed_id,
+            version,
+            services,
+            public_key,
+        } => {
+            if !check_client_version(state, conn_id, tx, version.as_deref()) {
+                return;
+            }
+            let Ok(principal) = identify(state, conn_id, tx, token) else {
+                return;
+            };
+
+            // A token that may only open tunnels authenticates the client
+            // without making it reachable as an agent.
+            if !auth::permits(principal.as_ref(), Scope::Accept) {
+                if fixed_id.is_some() {
+                    warn!("Registration rejected: token may not accept tunnels");
+                    deny_register(
+                        state,
+                        conn_id,
+                        tx,
+                        "This token may not register as an agent".to_string(),
+                    );
+                    return;
+                }
+                register_controller(state, conn_id, tx, principal, name);
+                return;
+            }
+
+            // A reserved ID takes precedence over an identity key.
+            let public_key = public_key.filter(|_| fixed_id.is_none());
+            if let Some(public_key) = public_key {
+                let aid = key_agent_id(&public_key);
+                if state.config.agent_key(&aid).is_some() {
+                    warn!(agent_id = %aid, "Registration rejected: key-backed ID is reserved");
+                    deny_register(
+                        state,
+                        conn_id,
+                        tx,
+                        format!("Agent ID '{}' is reserved on this server", aid),
+                    );
+                    return;
+                }
+                let registration = Registration {
+                    agent_id: aid,
+                    principal,
+                    tags,
+                    name,
+                    version,
+                    services,
+                    public_key: Some(public_key),
+                };
+                challenge_register(state, conn_id, tx, registration);
+                return;
+            }
+
+            let Some(fixed_id) = fixed_id else {
+                // Random IDs never collide with reserved or banned ones, nor
+                // with agents on other relays.
+                let aid = loop {
+                    let aid = generate_agent_id();
+                    if state.config.agent_key(&aid).is_none()
+                        && state.bans.find(&BanTarget::AgentId(aid.clone())).is_none()
+                        && !state
+                            .cluster
+                            .as_ref()
+                            .is_some_and(|c| c.agents.contains_key(&aid))
+                    {
+                        break aid;
+                    }
+                };
+                let registration = Registration {
+                    agent_id: aid,
+                    principal,
+                    tags,
+                    name,
+                    version,
+                    services,
+                    public_key: None,
+                };
+                register_agent(state, conn_id, tx, agent_id, registration).await;
+                return;
+            };
+            if state.config.agent_key(&fixed_id).is_none() {
+                warn!(agent_id = %fixed_id, "Registration rejected: agent ID has no key");
+                deny_register(
+                    state,
+                    conn_id,
+                    tx,
+                    format!("Agent ID '{}' is not reserved on this server", fixed_id),
+                );
+                return;
+            }
+            let registration = Registration {
+                agent_id: fixed_id,
+                principal,
+                tags,
+                name,
+                version,
+                services,
+                public_key: None,
+            };
+            challenge_register(state, conn_id, tx, registration);
+        }
+        ControlMessage::RegisterProof { proof } => {
+            let pending = state
+                .connections
+                .get_mut(conn_id)
+                .and_then(|mut c| c.pending_register.take());
+            let Some((registration, nonce)) = pending else {
+                let _ = tx.send(ControlMessage::Error {
+                    code: ErrorCode::InvalidMessage,
+                    message: "No registration challenge is pending".to_string(),
+                });
+                return;
+            };
+            let valid = match &registration.public_key {
+                Some(public_key) => {
+                    verify_register_signature(public_key, &registration.agent_id, &nonce, &proof)
+                }
+                None => state
+                    .config
+                    .agent_key(&registration.agent_id)
+                    .is_some_and(|key| {
+                        verify_register_proof(key, &registration.agent_id, &nonce, &proof)
+                    }),
+            };
+            if !valid {
+                warn!(agent_id = %registration.agent_id, "Registration rejected: invalid agent key");
+                state.auth_failed(conn_id, AuthFailure::AgentKey);
+                deny_register(state, conn_id, tx, "Invalid agent key".to_string());
+                return;
+            }
+            // The ID may have been banned while the challenge was out.
+            if let Some(ban) = state
+                .bans
+                .find(&BanTarget::AgentId(registration.agent_id.clone()))
+            {
+                refuse_banned(state, conn_id, ban);
+                return;
+            }
+            replace_agent(state, &registration.agent_id, conn_id);
+            register_agent(state, conn_id, tx, agent_id, registration).await;
         }
         ControlMessage::Connect {
             target_id,
             remote_host,
             remote_port,
+            request_id,
+            remote_socket,
+            pairing_token,
+            connect_timeout_ms,
+            requester,
+            extra_ports,
+            invite,
+            ttl_secs,
+            reverse_socks,
+            traffic_class,
         } => {
-            info!(
-                "Connect request: {} → {} ({}:{})",
-                conn_id, target_id, remote_host, remote_port
-            );
+            let target = describe_target(&remote_host, remote_port, remote_socket.as_deref());
+            info!(target = %target_id, remote = %target, extra_ports = extra_ports.len(), reverse_socks, "Connect request");
+
+            let requested = target_id.clone();
+            let audit = |agent_id: Option<String>, session_id: Option<String>, error| {
+                state.record(AuditEvent::Connect {
+                    conn_id: conn_id.to_string(),
+                    identity: state.identity(conn_id),
+                    target: requested.clone(),
+                    agent_id,
+                    remote_host: remote_host.clone(),
+                    remote_port,
+                    remote_socket: remote_socket.clone(),
+                    session_id,
+                    error,
+                });
+            };
+            let fail = |code: ErrorCode, message: String| {
+                audit(None, None, Some(code));
+                let _ = tx.send(ControlMessage::ConnectFailed {
+                    request_id: request_id.clone(),
+                    code,
+                    message,
+                });
+            };
 
-            match state.agents.get(&target_id) {
-                Some(agent_info) => {
-                    let session_id = Uuid::new_v4().to_string()[..8].to_string();
-
-                    state.sessions.insert(
-                        session_id.clone(),
-                        TunnelSession {
-                            session_id: session_id.clone(),
-                            agent_id: target_id.clone(),
-                            controller_id: conn_id.to_string(),
-                            remote_host: remote_host.clone(),
-                            remote_port,
-                        },
+            let controller = state
+                .connections
+                .get(conn_id)
+                .and_then(|c| c.principal.clone());
+            // A link from another relay forwards a Connect that relay has
+            // already authorized.
+            let peer = state.connections.get(conn_id).and_then(|c| c.peer.clone());
+            // An invitation stands in for the scope and the ACL; the agent's
+            // relay redeems it below.
+            if peer.is_none()
+                && invite.is_none()
+                && !auth::permits(controller.as_ref(), Scope::Connect)
+            {
+                warn!(
+                    identity = controller.as_ref().map_or("anonymous", |p| p.name.as_str()),
+                    "Connect refused: token may not open tunnels"
+                );
+                fail(
+                    ErrorCode::Unauthorized,
+                    "This token may not open tunnels".to_string(),
+                );
+                return;
+            }
+
+            // The target may be given by ID or by registered name.
+            let target_id = match state.resolve_agent(&target_id) {
+                Ok(agent_id) => agent_id,
+                Err(ResolveError::NotFound) => {
+                    fail(
+                        ErrorCode::AgentNotFound,
+                        not_found_message(state, &target_id),
                     );
+                    return;
+                }
+                Err(ResolveError::Ambiguous(candidates)) => {
+                    fail(
+                        ErrorCode::AmbiguousAgent,
+                        format!(
+                            "Name '{}' matches several agents: {}",
+                            target_id,
+                            candidates.join(", ")
+                        ),
+                    );
+                    return;
+                }
+            };
 
-                    let _ = agent_info.tx.send(ControlMessage::TunnelRequest {
-                        session_id,
-                        remote_host,
+            let allowed = |agent: Option<&Principal>, tags: &[String]| {
+                if acl::is_allowed(
+                    &state.config.acl,
+                    controller.as_ref(),
+                    &target_id,
+                    agent,
+                    tags,
+                ) {
+                    return true;
+                }
+                warn!(
+                    identity = controller.as_ref().map_or("anonymous", |p| p.name.as_str()),
+                    agent_id = %target_id,
+                    "Connect denied by ACL"
+                );
+                fail(
+                    ErrorCode::Unauthorized,
+                    format!("Not authorized to connect to agent '{}'", target_id),
+                );
+                false
+            };
+
+            // An agent on another relay is reached through a link to it.
+            let remote = match &state.cluster {
+                Some(cluster) if peer.is_none() && !state.agents.contains_key(&target_id) => {
+                    cluster
+                        .agents
+                        .get(&target_id)
+                        .map(|a| (cluster.clone(), a.clone()))
+                }
+                _ => None,
+            };
+            if let Some((cluster, agent)) = remote {
+                if invite.is_none() && !allowed(agent.principal().as_ref(), &agent.tags) {
+                    return;
+                }
+                info!(agent_id = %target_id, relay_id = %agent.relay_id, "Forwarding Connect to relay");
+                audit(Some(target_id.clone()), None, None);
+                let msg = ControlMessage::Connect {
+                    target_id,
+                    remote_host,
+                    remote_port,
+                    request_id,
+                    remote_socket,
+                    pairing_token,
+                    connect_timeout_ms,
+                    requester: controller.map(|p| p.name),
+                    extra_ports,
+                    invite,
+                    ttl_secs,
+                    reverse_socks,
+                    traffic_class,
+                };
+                tokio::spawn(
+                    cluster
+                        .forward_connect(state.clone(), conn_id.to_string(), agent.relay_id, msg)
+                        .in_current_span(),
+                );
+                return;
+            }
+
+            let Some(agent_info) = state.agents.get(&target_id) else {
+                fail(
+                    ErrorCode::AgentNotFound,
+                    format!("Agent '{}' not found", target_id),
+                );
+                return;
+            };
+
+            let invited = match &invite {
+                Some(token) => {
+                    if !redeem_invite(
+                        state,
+                        token,
+                        &target_id,
+                        normalize_host(&remote_host),
                         remote_port,
-                    });
+                        remote_socket.as_deref(),
+                        &extra_ports,
+                    ) {
+                        warn!(agent_id = %target_id, "Connect refused: invalid invitation");
+                        state.auth_failed(conn_id, AuthFailure::Invitation);
+                        fail(
+                            ErrorCode::Unauthorized,
+                            "The invitation is invalid, expired or for another target".to_string(),
+                        );
+                        return;
+                    }
+                    info!(agent_id = %target_id, "Invitation redeemed");
+                    true
                 }
                 None => {
-                    let _ = tx.send(ControlMessage::Error {
-                        message: format!("Agent '{}' not found", target_id),
+                    if peer.is_none() && !allowed(agent_info.principal.as_ref(), &agent_info.tags) {
+                        return;
+                    }
+                    false
+                }
+            };
+
+            // Reverse SOCKS targets are dialed by the controller instead.
+            if !reverse_socks
+                && !state.config.targets.permits(
+                    &target_id,
+                    agent_info.principal.as_ref(),
+                    &agent_info.tags,
+                    &remote_host,
+                    remote_socket.as_deref(),
+                )
+            {
+                warn!(agent_id = %target_id, target = %target, "Connect refused by target policy");
+                fail(
+                    ErrorCode::Unauthorized,
+                    format!("The relay does not allow tunnels to {}", target),
+                );
+                return;
+            }
+
+            let max_tunnels = state.config.limits.max_tunnels_per_agent;
+            let open_tunnels = state.tunnel_count(&target_id);
+            if open_tunnels >= max_tunnels {
+                warn!(agent_id = %target_id, open_tunnels, "Connect refused: tunnel limit reached");
+                fail(
+                    ErrorCode::LimitExceeded,
+                    format!(
+                        "Agent '{}' reached its limit of {} tunnels",
+                        target_id, max_tunnels
+                    ),
+                );
+                return;
+            }
+
+            let session_id = Uuid::new_v4().to_string()[..8].to_string();
+            let span = info_span!(
+                "session",
+                session_id = %session_id,
+                agent_id = %target_id,
+                target = %target,
+                bytes = field::Empty
+            );
+            audit(Some(target_id.clone()), Some(session_id.clone()), None);
+
+            state.sessions.insert(
+                session_id.clone(),
+                TunnelSession {
+                    session_id: session_id.clone(),
+                    agent_id: target_id.clone(),
+                    controller_id: conn_id.to_string(),
+                    request_id: request_id.clone(),
+                    remote_host: remote_host.clone(),
+                    remote_port,
+                    remote_socket: remote_socket.clone(),
+                    extra_ports: extra_ports.clone(),
+                    ttl_secs,
+                    reverse_socks,
+                    traffic_class,
+                    buffers: Arc::new(
+                        BufferBudget::new(state.config.limits.session_buffer_bytes)
+                            .shaped(agent_info.shaper.clone()),
+                    ),
+                    streams: Arc::default(),
+                    max_streams: state.config.limits.max_streams_per_session,
+                    traffic: Arc::default(),
+                    created_at: Instant::now(),
+                    accepted: false,
+                    span: span.clone(),
+                    exposure: None,
+                },
+            );
+            tokio::spawn(expire_unanswered(state.clone(), session_id.clone()).instrument(span));
+
+            let _ = agent_info.tx.send(ControlMessage::TunnelRequest {
+                session_id,
+                remote_host,
+                remote_port,
+                remote_socket,
+                requester: if peer.is_some() {
+                    requester
+                } else {
+                    controller.map(|p| p.name)
+                },
+                pairing_token,
+                connect_timeout_ms,
+                extra_ports,
+                invited,
+                ttl_secs,
+                reverse_socks,
+                traffic_class,
+            });
+        }
+        ControlMessage::TunnelReject {
+            session_id,
+            code,
+            message,
+        } => {
+            // Only the session's agent may refuse it.
+            if session_for(state, tx, &session_id, conn_id, Some(Role::Agent)).is_none() {
+                return;
+            }
+            if let Some((_, session)) = state.sessions.remove(&session_id) {
+                info!(reason = %message, "Tunnel rejected by agent");
+                state.record(AuditEvent::Reject {
+                    session_id: session.session_id.clone(),
+                    agent_id: session.agent_id.clone(),
+                    error: code,
+                });
+                if let Some(c) = state.connections.get(&session.controller_id) {
+                    let _ = c.tx.send(ControlMessage::ConnectFailed {
+                        request_id: session.request_id,
+                        code,
+                        message,
                     });
                 }
             }
         }
-        ControlMessage::TunnelAccept { session_id } => {
-            info!("Tunnel accepted: {}", session_id);
-            if let Some(session) = state.sessions.get(&session_id) {
+        ControlMessage::TunnelAccept {
+            session_id,
+            max_streams,
+        } => {
+            // Only the session's agent accepts it: an accept from anyone
+            // else would skip the agent's approval and start its TTL.
+            if session_for(state, tx, &session_id, conn_id, Some(Role::Agent)).is_none() {
+                return;
+            }
+            info!(?max_streams, "Tunnel accepted");
+            if let Some(mut session) = state.sessions.get_mut(&session_id) {
+                if let Some(agent_max) = max_streams {
+                    session.max_streams = session.max_streams.min(agent_max as usize);
+                }
+                if let Some(ttl_secs) = session.ttl_secs.filter(|_| !session.accepted) {
+                    tokio::spawn(
+                        expire_session(state.clone(), session_id.clone(), ttl_secs)
+                            .instrument(session.span.clone()),
+                    );
+                }
+                session.accepted = true;
+                state.record(AuditEvent::Accept {
+                    session_id: session_id.clone(),
+                    agent_id: session.agent_id.clone(),
+                });
                 if let Some(c) = state.connections.get(&session.controller_id) {
+                    let public_key = state
+                        .agents
+                        .get(&session.agent_id)
+                        .and_then(|a| a.public_key.clone());
                     let _ = c.tx.send(ControlMessage::TunnelReady {
                         session_id: session_id.clone(),
+                        request_id: session.request_id.clone(),
+                        agent_id: session.agent_id.clone(),
+                        public_key,
+                        max_streams: session.max_streams as u32,
                     });
                 }
             }
@@ -336,33 +1925,47 @@ async fn handle_message(
         ControlMessage::StreamOpen {
             session_id,
             stream_id,
+            remote_port,
         } => {
-            if let Some(session) = state.sessions.get(&session_id) {
-                let role = if conn_id == session.controller_id {
-                    "controller"
-                } else {
-                    "agent"
+            let session = state.sessions.get(&session_id).map(|s| s.clone());
+            if let Some(session) = session {
+                let Some(role) = session_side(state, &session, conn_id) else {
+                    refuse_outsider(tx, &session_id);
+                    return;
                 };
+                if let Some(port) = remote_port.filter(|p| !session.extra_ports.contains(p)) {
+                    warn!(port, "StreamOpen refused: port not forwarded by the tunnel");
+                    let _ = tx.send(ControlMessage::Error {
+                        code: ErrorCode::InvalidMessage,
+                        message: format!(
+                            "Port {} is not forwarded by session {}",
+                            port, session_id
+                        ),
+                    });
+                    return;
+                }
                 relay_message(
                     state,
                     &session,
                     ControlMessage::StreamOpen {
                         session_id,
                         stream_id,
+                        remote_port,
                     },
                     role,
-                );
+                )
+                .await;
             }
         }
         ControlMessage::StreamClose {
             session_id,
             stream_id,
         } => {
-            if let Some(session) = state.sessions.get(&session_id) {
-                let role = if conn_id == session.controller_id {
-                    "controller"
-                } else {
-                    "agent"
+            let session = state.sessions.get(&session_id).map(|s| s.clone());
+            if let Some(session) = session {
+                let Some(role) = session_side(state, &session, conn_id) else {
+                    refuse_outsider(tx, &session_id);
+                    return;
                 };
                 relay_message(
                     state,
@@ -372,30 +1975,1013 @@ async fn handle_message(
                         stream_id,
                     },
                     role,
-                );
+                )
+                .await;
+            }
+        }
+        ControlMessage::SessionPing {
+            session_id,
+            sent_ms,
+        } => {
+            // Pings go from the controller to the agent only.
+            if let Some(session) =
+                session_for(state, tx, &session_id, conn_id, Some(Role::Controller))
+            {
+                let msg = ControlMessage::SessionPing {
+                    session_id,
+                    sent_ms,
+                };
+                relay_message(state, &session, msg, Role::Controller).await;
+            }
+        }
+        ControlMessage::SessionPong {
+            session_id,
+            sent_ms,
+        } => {
+            if let Some(session) = session_for(state, tx, &session_id, conn_id, Some(Role::Agent)) {
+                let msg = ControlMessage::SessionPong {
+                    session_id,
+                    sent_ms,
+                };
+                relay_message(state, &session, msg, Role::Agent).await;
+            }
+        }
+        ControlMessage::StreamOpenFailed {
+            session_id,
+            stream_id,
+            code,
+            message,
+        } => {
+            // Only the session's agent dials targets.
+            if let Some(session) = session_for(state, tx, &session_id, conn_id, Some(Role::Agent)) {
+                warn!(%stream_id, ?code, reason = %message, "Agent could not reach target");
+                let msg = ControlMessage::StreamOpenFailed {
+                    session_id,
+                    stream_id,
+                    code,
+                    message,
+                };
+                relay_message(state, &session, msg, Role::Agent).await;
             }
         }
         ControlMessage::TunnelClose { session_id } => {
-            info!("Tunnel closing: {}", session_id);
+            if session_for(state, tx, &session_id, conn_id, None).is_none() {
+                return;
+            }
+            info!("Tunnel closing");
+            expose::stop(state, &session_id);
             if let Some((_, session)) = state.sessions.remove(&session_id) {
+                state.session_ended(&session);
+                observe::end_session(state, &session.session_id, "Tunnel closed");
+                state.record(AuditEvent::Close {
+                    session_id: session.session_id.clone(),
+                    agent_id: session.agent_id.clone(),
+                    controller_id: session.controller_id.clone(),
+                    reason: format!("closed by {}", conn_id),
+                });
                 let close_msg = ControlMessage::TunnelClose {
                     session_id: session.session_id,
                 };
-                if let Some(c) = state.connections.get(&session.controller_id) {
-                    let _ = c.tx.send(close_msg.clone());
+                if session.exposure.is_none() {
+                    if let Some(c) = state.connections.get(&session.controller_id) {
+                        let _ = c.tx.send(close_msg.clone());
+                    }
                 }
                 if let Some(a) = state.agents.get(&session.agent_id) {
                     let _ = a.tx.send(close_msg);
                 }
             }
         }
+        ControlMessage::ListAgents { tag } => {
+            let matches = |tags: &[String]| tag.as_deref().is_none_or(|t| tags_match(tags, t));
+            let mut agents: Vec<AgentSummary> = state
+                .agents
+                .iter()
+                .filter(|a| matches(&a.tags))
+                .map(|a| AgentSummary {
+                    agent_id: a.key().clone(),
+                    name: a.name.clone(),
+                    tags: a.tags.clone(),
+                    services: a.services.clone(),
+                })
+                .collect();
+            if let Some(cluster) = &state.cluster {
+                agents.extend(
+                    cluster
+                        .agents
+                        .iter()
+                        .filter(|a| matches(&a.tags) && !state.agents.contains_key(a.key()))
+                        .map(|a| AgentSummary {
+                            agent_id: a.key().clone(),
+                            name: a.name.clone(),
+                            tags: a.tags.clone(),
+                            services: a.services.clone(),
+                        }),
+                );
+            }
+            let _ = tx.send(ControlMessage::AgentList { agents });
+        }
+        ControlMessage::ObserveRequest { session_id } => {
+            let deny = |reason: &str| {
+                let _ = tx.send(ControlMessage::ObserveEnd {
+                    session_id: session_id.clone(),
+                    reason: reason.to_string(),
+                });
+            };
+
+            let observer = state
+                .connections
+                .get(conn_id)
+                .and_then(|c| c.principal.clone());
+            let Some(observer) = observer.filter(|p| p.observer) else {
+                deny("Observing sessions requires the observer role");
+                return;
+            };
+            let Some(controller_id) = state
+                .sessions
+                .get(&session_id)
+                .map(|s| s.controller_id.clone())
+            else {
+                deny("Session not found");
+                return;
+            };
+            let Some(controller) = state.connections.get(&controller_id) else {
+                deny("Session owner is not connected");
+                return;
+            };
+
+            info!(observer = %observer.name, "Observe requested");
+            state
+                .observe_requests
+                .insert((session_id.clone(), conn_id.to_string()));
+            let _ = controller.tx.send(ControlMessage::ObserveConsent {
+                session_id,
+                observer_id: conn_id.to_string(),
+                observer: observer.name,
+            });
+        }
+        ControlMessage::ObserveReply {
+            session_id,
+            observer_id,
+            allow,
+        } => {
+            // Only the session's controller can consent, and only to a pending request.
+            let Some(session) = state
+                .sessions
+                .get(&session_id)
+                .filter(|s| s.controller_id == conn_id)
+                .map(|s| s.clone())
+            else {
+                return;
+            };
+            if state
+                .observe_requests
+                .remove(&(session_id.clone(), observer_id.clone()))
+                .is_none()
+            {
+                return;
+            }
+
+            if allow {
+                observe::start(state, &session, &observer_id);
+            } else if let Some(c) = state.connections.get(&observer_id) {
+                let _ = c.tx.send(ControlMessage::ObserveEnd {
+                    session_id,
+                    reason: "Declined by the session owner".to_string(),
+                });
+            }
+        }
+        ControlMessage::ObserveEnd { session_id, .. } => {
+            let is_owner = state
+                .sessions
+                .get(&session_id)
+                .is_some_and(|s| s.controller_id == conn_id);
+            if is_owner {
+                info!("Observers revoked by owner");
+                observe::end_session(state, &session_id, "Revoked by the session owner");
+            } else {
+                observe::stop(state, &session_id, conn_id);
+            }
+        }
+        ControlMessage::Expose {
+            request_id,
+            remote_host,
+            remote_port,
+            public_port,
+        } => {
+            info!(%remote_host, remote_port, public_port, "Expose request");
+
+            let aid = agent_id.lock().await.clone();
+            let audit = |session_id: Option<String>, public_port: Option<u16>, error| {
+                state.record(AuditEvent::Expose {
+                    conn_id: conn_id.to_string(),
+                    identity: state.identity(conn_id),
+                    agent_id: aid.clone(),
+                    remote_host: remote_host.clone(),
+                    remote_port,
+                    public_port,
+                    hostname: None,
+                    session_id,
+                    error,
+                });
+            };
+            let fail = |code: ErrorCode, message: String| {
+                warn!(?code, "Expose refused: {}", message);
+                audit(None, None, Some(code));
+                let _ = tx.send(ControlMessage::ConnectFailed {
+                    request_id: request_id.clone(),
+                    code,
+                    message,
+                });
+            };
+
+            let (aid, agent_conn) = match exposing_agent(state, conn_id, aid.clone()) {
+                Ok(owner) => owner,
+                Err((code, message)) => {
+                    fail(code, message);
+                    return;
+                }
+            };
+            let (listener, port) = match expose::bind(&state.config.expose, public_port).await {
+                Ok(bound) => bound,
+                Err((code, message)) => {
+                    fail(code, message);
+                    return;
+                }
+            };
+
+            let session_id = Uuid::new_v4().to_string()[..8].to_string();
+            audit(Some(session_id.clone()), Some(port), None);
+            let session = exposed_session(
+                state,
+                conn_id,
+                aid,
+                ExposedTarget {
+                    session_id: session_id.clone(),
+                    request_id: request_id.clone(),
+                    remote_host,
+                    remote_port,
+                },
+                Exposure::Port(port),
+            );
+            expose::spawn(state, &session, listener, agent_conn);
+
+            let _ = tx.send(ControlMessage::ExposeReady {
+                request_id,
+                session_id,
+                public_port: port,
+            });
+        }
+        ControlMessage::ExposeHttp {
+            request_id,
+            hostname,
+            remote_host,
+            remote_port,
+        } => {
+            let hostname = hostname.trim_end_matches('.').to_ascii_lowercase();
+            info!(%hostname, %remote_host, remote_port, "HTTP expose request");
+            let aid = agent_id.lock().await.clone();
+            let target = ExposedTarget {
+                session_id: Uuid::new_v4().to_string()[..8].to_string(),
+                request_id,
+                remote_host,
+                remote_port,
+            };
+            expose_hostname(state, conn_id, tx, aid, target, Exposure::Http(hostname));
+        }
+        ControlMessage::ExposeTls {
+            request_id,
+            hostname,
+            remote_host,
+            remote_port,
+        } => {
+            let hostname = hostname.trim_end_matches('.').to_ascii_lowercase();
+            info!(%hostname, %remote_host, remote_port, "TLS expose request");
+            let aid = agent_id.lock().await.clone();
+            let target = ExposedTarget {
+                session_id: Uuid::new_v4().to_string()[..8].to_string(),
+                request_id,
+                remote_host,
+                remote_port,
+            };
+            expose_hostname(state, conn_id, tx, aid, target, Exposure::Tls(hostname));
+        }
+        ControlMessage::RelayHello { relay_id, secret } => {
+            let admitted = state.cluster.as_ref().is_some_and(|c| c.admits(&secret));
+            if !admitted {
+                warn!(relay_id = %relay_id, "Relay link rejected: wrong cluster secret");
+                state.auth_failed(conn_id, AuthFailure::ClusterSecret);
+                let _ = tx.send(ControlMessage::Error {
+                    code: ErrorCode::Unauthorized,
+                    message: "Not a member of this cluster".to_string(),
+                });
+                return;
+            }
+            info!(relay_id = %relay_id, "Relay linked");
+            if let Some(mut c) = state.connections.get_mut(conn_id) {
+                c.peer = Some(relay_id);
+            }
+            let _ = tx.send(register_ok(state, None));
+        }
+        ControlMessage::RegisterController { token, version } => {
+            if agent_id.lock().await.is_some() {
+                let _ = tx.send(ControlMessage::Error {
+                    code: ErrorCode::InvalidMessage,
+                    message: "Already registered as an agent".to_string(),
+                });
+                return;
+            }
+            if !check_client_version(state, conn_id, tx, version.as_deref()) {
+                return;
+            }
+            let Ok(principal) = identify(state, conn_id, tx, token) else {
+                return;
+            };
+            debug!(
+                version = version.as_deref().unwrap_or("-"),
+                "Controller version"
+            );
+            register_controller(state, conn_id, tx, principal, None);
+        }
+        ControlMessage::ProbeTarget {
+            request_id,
+            target_id,
+            host,
+            port,
+            ..
+        } => probe_target(state, conn_id, tx, request_id, target_id, host, port),
+        ControlMessage::ProbeResult {
+            request_id,
+            latency_ms,
+            code,
+            message,
+        } => {
+            // Only the agent a probe was sent to may answer it.
+            let aid = agent_id.lock().await.clone();
+            let Some((_, probe)) = state
+                .probes
+                .remove_if(&request_id, |_, p| Some(&p.agent_id) == aid.as_ref())
+            else {
+                return;
+            };
+            if let Some(c) = state.connections.get(&probe.controller_id) {
+                let _ = c.tx.send(ControlMessage::ProbeResult {
+                    request_id: probe.request_id,
+                    latency_ms,
+                    code,
+                    message,
+                });
+            }
+        }
+        ControlMessage::CreateInvite {
+            request_id,
+            remote_host,
+            remote_port,
+            ttl_secs,
+        } => {
+            let aid = agent_id.lock().await.clone();
+            create_invite(
+                state,
+                conn_id,
+                tx,
+                aid,
+                request_id,
+                normalize_host(&remote_host).to_string(),
+                remote_port,
+                ttl_secs,
+            );
+        }
+        ControlMessage::RequestAccess {
+            request_id,
+            target_id,
+            remote_host,
+            remote_port,
+            reason,
+        } => access::request(
+            state,
+            conn_id,
+            tx,
+            request_id,
+            target_id,
+            normalize_host(&remote_host).to_string(),
+            remote_port,
+            reason,
+        ),
+        ControlMessage::DecideAccess {
+            access_id,
+            approve,
+            ttl_secs,
+        } => {
+            let aid = agent_id.lock().await.clone();
+            access::decide(state, conn_id, tx, aid, access_id, approve, ttl_secs);
+        }
+        ControlMessage::AccessStatus {
+            request_id,
+            access_id,
+        } => access::status(state, tx, request_id, access_id),
+        ControlMessage::Unregister => {
+            let Some(aid) = agent_id.lock().await.take() else {
+                return;
+            };
+            if !remove_agent(state, conn_id, &aid) {
+                return;
+            }
+            info!(agent_id = %aid, "Agent unregistered");
+            state.record(AuditEvent::Unregister {
+                conn_id: conn_id.to_string(),
+                agent_id: aid.clone(),
+            });
+            let sessions: Vec<(String, String, bool)> = state
+                .sessions
+                .iter()
+                .filter(|s| s.agent_id == aid)
+                .map(|s| {
+                    let owned = s.exposure.is_some();
+                    (s.session_id.clone(), s.controller_id.clone(), owned)
+                })
+                .collect();
+            for (sid, controller_id, owned) in sessions {
+                if !owned {
+                    if let Some(c) = state.connections.get(&controller_id) {
+                        let _ = c.tx.send(ControlMessage::TunnelClose {
+                            session_id: sid.clone(),
+                        });
+                    }
+                }
+                close_session(state, &sid, format!("{} unregistered", aid));
+            }
+        }
         ControlMessage::Ping => {
-            let _ = tx.send(ControlMessage::Pong);
+            if let Some(aid) = agent_id.lock().await.as_ref() {
+                if let Some(agent) = state.agents.get(aid) {
+                    agent.usage.heartbeat();
+                }
+            }
+            let _ = tx.send(ControlMessage::Pong {
+                server_time_ms: unix_time_ms(),
+            });
         }
-        ControlMessage::Pong
+        ControlMessage::Pong { .. }
         | ControlMessage::RegisterOk { .. }
+        | ControlMessage::RegisterChallenge { .. }
         | ControlMessage::Error { .. }
         | ControlMessage::TunnelReady { .. }
-        | ControlMessage::TunnelRequest { .. } => {}
+        | ControlMessage::TunnelRequest { .. }
+        | ControlMessage::AgentList { .. }
+        | ControlMessage::ConnectFailed { .. }
+        | ControlMessage::ObserveConsent { .. }
+        | ControlMessage::SessionStats { .. }
+        | ControlMessage::ExposeReady { .. }
+        | ControlMessage::ExposeHttpReady { .. }
+        | ControlMessage::ExposeTlsReady { .. }
+        | ControlMessage::InviteCreated { .. }
+        | ControlMessage::AccessRequested { .. }
+        | ControlMessage::AccessUpdate { .. } => {}
+        // Turned into a `Connect` before dispatch.
+        ControlMessage::ConnectService { .. } => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cert::{self, PinnedCert};
+    use crate::config::ServerConfig;
+    use tokio::sync::mpsc;
+    use tunnel_protocol::AccessState;
+
+    /// One end of a loopback QUIC connection, to build `ClientTx`es from.
+    async fn loopback() -> (quinn::Endpoint, quinn::Connection) {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let (server_config, cert) = cert::generate_self_signed_cert().unwrap();
+        let server_config = quinn::ServerConfig::with_crypto(Arc::new(
+            quinn::crypto::rustls::QuicServerConfig::try_from(server_config).unwrap(),
+        ));
+        let server =
+            quinn::Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut crypto = rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(PinnedCert::new(cert))
+            .with_no_client_auth();
+        crypto.alpn_protocols = vec![b"tunnel".to_vec()];
+        let mut client = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+            quinn::crypto::rustls::QuicClientConfig::try_from(crypto).unwrap(),
+        )));
+        let connecting = client
+            .connect(server.local_addr().unwrap(), "localhost")
+            .unwrap();
+        let (accepted, _) = tokio::join!(
+            async { server.accept().await.unwrap().await.unwrap() },
+            connecting
+        );
+        (server, accepted)
+    }
+
+    /// A connection `conn_id` of the test relay and the messages sent to it.
+    struct Client {
+        conn_id: String,
+        tx: ClientTx,
+        rx: mpsc::Receiver<ControlMessage>,
+        agent_id: Arc<tokio::sync::Mutex<Option<String>>>,
+    }
+
+    impl Client {
+        fn new(state: &AppState, conn: &quinn::Connection, conn_id: &str) -> Self {
+            let (tx, rx) = ClientTx::new(conn.clone(), 16, Duration::from_secs(1));
+            state.connections.insert(
+                conn_id.to_string(),
+                ConnectionInfo {
+                    tx: tx.clone(),
+                    conn: conn.clone(),
+                    principal: None,
+                    role: None,
+                    pending_register: None,
+                    peer: None,
+                },
+            );
+            Self {
+                conn_id: conn_id.to_string(),
+                tx,
+                rx,
+                agent_id: Arc::default(),
+            }
+        }
+
+        /// Registers the connection as agent `agent_id`.
+        fn agent(
+            state: &AppState,
+            conn: &quinn::Connection,
+            conn_id: &str,
+            agent_id: &str,
+        ) -> Self {
+            let mut client = Self::new(state, conn, conn_id);
+            client.agent_id = Arc::new(tokio::sync::Mutex::new(Some(agent_id.to_string())));
+            state.agents.insert(
+                agent_id.to_string(),
+                AgentInfo {
+                    tx: client.tx.clone(),
+                    conn_id: conn_id.to_string(),
+                    principal: None,
+                    tags: Vec::new(),
+                    name: None,
+                    version: None,
+                    services: Vec::new(),
+                    public_key: None,
+                    connected_at: 0,
+                    usage: Arc::new(AgentUsage::new(0)),
+                    shaper: None,
+                },
+            );
+            client
+        }
+
+        async fn send(&self, state: &AppState, msg: ControlMessage) {
+            handle_message(state, &self.conn_id, &self.tx, &self.agent_id, msg).await;
+        }
+
+        /// The next message sent to this connection, if any.
+        fn next(&mut self) -> Option<ControlMessage> {
+            self.rx.try_recv().ok()
+        }
+    }
+
+    /// Opens a pending session from `controller_id` to `agent_id`.
+    fn open_session(state: &AppState, agent_id: &str, controller_id: &str) -> String {
+        let session_id = "s1".to_string();
+        state.sessions.insert(
+            session_id.clone(),
+            TunnelSession {
+                session_id: session_id.clone(),
+                agent_id: agent_id.to_string(),
+                controller_id: controller_id.to_string(),
+                request_id: "r1".to_string(),
+                remote_host: "127.0.0.1".to_string(),
+                remote_port: 22,
+                remote_socket: None,
+                extra_ports: Vec::new(),
+                ttl_secs: None,
+                reverse_socks: false,
+                traffic_class: TrafficClass::default(),
+                buffers: Arc::new(BufferBudget::new(1024)),
+                streams: Arc::default(),
+                max_streams: 8,
+                traffic: Arc::default(),
+                created_at: Instant::now(),
+                accepted: false,
+                span: Span::none(),
+                exposure: None,
+            },
+        );
+        session_id
+    }
+
+    fn is_unauthorized(msg: Option<ControlMessage>) -> bool {
+        matches!(
+            msg,
+            Some(ControlMessage::Error {
+                code: ErrorCode::Unauthorized,
+                ..
+            })
+        )
+    }
+
+    #[tokio::test]
+    async fn outsiders_cannot_drive_a_session() {
+        let (_endpoint, conn) = loopback().await;
+        let state = AppState::new(ServerConfig::default());
+        let mut agent = Client::agent(&state, &conn, "agent", "A1");
+        let mut controller = Client::new(&state, &conn, "controller");
+        let mut outsider = Client::new(&state, &conn, "outsider");
+        let session_id = open_session(&state, "A1", "controller");
+
+        let reject = || ControlMessage::TunnelReject {
+            session_id: session_id.clone(),
+            code: ErrorCode::Unauthorized,
+            message: "denied".to_string(),
+        };
+        for msg in [
+            reject(),
+            ControlMessage::TunnelAccept {
+                session_id: session_id.clone(),
+                max_streams: Some(1),
+            },
+            ControlMessage::SessionPing {
+                session_id: session_id.clone(),
+                sent_ms: 1,
+            },
+            ControlMessage::SessionPong {
+                session_id: session_id.clone(),
+                sent_ms: 1,
+            },
+            ControlMessage::StreamOpenFailed {
+                session_id: session_id.clone(),
+                stream_id: "1".to_string(),
+                code: ErrorCode::Timeout,
+                message: "refused".to_string(),
+            },
+            ControlMessage::TunnelClose {
+                session_id: session_id.clone(),
+            },
+        ] {
+            outsider.send(&state, msg).await;
+            assert!(is_unauthorized(outsider.next()));
+        }
+        let session = state.sessions.get(&session_id).unwrap().clone();
+        assert!(!session.accepted);
+        assert_eq!(session.max_streams, 8);
+        assert!(agent.next().is_none());
+        assert!(controller.next().is_none());
+
+        // Each message is only taken from its own side.
+        controller.send(&state, reject()).await;
+        assert!(is_unauthorized(controller.next()));
+        assert!(state.sessions.contains_key(&session_id));
+        controller
+            .send(
+                &state,
+                ControlMessage::TunnelAccept {
+                    session_id: session_id.clone(),
+                    max_streams: None,
+                },
+            )
+            .await;
+        assert!(is_unauthorized(controller.next()));
+        agent
+            .send(
+                &state,
+                ControlMessage::SessionPing {
+                    session_id: session_id.clone(),
+                    sent_ms: 1,
+                },
+            )
+            .await;
+        assert!(is_unauthorized(agent.next()));
+
+        agent
+            .send(
+                &state,
+                ControlMessage::TunnelAccept {
+                    session_id: session_id.clone(),
+                    max_streams: Some(4),
+                },
+            )
+            .await;
+        assert!(state.sessions.get(&session_id).unwrap().accepted);
+        assert!(matches!(
+            controller.next(),
+            Some(ControlMessage::TunnelReady { max_streams: 4, .. })
+        ));
+
+        controller
+            .send(
+                &state,
+                ControlMessage::TunnelClose {
+                    session_id: session_id.clone(),
+                },
+            )
+            .await;
+        assert!(!state.sessions.contains_key(&session_id));
+        assert!(matches!(
+            agent.next(),
+            Some(ControlMessage::TunnelClose { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn access_requests_reach_offline_agents_by_name() {
+        let (_endpoint, conn) = loopback().await;
+        let state = AppState::new(ServerConfig::default());
+        state
+            .db
+            .agent_registered("A1", Some("db"), None, &[], None, &[], None);
+        let mut controller = Client::new(&state, &conn, "controller");
+
+        let mut access_ids = Vec::new();
+        for i in 0..5 {
+            controller
+                .send(
+                    &state,
+                    ControlMessage::RequestAccess {
+                        request_id: format!("r{}", i),
+                        target_id: "DB".to_string(),
+                        remote_host: "127.0.0.1".to_string(),
+                        remote_port: 5432,
+                        reason: None,
+                    },
+                )
+                .await;
+            match controller.next() {
+                Some(ControlMessage::AccessUpdate {
+                    access_id: Some(access_id),
+                    state: AccessState::Pending,
+                    ..
+                }) => access_ids.push(access_id),
+                Some(ControlMessage::AccessUpdate {
+                    code: Some(ErrorCode::LimitExceeded),
+                    ..
+                }) => assert_eq!(i, 4),
+                other => panic!("unexpected reply {:?}", other),
+            }
+        }
+        assert_eq!(access_ids.len(), 4);
+
+        // The invitation is kept in the database, not in memory.
+        let agent = Client::agent(&state, &conn, "agent", "A1");
+        agent
+            .send(
+                &state,
+                ControlMessage::DecideAccess {
+                    access_id: access_ids[0].clone(),
+                    approve: true,
+                    ttl_secs: 60,
+                },
+            )
+            .await;
+        let Some(ControlMessage::AccessUpdate {
+            invite: Some(invite),
+            ..
+        }) = controller.next()
+        else {
+            panic!("no invitation");
+        };
+        assert!(state.invites.is_empty());
+        assert!(!redeem_invite(
+            &state,
+            &invite,
+            "A1",
+            "127.0.0.1",
+            22,
+            None,
+            &[]
+        ));
+        assert!(redeem_invite(
+            &state,
+            &invite,
+            "A1",
+            "127.0.0.1",
+            5432,
+            None,
+            &[]
+        ));
+        assert!(!redeem_invite(
+            &state,
+            &invite,
+            "A1",
+            "127.0.0.1",
+            5432,
+            None,
+            &[]
+        ));
+    }
+
+    #[test]
+    fn agents_of_one_identity_share_a_shaper() {
+        let mut config = ServerConfig::default();
+        config.limits.agent_rate_bytes = 1000;
+        let state = AppState::new(config);
+        let issued = crate::db::IssuedToken {
+            name: "free".to_string(),
+            groups: Vec::new(),
+            observer: false,
+            admin: false,
+            scopes: crate::config::all_scopes(),
+            rate_bytes: Some(500),
+            burst_bytes: Some(2000),
+            created_by: "root".to_string(),
+            created_at: 1,
+        };
+        assert!(state.db.issue_token(&issued, "s3cr3t").unwrap());
+
+        // Issued tokens override the limits, and a reconnecting agent gets
+        // the bucket it left behind, even under a new ID.
+        let (limits, first) = state.agent_shaper(Some("free"), "A1").unwrap();
+        assert_eq!(limits, (500, 2000));
+        let (_, second) = state.agent_shaper(Some("free"), "B2").unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        drop((first, second));
+        assert_eq!(state.shapers.len(), 1);
+
+        // Agents without a token are shaped one by one to the limits.
+        let (limits, anonymous) = state.agent_shaper(None, "C3").unwrap();
+        assert_eq!(limits, (1000, 1000));
+        let (_, other) = state.agent_shaper(None, "D4").unwrap();
+        assert!(!Arc::ptr_eq(&anonymous, &other));
+    }
+
+    #[tokio::test]
+    async fn relay_links_need_the_cluster_secret() {
+        let (_endpoint, conn) = loopback().await;
+        let mut state = AppState::new(ServerConfig::default());
+        state.cluster = Some(crate::cluster::Cluster::detached("cluster-secret"));
+        let mut relay = Client::new(&state, &conn, "relay");
+
+        relay
+            .send(
+                &state,
+                ControlMessage::RelayHello {
+                    relay_id: "relay-b".to_string(),
+                    secret: "guess".to_string(),
+                },
+            )
+            .await;
+        assert!(is_unauthorized(relay.next()));
+        assert!(state.connections.get("relay").unwrap().peer.is_none());
+
+        relay
+            .send(
+                &state,
+                ControlMessage::RelayHello {
+                    relay_id: "relay-b".to_string(),
+                    secret: "cluster-secret".to_string(),
+                },
+            )
+            .await;
+        assert!(matches!(
+            relay.next(),
+            Some(ControlMessage::RegisterOk { .. })
+        ));
+        assert_eq!(
+            state.connections.get("relay").unwrap().peer.as_deref(),
+            Some("relay-b")
+        );
+    }
+
+    #[tokio::test]
+    async fn invitations_open_one_tunnel_to_their_target() {
+        let (_endpoint, conn) = loopback().await;
+        let state = AppState::new(ServerConfig::default());
+        let mut agent = Client::agent(&state, &conn, "agent", "A1");
+        let mut controller = Client::new(&state, &conn, "controller");
+        let create = |request_id: &str| ControlMessage::CreateInvite {
+            request_id: request_id.to_string(),
+            remote_host: "127.0.0.1".to_string(),
+            remote_port: 5432,
+            ttl_secs: 60,
+        };
+
+        controller.send(&state, create("i0")).await;
+        assert!(matches!(
+            controller.next(),
+            Some(ControlMessage::InviteCreated {
+                token: None,
+                code: Some(ErrorCode::Unauthorized),
+                ..
+            })
+        ));
+
+        agent.send(&state, create("i1")).await;
+        let Some(ControlMessage::InviteCreated {
+            token: Some(token), ..
+        }) = agent.next()
+        else {
+            panic!("no invitation");
+        };
+        let redeem =
+            |agent_id: &str, host: &str, port: u16, socket: Option<&str>, extra: &[u16]| {
+                redeem_invite(&state, &token, agent_id, host, port, socket, extra)
+            };
+
+        // Anything but the invited target leaves the invitation unused.
+        assert!(!redeem("A2", "127.0.0.1", 5432, None, &[]));
+        assert!(!redeem("A1", "127.0.0.2", 5432, None, &[]));
+        assert!(!redeem("A1", "127.0.0.1", 22, None, &[]));
+        assert!(!redeem("A1", "127.0.0.1", 5432, Some("/run/db.sock"), &[]));
+        assert!(!redeem("A1", "127.0.0.1", 5432, None, &[22]));
+        assert!(redeem("A1", "127.0.0.1", 5432, None, &[]));
+        assert!(!redeem("A1", "127.0.0.1", 5432, None, &[]));
+
+        agent.send(&state, create("i2")).await;
+        let Some(ControlMessage::InviteCreated {
+            token: Some(expired),
+            ..
+        }) = agent.next()
+        else {
+            panic!("no invitation");
+        };
+        state.invites.get_mut(&expired).unwrap().expires_at_ms = unix_time_ms() - 1;
+        assert!(!redeem_invite(
+            &state,
+            &expired,
+            "A1",
+            "127.0.0.1",
+            5432,
+            None,
+            &[]
+        ));
+
+        for i in 0..MAX_INVITES_PER_AGENT {
+            agent.send(&state, create(&format!("n{}", i))).await;
+            assert!(matches!(
+                agent.next(),
+                Some(ControlMessage::InviteCreated { token: Some(_), .. })
+            ));
+        }
+        agent.send(&state, create("over")).await;
+        assert!(matches!(
+            agent.next(),
+            Some(ControlMessage::InviteCreated {
+                code: Some(ErrorCode::LimitExceeded),
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn connect_service_checks_access_before_services() {
+        let (_endpoint, conn) = loopback().await;
+        let state = AppState::new(ServerConfig {
+            acl: vec![crate::acl::AclRule {
+                controllers: vec!["alice".to_string()],
+                agents: vec!["A1".to_string()],
+            }],
+            ..Default::default()
+        });
+        let _agent = Client::agent(&state, &conn, "agent", "A1");
+        state.agents.get_mut("A1").unwrap().services =
+            vec![tunnel_protocol::ServiceInfo::parse("ssh=127.0.0.1:22").unwrap()];
+        let mut controller = Client::new(&state, &conn, "controller");
+        let principal = |name: &str, scopes: Vec<Scope>| Principal {
+            name: name.to_string(),
+            groups: Vec::new(),
+            observer: false,
+            admin: false,
+            scopes,
+        };
+        let connect = |service_name: &str| ControlMessage::ConnectService {
+            request_id: "r1".to_string(),
+            target_id: "A1".to_string(),
+            service_name: service_name.to_string(),
+            traffic_class: TrafficClass::default(),
+        };
+        async fn failure(
+            state: &AppState,
+            controller: &mut Client,
+            connect: ControlMessage,
+        ) -> (ErrorCode, String) {
+            controller.send(state, connect).await;
+            match controller.next() {
+                Some(ControlMessage::ConnectFailed { code, message, .. }) => (code, message),
+                other => panic!("unexpected reply: {:?}", other),
+            }
+        }
+
+        // A token without the connect scope, then an identity the ACL
+        // refuses: neither can tell a real service from a missing one.
+        let all = vec![Scope::Accept, Scope::Connect];
+        for (name, scopes) in [("alice", vec![Scope::Accept]), ("bob", all.clone())] {
+            state.connections.get_mut("controller").unwrap().principal =
+                Some(principal(name, scopes));
+            let (code, message) = failure(&state, &mut controller, connect("ssh")).await;
+            assert_eq!(code, ErrorCode::Unauthorized);
+            assert_eq!(
+                failure(&state, &mut controller, connect("missing")).await,
+                (code, message)
+            );
+        }
+
+        state.connections.get_mut("controller").unwrap().principal = Some(principal("alice", all));
+        let (code, _) = failure(&state, &mut controller, connect("missing")).await;
+        assert_eq!(code, ErrorCode::InvalidMessage);
     }
 }
@@ -0,0 +1,307 @@
+//! # HTTP Ingress
+//!
+//! Routes plain HTTP requests arriving on `[ingress] bind` to agents by
+//! `Host` header, so a webhook or a demo link can reach a service on an
+//! agent's machine without the tunnel app on the caller's side.
+//!
+//! An agent claims a hostname with `ExposeHttp`. For each TCP connection the
+//! ingress reads the request head, looks up its `Host`, and relays the whole
+//! connection, head included, through a new data stream to that agent. A
+//! keep-alive connection stays bound to the host of its first request.
+//!
+//! A hostname belongs to an identity: the one it is reserved for under
+//! `[ingress.hostnames]`, or else the first identity that claimed it, as
+//! recorded in the database. Other identities cannot claim it, even while
+//! it is not routed. Revoking an issued token releases its hostnames.
+
+use crate::config::IngressConfig;
+use crate::expose;
+use crate::relay::StreamSlot;
+use crate::state::AppState;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, field, info, info_span, warn, Instrument};
+use tunnel_protocol::ErrorCode;
+
+/// Largest request head read while looking for the `Host` header.
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+
+/// How long a client may take to send its request head.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Checks that `hostname` may be claimed under the ingress configuration,
+/// for the TLS listener when `tls` is set and the HTTP one otherwise.
+pub fn check_hostname(
+    config: &IngressConfig,
+    hostname: &str,
+    tls: bool,
+) -> Result<(), (ErrorCode, String)> {
+    let (listener, kind) = if tls {
+        (config.tls_bind, "TLS")
+    } else {
+        (config.bind, "HTTP")
+    };
+    if listener.is_none() {
+        return Err((
+            ErrorCode::Unauthorized,
+            format!("{} ingress is disabled on this relay", kind),
+        ));
+    }
+    if let Some(domain) = &config.domain {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let is_subdomain = hostname
+            .strip_suffix(&domain)
+            .and_then(|label| label.strip_suffix('.'))
+            .is_some_and(|label| !label.is_empty());
+        if !is_subdomain {
+            return Err((
+                ErrorCode::Unauthorized,
+                format!("Hostname must be a subdomain of {}", domain),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `identity` owns `hostname`, recording it as the owner when
+/// the hostname is neither reserved nor claimed yet. An anonymous claim
+/// records nothing and only succeeds on a hostname no identity owns.
+pub fn claim_hostname(
+    state: &AppState,
+    hostname: &str,
+    identity: Option<&str>,
+) -> Result<(), (ErrorCode, String)> {
+    let reserved = state
+        .config
+        .ingress
+        .hostnames
+        .iter()
+        .find(|(name, _)| name.trim_end_matches('.').eq_ignore_ascii_case(hostname));
+    let owned = match reserved {
+        Some((_, owner)) => identity == Some(owner.as_str()),
+        None => state.db.claim_hostname(hostname, identity).map_err(|e| {
+            (
+                ErrorCode::Internal,
+                format!("Failed to claim hostname: {}", e),
+            )
+        })?,
+    };
+    if !owned {
+        return Err((
+            ErrorCode::Unauthorized,
+            format!("Hostname '{}' belongs to another identity", hostname),
+        ));
+    }
+    Ok(())
+}
+
+/// Accepts HTTP connections on the configured address until the process exits.
+pub async fn run(state: AppState) {
+    let Some(addr) = state.config.ingress.bind else {
+        return;
+    };
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind HTTP ingress on {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("🚇 HTTP ingress listening on TCP {}", addr);
+
+    loop {
+        match listener.accept().await {
+            Ok((tcp, peer)) => {
+                let span = info_span!("ingress", peer = %peer);
+                tokio::spawn(serve(state.clone(), tcp).instrument(span));
+            }
+            Err(e) => {
+                warn!("Ingress accept failed: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+async fn serve(state: AppState, mut tcp: TcpStream) {
+    let head = match tokio::time::timeout(HEAD_TIMEOUT, read_head(&mut tcp)).await {
+        Ok(Some(head)) => head,
+        _ => {
+            respond(
+                &mut tcp,
+                "400 Bad Request",
+                "Malformed or incomplete request\n",
+            )
+            .await;
+            return;
+        }
+    };
+    let Some(host) = request_host(&head) else {
+        respond(&mut tcp, "400 Bad Request", "Missing Host header\n").await;
+        return;
+    };
+
+    let session = state
+        .http_routes
+        .get(&host)
+        .and_then(|sid| state.sessions.get(sid.value()).map(|s| s.clone()));
+    let Some(session) = session else {
+        debug!(%host, "No tunnel for host");
+        respond(
+            &mut tcp,
+            "404 Not Found",
+            "No tunnel is registered for this host\n",
+        )
+        .await;
+        return;
+    };
+    let agent_conn = state
+        .connections
+        .get(&session.controller_id)
+        .map(|c| c.conn.clone());
+    let Some(agent_conn) = agent_conn else {
+        respond(&mut tcp, "502 Bad Gateway", "The agent is not connected\n").await;
+        return;
+    };
+
+    let max_streams = state.config.limits.max_streams_per_session;
+    let Ok(slot) = StreamSlot::acquire_new(&session.streams, max_streams) else {
+        warn!(
+            parent: &session.span,
+            %host,
+            max_streams,
+            "Ingress request refused: session stream limit reached"
+        );
+        respond(
+            &mut tcp,
+            "503 Service Unavailable",
+            "Too many open connections\n",
+        )
+        .await;
+        return;
+    };
+    let span = info_span!(
+        parent: &session.span,
+        "stream",
+        stream_id = %slot.id(),
+        host = %host,
+        bytes_from_opener = field::Empty,
+        bytes_to_opener = field::Empty
+    );
+    async move {
+        info!("New ingress connection");
+        let Some((mut q_send, q_recv)) =
+            expose::open_agent_stream(&session, &agent_conn, slot.id()).await
+        else {
+            respond(&mut tcp, "502 Bad Gateway", "Failed to reach the agent\n").await;
+            return;
+        };
+        if q_send.write_all(&head).await.is_err() {
+            respond(&mut tcp, "502 Bad Gateway", "Failed to reach the agent\n").await;
+            return;
+        }
+        expose::relay_tcp(&state, &session, tcp, q_send, q_recv).await;
+    }
+    .instrument(span)
+    .await;
+}
+
+/// Reads until the end of the request head. The returned bytes may extend
+/// past it into the body; they are all forwarded.
+async fn read_head(tcp: &mut TcpStream) -> Option<Vec<u8>> {
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = tcp.read(&mut buf).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        head.extend_from_slice(&buf[..n]);
+        if head.windows(4).any(|w| w == b"\r\n\r\n") {
+            return Some(head);
+        }
+        if head.len() > MAX_HEAD_BYTES {
+            return None;
+        }
+    }
+}
+
+/// The lowercase `Host` of a request head, without its port.
+fn request_host(head: &[u8]) -> Option<String> {
+    let end = head.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let text = std::str::from_utf8(&head[..end]).ok()?;
+    let line = text.split("\r\n").skip(1).find_map(|l| {
+        let (name, value) = l.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("host")
+            .then_some(value.trim())
+    })?;
+    let host = match line.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => line,
+    };
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    (!host.is_empty()).then_some(host)
+}
+
+async fn respond(tcp: &mut TcpStream, status: &str, body: &str) {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let _ = tcp.write_all(response.as_bytes()).await;
+    let _ = tcp.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+
+    #[test]
+    fn parses_host_header() {
+        let head =
+            b"GET / HTTP/1.1\r\nUser-Agent: curl\r\nHOST: Demo.Tunnel.Example.com:8080\r\n\r\n";
+        assert_eq!(
+            request_host(head).as_deref(),
+            Some("demo.tunnel.example.com")
+        );
+        assert_eq!(request_host(b"GET / HTTP/1.1\r\n\r\nHost: x\r\n"), None);
+    }
+
+    #[test]
+    fn hostnames_must_sit_under_the_domain() {
+        let config = IngressConfig {
+            bind: Some("127.0.0.1:8080".parse().unwrap()),
+            tls_bind: None,
+            domain: Some("tunnel.example.com".to_string()),
+            ..IngressConfig::default()
+        };
+        assert!(check_hostname(&config, "demo.tunnel.example.com", false).is_ok());
+        assert!(check_hostname(&config, "tunnel.example.com", false).is_err());
+        assert!(check_hostname(&config, "demotunnel.example.com", false).is_err());
+        assert!(check_hostname(&config, "demo.tunnel.example.com", true).is_err());
+        assert!(check_hostname(&IngressConfig::default(), "demo.example.com", false).is_err());
+    }
+
+    #[test]
+    fn hostnames_belong_to_one_identity() {
+        let mut config = ServerConfig::default();
+        config
+            .ingress
+            .hostnames
+            .insert("Demo.Example.com.".to_string(), "alice".to_string());
+        let state = AppState::new(config);
+        assert!(claim_hostname(&state, "demo.example.com", Some("alice")).is_ok());
+        assert!(claim_hostname(&state, "demo.example.com", Some("bob")).is_err());
+        assert!(claim_hostname(&state, "demo.example.com", None).is_err());
+
+        assert!(claim_hostname(&state, "web.example.com", None).is_ok());
+        assert!(claim_hostname(&state, "web.example.com", Some("bob")).is_ok());
+        assert!(claim_hostname(&state, "web.example.com", Some("alice")).is_err());
+        assert!(claim_hostname(&state, "web.example.com", None).is_err());
+    }
+}
@@ -0,0 +1,211 @@
+//! # IP Filtering
+//!
+//! Refuses connections from addresses outside configured CIDR ranges, so a
+//! privately deployed relay only serves its own networks. Rules come from
+//! the `[ip_filter]` table:
+//!
+//! ```toml
+//! [ip_filter]
+//! allow = ["10.0.0.0/8", "192.168.0.0/16"]
+//! deny = ["10.66.0.0/16"]
+//! trusted_proxies = ["10.0.0.5"]
+//! ```
+//!
+//! An address listed in `deny` is always refused. When `allow` is
+//! non-empty, only addresses it lists are accepted. QUIC connections are
+//! checked before the handshake. REST API requests are checked too, and
+//! when they arrive from one of `trusted_proxies` the client address is
+//! taken from `X-Forwarded-For` instead.
+
+use axum::http::HeaderMap;
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An address range such as `10.0.0.0/8` or `fd00::/8`. A bare address
+/// stands for itself (`/32` or `/128`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Returns `true` if `ip` lies within this range.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("Invalid address in '{}'", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|&p| p <= max)
+                .ok_or_else(|| format!("Invalid prefix length in '{}'", s))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Address rules from the `[ip_filter]` table. Empty lists accept everyone.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct IpFilter {
+    /// Ranges clients must come from; any address when empty.
+    pub allow: Vec<Cidr>,
+
+    /// Ranges always refused, even when also allowed.
+    pub deny: Vec<Cidr>,
+
+    /// Reverse proxies in front of the REST API whose `X-Forwarded-For`
+    /// header is believed.
+    pub trusted_proxies: Vec<Cidr>,
+}
+
+impl IpFilter {
+    /// Returns `true` if a client at `ip` may connect.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|c| c.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip))
+    }
+
+    /// Client address of an HTTP request received from `peer`.
+    ///
+    /// When `peer` is a trusted proxy, `X-Forwarded-For` is walked from
+    /// the right, skipping further trusted proxies, and the first other
+    /// address is the client. Anything unparsable falls back to the last
+    /// address that was trusted to report it.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let trusted = |ip: IpAddr| self.trusted_proxies.iter().any(|c| c.contains(ip));
+        if !trusted(peer) {
+            return peer;
+        }
+        let hops = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .collect::<Vec<_>>();
+        let mut client = peer;
+        for hop in hops.iter().rev() {
+            match hop.trim().parse::<IpAddr>() {
+                Ok(ip) if trusted(ip) => client = ip,
+                Ok(ip) => return ip,
+                Err(_) => break,
+            }
+        }
+        client
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidrs(list: &[&str]) -> Vec<Cidr> {
+        list.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_parsing_and_matching() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.1.200.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(net.contains(ip("::ffff:10.1.0.9")));
+        assert!(!net.contains(ip("fd00::1")));
+
+        let v6: Cidr = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(ip("fd12:3456::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+
+        let host: Cidr = "192.0.2.7".parse().unwrap();
+        assert!(host.contains(ip("192.0.2.7")));
+        assert!(!host.contains(ip("192.0.2.8")));
+
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(ip("203.0.113.1")));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("fd00::/129".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let filter = IpFilter {
+            allow: cidrs(&["10.0.0.0/8"]),
+            deny: cidrs(&["10.66.0.0/16"]),
+            trusted_proxies: Vec::new(),
+        };
+        assert!(filter.permits(ip("10.1.2.3")));
+        assert!(!filter.permits(ip("10.66.1.1")));
+        assert!(!filter.permits(ip("203.0.113.1")));
+        assert!(IpFilter::default().permits(ip("203.0.113.1")));
+    }
+
+    #[test]
+    fn forwarded_for_only_from_trusted_proxies() {
+        let filter = IpFilter {
+            trusted_proxies: cidrs(&["10.0.0.5", "10.0.0.6"]),
+            ..IpFilter::default()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "198.51.100.1, 203.0.113.9, 10.0.0.6".parse().unwrap(),
+        );
+        // The left-most entry is client-supplied and cannot be trusted.
+        assert_eq!(
+            filter.client_ip(ip("10.0.0.5"), &headers),
+            ip("203.0.113.9")
+        );
+        assert_eq!(filter.client_ip(ip("192.0.2.1"), &headers), ip("192.0.2.1"));
+        assert_eq!(
+            filter.client_ip(ip("10.0.0.5"), &HeaderMap::new()),
+            ip("10.0.0.5")
+        );
+    }
+}
@@ -13,70 +13,331 @@
 //! ## Modules
 //!
 //! - [`protocol`] — QUIC message types (binary bincode-serialized)
+//! - [`config`]   — Optional TOML configuration file
+//! - [`auth`]     — Token authentication of registering clients
+//! - [`acl`]      — Controller-to-agent access control lists
+//! - [`access`]   — Access requests agent owners approve later
+//! - [`targets`]  — Targets agents may be asked to dial
+//! - [`ipfilter`] — CIDR allow/deny lists for incoming connections
+//! - [`audit`]    — Persistent JSONL audit log of tunnel events
+//! - [`bans`]     — Admin bans of agent IDs and tokens
+//! - [`db`]       — SQLite storage of agents, issued tokens and session history
+//! - [`cluster`]  — Agent registry shared with other relays through Redis
+//! - [`mdns`]     — Advertisement of the relay on the local network
 //! - [`state`]    — Shared application state (agent/session registries)
 //! - [`handlers`] — QUIC connection lifecycle and message dispatch
+//! - [`relay`]    — Budget-accounted copying of QUIC data streams
+//! - [`observe`]  — Read-only session observers for support
+//! - [`expose`]   — Public TCP ports forwarded to agents
+//! - [`ingress`]  — HTTP requests routed to agents by `Host` header
+//! - [`sni`]      — TLS connections routed to agents by SNI, unterminated
+//! - [`retention`] — Age and size limits for persisted records
 //! - [`api`]      — REST API endpoints
+//! - [`grpc`]     — gRPC control API (`grpc` feature)
+//! - [`telemetry`] — Log subscriber and optional OTLP span export
 
+mod access;
+mod acl;
 mod api;
+mod audit;
+mod auth;
+mod bans;
 mod cert;
+mod cluster;
+mod config;
+mod db;
+mod expose;
+#[cfg(feature = "grpc")]
+mod grpc;
 mod handlers;
+mod ingress;
+mod ipfilter;
+mod mdns;
+mod observe;
+mod relay;
+mod retention;
+mod sni;
 mod state;
+mod targets;
+mod telemetry;
 
+use crate::config::{Route, ServerConfig};
 use crate::state::AppState;
+use tower_http::cors::CorsLayer;
 
 /// Server entry point.
 ///
 /// Initializes logging, creates the shared state, configures routes,
-/// and starts listening for incoming HTTP connections on TCP 7070
-/// and QUIC connections on UDP 7070.
+/// and starts listening for incoming HTTP connections on TCP and QUIC
+/// connections on UDP, on port 7070 unless `[[listen]]` says otherwise.
 #[tokio::main]
 async fn main() {
     // Install default crypto provider for rustls
     let _ = rustls::crypto::ring::default_provider().install_default();
 
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "tunnel_server=info".into()),
-        )
-        .init();
+    let log_format = match telemetry::LogFormat::from_args() {
+        Ok(format) => format,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let (_telemetry, log_filter) = telemetry::init(log_format);
 
-    let state = AppState::new();
+    let config = match ServerConfig::load() {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    tracing::info!(
+        "Loaded {} token(s) and {} ACL rule(s)",
+        config.tokens.len(),
+        config.acl.len()
+    );
+
+    let mut state = AppState::new(config);
+    state.log_filter = log_filter;
+    if let Some(path) = state.config.audit.path.clone() {
+        match audit::AuditLog::open(path, &state.retention) {
+            Ok(log) => state.audit = std::sync::Arc::new(log),
+            Err(e) => {
+                tracing::error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(path) = state.config.bans.path.clone() {
+        match bans::BanList::load(path) {
+            Ok(bans) => state.bans = std::sync::Arc::new(bans),
+            Err(e) => {
+                tracing::error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(path) = config::cli_arg("--db").or_else(|| std::env::var("TUNNEL_DB").ok()) {
+        match db::Database::open(std::path::Path::new(&path)) {
+            Ok(db) => state.db = std::sync::Arc::new(db),
+            Err(e) => {
+                tracing::error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let (server_config, cert) =
+        cert::generate_self_signed_cert().expect("Failed to generate TLS cert");
+    if state.config.cluster.redis_url.is_some() {
+        match cluster::Cluster::start(&state.config.cluster, cert).await {
+            Ok(cluster) => state.cluster = Some(cluster),
+            Err(e) => {
+                tracing::error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(cluster) = state.cluster.clone() {
+        tokio::spawn(cluster.run(state.clone()));
+    }
+    tokio::spawn(observe::run_stats_loop(state.clone()));
+    tokio::spawn(retention::run_cleanup_loop(state.clone()));
+    tokio::spawn(ingress::run(state.clone()));
+    tokio::spawn(sni::run(state.clone()));
+    #[cfg(feature = "grpc")]
+    tokio::spawn(grpc::run(state.clone()));
+    #[cfg(not(feature = "grpc"))]
+    if state.config.api.grpc_bind.is_some() {
+        tracing::warn!("[api] grpc_bind is set but this build lacks the `grpc` feature");
+    }
 
     // ── HTTP API (Axum) ──
-    let app = axum::Router::new()
-        .route("/api/agents", axum::routing::get(api::list_agents))
-        .layer(tower_http::cors::CorsLayer::permissive())
-        .with_state(state.clone());
+    let cors = match api::cors_layer(&state.config.api) {
+        Ok(cors) => cors,
+        Err(e) => {
+            tracing::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let listeners = state.config.listeners();
 
-    let addr = std::net::SocketAddr::from(([0, 0, 0, 0], 7070));
-    let tcp_listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    #[cfg(unix)]
+    if let Some(path) = state.config.api.socket.clone() {
+        // Unix peers have no IP address; the proxy in front is local.
+        let local = std::net::SocketAddr::from(([127, 0, 0, 1], 0));
+        let app = http_router(&state, cors.clone(), &[Route::Api, Route::Admin])
+            .layer(axum::Extension(axum::extract::ConnectInfo(local)));
+        let _ = std::fs::remove_file(&path);
+        let listener = match tokio::net::UnixListener::bind(&path) {
+            Ok(listener) => listener,
+            Err(e) => {
+                tracing::error!("Failed to listen on {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        };
+        // Only the server's user and group may reach the admin API; the
+        // umask could have left the socket open to everyone.
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::Permissions::from_mode(0o660);
+            if let Err(e) = std::fs::set_permissions(&path, mode) {
+                tracing::error!("Failed to restrict {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+        tracing::info!(
+            "🚇 Tunnel Server (HTTP API) listening on {}",
+            path.display()
+        );
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service())
+                .await
+                .unwrap();
+        });
+    }
+    #[cfg(not(unix))]
+    if state.config.api.socket.is_some() {
+        tracing::warn!("[api] socket is set but Unix sockets are not supported on this platform");
+    }
 
-    tracing::info!("🚇 Tunnel Server (HTTP API) listening on TCP {}", addr);
-    tokio::spawn(async move {
-        axum::serve(tcp_listener, app).await.unwrap();
-    });
+    if cfg!(unix) && state.config.api.socket.is_some() && state.config.api.socket_only {
+        tracing::info!("HTTP API is not served on TCP: [api] socket_only is set");
+    } else {
+        for listener in &listeners {
+            if !listener.serves(Route::Api) && !listener.serves(Route::Admin) {
+                continue;
+            }
+            let app = http_router(&state, cors.clone(), &listener.serve);
+            let tcp_listener = match tokio::net::TcpListener::bind(listener.bind).await {
+                Ok(tcp_listener) => tcp_listener,
+                Err(e) => {
+                    tracing::error!("Failed to listen on TCP {}: {}", listener.bind, e);
+                    std::process::exit(1);
+                }
+            };
+            tracing::info!(
+                routes = ?listener.serve,
+                "🚇 Tunnel Server (HTTP API) listening on TCP {}",
+                listener.bind
+            );
+            tokio::spawn(async move {
+                axum::serve(
+                    tcp_listener,
+                    app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+                )
+                .await
+                .unwrap();
+            });
+        }
+    }
 
     // ── QUIC Protocol (Quinn) ──
-    let (server_config, _cert) =
-        cert::generate_self_signed_cert().expect("Failed to generate TLS cert");
     let mut transport_config = quinn::TransportConfig::default();
     transport_config.max_concurrent_bidi_streams(1024u32.into());
     transport_config.max_concurrent_uni_streams(1024u32.into());
+    transport_config.stream_receive_window(
+        quinn::VarInt::from_u64(state.config.limits.stream_buffer_bytes as u64)
+            .expect("stream_buffer_bytes out of range"),
+    );
 
     let mut quinn_config = quinn::ServerConfig::with_crypto(std::sync::Arc::new(
         quinn::crypto::rustls::QuicServerConfig::try_from(server_config)
             .expect("Failed to create QUIC config"),
     ));
     quinn_config.transport_config(std::sync::Arc::new(transport_config));
-    let endpoint = quinn::Endpoint::server(quinn_config, addr).unwrap();
 
-    tracing::info!(
-        "🚇 Tunnel Server (QUIC) listening on UDP {}",
-        endpoint.local_addr().unwrap()
-    );
+    let mut endpoints = Vec::new();
+    for listener in listeners.iter().filter(|l| l.serves(Route::Relay)) {
+        let endpoint = match quinn::Endpoint::server(quinn_config.clone(), listener.bind) {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                tracing::error!("Failed to listen on UDP {}: {}", listener.bind, e);
+                std::process::exit(1);
+            }
+        };
+        tracing::info!(
+            "🚇 Tunnel Server (QUIC) listening on UDP {}",
+            endpoint.local_addr().unwrap()
+        );
+        endpoints.push(endpoint);
+    }
+    let _mdns = if state.config.mdns.enabled {
+        let port = endpoints[0].local_addr().unwrap().port();
+        match mdns::advertise(&state.config.mdns, port) {
+            Ok(daemon) => Some(daemon),
+            Err(e) => {
+                tracing::error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut accept_loops = tokio::task::JoinSet::new();
+    for endpoint in endpoints {
+        accept_loops.spawn(accept_connections(endpoint, state.clone()));
+    }
+    while accept_loops.join_next().await.is_some() {}
+}
+
+/// Builds the REST API router with the route sets in `routes`.
+fn http_router(state: &AppState, cors: CorsLayer, routes: &[Route]) -> axum::Router {
+    let mut router = axum::Router::new();
+    if routes.contains(&Route::Api) {
+        router = router
+            .route(api::OPENAPI_PATH, axum::routing::get(api::openapi))
+            .route("/api/agents", axum::routing::get(api::list_agents))
+            .route("/api/sessions", axum::routing::get(api::list_sessions))
+            .route("/api/stats", axum::routing::get(api::get_stats));
+    }
+    if routes.contains(&Route::Admin) {
+        router = router
+            .route("/api/admin/purge", axum::routing::post(api::purge))
+            .route(
+                "/api/admin/log-level",
+                axum::routing::get(api::get_log_level).put(api::set_log_level),
+            )
+            .route(
+                "/api/admin/tokens",
+                axum::routing::get(api::list_tokens)
+                    .post(api::issue_token)
+                    .delete(api::revoke_token),
+            )
+            .route("/api/admin/agents", axum::routing::get(api::known_agents))
+            .route(
+                "/api/admin/sessions",
+                axum::routing::get(api::session_history),
+            )
+            .route(
+                "/api/admin/bans",
+                axum::routing::get(api::list_bans)
+                    .post(api::add_ban)
+                    .delete(api::remove_ban),
+            );
+    }
+    router
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            api::require_token,
+        ))
+        .layer(cors)
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            api::filter_ip,
+        ))
+        .with_state(state.clone())
+}
 
+/// Accepts QUIC connections on `endpoint` until it is closed.
+async fn accept_connections(endpoint: quinn::Endpoint, state: AppState) {
     while let Some(incoming) = endpoint.accept().await {
+        let remote = incoming.remote_address();
+        if !state.config.ip_filter.permits(remote.ip()) {
+            tracing::warn!(%remote, "Refusing QUIC connection: address not allowed");
+            incoming.refuse();
+            continue;
+        }
         let state_clone = state.clone();
         tokio::spawn(async move {
             match incoming.await {
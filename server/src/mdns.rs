@@ -0,0 +1,41 @@
+//! # LAN Discovery
+//!
+//! Advertises the relay on the local network over mDNS, so clients on the
+//! same LAN can find a self-hosted relay without typing its address:
+//!
+//! ```toml
+//! [mdns]
+//! enabled = true
+//! name = "office-relay"
+//! ```
+//!
+//! The service is announced as [`MDNS_SERVICE_TYPE`] on the QUIC port,
+//! with every address of the machine and a `version` TXT record. Clients
+//! browse for it with the `discover_servers` command.
+
+use crate::config::MdnsConfig;
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use tracing::info;
+use tunnel_protocol::MDNS_SERVICE_TYPE;
+
+/// Starts advertising the relay listening on `port`. The advertisement
+/// lasts as long as the returned daemon.
+pub fn advertise(config: &MdnsConfig, port: u16) -> Result<ServiceDaemon, String> {
+    let daemon = ServiceDaemon::new().map_err(|e| format!("Failed to start mDNS: {}", e))?;
+    let host_name = format!("{}.local.", config.name);
+    let service = ServiceInfo::new(
+        MDNS_SERVICE_TYPE,
+        &config.name,
+        &host_name,
+        "",
+        port,
+        &[("version", env!("CARGO_PKG_VERSION"))][..],
+    )
+    .map_err(|e| format!("Invalid mDNS name '{}': {}", config.name, e))?
+    .enable_addr_auto();
+    daemon
+        .register(service)
+        .map_err(|e| format!("Failed to advertise over mDNS: {}", e))?;
+    info!(name = %config.name, port, "Advertising the relay over mDNS");
+    Ok(daemon)
+}
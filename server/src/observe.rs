@@ -0,0 +1,115 @@
+//! # Session Observers
+//!
+//! Lets a support identity watch another controller's tunnel while
+//! diagnosing it. Observers see session metadata and counters, never
+//! payload bytes.
+//!
+//! ## Flow
+//!
+//! 1. An identity holding the observer role sends `ObserveRequest`.
+//! 2. The server forwards `ObserveConsent` to the session's controller.
+//! 3. The controller answers `ObserveReply { allow }`.
+//! 4. While allowed, the server pushes `SessionStats` every
+//!    [`STATS_INTERVAL`] until the session closes, the observer stops,
+//!    or the controller revokes access with `ObserveEnd`.
+
+use crate::state::{AppState, TunnelSession};
+use std::time::Duration;
+use tracing::info;
+use tunnel_protocol::{ControlMessage, SessionSnapshot};
+
+/// How often observers receive a fresh `SessionStats`.
+pub const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Builds the stats snapshot observers receive for `session`.
+pub fn snapshot(session: &TunnelSession) -> SessionSnapshot {
+    SessionSnapshot {
+        session_id: session.session_id.clone(),
+        agent_id: session.agent_id.clone(),
+        remote_host: session.remote_host.clone(),
+        remote_port: session.remote_port,
+        age_secs: session.created_at.elapsed().as_secs(),
+        streams: session.streams.len() as u64,
+        buffered_bytes: session.buffers.buffered() as u64,
+        high_water_bytes: session.buffers.high_water() as u64,
+        limit_bytes: session.buffers.limit() as u64,
+        relayed_bytes: session.buffers.relayed(),
+    }
+}
+
+/// Registers `observer_id` as watching `session_id` and sends a first snapshot.
+pub fn start(state: &AppState, session: &TunnelSession, observer_id: &str) {
+    let mut watchers = state
+        .observers
+        .entry(session.session_id.clone())
+        .or_default();
+    if !watchers.iter().any(|id| id == observer_id) {
+        watchers.push(observer_id.to_string());
+    }
+    drop(watchers);
+
+    info!(parent: &session.span, observer = observer_id, "Observer attached");
+    if let Some(c) = state.connections.get(observer_id) {
+        let _ = c.tx.send(ControlMessage::SessionStats {
+            stats: snapshot(session),
+        });
+    }
+}
+
+/// Drops every observer of `session_id`, telling each of them `reason`.
+pub fn end_session(state: &AppState, session_id: &str, reason: &str) {
+    state.observe_requests.retain(|(sid, _)| sid != session_id);
+    let Some((_, watchers)) = state.observers.remove(session_id) else {
+        return;
+    };
+    for observer_id in watchers {
+        if let Some(c) = state.connections.get(&observer_id) {
+            let _ = c.tx.send(ControlMessage::ObserveEnd {
+                session_id: session_id.to_string(),
+                reason: reason.to_string(),
+            });
+        }
+    }
+}
+
+/// Stops `observer_id` watching `session_id`.
+pub fn stop(state: &AppState, session_id: &str, observer_id: &str) {
+    state
+        .observe_requests
+        .remove(&(session_id.to_string(), observer_id.to_string()));
+    state.observers.remove_if_mut(session_id, |_, watchers| {
+        watchers.retain(|id| id != observer_id);
+        watchers.is_empty()
+    });
+}
+
+/// Forgets a disconnected connection in every observer list.
+pub fn forget_connection(state: &AppState, conn_id: &str) {
+    state.observe_requests.retain(|(_, id)| id != conn_id);
+    state.observers.retain(|_, watchers| {
+        watchers.retain(|id| id != conn_id);
+        !watchers.is_empty()
+    });
+}
+
+/// Pushes a `SessionStats` to every observer each [`STATS_INTERVAL`].
+pub async fn run_stats_loop(state: AppState) {
+    let mut interval = tokio::time::interval(STATS_INTERVAL);
+    loop {
+        interval.tick().await;
+        for entry in state.observers.iter() {
+            let Some(session) = state.sessions.get(entry.key()) else {
+                continue;
+            };
+            let stats = snapshot(&session);
+            drop(session);
+            for observer_id in entry.value() {
+                if let Some(c) = state.connections.get(observer_id) {
+                    let _ = c.tx.send(ControlMessage::SessionStats {
+                        stats: stats.clone(),
+                    });
+                }
+            }
+        }
+    }
+}
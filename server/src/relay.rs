@@ -0,0 +1,592 @@
+//! # Data Stream Relay
+//!
+//! Pipes bytes from one QUIC data stream to its counterpart on the other
+//! side of a session, while accounting how much each session holds in memory.
+//!
+//! Every chunk read from the source must reserve room in the session's
+//! [`BufferBudget`] before it is written to the destination. While the budget
+//! is exhausted the relay stops reading, so QUIC flow control pauses the
+//! sender. If a stream cannot make progress within the stall timeout it is
+//! reset with [`RESET_BUFFER_LIMIT`], so one stalled consumer cannot pin
+//! unbounded memory on the server.
+//!
+//! A session of a shaped agent also passes each chunk through the agent's
+//! [`Shaper`], a token bucket that holds the chunk back once the agent has
+//! used up its burst, so its traffic settles at the configured rate.
+
+use quinn::{RecvStream, SendStream, VarInt};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Semaphore;
+use tunnel_protocol::RESET_BUFFER_LIMIT;
+use uuid::Uuid;
+
+/// Per-session cap on bytes read from one side but not yet delivered to the other.
+#[derive(Debug)]
+pub struct BufferBudget {
+    permits: Semaphore,
+    limit: usize,
+    buffered: AtomicUsize,
+    high_water: AtomicUsize,
+    relayed: AtomicU64,
+    shaper: Option<Arc<Shaper>>,
+}
+
+impl BufferBudget {
+    /// Creates a budget allowing at most `limit` buffered bytes, capped at
+    /// what a semaphore can hold ([`Semaphore::MAX_PERMITS`]).
+    pub fn new(limit: usize) -> Self {
+        let limit = limit.min(Semaphore::MAX_PERMITS);
+        Self {
+            permits: Semaphore::new(limit),
+            limit,
+            buffered: AtomicUsize::new(0),
+            high_water: AtomicUsize::new(0),
+            relayed: AtomicU64::new(0),
+            shaper: None,
+        }
+    }
+
+    /// Passes the session's chunks through `shaper` before they are relayed.
+    pub fn shaped(mut self, shaper: Option<Arc<Shaper>>) -> Self {
+        self.shaper = shaper;
+        self
+    }
+
+    /// Waits until the agent's shaper lets `n` more bytes through.
+    async fn throttle(&self, n: usize) {
+        if let Some(shaper) = &self.shaper {
+            shaper.take(n).await;
+        }
+    }
+
+    /// The configured cap in bytes.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Bytes currently held in relay buffers.
+    pub fn buffered(&self) -> usize {
+        self.buffered.load(Ordering::Relaxed)
+    }
+
+    /// The largest value [`buffered`](Self::buffered) has reached.
+    pub fn high_water(&self) -> usize {
+        self.high_water.load(Ordering::Relaxed)
+    }
+
+    /// Total bytes that have passed through the budget.
+    pub fn relayed(&self) -> u64 {
+        self.relayed.load(Ordering::Relaxed)
+    }
+
+    fn track(&self, n: usize) {
+        self.relayed.fetch_add(n as u64, Ordering::Relaxed);
+        let now = self.buffered.fetch_add(n, Ordering::Relaxed) + n;
+        self.high_water.fetch_max(now, Ordering::Relaxed);
+    }
+
+    fn release(&self, n: usize) {
+        self.buffered.fetch_sub(n, Ordering::Relaxed);
+    }
+}
+
+/// Token bucket capping the bytes one agent relays per second.
+///
+/// The bucket holds up to `burst` bytes and refills at `rate` bytes per
+/// second. A chunk larger than what is left puts the bucket in debt, and
+/// it is held back until the debt is repaid, so chunks of any size pass.
+#[derive(Debug)]
+pub struct Shaper {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl Shaper {
+    /// Creates a full bucket of `burst` bytes refilling at `rate` bytes per
+    /// second.
+    pub fn new(rate: u64, burst: u64) -> Self {
+        Self {
+            rate: rate.max(1) as f64,
+            burst: burst as f64,
+            bucket: Mutex::new((burst as f64, Instant::now())),
+        }
+    }
+
+    /// Waits until `n` bytes may be relayed.
+    pub async fn take(&self, n: usize) {
+        let wait = self.reserve(n, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Returns whether the bucket has refilled to its burst at `now`, so a
+    /// new shaper would behave the same.
+    pub fn is_full(&self, now: Instant) -> bool {
+        let bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let (tokens, last) = *bucket;
+        tokens + now.saturating_duration_since(last).as_secs_f64() * self.rate >= self.burst
+    }
+
+    /// Takes `n` bytes from the bucket at `now` and returns how long the
+    /// caller must wait before sending them.
+    fn reserve(&self, n: usize, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let (tokens, last) = &mut *bucket;
+        let elapsed = now.saturating_duration_since(*last).as_secs_f64();
+        *tokens = (*tokens + elapsed * self.rate).min(self.burst) - n as f64;
+        *last = now.max(*last);
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        }
+    }
+}
+
+/// Bytes a session has relayed in each direction, counted as chunks are
+/// delivered so open sessions report live totals.
+#[derive(Debug, Default)]
+pub struct SessionTraffic {
+    to_agent: AtomicU64,
+    from_agent: AtomicU64,
+}
+
+impl SessionTraffic {
+    /// Bytes delivered to the agent side.
+    pub fn bytes_to_agent(&self) -> u64 {
+        self.to_agent.load(Ordering::Relaxed)
+    }
+
+    /// Bytes delivered from the agent side.
+    pub fn bytes_from_agent(&self) -> u64 {
+        self.from_agent.load(Ordering::Relaxed)
+    }
+
+    /// The counter for bytes flowing towards the agent, or away from it.
+    pub fn counter(&self, towards_agent: bool) -> &AtomicU64 {
+        if towards_agent {
+            &self.to_agent
+        } else {
+            &self.from_agent
+        }
+    }
+}
+
+/// The data streams open in one session, by stream ID.
+#[derive(Debug, Default)]
+pub struct StreamTable(Mutex<HashSet<String>>);
+
+impl StreamTable {
+    /// Number of data streams currently open.
+    pub fn len(&self) -> usize {
+        self.ids().len()
+    }
+
+    fn ids(&self) -> std::sync::MutexGuard<'_, HashSet<String>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Why a data stream could not join its session.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SlotError {
+    /// The session already has the maximum number of streams.
+    Limit,
+    /// Another open stream of the session uses the same stream ID.
+    DuplicateId,
+}
+
+/// Holds one data stream's ID in its session's [`StreamTable`] and counts
+/// it against the session's stream limit.
+///
+/// The slot is shared by both relay directions and released when the
+/// last of them drops it.
+#[derive(Debug)]
+pub struct StreamSlot {
+    table: Arc<StreamTable>,
+    id: String,
+}
+
+impl StreamSlot {
+    /// Claims `id` in `table`, unless `max` streams are open or the ID is
+    /// already in use within the session.
+    pub fn acquire(table: &Arc<StreamTable>, id: &str, max: usize) -> Result<Self, SlotError> {
+        let mut ids = table.ids();
+        if ids.len() >= max {
+            return Err(SlotError::Limit);
+        }
+        if !ids.insert(id.to_string()) {
+            return Err(SlotError::DuplicateId);
+        }
+        Ok(Self {
+            table: table.clone(),
+            id: id.to_string(),
+        })
+    }
+
+    /// Claims a fresh random stream ID in `table`, drawing again on a
+    /// collision. Used for streams the server opens itself.
+    pub fn acquire_new(table: &Arc<StreamTable>, max: usize) -> Result<Self, SlotError> {
+        loop {
+            let id = Uuid::new_v4().simple().to_string()[..8].to_string();
+            match Self::acquire(table, &id, max) {
+                Err(SlotError::DuplicateId) => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// The stream ID this slot holds.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        self.table.ids().remove(&self.id);
+    }
+}
+
+/// Why a relayed stream ended early.
+#[derive(Debug)]
+pub enum RelayError {
+    Io(std::io::Error),
+    /// The session budget or the destination stayed blocked past the stall timeout.
+    BufferLimit,
+}
+
+impl std::fmt::Display for RelayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::BufferLimit => write!(f, "buffer limit reached, stream reset"),
+        }
+    }
+}
+
+/// Copies `recv` into `send` until EOF, reserving each chunk against `budget`
+/// and adding every delivered chunk to `counter`.
+///
+/// Returns the number of bytes relayed. `chunk_size` bounds the bytes held
+/// for this stream at any moment.
+pub async fn relay_stream(
+    mut recv: RecvStream,
+    mut send: SendStream,
+    budget: &BufferBudget,
+    counter: &AtomicU64,
+    chunk_size: usize,
+    stall_timeout: Duration,
+) -> Result<u64, RelayError> {
+    let result = forward_chunks(
+        &mut recv,
+        &mut send,
+        budget,
+        counter,
+        chunk_size,
+        stall_timeout,
+    )
+    .await;
+    match &result {
+        Ok(_) => {
+            let _ = send.finish();
+        }
+        Err(RelayError::BufferLimit) => reset(&mut recv, &mut send),
+        Err(RelayError::Io(_)) => {}
+    }
+    result
+}
+
+/// Copies `reader` into `writer` until EOF, reserving each chunk against
+/// `budget` and giving up if either the budget or the writer stalls.
+/// Delivered chunks are added to `counter`.
+///
+/// Unlike [`relay_stream`] this neither finishes nor resets the writer, so it
+/// also serves the public TCP listeners.
+pub async fn copy_with_budget<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    budget: &BufferBudget,
+    counter: &AtomicU64,
+    chunk_size: usize,
+    stall_timeout: Duration,
+) -> Result<u64, RelayError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; chunk_size.min(budget.limit()).max(1)];
+    let mut total = 0u64;
+
+    loop {
+        let n = reader.read(&mut buf).await.map_err(RelayError::Io)?;
+        if n == 0 {
+            return Ok(total);
+        }
+        budget.throttle(n).await;
+
+        let permit = match tokio::time::timeout(
+            stall_timeout,
+            budget.permits.acquire_many(n as u32),
+        )
+        .await
+        {
+            Ok(Ok(permit)) => permit,
+            _ => return Err(RelayError::BufferLimit),
+        };
+
+        budget.track(n);
+        let written = tokio::time::timeout(stall_timeout, writer.write_all(&buf[..n])).await;
+        budget.release(n);
+        drop(permit);
+
+        match written {
+            Ok(Ok(())) => {
+                total += n as u64;
+                counter.fetch_add(n as u64, Ordering::Relaxed);
+            }
+            Ok(Err(e)) => return Err(RelayError::Io(e)),
+            Err(_) => return Err(RelayError::BufferLimit),
+        }
+    }
+}
+
+/// Moves chunks from `recv` to `send` until EOF without copying them: the
+/// buffers quinn hands out for received data are queued on `send` as they
+/// are. Budget and stall handling match [`copy_with_budget`].
+async fn forward_chunks(
+    recv: &mut RecvStream,
+    send: &mut SendStream,
+    budget: &BufferBudget,
+    counter: &AtomicU64,
+    chunk_size: usize,
+    stall_timeout: Duration,
+) -> Result<u64, RelayError> {
+    let max_len = chunk_size.min(budget.limit()).max(1);
+    let mut total = 0u64;
+
+    loop {
+        let bytes = match recv.read_chunk(max_len, true).await {
+            Ok(Some(chunk)) => chunk.bytes,
+            Ok(None) => return Ok(total),
+            Err(e) => return Err(RelayError::Io(e.into())),
+        };
+        let n = bytes.len();
+        if n == 0 {
+            continue;
+        }
+        budget.throttle(n).await;
+
+        let permit = match tokio::time::timeout(
+            stall_timeout,
+            budget.permits.acquire_many(n as u32),
+        )
+        .await
+        {
+            Ok(Ok(permit)) => permit,
+            _ => return Err(RelayError::BufferLimit),
+        };
+
+        budget.track(n);
+        let written = tokio::time::timeout(stall_timeout, send.write_chunk(bytes)).await;
+        budget.release(n);
+        drop(permit);
+
+        match written {
+            Ok(Ok(())) => {
+                total += n as u64;
+                counter.fetch_add(n as u64, Ordering::Relaxed);
+            }
+            Ok(Err(e)) => return Err(RelayError::Io(e.into())),
+            Err(_) => return Err(RelayError::BufferLimit),
+        }
+    }
+}
+
+fn reset(recv: &mut RecvStream, send: &mut SendStream) {
+    let code = VarInt::from_u32(RESET_BUFFER_LIMIT);
+    let _ = recv.stop(code);
+    let _ = send.reset(code);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_slot_rejects_duplicates_and_limit() {
+        let table = Arc::new(StreamTable::default());
+        let first = StreamSlot::acquire(&table, "a1b2c3d4", 2).unwrap();
+        assert_eq!(
+            StreamSlot::acquire(&table, "a1b2c3d4", 2).unwrap_err(),
+            SlotError::DuplicateId
+        );
+        let second = StreamSlot::acquire_new(&table, 2).unwrap();
+        assert_ne!(second.id(), first.id());
+        assert_eq!(
+            StreamSlot::acquire(&table, "e5f6a7b8", 2).unwrap_err(),
+            SlotError::Limit
+        );
+
+        // Dropping a slot frees both its ID and its place.
+        drop(first);
+        assert_eq!(table.len(), 1);
+        assert!(StreamSlot::acquire(&table, "a1b2c3d4", 2).is_ok());
+    }
+
+    #[test]
+    fn shaper_allows_burst_then_paces() {
+        let shaper = Shaper::new(1000, 4000);
+        let start = Instant::now();
+        assert_eq!(shaper.reserve(4000, start), Duration::ZERO);
+        // Empty now: 500 bytes take half a second at 1000 B/s.
+        assert_eq!(shaper.reserve(500, start), Duration::from_millis(500));
+        // Two seconds later the debt is repaid and 1500 bytes have accrued.
+        let later = start + Duration::from_secs(2);
+        assert_eq!(shaper.reserve(1500, later), Duration::ZERO);
+        // Idle time never fills the bucket beyond the burst.
+        let idle = later + Duration::from_secs(60);
+        assert!(shaper.is_full(idle));
+        assert_eq!(shaper.reserve(5000, idle), Duration::from_secs(1));
+        assert!(!shaper.is_full(idle + Duration::from_secs(4)));
+        assert!(shaper.is_full(idle + Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_budget_limit_fits_a_semaphore() {
+        assert_eq!(
+            BufferBudget::new(usize::MAX).limit(),
+            Semaphore::MAX_PERMITS
+        );
+        assert_eq!(BufferBudget::new(1024).limit(), 1024);
+    }
+
+    #[tokio::test]
+    async fn test_copy_counts_traffic_per_direction() {
+        let budget = BufferBudget::new(64);
+        let traffic = SessionTraffic::default();
+        let mut reader: &[u8] = &[7u8; 100];
+        let mut writer = Vec::new();
+        let total = copy_with_budget(
+            &mut reader,
+            &mut writer,
+            &budget,
+            traffic.counter(true),
+            16,
+            Duration::from_secs(1),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(total, 100);
+        assert_eq!(writer.len(), 100);
+        assert_eq!(traffic.bytes_to_agent(), 100);
+        assert_eq!(traffic.bytes_from_agent(), 0);
+        assert_eq!(budget.buffered(), 0);
+    }
+
+    /// Relays `bytes` from one QUIC stream to another over loopback,
+    /// with `forward_chunks` or, for comparison, the copying
+    /// `copy_with_budget`. Returns the throughput in MB/s.
+    async fn relay_throughput(bytes: usize, chunks: bool) -> f64 {
+        use crate::cert::{self, PinnedCert};
+
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let (server_config, cert) = cert::generate_self_signed_cert().unwrap();
+        let server_config = quinn::ServerConfig::with_crypto(Arc::new(
+            quinn::crypto::rustls::QuicServerConfig::try_from(server_config).unwrap(),
+        ));
+        let server =
+            quinn::Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut crypto = rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(PinnedCert::new(cert))
+            .with_no_client_auth();
+        crypto.alpn_protocols = vec![b"tunnel".to_vec()];
+        let mut client = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+            quinn::crypto::rustls::QuicClientConfig::try_from(crypto).unwrap(),
+        )));
+        let connecting = client
+            .connect(server.local_addr().unwrap(), "localhost")
+            .unwrap();
+        let (relay, peer) = tokio::join!(
+            async { server.accept().await.unwrap().await.unwrap() },
+            async { connecting.await.unwrap() }
+        );
+
+        // The peer sends on one stream and reads back on another, with
+        // the relay forwarding between them.
+        let (mut source, _) = peer.open_bi().await.unwrap();
+        source.write_all(&[0]).await.unwrap();
+        let (_, mut from_peer) = relay.accept_bi().await.unwrap();
+        from_peer.read_exact(&mut [0]).await.unwrap();
+        let (mut to_peer, _) = relay.open_bi().await.unwrap();
+        to_peer.write_all(&[0]).await.unwrap();
+        let (_, mut sink) = peer.accept_bi().await.unwrap();
+        sink.read_exact(&mut [0]).await.unwrap();
+
+        let start = std::time::Instant::now();
+        let send = tokio::spawn(async move {
+            let block = vec![7u8; 64 * 1024];
+            for _ in 0..bytes / block.len() {
+                source.write_all(&block).await.unwrap();
+            }
+            source.finish().unwrap();
+        });
+        let drain = tokio::spawn(async move { sink.read_to_end(usize::MAX).await.unwrap().len() });
+        let budget = BufferBudget::new(4 * 1024 * 1024);
+        let counter = AtomicU64::new(0);
+        let stall = Duration::from_secs(30);
+        let relayed = if chunks {
+            forward_chunks(
+                &mut from_peer,
+                &mut to_peer,
+                &budget,
+                &counter,
+                64 * 1024,
+                stall,
+            )
+            .await
+            .unwrap()
+        } else {
+            copy_with_budget(
+                &mut from_peer,
+                &mut to_peer,
+                &budget,
+                &counter,
+                64 * 1024,
+                stall,
+            )
+            .await
+            .unwrap()
+        };
+        to_peer.finish().unwrap();
+        send.await.unwrap();
+        assert_eq!(drain.await.unwrap(), bytes);
+        assert_eq!(relayed as usize, bytes);
+        bytes as f64 / start.elapsed().as_secs_f64() / 1e6
+    }
+
+    /// Compares the chunk-forwarding relay path with the copying one:
+    /// `cargo test --release -- --ignored --nocapture relay_throughput`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "benchmark"]
+    async fn bench_relay_throughput() {
+        let bytes = 512 * 1024 * 1024;
+        for round in 1..=3 {
+            let copied = relay_throughput(bytes, false).await;
+            let forwarded = relay_throughput(bytes, true).await;
+            println!(
+                "round {}: copy_with_budget {:.0} MB/s, forward_chunks {:.0} MB/s",
+                round, copied, forwarded
+            );
+        }
+    }
+}
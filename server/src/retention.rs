@@ -0,0 +1,251 @@
+//! # Retention
+//!
+//! Keeps the server's append-only JSONL files (audit trail, usage history)
+//! from growing without bound. Each record is one JSON object per line with
+//! a `ts` field in Unix milliseconds. Files register with [`Retention`]; a
+//! background task prunes them every `cleanup_interval_secs`, and admins can
+//! trigger the same pass on demand through `POST /api/admin/purge`.
+//!
+//! Pruning first drops records older than `max_age_days`, then the oldest
+//! remaining records until the file fits in `max_bytes`. The file is
+//! rewritten through a temporary sibling and renamed into place. Sessions
+//! in the [database](crate::db) history that ended more than `max_age_days`
+//! ago, and access requests made that long ago, are deleted in the same
+//! pass.
+
+use crate::config::RetentionConfig;
+use crate::db::Database;
+use crate::state::AppState;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tracing::{error, info, warn};
+use tunnel_protocol::unix_time_ms;
+use utoipa::ToSchema;
+
+const MS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// An append-only JSONL file subject to the retention policy.
+///
+/// Writers must hold [`lock`](Self::lock) while appending so a concurrent
+/// prune cannot drop their record.
+#[derive(Debug)]
+pub struct RetainedFile {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl RetainedFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Serializes appends with pruning.
+    pub fn lock(&self) -> MutexGuard<'_, ()> {
+        self.lock.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Outcome of pruning one file.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PruneReport {
+    pub path: String,
+    /// Records dropped for exceeding the age limit.
+    pub expired: usize,
+    /// Records dropped to bring the file under the size limit.
+    pub trimmed: usize,
+    /// Records left in the file.
+    pub kept: usize,
+    /// File size after pruning.
+    pub bytes: u64,
+}
+
+/// Registry of files the cleanup task maintains.
+#[derive(Debug, Default)]
+pub struct Retention {
+    files: Mutex<Vec<Arc<RetainedFile>>>,
+}
+
+impl Retention {
+    /// Adds `path` to the files pruned by the cleanup task.
+    pub fn register(&self, path: PathBuf) -> Arc<RetainedFile> {
+        let file = Arc::new(RetainedFile {
+            path,
+            lock: Mutex::new(()),
+        });
+        self.files.lock().unwrap().push(file.clone());
+        file
+    }
+
+    /// Prunes every registered file now. Files that fail are logged and skipped.
+    pub fn purge(&self, policy: &RetentionConfig) -> Vec<PruneReport> {
+        let files = self.files.lock().unwrap().clone();
+        let now_ms = unix_time_ms();
+        files
+            .iter()
+            .filter_map(|file| {
+                let _guard = file.lock();
+                match prune_jsonl(&file.path, policy, now_ms) {
+                    Ok(report) => Some(report),
+                    Err(e) => {
+                        error!("Failed to prune {}: {}", file.path.display(), e);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+/// Applies `policy` to the JSONL file at `path`. A missing file is not an error.
+pub fn prune_jsonl(path: &Path, policy: &RetentionConfig, now_ms: u64) -> io::Result<PruneReport> {
+    let raw = match std::fs::read_to_string(path) {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+
+    // Records without a readable `ts` are kept: their age is unknown.
+    let cutoff = now_ms.saturating_sub(policy.max_age_days.saturating_mul(MS_PER_DAY));
+    let mut lines: Vec<&str> = raw.lines().filter(|l| !l.trim().is_empty()).collect();
+    let before = lines.len();
+    if policy.max_age_days > 0 {
+        lines.retain(|line| record_ts(line).is_none_or(|ts| ts >= cutoff));
+    }
+    let expired = before - lines.len();
+
+    let mut size: u64 = lines.iter().map(|l| l.len() as u64 + 1).sum();
+    let mut skip = 0;
+    if policy.max_bytes > 0 {
+        while size > policy.max_bytes && skip < lines.len() {
+            size -= lines[skip].len() as u64 + 1;
+            skip += 1;
+        }
+    }
+    let lines = &lines[skip..];
+
+    if expired + skip > 0 {
+        let tmp = path.with_extension("jsonl.tmp");
+        let mut out = io::BufWriter::new(std::fs::File::create(&tmp)?);
+        for line in lines {
+            writeln!(out, "{}", line)?;
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        info!(
+            "Pruned {}: {} expired, {} over size, {} kept",
+            path.display(),
+            expired,
+            skip,
+            lines.len()
+        );
+    }
+
+    Ok(PruneReport {
+        path: path.display().to_string(),
+        expired,
+        trimmed: skip,
+        kept: lines.len(),
+        bytes: size,
+    })
+}
+
+fn record_ts(line: &str) -> Option<u64> {
+    serde_json::from_str::<serde_json::Value>(line)
+        .ok()?
+        .get("ts")?
+        .as_u64()
+}
+
+/// Deletes sessions that ended, and access requests made, more than
+/// `max_age_days` ago from the database.
+pub fn prune_history(db: &Database, policy: &RetentionConfig) {
+    if policy.max_age_days == 0 {
+        return;
+    }
+    let cutoff = unix_time_ms().saturating_sub(policy.max_age_days * MS_PER_DAY);
+    match db.prune_sessions(cutoff) {
+        Ok(0) => {}
+        Ok(n) => info!("Pruned {} session(s) from the history", n),
+        Err(e) => warn!("Failed to prune session history: {}", e),
+    }
+    match db.prune_access_requests(cutoff) {
+        Ok(0) => {}
+        Ok(n) => info!("Pruned {} access request(s)", n),
+        Err(e) => warn!("Failed to prune access requests: {}", e),
+    }
+}
+
+/// Prunes registered files and the session history every `cleanup_interval_secs`.
+pub async fn run_cleanup_loop(state: AppState) {
+    let policy = state.config.retention.clone();
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(policy.cleanup_interval_secs.max(1)));
+    loop {
+        interval.tick().await;
+        let retention = state.retention.clone();
+        let db = state.db.clone();
+        let policy = policy.clone();
+        let _ = tokio::task::spawn_blocking(move || {
+            retention.purge(&policy);
+            prune_history(&db, &policy);
+        })
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_age_days: u64, max_bytes: u64) -> RetentionConfig {
+        RetentionConfig {
+            max_age_days,
+            max_bytes,
+            cleanup_interval_secs: 3600,
+        }
+    }
+
+    fn write_records(name: &str, ts: &[u64]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "tunnel-retention-{}-{}.jsonl",
+            name,
+            std::process::id()
+        ));
+        let body: String = ts
+            .iter()
+            .map(|t| format!("{{\"ts\":{},\"event\":\"x\"}}\n", t))
+            .collect();
+        std::fs::write(&path, body).unwrap();
+        path
+    }
+
+    #[test]
+    fn drops_expired_records() {
+        let now = 10 * MS_PER_DAY;
+        let path = write_records("age", &[0, 8 * MS_PER_DAY, 9 * MS_PER_DAY]);
+        let report = prune_jsonl(&path, &policy(2, 0), now).unwrap();
+        assert_eq!((report.expired, report.kept), (1, 2));
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn trims_oldest_records_over_size() {
+        let path = write_records("size", &[1, 2, 3, 4]);
+        let line = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .next()
+            .unwrap()
+            .len() as u64
+            + 1;
+        let report = prune_jsonl(&path, &policy(0, line * 2), 5).unwrap();
+        assert_eq!((report.trimmed, report.kept), (2, 2));
+        let kept = std::fs::read_to_string(&path).unwrap();
+        assert!(kept.starts_with("{\"ts\":3"));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,287 @@
+//! # TLS Ingress
+//!
+//! Routes TLS connections arriving on `[ingress] tls_bind` to agents by the
+//! server name (SNI) in the ClientHello, without terminating TLS on the
+//! relay: certificates and keys stay with the service on the agent's side.
+//!
+//! An agent claims a hostname with `ExposeTls`. For each TCP connection the
+//! relay reads the first TLS record, takes the SNI from the ClientHello and
+//! relays the raw connection, that record included, through a new data
+//! stream to the agent. Connections without a known name get an
+//! `unrecognized_name` alert.
+
+use crate::expose;
+use crate::relay::StreamSlot;
+use crate::state::AppState;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, field, info, info_span, warn, Instrument};
+
+/// Largest TLS record: a 5-byte header and up to 16 KiB of payload.
+const MAX_RECORD_BYTES: usize = 5 + 16 * 1024;
+
+/// How long a client may take to send its ClientHello.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TLS 1.2 fatal `unrecognized_name` alert record.
+const ALERT_UNRECOGNIZED_NAME: [u8; 7] = [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x70];
+
+/// Why no server name could be taken from the bytes read so far.
+#[derive(Debug, PartialEq, Eq)]
+enum Hello {
+    /// The record is not complete yet.
+    Incomplete,
+    /// Not a ClientHello, or one without a host name.
+    Invalid,
+}
+
+/// Accepts TLS connections on the configured address until the process exits.
+pub async fn run(state: AppState) {
+    let Some(addr) = state.config.ingress.tls_bind else {
+        return;
+    };
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind TLS ingress on {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("🚇 TLS ingress listening on TCP {}", addr);
+
+    loop {
+        match listener.accept().await {
+            Ok((tcp, peer)) => {
+                let span = info_span!("tls_ingress", peer = %peer);
+                tokio::spawn(serve(state.clone(), tcp).instrument(span));
+            }
+            Err(e) => {
+                warn!("TLS ingress accept failed: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+async fn serve(state: AppState, mut tcp: TcpStream) {
+    let Ok(Some((hello, host))) = tokio::time::timeout(HELLO_TIMEOUT, read_hello(&mut tcp)).await
+    else {
+        debug!("No server name in ClientHello");
+        refuse(&mut tcp).await;
+        return;
+    };
+
+    let session = state
+        .tls_routes
+        .get(&host)
+        .and_then(|sid| state.sessions.get(sid.value()).map(|s| s.clone()));
+    let Some(session) = session else {
+        debug!(%host, "No tunnel for server name");
+        refuse(&mut tcp).await;
+        return;
+    };
+    let agent_conn = state
+        .connections
+        .get(&session.controller_id)
+        .map(|c| c.conn.clone());
+    let Some(agent_conn) = agent_conn else {
+        debug!(%host, "Agent not connected");
+        return;
+    };
+
+    let max_streams = state.config.limits.max_streams_per_session;
+    let Ok(slot) = StreamSlot::acquire_new(&session.streams, max_streams) else {
+        warn!(
+            parent: &session.span,
+            %host,
+            max_streams,
+            "TLS connection refused: session stream limit reached"
+        );
+        return;
+    };
+    let span = info_span!(
+        parent: &session.span,
+        "stream",
+        stream_id = %slot.id(),
+        host = %host,
+        bytes_from_opener = field::Empty,
+        bytes_to_opener = field::Empty
+    );
+    async move {
+        info!("New TLS ingress connection");
+        let Some((mut q_send, q_recv)) =
+            expose::open_agent_stream(&session, &agent_conn, slot.id()).await
+        else {
+            return;
+        };
+        if q_send.write_all(&hello).await.is_err() {
+            return;
+        }
+        expose::relay_tcp(&state, &session, tcp, q_send, q_recv).await;
+    }
+    .instrument(span)
+    .await;
+}
+
+/// Reads until the first TLS record is complete. Returns the bytes read,
+/// which may extend past the record, and the server name it carries.
+async fn read_hello(tcp: &mut TcpStream) -> Option<(Vec<u8>, String)> {
+    let mut hello = Vec::with_capacity(1024);
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = tcp.read(&mut buf).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        hello.extend_from_slice(&buf[..n]);
+        match server_name(&hello) {
+            Ok(host) => return Some((hello, host)),
+            Err(Hello::Incomplete) if hello.len() < MAX_RECORD_BYTES => {}
+            Err(_) => return None,
+        }
+    }
+}
+
+async fn refuse(tcp: &mut TcpStream) {
+    let _ = tcp.write_all(&ALERT_UNRECOGNIZED_NAME).await;
+    let _ = tcp.shutdown().await;
+}
+
+/// The lowercase host name from the SNI extension of the ClientHello in
+/// the first TLS record of `data`.
+fn server_name(data: &[u8]) -> Result<String, Hello> {
+    if data.len() < 5 {
+        return Err(Hello::Incomplete);
+    }
+    // Handshake record: type 22, then version and length.
+    if data[0] != 0x16 || data[1] != 0x03 {
+        return Err(Hello::Invalid);
+    }
+    let len = usize::from(u16::from_be_bytes([data[3], data[4]]));
+    let record = data.get(5..5 + len).ok_or(Hello::Incomplete)?;
+
+    let mut r = Reader(record);
+    // ClientHello handshake message, which must fit in this record.
+    if r.u8()? != 0x01 {
+        return Err(Hello::Invalid);
+    }
+    let body_len = r.u24()?;
+    let mut body = Reader(r.take(body_len)?);
+    body.take(2 + 32)?; // client_version, random
+    let session_id = usize::from(body.u8()?);
+    body.take(session_id)?;
+    let cipher_suites = usize::from(body.u16()?);
+    body.take(cipher_suites)?;
+    let compression = usize::from(body.u8()?);
+    body.take(compression)?;
+
+    let extensions = usize::from(body.u16()?);
+    let mut extensions = Reader(body.take(extensions)?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let len = usize::from(extensions.u16()?);
+        let mut data = Reader(extensions.take(len)?);
+        if kind != 0 {
+            continue;
+        }
+        let list = usize::from(data.u16()?);
+        let mut names = Reader(data.take(list)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let len = usize::from(names.u16()?);
+            let name = names.take(len)?;
+            if name_type == 0 {
+                let host = std::str::from_utf8(name).map_err(|_| Hello::Invalid)?;
+                let host = host.trim_end_matches('.').to_ascii_lowercase();
+                return if host.is_empty() {
+                    Err(Hello::Invalid)
+                } else {
+                    Ok(host)
+                };
+            }
+        }
+    }
+    Err(Hello::Invalid)
+}
+
+/// Big-endian reads from a complete record; running out of bytes means
+/// the ClientHello is malformed.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Hello> {
+        if self.0.len() < n {
+            return Err(Hello::Invalid);
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, Hello> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Hello> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Result<usize, Hello> {
+        let b = self.take(3)?;
+        Ok(usize::from(b[0]) << 16 | usize::from(b[1]) << 8 | usize::from(b[2]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A minimal ClientHello record with the given SNI host name.
+    fn client_hello(host: &str) -> Vec<u8> {
+        let mut sni = Vec::new();
+        sni.extend_from_slice(&((host.len() + 3) as u16).to_be_bytes());
+        sni.push(0);
+        sni.extend_from_slice(&(host.len() as u16).to_be_bytes());
+        sni.extend_from_slice(host.as_bytes());
+
+        let mut extensions = Vec::new();
+        // An unrelated extension first (supported_versions).
+        extensions.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        extensions.extend_from_slice(&[0x00, 0x00]);
+        extensions.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&sni);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0u8; 32]);
+        body.push(0);
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+        body.extend_from_slice(&[0x01, 0x00]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut handshake = vec![0x01];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn reads_server_name_from_client_hello() {
+        let hello = client_hello("Demo.Tunnel.Example.com");
+        assert_eq!(
+            server_name(&hello).as_deref(),
+            Ok("demo.tunnel.example.com")
+        );
+        assert_eq!(
+            server_name(&hello[..hello.len() - 1]),
+            Err(Hello::Incomplete)
+        );
+        assert_eq!(server_name(b"GET / HTTP/1.1\r\n"), Err(Hello::Invalid));
+    }
+}
@@ -4,19 +4,100 @@
 //! - **Agent registry**: maps agent IDs to their message senders
 //! - **Connection registry**: maps connection IDs to their message senders
 //! - **Session registry**: maps session IDs to tunnel session metadata
+//! - **Observer registry**: maps session IDs to connections watching them
+//! - **Exposure registry**: maps session IDs to public port listeners
+//! - **HTTP and TLS route registries**: map ingress hostnames to session IDs
 //!
 //! All registries use [`DashMap`] for lock-free concurrent access,
 //! since multiple QUIC connections are handled concurrently.
 
-use dashmap::DashMap;
+use crate::audit::{AuditEvent, AuditLog};
+use crate::auth::{self, AuthFailure, AuthFailures, Principal};
+use crate::bans::BanList;
+use crate::cluster::Cluster;
+use crate::config::ServerConfig;
+use crate::db::Database;
+use crate::relay::{BufferBudget, SessionTraffic, Shaper, StreamTable};
+use crate::retention::Retention;
+use crate::telemetry::LogFilter;
+use dashmap::{DashMap, DashSet};
+use quinn::VarInt;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
-use tunnel_protocol::ControlMessage;
+use tracing::warn;
+use tunnel_protocol::{
+    unix_time_ms, ControlMessage, ServiceInfo, TrafficClass, CLOSE_SLOW_CONSUMER,
+};
 use uuid::Uuid;
 
-/// Type alias for the unbounded sender used to push messages to a client's
-/// outbound QUIC control stream. Each connected client gets one of these.
-pub type ClientTx = mpsc::UnboundedSender<ControlMessage>;
+/// Bounded sender used to push messages to a client's outbound QUIC control
+/// stream. Each connected client gets one of these.
+///
+/// A client that stops reading fills its queue. Messages relayed from the
+/// other side of a session wait for room, which stalls that peer's control
+/// loop; if the queue stays full past the slow-consumer timeout, or a
+/// server-originated message finds it full, the connection is closed with
+/// [`CLOSE_SLOW_CONSUMER`].
+#[derive(Debug, Clone)]
+pub struct ClientTx {
+    tx: mpsc::Sender<ControlMessage>,
+    conn: quinn::Connection,
+    timeout: Duration,
+}
+
+/// The client's queue is closed or it was dropped as a slow consumer.
+#[derive(Debug)]
+pub struct ClientGone;
+
+impl ClientTx {
+    /// Creates the sender for `conn` and the receiver its outbound task drains.
+    pub fn new(
+        conn: quinn::Connection,
+        capacity: usize,
+        timeout: Duration,
+    ) -> (Self, mpsc::Receiver<ControlMessage>) {
+        let (tx, rx) = mpsc::channel(capacity.max(1));
+        (Self { tx, conn, timeout }, rx)
+    }
+
+    /// Queues a server-originated message without waiting.
+    pub fn send(&self, msg: ControlMessage) -> Result<(), ClientGone> {
+        match self.tx.try_send(msg) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.disconnect_slow();
+                Err(ClientGone)
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(ClientGone),
+        }
+    }
+
+    /// Queues a message relayed from a peer, waiting up to the slow-consumer
+    /// timeout for room. The wait holds up the peer's control loop.
+    pub async fn send_relayed(&self, msg: ControlMessage) -> Result<(), ClientGone> {
+        match tokio::time::timeout(self.timeout, self.tx.send(msg)).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => Err(ClientGone),
+            Err(_) => {
+                self.disconnect_slow();
+                Err(ClientGone)
+            }
+        }
+    }
+
+    fn disconnect_slow(&self) {
+        warn!(
+            "Closing {}: outbound queue saturated",
+            self.conn.remote_address()
+        );
+        self.conn.close(
+            VarInt::from_u32(CLOSE_SLOW_CONSUMER),
+            b"outbound queue saturated",
+        );
+    }
+}
 
 /// Generates a short, human-readable agent ID from a UUID.
 ///
@@ -38,12 +119,144 @@ pub struct AgentInfo {
     /// Channel to send messages to this agent's QUIC connection.
     pub tx: ClientTx,
     pub conn_id: String,
+
+    /// Identity the agent authenticated as, if it registered with a token.
+    pub principal: Option<Principal>,
+
+    /// Labels the agent registered with (e.g., `env=prod`).
+    pub tags: Vec<String>,
+
+    /// Alias the agent registered with, usable in place of its ID.
+    pub name: Option<String>,
+
+    /// Client software version the agent reported.
+    pub version: Option<String>,
+
+    /// Named services the agent advertises (e.g., `ssh` at `127.0.0.1:22`).
+    pub services: Vec<ServiceInfo>,
+
+    /// Ed25519 key the agent proved ownership of at registration.
+    pub public_key: Option<Vec<u8>>,
+
+    /// When the agent registered, milliseconds since the Unix epoch.
+    pub connected_at: u64,
+
+    /// Heartbeat and traffic counters, shared by every clone.
+    pub usage: Arc<AgentUsage>,
+
+    /// Token bucket shared by the agent's sessions, if its bandwidth is
+    /// capped.
+    pub shaper: Option<Arc<Shaper>>,
+}
+
+/// Counters kept for a registered agent.
+#[derive(Debug)]
+pub struct AgentUsage {
+    last_heartbeat: AtomicU64,
+    relayed: AtomicU64,
+}
+
+impl AgentUsage {
+    pub fn new(now: u64) -> Self {
+        Self {
+            last_heartbeat: AtomicU64::new(now),
+            relayed: AtomicU64::new(0),
+        }
+    }
+
+    /// Notes a `Ping` received from the agent.
+    pub fn heartbeat(&self) {
+        self.last_heartbeat.store(unix_time_ms(), Ordering::Relaxed);
+    }
+
+    /// Last `Ping` from the agent, or its registration if none arrived yet.
+    pub fn last_heartbeat(&self) -> u64 {
+        self.last_heartbeat.load(Ordering::Relaxed)
+    }
+
+    /// Adds the bytes of a session that ended.
+    pub fn add_relayed(&self, bytes: u64) {
+        self.relayed.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Why a `Connect` target could not be resolved to a single agent.
+#[derive(Debug)]
+pub enum ResolveError {
+    NotFound,
+    /// The name matched several agents; their IDs are listed.
+    Ambiguous(Vec<String>),
+}
+
+/// What a connection declared itself as when registering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Registered with `Register` and reachable as an agent.
+    Agent,
+    /// Registered with `RegisterController`, or with a `Register` whose
+    /// token may only open tunnels.
+    Controller,
 }
 
 #[derive(Clone)]
 pub struct ConnectionInfo {
     pub tx: ClientTx,
     pub conn: quinn::Connection,
+
+    /// Identity established by `Register`; `None` for anonymous clients.
+    pub principal: Option<Principal>,
+
+    /// Role declared by `Register` or `RegisterController`; `None` until
+    /// the client registers, and for clients that never do.
+    pub role: Option<Role>,
+
+    /// A `Register` for a fixed or key-backed agent ID waiting for its
+    /// `RegisterProof`, with the nonce it was challenged with.
+    pub pending_register: Option<(Registration, Vec<u8>)>,
+
+    /// Relay ID when the connection is a link from another relay of the
+    /// cluster, which acts as the controller of the sessions it forwards.
+    pub peer: Option<String>,
+}
+
+/// What an agent registers with, kept while its key is being checked.
+#[derive(Debug, Clone)]
+pub struct Registration {
+    pub agent_id: String,
+    pub principal: Option<Principal>,
+    pub tags: Vec<String>,
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub services: Vec<ServiceInfo>,
+    /// Ed25519 key the ID is derived from; `None` for a reserved ID,
+    /// proven with its pre-shared key instead.
+    pub public_key: Option<Vec<u8>>,
+}
+
+/// A `ProbeTarget` passed on to an agent and waiting for its `ProbeResult`,
+/// keyed by the server's own request ID.
+#[derive(Debug, Clone)]
+pub struct PendingProbe {
+    /// Connection that asked, and the request ID it used.
+    pub controller_id: String,
+    pub request_id: String,
+
+    /// Agent expected to answer.
+    pub agent_id: String,
+
+    pub started: Instant,
+}
+
+/// A single-use invitation from `CreateInvite`, keyed by its token.
+#[derive(Debug, Clone)]
+pub struct Invite {
+    /// Agent that issued it and the one target it opens.
+    pub agent_id: String,
+    pub remote_host: String,
+    pub remote_port: u16,
+
+    /// Milliseconds since the Unix epoch after which it no longer works.
+    pub expires_at_ms: u64,
 }
 
 /// Metadata for an active tunnel session between a controller and an agent.
@@ -59,19 +272,102 @@ pub struct TunnelSession {
     /// The connection ID of the controller that initiated this tunnel.
     pub controller_id: String,
 
+    /// The controller's `Connect.request_id`, echoed in `TunnelReady`.
+    pub request_id: String,
+
     /// The remote host the agent should connect to (e.g., "127.0.0.1").
     pub remote_host: String,
 
     /// The remote port on the agent side (e.g., 22 for SSH).
     pub remote_port: u16,
+
+    /// Unix socket on the agent side, replacing `remote_host:remote_port`.
+    pub remote_socket: Option<String>,
+
+    /// Further ports on `remote_host` the tunnel forwards; a `StreamOpen`
+    /// may only name one of these.
+    pub extra_ports: Vec<u16>,
+
+    /// How long the session stays open once accepted, if limited.
+    pub ttl_secs: Option<u64>,
+
+    /// Whether the agent proxies SOCKS connections back to the controller,
+    /// which opens no streams of its own.
+    pub reverse_socks: bool,
+
+    /// QUIC send priority class of the session's data streams.
+    pub traffic_class: TrafficClass,
+
+    /// Memory budget shared by all data streams of this session.
+    pub buffers: Arc<BufferBudget>,
+
+    /// Data streams currently relayed for this session, by stream ID.
+    pub streams: Arc<StreamTable>,
+
+    /// Most data streams the session may have open at once: the relay's
+    /// `max_streams_per_session`, lowered to the agent's own limit once it
+    /// accepts.
+    pub max_streams: usize,
+
+    /// Bytes relayed to and from the agent, per direction.
+    pub traffic: Arc<SessionTraffic>,
+
+    /// When the controller's `Connect` created the session.
+    pub created_at: Instant,
+
+    /// Whether the agent has answered `TunnelRequest` with `TunnelAccept`.
+    /// Sessions created by `Expose` or `ExposeHttp` start accepted.
+    pub accepted: bool,
+
+    /// Tracing span covering the session's lifetime; stream spans nest under it.
+    pub span: tracing::Span,
+
+    /// Where the relay publishes this session, for sessions created by
+    /// `Expose` or `ExposeHttp`. Such sessions are owned by the agent
+    /// itself: `controller_id` is the agent's connection.
+    pub exposure: Option<Exposure>,
+}
+
+/// How an agent-owned session is reachable from outside.
+#[derive(Debug, Clone)]
+pub enum Exposure {
+    /// A public TCP port of the relay.
+    Port(u16),
+    /// HTTP requests to the relay's ingress carrying this `Host`.
+    Http(String),
+    /// TLS connections to the relay's ingress naming this host in SNI.
+    Tls(String),
 }
 
+impl std::fmt::Display for Exposure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Port(port) => write!(f, "port {}", port),
+            Self::Http(hostname) => write!(f, "http://{}", hostname),
+            Self::Tls(hostname) => write!(f, "tls://{}", hostname),
+        }
+    }
+}
+
+impl TunnelSession {
+    /// Records the session's final byte count on its span.
+    pub fn finish_span(&self) {
+        self.span.record("bytes", self.buffers.relayed());
+    }
+}
+
+/// A shaper with the rate and burst, in bytes, it was created with.
+pub type RatedShaper = ((u64, u64), Arc<Shaper>);
+
 /// Shared application state, cloned and passed to each request handler.
 ///
 /// Uses `Arc<DashMap<...>>` for thread-safe, lock-free concurrent access
 /// across all QUIC handler tasks.
 #[derive(Clone)]
 pub struct AppState {
+    /// Server configuration loaded at startup.
+    pub config: Arc<ServerConfig>,
+
     /// Registry of currently connected agents, keyed by agent ID.
     pub agents: Arc<DashMap<String, AgentInfo>>,
 
@@ -81,15 +377,214 @@ pub struct AppState {
 
     /// Registry of active tunnel sessions, keyed by session ID.
     pub sessions: Arc<DashMap<String, TunnelSession>>,
+
+    /// Connection IDs watching each session, keyed by session ID.
+    pub observers: Arc<DashMap<String, Vec<String>>>,
+
+    /// `(session_id, observer_id)` pairs awaiting the controller's consent.
+    pub observe_requests: Arc<DashSet<(String, String)>>,
+
+    /// Accept loops of public port listeners, keyed by session ID.
+    pub exposures: Arc<DashMap<String, tokio::task::AbortHandle>>,
+
+    /// Sessions served by the HTTP ingress, keyed by lowercase hostname.
+    pub http_routes: Arc<DashMap<String, String>>,
+
+    /// Sessions served by the TLS ingress, keyed by lowercase SNI hostname.
+    pub tls_routes: Arc<DashMap<String, String>>,
+
+    /// Persisted files subject to the retention policy.
+    pub retention: Arc<Retention>,
+
+    /// Audit trail of registrations and tunnel events.
+    pub audit: Arc<AuditLog>,
+
+    /// Known agents, issued tokens and session history.
+    pub db: Arc<Database>,
+
+    /// Agent IDs and tokens refused by admins.
+    pub bans: Arc<BanList>,
+
+    /// Membership in a relay cluster, when `[cluster]` is configured.
+    pub cluster: Option<Arc<Cluster>>,
+
+    /// Probes waiting for the agent's answer, keyed by server request ID.
+    pub probes: Arc<DashMap<String, PendingProbe>>,
+
+    /// Unredeemed invitations, keyed by token.
+    pub invites: Arc<DashMap<String, Invite>>,
+
+    /// Connections waiting for the decision on an access request, keyed
+    /// by `access_id`.
+    pub access_waiters: Arc<DashMap<String, String>>,
+
+    /// Failed authentication attempts since startup.
+    pub auth_failures: Arc<AuthFailures>,
+
+    /// Bandwidth shapers with their rate and burst, keyed by identity, or
+    /// by agent ID for agents without a token. They outlive the agent's
+    /// connection, so reconnecting does not refill the burst.
+    pub shapers: Arc<DashMap<String, RatedShaper>>,
+
+    /// Changes the log filter at runtime.
+    pub log_filter: LogFilter,
 }
 
 impl AppState {
     /// Creates a new empty application state with all registries initialized.
-    pub fn new() -> Self {
+    pub fn new(config: ServerConfig) -> Self {
         Self {
+            config: Arc::new(config),
             agents: Arc::new(DashMap::new()),
             connections: Arc::new(DashMap::new()),
             sessions: Arc::new(DashMap::new()),
+            observers: Arc::new(DashMap::new()),
+            observe_requests: Arc::new(DashSet::new()),
+            exposures: Arc::new(DashMap::new()),
+            http_routes: Arc::new(DashMap::new()),
+            tls_routes: Arc::new(DashMap::new()),
+            retention: Arc::new(Retention::default()),
+            audit: Arc::new(AuditLog::disabled()),
+            db: Arc::new(Database::in_memory()),
+            bans: Arc::new(BanList::default()),
+            cluster: None,
+            probes: Arc::new(DashMap::new()),
+            invites: Arc::new(DashMap::new()),
+            access_waiters: Arc::new(DashMap::new()),
+            auth_failures: Arc::new(AuthFailures::default()),
+            shapers: Arc::new(DashMap::new()),
+            log_filter: LogFilter::default(),
+        }
+    }
+
+    /// Records `event` in the audit log and the session history.
+    pub fn record(&self, event: AuditEvent) {
+        self.db.record(&event);
+        self.audit.record(event);
+    }
+
+    /// Looks up the principal owning `token`, whether it is configured in
+    /// `[[tokens]]` or was issued through the admin API.
+    pub fn authenticate(&self, token: &str) -> Option<Principal> {
+        auth::authenticate(&self.config, token).or_else(|| self.db.authenticate(token))
+    }
+
+    /// Returns the rate, burst and shaper of agent `agent_id` registered as
+    /// `identity`, or `None` if it is not shaped. Agents of one identity share a shaper,
+    /// sized by their token's overrides in `[[tokens]]` or the database.
+    /// Shapers no agent holds are dropped once their bucket is full again.
+    pub fn agent_shaper(&self, identity: Option<&str>, agent_id: &str) -> Option<RatedShaper> {
+        let now = Instant::now();
+        self.shapers
+            .retain(|_, (_, shaper)| Arc::strong_count(shaper) > 1 || !shaper.is_full(now));
+        let overrides = identity
+            .and_then(|name| {
+                self.config
+                    .token_shaping(name)
+                    .or_else(|| self.db.token_shaping(name))
+            })
+            .unwrap_or_default();
+        let limits = self.config.agent_shaping(overrides)?;
+        let key = match identity {
+            Some(name) => format!("identity:{}", name),
+            None => format!("agent:{}", agent_id),
+        };
+        let mut entry = self
+            .shapers
+            .entry(key)
+            .or_insert_with(|| (limits, Arc::new(Shaper::new(limits.0, limits.1))));
+        if entry.0 != limits {
+            *entry = (limits, Arc::new(Shaper::new(limits.0, limits.1)));
+        }
+        Some(entry.clone())
+    }
+
+    /// Counts and logs a failed authentication attempt on QUIC connection
+    /// `conn_id`, by its remote address.
+    pub fn auth_failed(&self, conn_id: &str, kind: AuthFailure) {
+        if let Some(c) = self.connections.get(conn_id) {
+            self.auth_failures
+                .record(kind, c.conn.remote_address().ip());
+        }
+    }
+
+    /// Identity name of connection `conn_id`, if it registered with a token.
+    pub fn identity(&self, conn_id: &str) -> Option<String> {
+        self.connections
+            .get(conn_id)
+            .and_then(|c| c.principal.as_ref().map(|p| p.name.clone()))
+    }
+
+    /// Number of sessions, tunnels and exposed ports alike, held by `agent_id`.
+    pub fn tunnel_count(&self, agent_id: &str) -> usize {
+        self.sessions
+            .iter()
+            .filter(|s| s.agent_id == agent_id)
+            .count()
+    }
+
+    /// Bytes relayed for `agent_id` since it registered: its ended sessions
+    /// plus those still open.
+    pub fn bytes_relayed(&self, agent: &AgentInfo, agent_id: &str) -> u64 {
+        let open: u64 = self
+            .sessions
+            .iter()
+            .filter(|s| s.agent_id == agent_id)
+            .map(|s| s.buffers.relayed())
+            .sum();
+        agent.usage.relayed.load(Ordering::Relaxed) + open
+    }
+
+    /// Credits an ended session's traffic to its agent, if still registered.
+    pub fn session_ended(&self, session: &TunnelSession) {
+        session.finish_span();
+        if let Some(agent) = self.agents.get(&session.agent_id) {
+            agent.usage.add_relayed(session.buffers.relayed());
+        }
+    }
+
+    /// Services advertised by the agent `agent_id`, on this relay or
+    /// another relay of the cluster.
+    pub fn agent_services(&self, agent_id: &str) -> Option<Vec<ServiceInfo>> {
+        if let Some(agent) = self.agents.get(agent_id) {
+            return Some(agent.services.clone());
+        }
+        let cluster = self.cluster.as_ref()?;
+        cluster.agents.get(agent_id).map(|a| a.services.clone())
+    }
+
+    /// Resolves a `Connect` target to an agent ID.
+    ///
+    /// An exact agent ID always wins; otherwise `target` is matched
+    /// case-insensitively against registered agent names. Agents on other
+    /// relays of the cluster count too.
+    pub fn resolve_agent(&self, target: &str) -> Result<String, ResolveError> {
+        let remote = self.cluster.as_ref().map(|c| &c.agents);
+        if self.agents.contains_key(target) || remote.is_some_and(|r| r.contains_key(target)) {
+            return Ok(target.to_string());
+        }
+        let named = |name: Option<&str>| name.is_some_and(|n| n.eq_ignore_ascii_case(target));
+        let mut matches: Vec<String> = self
+            .agents
+            .iter()
+            .filter(|a| named(a.name.as_deref()))
+            .map(|a| a.key().clone())
+            .collect();
+        if let Some(remote) = remote {
+            matches.extend(
+                remote
+                    .iter()
+                    .filter(|a| named(a.name.as_deref()) && !self.agents.contains_key(a.key()))
+                    .map(|a| a.key().clone()),
+            );
+        }
+        match matches.len() {
+            0 => Err(ResolveError::NotFound),
+            1 => Ok(matches.remove(0)),
+            _ => {
+                matches.sort();
+                Err(ResolveError::Ambiguous(matches))
+            }
         }
     }
 }
@@ -0,0 +1,386 @@
+//! # Target Policy
+//!
+//! Restricts the targets controllers may ask an agent to dial, for relays
+//! operated on behalf of third parties. Rules come from the `[targets]`
+//! table and are checked on every `Connect` for an agent of this relay:
+//!
+//! ```toml
+//! [targets]
+//! deny_private = true
+//! deny = ["169.254.169.254", "*.internal.example.com"]
+//!
+//! [[targets.allow]]
+//! agents = ["tag:customer=acme"]
+//! hosts = ["10.20.0.0/16", "app.acme.local", "unix:/run/app.sock"]
+//! ```
+//!
+//! A target listed in `deny` is always refused. When `[[targets.allow]]`
+//! rules match the agent (same patterns as the `agents` of `[[acl]]`),
+//! only the hosts they list are accepted, private or not. Otherwise
+//! `deny_private` refuses loopback, private, link-local, shared and
+//! unspecified addresses, `localhost` and Unix sockets.
+//!
+//! The agent resolves host names itself, so a name that points to a
+//! private address on the agent's network passes `deny_private`; list
+//! allowed hosts explicitly where that matters.
+//!
+//! Addresses are compared in the form the agent's resolver reads them:
+//! `127.1`, `2130706433`, `0x7f.1` and `017700000001` are all 127.0.0.1,
+//! and `::ffff:10.0.0.1` is 10.0.0.1.
+//!
+//! ## Host Patterns
+//!
+//! - `10.0.0.0/8`, `fd00::1` — an address range or a single address
+//! - `*.example.com`         — any name below `example.com`
+//! - `unix:<path>`           — a Unix socket on the agent
+//! - anything else           — a host name, compared case-insensitively
+
+use crate::acl;
+use crate::auth::Principal;
+use crate::ipfilter::Cidr;
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::str::FromStr;
+use tunnel_protocol::normalize_host;
+
+/// One entry of `hosts` or `deny`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum HostPattern {
+    Range(Cidr),
+    Suffix(String),
+    Name(String),
+    Socket(String),
+}
+
+impl HostPattern {
+    fn matches(&self, target: &Target) -> bool {
+        match (self, target) {
+            (Self::Range(cidr), Target::Host(host)) => match host_ip(host) {
+                Some(HostIp::Ip(ip)) => cidr.contains(ip),
+                _ => false,
+            },
+            (Self::Suffix(suffix), Target::Host(host)) => host
+                .to_ascii_lowercase()
+                .strip_suffix(suffix.as_str())
+                .is_some_and(|label| label.ends_with('.') && label.len() > 1),
+            (Self::Name(name), Target::Host(host)) => host.eq_ignore_ascii_case(name),
+            (Self::Socket(path), Target::Socket(socket)) => path == socket,
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for HostPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("Empty host pattern".to_string());
+        }
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Self::Socket(path.to_string()));
+        }
+        if let Some(suffix) = s.strip_prefix("*.") {
+            return Ok(Self::Suffix(suffix.to_ascii_lowercase()));
+        }
+        let host = normalize_host(s);
+        if host.parse::<IpAddr>().is_ok() || host.contains('/') {
+            return host.parse().map(Self::Range);
+        }
+        Ok(Self::Name(host.to_ascii_lowercase()))
+    }
+}
+
+impl TryFrom<String> for HostPattern {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Hosts some agents may be asked to reach.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TargetAllow {
+    /// Patterns matched against the agent's ID, identity or tags.
+    pub agents: Vec<String>,
+
+    /// Targets the matching agents may dial.
+    pub hosts: Vec<HostPattern>,
+}
+
+/// Rules from the `[targets]` table. The defaults allow every target.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TargetPolicy {
+    /// Refuse private and loopback addresses, `localhost` and Unix
+    /// sockets unless an allow rule lists them.
+    pub deny_private: bool,
+
+    /// Targets always refused.
+    pub deny: Vec<HostPattern>,
+
+    /// Per-agent allow lists.
+    pub allow: Vec<TargetAllow>,
+}
+
+/// What a `Connect` asks the agent to dial.
+enum Target<'a> {
+    Host(&'a str),
+    Socket(&'a str),
+}
+
+impl TargetPolicy {
+    /// Returns `true` if the agent `agent_id` (registered as `agent` with
+    /// `agent_tags`) may be asked to dial `remote_host`, or the Unix
+    /// socket `remote_socket` when one is given.
+    pub fn permits(
+        &self,
+        agent_id: &str,
+        agent: Option<&Principal>,
+        agent_tags: &[String],
+        remote_host: &str,
+        remote_socket: Option<&str>,
+    ) -> bool {
+        let target = match remote_socket {
+            Some(path) => Target::Socket(path),
+            None => Target::Host(normalize_host(remote_host)),
+        };
+        if self.deny.iter().any(|p| p.matches(&target)) {
+            return false;
+        }
+        let mut rules = self
+            .allow
+            .iter()
+            .filter(|rule| {
+                rule.agents
+                    .iter()
+                    .any(|p| acl::matches_agent(p, agent_id, agent, agent_tags))
+            })
+            .peekable();
+        if rules.peek().is_some() {
+            return rules.any(|rule| rule.hosts.iter().any(|p| p.matches(&target)));
+        }
+        !(self.deny_private && is_private(&target))
+    }
+}
+
+/// How a target host reads as an address.
+enum HostIp {
+    Ip(IpAddr),
+    /// Numeric, but no address `inet_aton` would accept either.
+    Malformed,
+}
+
+/// Reads `host` as an address the way the agent's resolver would, with
+/// IPv4-mapped IPv6 addresses taken as IPv4. `None` for host names.
+fn host_ip(host: &str) -> Option<HostIp> {
+    let host = host.trim_end_matches('.');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Some(HostIp::Ip(ip.to_canonical()));
+    }
+    // `inet_aton` forms start every part with a digit; a name like
+    // `1password.com` has a part that does not parse as a number.
+    let parts: Vec<&str> = host.split('.').collect();
+    if !parts
+        .iter()
+        .all(|p| p.starts_with(|c: char| c.is_ascii_digit()))
+    {
+        return None;
+    }
+    if !parts.iter().all(|p| {
+        p.chars()
+            .all(|c| c.is_ascii_hexdigit() || c == 'x' || c == 'X')
+    }) {
+        return None;
+    }
+    Some(match inet_aton(&parts) {
+        Some(ip) => HostIp::Ip(IpAddr::V4(ip)),
+        None => HostIp::Malformed,
+    })
+}
+
+/// Parses one to four dot-separated parts, each decimal, octal (leading
+/// `0`) or hex (`0x`), the last filling the remaining bytes.
+fn inet_aton(parts: &[&str]) -> Option<std::net::Ipv4Addr> {
+    if parts.is_empty() || parts.len() > 4 {
+        return None;
+    }
+    let mut values = Vec::with_capacity(parts.len());
+    for part in parts {
+        let value = if let Some(hex) = part.strip_prefix("0x").or_else(|| part.strip_prefix("0X")) {
+            if hex.is_empty() {
+                0
+            } else {
+                u32::from_str_radix(hex, 16).ok()?
+            }
+        } else if part.len() > 1 && part.starts_with('0') {
+            u32::from_str_radix(&part[1..], 8).ok()?
+        } else {
+            part.parse::<u32>().ok()?
+        };
+        values.push(value);
+    }
+    let (last, leading) = values.split_last()?;
+    if leading.iter().any(|&v| v > 0xff) {
+        return None;
+    }
+    let last_bits = 8 * (4 - leading.len() as u32);
+    if last_bits < 32 && *last >> last_bits != 0 {
+        return None;
+    }
+    let address = leading
+        .iter()
+        .enumerate()
+        .fold(*last, |acc, (i, &v)| acc | v << (24 - 8 * i as u32));
+    Some(std::net::Ipv4Addr::from(address))
+}
+
+/// Whether `target` stays on the agent's machine or its private network.
+/// Numeric hosts that are no valid address are counted as private, since
+/// some resolver might still read them as one.
+fn is_private(target: &Target) -> bool {
+    let host = match target {
+        Target::Socket(_) => return true,
+        Target::Host(host) => host.trim_end_matches('.').to_ascii_lowercase(),
+    };
+    if host == "localhost" || host.ends_with(".localhost") {
+        return true;
+    }
+    let ip = match host_ip(&host) {
+        Some(HostIp::Ip(ip)) => Ok(ip),
+        Some(HostIp::Malformed) => return true,
+        None => Err(()),
+    };
+    match ip {
+        Ok(IpAddr::V4(ip)) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // Shared address space (100.64.0.0/10)
+                || (a == 100 && b & 0xc0 == 64)
+        }
+        Ok(IpAddr::V6(ip)) => {
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                // Unique local (fc00::/7) and link-local (fe80::/10)
+                || first & 0xfe00 == 0xfc00
+                || first & 0xffc0 == 0xfe80
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(list: &[&str]) -> Vec<HostPattern> {
+        list.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn deny_private_refuses_local_targets() {
+        let policy = TargetPolicy {
+            deny_private: true,
+            ..TargetPolicy::default()
+        };
+        let permits = |host: &str| policy.permits("A3F8-B2C1", None, &[], host, None);
+
+        for host in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.0.10",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "[::1]",
+            "fd12::1",
+            "fe80::1",
+            "::ffff:10.0.0.1",
+            "localhost",
+            "api.localhost",
+        ] {
+            assert!(!permits(host), "{} should be refused", host);
+        }
+        assert!(permits("203.0.113.7"));
+        assert!(permits("example.com"));
+        assert!(!policy.permits("A3F8-B2C1", None, &[], "", Some("/run/app.sock")));
+        assert!(TargetPolicy::default().permits("A3F8-B2C1", None, &[], "127.0.0.1", None));
+    }
+
+    #[test]
+    fn numeric_forms_of_private_addresses_are_refused() {
+        let policy = TargetPolicy {
+            deny_private: true,
+            deny: patterns(&["203.0.113.0/24"]),
+            ..TargetPolicy::default()
+        };
+        let permits = |host: &str| policy.permits("A3F8-B2C1", None, &[], host, None);
+
+        for host in [
+            // 127.0.0.1 in the forms inet_aton accepts
+            "127.1",
+            "127.0.1",
+            "2130706433",
+            "0x7f000001",
+            "0x7f.1",
+            "0x7F.0.0.1",
+            "017700000001",
+            "0177.0.0.1",
+            "127.1.",
+            // 10.0.0.1 and 192.168.1.1
+            "10.1",
+            "167772161",
+            "0300.0250.1.1",
+            // IPv4-mapped IPv6
+            "::ffff:127.0.0.1",
+            "[::ffff:7f00:1]",
+            "::ffff:192.168.0.1",
+            // Numeric but no address: refused rather than guessed
+            "256.1",
+            "1.2.3.4.5",
+            "09.1",
+        ] {
+            assert!(!permits(host), "{} should be refused", host);
+        }
+        // The deny list matches the same forms.
+        assert!(!permits("3405803777"));
+        assert!(!permits("::ffff:203.0.113.1"));
+        assert!(permits("3405803521"));
+        assert!(permits("1password.com"));
+        assert!(permits("1e100.net"));
+    }
+
+    #[test]
+    fn allow_rules_restrict_matching_agents_only() {
+        let policy = TargetPolicy {
+            deny_private: true,
+            deny: patterns(&["10.20.0.99"]),
+            allow: vec![TargetAllow {
+                agents: vec!["tag:customer=acme".to_string()],
+                hosts: patterns(&["10.20.0.0/16", "*.acme.example", "unix:/run/app.sock"]),
+            }],
+        };
+        let acme = vec!["customer=acme".to_string()];
+        let permits = |tags: &[String], host: &str, socket: Option<&str>| {
+            policy.permits("A3F8-B2C1", None, tags, host, socket)
+        };
+
+        assert!(permits(&acme, "10.20.3.4", None));
+        assert!(permits(&acme, "DB.Acme.Example", None));
+        assert!(permits(&acme, "", Some("/run/app.sock")));
+        assert!(!permits(&acme, "acme.example", None));
+        assert!(!permits(&acme, "203.0.113.7", None));
+        assert!(!permits(&acme, "10.20.0.99", None));
+        // Agents no rule names fall back to deny_private.
+        assert!(permits(&[], "203.0.113.7", None));
+        assert!(!permits(&[], "10.20.3.4", None));
+    }
+}
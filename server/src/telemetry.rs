@@ -0,0 +1,191 @@
+//! # Telemetry
+//!
+//! Sets up the `tracing` subscriber: logs filtered by `RUST_LOG`, either
+//! human-readable or, with `--log-format json`, one JSON object per line
+//! for Loki or ELK, plus, when built with the `otel` feature and
+//! `OTEL_EXPORTER_OTLP_ENDPOINT` is set, an OTLP exporter that ships spans
+//! to a collector such as Grafana Tempo.
+//!
+//! The server opens a `connection` span per QUIC connection, a `session`
+//! span per tunnel and a `stream` span per data stream, with byte counts
+//! recorded as attributes when they finish.
+//!
+//! The filter can be replaced while the server runs through [`LogFilter`],
+//! e.g. to turn on debug logging for a live problem:
+//! `PUT /api/admin/log-level` with `{"level": "debug"}`.
+
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+/// Filter used when `RUST_LOG` is not set.
+const DEFAULT_FILTER: &str = "tunnel_server=info";
+
+/// Levels accepted on their own by [`LogFilter::set`].
+const LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+/// Handle on the installed log filter. Without one, e.g. in tests, the
+/// filter cannot be changed.
+#[derive(Clone, Default)]
+pub struct LogFilter {
+    handle: Option<reload::Handle<EnvFilter, Registry>>,
+}
+
+impl LogFilter {
+    /// Returns the current filter directives.
+    pub fn current(&self) -> Option<String> {
+        self.handle
+            .as_ref()
+            .and_then(|h| h.with_current(|f| f.to_string()).ok())
+    }
+
+    /// Replaces the filter. A bare level such as `debug` applies to the
+    /// server's own logs; anything else is read as `RUST_LOG` directives,
+    /// e.g. `tunnel_server=debug,quinn=info`. Returns the new filter.
+    pub fn set(&self, level: &str) -> Result<String, String> {
+        let Some(handle) = &self.handle else {
+            return Err("Logging is not initialized".to_string());
+        };
+        let level = level.trim();
+        let directives = if LEVELS.contains(&level.to_ascii_lowercase().as_str()) {
+            format!("tunnel_server={}", level.to_ascii_lowercase())
+        } else {
+            level.to_string()
+        };
+        let filter = EnvFilter::try_new(&directives)
+            .map_err(|e| format!("Invalid log level '{}': {}", level, e))?;
+        let current = filter.to_string();
+        handle.reload(filter).map_err(|e| e.to_string())?;
+        Ok(current)
+    }
+}
+
+/// How log lines are written to stdout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines (the default).
+    Text,
+    /// One JSON object per line. The event's fields sit under `fields`,
+    /// and the enclosing spans, with their `session_id` and `agent_id`,
+    /// under `span` and `spans`.
+    Json,
+}
+
+impl LogFormat {
+    /// Reads `--log-format` from the command line, else `TUNNEL_LOG_FORMAT`.
+    pub fn from_args() -> Result<Self, String> {
+        match crate::config::cli_arg("--log-format")
+            .or_else(|| std::env::var("TUNNEL_LOG_FORMAT").ok())
+            .as_deref()
+        {
+            None | Some("text") => Ok(Self::Text),
+            Some("json") => Ok(Self::Json),
+            Some(other) => Err(format!(
+                "Unknown log format '{}': expected 'text' or 'json'",
+                other
+            )),
+        }
+    }
+}
+
+/// Flushes exported spans when dropped at shutdown.
+pub struct TelemetryGuard {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::TracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider.take() {
+            let _ = provider.shutdown();
+        }
+    }
+}
+
+/// Installs the global subscriber and returns the handle that changes its
+/// filter. Must be called from within the Tokio runtime.
+pub fn init(format: LogFormat) -> (TelemetryGuard, LogFilter) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| DEFAULT_FILTER.into());
+    let (filter, handle) = reload::Layer::new(filter);
+    let log_filter = LogFilter {
+        handle: Some(handle),
+    };
+    let endpoint = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok();
+    let text = (format == LogFormat::Text).then(tracing_subscriber::fmt::layer);
+    let json = (format == LogFormat::Json).then(|| {
+        tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+    });
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(text)
+        .with(json);
+
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry::trace::TracerProvider as _;
+
+        let provider = endpoint.and_then(|endpoint| {
+            match opentelemetry_otlp::SpanExporter::builder()
+                .with_tonic()
+                .build()
+            {
+                Ok(exporter) => Some((endpoint, exporter)),
+                Err(e) => {
+                    eprintln!("Failed to create OTLP exporter: {}", e);
+                    None
+                }
+            }
+            .map(|(endpoint, exporter)| {
+                eprintln!("Exporting traces to {}", endpoint);
+                opentelemetry_sdk::trace::TracerProvider::builder()
+                    .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+                    .with_resource(opentelemetry_sdk::Resource::new(vec![
+                        opentelemetry::KeyValue::new("service.name", "tunnel-server"),
+                    ]))
+                    .build()
+            })
+        });
+        let layer = provider
+            .as_ref()
+            .map(|p| tracing_opentelemetry::layer().with_tracer(p.tracer("tunnel-server")));
+        registry.with(layer).init();
+        (TelemetryGuard { provider }, log_filter)
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        registry.init();
+        if endpoint.is_some() {
+            tracing::warn!(
+                "OTEL_EXPORTER_OTLP_ENDPOINT is set but this build lacks the `otel` feature"
+            );
+        }
+        (TelemetryGuard {}, log_filter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bare_levels_apply_to_the_server() {
+        let (_layer, handle) = reload::Layer::<_, Registry>::new(EnvFilter::new(DEFAULT_FILTER));
+        let filter = LogFilter {
+            handle: Some(handle),
+        };
+
+        assert_eq!(filter.current().as_deref(), Some(DEFAULT_FILTER));
+        assert_eq!(filter.set(" DEBUG ").unwrap(), "tunnel_server=debug");
+        assert_eq!(
+            filter.set("tunnel_server=trace,quinn=info").unwrap(),
+            filter.current().unwrap()
+        );
+        assert!(filter.set("tunnel_server=loud").is_err());
+        assert!(LogFilter::default().set("debug").is_err());
+    }
+}
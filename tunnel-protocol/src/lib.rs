@@ -1,5 +1,12 @@
+use bincode::Options;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+use ring::{digest, hmac};
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "tls")]
+pub mod tls;
+
 /// Type for the single byte tag that precedes the payload.
 pub type MessageTag = u8;
 
@@ -16,6 +23,131 @@ pub const TAG_DATA: MessageTag = 0x0A;
 pub const TAG_PING: MessageTag = 0x0B;
 pub const TAG_PONG: MessageTag = 0x0C;
 pub const TAG_ERROR: MessageTag = 0x0D;
+pub const TAG_LIST_AGENTS: MessageTag = 0x0E;
+pub const TAG_AGENT_LIST: MessageTag = 0x0F;
+pub const TAG_TUNNEL_REJECT: MessageTag = 0x10;
+pub const TAG_CONNECT_FAILED: MessageTag = 0x11;
+pub const TAG_OBSERVE_REQUEST: MessageTag = 0x12;
+pub const TAG_OBSERVE_CONSENT: MessageTag = 0x13;
+pub const TAG_OBSERVE_REPLY: MessageTag = 0x14;
+pub const TAG_SESSION_STATS: MessageTag = 0x15;
+pub const TAG_OBSERVE_END: MessageTag = 0x16;
+pub const TAG_EXPOSE: MessageTag = 0x17;
+pub const TAG_EXPOSE_READY: MessageTag = 0x18;
+pub const TAG_EXPOSE_HTTP: MessageTag = 0x19;
+pub const TAG_EXPOSE_HTTP_READY: MessageTag = 0x1A;
+pub const TAG_SESSION_PING: MessageTag = 0x1B;
+pub const TAG_SESSION_PONG: MessageTag = 0x1C;
+pub const TAG_STREAM_OPEN_FAILED: MessageTag = 0x1D;
+pub const TAG_REGISTER_CHALLENGE: MessageTag = 0x1E;
+pub const TAG_REGISTER_PROOF: MessageTag = 0x1F;
+pub const TAG_RELAY_HELLO: MessageTag = 0x20;
+pub const TAG_UNREGISTER: MessageTag = 0x21;
+pub const TAG_REGISTER_CONTROLLER: MessageTag = 0x22;
+pub const TAG_PROBE_TARGET: MessageTag = 0x23;
+pub const TAG_PROBE_RESULT: MessageTag = 0x24;
+pub const TAG_CREATE_INVITE: MessageTag = 0x25;
+pub const TAG_INVITE_CREATED: MessageTag = 0x26;
+pub const TAG_EXPOSE_TLS: MessageTag = 0x27;
+pub const TAG_EXPOSE_TLS_READY: MessageTag = 0x28;
+pub const TAG_CONNECT_SERVICE: MessageTag = 0x29;
+pub const TAG_REQUEST_ACCESS: MessageTag = 0x2A;
+pub const TAG_ACCESS_REQUESTED: MessageTag = 0x2B;
+pub const TAG_DECIDE_ACCESS: MessageTag = 0x2C;
+pub const TAG_ACCESS_STATUS: MessageTag = 0x2D;
+pub const TAG_ACCESS_UPDATE: MessageTag = 0x2E;
+
+/// Largest control frame (tag plus payload) either side accepts.
+pub const MAX_CONTROL_FRAME: usize = 256 * 1024;
+
+/// Longest accepted ID (agent, session, stream, request or connection ID).
+pub const MAX_ID_LEN: usize = 64;
+
+/// Longest accepted registration token.
+pub const MAX_TOKEN_LEN: usize = 512;
+
+/// Longest accepted agent name or tag.
+pub const MAX_LABEL_LEN: usize = 128;
+
+/// Longest accepted DNS name, as limited by RFC 1035.
+pub const MAX_HOSTNAME_LEN: usize = 253;
+
+/// Most tags an agent may register with.
+pub const MAX_TAGS: usize = 32;
+
+/// Most services an agent may advertise.
+pub const MAX_SERVICES: usize = 32;
+
+/// Longest accepted Unix socket path (the `sun_path` size on macOS).
+pub const MAX_SOCKET_PATH: usize = 104;
+
+/// Longest accepted free-text message or reason.
+pub const MAX_TEXT_LEN: usize = 1024;
+
+/// Longest per-tunnel target connect timeout a controller may request.
+pub const MAX_CONNECT_TIMEOUT_MS: u32 = 300_000;
+
+/// Most ports one tunnel may forward besides its `remote_port`.
+pub const MAX_EXTRA_PORTS: usize = 64;
+
+/// Longest lifetime a controller may give a tunnel with `ttl_secs`.
+pub const MAX_TUNNEL_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Longest an invitation from `CreateInvite` may stay valid.
+pub const MAX_INVITE_TTL_SECS: u64 = 24 * 60 * 60;
+
+/// How long a `RequestAccess` waits for the agent's decision before it
+/// expires.
+pub const ACCESS_REQUEST_TTL_MS: u64 = 7 * 24 * 60 * 60 * 1000;
+
+/// mDNS service type relays advertise on the local network with.
+pub const MDNS_SERVICE_TYPE: &str = "_tunnel-relay._udp.local.";
+
+/// Length of the nonce in `RegisterChallenge`.
+pub const CHALLENGE_LEN: usize = 32;
+
+/// Length of the HMAC-SHA256 in `RegisterProof`.
+pub const PROOF_LEN: usize = 32;
+
+/// Length of an Ed25519 public key in `Register`.
+pub const PUBLIC_KEY_LEN: usize = 32;
+
+/// Length of the Ed25519 signature in `RegisterProof` for a key-backed ID.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// QUIC send priority of the control stream on both ends. Data streams of
+/// [`TrafficClass::Interactive`] tunnels keep the default of 0, so control
+/// messages such as heartbeats and tunnel setup are sent ahead of all data
+/// sharing the connection.
+pub const CONTROL_STREAM_PRIORITY: i32 = 1;
+
+/// Type for the QUIC application error code used when resetting a data stream.
+pub type ResetCode = u32;
+
+/// The relay could not buffer more data for the stream's session in time:
+/// the peer stopped consuming and the session's memory cap was reached.
+pub const RESET_BUFFER_LIMIT: ResetCode = 0x01;
+
+/// The session already has the maximum number of concurrent streams.
+pub const RESET_STREAM_LIMIT: ResetCode = 0x02;
+
+/// Another open stream of the session already uses the stream ID.
+pub const RESET_DUPLICATE_STREAM: ResetCode = 0x03;
+
+/// Type for the QUIC application error code used when closing a connection.
+pub type CloseCode = u32;
+
+/// The peer did not drain its control messages and its outbound queue
+/// stayed full past the server's slow-consumer timeout.
+pub const CLOSE_SLOW_CONSUMER: CloseCode = 0x01;
+
+/// Another connection registered the same fixed agent ID and proved its
+/// key, replacing this one.
+pub const CLOSE_REPLACED: CloseCode = 0x02;
+
+/// An admin banned the client's agent ID or token; the close reason holds
+/// the ban's reason.
+pub const CLOSE_BANNED: CloseCode = 0x03;
 
 /// Control messages in the tunnel protocol.
 ///
@@ -23,25 +155,126 @@ pub const TAG_ERROR: MessageTag = 0x0D;
 /// `Data` messages are handled separately as raw bytes.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum ControlMessage {
-    Register,
+    Register {
+        /// Optional credential identifying this client to the server.
+        token: Option<String>,
+        /// Free-form labels such as `env=prod` used to group and filter agents.
+        tags: Vec<String>,
+        /// Human-friendly alias controllers may use instead of the agent ID.
+        name: Option<String>,
+        /// Fixed agent ID to register as instead of a random one. The
+        /// server answers with `RegisterChallenge` and only grants the ID
+        /// once `RegisterProof` shows the agent holds its pre-shared key.
+        agent_id: Option<String>,
+        /// Client software version, shown in the server's agent list.
+        version: Option<String>,
+        /// Named services on the agent's side, listed to controllers so
+        /// they can pick a target by name.
+        services: Vec<ServiceInfo>,
+        /// Ed25519 public key of the agent's identity. The server grants
+        /// the ID [`key_agent_id`] derives from it once `RegisterProof`
+        /// carries a signature of its challenge by the matching key.
+        /// Ignored when `agent_id` is set.
+        public_key: Option<Vec<u8>>,
+    },
     RegisterOk {
-        agent_id: String,
+        /// The ID controllers reach this client by; `None` when its token
+        /// may only open tunnels, so it was not registered as an agent.
+        agent_id: Option<String>,
+        /// Server wall-clock time, milliseconds since the Unix epoch.
+        server_time_ms: u64,
+        /// Most bytes the server buffers per data stream. Clients cap
+        /// their relay chunk size to it, as larger chunks only wait for
+        /// QUIC flow control.
+        max_chunk_bytes: u32,
+        /// Server software version.
+        server_version: String,
     },
     Connect {
+        /// The agent ID (e.g., "A3F8-B2C1") or registered name of the target.
         target_id: String,
         remote_host: String,
         remote_port: u16,
+        /// Client-chosen ID echoed back in `TunnelReady`, so concurrent
+        /// connects can be told apart.
+        request_id: String,
+        /// Unix socket on the agent to forward to instead of
+        /// `remote_host:remote_port`, which are then ignored.
+        remote_socket: Option<String>,
+        /// One-time token from the agent's pairing code, passed on in
+        /// `TunnelRequest` so the agent can accept without asking.
+        pairing_token: Option<String>,
+        /// How long the agent may spend connecting to the target for each
+        /// data stream; `None` uses the agent's default.
+        connect_timeout_ms: Option<u32>,
+        /// Identity of the controller, set by a relay forwarding the
+        /// `Connect` to the cluster peer the agent is connected to.
+        /// Ignored from other clients.
+        requester: Option<String>,
+        /// Further ports on `remote_host` the tunnel forwards; each data
+        /// stream names its port in `StreamOpen`.
+        extra_ports: Vec<u16>,
+        /// Invitation token from the agent's `CreateInvite`. It stands in
+        /// for the `connect` scope and ACL rules, for the invited target
+        /// only, and is consumed by the first `Connect` that uses it.
+        invite: Option<String>,
+        /// How long the tunnel stays open once accepted; the server and
+        /// the agent both close it when the time is up.
+        ttl_secs: Option<u64>,
+        /// Reverse SOCKS: the agent serves a SOCKS5 proxy on `remote_port`
+        /// of its loopback addresses and opens a data stream to the
+        /// controller for each of its connections, which the controller
+        /// dials from its own network. `remote_host` is ignored.
+        reverse_socks: bool,
+        /// How the tunnel's data streams are scheduled against other
+        /// streams sharing a connection.
+        traffic_class: TrafficClass,
     },
     TunnelRequest {
         session_id: String,
         remote_host: String,
         remote_port: u16,
+        /// Unix socket target, as in `Connect`.
+        remote_socket: Option<String>,
+        /// Identity name of the controller; `None` if it did not register
+        /// with a token.
+        requester: Option<String>,
+        /// Pairing token from `Connect`.
+        pairing_token: Option<String>,
+        /// Target connect timeout from `Connect`.
+        connect_timeout_ms: Option<u32>,
+        /// Further ports from `Connect`.
+        extra_ports: Vec<u16>,
+        /// Set when the controller redeemed one of the agent's own
+        /// invitations, so the agent accepts without asking.
+        invited: bool,
+        /// Tunnel lifetime from `Connect`.
+        ttl_secs: Option<u64>,
+        /// Reverse SOCKS mode from `Connect`.
+        reverse_socks: bool,
+        /// Traffic class from `Connect`.
+        traffic_class: TrafficClass,
     },
     TunnelAccept {
         session_id: String,
+        /// Most data streams the agent serves at once in this session;
+        /// `None` if it sets no limit of its own.
+        max_streams: Option<u32>,
     },
     TunnelReady {
         session_id: String,
+        /// The `request_id` of the `Connect` this session answers.
+        request_id: String,
+        /// ID of the agent that accepted, resolved from the `Connect`
+        /// target.
+        agent_id: String,
+        /// Ed25519 key the agent proved at registration; `None` for agents
+        /// with a random or pre-shared-key ID.
+        public_key: Option<Vec<u8>>,
+        /// Most data streams the session may have open at once: the lower
+        /// of the relay's and the agent's limits. Further streams are
+        /// refused with `StreamOpenFailed` and `ErrorCode::LimitExceeded`.
+        max_streams: u32,
     },
     TunnelClose {
         session_id: String,
@@ -49,23 +282,556 @@ pub enum ControlMessage {
     StreamOpen {
         session_id: String,
         stream_id: String,
+        /// Which of the tunnel's `extra_ports` the stream goes to; `None`
+        /// for its `remote_port`.
+        remote_port: Option<u16>,
     },
     StreamClose {
         session_id: String,
         stream_id: String,
     },
     Ping,
-    Pong,
+    Pong {
+        /// Server wall-clock time, milliseconds since the Unix epoch.
+        server_time_ms: u64,
+    },
     Error {
+        code: ErrorCode,
+        message: String,
+    },
+    /// Asks the server for connected agents, optionally filtered by tag.
+    ListAgents {
+        tag: Option<String>,
+    },
+    AgentList {
+        agents: Vec<AgentSummary>,
+    },
+    /// Sent by an agent that refuses a `TunnelRequest`.
+    TunnelReject {
+        session_id: String,
+        code: ErrorCode,
+        message: String,
+    },
+    /// Tells a client that its `Connect` or `Expose` with `request_id` failed.
+    ConnectFailed {
+        request_id: String,
+        code: ErrorCode,
         message: String,
     },
+    /// Asks to watch a session's metadata and stats. Requires the observer
+    /// role and the consent of the session's controller.
+    ObserveRequest {
+        session_id: String,
+    },
+    /// Asks a session's controller whether `observer` may watch it.
+    ObserveConsent {
+        session_id: String,
+        /// Connection ID of the requester, echoed in `ObserveReply`.
+        observer_id: String,
+        /// Identity name of the requester.
+        observer: String,
+    },
+    /// The controller's answer to `ObserveConsent`.
+    ObserveReply {
+        session_id: String,
+        observer_id: String,
+        allow: bool,
+    },
+    /// Periodic snapshot pushed to observers of a session.
+    SessionStats {
+        stats: SessionSnapshot,
+    },
+    /// Ends observation. From an observer it stops watching; from the
+    /// controller it revokes every observer; from the server it tells an
+    /// observer why it was dropped.
+    ObserveEnd {
+        session_id: String,
+        reason: String,
+    },
+    /// Asks the relay to listen on a public TCP port and forward each
+    /// connection to `remote_host:remote_port` on this agent. A
+    /// `public_port` of 0 lets the server pick one from its range.
+    Expose {
+        request_id: String,
+        remote_host: String,
+        remote_port: u16,
+        public_port: u16,
+    },
+    /// The relay is listening on `public_port` for the `Expose` with
+    /// `request_id`. `TunnelClose { session_id }` stops it.
+    ExposeReady {
+        request_id: String,
+        session_id: String,
+        public_port: u16,
+    },
+    /// Asks the relay to route HTTP requests whose `Host` header is
+    /// `hostname` to `remote_host:remote_port` on this agent.
+    ExposeHttp {
+        request_id: String,
+        hostname: String,
+        remote_host: String,
+        remote_port: u16,
+    },
+    /// The relay routes `hostname` for the `ExposeHttp` with `request_id`.
+    /// `TunnelClose { session_id }` stops it.
+    ExposeHttpReady {
+        request_id: String,
+        session_id: String,
+        hostname: String,
+    },
+    /// Measures the round trip through the relay to a session's agent.
+    /// Sent by the controller and relayed to the agent, which echoes
+    /// `sent_ms` back in `SessionPong`.
+    SessionPing {
+        session_id: String,
+        /// Controller wall-clock time, milliseconds since the Unix epoch.
+        sent_ms: u64,
+    },
+    /// The agent's answer to `SessionPing`, relayed to the controller.
+    SessionPong {
+        session_id: String,
+        /// The `sent_ms` of the ping being answered.
+        sent_ms: u64,
+    },
+    /// Sent by the agent, and relayed to the controller, when it could not
+    /// connect to the target for a data stream. The stream is then closed.
+    /// `code` is `Timeout` when the connect timeout expired.
+    StreamOpenFailed {
+        session_id: String,
+        stream_id: String,
+        code: ErrorCode,
+        message: String,
+    },
+    /// Sent in reply to a `Register` asking for a fixed agent ID: a random
+    /// nonce the agent must sign with the ID's pre-shared key.
+    RegisterChallenge {
+        nonce: Vec<u8>,
+    },
+    /// The agent's answer to `RegisterChallenge`, computed with
+    /// [`register_proof`], or with [`sign_register`] for a key-backed ID.
+    RegisterProof {
+        proof: Vec<u8>,
+    },
+    /// Opens a link from another relay of the same cluster, which then
+    /// forwards its controllers' tunnels to this relay's agents. Answered
+    /// with `RegisterOk` once `secret` matches the cluster secret.
+    RelayHello {
+        relay_id: String,
+        secret: String,
+    },
+    /// Sent by an agent shutting down cleanly, before it closes the
+    /// connection. The server drops the registration at once and closes
+    /// the agent's sessions, telling their controllers with `TunnelClose`.
+    Unregister,
+    /// Identifies the connection as a controller that only opens tunnels,
+    /// never reachable as an agent. Answered with `RegisterOk` without an
+    /// agent ID.
+    RegisterController {
+        /// Optional credential identifying this client to the server.
+        token: Option<String>,
+        /// Client software version.
+        version: Option<String>,
+    },
+    /// Asks an agent to try a TCP connect to `host:port` and report how it
+    /// went in `ProbeResult`, without opening a tunnel. The server checks
+    /// the controller may connect to `target_id`, replaces `request_id`
+    /// with its own and sets `requester` before passing it on.
+    ProbeTarget {
+        request_id: String,
+        /// Agent ID or registered name of the agent to probe from.
+        target_id: String,
+        host: String,
+        port: u16,
+        /// Identity name of the controller; set by the server.
+        requester: Option<String>,
+    },
+    /// The outcome of a `ProbeTarget`, from the agent or from a server
+    /// that refused to pass the probe on.
+    ProbeResult {
+        request_id: String,
+        /// How long the connect took; `None` when it failed.
+        latency_ms: Option<u64>,
+        /// Why the probe failed; `Timeout` when the connect timed out.
+        code: Option<ErrorCode>,
+        message: Option<String>,
+    },
+    /// Asks the server for a single-use invitation letting any controller
+    /// open one tunnel to `remote_host:remote_port` on this agent within
+    /// `ttl_secs`. Answered with `InviteCreated`.
+    CreateInvite {
+        request_id: String,
+        remote_host: String,
+        remote_port: u16,
+        ttl_secs: u64,
+    },
+    /// The invitation asked for by `CreateInvite`, or why it was refused.
+    InviteCreated {
+        request_id: String,
+        /// The token to pass in `Connect.invite`; `None` when refused.
+        token: Option<String>,
+        /// When the token stops working, milliseconds since the Unix epoch.
+        expires_at_ms: u64,
+        code: Option<ErrorCode>,
+        message: Option<String>,
+    },
+    /// Asks the relay to route TLS connections whose ClientHello names
+    /// `hostname` (SNI) to `remote_host:remote_port` on this agent. The
+    /// relay forwards the raw connection; TLS ends on the agent's side.
+    ExposeTls {
+        request_id: String,
+        hostname: String,
+        remote_host: String,
+        remote_port: u16,
+    },
+    /// The relay routes `hostname` for the `ExposeTls` with `request_id`.
+    /// `TunnelClose { session_id }` stops it.
+    ExposeTlsReady {
+        request_id: String,
+        session_id: String,
+        hostname: String,
+    },
+    /// Opens a tunnel to the service `service_name` that `target_id`
+    /// advertises, without the controller knowing its host and port. The
+    /// server looks the service up and handles it as a `Connect`, answered
+    /// with `TunnelReady` or `ConnectFailed`.
+    ConnectService {
+        request_id: String,
+        /// Agent ID or registered name of the agent.
+        target_id: String,
+        service_name: String,
+        /// Scheduling class of the tunnel's data streams.
+        traffic_class: TrafficClass,
+    },
+    /// Asks the owner of `target_id` for access to `remote_host:remote_port`
+    /// on it. The server keeps the request until the agent decides, even
+    /// while the agent is offline, and answers at once with a pending
+    /// `AccessUpdate` carrying the request's `access_id`.
+    RequestAccess {
+        request_id: String,
+        /// Agent ID or registered name of the agent.
+        target_id: String,
+        remote_host: String,
+        remote_port: u16,
+        /// Why access is wanted, shown to the agent's owner.
+        reason: Option<String>,
+    },
+    /// An access request awaiting this agent's decision. Sent when it is
+    /// made and again each time the agent registers until it is decided.
+    AccessRequested {
+        access_id: String,
+        /// Identity of the controller that asked, if it had a token.
+        requester: Option<String>,
+        remote_host: String,
+        remote_port: u16,
+        reason: Option<String>,
+        /// When it was made, milliseconds since the Unix epoch.
+        requested_at_ms: u64,
+    },
+    /// The agent's decision on an `AccessRequested`. An approval lets the
+    /// controller collect one invitation for the target within `ttl_secs`.
+    DecideAccess {
+        access_id: String,
+        approve: bool,
+        ttl_secs: u64,
+    },
+    /// Asks where the access request `access_id` stands, e.g. after
+    /// reconnecting. Answered with `AccessUpdate`.
+    AccessStatus {
+        request_id: String,
+        access_id: String,
+    },
+    /// Where an access request stands: the answer to `RequestAccess` and
+    /// `AccessStatus`, also pushed to the requesting connection when the
+    /// agent decides. The first update after an approval carries the
+    /// invitation; later ones do not.
+    AccessUpdate {
+        request_id: String,
+        /// `None` when the request was refused before being queued.
+        access_id: Option<String>,
+        state: AccessState,
+        /// Token to pass in `Connect.invite`.
+        invite: Option<String>,
+        /// When the approval runs out, milliseconds since the Unix epoch;
+        /// 0 unless approved.
+        expires_at_ms: u64,
+        code: Option<ErrorCode>,
+        message: Option<String>,
+    },
+}
+
+/// Progress of a `RequestAccess`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessState {
+    /// Waiting for the agent's decision.
+    Pending,
+    Approved,
+    Denied,
+    /// Undecided for [`ACCESS_REQUEST_TTL_MS`], or approved and not used
+    /// in time.
+    Expired,
+}
+
+/// Metadata and counters of a tunnel session, without any payload bytes.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SessionSnapshot {
+    pub session_id: String,
+    pub agent_id: String,
+    pub remote_host: String,
+    pub remote_port: u16,
+    /// Seconds since the session was requested.
+    pub age_secs: u64,
+    /// Data streams currently open.
+    pub streams: u64,
+    /// Bytes held in relay buffers right now.
+    pub buffered_bytes: u64,
+    /// Peak of `buffered_bytes` over the session's lifetime.
+    pub high_water_bytes: u64,
+    /// The session's buffer cap.
+    pub limit_bytes: u64,
+    /// Total bytes relayed in both directions.
+    pub relayed_bytes: u64,
+}
+
+/// A connected agent as reported by `AgentList`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct AgentSummary {
+    pub agent_id: String,
+    pub name: Option<String>,
+    pub tags: Vec<String>,
+    pub services: Vec<ServiceInfo>,
+}
+
+/// A named target an agent advertises, e.g. `ssh` at `127.0.0.1:22`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ServiceInfo {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    /// What controllers need to know to use the service, e.g. how it
+    /// authenticates.
+    #[serde(default)]
+    pub note: Option<String>,
+}
+
+impl ServiceInfo {
+    /// Parses `name=host:port` with an optional `#note`, e.g.
+    /// `grafana=127.0.0.1:3000#SSO login` or `db=[::1]:5432`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid service '{}': expected name=host:port", spec.trim());
+        let (spec, note) = match spec.split_once('#') {
+            Some((spec, note)) => (spec, Some(note.trim().to_string())),
+            None => (spec, None),
+        };
+        let (name, target) = spec.split_once('=').ok_or_else(invalid)?;
+        let (host, port) = target.trim().rsplit_once(':').ok_or_else(invalid)?;
+        let service = Self {
+            name: name.trim().to_string(),
+            host: normalize_host(host).to_string(),
+            port: port.parse().map_err(|_| invalid())?,
+            note: note.filter(|n| !n.is_empty()),
+        };
+        check_service(&service)?;
+        Ok(service)
+    }
+}
+
+/// Scheduling class of a tunnel's data streams. When a connection is
+/// saturated, each end sends the frames of interactive streams before
+/// those of bulk streams, so a large copy through one tunnel does not
+/// stall a shell in another.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TrafficClass {
+    /// Latency-sensitive traffic such as SSH or RDP sessions.
+    #[default]
+    Interactive,
+    /// Throughput traffic such as file transfers or backups, sent only
+    /// when no interactive stream has data waiting.
+    Bulk,
+}
+
+impl TrafficClass {
+    /// QUIC send priority of the class's data streams, below
+    /// [`CONTROL_STREAM_PRIORITY`].
+    pub fn stream_priority(self) -> i32 {
+        match self {
+            Self::Interactive => 0,
+            Self::Bulk => -1,
+        }
+    }
+}
+
+impl std::str::FromStr for TrafficClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interactive" => Ok(Self::Interactive),
+            "bulk" => Ok(Self::Bulk),
+            _ => Err(format!(
+                "Unknown traffic class '{}': expected interactive or bulk",
+                s
+            )),
+        }
+    }
+}
+
+/// Finds the service called `name` among `services`, ignoring case.
+pub fn find_service<'a>(services: &'a [ServiceInfo], name: &str) -> Option<&'a ServiceInfo> {
+    services
+        .iter()
+        .find(|s| s.name.eq_ignore_ascii_case(name.trim()))
+}
+
+/// Returns `true` if `tags` satisfies `filter`.
+///
+/// A filter of the form `key=value` must match a tag exactly, while a bare
+/// `key` matches any tag with that key (e.g., `env` matches `env=prod`).
+pub fn tags_match(tags: &[String], filter: &str) -> bool {
+    tags.iter().any(|tag| {
+        tag == filter || (!filter.contains('=') && tag.split('=').next() == Some(filter))
+    })
+}
+
+/// Machine-readable classification carried by `ControlMessage::Error`,
+/// so clients can react to specific failures without parsing the message.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// Unclassified failure; see the accompanying message.
+    Internal,
+    /// The requested agent is not connected to the server.
+    AgentNotFound,
+    /// The credential was rejected or the access policy denied the request.
+    Unauthorized,
+    /// A name given as `target_id` matches more than one connected agent.
+    AmbiguousAgent,
+    /// A configured maximum (tunnels per agent, streams per session) was reached.
+    LimitExceeded,
+    /// The message was malformed or failed field validation.
+    InvalidMessage,
+    /// The requested public port or hostname is already taken.
+    InUse,
+    /// The target agent did not answer a `TunnelRequest` in time.
+    Timeout,
+    /// The client is older than the server's minimum supported version.
+    UpgradeRequired,
+}
+
+/// Current wall-clock time in milliseconds since the Unix epoch.
+pub fn unix_time_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Estimates how far the server clock is ahead of the local clock, in ms.
+///
+/// `sent_ms` and `received_ms` are local times around a request the server
+/// stamped with `server_ms`; the server is assumed to have stamped it
+/// halfway through the round trip. Negative means the server is behind.
+pub fn estimate_clock_skew_ms(sent_ms: u64, server_ms: u64, received_ms: u64) -> i64 {
+    let midpoint = sent_ms as i64 + (received_ms.saturating_sub(sent_ms) as i64) / 2;
+    server_ms as i64 - midpoint
+}
+
+/// Returns a fresh random nonce for `RegisterChallenge`.
+pub fn register_challenge() -> Vec<u8> {
+    let mut nonce = vec![0; CHALLENGE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .expect("system random number generator failed");
+    nonce
+}
+
+/// Computes the `RegisterProof` for `nonce`: an HMAC-SHA256 keyed with the
+/// agent's pre-shared `key` over the agent ID and the nonce, so a proof
+/// cannot be replayed for another ID or another registration.
+pub fn register_proof(key: &str, agent_id: &str, nonce: &[u8]) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes());
+    hmac::sign(&key, &register_proof_input(agent_id, nonce))
+        .as_ref()
+        .to_vec()
+}
+
+/// Checks a `RegisterProof` in constant time.
+pub fn verify_register_proof(key: &str, agent_id: &str, nonce: &[u8], proof: &[u8]) -> bool {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes());
+    hmac::verify(&key, &register_proof_input(agent_id, nonce), proof).is_ok()
+}
+
+/// Generates a new Ed25519 identity key, PKCS#8-encoded.
+pub fn generate_identity_key() -> Vec<u8> {
+    Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .expect("system random number generator failed")
+        .as_ref()
+        .to_vec()
+}
+
+/// The public key of a PKCS#8 identity key; `None` if it is not a valid
+/// Ed25519 key.
+pub fn identity_public_key(pkcs8: &[u8]) -> Option<Vec<u8>> {
+    let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8).ok()?;
+    Some(key_pair.public_key().as_ref().to_vec())
+}
+
+/// The agent ID backed by an Ed25519 public key: the first 8 bytes of its
+/// SHA-256 as "XXXX-XXXX-XXXX-XXXX". It is longer than a random ID, so the
+/// two never collide, and too long to find another key for.
+pub fn key_agent_id(public_key: &[u8]) -> String {
+    let hash = digest::digest(&digest::SHA256, public_key);
+    let hex: String = hash.as_ref()[..8]
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect();
+    format!(
+        "{}-{}-{}-{}",
+        &hex[..4],
+        &hex[4..8],
+        &hex[8..12],
+        &hex[12..]
+    )
+}
+
+/// Computes the `RegisterProof` of a key-backed ID: an Ed25519 signature
+/// with the PKCS#8 identity key over the agent ID and the nonce. `None` if
+/// the key is not valid.
+pub fn sign_register(pkcs8: &[u8], agent_id: &str, nonce: &[u8]) -> Option<Vec<u8>> {
+    let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8).ok()?;
+    Some(
+        key_pair
+            .sign(&register_proof_input(agent_id, nonce))
+            .as_ref()
+            .to_vec(),
+    )
+}
+
+/// Checks a `RegisterProof` signed with [`sign_register`].
+pub fn verify_register_signature(
+    public_key: &[u8],
+    agent_id: &str,
+    nonce: &[u8],
+    signature: &[u8],
+) -> bool {
+    signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+        .verify(&register_proof_input(agent_id, nonce), signature)
+        .is_ok()
+}
+
+fn register_proof_input(agent_id: &str, nonce: &[u8]) -> Vec<u8> {
+    let mut input = Vec::with_capacity(agent_id.len() + 1 + nonce.len());
+    input.extend_from_slice(agent_id.as_bytes());
+    input.push(0);
+    input.extend_from_slice(nonce);
+    input
 }
 
 impl ControlMessage {
     /// Returns the corresponding 1-byte tag for this control message.
     pub fn tag(&self) -> MessageTag {
         match self {
-            Self::Register => TAG_REGISTER,
+            Self::Register { .. } => TAG_REGISTER,
             Self::RegisterOk { .. } => TAG_REGISTER_OK,
             Self::Connect { .. } => TAG_CONNECT,
             Self::TunnelRequest { .. } => TAG_TUNNEL_REQUEST,
@@ -75,8 +841,41 @@ impl ControlMessage {
             Self::StreamOpen { .. } => TAG_STREAM_OPEN,
             Self::StreamClose { .. } => TAG_STREAM_CLOSE,
             Self::Ping => TAG_PING,
-            Self::Pong => TAG_PONG,
+            Self::Pong { .. } => TAG_PONG,
             Self::Error { .. } => TAG_ERROR,
+            Self::ListAgents { .. } => TAG_LIST_AGENTS,
+            Self::AgentList { .. } => TAG_AGENT_LIST,
+            Self::TunnelReject { .. } => TAG_TUNNEL_REJECT,
+            Self::ConnectFailed { .. } => TAG_CONNECT_FAILED,
+            Self::ObserveRequest { .. } => TAG_OBSERVE_REQUEST,
+            Self::ObserveConsent { .. } => TAG_OBSERVE_CONSENT,
+            Self::ObserveReply { .. } => TAG_OBSERVE_REPLY,
+            Self::SessionStats { .. } => TAG_SESSION_STATS,
+            Self::ObserveEnd { .. } => TAG_OBSERVE_END,
+            Self::Expose { .. } => TAG_EXPOSE,
+            Self::ExposeReady { .. } => TAG_EXPOSE_READY,
+            Self::ExposeHttp { .. } => TAG_EXPOSE_HTTP,
+            Self::ExposeHttpReady { .. } => TAG_EXPOSE_HTTP_READY,
+            Self::SessionPing { .. } => TAG_SESSION_PING,
+            Self::SessionPong { .. } => TAG_SESSION_PONG,
+            Self::StreamOpenFailed { .. } => TAG_STREAM_OPEN_FAILED,
+            Self::RegisterChallenge { .. } => TAG_REGISTER_CHALLENGE,
+            Self::RegisterProof { .. } => TAG_REGISTER_PROOF,
+            Self::RelayHello { .. } => TAG_RELAY_HELLO,
+            Self::Unregister => TAG_UNREGISTER,
+            Self::RegisterController { .. } => TAG_REGISTER_CONTROLLER,
+            Self::ProbeTarget { .. } => TAG_PROBE_TARGET,
+            Self::ProbeResult { .. } => TAG_PROBE_RESULT,
+            Self::CreateInvite { .. } => TAG_CREATE_INVITE,
+            Self::InviteCreated { .. } => TAG_INVITE_CREATED,
+            Self::ExposeTls { .. } => TAG_EXPOSE_TLS,
+            Self::ExposeTlsReady { .. } => TAG_EXPOSE_TLS_READY,
+            Self::ConnectService { .. } => TAG_CONNECT_SERVICE,
+            Self::RequestAccess { .. } => TAG_REQUEST_ACCESS,
+            Self::AccessRequested { .. } => TAG_ACCESS_REQUESTED,
+            Self::DecideAccess { .. } => TAG_DECIDE_ACCESS,
+            Self::AccessStatus { .. } => TAG_ACCESS_STATUS,
+            Self::AccessUpdate { .. } => TAG_ACCESS_UPDATE,
         }
     }
 
@@ -105,9 +904,705 @@ impl ControlMessage {
             return Err("Cannot deserialize Data message as ControlMessage".into());
         }
 
-        let msg: Self = bincode::deserialize(&buf[1..]).map_err(|e| e.to_string())?;
+        // Same encoding as `bincode::serialize`, but trailing bytes are an error.
+        let msg: Self = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .reject_trailing_bytes()
+            .deserialize(&buf[1..])
+            .map_err(|e| e.to_string())?;
+        if msg.tag() != tag {
+            return Err(format!(
+                "Tag 0x{:02X} does not match message 0x{:02X}",
+                tag,
+                msg.tag()
+            ));
+        }
         Ok(msg)
     }
+
+    /// The tunnel session this message concerns, if any.
+    pub fn session_id(&self) -> Option<&str> {
+        match self {
+            Self::TunnelRequest { session_id, .. }
+            | Self::TunnelAccept { session_id, .. }
+            | Self::TunnelReady { session_id, .. }
+            | Self::TunnelClose { session_id }
+            | Self::StreamOpen { session_id, .. }
+            | Self::StreamClose { session_id, .. }
+            | Self::TunnelReject { session_id, .. }
+            | Self::ObserveRequest { session_id }
+            | Self::ObserveConsent { session_id, .. }
+            | Self::ObserveReply { session_id, .. }
+            | Self::ObserveEnd { session_id, .. }
+            | Self::ExposeReady { session_id, .. }
+            | Self::ExposeHttpReady { session_id, .. }
+            | Self::ExposeTlsReady { session_id, .. }
+            | Self::SessionPing { session_id, .. }
+            | Self::SessionPong { session_id, .. }
+            | Self::StreamOpenFailed { session_id, .. } => Some(session_id),
+            Self::SessionStats { stats } => Some(&stats.session_id),
+            _ => None,
+        }
+    }
+
+    /// Checks field contents before the message is dispatched: ID and text
+    /// lengths, tag counts, target ports and hostname syntax.
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Self::Register {
+                token,
+                tags,
+                name,
+                agent_id,
+                version,
+                services,
+                public_key,
+            } => {
+                if let Some(token) = token {
+                    check_len("token", token, MAX_TOKEN_LEN)?;
+                }
+                if let Some(agent_id) = agent_id {
+                    check_id("agent_id", agent_id)?;
+                }
+                check_public_key(public_key)?;
+                if let Some(name) = name {
+                    check_label("name", name)?;
+                }
+                if let Some(version) = version {
+                    check_label("version", version)?;
+                }
+                if tags.len() > MAX_TAGS {
+                    return Err(format!("at most {} tags are allowed", MAX_TAGS));
+                }
+                tags.iter().try_for_each(|t| check_label("tag", t))?;
+                if services.len() > MAX_SERVICES {
+                    return Err(format!("at most {} services are allowed", MAX_SERVICES));
+                }
+                for (i, service) in services.iter().enumerate() {
+                    check_service(service)?;
+                    if find_service(&services[..i], &service.name).is_some() {
+                        return Err(format!("service '{}' is listed twice", service.name));
+                    }
+                }
+                Ok(())
+            }
+            Self::RegisterController { token, version } => {
+                if let Some(token) = token {
+                    check_len("token", token, MAX_TOKEN_LEN)?;
+                }
+                match version {
+                    Some(version) => check_label("version", version),
+                    None => Ok(()),
+                }
+            }
+            Self::ConnectService {
+                request_id,
+                target_id,
+                service_name,
+                traffic_class: _,
+            } => {
+                check_id("request_id", request_id)?;
+                check_label("target_id", target_id)?;
+                check_label("service_name", service_name)
+            }
+            Self::RequestAccess {
+                request_id,
+                target_id,
+                remote_host,
+                remote_port,
+                reason,
+            } => {
+                check_id("request_id", request_id)?;
+                check_label("target_id", target_id)?;
+                if let Some(reason) = reason {
+                    check_len("reason", reason, MAX_TEXT_LEN)?;
+                }
+                check_target(remote_host, *remote_port)
+            }
+            Self::AccessRequested {
+                access_id,
+                requester,
+                remote_host,
+                remote_port,
+                reason,
+                ..
+            } => {
+                check_id("access_id", access_id)?;
+                if let Some(requester) = requester {
+                    check_label("requester", requester)?;
+                }
+                if let Some(reason) = reason {
+                    check_len("reason", reason, MAX_TEXT_LEN)?;
+                }
+                check_target(remote_host, *remote_port)
+            }
+            Self::DecideAccess {
+                access_id,
+                approve,
+                ttl_secs,
+            } => {
+                check_id("access_id", access_id)?;
+                if *approve && (*ttl_secs == 0 || *ttl_secs > MAX_INVITE_TTL_SECS) {
+                    return Err(format!(
+                        "ttl_secs must be between 1 and {}",
+                        MAX_INVITE_TTL_SECS
+                    ));
+                }
+                Ok(())
+            }
+            Self::AccessStatus {
+                request_id,
+                access_id,
+            } => {
+                check_id("request_id", request_id)?;
+                check_id("access_id", access_id)
+            }
+            Self::AccessUpdate {
+                request_id,
+                access_id,
+                invite,
+                message,
+                ..
+            } => {
+                check_id("request_id", request_id)?;
+                if let Some(access_id) = access_id {
+                    check_id("access_id", access_id)?;
+                }
+                if let Some(invite) = invite {
+                    check_id("invite", invite)?;
+                }
+                match message {
+                    Some(message) => check_len("message", message, MAX_TEXT_LEN),
+                    None => Ok(()),
+                }
+            }
+            Self::ProbeTarget {
+                request_id,
+                target_id,
+                host,
+                port,
+                requester,
+            } => {
+                check_id("request_id", request_id)?;
+                check_label("target_id", target_id)?;
+                if let Some(requester) = requester {
+                    check_label("requester", requester)?;
+                }
+                check_target(host, *port)
+            }
+            Self::ProbeResult {
+                request_id,
+                message,
+                ..
+            } => {
+                check_id("request_id", request_id)?;
+                match message {
+                    Some(message) => check_len("message", message, MAX_TEXT_LEN),
+                    None => Ok(()),
+                }
+            }
+            Self::CreateInvite {
+                request_id,
+                remote_host,
+                remote_port,
+                ttl_secs,
+            } => {
+                check_id("request_id", request_id)?;
+                if *ttl_secs == 0 || *ttl_secs > MAX_INVITE_TTL_SECS {
+                    return Err(format!(
+                        "ttl_secs must be between 1 and {}",
+                        MAX_INVITE_TTL_SECS
+                    ));
+                }
+                check_target(remote_host, *remote_port)
+            }
+            Self::InviteCreated {
+                request_id,
+                token,
+                message,
+                ..
+            } => {
+                check_id("request_id", request_id)?;
+                if let Some(token) = token {
+                    check_id("token", token)?;
+                }
+                match message {
+                    Some(message) => check_len("message", message, MAX_TEXT_LEN),
+                    None => Ok(()),
+                }
+            }
+            Self::RegisterChallenge { nonce } => {
+                if nonce.len() != CHALLENGE_LEN {
+                    return Err(format!("nonce must be {} bytes", CHALLENGE_LEN));
+                }
+                Ok(())
+            }
+            Self::RegisterProof { proof } => {
+                if proof.len() != PROOF_LEN && proof.len() != SIGNATURE_LEN {
+                    return Err(format!(
+                        "proof must be {} or {} bytes",
+                        PROOF_LEN, SIGNATURE_LEN
+                    ));
+                }
+                Ok(())
+            }
+            Self::RelayHello { relay_id, secret } => {
+                check_len("secret", secret, MAX_TOKEN_LEN)?;
+                check_id("relay_id", relay_id)
+            }
+            Self::RegisterOk {
+                agent_id,
+                max_chunk_bytes,
+                server_version,
+                ..
+            } => {
+                if *max_chunk_bytes == 0 {
+                    return Err("max_chunk_bytes must not be 0".into());
+                }
+                check_label("server_version", server_version)?;
+                match agent_id {
+                    Some(agent_id) => check_id("agent_id", agent_id),
+                    None => Ok(()),
+                }
+            }
+            Self::Connect {
+                target_id,
+                remote_host,
+                remote_port,
+                request_id,
+                remote_socket,
+                pairing_token,
+                connect_timeout_ms,
+                requester,
+                extra_ports,
+                invite,
+                ttl_secs,
+                reverse_socks,
+                traffic_class: _,
+            } => {
+                check_label("target_id", target_id)?;
+                check_tunnel_target(remote_host, *remote_port, remote_socket.as_deref())?;
+                check_extra_ports(extra_ports, remote_socket.is_some())?;
+                check_reverse_socks(*reverse_socks, remote_socket.is_some(), extra_ports)?;
+                if let Some(token) = pairing_token {
+                    check_id("pairing_token", token)?;
+                }
+                if let Some(token) = invite {
+                    check_id("invite", token)?;
+                }
+                check_tunnel_ttl(*ttl_secs)?;
+                if let Some(requester) = requester {
+                    check_label("requester", requester)?;
+                }
+                check_connect_timeout(*connect_timeout_ms)?;
+                check_id("request_id", request_id)
+            }
+            Self::TunnelRequest {
+                session_id,
+                remote_host,
+                remote_port,
+                remote_socket,
+                requester,
+                pairing_token,
+                connect_timeout_ms,
+                extra_ports,
+                ttl_secs,
+                reverse_socks,
+                ..
+            } => {
+                check_id("session_id", session_id)?;
+                check_tunnel_ttl(*ttl_secs)?;
+                check_extra_ports(extra_ports, remote_socket.is_some())?;
+                check_reverse_socks(*reverse_socks, remote_socket.is_some(), extra_ports)?;
+                if let Some(requester) = requester {
+                    check_label("requester", requester)?;
+                }
+                if let Some(token) = pairing_token {
+                    check_id("pairing_token", token)?;
+                }
+                check_connect_timeout(*connect_timeout_ms)?;
+                check_tunnel_target(remote_host, *remote_port, remote_socket.as_deref())
+            }
+            Self::TunnelAccept {
+                session_id,
+                max_streams,
+            } => {
+                check_id("session_id", session_id)?;
+                if *max_streams == Some(0) {
+                    return Err("max_streams must not be 0".into());
+                }
+                Ok(())
+            }
+            Self::TunnelClose { session_id }
+            | Self::ObserveRequest { session_id }
+            | Self::SessionPing { session_id, .. }
+            | Self::SessionPong { session_id, .. } => check_id("session_id", session_id),
+            Self::TunnelReady {
+                session_id,
+                request_id,
+                agent_id,
+                public_key,
+                ..
+            } => {
+                check_id("session_id", session_id)?;
+                check_id("request_id", request_id)?;
+                check_id("agent_id", agent_id)?;
+                check_public_key(public_key)
+            }
+            Self::StreamOpen {
+                session_id,
+                stream_id,
+                remote_port,
+            } => {
+                check_id("session_id", session_id)?;
+                if *remote_port == Some(0) {
+                    return Err("remote_port must not be 0".into());
+                }
+                check_id("stream_id", stream_id)
+            }
+            Self::StreamClose {
+                session_id,
+                stream_id,
+            } => {
+                check_id("session_id", session_id)?;
+                check_id("stream_id", stream_id)
+            }
+            Self::Ping | Self::Pong { .. } | Self::Unregister => Ok(()),
+            Self::Error { message, .. } => check_len("message", message, MAX_TEXT_LEN),
+            Self::ListAgents { tag } => match tag {
+                Some(tag) => check_label("tag", tag),
+                None => Ok(()),
+            },
+            Self::AgentList { .. } | Self::SessionStats { .. } => Ok(()),
+            Self::TunnelReject {
+                session_id,
+                message,
+                ..
+            } => {
+                check_id("session_id", session_id)?;
+                check_len("message", message, MAX_TEXT_LEN)
+            }
+            Self::ConnectFailed {
+                request_id,
+                message,
+                ..
+            } => {
+                check_id("request_id", request_id)?;
+                check_len("message", message, MAX_TEXT_LEN)
+            }
+            Self::StreamOpenFailed {
+                session_id,
+                stream_id,
+                message,
+                ..
+            } => {
+                check_id("session_id", session_id)?;
+                check_id("stream_id", stream_id)?;
+                check_len("message", message, MAX_TEXT_LEN)
+            }
+            Self::ObserveConsent {
+                session_id,
+                observer_id,
+                observer,
+            } => {
+                check_id("session_id", session_id)?;
+                check_id("observer_id", observer_id)?;
+                check_label("observer", observer)
+            }
+            Self::ObserveReply {
+                session_id,
+                observer_id,
+                ..
+            } => {
+                check_id("session_id", session_id)?;
+                check_id("observer_id", observer_id)
+            }
+            Self::ObserveEnd { session_id, reason } => {
+                check_id("session_id", session_id)?;
+                check_len("reason", reason, MAX_TEXT_LEN)
+            }
+            Self::Expose {
+                request_id,
+                remote_host,
+                remote_port,
+                ..
+            } => {
+                check_id("request_id", request_id)?;
+                check_target(remote_host, *remote_port)
+            }
+            Self::ExposeReady {
+                request_id,
+                session_id,
+                public_port,
+            } => {
+                check_id("request_id", request_id)?;
+                check_id("session_id", session_id)?;
+                if *public_port == 0 {
+                    return Err("public_port must not be 0".into());
+                }
+                Ok(())
+            }
+            Self::ExposeHttp {
+                request_id,
+                hostname,
+                remote_host,
+                remote_port,
+            }
+            | Self::ExposeTls {
+                request_id,
+                hostname,
+                remote_host,
+                remote_port,
+            } => {
+                check_id("request_id", request_id)?;
+                check_hostname(hostname)?;
+                check_target(remote_host, *remote_port)
+            }
+            Self::ExposeHttpReady {
+                request_id,
+                session_id,
+                hostname,
+            }
+            | Self::ExposeTlsReady {
+                request_id,
+                session_id,
+                hostname,
+            } => {
+                check_id("request_id", request_id)?;
+                check_id("session_id", session_id)?;
+                check_hostname(hostname)
+            }
+        }
+    }
+}
+
+fn check_len(field: &str, value: &str, max: usize) -> Result<(), String> {
+    if value.len() > max {
+        return Err(format!("{} exceeds {} bytes", field, max));
+    }
+    Ok(())
+}
+
+fn check_public_key(public_key: &Option<Vec<u8>>) -> Result<(), String> {
+    match public_key {
+        Some(key) if key.len() != PUBLIC_KEY_LEN => {
+            Err(format!("public_key must be {} bytes", PUBLIC_KEY_LEN))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_id(field: &str, value: &str) -> Result<(), String> {
+    if value.is_empty() {
+        return Err(format!("{} is empty", field));
+    }
+    check_len(field, value, MAX_ID_LEN)?;
+    if value.chars().any(|c| c.is_control() || c.is_whitespace()) {
+        return Err(format!(
+            "{} contains whitespace or control characters",
+            field
+        ));
+    }
+    Ok(())
+}
+
+fn check_label(field: &str, value: &str) -> Result<(), String> {
+    if value.trim().is_empty() {
+        return Err(format!("{} is empty", field));
+    }
+    check_len(field, value, MAX_LABEL_LEN)?;
+    if value.chars().any(char::is_control) {
+        return Err(format!("{} contains control characters", field));
+    }
+    Ok(())
+}
+
+fn check_service(service: &ServiceInfo) -> Result<(), String> {
+    check_label("service name", &service.name)?;
+    if let Some(note) = &service.note {
+        check_label("service note", note)?;
+    }
+    check_target(&service.host, service.port)
+}
+
+fn check_target(host: &str, port: u16) -> Result<(), String> {
+    if port == 0 {
+        return Err("remote_port must be between 1 and 65535".into());
+    }
+    if host != ECHO_HOST && !is_valid_host(host) {
+        return Err(format!(
+            "remote_host '{}' is not a valid hostname or IP",
+            host
+        ));
+    }
+    Ok(())
+}
+
+/// Checks the target of a tunnel: the Unix socket path when one is given,
+/// otherwise `host:port`.
+fn check_tunnel_target(host: &str, port: u16, socket: Option<&str>) -> Result<(), String> {
+    match socket {
+        Some(path) => check_socket_path(path),
+        None => check_target(host, port),
+    }
+}
+
+/// Checks a requested target connect timeout: non-zero and at most
+/// [`MAX_CONNECT_TIMEOUT_MS`].
+fn check_connect_timeout(timeout_ms: Option<u32>) -> Result<(), String> {
+    match timeout_ms {
+        Some(0) => Err("connect_timeout_ms must not be 0".into()),
+        Some(ms) if ms > MAX_CONNECT_TIMEOUT_MS => Err(format!(
+            "connect_timeout_ms is {}; the maximum is {}",
+            ms, MAX_CONNECT_TIMEOUT_MS
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Checks a tunnel lifetime: when set, between 1 and [`MAX_TUNNEL_TTL_SECS`].
+fn check_tunnel_ttl(ttl_secs: Option<u64>) -> Result<(), String> {
+    match ttl_secs {
+        Some(0) => Err("ttl_secs must not be 0".into()),
+        Some(secs) if secs > MAX_TUNNEL_TTL_SECS => Err(format!(
+            "ttl_secs is {}; the maximum is {}",
+            secs, MAX_TUNNEL_TTL_SECS
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Checks the extra ports of a tunnel: at most [`MAX_EXTRA_PORTS`], none
+/// of them 0, and none at all for a Unix socket target.
+fn check_extra_ports(ports: &[u16], socket: bool) -> Result<(), String> {
+    if ports.is_empty() {
+        return Ok(());
+    }
+    if socket {
+        return Err("extra_ports cannot be combined with remote_socket".into());
+    }
+    if ports.len() > MAX_EXTRA_PORTS {
+        return Err(format!(
+            "at most {} extra ports are allowed",
+            MAX_EXTRA_PORTS
+        ));
+    }
+    if ports.contains(&0) {
+        return Err("extra_ports must not contain 0".into());
+    }
+    Ok(())
+}
+
+/// A reverse SOCKS tunnel listens on its one port on the agent.
+fn check_reverse_socks(
+    reverse_socks: bool,
+    socket: bool,
+    extra_ports: &[u16],
+) -> Result<(), String> {
+    if reverse_socks && (socket || !extra_ports.is_empty()) {
+        return Err("reverse_socks cannot be combined with remote_socket or extra_ports".into());
+    }
+    Ok(())
+}
+
+fn check_socket_path(path: &str) -> Result<(), String> {
+    check_len("remote_socket", path, MAX_SOCKET_PATH)?;
+    if !path.starts_with('/') {
+        return Err(format!("remote_socket '{}' is not an absolute path", path));
+    }
+    if path.chars().any(char::is_control) {
+        return Err("remote_socket contains control characters".into());
+    }
+    Ok(())
+}
+
+fn check_hostname(hostname: &str) -> Result<(), String> {
+    check_len("hostname", hostname, MAX_HOSTNAME_LEN)?;
+    // Browsers and certificates only take letters, digits and hyphens.
+    if hostname.parse::<std::net::IpAddr>().is_ok()
+        || hostname.contains('_')
+        || !is_valid_host(hostname)
+    {
+        return Err(format!("hostname '{}' is not a valid DNS name", hostname));
+    }
+    Ok(())
+}
+
+/// Formats a target as `host:port`, bracketing IPv6 literals (`[::1]:22`).
+pub fn host_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Formats a tunnel target for logs: `unix:<path>` for a Unix socket,
+/// otherwise `host:port`.
+pub fn describe_target(host: &str, port: u16, socket: Option<&str>) -> String {
+    match socket {
+        Some(path) => format!("unix:{}", path),
+        None => host_port(host, port),
+    }
+}
+
+/// Strips surrounding whitespace and the brackets of an IPv6 literal as
+/// typed in a URL (`[::1]` becomes `::1`), the form messages carry.
+pub fn normalize_host(host: &str) -> &str {
+    let host = host.trim();
+    match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        Some(inner) if inner.parse::<std::net::Ipv6Addr>().is_ok() => inner,
+        _ => host,
+    }
+}
+
+/// Parses a `major.minor.patch` version, ignoring a pre-release or build
+/// suffix (`1.4.0-beta.2`). Missing minor and patch parts count as 0.
+pub fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let core = version.trim().split(['-', '+']).next()?;
+    let mut parts = core.split('.').map(|p| p.parse::<u64>().ok());
+    let major = parts.next()??;
+    let minor = parts.next().unwrap_or(Some(0))?;
+    let patch = parts.next().unwrap_or(Some(0))?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((major, minor, patch))
+}
+
+/// Returns `true` if `version` is `min` or newer. A version that does not
+/// parse is never new enough.
+pub fn version_at_least(version: &str, min: &str) -> bool {
+    match (parse_version(version), parse_version(min)) {
+        (Some(version), Some(min)) => version >= min,
+        _ => false,
+    }
+}
+
+/// `remote_host` naming the echo service built into the agent, which sends
+/// every byte of a stream back instead of dialing anything. It lets users
+/// check a tunnel end to end; `remote_port` is ignored but must be valid.
+pub const ECHO_HOST: &str = "@echo";
+
+/// Returns `true` if `host` is an IP address or an RFC 1123 hostname.
+/// Labels may also hold underscores, as in `_ldap._tcp.corp` or the
+/// `db_primary` names of Docker and Windows hosts, which resolvers accept.
+pub fn is_valid_host(host: &str) -> bool {
+    if host.parse::<std::net::IpAddr>().is_ok() {
+        return true;
+    }
+    let host = host.strip_suffix('.').unwrap_or(host);
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        })
 }
 
 /// Packs a raw DATA message into the defined binary protocol format.
@@ -153,20 +1648,558 @@ mod tests {
     #[test]
     fn test_control_message_serialization() {
         let msg = ControlMessage::RegisterOk {
-            agent_id: "A3F8-B2C1".to_string(),
+            agent_id: Some("A3F8-B2C1".to_string()),
+            server_time_ms: 1_700_000_000_000,
+            max_chunk_bytes: 256 * 1024,
+            server_version: "0.6.0".to_string(),
         };
         let bytes = msg.serialize().unwrap();
         assert_eq!(bytes[0], TAG_REGISTER_OK);
 
         let decoded = ControlMessage::deserialize(&bytes).unwrap();
         match decoded {
-            ControlMessage::RegisterOk { agent_id } => {
-                assert_eq!(agent_id, "A3F8-B2C1");
+            ControlMessage::RegisterOk {
+                agent_id,
+                server_time_ms,
+                max_chunk_bytes,
+                server_version,
+            } => {
+                assert_eq!(agent_id.as_deref(), Some("A3F8-B2C1"));
+                assert_eq!(server_time_ms, 1_700_000_000_000);
+                assert_eq!(max_chunk_bytes, 256 * 1024);
+                assert_eq!(server_version, "0.6.0");
             }
             _ => panic!("Wrong variant"),
         }
     }
 
+    #[test]
+    fn test_version_at_least() {
+        assert_eq!(parse_version("1.4.2"), Some((1, 4, 2)));
+        assert_eq!(parse_version("1.4"), Some((1, 4, 0)));
+        assert_eq!(parse_version("2.0.0-beta.1"), Some((2, 0, 0)));
+        assert_eq!(parse_version("1.x"), None);
+        assert_eq!(parse_version("1.2.3.4"), None);
+        assert!(version_at_least("0.10.0", "0.9.3"));
+        assert!(version_at_least("0.9.3", "0.9.3"));
+        assert!(!version_at_least("0.9.2", "0.9.3"));
+        assert!(!version_at_least("dev", "0.1.0"));
+    }
+
+    #[test]
+    fn test_error_message_roundtrip() {
+        let msg = ControlMessage::Error {
+            code: ErrorCode::Unauthorized,
+            message: "denied".to_string(),
+        };
+        let bytes = msg.serialize().unwrap();
+        assert_eq!(bytes[0], TAG_ERROR);
+
+        match ControlMessage::deserialize(&bytes).unwrap() {
+            ControlMessage::Error { code, message } => {
+                assert_eq!(code, ErrorCode::Unauthorized);
+                assert_eq!(message, "denied");
+            }
+            _ => panic!("Wrong variant"),
+        }
+    }
+
+    #[test]
+    fn test_estimate_clock_skew() {
+        // 200ms round trip, server stamped 5s ahead of the midpoint.
+        assert_eq!(estimate_clock_skew_ms(10_000, 15_100, 10_200), 5_000);
+        // Server behind the local clock.
+        assert_eq!(estimate_clock_skew_ms(10_000, 7_100, 10_200), -3_000);
+    }
+
+    #[test]
+    fn test_deserialize_rejects_trailing_bytes_and_wrong_tag() {
+        let mut bytes = ControlMessage::Ping.serialize().unwrap();
+        bytes.push(0);
+        assert!(ControlMessage::deserialize(&bytes).is_err());
+
+        let mut bytes = ControlMessage::Ping.serialize().unwrap();
+        bytes[0] = TAG_REGISTER;
+        assert!(ControlMessage::deserialize(&bytes).is_err());
+    }
+
+    /// A `Connect` to 127.0.0.1:22 for tests to adjust.
+    fn connect() -> ControlMessage {
+        ControlMessage::Connect {
+            target_id: "A3F8-B2C1".to_string(),
+            remote_host: "127.0.0.1".to_string(),
+            remote_port: 22,
+            request_id: "pending-1".to_string(),
+            remote_socket: None,
+            pairing_token: None,
+            connect_timeout_ms: None,
+            requester: None,
+            extra_ports: Vec::new(),
+            invite: None,
+            ttl_secs: None,
+            reverse_socks: false,
+            traffic_class: TrafficClass::Interactive,
+        }
+    }
+
+    /// The `TunnelRequest` an agent gets for [`connect`].
+    fn tunnel_request() -> ControlMessage {
+        ControlMessage::TunnelRequest {
+            session_id: "b7e1c2d4".to_string(),
+            remote_host: "127.0.0.1".to_string(),
+            remote_port: 22,
+            remote_socket: None,
+            requester: None,
+            pairing_token: None,
+            connect_timeout_ms: None,
+            extra_ports: Vec::new(),
+            invited: false,
+            ttl_secs: None,
+            reverse_socks: false,
+            traffic_class: TrafficClass::Interactive,
+        }
+    }
+
+    #[test]
+    fn test_validate_hosts() {
+        let connect = |host: &str, port: u16| {
+            let mut msg = connect();
+            if let ControlMessage::Connect {
+                remote_host,
+                remote_port,
+                ..
+            } = &mut msg
+            {
+                *remote_host = host.to_string();
+                *remote_port = port;
+            }
+            msg
+        };
+        assert!(connect("127.0.0.1", 22).validate().is_ok());
+        assert!(connect("db.internal", 5432).validate().is_ok());
+        assert!(connect("::1", 80).validate().is_ok());
+        assert!(connect("db.internal", 0).validate().is_err());
+        assert!(connect("bad host", 80).validate().is_err());
+        assert!(connect("-bad.example", 80).validate().is_err());
+        assert!(connect("db_primary.corp", 5432).validate().is_ok());
+        assert!(connect("_ldap._tcp.corp", 389).validate().is_ok());
+        assert!(connect(ECHO_HOST, 7).validate().is_ok());
+        assert!(connect("@other", 7).validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_expose_hostnames() {
+        let expose = |hostname: &str| ControlMessage::ExposeHttp {
+            request_id: "pending-1".to_string(),
+            hostname: hostname.to_string(),
+            remote_host: "127.0.0.1".to_string(),
+            remote_port: 3000,
+        };
+        assert!(expose("app.example.com").validate().is_ok());
+        assert!(expose("my_app.example.com").validate().is_err());
+        let long = format!("{}.example.com", vec!["a".repeat(63); 3].join("."));
+        assert!(long.len() > MAX_LABEL_LEN && long.len() <= MAX_HOSTNAME_LEN);
+        assert!(expose(&long).validate().is_ok());
+        assert!(
+            expose(&format!("{}.{}", "a".repeat(63), long))
+                .validate()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_validate_pairing_token() {
+        let paired = |token: &str| {
+            let mut msg = connect();
+            if let ControlMessage::Connect { pairing_token, .. } = &mut msg {
+                *pairing_token = Some(token.to_string());
+            }
+            msg
+        };
+        assert!(paired("4f1c9a7e2b").validate().is_ok());
+        assert!(paired("4f1c 9a7e").validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_extra_ports() {
+        let multi = |ports: Vec<u16>| {
+            let mut msg = connect();
+            if let ControlMessage::Connect {
+                remote_port,
+                extra_ports,
+                ..
+            } = &mut msg
+            {
+                *remote_port = 8000;
+                *extra_ports = ports;
+            }
+            msg
+        };
+        assert!(multi((8001..=8010).collect()).validate().is_ok());
+        assert!(multi(vec![8001, 0]).validate().is_err());
+        assert!(multi(vec![9000; MAX_EXTRA_PORTS + 1]).validate().is_err());
+
+        let stream = |port: Option<u16>| ControlMessage::StreamOpen {
+            session_id: "b7e1c2d4".to_string(),
+            stream_id: "a1b2c3d4".to_string(),
+            remote_port: port,
+        };
+        assert!(stream(Some(8005)).validate().is_ok());
+        assert!(stream(Some(0)).validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_reverse_socks() {
+        let socks = |ports: Vec<u16>| {
+            let mut msg = connect();
+            if let ControlMessage::Connect {
+                remote_port,
+                extra_ports,
+                reverse_socks,
+                traffic_class,
+                ..
+            } = &mut msg
+            {
+                *remote_port = 1080;
+                *extra_ports = ports;
+                *reverse_socks = true;
+                *traffic_class = TrafficClass::Bulk;
+            }
+            msg
+        };
+        assert!(socks(Vec::new()).validate().is_ok());
+        assert!(socks(vec![1081]).validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_unix_socket() {
+        let connect_unix = |path: &str| {
+            let mut msg = connect();
+            if let ControlMessage::Connect {
+                remote_host,
+                remote_port,
+                remote_socket,
+                ..
+            } = &mut msg
+            {
+                remote_host.clear();
+                *remote_port = 0;
+                *remote_socket = Some(path.to_string());
+            }
+            msg
+        };
+        assert!(connect_unix("/var/run/docker.sock").validate().is_ok());
+        assert!(connect_unix("run/docker.sock").validate().is_err());
+        assert!(
+            connect_unix(&format!("/{}", "s".repeat(MAX_SOCKET_PATH)))
+                .validate()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_validate_requester() {
+        let request = |name: Option<&str>| {
+            let mut msg = tunnel_request();
+            if let ControlMessage::TunnelRequest { requester, .. } = &mut msg {
+                *requester = name.map(str::to_string);
+            }
+            msg
+        };
+        assert!(request(None).validate().is_ok());
+        assert!(request(Some("alice")).validate().is_ok());
+        assert!(request(Some("alice\n")).validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_connect_timeout() {
+        let dial_timeout = |ms: Option<u32>| {
+            let mut msg = tunnel_request();
+            if let ControlMessage::TunnelRequest {
+                connect_timeout_ms, ..
+            } = &mut msg
+            {
+                *connect_timeout_ms = ms;
+            }
+            msg
+        };
+        assert!(dial_timeout(Some(5_000)).validate().is_ok());
+        assert!(dial_timeout(Some(0)).validate().is_err());
+        assert!(
+            dial_timeout(Some(MAX_CONNECT_TIMEOUT_MS + 1))
+                .validate()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_validate_ttl() {
+        let timed = |secs: Option<u64>| {
+            let mut msg = tunnel_request();
+            if let ControlMessage::TunnelRequest { ttl_secs, .. } = &mut msg {
+                *ttl_secs = secs;
+            }
+            msg
+        };
+        assert!(timed(Some(3600)).validate().is_ok());
+        assert!(timed(Some(0)).validate().is_err());
+        assert!(timed(Some(MAX_TUNNEL_TTL_SECS + 1)).validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_register() {
+        let register = ControlMessage::Register {
+            token: None,
+            tags: vec!["env=prod".to_string(); MAX_TAGS + 1],
+            name: None,
+            agent_id: None,
+            version: None,
+            services: Vec::new(),
+            public_key: None,
+        };
+        assert!(register.validate().is_err());
+
+        let ssh = ServiceInfo::parse("ssh = 127.0.0.1:22").unwrap();
+        assert_eq!(
+            (ssh.name.as_str(), ssh.host.as_str(), ssh.port),
+            ("ssh", "127.0.0.1", 22)
+        );
+        assert_eq!(ServiceInfo::parse("db=[::1]:5432").unwrap().host, "::1");
+        assert!(ServiceInfo::parse("web=localhost").is_err());
+        assert!(ServiceInfo::parse("web=localhost:0").is_err());
+        let advertise = |services: Vec<ServiceInfo>| ControlMessage::Register {
+            token: None,
+            tags: Vec::new(),
+            name: None,
+            agent_id: None,
+            version: None,
+            services,
+            public_key: None,
+        };
+        assert!(advertise(vec![ssh.clone()]).validate().is_ok());
+        assert!(
+            advertise(vec![ssh.clone(), ssh.clone()])
+                .validate()
+                .is_err()
+        );
+        assert_eq!(find_service(std::slice::from_ref(&ssh), "SSH"), Some(&ssh));
+        let noted = ServiceInfo::parse("grafana=127.0.0.1:3000 # SSO login").unwrap();
+        assert_eq!(noted.note.as_deref(), Some("SSO login"));
+        assert_eq!(noted.port, 3000);
+        assert_eq!(ServiceInfo::parse("ssh=127.0.0.1:22#").unwrap().note, None);
+    }
+
+    #[test]
+    fn test_connect_service() {
+        let by_name = |service_name: &str| ControlMessage::ConnectService {
+            request_id: "pending-1".to_string(),
+            target_id: "OFFICE-PC".to_string(),
+            service_name: service_name.to_string(),
+            traffic_class: TrafficClass::Interactive,
+        };
+        let encoded = by_name("ssh").serialize().unwrap();
+        assert_eq!(encoded[0], TAG_CONNECT_SERVICE);
+        assert!(ControlMessage::deserialize(&encoded).is_ok());
+        assert!(by_name("ssh").validate().is_ok());
+        assert!(by_name(" ").validate().is_err());
+    }
+
+    #[test]
+    fn test_access_messages() {
+        let request = |host: &str| ControlMessage::RequestAccess {
+            request_id: "access-1".to_string(),
+            target_id: "OFFICE-PC".to_string(),
+            remote_host: host.to_string(),
+            remote_port: 5432,
+            reason: Some("Quarterly report".to_string()),
+        };
+        let encoded = request("db.internal").serialize().unwrap();
+        assert_eq!(encoded[0], TAG_REQUEST_ACCESS);
+        assert!(ControlMessage::deserialize(&encoded).is_ok());
+        assert!(request("bad host").validate().is_err());
+        let decide = |approve: bool, ttl_secs: u64| ControlMessage::DecideAccess {
+            access_id: "4b1d0c2e".to_string(),
+            approve,
+            ttl_secs,
+        };
+        let encoded = decide(true, 3600).serialize().unwrap();
+        assert_eq!(encoded[0], TAG_DECIDE_ACCESS);
+        assert!(decide(true, 3600).validate().is_ok());
+        assert!(decide(true, 0).validate().is_err());
+        assert!(decide(false, 0).validate().is_ok());
+        let update = ControlMessage::AccessUpdate {
+            request_id: "access-1".to_string(),
+            access_id: Some("4b1d0c2e".to_string()),
+            state: AccessState::Approved,
+            invite: Some("9f2c".to_string()),
+            expires_at_ms: 1_700_000_000_000,
+            code: None,
+            message: None,
+        };
+        let encoded = update.serialize().unwrap();
+        assert_eq!(encoded[0], TAG_ACCESS_UPDATE);
+        assert!(matches!(
+            ControlMessage::deserialize(&encoded),
+            Ok(ControlMessage::AccessUpdate {
+                state: AccessState::Approved,
+                invite: Some(_),
+                ..
+            })
+        ));
+        assert!(update.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate() {
+        let controller = |version: &str| ControlMessage::RegisterController {
+            token: Some("secret".to_string()),
+            version: Some(version.to_string()),
+        };
+        let encoded = controller("0.6.0").serialize().unwrap();
+        assert_eq!(encoded[0], TAG_REGISTER_CONTROLLER);
+        assert!(ControlMessage::deserialize(&encoded).is_ok());
+        assert!(controller("0.6.0").validate().is_ok());
+        assert!(controller("0.6\n").validate().is_err());
+
+        let probe = |host: &str, port: u16| ControlMessage::ProbeTarget {
+            request_id: "probe-1".to_string(),
+            target_id: "OFFICE-PC".to_string(),
+            host: host.to_string(),
+            port,
+            requester: None,
+        };
+        let encoded = probe("db.internal", 5432).serialize().unwrap();
+        assert_eq!(encoded[0], TAG_PROBE_TARGET);
+        assert!(ControlMessage::deserialize(&encoded).is_ok());
+        assert!(probe("db.internal", 0).validate().is_err());
+        assert!(probe("bad host", 5432).validate().is_err());
+
+        let invite = |ttl_secs: u64| ControlMessage::CreateInvite {
+            request_id: "invite-1".to_string(),
+            remote_host: "127.0.0.1".to_string(),
+            remote_port: 8080,
+            ttl_secs,
+        };
+        let encoded = invite(3600).serialize().unwrap();
+        assert_eq!(encoded[0], TAG_CREATE_INVITE);
+        assert!(ControlMessage::deserialize(&encoded).is_ok());
+        assert!(invite(0).validate().is_err());
+        assert!(invite(MAX_INVITE_TTL_SECS + 1).validate().is_err());
+
+        let ready = |public_key: Option<Vec<u8>>| ControlMessage::TunnelReady {
+            session_id: "sess-1".to_string(),
+            request_id: "req-1".to_string(),
+            agent_id: "A3F8-B2C1".to_string(),
+            public_key,
+            max_streams: 256,
+        };
+        let encoded = ready(Some(vec![7; PUBLIC_KEY_LEN])).serialize().unwrap();
+        assert_eq!(encoded[0], TAG_TUNNEL_READY);
+        assert!(ControlMessage::deserialize(&encoded).is_ok());
+        assert!(ready(None).validate().is_ok());
+        assert!(ready(Some(vec![7; 31])).validate().is_err());
+
+        let accept = |max_streams| ControlMessage::TunnelAccept {
+            session_id: "sess-1".to_string(),
+            max_streams,
+        };
+        assert!(accept(None).validate().is_ok());
+        assert!(accept(Some(64)).validate().is_ok());
+        assert!(accept(Some(0)).validate().is_err());
+    }
+
+    #[test]
+    fn test_register_proof() {
+        let nonce = register_challenge();
+        assert_eq!(nonce.len(), CHALLENGE_LEN);
+        assert_ne!(nonce, register_challenge());
+
+        let proof = register_proof("s3cret", "OFFICE-PC", &nonce);
+        assert_eq!(proof.len(), PROOF_LEN);
+        assert!(verify_register_proof("s3cret", "OFFICE-PC", &nonce, &proof));
+        assert!(!verify_register_proof("wrong", "OFFICE-PC", &nonce, &proof));
+        assert!(!verify_register_proof("s3cret", "OTHER-PC", &nonce, &proof));
+        assert!(!verify_register_proof(
+            "s3cret",
+            "OFFICE-PC",
+            &register_challenge(),
+            &proof
+        ));
+
+        let msg = ControlMessage::RegisterProof { proof };
+        assert!(msg.validate().is_ok());
+        let decoded = ControlMessage::deserialize(&msg.serialize().unwrap()).unwrap();
+        assert!(matches!(decoded, ControlMessage::RegisterProof { .. }));
+        let short = ControlMessage::RegisterChallenge { nonce: vec![0; 8] };
+        assert!(short.validate().is_err());
+    }
+
+    #[test]
+    fn test_register_signature() {
+        let key = generate_identity_key();
+        let public_key = identity_public_key(&key).unwrap();
+        assert_eq!(public_key.len(), PUBLIC_KEY_LEN);
+        let agent_id = key_agent_id(&public_key);
+        assert_eq!(agent_id.len(), 19);
+        assert_eq!(agent_id, key_agent_id(&public_key));
+        assert!(check_id("agent_id", &agent_id).is_ok());
+
+        let nonce = register_challenge();
+        let signature = sign_register(&key, &agent_id, &nonce).unwrap();
+        assert_eq!(signature.len(), SIGNATURE_LEN);
+        assert!(verify_register_signature(
+            &public_key,
+            &agent_id,
+            &nonce,
+            &signature
+        ));
+        assert!(!verify_register_signature(
+            &public_key,
+            "A3F8-B2C1",
+            &nonce,
+            &signature
+        ));
+        assert!(!verify_register_signature(
+            &public_key,
+            &agent_id,
+            &register_challenge(),
+            &signature
+        ));
+        let other = identity_public_key(&generate_identity_key()).unwrap();
+        assert!(!verify_register_signature(
+            &other, &agent_id, &nonce, &signature
+        ));
+        assert!(identity_public_key(b"not a key").is_none());
+        assert!(
+            ControlMessage::RegisterProof { proof: signature }
+                .validate()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_ipv6_targets() {
+        assert_eq!(host_port("::1", 22), "[::1]:22");
+        assert_eq!(host_port("db.internal", 5432), "db.internal:5432");
+        assert_eq!(normalize_host(" [fe80::1] "), "fe80::1");
+        assert_eq!(normalize_host("[not-ip]"), "[not-ip]");
+        assert!(is_valid_host(normalize_host("[::1]")));
+        assert_eq!(describe_target("::1", 22, None), "[::1]:22");
+        assert_eq!(
+            describe_target("", 0, Some("/var/run/docker.sock")),
+            "unix:/var/run/docker.sock"
+        );
+    }
+
+    #[test]
+    fn test_tags_match() {
+        let tags = vec!["env=prod".to_string(), "site=hanoi".to_string()];
+        assert!(tags_match(&tags, "env=prod"));
+        assert!(tags_match(&tags, "site"));
+        assert!(!tags_match(&tags, "env=dev"));
+        assert!(!tags_match(&tags, "region"));
+    }
+
     #[test]
     fn test_data_message() {
         let session = [1, 2, 3, 4, 5, 6, 7, 8];
@@ -181,4 +2214,13 @@ mod tests {
         assert_eq!(st, stream);
         assert_eq!(p, payload);
     }
+
+    #[test]
+    fn traffic_classes_rank_below_control() {
+        let interactive = TrafficClass::Interactive.stream_priority();
+        let bulk = TrafficClass::Bulk.stream_priority();
+        assert!(bulk < interactive && interactive < CONTROL_STREAM_PRIORITY);
+        assert_eq!("bulk".parse(), Ok(TrafficClass::Bulk));
+        assert!("realtime".parse::<TrafficClass>().is_err());
+    }
 }